@@ -0,0 +1,6 @@
+fn main() {
+    // The gRPC service definition is compiled only with the `grpc` feature, which needs `protoc`
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/ledger_map.proto")
+        .expect("Failed to compile proto/ledger_map.proto");
+}
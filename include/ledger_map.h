@@ -0,0 +1,112 @@
+#ifndef LEDGER_MAP_H
+#define LEDGER_MAP_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Returned by the fallible functions on success.
+#define LEDGER_MAP_OK 0
+
+typedef struct LedgerMap LedgerMap;
+
+// Called by `ledger_map_iter` with each entry. Returning `false` stops the iteration.
+// The pointers are only valid for the duration of the call.
+typedef bool (*LedgerMapEntryCallback)(void *user_data,
+                                       const char *label,
+                                       const uint8_t *key,
+                                       size_t key_len,
+                                       const uint8_t *value,
+                                       size_t value_len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Open the ledger stored in the file at `path`, or in the default file if `path` is NULL.
+// If `labels_len` is not 0, only the `labels_len` labels in `labels` are indexed.
+// Returns NULL on failure. The ledger must be closed with `ledger_map_close`.
+// Each ledger keeps its own file open, so ledgers in different files can be open at the same
+// time, also on the same thread. A file must not be open in more than one ledger at a time.
+//
+// # Safety
+// `path` must be NULL or a valid C string, and `labels` must point to `labels_len` valid
+// C strings.
+LedgerMap *ledger_map_open(const char *path, const char *const *labels, size_t labels_len);
+
+// Close a ledger opened with `ledger_map_open`. Uncommitted entries are discarded.
+//
+// # Safety
+// `ledger_map` must be NULL or a ledger returned by `ledger_map_open`, not closed before.
+void ledger_map_close(LedgerMap *ledger_map);
+
+// Insert or update the entry with `key` under `label`, in the next block.
+//
+// # Safety
+// `ledger_map` must be an open ledger, `label` a valid C string, and `key` and `value` must
+// point to `key_len` and `value_len` bytes.
+uint32_t ledger_map_upsert(LedgerMap *ledger_map,
+                           const char *label,
+                           const uint8_t *key,
+                           size_t key_len,
+                           const uint8_t *value,
+                           size_t value_len);
+
+// Look up the value of the entry with `key` under `label`. On success, `*value` and `*value_len`
+// are set to a copy of the value, which must be released with `ledger_map_bytes_free`.
+//
+// # Safety
+// `ledger_map` must be an open ledger, `label` a valid C string, `key` must point to `key_len`
+// bytes, and `value` and `value_len` must be valid for writes.
+uint32_t ledger_map_get(LedgerMap *ledger_map,
+                        const char *label,
+                        const uint8_t *key,
+                        size_t key_len,
+                        uint8_t **value,
+                        size_t *value_len);
+
+// Release a value returned by `ledger_map_get`.
+//
+// # Safety
+// `bytes` and `len` must have been returned by `ledger_map_get`, and not released before.
+void ledger_map_bytes_free(uint8_t *bytes, size_t len);
+
+// Delete the entry with `key` under `label`, in the next block.
+//
+// # Safety
+// `ledger_map` must be an open ledger, `label` a valid C string, and `key` must point to
+// `key_len` bytes.
+uint32_t ledger_map_delete(LedgerMap *ledger_map,
+                           const char *label,
+                           const uint8_t *key,
+                           size_t key_len);
+
+// Commit the next block to the persistent storage.
+//
+// # Safety
+// `ledger_map` must be an open ledger.
+uint32_t ledger_map_commit_block(LedgerMap *ledger_map);
+
+// Call `callback` with each committed entry, optionally only of `label` if it is not NULL.
+//
+// # Safety
+// `ledger_map` must be an open ledger, `label` NULL or a valid C string, and `callback` must not
+// modify the ledger.
+uint32_t ledger_map_iter(LedgerMap *ledger_map,
+                         const char *label,
+                         LedgerMapEntryCallback callback,
+                         void *user_data);
+
+// Message of the last failure on the calling thread, or NULL if nothing failed yet.
+// The message is valid until the next failing call on the same thread.
+const char *ledger_map_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* LEDGER_MAP_H */
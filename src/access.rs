@@ -0,0 +1,130 @@
+//! This module implements authorization of the ledger writes: an `AccessController` set with
+//! `LedgerMap::set_access_controller` is consulted on every `upsert`, `delete` and
+//! `commit_block`, with the principal (identity) of the caller, and denied operations fail with
+//! `LedgerError::Unauthorized`.
+//!
+//! Labels can also have a declarative `LabelPolicy`, set with `LedgerMap::set_label_policy`: it
+//! is persisted in the ledger, so that it applies in every process that opens the ledger, and it
+//! is enforced on every write of the label, including the writes merged from other ledgers.
+//!
+//! The principal comes from a `PrincipalSource`. By default it is the caller of the canister
+//! method on the Internet Computer, and empty on the other platforms, where the application sets
+//! its own source, e.g. the authenticated user of the current request.
+//!
+//! Example usage:
+//!
+//! ```rust,no_run
+//! use ledger_map::access::AccessRequest;
+//! use ledger_map::LedgerMap;
+//!
+//! let admin = b"admin".to_vec();
+//! let ledger_map = LedgerMap::builder()
+//!     // Only the admin can write the "config" label
+//!     .access_controller(move |request: &AccessRequest| {
+//!         request.label != "config" || request.principal == admin
+//!     })
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Ledger operation checked by an `AccessController`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessOperation {
+    Upsert,
+    Delete,
+    CommitBlock,
+}
+
+impl std::fmt::Display for AccessOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessOperation::Upsert => write!(f, "upsert"),
+            AccessOperation::Delete => write!(f, "delete"),
+            AccessOperation::CommitBlock => write!(f, "commit block"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessRequest<'a> {
+    pub principal: &'a [u8],
+    pub operation: AccessOperation,
+    /// Label of the entry, empty for `AccessOperation::CommitBlock`.
+    pub label: &'a str,
+    /// Key of the entry, empty for `AccessOperation::CommitBlock`.
+    pub key: &'a [u8],
+}
+
+/// Authorization policy of the ledger writes. Any `Fn(&AccessRequest) -> bool` closure is an
+/// access controller.
+pub trait AccessController: Send {
+    /// Returns whether the operation is allowed.
+    fn is_allowed(&self, request: &AccessRequest) -> bool;
+}
+
+impl<F: Fn(&AccessRequest) -> bool + Send> AccessController for F {
+    fn is_allowed(&self, request: &AccessRequest) -> bool {
+        self(request)
+    }
+}
+
+impl std::fmt::Debug for dyn AccessController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AccessController")
+    }
+}
+
+/// Source of the principal of the current caller. Any `Fn() -> Vec<u8>` closure is a principal
+/// source.
+pub trait PrincipalSource: Send {
+    fn principal(&self) -> Vec<u8>;
+}
+
+impl<F: Fn() -> Vec<u8> + Send> PrincipalSource for F {
+    fn principal(&self) -> Vec<u8> {
+        self()
+    }
+}
+
+impl std::fmt::Debug for dyn PrincipalSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PrincipalSource")
+    }
+}
+
+/// Write policy of a label, see `LedgerMap::set_label_policy`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LabelPolicy {
+    /// Reject all writes of the label.
+    pub read_only: bool,
+    /// Reject deletes of the label, so that its entries can only be added or updated.
+    pub append_only: bool,
+    /// Principals allowed to write the label, or `None` to allow all of them.
+    pub writers: Option<Vec<Vec<u8>>>,
+}
+
+impl LabelPolicy {
+    /// Returns the reason why `principal` is not allowed to `operation` the label, if it is not.
+    pub(crate) fn check(&self, principal: &[u8], operation: AccessOperation) -> Option<String> {
+        if self.read_only {
+            return Some("label is read-only".to_string());
+        }
+        if self.append_only && operation == AccessOperation::Delete {
+            return Some("label is append-only".to_string());
+        }
+        self.check_writer(principal)
+    }
+
+    /// Returns the reason why `principal` is not one of the writers of the label, if it is not.
+    pub(crate) fn check_writer(&self, principal: &[u8]) -> Option<String> {
+        match &self.writers {
+            Some(writers) if !writers.iter().any(|writer| writer == principal) => Some(format!(
+                "principal {} is not a writer of the label",
+                hex::encode(principal)
+            )),
+            _ => None,
+        }
+    }
+}
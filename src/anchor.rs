@@ -0,0 +1,137 @@
+//! This module implements external anchoring of the ledger: publishing the chain hash of the
+//! ledger periodically outside of the ledger (e.g. to another chain or to a transparency log),
+//! so that a later rewrite of the history can be detected by comparing the ledger against the
+//! published anchors with `LedgerMap::check_fork`.
+//!
+//! Example usage:
+//!
+//! ```rust,no_run
+//! use ledger_map::anchor::FileAnchor;
+//! use ledger_map::LedgerMap;
+//!
+//! let ledger_map = LedgerMap::builder()
+//!     .anchor(FileAnchor::new("anchors.txt".into()), 100)
+//!     .build()
+//!     .unwrap();
+//! ```
+
+/// Chain hash of the ledger after a commit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnchorPoint {
+    /// Number of blocks in the ledger, including the committed block.
+    pub blocks_count: u64,
+    pub chain_hash: Vec<u8>,
+    /// Timestamp of the committed block.
+    pub timestamp_ns: u64,
+}
+
+/// Publisher of anchor points, see `LedgerMap::set_anchor`.
+pub trait Anchor: Send {
+    fn anchor(&mut self, point: &AnchorPoint) -> anyhow::Result<()>;
+}
+
+impl std::fmt::Debug for dyn Anchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Anchor")
+    }
+}
+
+/// Anchor that appends the anchor points to a text file, one per line, formatted as
+/// `<blocks_count> <hex chain hash> <timestamp_ns>`. The file should be kept on a different
+/// storage than the ledger, or copied elsewhere, to be useful as tamper evidence.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "wasm32", target_os = "wasi")
+))]
+pub struct FileAnchor {
+    path: std::path::PathBuf,
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "wasm32", target_os = "wasi")
+))]
+impl FileAnchor {
+    pub fn new(path: std::path::PathBuf) -> Self {
+        FileAnchor { path }
+    }
+
+    /// Read the anchor points written to `path`, in the order they were written.
+    pub fn read(path: &std::path::Path) -> anyhow::Result<Vec<AnchorPoint>> {
+        std::fs::read_to_string(path)?
+            .lines()
+            .map(|line| {
+                let fields = line.split(' ').collect::<Vec<_>>();
+                match fields[..] {
+                    [blocks_count, chain_hash, timestamp_ns] => Ok(AnchorPoint {
+                        blocks_count: blocks_count.parse()?,
+                        chain_hash: hex::decode(chain_hash)?,
+                        timestamp_ns: timestamp_ns.parse()?,
+                    }),
+                    _ => Err(anyhow::format_err!("Invalid anchor line {:?}", line)),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "wasm32", target_os = "wasi")
+))]
+impl Anchor for FileAnchor {
+    fn anchor(&mut self, point: &AnchorPoint) -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(
+            file,
+            "{} {} {}",
+            point.blocks_count,
+            hex::encode(&point.chain_hash),
+            point.timestamp_ns
+        )?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    test,
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    )
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_anchor() {
+        let path = tempfile::tempdir().unwrap().keep().join("anchors.txt");
+        let points = vec![
+            AnchorPoint {
+                blocks_count: 10,
+                chain_hash: vec![1, 2, 3],
+                timestamp_ns: 100,
+            },
+            AnchorPoint {
+                blocks_count: 20,
+                chain_hash: vec![4, 5, 6],
+                timestamp_ns: 200,
+            },
+        ];
+        let mut anchor = FileAnchor::new(path.clone());
+        for point in &points {
+            anchor.anchor(point).unwrap();
+        }
+        assert_eq!(FileAnchor::read(&path).unwrap(), points);
+    }
+}
@@ -0,0 +1,97 @@
+/// This module implements certified reads of ledger entries on the Internet Computer.
+///
+/// The indexed entries are kept in a hash tree, which is combined with the ledger tip hash into
+/// the root hash that the canister sets as its certified data:
+///
+/// ```text
+/// root = fork(labeled("tip", leaf(tip_hash)),
+///             labeled("entries", labeled(<label>, labeled(<key>, leaf(sha256(value))))...))
+/// ```
+///
+/// A witness for a single entry then proves both the value and the ledger tip to agents.
+use crate::ledger_entry::{LedgerEntry, Operation};
+use ic_certification::{fork, labeled, leaf, pruned, AsHashTree, Hash, HashTree, RbTree};
+use sha2::Digest;
+
+const TIP_LABEL: &[u8] = b"tip";
+const ENTRIES_LABEL: &[u8] = b"entries";
+
+/// Value of a ledger entry, with the data certificate and the hash tree witness that bind
+/// the value to the certified data of the canister.
+#[derive(Debug)]
+pub struct CertifiedEntry {
+    pub value: Vec<u8>,
+    pub certificate: Vec<u8>,
+    pub witness: HashTree,
+}
+
+#[derive(Default)]
+pub(crate) struct CertifiedIndex {
+    entries: RbTree<Vec<u8>, RbTree<Vec<u8>, Hash>>,
+}
+
+impl std::fmt::Debug for CertifiedIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CertifiedIndex {{ root_hash: {} }}",
+            hex::encode(self.entries.root_hash())
+        )
+    }
+}
+
+impl CertifiedIndex {
+    pub(crate) fn clear(&mut self) {
+        self.entries = RbTree::default();
+    }
+
+    pub(crate) fn apply(&mut self, entry: &LedgerEntry) {
+        let label = entry.label().as_bytes();
+        match entry.operation() {
+            Operation::Upsert => {
+                let value_hash: Hash = sha2::Sha256::digest(entry.value()).into();
+                self.insert_value_hash(label, entry.key(), value_hash);
+            }
+            Operation::Delete => {
+                let mut is_empty = false;
+                self.entries.modify(label, |values| {
+                    values.delete(entry.key());
+                    is_empty = values.is_empty();
+                });
+                if is_empty {
+                    self.entries.delete(label);
+                }
+            }
+        }
+    }
+
+    /// Insert the hash of the value of the entry `key` of `label`.
+    pub(crate) fn insert_value_hash(&mut self, label: &[u8], key: &[u8], value_hash: Hash) {
+        if self.entries.get(label).is_none() {
+            self.entries.insert(label.to_vec(), RbTree::default());
+        }
+        self.entries
+            .modify(label, |values| values.insert(key.to_vec(), value_hash));
+    }
+
+    fn tree(&self, tip_hash: &[u8], entries: HashTree) -> HashTree {
+        fork(
+            labeled(TIP_LABEL.to_vec(), leaf(tip_hash.to_vec())),
+            labeled(ENTRIES_LABEL.to_vec(), entries),
+        )
+    }
+
+    /// Root hash to set as the certified data of the canister.
+    pub(crate) fn root_hash(&self, tip_hash: &[u8]) -> Hash {
+        self.tree(tip_hash, pruned(self.entries.root_hash()))
+            .digest()
+    }
+
+    /// Witness for the entry with the given label and key, or for its absence.
+    pub(crate) fn witness(&self, tip_hash: &[u8], label: &str, key: &[u8]) -> HashTree {
+        let entries = self
+            .entries
+            .nested_witness(label.as_bytes(), |values| values.witness(key));
+        self.tree(tip_hash, entries)
+    }
+}
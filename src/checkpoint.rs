@@ -0,0 +1,96 @@
+//! This module implements quorum checkpoints: every N blocks, the ledger collects signatures of
+//! the chain hash from a configured set of signers (e.g. independent operators, each with their
+//! own key), and commits them in a checkpoint block under `CHECKPOINT_LABEL`. A verifier can then
+//! require that each checkpoint is signed by a quorum of the signer set with
+//! `LedgerMap::verify_checkpoints`, so that a single compromised writer key is not enough to
+//! rewrite the history unnoticed.
+//!
+//! The signature scheme is up to the application, which provides the signers and the verifier.
+//!
+//! Example usage:
+//!
+//! ```rust,no_run
+//! use ledger_map::checkpoint::{CheckpointSigner, SignatureVerifier};
+//! use ledger_map::LedgerMap;
+//!
+//! fn example(signers: Vec<Box<dyn CheckpointSigner>>, verifier: &dyn SignatureVerifier) {
+//!     let signer_ids = signers.iter().map(|s| s.signer_id()).collect::<Vec<_>>();
+//!     let ledger_map = LedgerMap::builder()
+//!         .checkpoint_signers(signers, 100)
+//!         .build()
+//!         .unwrap();
+//!     // Require 2 of the signers for each checkpoint, and a checkpoint every 100 blocks
+//!     ledger_map.verify_checkpoints(&signer_ids, 2, 100, verifier).unwrap();
+//! }
+//! ```
+use borsh::{BorshDeserialize, BorshSerialize};
+use sha2::Digest;
+use std::collections::BTreeSet;
+
+/// Domain separator of the signed checkpoint messages.
+const CHECKPOINT_MESSAGE_PREFIX: &[u8] = b"ledger-map/checkpoint";
+
+/// Member of the signer set of the checkpoints, see `LedgerMap::set_checkpoint_signers`.
+pub trait CheckpointSigner: Send {
+    /// Identifier of the signer in the signer set, e.g. its public key.
+    fn signer_id(&self) -> Vec<u8>;
+    fn sign(&mut self, message: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+impl std::fmt::Debug for dyn CheckpointSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CheckpointSigner({})", hex::encode(self.signer_id()))
+    }
+}
+
+/// Verifier of the checkpoint signatures, matching the signature scheme of the signers.
+pub trait SignatureVerifier {
+    fn verify(&self, signer_id: &[u8], message: &[u8], signature: &[u8]) -> bool;
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointSignature {
+    pub signer_id: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Checkpoint of the chain, stored in the checkpoint block under `CHECKPOINT_LABEL`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Number of blocks before the checkpoint block.
+    pub blocks_count: u64,
+    /// Chain hash of the last block before the checkpoint block.
+    pub tip_hash: Vec<u8>,
+    pub signatures: Vec<CheckpointSignature>,
+}
+
+impl Checkpoint {
+    /// Message that the signers sign for the checkpoint of `tip_hash` after `blocks_count` blocks.
+    pub fn message(blocks_count: u64, tip_hash: &[u8]) -> Vec<u8> {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(CHECKPOINT_MESSAGE_PREFIX);
+        hasher.update(blocks_count.to_le_bytes());
+        hasher.update(tip_hash);
+        hasher.finalize().to_vec()
+    }
+
+    /// Number of distinct signers of `signer_ids` with a valid signature of the checkpoint.
+    pub fn count_valid_signers(
+        &self,
+        signer_ids: &[Vec<u8>],
+        verifier: &dyn SignatureVerifier,
+    ) -> usize {
+        let message = Self::message(self.blocks_count, &self.tip_hash);
+        signer_ids
+            .iter()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|signer_id| {
+                self.signatures.iter().any(|signature| {
+                    signature.signer_id == **signer_id
+                        && verifier.verify(signer_id, &message, &signature.signature)
+                })
+            })
+            .count()
+    }
+}
@@ -0,0 +1,108 @@
+//! This module implements cold storage of old blocks: before old blocks are pruned from the
+//! (hot) persistent storage with `LedgerMap::spill_to_cold_storage`, their full raw bytes are
+//! stored in a cheaper, secondary storage, such as a directory of files or an object store.
+//! Reading a pruned block with `LedgerMap::get_block_at_offset` then fetches it from the cold
+//! storage and verifies it against the chain hash kept in the pruned block.
+//!
+//! Example usage:
+//!
+//! ```rust,no_run
+//! use ledger_map::cold_storage::DirColdStorage;
+//! use ledger_map::LedgerMap;
+//!
+//! let mut ledger_map = LedgerMap::builder()
+//!     .cold_storage(DirColdStorage::new("/var/lib/ledger/cold".into()))
+//!     .build()
+//!     .unwrap();
+//! // Keep only the last 1000 blocks in the hot storage
+//! let blocks_count = ledger_map.get_blocks_count().saturating_sub(1000);
+//! ledger_map.spill_to_cold_storage(blocks_count).unwrap();
+//! ```
+
+/// Secondary storage of the raw blocks (header and body) of pruned blocks, addressed by the
+/// chain hash of the block.
+pub trait ColdStorage: Send {
+    fn put_block(&mut self, chain_hash: &[u8], raw_block: &[u8]) -> anyhow::Result<()>;
+    /// Returns the raw block with `chain_hash`, or `None` if there is no such block.
+    fn get_block(&self, chain_hash: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+}
+
+impl std::fmt::Debug for dyn ColdStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ColdStorage")
+    }
+}
+
+/// Cold storage in a local directory, with one file per block, named by the hex chain hash.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "wasm32", target_os = "wasi")
+))]
+pub struct DirColdStorage {
+    dir: std::path::PathBuf,
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "wasm32", target_os = "wasi")
+))]
+impl DirColdStorage {
+    pub fn new(dir: std::path::PathBuf) -> Self {
+        DirColdStorage { dir }
+    }
+
+    fn block_path(&self, chain_hash: &[u8]) -> std::path::PathBuf {
+        self.dir.join(format!("{}.block", hex::encode(chain_hash)))
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "wasm32", target_os = "wasi")
+))]
+impl ColdStorage for DirColdStorage {
+    fn put_block(&mut self, chain_hash: &[u8], raw_block: &[u8]) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        // Write to a temporary file first, so that a block file is either complete or missing
+        let path = self.block_path(chain_hash);
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, raw_block)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn get_block(&self, chain_hash: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.block_path(chain_hash)) {
+            Ok(raw_block) => Ok(Some(raw_block)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(all(
+    test,
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    )
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_cold_storage() {
+        let dir = tempfile::tempdir().unwrap().keep().join("cold");
+        let mut storage = DirColdStorage::new(dir);
+        assert_eq!(storage.get_block(&[1, 2, 3]).unwrap(), None);
+        storage.put_block(&[1, 2, 3], b"raw block").unwrap();
+        assert_eq!(
+            storage.get_block(&[1, 2, 3]).unwrap(),
+            Some(b"raw block".to_vec())
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! This module implements the encryption at rest of the block bodies. With
+//! `LedgerMapBuilder::encryption_keys`, new blocks are encrypted with AES-256-GCM under the key
+//! of the current key epoch, and the epoch is stored in front of the ciphertext of each block, so
+//! that blocks encrypted under earlier keys stay readable after `LedgerMap::rotate_encryption_key`.
+//! `LedgerMap::rewrap` re-encrypts the blocks of earlier epochs under the current key, after
+//! which the earlier keys are no longer needed.
+//!
+//! Only the block bodies are encrypted. The block headers, and so the sizes and positions of the
+//! blocks, stay in the clear, and chain hashes are computed over the decrypted blocks, so they do
+//! not change when blocks are re-encrypted.
+//!
+//! Example usage:
+//!
+//! ```rust
+//! use ledger_map::encryption::EncryptionKeys;
+//! use ledger_map::LedgerMap;
+//!
+//! let mut ledger_map = LedgerMap::builder()
+//!     .in_memory()
+//!     .encryption_keys(EncryptionKeys::new(1, [7u8; 32]))
+//!     .build()
+//!     .unwrap();
+//! ledger_map.upsert("Label1", b"key1".to_vec(), b"value1".to_vec()).unwrap();
+//! ledger_map.commit_block().unwrap();
+//!
+//! // New blocks use the key of epoch 2, and the existing blocks are re-encrypted with it
+//! ledger_map.rotate_encryption_key(2, [8u8; 32]).unwrap();
+//! assert_eq!(ledger_map.rewrap().unwrap(), 1);
+//! ```
+
+use crate::LedgerError;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::collections::BTreeMap;
+
+/// Length of the AES-256-GCM encryption keys, in bytes.
+pub const ENCRYPTION_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+/// Key epoch, ciphertext length and nonce in front of the ciphertext of an encrypted block body.
+const ENCRYPTED_BODY_PREFIX_LEN: usize = 4 + 4 + NONCE_LEN;
+
+/// Encryption keys of a ledger, by key epoch. New blocks are encrypted with the key of the
+/// current epoch, blocks of other epochs are decrypted with the key of their epoch.
+#[derive(Clone)]
+pub struct EncryptionKeys {
+    keys: BTreeMap<u32, [u8; ENCRYPTION_KEY_LEN]>,
+    current_epoch: u32,
+}
+
+impl std::fmt::Debug for EncryptionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKeys")
+            .field("epochs", &self.keys.keys().collect::<Vec<_>>())
+            .field("current_epoch", &self.current_epoch)
+            .finish()
+    }
+}
+
+impl EncryptionKeys {
+    /// Keys with only `key`, of the current epoch `epoch`.
+    pub fn new(epoch: u32, key: [u8; ENCRYPTION_KEY_LEN]) -> Self {
+        EncryptionKeys {
+            keys: BTreeMap::from([(epoch, key)]),
+            current_epoch: epoch,
+        }
+    }
+
+    /// Add the key of an earlier `epoch`, to read the blocks that were encrypted with it.
+    pub fn with_key(mut self, epoch: u32, key: [u8; ENCRYPTION_KEY_LEN]) -> Self {
+        self.keys.insert(epoch, key);
+        self
+    }
+
+    /// Add `key` as the key of the new current `epoch`, which must not have a key yet.
+    pub fn rotate(&mut self, epoch: u32, key: [u8; ENCRYPTION_KEY_LEN]) -> Result<(), LedgerError> {
+        if self.keys.contains_key(&epoch) {
+            return Err(LedgerError::Encryption(format!(
+                "Key epoch {} already has a key",
+                epoch
+            )));
+        }
+        self.keys.insert(epoch, key);
+        self.current_epoch = epoch;
+        Ok(())
+    }
+
+    /// Remove the key of the earlier `epoch`, e.g. after `LedgerMap::rewrap`.
+    pub fn remove_key(&mut self, epoch: u32) -> Result<(), LedgerError> {
+        if epoch == self.current_epoch {
+            return Err(LedgerError::Encryption(format!(
+                "Cannot remove the key of the current epoch {}",
+                epoch
+            )));
+        }
+        self.keys.remove(&epoch);
+        Ok(())
+    }
+
+    pub fn current_epoch(&self) -> u32 {
+        self.current_epoch
+    }
+
+    /// Epochs with a key, in ascending order.
+    pub fn epochs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.keys.keys().copied()
+    }
+
+    fn cipher(&self, epoch: u32) -> Result<Aes256Gcm, LedgerError> {
+        let key = self.keys.get(&epoch).ok_or_else(|| {
+            LedgerError::Encryption(format!("No encryption key for key epoch {}", epoch))
+        })?;
+        Aes256Gcm::new_from_slice(key).map_err(|e| LedgerError::Encryption(e.to_string()))
+    }
+
+    /// Encrypt `plaintext` with the key of the current epoch and a random nonce, authenticating
+    /// `aad` with it. The result starts with the key epoch, see `body_epoch`.
+    pub(crate) fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, LedgerError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        crate::platform_specific::fill_random(&mut nonce).map_err(LedgerError::Encryption)?;
+        let ciphertext = self
+            .cipher(self.current_epoch)?
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|e| LedgerError::Encryption(format!("Failed to encrypt block: {}", e)))?;
+        let mut body = Vec::with_capacity(ENCRYPTED_BODY_PREFIX_LEN + ciphertext.len());
+        body.extend_from_slice(&self.current_epoch.to_le_bytes());
+        body.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        body.extend_from_slice(&nonce);
+        body.extend_from_slice(&ciphertext);
+        Ok(body)
+    }
+
+    /// Decrypt the encrypted block `body`, which may be followed by padding, with the key of its
+    /// epoch. Fails if the key is missing, or if the ciphertext or `aad` were tampered with.
+    pub(crate) fn decrypt(&self, aad: &[u8], body: &[u8]) -> Result<Vec<u8>, LedgerError> {
+        let len = encrypted_body_len(body)?;
+        let nonce = &body[8..ENCRYPTED_BODY_PREFIX_LEN];
+        self.cipher(body_epoch(body)?)?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: &body[ENCRYPTED_BODY_PREFIX_LEN..len],
+                    aad,
+                },
+            )
+            .map_err(|_| {
+                LedgerError::Encryption(
+                    "Failed to decrypt block: authentication failed".to_string(),
+                )
+            })
+    }
+}
+
+/// Key epoch of the encrypted block `body`.
+pub(crate) fn body_epoch(body: &[u8]) -> Result<u32, LedgerError> {
+    body.get(0..4)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u32::from_le_bytes)
+        .ok_or_else(|| LedgerError::BlockCorrupted("Encrypted block too short".to_string()))
+}
+
+/// Length of the encrypted block `body` without the padding after it.
+pub(crate) fn encrypted_body_len(body: &[u8]) -> Result<usize, LedgerError> {
+    let ciphertext_len = body
+        .get(4..8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u32::from_le_bytes)
+        .ok_or_else(|| LedgerError::BlockCorrupted("Encrypted block too short".to_string()))?;
+    let len = ENCRYPTED_BODY_PREFIX_LEN + ciphertext_len as usize;
+    if body.len() < len {
+        return Err(LedgerError::BlockCorrupted(
+            "Encrypted block too short".to_string(),
+        ));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let mut keys = EncryptionKeys::new(1, [1u8; 32]);
+        let body = keys.encrypt(b"aad", b"plaintext").unwrap();
+        assert_eq!(body_epoch(&body), Ok(1));
+        assert_eq!(encrypted_body_len(&body), Ok(body.len()));
+        assert_eq!(keys.decrypt(b"aad", &body).unwrap(), b"plaintext");
+
+        // Padding after the body is ignored, but the ciphertext and aad are authenticated
+        let mut padded = body.clone();
+        padded.extend_from_slice(&[0u8; 7]);
+        assert_eq!(keys.decrypt(b"aad", &padded).unwrap(), b"plaintext");
+        assert!(keys.decrypt(b"other", &body).is_err());
+        let mut tampered = body.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(keys.decrypt(b"aad", &tampered).is_err());
+
+        keys.rotate(2, [2u8; 32]).unwrap();
+        assert!(keys.rotate(1, [3u8; 32]).is_err());
+        assert_eq!(
+            body_epoch(&keys.encrypt(b"aad", b"plaintext").unwrap()),
+            Ok(2)
+        );
+        assert_eq!(keys.decrypt(b"aad", &body).unwrap(), b"plaintext");
+        assert!(keys.remove_key(2).is_err());
+        keys.remove_key(1).unwrap();
+        assert!(matches!(
+            keys.decrypt(b"aad", &body),
+            Err(LedgerError::Encryption(_))
+        ));
+    }
+}
@@ -1,21 +1,130 @@
-#[derive(Debug, PartialEq)]
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Numeric code of `LedgerError::Other`, and of errors that do not wrap a `LedgerError`.
+pub const OTHER_ERROR_CODE: u32 = 255;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum LedgerError {
     EntryNotFound,
     BlockEmpty,
     BlockCorrupted(String),
     UnsupportedBlockVersion(u32),
+    /// Reading or writing the persistent storage failed.
+    #[cfg(feature = "std")]
+    Io {
+        kind: std::io::ErrorKind,
+        message: String,
+    },
+    /// The persistent storage is full, or its quota does not allow it to grow any further.
+    StorageFull(String),
+    /// A block or entry could not be serialized or deserialized.
+    Serialization(String),
+    /// The parent hash of the block at `offset` does not match the chain hash of the previous block.
+    HashMismatch {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+        offset: u64,
+    },
+    QuotaExceeded {
+        quota_bytes: u64,
+        required_bytes: u64,
+    },
+    KeyTooLarge {
+        size_bytes: usize,
+        max_size_bytes: usize,
+    },
+    ValueTooLarge {
+        size_bytes: usize,
+        max_size_bytes: usize,
+    },
+    InvalidLabel(String),
+    /// The entries of the block at `offset` were removed by `LedgerMap::prune_blocks`.
+    BlockPruned {
+        offset: u64,
+    },
+    /// The access controller of the ledger denied the operation.
+    Unauthorized(String),
+    /// No block of the ledger has this chain hash, e.g. because it is from a fork of the chain.
+    UnknownChainHash(Vec<u8>),
+    /// The timestamp of the block at `offset` is outside of the tolerated window `min_ns..=max_ns`,
+    /// see `LedgerMap::set_timestamp_skew`.
+    TimestampOutOfRange {
+        offset: u64,
+        timestamp_ns: u64,
+        min_ns: u64,
+        max_ns: u64,
+    },
+    /// A block could not be encrypted or decrypted, e.g. because the key of its key epoch is
+    /// missing, see `encryption::EncryptionKeys`.
+    Encryption(String),
     Other(String),
 }
 
-impl<E: std::error::Error> From<E> for LedgerError {
-    fn from(error: E) -> Self {
-        LedgerError::Other(error.to_string())
+impl LedgerError {
+    /// Stable numeric code of the error kind, for FFI and JavaScript callers.
+    /// Codes are never changed or reused, new variants get new codes.
+    pub fn code(&self) -> u32 {
+        match self {
+            LedgerError::EntryNotFound => 1,
+            LedgerError::BlockEmpty => 2,
+            LedgerError::BlockCorrupted(_) => 3,
+            LedgerError::UnsupportedBlockVersion(_) => 4,
+            #[cfg(feature = "std")]
+            LedgerError::Io { .. } => 5,
+            LedgerError::Serialization(_) => 6,
+            LedgerError::HashMismatch { .. } => 7,
+            LedgerError::QuotaExceeded { .. } => 8,
+            LedgerError::KeyTooLarge { .. } => 9,
+            LedgerError::ValueTooLarge { .. } => 10,
+            LedgerError::InvalidLabel(_) => 11,
+            LedgerError::BlockPruned { .. } => 12,
+            LedgerError::Unauthorized(_) => 13,
+            LedgerError::UnknownChainHash(_) => 14,
+            LedgerError::TimestampOutOfRange { .. } => 15,
+            LedgerError::StorageFull(_) => 16,
+            LedgerError::Encryption(_) => 17,
+            LedgerError::Other(_) => OTHER_ERROR_CODE,
+        }
+    }
+}
+
+/// Returns the code of the first `LedgerError` in the chain of `error`, or `OTHER_ERROR_CODE`.
+#[cfg(feature = "std")]
+pub fn error_code(error: &anyhow::Error) -> u32 {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<LedgerError>())
+        .map(LedgerError::code)
+        .unwrap_or(OTHER_ERROR_CODE)
+}
+
+impl core::error::Error for LedgerError {}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for LedgerError {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::StorageFull => LedgerError::StorageFull(error.to_string()),
+            kind => LedgerError::Io {
+                kind,
+                message: error.to_string(),
+            },
+        }
     }
 }
 
-impl From<LedgerError> for anyhow::Error {
+#[cfg(feature = "std")]
+impl From<LedgerError> for std::io::Error {
     fn from(error: LedgerError) -> Self {
-        anyhow::anyhow!(error)
+        match error {
+            LedgerError::StorageFull(message) => {
+                std::io::Error::new(std::io::ErrorKind::StorageFull, message)
+            }
+            LedgerError::Io { kind, message } => std::io::Error::new(kind, message),
+            error => std::io::Error::other(error),
+        }
     }
 }
 
@@ -25,8 +134,8 @@ impl From<LedgerError> for String {
     }
 }
 
-impl std::fmt::Display for LedgerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             LedgerError::EntryNotFound => write!(f, "Entry not found"),
             LedgerError::BlockEmpty => write!(f, "Block is empty"),
@@ -34,7 +143,101 @@ impl std::fmt::Display for LedgerError {
             LedgerError::UnsupportedBlockVersion(version) => {
                 write!(f, "Unsupported block version: {}", version)
             }
+            #[cfg(feature = "std")]
+            LedgerError::Io { kind, message } => write!(f, "I/O error ({:?}): {}", kind, message),
+            LedgerError::StorageFull(err) => write!(f, "Persistent storage is full: {}", err),
+            LedgerError::Serialization(err) => write!(f, "Serialization error: {}", err),
+            LedgerError::HashMismatch {
+                expected,
+                actual,
+                offset,
+            } => write!(
+                f,
+                "Hash mismatch at offset {}: expected parent hash {}, got {}",
+                offset,
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+            LedgerError::QuotaExceeded {
+                quota_bytes,
+                required_bytes,
+            } => write!(
+                f,
+                "Storage quota exceeded: {} bytes required, quota is {} bytes",
+                required_bytes, quota_bytes
+            ),
+            LedgerError::KeyTooLarge {
+                size_bytes,
+                max_size_bytes,
+            } => write!(
+                f,
+                "Key too large: {} bytes, maximum is {} bytes",
+                size_bytes, max_size_bytes
+            ),
+            LedgerError::ValueTooLarge {
+                size_bytes,
+                max_size_bytes,
+            } => write!(
+                f,
+                "Value too large: {} bytes, maximum is {} bytes",
+                size_bytes, max_size_bytes
+            ),
+            LedgerError::InvalidLabel(err) => write!(f, "Invalid label: {}", err),
+            LedgerError::BlockPruned { offset } => {
+                write!(f, "Block at offset {} was pruned", offset)
+            }
+            LedgerError::Unauthorized(err) => write!(f, "Unauthorized: {}", err),
+            LedgerError::UnknownChainHash(chain_hash) => {
+                write!(f, "Unknown chain hash: {}", hex::encode(chain_hash))
+            }
+            LedgerError::TimestampOutOfRange {
+                offset,
+                timestamp_ns,
+                min_ns,
+                max_ns,
+            } => write!(
+                f,
+                "Timestamp {} of the block at offset {} is outside of the tolerated range {}..={}",
+                timestamp_ns, offset, min_ns, max_ns
+            ),
+            LedgerError::Encryption(err) => write!(f, "Encryption error: {}", err),
             LedgerError::Other(err) => write!(f, "Other error: {}", err),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!(LedgerError::EntryNotFound.code(), 1);
+        assert_eq!(
+            LedgerError::Other("error".to_string()).code(),
+            OTHER_ERROR_CODE
+        );
+
+        let error = anyhow::Error::from(LedgerError::InvalidLabel("label is empty".to_string()));
+        assert_eq!(error_code(&error), 11);
+        assert_eq!(error_code(&error.context("Failed to upsert")), 11);
+        assert_eq!(error_code(&anyhow::format_err!("error")), OTHER_ERROR_CODE);
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let error = LedgerError::from(std::io::Error::new(
+            std::io::ErrorKind::StorageFull,
+            "no space left",
+        ));
+        assert_eq!(error, LedgerError::StorageFull("no space left".to_string()));
+        assert_eq!(error.code(), 16);
+        assert_eq!(
+            LedgerError::from(std::io::Error::other("failed")),
+            LedgerError::Io {
+                kind: std::io::ErrorKind::Other,
+                message: "failed".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,452 @@
+//! This module implements `FaultyStorage`, a persistent storage backend for tests, which wraps
+//! another backend and injects storage faults at configurable offsets: short reads, torn writes,
+//! failing grows and exceeded storage quotas. Faults are deterministic, so that the crash-recovery
+//! and error handling paths of the ledger, and of the applications on top of it, can be tested.
+//!
+//! A torn write persists the bytes before the fault offset and then fails, like a process that
+//! crashed in the middle of the write. After a torn write the storage rejects all writes until
+//! `FaultHandle::clear`, so that nothing else reaches the storage before the "restart".
+//! Writes beyond a quota fail with `LedgerError::StorageFull`.
+//!
+//! Example usage:
+//!
+//! ```rust,no_run
+//! use ledger_map::faulty_storage::{Fault, FaultyStorage};
+//! use ledger_map::platform_specific::{self, BackingFile};
+//! use ledger_map::LedgerMap;
+//!
+//! let path = std::path::PathBuf::from("/tmp/ledger_map/test.bin");
+//! let storage = FaultyStorage::new(Box::new(BackingFile::new(Some(path.clone())).unwrap()));
+//! let faults = storage.handle();
+//! platform_specific::set_storage_backend(Box::new(storage));
+//! let mut ledger_map = LedgerMap::new(None).unwrap();
+//! ledger_map.upsert("Label1", b"key", b"value").unwrap();
+//! // Crash in the middle of the block
+//! faults.inject(Fault::TornWrite { offset: ledger_map.get_next_block_start_pos() + 20 });
+//! assert!(ledger_map.commit_block().is_err());
+//! // Restart and recover
+//! let ledger_map = LedgerMap::new_with_path(None, Some(path)).unwrap();
+//! ```
+use crate::platform_specific::{StorageBackend, PERSISTENT_STORAGE_PAGE_SIZE};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Storage fault, injected with `FaultHandle::inject`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Reads that cover `offset` return only the bytes before it, and fail.
+    ShortRead { offset: u64 },
+    /// The next write that covers `offset` persists only the bytes before it, and fails. The
+    /// storage then rejects all writes until `FaultHandle::clear`.
+    TornWrite { offset: u64 },
+    /// Growing the storage beyond `max_bytes` fails.
+    GrowFailure { max_bytes: u64 },
+    /// Writes and grows beyond `max_bytes` fail with a quota error.
+    Quota { max_bytes: u64 },
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    faults: Vec<Fault>,
+    crashed: bool,
+}
+
+/// Handle to the faults of a `FaultyStorage`, to change them after the storage is installed.
+#[derive(Clone, Debug, Default)]
+pub struct FaultHandle(Arc<Mutex<FaultState>>);
+
+impl FaultHandle {
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn inject(&self, fault: Fault) {
+        self.state().faults.push(fault);
+    }
+
+    /// Remove all faults, and accept writes again after a torn write.
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.faults.clear();
+        state.crashed = false;
+    }
+
+    pub fn faults(&self) -> Vec<Fault> {
+        self.state().faults.clone()
+    }
+
+    /// Returns whether a torn write happened since the last `clear`.
+    pub fn has_crashed(&self) -> bool {
+        self.state().crashed
+    }
+}
+
+pub struct FaultyStorage {
+    inner: Box<dyn StorageBackend>,
+    faults: FaultHandle,
+}
+
+impl FaultyStorage {
+    pub fn new(inner: Box<dyn StorageBackend>) -> Self {
+        FaultyStorage {
+            inner,
+            faults: FaultHandle::default(),
+        }
+    }
+
+    pub fn handle(&self) -> FaultHandle {
+        self.faults.clone()
+    }
+
+    pub fn into_inner(self) -> Box<dyn StorageBackend> {
+        self.inner
+    }
+}
+
+/// Returns whether `offset` is within `len` bytes from `start`.
+fn covers(start: u64, len: usize, offset: u64) -> bool {
+    start <= offset && offset < start + len as u64
+}
+
+impl StorageBackend for FaultyStorage {
+    fn path(&self) -> Option<PathBuf> {
+        self.inner.path()
+    }
+
+    fn size_bytes(&self) -> Result<u64, String> {
+        self.inner.size_bytes()
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        let short_read_at = self
+            .faults
+            .state()
+            .faults
+            .iter()
+            .find_map(|fault| match fault {
+                Fault::ShortRead { offset: at } if covers(offset, buf.len(), *at) => Some(*at),
+                _ => None,
+            });
+        match short_read_at {
+            Some(at) => {
+                let len = (at - offset) as usize;
+                self.inner.read(offset, &mut buf[..len])?;
+                Err(format!(
+                    "Short read from persistent storage @ 0x{:0x}: {} of {} bytes",
+                    offset,
+                    len,
+                    buf.len()
+                ))
+            }
+            None => self.inner.read(offset, buf),
+        }
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        let mut state = self.faults.state();
+        if state.crashed {
+            return Err(std::io::Error::other(
+                "Persistent storage is unavailable after a torn write",
+            ));
+        }
+        let end = offset + buf.len() as u64;
+        for fault in &state.faults {
+            if let Fault::Quota { max_bytes } = fault {
+                if end > *max_bytes {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::StorageFull,
+                        format!(
+                            "Storage quota of {} bytes exceeded by a write up to {} bytes",
+                            max_bytes, end
+                        ),
+                    ));
+                }
+            }
+        }
+        let torn_write = state.faults.iter().position(|fault| match fault {
+            Fault::TornWrite { offset: at } => covers(offset, buf.len(), *at),
+            _ => false,
+        });
+        match torn_write {
+            Some(index) => {
+                let Fault::TornWrite { offset: at } = state.faults.remove(index) else {
+                    unreachable!()
+                };
+                state.crashed = true;
+                let len = (at - offset) as usize;
+                self.inner.write(offset, &buf[..len])?;
+                Err(std::io::Error::other(format!(
+                    "Torn write to persistent storage @ 0x{:0x}: {} of {} bytes written",
+                    offset,
+                    len,
+                    buf.len()
+                )))
+            }
+            None => self.inner.write(offset, buf),
+        }
+    }
+
+    fn grow(&mut self, additional_pages: u64) -> Result<u64, String> {
+        let new_size_bytes =
+            self.inner.size_bytes()? + additional_pages * PERSISTENT_STORAGE_PAGE_SIZE;
+        for fault in &self.faults.state().faults {
+            match fault {
+                Fault::GrowFailure { max_bytes } if new_size_bytes > *max_bytes => {
+                    return Err(format!(
+                        "Failed to grow persistent storage to {} bytes",
+                        new_size_bytes
+                    ));
+                }
+                Fault::Quota { max_bytes } if new_size_bytes > *max_bytes => {
+                    return Err(format!(
+                        "Storage quota of {} bytes exceeded by growing to {} bytes",
+                        max_bytes, new_size_bytes
+                    ));
+                }
+                _ => {}
+            }
+        }
+        self.inner.grow(additional_pages)
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform_specific::{persistent_storage_grow, set_storage_backend, BackingFile};
+    use crate::{LedgerError, LedgerMap};
+
+    fn new_faulty_ledger() -> (LedgerMap, FaultHandle, PathBuf) {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let storage =
+            FaultyStorage::new(Box::new(BackingFile::new(Some(file_path.clone())).unwrap()));
+        let faults = storage.handle();
+        set_storage_backend(Box::new(storage));
+        (LedgerMap::new(None).unwrap(), faults, file_path)
+    }
+
+    #[test]
+    fn test_torn_write_recovery() {
+        let (mut ledger_map, faults, file_path) = new_faulty_ledger();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        let blocks_count = ledger_map.get_blocks_count();
+
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        faults.inject(Fault::TornWrite {
+            offset: ledger_map.get_next_block_start_pos() + 20,
+        });
+        let err = ledger_map.commit_block().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LedgerError>(),
+            Some(LedgerError::Io { .. })
+        ));
+        assert!(faults.has_crashed());
+        drop(ledger_map);
+
+        // Restart from the same file: the torn block is not part of the ledger
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), blocks_count);
+        assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), b"value1");
+        assert_eq!(
+            ledger_map.get("Label1", b"key2"),
+            Err(LedgerError::EntryNotFound)
+        );
+
+        // And the ledger keeps working after the recovery
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), blocks_count + 1);
+        assert_eq!(ledger_map.get("Label1", b"key2").unwrap(), b"value2");
+    }
+
+    /// Ledger with entry hashes, whose first `superseded` blocks hold only superseded values of
+    /// about 300 KB, followed by `live` blocks with current values of that size.
+    fn new_faulty_prunable_ledger(superseded: u8, live: u8) -> (LedgerMap, FaultHandle, PathBuf) {
+        let (_, faults, file_path) = new_faulty_ledger();
+        let mut ledger_map = LedgerMap::builder().entry_hashes().build().unwrap();
+        for i in 0..superseded + live {
+            let mut x = 0x2545f4914f6cdd1du64 + i as u64;
+            let value = (0..300_000)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    x as u8
+                })
+                .collect::<Vec<_>>();
+            let key = if i < superseded {
+                b"old".to_vec()
+            } else {
+                vec![i]
+            };
+            ledger_map.upsert("Label1", key, value).unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        ledger_map.upsert("Label1", b"old", b"new").unwrap();
+        ledger_map.commit_block().unwrap();
+        (ledger_map, faults, file_path)
+    }
+
+    #[test]
+    fn test_interrupted_pruning_recovery() {
+        let (mut ledger_map, faults, file_path) = new_faulty_prunable_ledger(4, 5);
+        let tip_hash = ledger_map.get_latest_block_hash();
+        let entries = ledger_map.iter(None).cloned().collect::<Vec<_>>();
+
+        // Crash while the first compacted blocks overwrite the original ones
+        faults.inject(Fault::TornWrite {
+            offset: ledger_map.get_data_start_pos() + 20,
+        });
+        assert!(ledger_map.prune_blocks(4).is_err());
+        assert!(faults.has_crashed());
+        drop(ledger_map);
+
+        // The interrupted step is completed on restart, and pruning again reclaims the rest
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path.clone())).unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 10);
+        assert_eq!(ledger_map.verify_chain().unwrap(), tip_hash);
+        assert!(ledger_map.iter_raw().next().unwrap().unwrap().1.is_pruned());
+        let data_end = ledger_map.get_next_block_start_pos();
+        assert!(ledger_map.prune_blocks(4).unwrap() > 1_000_000);
+        assert_eq!(ledger_map.verify_chain().unwrap(), tip_hash);
+        assert!(ledger_map.get_next_block_start_pos() < data_end - 1_000_000);
+        assert_eq!(
+            ledger_map
+                .iter_raw()
+                .filter(|block| block.as_ref().unwrap().1.is_pruned())
+                .count(),
+            4
+        );
+        drop(ledger_map);
+        let ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        assert_eq!(ledger_map.get_latest_block_hash(), tip_hash);
+        for entry in entries {
+            assert_eq!(
+                ledger_map.get(entry.label(), entry.key()).unwrap(),
+                entry.value()
+            );
+        }
+    }
+
+    #[test]
+    fn test_interrupted_pruning_staging() {
+        let (mut ledger_map, faults, file_path) = new_faulty_prunable_ledger(4, 5);
+        let tip_hash = ledger_map.get_latest_block_hash();
+        let data_end = ledger_map.get_next_block_start_pos();
+
+        // Crash while the compacted blocks are staged after the end of the ledger
+        faults.inject(Fault::TornWrite {
+            offset: data_end + 100,
+        });
+        assert!(ledger_map.prune_blocks(4).is_err());
+        assert!(faults.has_crashed());
+        drop(ledger_map);
+
+        // Nothing was committed, so the ledger is unchanged
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        assert_eq!(ledger_map.verify_chain().unwrap(), tip_hash);
+        assert_eq!(ledger_map.get_next_block_start_pos(), data_end);
+        assert!(!ledger_map
+            .iter_raw()
+            .any(|block| block.unwrap().1.is_pruned()));
+        assert!(ledger_map.prune_blocks(4).unwrap() > 1_000_000);
+        assert_eq!(ledger_map.verify_chain().unwrap(), tip_hash);
+    }
+
+    #[test]
+    fn test_short_read() {
+        let (mut ledger_map, faults, _) = new_faulty_ledger();
+        let block_start_pos = ledger_map.get_next_block_start_pos();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        faults.inject(Fault::ShortRead {
+            offset: block_start_pos + 20,
+        });
+        assert!(matches!(
+            ledger_map.read_raw_blocks(block_start_pos, u64::MAX),
+            Err(LedgerError::BlockCorrupted(_))
+        ));
+
+        faults.clear();
+        assert!(ledger_map
+            .read_raw_blocks(block_start_pos, u64::MAX)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_lazy_index_read_failure() {
+        let (mut ledger_map, faults, _) = new_faulty_ledger();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        let block_start_pos = ledger_map.get_next_block_start_pos();
+        ledger_map.upsert("Label2", b"key1", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        drop(ledger_map);
+        let mut ledger_map = LedgerMap::builder().lazy_index(true).build().unwrap();
+
+        // A label index that fails to load fails the operations that need it, instead of
+        // silently leaving out the entries of the label
+        faults.inject(Fault::ShortRead {
+            offset: block_start_pos + 20,
+        });
+        assert!(ledger_map.storage_stats().is_err());
+        assert!(ledger_map
+            .register_index("Label2", "by_value", |value| vec![value.to_vec()])
+            .is_err());
+        assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), b"value1");
+
+        faults.clear();
+        assert_eq!(
+            ledger_map.storage_stats().unwrap().label_live_bytes.len(),
+            2
+        );
+        ledger_map
+            .register_index("Label2", "by_value", |value| vec![value.to_vec()])
+            .unwrap();
+        assert_eq!(
+            ledger_map
+                .get_by_index("Label2", "by_value", b"value2")
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_quota_and_grow_failures() {
+        let (mut ledger_map, faults, _) = new_faulty_ledger();
+        let size_bytes = crate::platform_specific::persistent_storage_size_bytes();
+
+        faults.inject(Fault::GrowFailure {
+            max_bytes: size_bytes,
+        });
+        assert!(persistent_storage_grow(1).is_err());
+        faults.clear();
+        assert_eq!(persistent_storage_grow(1), Ok(size_bytes));
+
+        let quota_bytes = ledger_map.get_next_block_start_pos() + 16;
+        faults.inject(Fault::Quota {
+            max_bytes: quota_bytes,
+        });
+        assert!(
+            persistent_storage_grow(quota_bytes.div_ceil(PERSISTENT_STORAGE_PAGE_SIZE)).is_err()
+        );
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        let err = ledger_map.commit_block().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LedgerError>(),
+            Some(LedgerError::StorageFull(_))
+        ));
+        assert!(!faults.has_crashed());
+    }
+}
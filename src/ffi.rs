@@ -0,0 +1,431 @@
+/// This module implements the C API of LedgerMap, for calling the ledger from C and C++.
+///
+/// Functions that can fail return `LEDGER_MAP_OK` (0) on success, or the stable code of the
+/// `LedgerError` on failure (see `LedgerError::code`). The message of the last failure on the
+/// calling thread is available from `ledger_map_last_error`.
+///
+/// The persistent storage is bound to the thread that opened the ledger, so each ledger handle
+/// must only be used from the thread that created it.
+///
+/// The C header `include/ledger_map.h` is generated with cbindgen:
+/// `cbindgen --config cbindgen.toml --output include/ledger_map.h`
+use crate::errors::{error_code, OTHER_ERROR_CODE};
+use crate::LedgerMap;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+
+/// Returned by the fallible functions on success.
+pub const LEDGER_MAP_OK: u32 = 0;
+
+/// Called by `ledger_map_iter` with each entry. Returning `false` stops the iteration.
+/// The pointers are only valid for the duration of the call.
+pub type LedgerMapEntryCallback = extern "C" fn(
+    user_data: *mut c_void,
+    label: *const c_char,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> bool;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Interior NUL bytes cannot be represented in a C string
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| last_error.replace(Some(message)));
+}
+
+/// Run `f`, and convert its outcome (including a panic) to an error code for C callers.
+fn ffi_call(f: impl FnOnce() -> anyhow::Result<()>) -> u32 {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => LEDGER_MAP_OK,
+        Ok(Err(err)) => {
+            set_last_error(format!("{:#}", err));
+            error_code(&err)
+        }
+        Err(_) => {
+            set_last_error("Panic in LedgerMap".to_string());
+            OTHER_ERROR_CODE
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> anyhow::Result<&'a str> {
+    if ptr.is_null() {
+        anyhow::bail!("Argument {} is NULL", name);
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|e| anyhow::format_err!("Argument {} is not valid UTF-8: {}", name, e))
+}
+
+unsafe fn bytes_arg<'a>(ptr: *const u8, len: usize, name: &str) -> anyhow::Result<&'a [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if ptr.is_null() {
+        anyhow::bail!("Argument {} is NULL", name);
+    }
+    Ok(std::slice::from_raw_parts(ptr, len))
+}
+
+unsafe fn ledger_arg<'a>(ledger_map: *mut LedgerMap) -> anyhow::Result<&'a mut LedgerMap> {
+    ledger_map
+        .as_mut()
+        .ok_or_else(|| anyhow::format_err!("Argument ledger_map is NULL"))
+}
+
+/// Open the ledger stored in the file at `path`, or in the default file if `path` is NULL.
+/// If `labels_len` is not 0, only the `labels_len` labels in `labels` are indexed.
+/// Returns NULL on failure. The ledger must be closed with `ledger_map_close`.
+/// Each ledger keeps its own file open, so ledgers in different files can be open at the same
+/// time, also on the same thread. A file must not be open in more than one ledger at a time.
+///
+/// # Safety
+/// `path` must be NULL or a valid C string, and `labels` must point to `labels_len` valid
+/// C strings.
+#[no_mangle]
+pub unsafe extern "C" fn ledger_map_open(
+    path: *const c_char,
+    labels: *const *const c_char,
+    labels_len: usize,
+) -> *mut LedgerMap {
+    let mut ledger_map = None;
+    ffi_call(|| {
+        let path = if path.is_null() {
+            None
+        } else {
+            Some(PathBuf::from(str_arg(path, "path")?))
+        };
+        let labels_to_index = if labels_len == 0 {
+            None
+        } else if labels.is_null() {
+            anyhow::bail!("Argument labels is NULL");
+        } else {
+            let labels = std::slice::from_raw_parts(labels, labels_len)
+                .iter()
+                .map(|label| str_arg(*label, "labels").map(str::to_string))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Some(labels)
+        };
+        ledger_map = Some(LedgerMap::new_with_path(labels_to_index, path)?);
+        Ok(())
+    });
+    ledger_map.map_or(std::ptr::null_mut(), |ledger_map| {
+        Box::into_raw(Box::new(ledger_map))
+    })
+}
+
+/// Close a ledger opened with `ledger_map_open`. Uncommitted entries are discarded.
+///
+/// # Safety
+/// `ledger_map` must be NULL or a ledger returned by `ledger_map_open`, not closed before.
+#[no_mangle]
+pub unsafe extern "C" fn ledger_map_close(ledger_map: *mut LedgerMap) {
+    if !ledger_map.is_null() {
+        drop(Box::from_raw(ledger_map));
+    }
+}
+
+/// Insert or update the entry with `key` under `label`, in the next block.
+///
+/// # Safety
+/// `ledger_map` must be an open ledger, `label` a valid C string, and `key` and `value` must
+/// point to `key_len` and `value_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ledger_map_upsert(
+    ledger_map: *mut LedgerMap,
+    label: *const c_char,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> u32 {
+    ffi_call(|| {
+        ledger_arg(ledger_map)?.upsert(
+            str_arg(label, "label")?,
+            bytes_arg(key, key_len, "key")?,
+            bytes_arg(value, value_len, "value")?,
+        )?;
+        Ok(())
+    })
+}
+
+/// Look up the value of the entry with `key` under `label`. On success, `*value` and `*value_len`
+/// are set to a copy of the value, which must be released with `ledger_map_bytes_free`.
+///
+/// # Safety
+/// `ledger_map` must be an open ledger, `label` a valid C string, `key` must point to `key_len`
+/// bytes, and `value` and `value_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ledger_map_get(
+    ledger_map: *mut LedgerMap,
+    label: *const c_char,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> u32 {
+    ffi_call(|| {
+        if value.is_null() || value_len.is_null() {
+            anyhow::bail!("Argument value or value_len is NULL");
+        }
+        let found = ledger_arg(ledger_map)?
+            .get(str_arg(label, "label")?, bytes_arg(key, key_len, "key")?)?
+            .into_boxed_slice();
+        *value_len = found.len();
+        *value = Box::into_raw(found) as *mut u8;
+        Ok(())
+    })
+}
+
+/// Release a value returned by `ledger_map_get`.
+///
+/// # Safety
+/// `bytes` and `len` must have been returned by `ledger_map_get`, and not released before.
+#[no_mangle]
+pub unsafe extern "C" fn ledger_map_bytes_free(bytes: *mut u8, len: usize) {
+    if !bytes.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            bytes, len,
+        )));
+    }
+}
+
+/// Delete the entry with `key` under `label`, in the next block.
+///
+/// # Safety
+/// `ledger_map` must be an open ledger, `label` a valid C string, and `key` must point to
+/// `key_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ledger_map_delete(
+    ledger_map: *mut LedgerMap,
+    label: *const c_char,
+    key: *const u8,
+    key_len: usize,
+) -> u32 {
+    ffi_call(|| {
+        ledger_arg(ledger_map)?
+            .delete(str_arg(label, "label")?, bytes_arg(key, key_len, "key")?)?;
+        Ok(())
+    })
+}
+
+/// Commit the next block to the persistent storage.
+///
+/// # Safety
+/// `ledger_map` must be an open ledger.
+#[no_mangle]
+pub unsafe extern "C" fn ledger_map_commit_block(ledger_map: *mut LedgerMap) -> u32 {
+    ffi_call(|| ledger_arg(ledger_map)?.commit_block())
+}
+
+/// Call `callback` with each committed entry, optionally only of `label` if it is not NULL.
+///
+/// # Safety
+/// `ledger_map` must be an open ledger, `label` NULL or a valid C string, and `callback` must not
+/// modify the ledger.
+#[no_mangle]
+pub unsafe extern "C" fn ledger_map_iter(
+    ledger_map: *mut LedgerMap,
+    label: *const c_char,
+    callback: LedgerMapEntryCallback,
+    user_data: *mut c_void,
+) -> u32 {
+    ffi_call(|| {
+        let label = if label.is_null() {
+            None
+        } else {
+            Some(str_arg(label, "label")?)
+        };
+        for entry in ledger_arg(ledger_map)?.iter(label) {
+            let entry_label = CString::new(entry.label())?;
+            if !callback(
+                user_data,
+                entry_label.as_ptr(),
+                entry.key().as_ptr(),
+                entry.key().len(),
+                entry.value().as_ptr(),
+                entry.value().len(),
+            ) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Message of the last failure on the calling thread, or NULL if nothing failed yet.
+/// The message is valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn ledger_map_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LedgerError;
+
+    extern "C" fn count_entries(
+        user_data: *mut c_void,
+        label: *const c_char,
+        _key: *const u8,
+        _key_len: usize,
+        _value: *const u8,
+        value_len: usize,
+    ) -> bool {
+        let label = unsafe { CStr::from_ptr(label) };
+        assert_eq!(label.to_str().unwrap(), "label");
+        assert_eq!(value_len, 5);
+        let count = unsafe { &mut *(user_data as *mut usize) };
+        *count += 1;
+        true
+    }
+
+    #[test]
+    fn test_ffi() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let path = CString::new(file_path.to_str().unwrap()).unwrap();
+        let label = CString::new("label").unwrap();
+
+        unsafe {
+            let ledger_map = ledger_map_open(path.as_ptr(), std::ptr::null(), 0);
+            assert!(!ledger_map.is_null());
+            for key in [b"key1", b"key2"] {
+                let rc = ledger_map_upsert(
+                    ledger_map,
+                    label.as_ptr(),
+                    key.as_ptr(),
+                    4,
+                    b"value".as_ptr(),
+                    5,
+                );
+                assert_eq!(rc, LEDGER_MAP_OK);
+            }
+            assert_eq!(ledger_map_commit_block(ledger_map), LEDGER_MAP_OK);
+
+            let mut value = std::ptr::null_mut();
+            let mut value_len = 0;
+            let rc = ledger_map_get(
+                ledger_map,
+                label.as_ptr(),
+                b"key1".as_ptr(),
+                4,
+                &mut value,
+                &mut value_len,
+            );
+            assert_eq!(rc, LEDGER_MAP_OK);
+            assert_eq!(std::slice::from_raw_parts(value, value_len), b"value");
+            ledger_map_bytes_free(value, value_len);
+
+            let rc = ledger_map_get(
+                ledger_map,
+                label.as_ptr(),
+                b"none".as_ptr(),
+                4,
+                &mut value,
+                &mut value_len,
+            );
+            assert_eq!(rc, LedgerError::EntryNotFound.code());
+            assert!(!ledger_map_last_error().is_null());
+
+            let rc = ledger_map_upsert(
+                ledger_map,
+                std::ptr::null(),
+                b"key".as_ptr(),
+                3,
+                b"value".as_ptr(),
+                5,
+            );
+            assert_eq!(rc, OTHER_ERROR_CODE);
+            let message = CStr::from_ptr(ledger_map_last_error()).to_str().unwrap();
+            assert!(message.contains("label"));
+
+            let mut count = 0usize;
+            let rc = ledger_map_iter(
+                ledger_map,
+                std::ptr::null(),
+                count_entries,
+                &mut count as *mut usize as *mut c_void,
+            );
+            assert_eq!(rc, LEDGER_MAP_OK);
+            assert_eq!(count, 2);
+
+            assert_eq!(
+                ledger_map_delete(ledger_map, label.as_ptr(), b"key1".as_ptr(), 4),
+                LEDGER_MAP_OK
+            );
+            assert_eq!(ledger_map_commit_block(ledger_map), LEDGER_MAP_OK);
+            ledger_map_close(ledger_map);
+
+            // Reopening reads the committed blocks
+            let ledger_map = ledger_map_open(path.as_ptr(), std::ptr::null(), 0);
+            let mut count = 0usize;
+            ledger_map_iter(
+                ledger_map,
+                label.as_ptr(),
+                count_entries,
+                &mut count as *mut usize as *mut c_void,
+            );
+            assert_eq!(count, 1);
+            ledger_map_close(ledger_map);
+        }
+    }
+
+    #[test]
+    fn test_ffi_two_ledgers_on_one_thread() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let first_path = CString::new(dir.join("first.bin").to_str().unwrap()).unwrap();
+        let second_path = CString::new(dir.join("second.bin").to_str().unwrap()).unwrap();
+        let label = CString::new("label").unwrap();
+
+        unsafe {
+            let first = ledger_map_open(first_path.as_ptr(), std::ptr::null(), 0);
+            let second = ledger_map_open(second_path.as_ptr(), std::ptr::null(), 0);
+            assert!(!first.is_null() && !second.is_null());
+            for (ledger_map, key) in [(first, b"key1"), (second, b"key2"), (first, b"key3")] {
+                let rc = ledger_map_upsert(
+                    ledger_map,
+                    label.as_ptr(),
+                    key.as_ptr(),
+                    4,
+                    b"value".as_ptr(),
+                    5,
+                );
+                assert_eq!(rc, LEDGER_MAP_OK);
+                assert_eq!(ledger_map_commit_block(ledger_map), LEDGER_MAP_OK);
+            }
+            ledger_map_close(first);
+            ledger_map_close(second);
+
+            // Each file holds only the blocks of its own ledger
+            for (path, expected_count) in [(&first_path, 2), (&second_path, 1)] {
+                let ledger_map = ledger_map_open(path.as_ptr(), std::ptr::null(), 0);
+                let mut count = 0usize;
+                let rc = ledger_map_iter(
+                    ledger_map,
+                    label.as_ptr(),
+                    count_entries,
+                    &mut count as *mut usize as *mut c_void,
+                );
+                assert_eq!(rc, LEDGER_MAP_OK);
+                assert_eq!(count, expected_count);
+                ledger_map_close(ledger_map);
+            }
+        }
+    }
+}
@@ -0,0 +1,449 @@
+/// This module implements a remote LedgerMap service over gRPC, and a client for it.
+///
+/// A central server owns the ledger, and services write to it through `AppendEntries` and
+/// `CommitBlock`. Requests are applied one at a time in the order the server receives them,
+/// and writers can pass the tip hash they expect to detect blocks committed by other writers.
+/// The service definition is in `proto/ledger_map.proto`.
+///
+/// Example usage:
+///
+/// ```rust,no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use ledger_map::grpc::{serve, LedgerMapClient};
+///
+/// tokio::spawn(serve("127.0.0.1:50051".parse()?, None));
+///
+/// let mut client = LedgerMapClient::connect("http://127.0.0.1:50051").await?;
+/// client.upsert("Label1", b"key1", b"value1").await?;
+/// client.commit_block().await?;
+/// assert_eq!(client.get("Label1", b"key1").await?, Some(b"value1".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+use crate::errors::{error_code, LedgerError};
+use crate::info;
+use crate::ledger_thread::LedgerThread;
+use crate::{LedgerBlock, LedgerEntry, LedgerMap, Operation};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+
+/// Types and service stubs generated from `proto/ledger_map.proto`.
+pub mod pb {
+    tonic::include_proto!("ledger_map.v1");
+}
+
+use pb::ledger_map_service_client::LedgerMapServiceClient;
+use pb::ledger_map_service_server::{LedgerMapService, LedgerMapServiceServer};
+
+/// Name of the response metadata key with the `LedgerError` code of a failed request.
+pub const ERROR_CODE_METADATA_KEY: &str = "ledger-error-code";
+
+/// Number of blocks read from the ledger at a time by `StreamBlocks`.
+const STREAM_BLOCKS_BATCH_SIZE: usize = 64;
+
+fn to_status(error: anyhow::Error) -> Status {
+    let code = match error.downcast_ref::<LedgerError>() {
+        Some(LedgerError::EntryNotFound) | Some(LedgerError::BlockPruned { .. }) => {
+            tonic::Code::NotFound
+        }
+        Some(LedgerError::HashMismatch { .. }) | Some(LedgerError::UnknownChainHash(_)) => {
+            tonic::Code::FailedPrecondition
+        }
+        Some(LedgerError::QuotaExceeded { .. }) | Some(LedgerError::StorageFull(_)) => {
+            tonic::Code::ResourceExhausted
+        }
+        Some(LedgerError::TimestampOutOfRange { .. }) => tonic::Code::OutOfRange,
+        Some(LedgerError::Unauthorized(_)) => tonic::Code::PermissionDenied,
+        Some(LedgerError::KeyTooLarge { .. })
+        | Some(LedgerError::ValueTooLarge { .. })
+        | Some(LedgerError::InvalidLabel(_)) => tonic::Code::InvalidArgument,
+        _ => tonic::Code::Internal,
+    };
+    let mut status = Status::new(code, error.to_string());
+    status.metadata_mut().insert(
+        ERROR_CODE_METADATA_KEY,
+        MetadataValue::from(error_code(&error)),
+    );
+    status
+}
+
+impl From<&LedgerEntry> for pb::Entry {
+    fn from(entry: &LedgerEntry) -> Self {
+        let operation = match entry.operation() {
+            Operation::Upsert => pb::Operation::Upsert,
+            Operation::Delete => pb::Operation::Delete,
+        };
+        pb::Entry {
+            label: entry.label().to_string(),
+            key: entry.key().to_vec(),
+            value: entry.value().to_vec(),
+            operation: operation.into(),
+        }
+    }
+}
+
+impl From<&LedgerBlock> for pb::Block {
+    fn from(block: &LedgerBlock) -> Self {
+        pb::Block {
+            offset: block.get_offset(),
+            version: block.version(),
+            timestamp: block.timestamp(),
+            parent_hash: block.parent_hash().to_vec(),
+            entries: block.entries().iter().map(pb::Entry::from).collect(),
+        }
+    }
+}
+
+/// Validate all entries before appending any, so that a request is applied entirely or not at all.
+fn validate_entries(ledger_map: &LedgerMap, entries: &[pb::Entry]) -> Result<(), LedgerError> {
+    for entry in entries {
+        ledger_map.validate_label(&entry.label)?;
+        if entry.key.len() > ledger_map.get_max_key_size() {
+            return Err(LedgerError::KeyTooLarge {
+                size_bytes: entry.key.len(),
+                max_size_bytes: ledger_map.get_max_key_size(),
+            });
+        }
+        if entry.value.len() > ledger_map.get_max_value_size() {
+            return Err(LedgerError::ValueTooLarge {
+                size_bytes: entry.value.len(),
+                max_size_bytes: ledger_map.get_max_value_size(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// gRPC service implementation, wrapping a ledger owned by a dedicated thread.
+pub struct LedgerMapGrpcService {
+    ledger: LedgerThread,
+}
+
+impl LedgerMapGrpcService {
+    /// Open the ledger stored at `path`, or in the default file if `None`.
+    pub fn new(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        Ok(LedgerMapGrpcService {
+            ledger: LedgerThread::spawn(path)?,
+        })
+    }
+
+    pub fn into_server(self) -> LedgerMapServiceServer<Self> {
+        LedgerMapServiceServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl LedgerMapService for LedgerMapGrpcService {
+    async fn append_entries(
+        &self,
+        request: Request<pb::AppendEntriesRequest>,
+    ) -> Result<Response<pb::AppendEntriesResponse>, Status> {
+        let request = request.into_inner();
+        if let Some(entry) = request
+            .entries
+            .iter()
+            .find(|entry| pb::Operation::try_from(entry.operation).is_err())
+        {
+            return Err(Status::invalid_argument(format!(
+                "Unknown operation {}",
+                entry.operation
+            )));
+        }
+        let next_block_entries = self
+            .ledger
+            .run(move |ledger_map| {
+                if let Some(expected_tip_hash) = request.expected_tip_hash {
+                    let tip_hash = ledger_map.get_latest_block_hash();
+                    if tip_hash != expected_tip_hash {
+                        return Err(LedgerError::HashMismatch {
+                            expected: expected_tip_hash,
+                            actual: tip_hash,
+                            offset: ledger_map.get_next_block_start_pos(),
+                        }
+                        .into());
+                    }
+                }
+                validate_entries(ledger_map, &request.entries)?;
+                for entry in request.entries {
+                    match entry.operation() {
+                        pb::Operation::Upsert => {
+                            ledger_map.upsert(entry.label, entry.key, entry.value)?
+                        }
+                        pb::Operation::Delete => ledger_map.delete(entry.label, entry.key)?,
+                    }
+                }
+                Ok(ledger_map.get_next_block_entries_count(None) as u64)
+            })
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(pb::AppendEntriesResponse {
+            next_block_entries,
+        }))
+    }
+
+    async fn commit_block(
+        &self,
+        _request: Request<pb::CommitBlockRequest>,
+    ) -> Result<Response<pb::CommitBlockResponse>, Status> {
+        let response = self
+            .ledger
+            .run(|ledger_map| {
+                ledger_map.commit_block()?;
+                Ok(pb::CommitBlockResponse {
+                    blocks_count: ledger_map.get_blocks_count() as u64,
+                    tip_hash: ledger_map.get_latest_block_hash(),
+                    tip_block_start: ledger_map.get_latest_block_start_pos(),
+                })
+            })
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(response))
+    }
+
+    async fn get_entry(
+        &self,
+        request: Request<pb::GetEntryRequest>,
+    ) -> Result<Response<pb::GetEntryResponse>, Status> {
+        let request = request.into_inner();
+        let value = self
+            .ledger
+            .run(move |ledger_map| Ok(ledger_map.get(&request.label, &request.key)?))
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(pb::GetEntryResponse { value }))
+    }
+
+    type StreamBlocksStream = ReceiverStream<Result<pb::Block, Status>>;
+
+    async fn stream_blocks(
+        &self,
+        request: Request<pb::StreamBlocksRequest>,
+    ) -> Result<Response<Self::StreamBlocksStream>, Status> {
+        let mut start = request.into_inner().start as usize;
+        let ledger = self.ledger.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(STREAM_BLOCKS_BATCH_SIZE);
+        // Read the blocks in batches, so that slow clients do not block the ledger thread
+        tokio::spawn(async move {
+            loop {
+                let batch = ledger
+                    .run(move |ledger_map| {
+                        ledger_map
+                            .iter_raw_from_block(start)
+                            .take(STREAM_BLOCKS_BATCH_SIZE)
+                            .map(|block| block.map(|(_, block)| pb::Block::from(&block)))
+                            .collect::<anyhow::Result<Vec<_>>>()
+                    })
+                    .await;
+                let batch = match batch {
+                    Ok(batch) if batch.is_empty() => break,
+                    Ok(batch) => batch,
+                    Err(err) => {
+                        let _ = tx.send(Err(to_status(err))).await;
+                        break;
+                    }
+                };
+                start += batch.len();
+                for block in batch {
+                    if tx.send(Ok(block)).await.is_err() {
+                        // The client went away
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Serve the ledger stored at `path` (or in the default file, if `None`) over gRPC on `addr`.
+pub async fn serve(addr: SocketAddr, path: Option<PathBuf>) -> anyhow::Result<()> {
+    let service = LedgerMapGrpcService::new(path)?;
+    info!("Serving ledger over gRPC on {}", addr);
+    Server::builder()
+        .add_service(service.into_server())
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+/// Client of a remote ledger served with `serve`.
+#[derive(Clone)]
+pub struct LedgerMapClient {
+    inner: LedgerMapServiceClient<Channel>,
+}
+
+impl LedgerMapClient {
+    pub async fn connect(endpoint: impl Into<String>) -> anyhow::Result<Self> {
+        let inner = LedgerMapServiceClient::connect(endpoint.into()).await?;
+        Ok(LedgerMapClient { inner })
+    }
+
+    /// Append `entries` to the next block. If `expected_tip_hash` is set, the entries are
+    /// rejected with `FAILED_PRECONDITION` if another block was committed in the meantime.
+    /// Returns the number of entries in the next block.
+    pub async fn append_entries(
+        &mut self,
+        entries: Vec<pb::Entry>,
+        expected_tip_hash: Option<Vec<u8>>,
+    ) -> Result<u64, Status> {
+        let response = self
+            .inner
+            .append_entries(pb::AppendEntriesRequest {
+                entries,
+                expected_tip_hash,
+            })
+            .await?;
+        Ok(response.into_inner().next_block_entries)
+    }
+
+    pub async fn upsert(&mut self, label: &str, key: &[u8], value: &[u8]) -> Result<(), Status> {
+        let entry = pb::Entry {
+            label: label.to_string(),
+            key: key.to_vec(),
+            value: value.to_vec(),
+            operation: pb::Operation::Upsert.into(),
+        };
+        self.append_entries(vec![entry], None).await.map(|_| ())
+    }
+
+    pub async fn delete(&mut self, label: &str, key: &[u8]) -> Result<(), Status> {
+        let entry = pb::Entry {
+            label: label.to_string(),
+            key: key.to_vec(),
+            value: Vec::new(),
+            operation: pb::Operation::Delete.into(),
+        };
+        self.append_entries(vec![entry], None).await.map(|_| ())
+    }
+
+    pub async fn commit_block(&mut self) -> Result<pb::CommitBlockResponse, Status> {
+        let response = self.inner.commit_block(pb::CommitBlockRequest {}).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Returns the committed value of the entry, or `None` if there is no such entry.
+    pub async fn get(&mut self, label: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Status> {
+        let request = pb::GetEntryRequest {
+            label: label.to_string(),
+            key: key.to_vec(),
+        };
+        match self.inner.get_entry(request).await {
+            Ok(response) => Ok(Some(response.into_inner().value)),
+            Err(status) if status.code() == tonic::Code::NotFound => Ok(None),
+            Err(status) => Err(status),
+        }
+    }
+
+    /// Stream the committed blocks, starting with the block with index `start`.
+    pub async fn stream_blocks(
+        &mut self,
+        start: u64,
+    ) -> Result<tonic::Streaming<pb::Block>, Status> {
+        let response = self
+            .inner
+            .stream_blocks(pb::StreamBlocksRequest { start })
+            .await?;
+        Ok(response.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_grpc_service() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = LedgerMapGrpcService::new(Some(file_path)).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(service.into_server())
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        let mut client = LedgerMapClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        client.upsert("label", b"key1", b"value1").await.unwrap();
+        client.upsert("label", b"key2", b"value2").await.unwrap();
+        let committed = client.commit_block().await.unwrap();
+        assert_eq!(committed.blocks_count, 1);
+        assert_eq!(
+            client.get("label", b"key1").await.unwrap(),
+            Some(b"value1".to_vec())
+        );
+        assert_eq!(client.get("label", b"none").await.unwrap(), None);
+
+        // A writer that has not seen the last block is rejected
+        let entry = pb::Entry {
+            label: "label".to_string(),
+            key: b"key3".to_vec(),
+            value: b"value3".to_vec(),
+            operation: pb::Operation::Upsert.into(),
+        };
+        let status = client
+            .append_entries(vec![entry.clone()], Some(vec![0u8; 32]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(status.metadata().get(ERROR_CODE_METADATA_KEY).unwrap(), "7");
+
+        // Invalid requests are rejected as a whole
+        let invalid = pb::Entry {
+            label: String::new(),
+            ..entry.clone()
+        };
+        let status = client
+            .append_entries(vec![entry.clone(), invalid], None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let unknown_operation = pb::Entry {
+            operation: 7,
+            ..entry.clone()
+        };
+        let status = client
+            .append_entries(vec![entry.clone(), unknown_operation], None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let pending = client
+            .append_entries(vec![entry], Some(committed.tip_hash))
+            .await
+            .unwrap();
+        assert_eq!(pending, 1);
+        client.delete("label", b"key1").await.unwrap();
+        client.commit_block().await.unwrap();
+        assert_eq!(client.get("label", b"key1").await.unwrap(), None);
+
+        let mut blocks = client.stream_blocks(0).await.unwrap();
+        let mut count = 0;
+        while let Some(block) = blocks.message().await.unwrap() {
+            assert!(!block.entries.is_empty());
+            count += 1;
+        }
+        assert_eq!(count, 2);
+        let mut blocks = client.stream_blocks(1).await.unwrap();
+        let block = blocks.message().await.unwrap().unwrap();
+        assert_eq!(block.entries[0].operation(), pb::Operation::Delete);
+        assert!(blocks.message().await.unwrap().is_none());
+        assert!(client
+            .stream_blocks(2)
+            .await
+            .unwrap()
+            .message()
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,467 @@
+//! This module specifies the chain hash of the ledger blocks, byte by byte, so that it can be
+//! reimplemented and verified outside of this crate, e.g. in JavaScript. The encoding is written
+//! out explicitly here instead of relying on the serialization of the block structs, so that it
+//! does not change when the storage format does. Any change of the hashed bytes gets a new
+//! chain hash version.
+//!
+//! The chain hash of a block is of version 2 if the block has the `BLOCK_FIELD_ENTRY_HASHES`
+//! field, and of version 1 otherwise. `block_chain_hash` computes the chain hash of a block.
+//!
+//! # Chain hash, version 1
+//!
+//! ```text
+//! chain_hash = SHA-256(parent_hash || entry_bytes(entry_1) || ... || entry_bytes(entry_n) || timestamp_ns)
+//! ```
+//!
+//! - `parent_hash`: chain hash of the previous block (32 bytes), or no bytes for the first block.
+//! - `entry_1 .. entry_n`: the entries of the block in block order; the number of entries is not
+//!   hashed, and a block without entries hashes no entry bytes.
+//! - `timestamp_ns`: timestamp of the block in nanoseconds, as 8 bytes little-endian.
+//!
+//! ```text
+//! entry_bytes = 0x00
+//!               || u32_le(len(label)) || label
+//!               || u32_le(len(key)) || key
+//!               || u32_le(len(value)) || value
+//!               || operation
+//! ```
+//!
+//! - The leading `0x00` is the version of the entry encoding.
+//! - `label` is encoded as UTF-8, and the lengths are in bytes.
+//! - `operation` is `0x00` for an upsert and `0x01` for a delete. Deletes have an empty value.
+//!
+//! # Chain hash, version 2
+//!
+//! ```text
+//! chain_hash = SHA-256(parent_hash || entry_hash(entry_1) || ... || entry_hash(entry_n) || timestamp_ns)
+//! entry_hash = SHA-256(entry_bytes)
+//! ```
+//!
+//! With `parent_hash`, `entry_bytes` and `timestamp_ns` as in version 1. The entry hashes are
+//! stored in the block, so that the integrity of a single entry can be checked with only the
+//! entry and the hashes of the other entries of the block, see `verify_entry`.
+//!
+//! # Block fields
+//!
+//! Only two block fields take part in the chain hash: `BLOCK_FIELD_ENTRY_HASHES`, which selects
+//! version 2 and holds the hashed entry hashes, and `BLOCK_FIELD_PRUNED_ENTRY_INDEXES` of pruned
+//! blocks, below. All other block fields, e.g. signatures, merkle roots and annotations, are NOT
+//! hashed and so NOT authenticated by the chain: they can be changed without changing the chain
+//! hash of the block, and must be verified by other means if they are relied on.
+//!
+//! # Pruned blocks
+//!
+//! Pruned blocks (see `LedgerMap::prune_blocks`) keep only some of their entries, and their
+//! chain hash must still match the one of the original block:
+//!
+//! - A block with a version 2 chain hash keeps the `BLOCK_FIELD_ENTRY_HASHES` of all original
+//!   entries, and the index of each kept entry among them in `BLOCK_FIELD_PRUNED_ENTRY_INDEXES`
+//!   (`u32_le` each, strictly increasing). The hash of each kept entry must match the stored hash
+//!   at its index, and the chain hash is computed from all the stored hashes as above.
+//! - A block with a version 1 chain hash can only be pruned of all its entries, since its chain
+//!   hash is computed from the entry bytes. It records its chain hash in
+//!   `BLOCK_FIELD_PRUNED_CHAIN_HASH` instead, which must not be combined with entries. That hash
+//!   is not computed from the block: it is authenticated only by the parent hash of the next
+//!   block, or for the tip by the trusted tip hash.
+//!
+//! # Test vectors
+//!
+//! `tests/vectors/chain_hash_v1.json` and `tests/vectors/chain_hash_v2.json` hold the vectors of
+//! `test_vectors` for each version, with all byte strings hex encoded and the timestamps as
+//! decimal strings, since they do not fit in a JSON number.
+use crate::ledger_entry::{
+    LedgerBlock, LedgerEntry, Operation, BLOCK_FIELD_ENTRY_HASHES, BLOCK_FIELD_PRUNED_ENTRY_INDEXES,
+};
+use crate::LedgerError;
+use serde::Serialize;
+use sha2::Digest;
+
+/// Latest version of the chain hash specified by this module.
+pub const CHAIN_HASH_VERSION: u32 = 2;
+
+/// Version byte of the entry encoding.
+const ENTRY_ENCODING_V1: u8 = 0;
+
+/// Bytes of `entry` that are hashed into the chain hash.
+pub fn entry_bytes(entry: &LedgerEntry) -> Vec<u8> {
+    let (label, key, value) = (entry.label().as_bytes(), entry.key(), entry.value());
+    let mut bytes = Vec::with_capacity(1 + 12 + label.len() + key.len() + value.len() + 1);
+    bytes.push(ENTRY_ENCODING_V1);
+    for field in [label, key, value] {
+        bytes.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(field);
+    }
+    bytes.push(match entry.operation() {
+        Operation::Upsert => 0,
+        Operation::Delete => 1,
+    });
+    bytes
+}
+
+/// Chain hash (version 1) of a block with the given parent hash, entries and timestamp.
+pub fn chain_hash(parent_hash: &[u8], entries: &[LedgerEntry], timestamp_ns: u64) -> Vec<u8> {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(parent_hash);
+    for entry in entries {
+        hasher.update(entry_bytes(entry));
+    }
+    hasher.update(timestamp_ns.to_le_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// SHA-256 hash of the `entry_bytes` of `entry`, the building block of the version 2 chain hash.
+pub fn entry_hash(entry: &LedgerEntry) -> [u8; 32] {
+    sha2::Sha256::digest(entry_bytes(entry)).into()
+}
+
+/// Chain hash (version 2) of a block with the given parent hash, entry hashes and timestamp.
+pub fn chain_hash_from_entry_hashes(
+    parent_hash: &[u8],
+    entry_hashes: &[[u8; 32]],
+    timestamp_ns: u64,
+) -> Vec<u8> {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(parent_hash);
+    for entry_hash in entry_hashes {
+        hasher.update(entry_hash);
+    }
+    hasher.update(timestamp_ns.to_le_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// Chain hash of `block`, of the version that the block uses. Fails if the entry hashes stored
+/// in the block do not match its entries, or if the block is not a valid pruned block.
+pub fn block_chain_hash(block: &LedgerBlock) -> Result<Vec<u8>, LedgerError> {
+    let corrupted = |problem: &str| {
+        LedgerError::BlockCorrupted(format!(
+            "{} in the block at offset {}",
+            problem,
+            block.get_offset()
+        ))
+    };
+    if let Some(chain_hash) = block.pruned_chain_hash() {
+        // The recorded chain hash does not cover any entries, see the module documentation
+        if !block.entries().is_empty() {
+            return Err(corrupted("Pruned chain hash with entries"));
+        }
+        return Ok(chain_hash.to_vec());
+    }
+    match block.field(BLOCK_FIELD_ENTRY_HASHES) {
+        Some(stored) => {
+            let stored_hashes = block.entry_hashes().unwrap_or_default();
+            let entry_hashes = block.entries().iter().map(entry_hash).collect::<Vec<_>>();
+            let is_valid = stored.len() % 32 == 0
+                && match block.field(BLOCK_FIELD_PRUNED_ENTRY_INDEXES) {
+                    Some(field) => {
+                        let indexes = block.pruned_entry_indexes().unwrap_or_default();
+                        field.len() == 4 * indexes.len()
+                            && indexes.len() == entry_hashes.len()
+                            && indexes.windows(2).all(|pair| pair[0] < pair[1])
+                            && indexes.iter().zip(&entry_hashes).all(|(index, hash)| {
+                                stored_hashes.get(*index as usize) == Some(hash)
+                            })
+                    }
+                    None => stored_hashes == entry_hashes,
+                };
+            if !is_valid {
+                return Err(corrupted("Entry hashes that do not match the entries"));
+            }
+            Ok(chain_hash_from_entry_hashes(
+                block.parent_hash(),
+                &stored_hashes,
+                block.timestamp(),
+            ))
+        }
+        None if block.field(BLOCK_FIELD_PRUNED_ENTRY_INDEXES).is_some() => {
+            Err(corrupted("Pruned entry indexes without entry hashes"))
+        }
+        None => Ok(chain_hash(
+            block.parent_hash(),
+            block.entries(),
+            block.timestamp(),
+        )),
+    }
+}
+
+/// Check that `entry` is the entry with index `index` of the block with the chain hash
+/// `chain_hash` (version 2), given only the entry hashes, parent hash and timestamp of the block.
+pub fn verify_entry(
+    entry: &LedgerEntry,
+    index: usize,
+    entry_hashes: &[[u8; 32]],
+    parent_hash: &[u8],
+    timestamp_ns: u64,
+    chain_hash: &[u8],
+) -> bool {
+    entry_hashes.get(index) == Some(&entry_hash(entry))
+        && chain_hash_from_entry_hashes(parent_hash, entry_hashes, timestamp_ns) == chain_hash
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TestVectors {
+    pub version: u32,
+    pub vectors: Vec<TestVector>,
+}
+
+/// Test vector of a block, with its hex encoded inputs and chain hash.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TestVector {
+    pub name: String,
+    pub parent_hash: String,
+    pub timestamp_ns: String,
+    pub entries: Vec<TestVectorEntry>,
+    pub chain_hash: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TestVectorEntry {
+    pub label: String,
+    pub key: String,
+    pub value: String,
+    /// `upsert` or `delete`.
+    pub operation: String,
+    /// Hex encoded `entry_bytes` of the entry.
+    pub encoded: String,
+    /// Hex encoded `entry_hash` of the entry, in the vectors of version 2.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl TestVector {
+    fn new(
+        version: u32,
+        name: &str,
+        parent_hash: &[u8],
+        entries: &[LedgerEntry],
+        timestamp_ns: u64,
+    ) -> Self {
+        let entry_hashes = entries.iter().map(entry_hash).collect::<Vec<_>>();
+        TestVector {
+            name: name.to_string(),
+            parent_hash: hex::encode(parent_hash),
+            timestamp_ns: timestamp_ns.to_string(),
+            entries: entries
+                .iter()
+                .map(|entry| TestVectorEntry {
+                    label: entry.label().to_string(),
+                    key: hex::encode(entry.key()),
+                    value: hex::encode(entry.value()),
+                    operation: match entry.operation() {
+                        Operation::Upsert => "upsert".to_string(),
+                        Operation::Delete => "delete".to_string(),
+                    },
+                    encoded: hex::encode(entry_bytes(entry)),
+                    hash: (version == 2).then(|| hex::encode(entry_hash(entry))),
+                })
+                .collect(),
+            chain_hash: hex::encode(match version {
+                1 => chain_hash(parent_hash, entries, timestamp_ns),
+                _ => chain_hash_from_entry_hashes(parent_hash, &entry_hashes, timestamp_ns),
+            }),
+        }
+    }
+}
+
+/// Test vectors of the chain hash of the given version, for checking other implementations
+/// against this one.
+pub fn test_vectors(version: u32) -> TestVectors {
+    let first_block = [LedgerEntry::new(
+        "Label1",
+        b"key1",
+        b"value1",
+        Operation::Upsert,
+    )];
+    let first_block_timestamp_ns = 1_700_000_000_000_000_000;
+    let first_block_vector = TestVector::new(
+        version,
+        "first_block",
+        &[],
+        &first_block,
+        first_block_timestamp_ns,
+    );
+    let first_block_hash = hex::decode(&first_block_vector.chain_hash).expect("Hex chain hash");
+    TestVectors {
+        version,
+        vectors: vec![
+            TestVector::new(version, "empty_first_block", &[], &[], 0),
+            first_block_vector,
+            TestVector::new(
+                version,
+                "delete_and_binary_key",
+                &first_block_hash,
+                &[
+                    LedgerEntry::new("accounts/eu", [0x00, 0xff], [1, 2, 3], Operation::Upsert),
+                    LedgerEntry::new("Label1", b"key1", b"", Operation::Delete),
+                ],
+                1_700_000_001_000_000_000,
+            ),
+            TestVector::new(
+                version,
+                "unicode_label_and_max_timestamp",
+                &[0xab; 32],
+                &[LedgerEntry::new("ünïcødé", b"", b"", Operation::Upsert)],
+                u64::MAX,
+            ),
+            TestVector::new(
+                version,
+                "short_parent_hash",
+                &[0, 1, 2, 3],
+                &[LedgerEntry::new(
+                    "Label2",
+                    [4, 5, 6, 7],
+                    [8, 9, 10, 11],
+                    Operation::Upsert,
+                )],
+                0,
+            ),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_bytes_match_entry_serialization() {
+        // The v1 entry encoding is the borsh serialization of the v1 entries
+        for vector in test_vectors(1).vectors {
+            for entry in vector.entries {
+                let operation = match entry.operation.as_str() {
+                    "upsert" => Operation::Upsert,
+                    _ => Operation::Delete,
+                };
+                let entry = LedgerEntry::new(
+                    entry.label,
+                    hex::decode(entry.key).unwrap(),
+                    hex::decode(entry.value).unwrap(),
+                    operation,
+                );
+                assert_eq!(entry_bytes(&entry), borsh::to_vec(&entry).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_published_test_vectors() {
+        let published: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/vectors/chain_hash_v1.json")).unwrap();
+        assert_eq!(serde_json::to_value(test_vectors(1)).unwrap(), published);
+        let published: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/vectors/chain_hash_v2.json")).unwrap();
+        assert_eq!(serde_json::to_value(test_vectors(2)).unwrap(), published);
+    }
+
+    #[test]
+    fn test_verify_entry() {
+        let entries = [
+            LedgerEntry::new("Label1", b"key1", b"value1", Operation::Upsert),
+            LedgerEntry::new("Label1", b"key2", b"", Operation::Delete),
+        ];
+        let entry_hashes = entries.iter().map(entry_hash).collect::<Vec<_>>();
+        let chain_hash = chain_hash_from_entry_hashes(&[1; 32], &entry_hashes, 42);
+        assert!(verify_entry(
+            &entries[1],
+            1,
+            &entry_hashes,
+            &[1; 32],
+            42,
+            &chain_hash
+        ));
+        assert!(!verify_entry(
+            &entries[1],
+            0,
+            &entry_hashes,
+            &[1; 32],
+            42,
+            &chain_hash
+        ));
+        assert!(!verify_entry(
+            &entries[0],
+            2,
+            &entry_hashes,
+            &[1; 32],
+            42,
+            &chain_hash
+        ));
+        assert!(!verify_entry(
+            &entries[0],
+            0,
+            &entry_hashes,
+            &[1; 32],
+            43,
+            &chain_hash
+        ));
+        let tampered = LedgerEntry::new("Label1", b"key1", b"value2", Operation::Upsert);
+        assert!(!verify_entry(
+            &tampered,
+            0,
+            &entry_hashes,
+            &[1; 32],
+            42,
+            &chain_hash
+        ));
+    }
+
+    #[test]
+    fn test_pruned_block_chain_hash() {
+        use crate::ledger_entry::{BlockField, BLOCK_FIELD_PRUNED_CHAIN_HASH};
+
+        let entries = [
+            LedgerEntry::new("Label1", b"key1", b"value1", Operation::Upsert),
+            LedgerEntry::new("Label1", b"key2", b"value2", Operation::Upsert),
+            LedgerEntry::new("Label2", b"key3", b"value3", Operation::Upsert),
+        ];
+        let entry_hashes = entries.iter().map(entry_hash).collect::<Vec<_>>();
+        let chain_hash = chain_hash_from_entry_hashes(&[1; 32], &entry_hashes, 42);
+        let pruned = |entries: Vec<LedgerEntry>, indexes: &[u32]| {
+            LedgerBlock::new_v2(
+                entries,
+                42,
+                vec![1; 32],
+                vec![
+                    BlockField::new(BLOCK_FIELD_ENTRY_HASHES, entry_hashes.concat()),
+                    BlockField::new(
+                        BLOCK_FIELD_PRUNED_ENTRY_INDEXES,
+                        indexes
+                            .iter()
+                            .flat_map(|index| index.to_le_bytes())
+                            .collect::<Vec<_>>(),
+                    ),
+                ],
+            )
+        };
+
+        // The kept entries are checked against the stored hashes at their indexes
+        assert_eq!(
+            block_chain_hash(&pruned(vec![entries[1].clone()], &[1])).unwrap(),
+            chain_hash
+        );
+        assert_eq!(block_chain_hash(&pruned(vec![], &[])).unwrap(), chain_hash);
+        let tampered = LedgerEntry::new("Label1", b"key2", b"forged", Operation::Upsert);
+        for block in [
+            pruned(vec![tampered], &[1]),
+            pruned(vec![entries[1].clone()], &[0]),
+            pruned(vec![entries[1].clone()], &[7]),
+            pruned(vec![entries[1].clone(), entries[0].clone()], &[1, 0]),
+            pruned(vec![entries[1].clone()], &[]),
+        ] {
+            assert!(matches!(
+                block_chain_hash(&block),
+                Err(LedgerError::BlockCorrupted(_))
+            ));
+        }
+
+        // A recorded chain hash cannot vouch for entries
+        let recorded = |entries: Vec<LedgerEntry>| {
+            LedgerBlock::new_v2(
+                entries,
+                42,
+                vec![1; 32],
+                vec![BlockField::new(BLOCK_FIELD_PRUNED_CHAIN_HASH, [9; 32])],
+            )
+        };
+        assert_eq!(block_chain_hash(&recorded(vec![])).unwrap(), vec![9; 32]);
+        assert!(block_chain_hash(&recorded(vec![entries[0].clone()])).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+import { WasmLedgerMap } from '../../../dist/wasm';
+import { LedgerMessagePort, serveLedgerMap } from '../worker';
+import { LedgerMapWorkerClient } from '../worker-client';
+
+/** Connected pair of in-memory ports, delivering messages asynchronously like `MessageChannel`. */
+function portPair(): [LedgerMessagePort, LedgerMessagePort] {
+    const port1: LedgerMessagePort = { postMessage: (message) => deliver(port2, message), onmessage: null };
+    const port2: LedgerMessagePort = { postMessage: (message) => deliver(port1, message), onmessage: null };
+    function deliver(port: LedgerMessagePort, data: unknown) {
+        setTimeout(() => port.onmessage?.({ data } as MessageEvent), 0);
+    }
+    return [port1, port2];
+}
+
+describe('LedgerMapWorkerClient', () => {
+    const testKey = new Uint8Array([1, 2, 3]);
+    const testValue = new Uint8Array([4, 5, 6]);
+    let ledger: Promise<WasmLedgerMap>;
+
+    beforeEach(() => {
+        ledger = Promise.resolve(new WasmLedgerMap(['test']));
+    });
+
+    function connect(): LedgerMapWorkerClient {
+        const [clientPort, workerPort] = portPair();
+        serveLedgerMap(workerPort, ledger);
+        return new LedgerMapWorkerClient(clientPort);
+    }
+
+    it('should call the ledger in the worker', async () => {
+        const client = connect();
+        await client.upsert('test', testKey, testValue);
+        await client.commitBlock();
+        expect(Array.from(await client.get('test', testKey))).toEqual(Array.from(testValue));
+        expect(await client.getBlocksCount()).toBe(1);
+    });
+
+    it('should share one ledger between clients', async () => {
+        const client1 = connect();
+        const client2 = connect();
+        await client1.upsert('test', testKey, testValue);
+        await client1.commitBlock();
+        expect(Array.from(await client2.get('test', testKey))).toEqual(Array.from(testValue));
+        expect(await client2.getBlocksCount()).toBe(1);
+    });
+
+    it('should reject with the error of the ledger', async () => {
+        const client = connect();
+        await expect(client.get('unknown', testKey)).rejects.toThrow('Invalid label: unknown');
+    });
+
+    it('should reject unknown methods', async () => {
+        const client = connect();
+        await expect(client.call('not_a_method' as never)).rejects.toThrow('Unknown ledger method');
+    });
+});
@@ -1,5 +1,48 @@
 import init, { WasmLedgerMap } from '../../dist/wasm';
 
+export type { LedgerBlock, LedgerEntry, Operation } from '../../dist/wasm';
+export { LedgerMapWorkerClient } from './worker-client';
+export { serveLedgerMap, startLedgerWorker } from './worker';
+
+/**
+ * Numeric codes of the errors thrown by the ledger, in the `code` property of the error.
+ * Codes are stable across releases.
+ */
+export enum LedgerErrorCode {
+    EntryNotFound = 1,
+    BlockEmpty = 2,
+    BlockCorrupted = 3,
+    UnsupportedBlockVersion = 4,
+    Io = 5,
+    Serialization = 6,
+    HashMismatch = 7,
+    QuotaExceeded = 8,
+    KeyTooLarge = 9,
+    ValueTooLarge = 10,
+    InvalidLabel = 11,
+    BlockPruned = 12,
+    Unauthorized = 13,
+    UnknownChainHash = 14,
+    TimestampOutOfRange = 15,
+    StorageFull = 16,
+    Other = 255,
+}
+
+/**
+ * Error thrown by the ledger, with name `LedgerError`.
+ */
+export interface LedgerError extends Error {
+    code: LedgerErrorCode;
+}
+
+/**
+ * Check whether a caught value is an error thrown by the ledger
+ * @param error The caught value
+ */
+export function isLedgerError(error: unknown): error is LedgerError {
+    return error instanceof Error && error.name === 'LedgerError' && typeof (error as LedgerError).code === 'number';
+}
+
 export interface LedgerMapOptions {
     labels?: string[];
 }
@@ -0,0 +1,76 @@
+import type { LedgerMessagePort, LedgerWorkerMethod, LedgerWorkerRequest, LedgerWorkerResponse } from './worker';
+
+/**
+ * Client of a ledger hosted in a worker with `startLedgerWorker`.
+ * All methods are asynchronous, and reject with the error thrown by the ledger in the worker.
+ */
+export class LedgerMapWorkerClient {
+    private nextId = 1;
+    private pending = new Map<number, { resolve: (result: unknown) => void; reject: (error: Error) => void }>();
+
+    /**
+     * @param port The worker, or the port of a SharedWorker
+     */
+    constructor(private port: LedgerMessagePort) {
+        this.port.onmessage = (event: MessageEvent) => this.handleResponse(event.data as LedgerWorkerResponse);
+    }
+
+    /**
+     * Call a method of the ledger in the worker
+     * @param method The method name
+     * @param args The method arguments
+     */
+    call<T>(method: LedgerWorkerMethod, ...args: unknown[]): Promise<T> {
+        const request: LedgerWorkerRequest = { id: this.nextId++, method, args };
+        return new Promise<T>((resolve, reject) => {
+            this.pending.set(request.id, { resolve: resolve as (result: unknown) => void, reject });
+            this.port.postMessage(request);
+        });
+    }
+
+    upsert(label: string, key: Uint8Array, value: Uint8Array): Promise<void> {
+        return this.call('upsert', label, key, value);
+    }
+
+    get(label: string, key: Uint8Array): Promise<Uint8Array> {
+        return this.call('get', label, key);
+    }
+
+    delete(label: string, key: Uint8Array): Promise<void> {
+        return this.call('delete', label, key);
+    }
+
+    commitBlock(): Promise<void> {
+        return this.call('commit_block');
+    }
+
+    getBlocksCount(): Promise<number> {
+        return this.call('get_blocks_count');
+    }
+
+    getLatestBlockHash(): Promise<Uint8Array> {
+        return this.call('get_latest_block_hash');
+    }
+
+    refreshLedger(): Promise<void> {
+        return this.call('refresh');
+    }
+
+    private handleResponse(response: LedgerWorkerResponse): void {
+        const pending = this.pending.get(response.id);
+        if (!pending) {
+            return;
+        }
+        this.pending.delete(response.id);
+        if (response.error) {
+            const error = new Error(response.error.message) as Error & { code?: number };
+            error.name = response.error.name;
+            if (response.error.code !== undefined) {
+                error.code = response.error.code;
+            }
+            pending.reject(error);
+        } else {
+            pending.resolve(response.result);
+        }
+    }
+}
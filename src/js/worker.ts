@@ -0,0 +1,89 @@
+import init, { WasmLedgerMap } from '../../dist/wasm';
+
+/**
+ * Methods of the ledger that can be called through a worker, with their arguments.
+ * Calls are processed one at a time, in the order they arrive from all connected tabs.
+ */
+export const LEDGER_WORKER_METHODS = [
+    'upsert',
+    'get',
+    'delete',
+    'commit_block',
+    'refresh',
+    'get_blocks_count',
+    'get_latest_block_hash',
+    'get_block',
+    'get_blocks',
+    'get_block_entries',
+    'get_next_block_entries',
+    'get_raw_bytes',
+    'apply_raw_bytes',
+] as const;
+
+export type LedgerWorkerMethod = typeof LEDGER_WORKER_METHODS[number];
+
+/** Request sent to the ledger worker. */
+export interface LedgerWorkerRequest {
+    id: number;
+    method: LedgerWorkerMethod;
+    args: unknown[];
+}
+
+/** Response of the ledger worker, with either the result or the error of the call. */
+export interface LedgerWorkerResponse {
+    id: number;
+    result?: unknown;
+    error?: {
+        name: string;
+        message: string;
+        code?: number;
+    };
+}
+
+/** The subset of `MessagePort` and `Worker` used to exchange messages with the worker. */
+export interface LedgerMessagePort {
+    postMessage(message: unknown): void;
+    onmessage: ((event: MessageEvent) => void) | null;
+}
+
+/**
+ * Answer the ledger requests arriving on `port`, with the ledger once it's ready.
+ * @param port The port to receive requests from and send responses to
+ * @param ledger The ledger shared by all ports
+ */
+export function serveLedgerMap(port: LedgerMessagePort, ledger: Promise<WasmLedgerMap>): void {
+    port.onmessage = async (event: MessageEvent) => {
+        const request = event.data as LedgerWorkerRequest;
+        const response: LedgerWorkerResponse = { id: request.id };
+        try {
+            if (!LEDGER_WORKER_METHODS.includes(request.method)) {
+                throw new Error(`Unknown ledger method: ${request.method}`);
+            }
+            const instance = (await ledger) as unknown as Record<string, (...args: unknown[]) => unknown>;
+            response.result = instance[request.method](...request.args);
+        } catch (e) {
+            const error = e as Error & { code?: number };
+            response.error = { name: error.name, message: error.message, code: error.code };
+        }
+        port.postMessage(response);
+    };
+}
+
+/**
+ * Host the ledger in a dedicated or shared worker, so that it's refreshed off the main thread,
+ * and all tabs connected to a SharedWorker use one consistent ledger. Call this in the worker script.
+ * Local storage is not available in workers, so the ledger data is not persisted there;
+ * use `get_raw_bytes` and `apply_raw_bytes` to back up and restore it.
+ * @param scope The global scope of the worker
+ * @param labels The labels to index
+ */
+export function startLedgerWorker(scope: typeof globalThis, labels?: string[]): void {
+    const ledger = init().then(() => new WasmLedgerMap(labels));
+    if ('onconnect' in scope) {
+        // SharedWorker: each tab connects with its own port
+        (scope as unknown as { onconnect: (event: MessageEvent) => void }).onconnect = (event: MessageEvent) =>
+            serveLedgerMap(event.ports[0], ledger);
+    } else {
+        serveLedgerMap(scope as unknown as LedgerMessagePort, ledger);
+    }
+}
@@ -0,0 +1,184 @@
+//! This module implements the in-memory index of the committed entries of a label, with the map
+//! data structure chosen per label, see `LedgerMapBuilder::label_index_kind`.
+use crate::ledger_entry::{EntryKey, LedgerEntry};
+use crate::AHashMap;
+use indexmap::IndexMap;
+use std::collections::{btree_map, hash_map, BTreeMap};
+use std::ops::Bound;
+
+/// Data structure of the in-memory index of a label.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LabelIndexKind {
+    /// Entries in insertion order, the default.
+    #[default]
+    IndexMap,
+    /// Entries sorted by key, so that the entries of the label are iterated in key order.
+    BTreeMap,
+    /// Entries in no particular order, for the fastest lookups and updates.
+    HashMap,
+}
+
+/// Committed entries of a label, by key.
+#[derive(Debug, PartialEq)]
+pub(crate) enum LabelIndex {
+    Indexed(IndexMap<EntryKey, LedgerEntry>),
+    Sorted(BTreeMap<EntryKey, LedgerEntry>),
+    Hashed(AHashMap<EntryKey, LedgerEntry>),
+}
+
+impl LabelIndex {
+    pub(crate) fn new(kind: LabelIndexKind) -> Self {
+        match kind {
+            LabelIndexKind::IndexMap => LabelIndex::Indexed(IndexMap::new()),
+            LabelIndexKind::BTreeMap => LabelIndex::Sorted(BTreeMap::new()),
+            LabelIndexKind::HashMap => LabelIndex::Hashed(AHashMap::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&LedgerEntry> {
+        match self {
+            LabelIndex::Indexed(entries) => entries.get(key),
+            LabelIndex::Sorted(entries) => entries.get(key),
+            LabelIndex::Hashed(entries) => entries.get(key),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: EntryKey, entry: LedgerEntry) {
+        match self {
+            LabelIndex::Indexed(entries) => {
+                entries.insert(key, entry);
+            }
+            LabelIndex::Sorted(entries) => {
+                entries.insert(key, entry);
+            }
+            LabelIndex::Hashed(entries) => {
+                entries.insert(key, entry);
+            }
+        }
+    }
+
+    /// Remove the entry of `key`. In an `IndexMap`, the last entry takes its place.
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        match self {
+            LabelIndex::Indexed(entries) => {
+                entries.swap_remove(key);
+            }
+            LabelIndex::Sorted(entries) => {
+                entries.remove(key);
+            }
+            LabelIndex::Hashed(entries) => {
+                entries.remove(key);
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            LabelIndex::Indexed(entries) => entries.len(),
+            LabelIndex::Sorted(entries) => entries.len(),
+            LabelIndex::Hashed(entries) => entries.len(),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_> {
+        match self {
+            LabelIndex::Indexed(entries) => Iter::Indexed(entries.iter()),
+            LabelIndex::Sorted(entries) => Iter::Sorted(entries.iter()),
+            LabelIndex::Hashed(entries) => Iter::Hashed(entries.iter()),
+        }
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &LedgerEntry> {
+        self.iter().map(|(_, entry)| entry)
+    }
+
+    /// The first `limit` entries in key order for which `filter` returns true, with keys after
+    /// `after_key`, or from the first key if `None`. A `BTreeMap` is read from `after_key` on,
+    /// while the other maps are scanned once and only the selected entries are sorted.
+    pub(crate) fn first_after(
+        &self,
+        after_key: Option<&[u8]>,
+        limit: usize,
+        filter: impl Fn(&LedgerEntry) -> bool,
+    ) -> Vec<&LedgerEntry> {
+        if let LabelIndex::Sorted(entries) = self {
+            let lower = after_key.map_or(Bound::Unbounded, Bound::Excluded);
+            return entries
+                .range::<[u8], _>((lower, Bound::Unbounded))
+                .map(|(_, entry)| entry)
+                .filter(|entry| filter(entry))
+                .take(limit)
+                .collect();
+        }
+        let mut selected = self
+            .values()
+            .filter(|entry| after_key.is_none_or(|after_key| entry.key() > after_key))
+            .filter(|entry| filter(entry))
+            .collect::<Vec<_>>();
+        if limit > 0 && selected.len() > limit {
+            selected.select_nth_unstable_by(limit - 1, |a, b| a.key().cmp(b.key()));
+        }
+        selected.truncate(limit);
+        selected.sort_unstable_by(|a, b| a.key().cmp(b.key()));
+        selected
+    }
+}
+
+impl Extend<(EntryKey, LedgerEntry)> for LabelIndex {
+    fn extend<I: IntoIterator<Item = (EntryKey, LedgerEntry)>>(&mut self, iter: I) {
+        match self {
+            LabelIndex::Indexed(entries) => entries.extend(iter),
+            LabelIndex::Sorted(entries) => entries.extend(iter),
+            LabelIndex::Hashed(entries) => entries.extend(iter),
+        }
+    }
+}
+
+impl IntoIterator for LabelIndex {
+    type Item = (EntryKey, LedgerEntry);
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        match self {
+            LabelIndex::Indexed(entries) => IntoIter::Indexed(entries.into_iter()),
+            LabelIndex::Sorted(entries) => IntoIter::Sorted(entries.into_iter()),
+            LabelIndex::Hashed(entries) => IntoIter::Hashed(entries.into_iter()),
+        }
+    }
+}
+
+pub(crate) enum Iter<'a> {
+    Indexed(indexmap::map::Iter<'a, EntryKey, LedgerEntry>),
+    Sorted(btree_map::Iter<'a, EntryKey, LedgerEntry>),
+    Hashed(hash_map::Iter<'a, EntryKey, LedgerEntry>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a EntryKey, &'a LedgerEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Indexed(iter) => iter.next(),
+            Iter::Sorted(iter) => iter.next(),
+            Iter::Hashed(iter) => iter.next(),
+        }
+    }
+}
+
+pub(crate) enum IntoIter {
+    Indexed(indexmap::map::IntoIter<EntryKey, LedgerEntry>),
+    Sorted(btree_map::IntoIter<EntryKey, LedgerEntry>),
+    Hashed(hash_map::IntoIter<EntryKey, LedgerEntry>),
+}
+
+impl Iterator for IntoIter {
+    type Item = (EntryKey, LedgerEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            IntoIter::Indexed(iter) => iter.next(),
+            IntoIter::Sorted(iter) => iter.next(),
+            IntoIter::Hashed(iter) => iter.next(),
+        }
+    }
+}
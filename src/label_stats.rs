@@ -0,0 +1,128 @@
+//! This module implements per-label statistics of the committed entries, see
+//! `LedgerMap::label_stats`. They are maintained incrementally as blocks are committed or read
+//! at refresh, so reading them does not iterate over the entries.
+use crate::ledger_entry::{EntryKey, LedgerEntry, Operation};
+use crate::ledger_map::{RENAME_LABEL, RESERVED_LABEL_PREFIX};
+use std::collections::BTreeMap;
+
+/// Statistics of the committed entries of a label.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LabelStats {
+    /// Number of keys whose latest entry is an upsert.
+    pub live_entries: u64,
+    /// Number of keys whose latest entry is a delete.
+    pub tombstones: u64,
+    /// Total size of the values of the live entries, as stored in the entries. Chunked values
+    /// and blob references count the size of their stored reference, not of the whole value.
+    pub value_bytes: u64,
+    /// Index of the last block with an entry of the label.
+    pub last_updated_block: u64,
+    /// Timestamp of the last block with an entry of the label, in nanoseconds.
+    pub last_updated_timestamp_ns: u64,
+}
+
+/// State of a key that the statistics are derived from.
+#[derive(Clone, Copy, Debug)]
+struct KeyState {
+    /// Value size of a live key, or `None` for a deleted key.
+    value_bytes: Option<u64>,
+    /// Index of the block with the last write of the key.
+    block_index: u64,
+}
+
+/// Statistics of all non-reserved labels, with the state of each key that they are derived from.
+#[derive(Debug, Default)]
+pub(crate) struct LabelStatsIndex {
+    keys: BTreeMap<(String, EntryKey), KeyState>,
+    stats: BTreeMap<String, LabelStats>,
+}
+
+impl LabelStatsIndex {
+    pub(crate) fn clear(&mut self) {
+        self.keys.clear();
+        self.stats.clear();
+    }
+
+    pub(crate) fn get(&self, label: &str) -> Option<&LabelStats> {
+        self.stats.get(label)
+    }
+
+    /// Index of the block with the last committed write of `key` of `label`.
+    pub(crate) fn last_write_block(&self, label: &str, key: &[u8]) -> Option<u64> {
+        self.keys
+            .get(&(label.to_string(), key.to_vec()))
+            .map(|state| state.block_index)
+    }
+
+    /// Apply an entry committed in block `block_index` at `timestamp_ns`: upserts and deletes
+    /// of non-reserved labels, and label renames.
+    pub(crate) fn apply(&mut self, entry: &LedgerEntry, block_index: u64, timestamp_ns: u64) {
+        if entry.label() == RENAME_LABEL && entry.operation() == Operation::Upsert {
+            let old_label = String::from_utf8_lossy(entry.key()).to_string();
+            let new_label = String::from_utf8_lossy(entry.value()).to_string();
+            self._rename(&old_label, &new_label, block_index, timestamp_ns);
+            return;
+        }
+        if entry.label().starts_with(RESERVED_LABEL_PREFIX) {
+            return;
+        }
+        let value_bytes = match entry.operation() {
+            Operation::Upsert => Some(entry.value().len() as u64),
+            Operation::Delete => None,
+        };
+        let new_state = KeyState {
+            value_bytes,
+            block_index,
+        };
+        self._set_key_state(entry.label(), entry.key(), new_state);
+        self._set_last_updated(entry.label(), block_index, timestamp_ns);
+    }
+
+    /// Set the state of a key, and update the statistics of its label accordingly.
+    fn _set_key_state(&mut self, label: &str, key: &[u8], new_state: KeyState) {
+        let old_state = self
+            .keys
+            .insert((label.to_string(), key.to_vec()), new_state);
+        let stats = self.stats.entry(label.to_string()).or_default();
+        match old_state.map(|state| state.value_bytes) {
+            Some(Some(value_bytes)) => {
+                stats.live_entries -= 1;
+                stats.value_bytes -= value_bytes;
+            }
+            Some(None) => stats.tombstones -= 1,
+            None => {}
+        }
+        match new_state.value_bytes {
+            Some(value_bytes) => {
+                stats.live_entries += 1;
+                stats.value_bytes += value_bytes;
+            }
+            None => stats.tombstones += 1,
+        }
+    }
+
+    fn _set_last_updated(&mut self, label: &str, block_index: u64, timestamp_ns: u64) {
+        let stats = self.stats.entry(label.to_string()).or_default();
+        stats.last_updated_block = block_index;
+        stats.last_updated_timestamp_ns = timestamp_ns;
+    }
+
+    fn _rename(&mut self, old_label: &str, new_label: &str, block_index: u64, timestamp_ns: u64) {
+        let renamed = self
+            .keys
+            .keys()
+            .filter(|(label, _)| label == old_label)
+            .cloned()
+            .collect::<Vec<_>>();
+        if renamed.is_empty() {
+            return;
+        }
+        for state_key in renamed {
+            if let Some(state) = self.keys.remove(&state_key) {
+                self._set_key_state(new_label, &state_key.1, state);
+            }
+        }
+        self.stats.remove(old_label);
+        self._set_last_updated(new_label, block_index, timestamp_ns);
+    }
+}
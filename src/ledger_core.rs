@@ -0,0 +1,629 @@
+//! This module implements the `no_std` core of the ledger, which needs only `alloc`, for embedded
+//! devices that journal the ledger to flash. `CoreLedger` reads and writes the same persistent
+//! storage layout as `LedgerMap`: the partition table, followed by the blocks of the data
+//! partition. A ledger journaled on a device can therefore be opened with `LedgerMap` on a host,
+//! and the other way around. The storage is reached only through the `CoreStorage` trait, which
+//! the device implements on top of its flash driver, flash translation layer or file system.
+//!
+//! The core keeps an in-memory index of the entries of all labels, commits blocks of version 1,
+//! and reads unencrypted blocks of versions 1 to 3 that were not pruned. The features of
+//! `LedgerMap` that need the standard library, such as secondary indexes, compaction, cold
+//! storage or encryption, are not available in the core.
+//!
+//! Example usage:
+//!
+//! ```rust
+//! use ledger_map::CoreLedger;
+//!
+//! // A `Vec<u8>` is a storage in RAM, a device would implement `CoreStorage` for its flash
+//! let mut ledger = CoreLedger::open(Vec::new()).unwrap();
+//! ledger.upsert("Label1", b"key1", b"value1").unwrap();
+//! ledger.commit_block(1_000_000_000).unwrap();
+//!
+//! // Reopen the ledger from its storage
+//! let ledger = CoreLedger::open(ledger.into_storage()).unwrap();
+//! assert_eq!(ledger.get("Label1", b"key1"), Some(&b"value1"[..]));
+//! assert_eq!(ledger.get_blocks_count(), 1);
+//! ```
+
+use crate::LedgerError;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use sha2::{Digest, Sha256};
+
+/// Start of the data partition of the ledgers that `CoreLedger` creates. It is much lower than
+/// the default of `LedgerMap`, to fit small flash storages.
+pub const CORE_DATA_PARTITION_START: u64 = 4096;
+
+// Layout of the partition table and the block headers, as in `partition_table` and `ledger_entry`
+const PARTITION_TABLE_MAGIC: &[u8; 8] = b"LedgPart";
+const PARTITION_TABLE_ENTRY_LEN: usize = 16;
+const PARTITION_TABLE_MAX_ENTRIES: usize = 128;
+const PARTITION_TABLE_LEN: usize = 8 + PARTITION_TABLE_MAX_ENTRIES * PARTITION_TABLE_ENTRY_LEN;
+const PART_DATA: usize = 1;
+const PART_JOURNAL: usize = PARTITION_TABLE_MAX_ENTRIES - 1;
+const BLOCK_HEADER_LEN: u64 = 16;
+const LATEST_BLOCK_VERSION: u32 = 3;
+const BLOCK_VERSION_ENCRYPTED_FLAG: u32 = 1 << 31;
+const BLOCK_FIELD_PRUNED_CHAIN_HASH: u16 = 6;
+const BLOCK_FIELD_ENTRY_HASHES: u16 = 7;
+const BLOCK_FIELD_PRUNED_ENTRY_INDEXES: u16 = 8;
+/// Compression level of the block bodies, the default level of `LedgerMap`.
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// Persistent storage of a `CoreLedger`: a flat, byte-addressable storage, such as a file on a
+/// flash file system or a flash partition behind a flash translation layer.
+pub trait CoreStorage {
+    fn size_bytes(&self) -> u64;
+    /// Read `buf.len()` bytes at `offset`, which must be below `size_bytes`.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), LedgerError>;
+    /// Write `buf` at `offset`, growing the storage as needed. Fails with
+    /// `LedgerError::StorageFull` when the storage cannot grow any further.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), LedgerError>;
+    /// Make all writes so far durable. Called once per committed block.
+    fn sync(&mut self) -> Result<(), LedgerError> {
+        Ok(())
+    }
+}
+
+/// Storage in RAM, e.g. for tests, or to build a ledger image that is flashed later.
+impl CoreStorage for Vec<u8> {
+    fn size_bytes(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), LedgerError> {
+        let start = offset as usize;
+        let bytes = self.get(start..start + buf.len()).ok_or_else(|| {
+            LedgerError::Other(format!(
+                "Read of {} bytes at offset {} is beyond the end of the storage",
+                buf.len(),
+                offset
+            ))
+        })?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), LedgerError> {
+        let start = offset as usize;
+        if self.len() < start + buf.len() {
+            self.resize(start + buf.len(), 0);
+        }
+        self[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Entry of a `CoreLedger` block.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CoreEntry {
+    pub label: String,
+    pub key: Vec<u8>,
+    /// Value of an upsert, empty for a delete.
+    pub value: Vec<u8>,
+    pub delete: bool,
+}
+
+impl CoreEntry {
+    /// Bytes of the entry that are hashed into the chain hash, see `hashing`. They are also the
+    /// serialized entry in the block body.
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(1 + 12 + self.label.len() + self.key.len() + self.value.len() + 1);
+        bytes.push(0);
+        for field in [self.label.as_bytes(), &self.key, &self.value] {
+            bytes.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes.push(self.delete as u8);
+        bytes
+    }
+}
+
+/// Ledger on a `CoreStorage`, with an in-memory index of the committed entries, see the module
+/// documentation.
+pub struct CoreLedger<S: CoreStorage> {
+    storage: S,
+    first_block_start_pos: u64,
+    last_block_start_pos: Option<u64>,
+    next_block_start_pos: u64,
+    blocks_count: u64,
+    chain_hash: Vec<u8>,
+    last_timestamp_ns: u64,
+    entries: BTreeMap<String, BTreeMap<Vec<u8>, Vec<u8>>>,
+    next_block_entries: Vec<CoreEntry>,
+}
+
+impl<S: CoreStorage> CoreLedger<S> {
+    /// Open the ledger in `storage`, verifying the chain of its blocks, or create a new ledger
+    /// if the storage is empty. Fails if the storage holds other data than a ledger.
+    pub fn open(mut storage: S) -> Result<Self, LedgerError> {
+        let first_block_start_pos = match read_partition_table(&mut storage)? {
+            Some(data_start) => data_start,
+            None => {
+                let mut table = vec![0u8; PARTITION_TABLE_LEN];
+                table[..8].copy_from_slice(PARTITION_TABLE_MAGIC);
+                table[8..24].copy_from_slice(&partition_table_entry(b"PARTTABL", 8));
+                table[24..40]
+                    .copy_from_slice(&partition_table_entry(b"DATA", CORE_DATA_PARTITION_START));
+                storage.write(0, &table)?;
+                storage.write(CORE_DATA_PARTITION_START, &[0u8; BLOCK_HEADER_LEN as usize])?;
+                storage.sync()?;
+                CORE_DATA_PARTITION_START
+            }
+        };
+        let mut ledger = CoreLedger {
+            storage,
+            first_block_start_pos,
+            last_block_start_pos: None,
+            next_block_start_pos: first_block_start_pos,
+            blocks_count: 0,
+            chain_hash: Vec::new(),
+            last_timestamp_ns: 0,
+            entries: BTreeMap::new(),
+            next_block_entries: Vec::new(),
+        };
+        while let Some((jump_bytes_next, block)) =
+            ledger._read_block(ledger.next_block_start_pos)?
+        {
+            ledger._apply_block(ledger.next_block_start_pos, jump_bytes_next, block);
+        }
+        Ok(ledger)
+    }
+
+    /// Stage an upsert of `key` of `label` to `value` in the next block.
+    pub fn upsert<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        label: &str,
+        key: K,
+        value: V,
+    ) -> Result<(), LedgerError> {
+        self._stage(CoreEntry {
+            label: label.to_string(),
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+            delete: false,
+        })
+    }
+
+    /// Stage a delete of `key` of `label` in the next block.
+    pub fn delete<K: AsRef<[u8]>>(&mut self, label: &str, key: K) -> Result<(), LedgerError> {
+        self._stage(CoreEntry {
+            label: label.to_string(),
+            key: key.as_ref().to_vec(),
+            value: Vec::new(),
+            delete: true,
+        })
+    }
+
+    /// Value of `key` of `label`, including the entries staged for the next block.
+    pub fn get(&self, label: &str, key: &[u8]) -> Option<&[u8]> {
+        match self
+            .next_block_entries
+            .iter()
+            .find(|entry| entry.label == label && entry.key == key)
+        {
+            Some(entry) if entry.delete => None,
+            Some(entry) => Some(&entry.value),
+            None => self
+                .entries
+                .get(label)
+                .and_then(|entries| entries.get(key))
+                .map(Vec::as_slice),
+        }
+    }
+
+    /// Committed keys and values of `label`, ordered by key.
+    pub fn iter<'a>(&'a self, label: &str) -> impl Iterator<Item = (&'a [u8], &'a [u8])> + 'a {
+        self.entries
+            .get(label)
+            .into_iter()
+            .flat_map(|entries| entries.iter())
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+    }
+
+    /// Commit the staged entries as a new block with the timestamp `timestamp_ns`, which must not
+    /// be before the one of the previous block. The core has no clock, so the device provides the
+    /// timestamp. Does nothing if no entries are staged.
+    pub fn commit_block(&mut self, timestamp_ns: u64) -> Result<(), LedgerError> {
+        if self.next_block_entries.is_empty() {
+            return Ok(());
+        }
+        if timestamp_ns < self.last_timestamp_ns {
+            return Err(LedgerError::Other(format!(
+                "Block timestamp {} is before the timestamp {} of the previous block",
+                timestamp_ns, self.last_timestamp_ns
+            )));
+        }
+        let entries = core::mem::take(&mut self.next_block_entries);
+        let block = CoreBlock {
+            entries,
+            timestamp_ns,
+            parent_hash: self.chain_hash.clone(),
+            entry_hashes: None,
+        };
+        let mut raw_block = Vec::new();
+        raw_block.extend_from_slice(&(block.entries.len() as u32).to_le_bytes());
+        for entry in &block.entries {
+            raw_block.extend_from_slice(&entry.bytes());
+        }
+        raw_block.extend_from_slice(&timestamp_ns.to_le_bytes());
+        raw_block.extend_from_slice(&(block.parent_hash.len() as u32).to_le_bytes());
+        raw_block.extend_from_slice(&block.parent_hash);
+        let body = miniz_oxide::deflate::compress_to_vec_zlib(&raw_block, COMPRESSION_LEVEL);
+
+        // The body first and the header last, so an interrupted write never yields a valid block
+        let block_start_pos = self.next_block_start_pos;
+        let jump_bytes_next = BLOCK_HEADER_LEN as u32 + body.len() as u32;
+        let jump_bytes_prev = match self.last_block_start_pos {
+            Some(last_block_start_pos) => {
+                (last_block_start_pos as i64 - block_start_pos as i64) as i32
+            }
+            None => 0,
+        };
+        let mut header = [0u8; BLOCK_HEADER_LEN as usize];
+        header[0..4].copy_from_slice(&1u32.to_le_bytes());
+        header[4..8].copy_from_slice(&jump_bytes_prev.to_le_bytes());
+        header[8..12].copy_from_slice(&jump_bytes_next.to_le_bytes());
+        let result = self
+            .storage
+            .write(block_start_pos + BLOCK_HEADER_LEN, &body)
+            .and_then(|_| self.storage.write(block_start_pos, &header))
+            .and_then(|_| {
+                self.storage.write(
+                    block_start_pos + jump_bytes_next as u64,
+                    &[0u8; BLOCK_HEADER_LEN as usize],
+                )
+            })
+            .and_then(|_| self.storage.sync());
+        if let Err(err) = result {
+            self.next_block_entries = block.entries;
+            return Err(err);
+        }
+        self._apply_block(block_start_pos, jump_bytes_next, block);
+        Ok(())
+    }
+
+    pub fn get_blocks_count(&self) -> u64 {
+        self.blocks_count
+    }
+
+    /// Chain hash of the last block, or an empty hash if the ledger has no blocks.
+    pub fn get_latest_block_hash(&self) -> &[u8] {
+        &self.chain_hash
+    }
+
+    /// Storage offset of the first block.
+    pub fn get_data_start_pos(&self) -> u64 {
+        self.first_block_start_pos
+    }
+
+    pub fn get_next_block_start_pos(&self) -> u64 {
+        self.next_block_start_pos
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    fn _stage(&mut self, entry: CoreEntry) -> Result<(), LedgerError> {
+        if entry.label.is_empty() {
+            return Err(LedgerError::InvalidLabel("label is empty".to_string()));
+        }
+        self.next_block_entries
+            .retain(|staged| staged.label != entry.label || staged.key != entry.key);
+        self.next_block_entries.push(entry);
+        Ok(())
+    }
+
+    /// Update the index and the chain with `block`, committed at `block_start_pos`.
+    fn _apply_block(&mut self, block_start_pos: u64, jump_bytes_next: u32, block: CoreBlock) {
+        self.chain_hash = block.chain_hash();
+        self.last_timestamp_ns = block.timestamp_ns;
+        for entry in block.entries {
+            let entries = self.entries.entry(entry.label).or_default();
+            match entry.delete {
+                true => entries.remove(&entry.key),
+                false => entries.insert(entry.key, entry.value),
+            };
+        }
+        self.last_block_start_pos = Some(block_start_pos);
+        self.next_block_start_pos = block_start_pos + jump_bytes_next as u64;
+        self.blocks_count += 1;
+    }
+
+    /// Jump to the next block and the block at `pos`, or `None` at the end of the chain. The
+    /// parent hash of the block must match the chain hash of the previous block.
+    fn _read_block(&mut self, pos: u64) -> Result<Option<(u32, CoreBlock)>, LedgerError> {
+        if pos + BLOCK_HEADER_LEN > self.storage.size_bytes() {
+            return Ok(None);
+        }
+        let mut header = [0u8; BLOCK_HEADER_LEN as usize];
+        self.storage.read(pos, &mut header)?;
+        let version_field = u32_at(&header, 0);
+        let jump_bytes_next = u32_at(&header, 8);
+        let block_version = version_field & !BLOCK_VERSION_ENCRYPTED_FLAG;
+        match block_version {
+            0 if version_field == 0 => return Ok(None),
+            _ if version_field & BLOCK_VERSION_ENCRYPTED_FLAG != 0 => {
+                return Err(LedgerError::Other(format!(
+                    "The block at offset {} is encrypted, which the core does not support",
+                    pos
+                )))
+            }
+            1..=LATEST_BLOCK_VERSION => {}
+            _ => return Err(LedgerError::UnsupportedBlockVersion(block_version)),
+        }
+        if (jump_bytes_next as u64) < BLOCK_HEADER_LEN {
+            return Err(LedgerError::BlockCorrupted(format!(
+                "Invalid block length {} at offset {}",
+                jump_bytes_next, pos
+            )));
+        }
+        let mut body = vec![0u8; jump_bytes_next as usize - BLOCK_HEADER_LEN as usize];
+        self.storage.read(pos + BLOCK_HEADER_LEN, &mut body)?;
+        // Blocks of version 3 start with the uncompressed block summary, which the core skips
+        let summary_len = match block_version {
+            3 => u32_at(&header, 12) as usize,
+            _ => 0,
+        };
+        let compressed = body.get(summary_len..).ok_or_else(|| {
+            LedgerError::BlockCorrupted(format!("Block summary too long at offset {}", pos))
+        })?;
+        let raw_block = miniz_oxide::inflate::decompress_to_vec_zlib(compressed).map_err(|e| {
+            LedgerError::Serialization(format!("Invalid block body at offset {}: {}", pos, e))
+        })?;
+        let block = CoreBlock::deserialize(&raw_block, block_version)
+            .map_err(|e| LedgerError::BlockCorrupted(format!("{} at offset {}", e, pos)))?;
+        if block.parent_hash != self.chain_hash {
+            return Err(LedgerError::HashMismatch {
+                expected: self.chain_hash.clone(),
+                actual: block.parent_hash,
+                offset: pos,
+            });
+        }
+        Ok(Some((jump_bytes_next, block)))
+    }
+}
+
+/// Block of a `CoreLedger`.
+struct CoreBlock {
+    entries: Vec<CoreEntry>,
+    timestamp_ns: u64,
+    parent_hash: Vec<u8>,
+    /// The `BLOCK_FIELD_ENTRY_HASHES` of blocks of version 2 and later that have them.
+    entry_hashes: Option<Vec<u8>>,
+}
+
+impl CoreBlock {
+    /// Decode the decompressed borsh encoding of a block body of `version`, see `ledger_entry`.
+    fn deserialize(data: &[u8], version: u32) -> Result<Self, String> {
+        let mut reader = BorshReader { data };
+        let entries_count = reader.u32()?;
+        let mut entries = Vec::new();
+        for _ in 0..entries_count {
+            if reader.u8()? != 0 {
+                return Err("Unsupported entry version".to_string());
+            }
+            let label = String::from_utf8(reader.bytes_with_len()?.to_vec())
+                .map_err(|_| "Entry label is not UTF-8".to_string())?;
+            let key = reader.bytes_with_len()?.to_vec();
+            let value = reader.bytes_with_len()?.to_vec();
+            let delete = match reader.u8()? {
+                0 => false,
+                1 => true,
+                operation => return Err(format!("Invalid entry operation {}", operation)),
+            };
+            entries.push(CoreEntry {
+                label,
+                key,
+                value,
+                delete,
+            });
+        }
+        let timestamp_ns = reader.u64()?;
+        let parent_hash = reader.bytes_with_len()?.to_vec();
+        let mut entry_hashes = None;
+        if version >= 2 {
+            for _ in 0..reader.u32()? {
+                let tag = u16::from_le_bytes(reader.bytes(2)?.try_into().expect("2 bytes"));
+                let value = reader.bytes_with_len()?;
+                match tag {
+                    BLOCK_FIELD_PRUNED_CHAIN_HASH | BLOCK_FIELD_PRUNED_ENTRY_INDEXES => {
+                        return Err("Pruned blocks are not supported by the core".to_string())
+                    }
+                    BLOCK_FIELD_ENTRY_HASHES => entry_hashes = Some(value.to_vec()),
+                    _ => {}
+                }
+            }
+        }
+        let block = CoreBlock {
+            entries,
+            timestamp_ns,
+            parent_hash,
+            entry_hashes,
+        };
+        if let Some(entry_hashes) = &block.entry_hashes {
+            if *entry_hashes != block.computed_entry_hashes() {
+                return Err("Entry hashes do not match the entries".to_string());
+            }
+        }
+        Ok(block)
+    }
+
+    fn computed_entry_hashes(&self) -> Vec<u8> {
+        self.entries
+            .iter()
+            .flat_map(|entry| Sha256::digest(entry.bytes()))
+            .collect()
+    }
+
+    /// Chain hash of the block, of version 2 if it has entry hashes, and of version 1 otherwise,
+    /// see `hashing`.
+    fn chain_hash(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.parent_hash);
+        match &self.entry_hashes {
+            Some(entry_hashes) => hasher.update(entry_hashes),
+            None => {
+                for entry in &self.entries {
+                    hasher.update(entry.bytes());
+                }
+            }
+        }
+        hasher.update(self.timestamp_ns.to_le_bytes());
+        hasher.finalize().to_vec()
+    }
+}
+
+/// Reader of borsh-encoded little-endian integers and length-prefixed byte strings.
+struct BorshReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> BorshReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("Block body too short".to_string());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn bytes_with_len(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32_at(self.bytes(4)?, 0))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(
+            self.bytes(8)?.try_into().expect("8 bytes"),
+        ))
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn partition_table_entry(name: &[u8], start_lba: u64) -> [u8; PARTITION_TABLE_ENTRY_LEN] {
+    let mut entry = [0u8; PARTITION_TABLE_ENTRY_LEN];
+    entry[..name.len()].copy_from_slice(name);
+    entry[8..].copy_from_slice(&start_lba.to_le_bytes());
+    entry
+}
+
+/// Start of the data partition of the ledger in `storage`, or `None` if the storage is empty.
+fn read_partition_table(storage: &mut impl CoreStorage) -> Result<Option<u64>, LedgerError> {
+    let size_bytes = storage.size_bytes();
+    if size_bytes == 0 {
+        return Ok(None);
+    }
+    if size_bytes < PARTITION_TABLE_LEN as u64 {
+        return Err(LedgerError::Other(
+            "The storage is too small for a partition table".to_string(),
+        ));
+    }
+    let mut table = vec![0u8; PARTITION_TABLE_LEN];
+    storage.read(0, &mut table)?;
+    if table.iter().all(|byte| *byte == 0) {
+        return Ok(None);
+    }
+    if &table[..8] != PARTITION_TABLE_MAGIC {
+        return Err(LedgerError::Other(
+            "The storage does not start with a partition table".to_string(),
+        ));
+    }
+    let entry = |index: usize| &table[8 + index * PARTITION_TABLE_ENTRY_LEN..][..16];
+    // A compaction of `LedgerMap::prune_blocks` was interrupted, which only `LedgerMap` completes
+    if entry(PART_JOURNAL) != [0u8; PARTITION_TABLE_ENTRY_LEN] {
+        return Err(LedgerError::Other(
+            "The ledger has a pending compaction journal, open it with LedgerMap first".to_string(),
+        ));
+    }
+    Ok(Some(u64::from_le_bytes(
+        entry(PART_DATA)[8..].try_into().expect("8 bytes"),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LedgerMap;
+
+    #[test]
+    fn test_core_ledger() {
+        let mut ledger = CoreLedger::open(Vec::new()).unwrap();
+        ledger.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger.upsert("Label2", b"key1", b"value3").unwrap();
+        assert_eq!(ledger.get("Label1", b"key1"), Some(&b"value1"[..]));
+        assert_eq!(ledger.iter("Label1").count(), 0);
+        ledger.commit_block(1).unwrap();
+        ledger.delete("Label1", b"key1").unwrap();
+        assert_eq!(ledger.get("Label1", b"key1"), None);
+        ledger.commit_block(2).unwrap();
+        assert!(ledger.upsert("", b"key", b"value").is_err());
+        ledger.upsert("Label1", b"key3", b"value4").unwrap();
+        assert!(ledger.commit_block(1).is_err());
+        ledger.commit_block(3).unwrap();
+        let tip_hash = ledger.get_latest_block_hash().to_vec();
+
+        let ledger = CoreLedger::open(ledger.into_storage()).unwrap();
+        assert_eq!(ledger.get_blocks_count(), 3);
+        assert_eq!(ledger.get_latest_block_hash(), tip_hash);
+        assert_eq!(
+            ledger.iter("Label1").collect::<Vec<_>>(),
+            vec![
+                (&b"key2"[..], &b"value2"[..]),
+                (&b"key3"[..], &b"value4"[..])
+            ]
+        );
+        assert_eq!(ledger.get("Label2", b"key1"), Some(&b"value3"[..]));
+
+        // A corrupted block body fails the chain verification
+        let mut storage = ledger.into_storage();
+        let last = storage.len() - BLOCK_HEADER_LEN as usize - 1;
+        storage[last] ^= 0xff;
+        assert!(CoreLedger::open(storage).is_err());
+        assert!(CoreLedger::open(vec![1u8; PARTITION_TABLE_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_core_ledger_opened_by_ledger_map() {
+        let mut ledger = CoreLedger::open(Vec::new()).unwrap();
+        ledger.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger.commit_block(1).unwrap();
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        std::fs::write(&file_path, ledger.into_storage()).unwrap();
+
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path.clone())).unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 1);
+        assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), b"value1");
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        let tip_hash = ledger_map.get_latest_block_hash();
+        drop(ledger_map);
+
+        let ledger = CoreLedger::open(std::fs::read(&file_path).unwrap()).unwrap();
+        assert_eq!(ledger.get_blocks_count(), 2);
+        assert_eq!(ledger.get_latest_block_hash(), tip_hash);
+        assert_eq!(ledger.get("Label1", b"key2"), Some(&b"value2"[..]));
+    }
+}
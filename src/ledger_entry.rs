@@ -5,10 +5,40 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use flate2::write::ZlibEncoder;
 use flate2::{read::ZlibDecoder, Compression};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::io;
 
+/// Block version that new blocks are written with, unless configured otherwise.
+pub const LEDGER_BLOCK_VERSION: u32 = 1;
+/// Latest block version that this version of the library can read and write.
+pub const LATEST_BLOCK_VERSION: u32 = 3;
+/// Flag in the block version field of the header of a block with an encrypted body, see
+/// `encryption`. It is not part of the block version.
+pub const BLOCK_VERSION_ENCRYPTED_FLAG: u32 = 1 << 31;
+
+/// Tags of the well-known optional block fields. Except for `BLOCK_FIELD_ENTRY_HASHES` and
+/// `BLOCK_FIELD_PRUNED_ENTRY_INDEXES`, block fields are NOT covered by the chain hash, see
+/// `BlockField`.
+pub const BLOCK_FIELD_SIGNATURE: u16 = 1;
+pub const BLOCK_FIELD_COMPRESSION: u16 = 2;
+pub const BLOCK_FIELD_MERKLE_ROOT: u16 = 3;
+pub const BLOCK_FIELD_ANNOTATION: u16 = 4;
+/// Chain hash of the ledger tip before a block format migration, see `LedgerMap::migrate_format`.
+pub const BLOCK_FIELD_MIGRATION_TIP_HASH: u16 = 5;
+/// Chain hash of a pruned block whose chain hash is of version 1, and whose entries were all
+/// removed, see `LedgerMap::prune_blocks`. It is not computed from the block, see `hashing`.
+pub const BLOCK_FIELD_PRUNED_CHAIN_HASH: u16 = 6;
+/// SHA-256 hash of each entry of the block, concatenated in entry order. Unlike the other fields,
+/// it is part of the chain hash: the chain hash of the block is computed from the entry hashes,
+/// see `hashing`.
+pub const BLOCK_FIELD_ENTRY_HASHES: u16 = 7;
+/// Index of each entry kept in a pruned block among the entries of the original block, as u32
+/// little-endian, see `LedgerMap::prune_blocks`. The block keeps the `BLOCK_FIELD_ENTRY_HASHES`
+/// of all original entries, so that its chain hash is still computed, see `hashing`.
+pub const BLOCK_FIELD_PRUNED_ENTRY_INDEXES: u16 = 8;
+
 /// Enum defining the different operations that can be performed on entries.
-#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Operation {
     Upsert,
     Delete,
@@ -104,8 +134,16 @@ pub enum LedgerBlockHeader {
 
 impl LedgerBlockHeader {
     pub fn new(jump_bytes_prev: i32, jump_bytes_next: u32) -> Self {
+        Self::new_with_version(LEDGER_BLOCK_VERSION, jump_bytes_prev, jump_bytes_next)
+    }
+
+    pub fn new_with_version(
+        block_version: u32,
+        jump_bytes_prev: i32,
+        jump_bytes_next: u32,
+    ) -> Self {
         LedgerBlockHeader::V1(LedgerBlockHeaderV1 {
-            block_version: 1,
+            block_version,
             jump_bytes_prev,
             jump_bytes_next,
             reserved: 0,
@@ -118,7 +156,28 @@ impl LedgerBlockHeader {
 
     pub fn block_version(&self) -> u32 {
         match self {
-            LedgerBlockHeader::V1(header) => header.block_version,
+            LedgerBlockHeader::V1(header) => header.block_version & !BLOCK_VERSION_ENCRYPTED_FLAG,
+        }
+    }
+
+    /// Whether the block body is encrypted, see `encryption`.
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            LedgerBlockHeader::V1(header) => {
+                header.block_version & BLOCK_VERSION_ENCRYPTED_FLAG != 0
+            }
+        }
+    }
+
+    pub fn with_encrypted(self, encrypted: bool) -> Self {
+        match self {
+            LedgerBlockHeader::V1(header) => LedgerBlockHeader::V1(LedgerBlockHeaderV1 {
+                block_version: match encrypted {
+                    true => header.block_version | BLOCK_VERSION_ENCRYPTED_FLAG,
+                    false => header.block_version & !BLOCK_VERSION_ENCRYPTED_FLAG,
+                },
+                ..header
+            }),
         }
     }
 
@@ -134,6 +193,26 @@ impl LedgerBlockHeader {
         }
     }
 
+    /// Length of the `BlockSummary` in front of the block body, which blocks of version 3 and
+    /// later record in the last header field. `None` for older blocks, which have no summary, and
+    /// for encrypted blocks, whose summary can only be read after decrypting the body.
+    pub fn summary_len(&self) -> Option<u32> {
+        match self {
+            _ if self.is_encrypted() => None,
+            LedgerBlockHeader::V1(header) if header.block_version >= 3 => Some(header.reserved),
+            LedgerBlockHeader::V1(_) => None,
+        }
+    }
+
+    pub fn with_summary_len(self, summary_len: u32) -> Self {
+        match self {
+            LedgerBlockHeader::V1(header) => LedgerBlockHeader::V1(LedgerBlockHeaderV1 {
+                reserved: summary_len,
+                ..header
+            }),
+        }
+    }
+
     /// Block header is always serialized to 4x 32-bit integers
     pub fn serialize(&self) -> anyhow::Result<Vec<u8>> {
         match self {
@@ -149,17 +228,29 @@ impl LedgerBlockHeader {
         }
     }
 
+    /// All block versions so far share the same header layout, and differ only in the block
+    /// body, which `LedgerBlock::deserialize` decodes based on the block version. Since version
+    /// 3, the last header field holds the length of the block summary, see `summary_len`.
+    /// Upgrade path: a new block body format gets a new block version and a new `LedgerBlock`
+    /// variant, and is accepted here by raising `LATEST_BLOCK_VERSION`. A new header layout
+    /// would also need a new `LedgerBlockHeader` variant, selected here by the block version.
+    /// Since version 2, optional data should be added as `BlockField`s instead of new versions.
+    /// The version field may also have the `BLOCK_VERSION_ENCRYPTED_FLAG` set.
     pub fn deserialize(data: &[u8]) -> Result<Self, LedgerError> {
-        let mut bytes = [0u8; 16];
-        bytes.copy_from_slice(&data[0..16]);
-        let block_version = u32::from_le_bytes(bytes[0..4].try_into()?);
+        let read_le_bytes = |offset: usize| -> Result<[u8; 4], LedgerError> {
+            data.get(offset..offset + 4)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| LedgerError::BlockCorrupted("Block header too short".to_string()))
+        };
+        let version_field = u32::from_le_bytes(read_le_bytes(0)?);
+        let block_version = version_field & !BLOCK_VERSION_ENCRYPTED_FLAG;
         match block_version {
-            0 => Err(LedgerError::BlockEmpty),
-            1 => Ok(LedgerBlockHeader::V1(LedgerBlockHeaderV1 {
-                block_version,
-                jump_bytes_prev: i32::from_le_bytes(bytes[4..8].try_into()?),
-                jump_bytes_next: u32::from_le_bytes(bytes[8..12].try_into()?),
-                reserved: u32::from_le_bytes(bytes[12..16].try_into()?),
+            0 if version_field == 0 => Err(LedgerError::BlockEmpty),
+            1..=LATEST_BLOCK_VERSION => Ok(LedgerBlockHeader::V1(LedgerBlockHeaderV1 {
+                block_version: version_field,
+                jump_bytes_prev: i32::from_le_bytes(read_le_bytes(4)?),
+                jump_bytes_next: u32::from_le_bytes(read_le_bytes(8)?),
+                reserved: u32::from_le_bytes(read_le_bytes(12)?),
             })),
             _ => Err(LedgerError::BlockCorrupted(format!(
                 "Unsupported block version: {}",
@@ -181,8 +272,11 @@ impl std::fmt::Display for LedgerBlockHeaderV1 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "block_version: {}, jump_bytes_prev: {}, jump_bytes_next: {}",
-            self.block_version, self.jump_bytes_prev, self.jump_bytes_next
+            "block_version: {}, jump_bytes_prev: {}, jump_bytes_next: {}, encrypted: {}",
+            self.block_version & !BLOCK_VERSION_ENCRYPTED_FLAG,
+            self.jump_bytes_prev,
+            self.jump_bytes_next,
+            self.block_version & BLOCK_VERSION_ENCRYPTED_FLAG != 0
         )
     }
 }
@@ -211,15 +305,142 @@ impl LedgerBlockV1 {
     }
 
     pub fn serialize(&self) -> io::Result<Vec<u8>> {
-        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
-        borsh::to_writer(&mut e, self)?;
-        e.finish()
+        self.serialize_into(Vec::new())
+    }
+
+    /// Serialize (and compress) the block directly into `writer`, without materializing
+    /// the full serialized block in memory. Returns the writer once the stream is finished.
+    pub fn serialize_into<W: io::Write>(&self, writer: W) -> io::Result<W> {
+        serialize_compressed_into(self, writer)
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, LedgerError> {
+        deserialize_compressed(data)
+    }
+
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+fn serialize_compressed_into<T: BorshSerialize, W: io::Write>(
+    value: &T,
+    writer: W,
+) -> io::Result<W> {
+    let mut e = ZlibEncoder::new(writer, Compression::default());
+    borsh::to_writer(&mut e, value)?;
+    e.finish()
+}
+
+fn deserialize_compressed<T: BorshDeserialize>(data: &[u8]) -> Result<T, LedgerError> {
+    let mut e = ZlibDecoder::new(data);
+    borsh::de::from_reader(&mut e).map_err(|e| LedgerError::Serialization(e.to_string()))
+}
+
+/// Optional block field, stored in tag-length-value form in blocks of version 2 and later.
+/// Readers keep fields with unknown tags but otherwise ignore them, so new kinds of block data
+/// (signatures, merkle roots, annotations, ...) do not require a new block version.
+///
+/// # Security
+///
+/// Block fields are NOT authenticated: they are not part of the chain hash, so that e.g.
+/// signatures of the block can be attached, and anyone who can write the storage can add,
+/// change or remove them without breaking the chain. The only exceptions are
+/// `BLOCK_FIELD_ENTRY_HASHES` and `BLOCK_FIELD_PRUNED_ENTRY_INDEXES`, which are checked against
+/// the chain hash, see `hashing`. Do not trust the value of any other field, e.g. an annotation
+/// or a merkle root, unless it carries its own proof, e.g. a signature over the chain hash.
+#[derive(BorshSerialize, BorshDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct BlockField {
+    pub tag: u16,
+    pub value: Vec<u8>,
+}
+
+impl BlockField {
+    pub fn new<V: AsRef<[u8]>>(tag: u16, value: V) -> Self {
+        BlockField {
+            tag,
+            value: value.as_ref().to_vec(),
+        }
+    }
+}
+
+/// Summary of the entries of a block: their number, and the labels they touch, sorted.
+/// Blocks of version 3 and later store it uncompressed in front of the compressed block body,
+/// so that scanners can skip blocks of other labels without decompressing them.
+#[derive(BorshSerialize, BorshDeserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct BlockSummary {
+    pub entry_count: u32,
+    pub labels: Vec<String>,
+}
+
+impl BlockSummary {
+    pub fn from_entries(entries: &[LedgerEntry]) -> Self {
+        let labels = entries
+            .iter()
+            .map(|entry| entry.label())
+            .collect::<BTreeSet<_>>();
+        BlockSummary {
+            entry_count: entries.len() as u32,
+            labels: labels.into_iter().map(str::to_string).collect(),
+        }
+    }
+
+    pub fn touches_label(&self, label: &str) -> bool {
+        self.labels
+            .binary_search_by(|summary_label| summary_label.as_str().cmp(label))
+            .is_ok()
+    }
+
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        borsh::to_vec(self)
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, LedgerError> {
+        BlockSummary::try_from_slice(data)
+            .map_err(|e| LedgerError::BlockCorrupted(format!("Invalid block summary: {}", e)))
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LedgerBlockV2 {
+    entries: Vec<LedgerEntry>,
+    timestamp: u64,
+    parent_hash: Vec<u8>,
+    fields: Vec<BlockField>,
+    #[borsh(skip)]
+    offset: u64,
+}
+
+impl LedgerBlockV2 {
+    pub fn new(
+        entries: Vec<LedgerEntry>,
+        timestamp: u64,
+        parent_hash: Vec<u8>,
+        fields: Vec<BlockField>,
+    ) -> Self {
+        LedgerBlockV2 {
+            entries,
+            timestamp,
+            parent_hash,
+            fields,
+            offset: 0,
+        }
+    }
+
+    pub fn with_offset(self, offset: u64) -> Self {
+        LedgerBlockV2 { offset, ..self }
+    }
+
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        self.serialize_into(Vec::new())
+    }
+
+    pub fn serialize_into<W: io::Write>(&self, writer: W) -> io::Result<W> {
+        serialize_compressed_into(self, writer)
     }
 
     pub fn deserialize(data: &[u8]) -> Result<Self, LedgerError> {
-        let mut e = ZlibDecoder::new(data);
-        let v = borsh::de::from_reader(&mut e)?;
-        Ok(v)
+        deserialize_compressed(data)
     }
 
     pub fn get_offset(&self) -> u64 {
@@ -230,6 +451,9 @@ impl LedgerBlockV1 {
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum LedgerBlock {
     V1(LedgerBlockV1),
+    V2(LedgerBlockV2),
+    /// Body of version 2, preceded by the uncompressed `BlockSummary` of the block.
+    V3(LedgerBlockV2),
 }
 
 impl LedgerBlock {
@@ -237,52 +461,233 @@ impl LedgerBlock {
         LedgerBlock::V1(LedgerBlockV1::new(entries, timestamp, parent_hash))
     }
 
+    pub fn new_v2(
+        entries: Vec<LedgerEntry>,
+        timestamp: u64,
+        parent_hash: Vec<u8>,
+        fields: Vec<BlockField>,
+    ) -> Self {
+        LedgerBlock::V2(LedgerBlockV2::new(entries, timestamp, parent_hash, fields))
+    }
+
+    /// Create a block of the given version, without optional fields.
+    pub fn new_with_version(
+        version: u32,
+        entries: Vec<LedgerEntry>,
+        timestamp: u64,
+        parent_hash: Vec<u8>,
+    ) -> Result<Self, LedgerError> {
+        match version {
+            1 => Ok(Self::new(entries, timestamp, parent_hash)),
+            2 => Ok(Self::new_v2(entries, timestamp, parent_hash, Vec::new())),
+            3 => Ok(LedgerBlock::V3(LedgerBlockV2::new(
+                entries,
+                timestamp,
+                parent_hash,
+                Vec::new(),
+            ))),
+            _ => Err(LedgerError::UnsupportedBlockVersion(version)),
+        }
+    }
+
+    /// Convert the block to the given block version, keeping its entries and optional fields.
+    /// Fails if the target version cannot hold the optional fields of the block.
+    pub fn into_version(self, version: u32) -> Result<Self, LedgerError> {
+        if self.version() == version {
+            return Ok(self);
+        }
+        let block = match self {
+            LedgerBlock::V1(block) => LedgerBlockV2 {
+                entries: block.entries,
+                timestamp: block.timestamp,
+                parent_hash: block.parent_hash,
+                fields: Vec::new(),
+                offset: block.offset,
+            },
+            LedgerBlock::V2(block) | LedgerBlock::V3(block) => block,
+        };
+        match version {
+            1 if block.fields.is_empty() => Ok(LedgerBlock::V1(LedgerBlockV1 {
+                entries: block.entries,
+                timestamp: block.timestamp,
+                parent_hash: block.parent_hash,
+                offset: block.offset,
+            })),
+            2 => Ok(LedgerBlock::V2(block)),
+            3 => Ok(LedgerBlock::V3(block)),
+            _ => Err(LedgerError::UnsupportedBlockVersion(version)),
+        }
+    }
+
+    /// Add an optional field to the block. Blocks of version 1 cannot hold optional fields.
+    pub fn add_field(&mut self, field: BlockField) -> Result<(), LedgerError> {
+        match self {
+            LedgerBlock::V1(_) => Err(LedgerError::UnsupportedBlockVersion(1)),
+            LedgerBlock::V2(block) | LedgerBlock::V3(block) => {
+                block.fields.push(field);
+                Ok(())
+            }
+        }
+    }
+
     pub fn with_offset(self, offset: u64) -> Self {
         match self {
             LedgerBlock::V1(block) => LedgerBlock::V1(block.with_offset(offset)),
+            LedgerBlock::V2(block) => LedgerBlock::V2(block.with_offset(offset)),
+            LedgerBlock::V3(block) => LedgerBlock::V3(block.with_offset(offset)),
         }
     }
 
     pub fn get_offset(&self) -> u64 {
         match self {
             LedgerBlock::V1(block) => block.get_offset(),
+            LedgerBlock::V2(block) | LedgerBlock::V3(block) => block.get_offset(),
         }
     }
 
     pub fn entries(&self) -> &[LedgerEntry] {
         match self {
             LedgerBlock::V1(block) => &block.entries,
+            LedgerBlock::V2(block) | LedgerBlock::V3(block) => &block.entries,
+        }
+    }
+
+    /// Optional fields of the block. Blocks of version 1 have no fields.
+    pub fn fields(&self) -> &[BlockField] {
+        match self {
+            LedgerBlock::V1(_) => &[],
+            LedgerBlock::V2(block) | LedgerBlock::V3(block) => &block.fields,
+        }
+    }
+
+    /// Value of the first optional field with the given tag, if any.
+    pub fn field(&self, tag: u16) -> Option<&[u8]> {
+        self.fields()
+            .iter()
+            .find(|field| field.tag == tag)
+            .map(|field| field.value.as_slice())
+    }
+
+    /// Chain hash recorded in a pruned block without entries, which can no longer be computed.
+    pub fn pruned_chain_hash(&self) -> Option<&[u8]> {
+        self.field(BLOCK_FIELD_PRUNED_CHAIN_HASH)
+    }
+
+    /// Indexes of the entries of a pruned block among the entries of the original block, see
+    /// `BLOCK_FIELD_PRUNED_ENTRY_INDEXES`.
+    pub fn pruned_entry_indexes(&self) -> Option<Vec<u32>> {
+        self.field(BLOCK_FIELD_PRUNED_ENTRY_INDEXES).map(|indexes| {
+            indexes
+                .chunks_exact(4)
+                .map(|index| u32::from_le_bytes(index.try_into().expect("Chunk of 4 bytes")))
+                .collect()
+        })
+    }
+
+    pub fn is_pruned(&self) -> bool {
+        self.pruned_chain_hash().is_some() || self.field(BLOCK_FIELD_PRUNED_ENTRY_INDEXES).is_some()
+    }
+
+    /// Hashes of the entries of the block, if the block has them, see `BLOCK_FIELD_ENTRY_HASHES`.
+    pub fn entry_hashes(&self) -> Option<Vec<[u8; 32]>> {
+        self.field(BLOCK_FIELD_ENTRY_HASHES).map(|hashes| {
+            hashes
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().expect("Chunk of 32 bytes"))
+                .collect()
+        })
+    }
+
+    /// Summary of the entries of the block, see `BlockSummary`.
+    pub fn summary(&self) -> BlockSummary {
+        BlockSummary::from_entries(self.entries())
+    }
+
+    /// Length of the serialized summary in front of the block body, for blocks of version 3
+    /// and later.
+    pub fn summary_len(&self) -> io::Result<Option<u32>> {
+        match self {
+            LedgerBlock::V1(_) | LedgerBlock::V2(_) => Ok(None),
+            LedgerBlock::V3(_) => Ok(Some(self.summary().serialize()?.len() as u32)),
         }
     }
 
     pub fn serialize(&self) -> io::Result<Vec<u8>> {
         match self {
             LedgerBlock::V1(block) => block.serialize(),
+            LedgerBlock::V2(block) => block.serialize(),
+            LedgerBlock::V3(_) => self.serialize_into(Vec::new()),
+        }
+    }
+
+    pub fn serialize_into<W: io::Write>(&self, mut writer: W) -> io::Result<W> {
+        match self {
+            LedgerBlock::V1(block) => block.serialize_into(writer),
+            LedgerBlock::V2(block) => block.serialize_into(writer),
+            LedgerBlock::V3(block) => {
+                writer.write_all(&self.summary().serialize()?)?;
+                block.serialize_into(writer)
+            }
         }
     }
 
     pub fn version(&self) -> u32 {
         match self {
             LedgerBlock::V1(_) => 1,
+            LedgerBlock::V2(_) => 2,
+            LedgerBlock::V3(_) => 3,
         }
     }
 
     pub fn deserialize(data: &[u8], version: u32) -> Result<Self, LedgerError> {
         match version {
             1 => Ok(LedgerBlock::V1(LedgerBlockV1::deserialize(data)?)),
+            2 => Ok(LedgerBlock::V2(LedgerBlockV2::deserialize(data)?)),
+            3 => {
+                let mut data = data;
+                let summary =
+                    <BlockSummary as BorshDeserialize>::deserialize(&mut data).map_err(|e| {
+                        LedgerError::BlockCorrupted(format!("Invalid block summary: {}", e))
+                    })?;
+                let block = LedgerBlockV2::deserialize(data)?;
+                // The summary is not part of the chain hash, so it must match the entries
+                if summary != BlockSummary::from_entries(&block.entries) {
+                    return Err(LedgerError::BlockCorrupted(
+                        "Block summary does not match the block entries".to_string(),
+                    ));
+                }
+                Ok(LedgerBlock::V3(block))
+            }
             _ => Err(LedgerError::UnsupportedBlockVersion(version)),
         }
     }
 
+    /// Length of the serialized block of the given version at the start of `data`, which may be
+    /// followed by padding, e.g. after an interrupted `LedgerMap::prune_blocks`.
+    pub fn serialized_len(data: &[u8], version: u32) -> Result<usize, LedgerError> {
+        let mut body = data;
+        if version >= 3 {
+            <BlockSummary as BorshDeserialize>::deserialize(&mut body).map_err(|e| {
+                LedgerError::BlockCorrupted(format!("Invalid block summary: {}", e))
+            })?;
+        }
+        let mut decoder = ZlibDecoder::new(body);
+        io::copy(&mut decoder, &mut io::sink())
+            .map_err(|e| LedgerError::Serialization(e.to_string()))?;
+        Ok(data.len() - body.len() + decoder.total_in() as usize)
+    }
+
     pub fn timestamp(&self) -> u64 {
         match self {
             LedgerBlock::V1(block) => block.timestamp,
+            LedgerBlock::V2(block) | LedgerBlock::V3(block) => block.timestamp,
         }
     }
 
     pub fn parent_hash(&self) -> &[u8] {
         match self {
             LedgerBlock::V1(block) => &block.parent_hash,
+            LedgerBlock::V2(block) | LedgerBlock::V3(block) => &block.parent_hash,
         }
     }
 }
@@ -324,6 +729,115 @@ mod tests {
         assert_eq!(entry.operation(), Operation::Upsert);
     }
 
+    #[test]
+    fn test_block_serialize_into_matches_serialize() {
+        let entries = (0..100).map(create_dummy_ledger_entry).collect::<Vec<_>>();
+        let block = LedgerBlock::new(entries, 42, vec![1, 2, 3]);
+
+        let streamed = block.serialize_into(Vec::new()).unwrap();
+        assert_eq!(streamed, block.serialize().unwrap());
+        assert_eq!(
+            LedgerBlock::deserialize(&streamed, block.version()).unwrap(),
+            block
+        );
+    }
+
+    #[test]
+    fn test_block_serialized_len_with_padding() {
+        let entries = (0..10).map(create_dummy_ledger_entry).collect::<Vec<_>>();
+        for version in 1..=LATEST_BLOCK_VERSION {
+            let block =
+                LedgerBlock::new_with_version(version, entries.clone(), 42, vec![1, 2, 3]).unwrap();
+            let mut bytes = block.serialize().unwrap();
+            let len = bytes.len();
+            bytes.extend_from_slice(&[0xab; 100]);
+            assert_eq!(LedgerBlock::serialized_len(&bytes, version), Ok(len));
+            assert_eq!(LedgerBlock::deserialize(&bytes, version).unwrap(), block);
+        }
+    }
+
+    #[test]
+    fn test_block_v2_fields() {
+        let entries = (0..10).map(create_dummy_ledger_entry).collect::<Vec<_>>();
+        let fields = vec![
+            BlockField::new(BLOCK_FIELD_ANNOTATION, b"release 1.2"),
+            BlockField::new(0xbeef, b"unknown field"),
+        ];
+        let block = LedgerBlock::new_v2(entries.clone(), 42, vec![1, 2, 3], fields.clone());
+        assert_eq!(block.version(), 2);
+        assert_eq!(
+            block.field(BLOCK_FIELD_ANNOTATION),
+            Some(&b"release 1.2"[..])
+        );
+        assert_eq!(block.field(BLOCK_FIELD_SIGNATURE), None);
+
+        let decoded = LedgerBlock::deserialize(&block.serialize().unwrap(), 2).unwrap();
+        assert_eq!(decoded, block);
+        assert_eq!(decoded.fields(), fields.as_slice());
+        assert_eq!(decoded.entries(), entries.as_slice());
+
+        let block_v1 = LedgerBlock::new_with_version(1, entries, 42, vec![1, 2, 3]).unwrap();
+        assert!(block_v1.fields().is_empty());
+        assert_eq!(
+            LedgerBlock::new_with_version(LATEST_BLOCK_VERSION + 1, vec![], 0, vec![]),
+            Err(LedgerError::UnsupportedBlockVersion(
+                LATEST_BLOCK_VERSION + 1
+            ))
+        );
+        assert_eq!(
+            LedgerBlockHeader::new_with_version(2, 0, 16).block_version(),
+            LedgerBlockHeader::deserialize(
+                &LedgerBlockHeader::new_with_version(2, 0, 16)
+                    .serialize()
+                    .unwrap()
+            )
+            .unwrap()
+            .block_version()
+        );
+    }
+
+    #[test]
+    fn test_block_v3_summary() {
+        let mut entries = (0..10).map(create_dummy_ledger_entry).collect::<Vec<_>>();
+        entries.push(LedgerEntry::new("a_label", b"key", b"", Operation::Delete));
+        let mut block =
+            LedgerBlock::new_with_version(3, entries.clone(), 42, vec![1, 2, 3]).unwrap();
+        block
+            .add_field(BlockField::new(BLOCK_FIELD_ANNOTATION, b"release 1.3"))
+            .unwrap();
+        let summary = block.summary();
+        assert_eq!(summary.entry_count, 11);
+        assert_eq!(summary.labels, vec!["a_label", "test_label"]);
+        assert!(summary.touches_label("test_label"));
+        assert!(!summary.touches_label("other_label"));
+
+        // The summary is stored uncompressed in front of the body
+        let bytes = block.serialize().unwrap();
+        let summary_len = block.summary_len().unwrap().unwrap() as usize;
+        assert_eq!(
+            BlockSummary::deserialize(&bytes[..summary_len]).unwrap(),
+            summary
+        );
+        assert_eq!(LedgerBlock::deserialize(&bytes, 3).unwrap(), block);
+        assert_eq!(
+            block.clone().into_version(2).unwrap().entries(),
+            entries.as_slice()
+        );
+
+        // A summary that does not match the entries is rejected
+        let mut bytes = bytes;
+        bytes[0] ^= 1;
+        assert!(matches!(
+            LedgerBlock::deserialize(&bytes, 3),
+            Err(LedgerError::BlockCorrupted(_))
+        ));
+
+        let header = LedgerBlockHeader::new_with_version(3, 0, 100).with_summary_len(17);
+        let header = LedgerBlockHeader::deserialize(&header.serialize().unwrap()).unwrap();
+        assert_eq!(header.summary_len(), Some(17));
+        assert_eq!(LedgerBlockHeader::new(0, 100).summary_len(), None);
+    }
+
     #[test]
     fn test_operation_enum() {
         assert_eq!(Operation::Upsert as u8, 0);
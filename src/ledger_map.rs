@@ -1,27 +1,562 @@
+use crate::access::{
+    AccessController, AccessOperation, AccessRequest, LabelPolicy, PrincipalSource,
+};
+use crate::anchor::{Anchor, AnchorPoint};
+use crate::checkpoint::{Checkpoint, CheckpointSignature, CheckpointSigner, SignatureVerifier};
+use crate::cold_storage::ColdStorage;
+#[cfg(feature = "encryption")]
+use crate::encryption::{self, EncryptionKeys};
 use crate::errors::LedgerError;
+use crate::hashing;
+use crate::label_index::{LabelIndex, LabelIndexKind};
+use crate::label_stats::{LabelStats, LabelStatsIndex};
 use crate::ledger_entry::{
-    EntryKey, EntryValue, LedgerBlock, LedgerBlockHeader, LedgerEntry, Operation,
+    BlockField, BlockSummary, EntryKey, EntryValue, LedgerBlock, LedgerBlockHeader, LedgerEntry,
+    Operation, BLOCK_FIELD_ENTRY_HASHES, BLOCK_FIELD_MIGRATION_TIP_HASH,
+    BLOCK_FIELD_PRUNED_CHAIN_HASH, BLOCK_FIELD_PRUNED_ENTRY_INDEXES, LATEST_BLOCK_VERSION,
+    LEDGER_BLOCK_VERSION,
 };
 use crate::metadata::Metadata;
-use crate::partition_table;
+use crate::partition_table::{self, PartitionTable};
 use crate::platform_specific::{
-    persistent_storage_read, persistent_storage_size_bytes, persistent_storage_write,
+    persistent_storage_read, persistent_storage_size_bytes, persistent_storage_sync,
+    persistent_storage_write,
+};
+use crate::proof::{ProofBlob, ProofBlock, PROOF_BLOB_VERSION};
+use crate::query::Query;
+use crate::scan::{Scan, ScanHandle};
+use crate::secondary_index::{IndexExtractor, IndexKey, SecondaryIndex};
+use crate::snapshot::{Snapshot, SnapshotPartition, SnapshotSource, SNAPSHOT_FORMAT_VERSION};
+use crate::state_proof::{
+    LiveState, NonInclusionProof, StateDigests, StateLeaf, StateLeafProof, StateTree, SyncPlan,
+    NON_INCLUSION_PROOF_VERSION,
 };
-use crate::{debug, info, warn};
+use crate::value_reader::ValueReader;
+use crate::value_writer::ValueWriter;
+use crate::{debug, error, info, warn};
 use crate::{platform_specific, AHashSet};
 use anyhow::Result;
-use borsh::to_vec;
-use indexmap::IndexMap;
+use borsh::{to_vec, BorshDeserialize, BorshSerialize};
+use indexmap::{IndexMap, IndexSet};
 use sha2::Digest;
-use std::{cell::RefCell, mem::size_of};
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Default maximum size of an entry key, in bytes.
+pub const DEFAULT_MAX_KEY_SIZE_BYTES: usize = 64 * 1024;
+/// Default maximum size of an entry value, in bytes.
+pub const DEFAULT_MAX_VALUE_SIZE_BYTES: usize = 16 * 1024 * 1024;
+/// Default size of the chunks of the values stored with `LedgerMap::upsert_chunked`, in bytes.
+pub const DEFAULT_CHUNK_SIZE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Maximum size of a label, in bytes.
+pub const MAX_LABEL_SIZE_BYTES: usize = 256;
+/// Labels with this prefix are reserved for entries that the ledger itself writes,
+/// such as checkpoints and configuration.
+pub const RESERVED_LABEL_PREFIX: &str = "__ledger/";
+
+/// Label of the genesis block entry, which holds the `LedgerInfo` under `GENESIS_INFO_KEY`.
+pub const GENESIS_LABEL: &str = "__ledger/genesis";
+pub const GENESIS_INFO_KEY: &[u8] = b"info";
+/// Hash algorithm used for the block chain hashes.
+pub const HASH_ALGORITHM: &str = "sha256";
+/// Label of the entries that record label renames, see `LedgerMap::rename_label`. The key of a
+/// rename record is the old label, and the value is the new label.
+pub const RENAME_LABEL: &str = "__ledger/rename";
+/// Label of the entries that record merges, see `LedgerMap::merge_from`. The key of a merge
+/// record is the tip hash of the merged ledger, and the value is the borsh-encoded `MergeRecord`.
+pub const MERGE_LABEL: &str = "__ledger/merge";
+/// Label of the entries that record quorum checkpoints, see `LedgerMap::set_checkpoint_signers`.
+/// The key of a checkpoint record is the number of blocks before the checkpoint block as u64 LE,
+/// and the value is the borsh-encoded `Checkpoint`.
+pub const CHECKPOINT_LABEL: &str = "__ledger/checkpoint";
+/// Label of the entries that record label policies, see `LedgerMap::set_label_policy`. The key of
+/// a policy record is the label, and the value is the borsh-encoded `LabelPolicy`. A delete of
+/// the record removes the policy.
+pub const POLICY_LABEL: &str = "__ledger/policy";
+/// Label of the chunks of the values stored with `LedgerMap::upsert_chunked`. Each chunk is in a
+/// block of its own. The key of a chunk is the borsh-encoded `(label, key, chunk_index)` of the
+/// entry it belongs to, and the value is the chunk.
+pub const CHUNK_LABEL: &str = "__ledger/chunk";
+/// Label of the entries that record which entries hold a `ChunkedValue`, see
+/// `LedgerMap::upsert_chunked`. The key of a record is the borsh-encoded `(label, key)` of the
+/// entry, and the value is empty. A delete of the record marks the entry as no longer chunked.
+pub const CHUNKED_LABEL: &str = "__ledger/chunked";
+/// Label of the blobs of the content-addressed blob store, see `LedgerMap::put_blob`. The key of
+/// a blob is its SHA-256 hash, and the value is the borsh-encoded `ChunkedValue` of its chunks,
+/// which are stored under `CHUNK_LABEL`.
+pub const BLOB_LABEL: &str = "__ledger/blob";
+/// Label of the entries that record which entries hold the hash of a blob instead of a value,
+/// see `LedgerMap::upsert_blob_ref`. The key of a record is the borsh-encoded `(label, key)` of
+/// the entry, and the value is the hash of the blob. A delete of the record marks the entry as a
+/// plain value.
+pub const BLOB_REF_LABEL: &str = "__ledger/blob_ref";
+
+/// Identity and configuration of a ledger, recorded in its genesis block.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LedgerInfo {
+    /// Unique identifier of the ledger, formatted as a UUID.
+    pub ledger_id: String,
+    pub created_at_ns: u64,
+    /// Block version the ledger was created with.
+    pub format_version: u32,
+    pub hash_algorithm: String,
+    /// Application-defined metadata.
+    pub metadata: BTreeMap<String, Vec<u8>>,
+}
+
+/// Separator of the segments of hierarchical labels, such as `users/eu/profile`.
+pub const LABEL_SEGMENT_SEPARATOR: char = '/';
+
+/// Check that `label` can be used for user entries: it must be non-empty, at most
+/// `MAX_LABEL_SIZE_BYTES` long, free of control characters, and outside of the reserved
+/// namespace. Labels that are not in a ledger yet must also be free of empty segments, see
+/// `validate_new_label` and `LedgerMap::validate_label`.
+pub fn validate_label(label: &str) -> Result<(), LedgerError> {
+    if label.is_empty() {
+        return Err(LedgerError::InvalidLabel("label is empty".to_string()));
+    }
+    if label.len() > MAX_LABEL_SIZE_BYTES {
+        return Err(LedgerError::InvalidLabel(format!(
+            "label is {} bytes long, maximum is {} bytes",
+            label.len(),
+            MAX_LABEL_SIZE_BYTES
+        )));
+    }
+    if label.chars().any(char::is_control) {
+        return Err(LedgerError::InvalidLabel(format!(
+            "label {:?} contains control characters",
+            label
+        )));
+    }
+    if label.starts_with(RESERVED_LABEL_PREFIX) {
+        return Err(LedgerError::InvalidLabel(format!(
+            "label {:?} uses the reserved prefix {}",
+            label, RESERVED_LABEL_PREFIX
+        )));
+    }
+    Ok(())
+}
+
+/// Check that `label` can be used for the first entries of a label, as `validate_label`, and
+/// that it has no empty segments, e.g. `users//eu` or `users/`. Labels with empty segments that
+/// were committed before segments were introduced can still be used, see
+/// `LedgerMap::validate_label`.
+pub fn validate_new_label(label: &str) -> Result<(), LedgerError> {
+    validate_label(label)?;
+    if label.split(LABEL_SEGMENT_SEPARATOR).any(str::is_empty) {
+        return Err(LedgerError::InvalidLabel(format!(
+            "label {:?} contains an empty segment",
+            label
+        )));
+    }
+    Ok(())
+}
+
+/// Check whether `label` matches the glob `pattern`. Patterns are matched segment by segment:
+/// `*` matches any characters within a segment, and a `**` segment matches any number of
+/// segments, e.g. `users/*` matches `users/eu` but not `users/eu/profile`, and `users/**`
+/// matches both.
+pub fn label_matches(pattern: &str, label: &str) -> bool {
+    /// Match `text` against `pattern`, whose `is_star` elements match any number of elements of
+    /// `text`, and whose other elements match a single element with `matches`. Instead of trying
+    /// every split recursively, a mismatch only moves back to the last star, which then matches
+    /// one more element, so this takes `O(pattern.len() * text.len())` steps at most.
+    fn wildcard_match<P, T>(
+        pattern: &[P],
+        text: &[T],
+        is_star: impl Fn(&P) -> bool,
+        matches: impl Fn(&P, &T) -> bool,
+    ) -> bool {
+        let (mut p, mut t) = (0, 0);
+        // Position in `pattern` after the last star, and position in `text` it matches up to
+        let mut last_star = None;
+        while t < text.len() {
+            if p < pattern.len() && is_star(&pattern[p]) {
+                p += 1;
+                last_star = Some((p, t));
+            } else if p < pattern.len() && matches(&pattern[p], &text[t]) {
+                p += 1;
+                t += 1;
+            } else if let Some((star_p, star_t)) = last_star {
+                p = star_p;
+                t = star_t + 1;
+                last_star = Some((star_p, t));
+            } else {
+                return false;
+            }
+        }
+        pattern[p..].iter().all(is_star)
+    }
+
+    let pattern = pattern.split(LABEL_SEGMENT_SEPARATOR).collect::<Vec<_>>();
+    let label = label.split(LABEL_SEGMENT_SEPARATOR).collect::<Vec<_>>();
+    wildcard_match(
+        &pattern,
+        &label,
+        |segment_pattern| *segment_pattern == "**",
+        |segment_pattern, segment| {
+            wildcard_match(
+                segment_pattern.as_bytes(),
+                segment.as_bytes(),
+                |c| *c == b'*',
+                |c, d| c == d,
+            )
+        },
+    )
+}
+
+/// Source of block timestamps. Applications can provide their own clock, e.g. to get
+/// deterministic block timestamps when replaying the same operations on multiple replicas.
+/// Any `Fn() -> u64` closure is a clock.
+pub trait Clock: Send {
+    /// Returns the current time, in nanoseconds since the Unix epoch.
+    fn now_nanos(&self) -> u64;
+}
+
+impl<F: Fn() -> u64 + Send> Clock for F {
+    fn now_nanos(&self) -> u64 {
+        self()
+    }
+}
+
+/// Clock of the platform, and the default clock of the ledger: the system time on native and
+/// WASI builds, the time of the Internet Computer in canisters, and the browser's clock in the
+/// browser. Each platform implements `Clock` for it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl std::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Clock")
+    }
+}
+
+/// Clock that returns `start_ns`, `start_ns + step_ns`, `start_ns + 2 * step_ns`, ... on
+/// successive calls, for reproducible block timestamps, see `LedgerMapBuilder::deterministic`.
+#[derive(Debug)]
+pub struct CounterClock {
+    next_ns: AtomicU64,
+    step_ns: u64,
+}
+
+impl CounterClock {
+    pub fn new(start_ns: u64, step_ns: u64) -> Self {
+        CounterClock {
+            next_ns: AtomicU64::new(start_ns),
+            step_ns,
+        }
+    }
+}
+
+impl Clock for CounterClock {
+    fn now_nanos(&self) -> u64 {
+        self.next_ns.fetch_add(self.step_ns, Ordering::Relaxed)
+    }
+}
+
+/// Side of a merge, see `LedgerMap::merge_from`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeSide {
+    /// The ledger that is merged into.
+    Ours,
+    /// The ledger that is merged from.
+    Theirs,
+}
+
+/// Key written on both sides of a merge since the common prefix, with different results.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeConflict {
+    /// Last entry for the key on our side.
+    pub ours: LedgerEntry,
+    /// Timestamp of the block of `ours`.
+    pub ours_timestamp_ns: u64,
+    /// Last entry for the key on their side.
+    pub theirs: LedgerEntry,
+    /// Timestamp of the block of `theirs`.
+    pub theirs_timestamp_ns: u64,
+}
+
+/// How `LedgerMap::merge_from` resolves conflicts.
+pub enum MergeStrategy {
+    /// The entry from the block with the later timestamp wins. On equal timestamps the greater
+    /// value wins, so that the result does not depend on the merge direction.
+    LastWriterWins,
+    /// The callback picks the winning side of each conflict.
+    Custom(Box<dyn FnMut(&MergeConflict) -> MergeSide>),
+}
+
+impl MergeStrategy {
+    fn resolve(&mut self, conflict: &MergeConflict) -> MergeSide {
+        match self {
+            MergeStrategy::LastWriterWins => {
+                let ours = (conflict.ours_timestamp_ns, conflict.ours.value());
+                let theirs = (conflict.theirs_timestamp_ns, conflict.theirs.value());
+                if theirs > ours {
+                    MergeSide::Theirs
+                } else {
+                    MergeSide::Ours
+                }
+            }
+            MergeStrategy::Custom(resolve) => resolve(conflict),
+        }
+    }
+}
+
+/// Resolution of a conflict of a merge.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MergeResolution {
+    pub label: String,
+    pub key: Vec<u8>,
+    pub winner: MergeSide,
+}
+
+/// Record of a merge, stored in the merge block under `MERGE_LABEL`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MergeRecord {
+    /// Chain hash of the last block both ledgers have in common, empty if there is none.
+    pub base_hash: Vec<u8>,
+    /// Chain hash of the tip of the merged ledger.
+    pub theirs_tip_hash: Vec<u8>,
+    /// Number of their entries that were added to the merge block.
+    pub applied_entries: u64,
+    pub resolutions: Vec<MergeResolution>,
+}
+
+/// Value of an entry stored in chunks with `LedgerMap::upsert_chunked`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkedValue {
+    /// Length of the whole value, in bytes.
+    pub len: u64,
+    /// SHA-256 hash of the whole value.
+    pub sha256: Vec<u8>,
+    /// Indexes of the blocks of the chunks, in the order of the chunks.
+    pub chunk_blocks: Vec<u64>,
+}
+
+impl ChunkedValue {
+    /// Check the length and hash of the re-assembled value against the expected ones.
+    pub(crate) fn check(&self, len: u64, sha256: &[u8]) -> Result<(), LedgerError> {
+        if len != self.len || sha256 != self.sha256 {
+            return Err(LedgerError::BlockCorrupted(
+                "Chunked value does not match its length and hash".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Redo record of one step of `LedgerMap::_compact_blocks`: the compacted blocks to write at
+/// `dst`, and the headers of the neighbouring blocks to write at their offsets. Applying it is
+/// idempotent, so an interrupted step is completed by applying it again.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct CompactionJournal {
+    dst: u64,
+    data: Vec<u8>,
+    headers: Vec<(u64, Vec<u8>)>,
+}
+
+/// Kind of reference that a committed entry holds instead of its value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ValueRef {
+    /// A `ChunkedValue`, see `LedgerMap::upsert_chunked`.
+    Chunked,
+    /// The hash of a blob, see `LedgerMap::upsert_blob_ref`.
+    Blob(Vec<u8>),
+}
+
+/// Result of `LedgerMap::merge_from`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// Their ledger has no blocks that are missing in ours.
+    UpToDate,
+    /// Our ledger had no blocks of its own since the common prefix, and their blocks were
+    /// appended as they are.
+    FastForward { blocks: usize },
+    /// Both ledgers diverged, and a merge block was committed.
+    Merged(MergeRecord),
+}
+
+/// Relation of the local chain to a remote chain, as returned by `LedgerMap::check_fork`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForkStatus {
+    /// Both chains have the same tip.
+    Equal,
+    /// The remote chain is longer and extends the local chain: its chain hash at the local height
+    /// is the local tip, see `LedgerMap::check_fork_at`.
+    Ancestor,
+    /// The remote chain is longer. Whether it extends the local chain or forked from it can only
+    /// be told from its chain hash at the local height, see `LedgerMap::check_fork_at`.
+    NeedsVerification,
+    /// The local chain extends the remote chain.
+    Descendant,
+    /// The chains differ at or before the remote tip, e.g. because the remote history was
+    /// rewritten.
+    Fork,
+}
+
+/// Storage usage of the ledger, as returned by `LedgerMap::storage_stats`.
+/// Entry bytes are the uncompressed sizes of the entry label, key, and value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// Size of the persistent storage (file or stable memory), in bytes.
+    pub storage_bytes: u64,
+    /// Bytes occupied by the committed blocks, including the block headers.
+    pub blocks_bytes: u64,
+    /// Entry bytes of the live (not deleted or overwritten) entries, per indexed label.
+    pub label_live_bytes: IndexMap<String, u64>,
+    /// Entry bytes of all delete entries (tombstones) in the committed blocks.
+    pub tombstone_bytes: u64,
+    /// Estimated entry bytes that compaction would reclaim: overwritten entries and tombstones.
+    /// Entries of labels that are not indexed are all counted as reclaimable.
+    pub reclaimable_bytes: u64,
+}
+
+/// Tolerated skew of the timestamps of the blocks appended with `LedgerMap::append_raw_blocks`,
+/// e.g. to sanity-check blocks fetched from untrusted mirrors. `None` tolerates any skew.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimestampSkew {
+    /// How far a block timestamp may be ahead of the local clock.
+    pub max_ahead_of_local_ns: Option<u64>,
+    /// How far a block timestamp may be behind the timestamp of its parent block.
+    pub max_behind_parent_ns: Option<u64>,
+}
+
+/// When the committed blocks are made durable, see `LedgerMapBuilder::durability`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave writing the blocks to the disk to the operating system, the default except on
+    /// Windows, iOS and Android. A committed block survives a crash of the process, but not
+    /// necessarily a crash of the machine.
+    #[cfg_attr(not(any(windows, target_os = "ios", target_os = "android")), default)]
+    Buffered,
+    /// Sync the persistent storage, e.g. with fsync, after each committed or appended block, so
+    /// that the block also survives a power loss. The default on Windows, which does not
+    /// otherwise guarantee that writes reach the disk before the system stops, and on iOS and
+    /// Android, where the app may be killed at any time.
+    #[cfg_attr(any(windows, target_os = "ios", target_os = "android"), default)]
+    Sync,
+}
+
+/// Result of a commit of a block, see `LedgerMap::commit_block_described`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitResult {
+    /// Chain hash of the committed block.
+    pub block_hash: Vec<u8>,
+    pub block_index: usize,
+    /// Label, key, and operation of each entry of the block, in block order, including the
+    /// records of reserved labels.
+    pub changed: Vec<(String, EntryKey, Operation)>,
+}
+
+/// Result of a verified clone of the ledger, see `LedgerMap::clone_to`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloneReport {
+    pub path: std::path::PathBuf,
+    /// Number of blocks verified in the source and again in the clone.
+    pub blocks_count: u64,
+    /// Bytes of blocks copied, including the block headers.
+    pub blocks_bytes: u64,
+    /// Chain hash of the last block of the clone, equal to the one of the source.
+    pub tip_chain_hash: Vec<u8>,
+}
+
+/// Position in the entries of a label after a page returned by `LedgerMap::iter_page`. The cursor
+/// is the last key of the page, so it stays valid when the ledger is refreshed or compacted, and
+/// entries committed meanwhile are returned if their key comes after it. It is formatted as an
+/// opaque string, e.g. for the `next` links of a REST API, and parsed back with `str::parse`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cursor {
+    after_key: EntryKey,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(&self.after_key))
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = LedgerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let after_key = hex::decode(s)
+            .map_err(|e| LedgerError::Serialization(format!("Invalid cursor {:?}: {}", s, e)))?;
+        Ok(Cursor { after_key })
+    }
+}
 
 #[derive(Debug)]
 pub struct LedgerMap {
     metadata: RefCell<Metadata>,
     labels_to_index: Option<AHashSet<String>>,
-    entries: IndexMap<String, IndexMap<EntryKey, LedgerEntry>>,
+    entries: IndexMap<String, LabelIndex>,
+    /// Indexes of the labels that are not materialized in `entries` yet, see
+    /// `LedgerMapBuilder::lazy_index`. Each one is loaded from the blocks on first access.
+    lazy_entries: IndexMap<String, OnceCell<Option<LabelIndex>>>,
+    label_index_kinds: BTreeMap<String, LabelIndexKind>,
     next_block_entries: IndexMap<String, IndexMap<EntryKey, LedgerEntry>>,
-    current_timestamp_nanos: fn() -> u64,
+    /// Secondary indexes of the committed entries, per label and index name.
+    secondary_indexes: IndexMap<String, IndexMap<String, SecondaryIndex>>,
+    /// Live entries of all labels, for the state root.
+    live_state: LiveState,
+    label_stats: LabelStatsIndex,
+    /// Indexes of the blocks with entries of each label, as stored in the blocks.
+    label_blocks: BTreeMap<String, Vec<usize>>,
+    clock: Box<dyn Clock>,
+    /// Anchor and the number of blocks between anchor points.
+    anchor: Option<(Box<dyn Anchor>, u64)>,
+    /// Checkpoint signers and the number of blocks between checkpoints.
+    checkpoint_signers: Option<(Vec<Box<dyn CheckpointSigner>>, u64)>,
+    access_controller: Option<Box<dyn AccessController>>,
+    principal_source: Box<dyn PrincipalSource>,
+    /// Committed write policies, per label.
+    label_policies: BTreeMap<String, LabelPolicy>,
+    /// Label and key of the committed entries that hold a reference instead of their value.
+    value_refs: BTreeMap<(String, Vec<u8>), ValueRef>,
+    /// `ChunkedValue` of the committed blobs, by blob hash.
+    blobs: BTreeMap<Vec<u8>, ChunkedValue>,
+    /// Number of committed entries that refer to each blob, by blob hash.
+    blob_ref_counts: BTreeMap<Vec<u8>, u64>,
+    /// Storage of the full blocks spilled by `spill_to_cold_storage`.
+    cold_storage: Option<Box<dyn ColdStorage>>,
+    /// Keys to encrypt new blocks and decrypt the encrypted ones, see `encryption`.
+    #[cfg(feature = "encryption")]
+    encryption_keys: Option<EncryptionKeys>,
+    storage_quota_bytes: Option<u64>,
+    max_key_size_bytes: usize,
+    max_value_size_bytes: usize,
+    chunk_size_bytes: usize,
+    timestamp_skew: TimestampSkew,
+    ledger_info: Option<LedgerInfo>,
+    block_version: u32,
+    /// Store the entry hashes in new blocks, see `LedgerMapBuilder::entry_hashes`.
+    entry_hashes: bool,
+    /// Avoid environment-dependent behavior, see `LedgerMapBuilder::deterministic`.
+    deterministic: bool,
+    /// See `LedgerMapBuilder::monotonic_timestamps`.
+    monotonic_timestamps: bool,
+    /// See `LedgerMapBuilder::reject_non_monotonic_timestamps`.
+    reject_non_monotonic_timestamps: bool,
+    /// See `LedgerMapBuilder::lazy_index`.
+    lazy_index: bool,
+    /// See `LedgerMapBuilder::durability`.
+    durability: Durability,
+    /// See `LedgerMapBuilder::auto_commit`.
+    auto_commit_entries: Option<usize>,
+    /// Index of the partition of the ledger blocks in the partition table.
+    data_partition: usize,
+    /// Start of the data partition, and the start of the next partition, if any.
+    data_partition_bounds: (u64, Option<u64>),
+    /// Persistent storage of the ledger, activated before each access, see `_activate_storage`.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    storage_backend: platform_specific::SharedStorageBackend,
+    #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+    certified_index: crate::certification::CertifiedIndex,
 }
 
 impl Default for LedgerMap {
@@ -35,35 +570,92 @@ impl LedgerMap {
     /// If `labels_to_index` is `None`, then all labels will be indexed.
     /// Note that iterating over non-indexed labels will not be possible through .iter()
     pub fn new(labels_to_index: Option<Vec<String>>) -> anyhow::Result<Self> {
-        let mut result = LedgerMap {
-            metadata: RefCell::new(Metadata::new()),
-            labels_to_index: labels_to_index.map(AHashSet::from_iter),
-            entries: IndexMap::new(),
-            next_block_entries: IndexMap::new(),
-            current_timestamp_nanos: platform_specific::get_timestamp_nanos,
-        };
-        result.refresh_ledger()?;
-        Ok(result)
+        LedgerMapBuilder {
+            labels_to_index,
+            ..LedgerMapBuilder::new()
+        }
+        .build()
     }
 
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    /// Returns a builder for configuring a new LedgerMap instance.
+    pub fn builder() -> LedgerMapBuilder {
+        LedgerMapBuilder::new()
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", feature = "browser"),
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
     pub fn new_with_path(
         labels_to_index: Option<Vec<String>>,
         path: Option<std::path::PathBuf>,
     ) -> anyhow::Result<Self> {
-        platform_specific::set_backing_file(path).map_err(|e| anyhow::format_err!("{:?}", e))?;
-        Self::new(labels_to_index)
+        LedgerMapBuilder {
+            labels_to_index,
+            ..LedgerMapBuilder::new()
+        }
+        .path(path)
+        .build()
     }
 
-    #[cfg(all(target_arch = "wasm32", feature = "browser"))]
-    pub fn new_with_path(
+    /// Create a new LedgerMap instance, with the ledger split across multiple segment files
+    /// of at most `segment_size_bytes` each. New segments are created as the ledger grows.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn new_with_segmented_path(
         labels_to_index: Option<Vec<String>>,
-        _path: Option<std::path::PathBuf>,
+        path: Option<std::path::PathBuf>,
+        segment_size_bytes: u64,
     ) -> anyhow::Result<Self> {
-        Self::new(labels_to_index)
+        LedgerMapBuilder {
+            labels_to_index,
+            ..LedgerMapBuilder::new()
+        }
+        .segmented_path(path, segment_size_bytes)
+        .build()
     }
 
+    /// Create a new LedgerMap instance, with the ledger in a new, empty `MemoryStorage`.
+    /// Nothing is written to the file system, which makes it suitable for isolated tests.
     #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn new_in_memory(labels_to_index: Option<Vec<String>>) -> anyhow::Result<Self> {
+        LedgerMapBuilder {
+            labels_to_index,
+            ..LedgerMapBuilder::new()
+        }
+        .in_memory()
+        .build()
+    }
+
+    /// Create a new LedgerMap instance with a custom partition table layout.
+    /// The layout is persisted only if the persistent storage is not yet initialized,
+    /// otherwise the layout the ledger was created with is kept.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", feature = "browser"),
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
+    pub fn new_with_partition_table(
+        labels_to_index: Option<Vec<String>>,
+        path: Option<std::path::PathBuf>,
+        partition_table: PartitionTable,
+    ) -> anyhow::Result<Self> {
+        LedgerMapBuilder {
+            labels_to_index,
+            ..LedgerMapBuilder::new()
+        }
+        .path(path)
+        .partition_table(partition_table)
+        .build()
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
     pub fn get_file_path(&self) -> Option<std::path::PathBuf> {
         platform_specific::get_backing_file_path()
     }
@@ -73,11 +665,191 @@ impl LedgerMap {
         None
     }
 
-    #[cfg(test)]
-    fn with_timestamp_fn(self, get_timestamp_nanos: fn() -> u64) -> Self {
-        LedgerMap {
-            current_timestamp_nanos: get_timestamp_nanos,
-            ..self
+    /// Use `get_timestamp_nanos` instead of the system clock for block timestamps.
+    pub fn with_timestamp_fn(self, get_timestamp_nanos: fn() -> u64) -> Self {
+        self.with_clock(get_timestamp_nanos)
+    }
+
+    /// Use `clock` instead of the system clock for block timestamps.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.set_clock(clock);
+        self
+    }
+
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Pass the chain hash to `anchor` after each commit that makes the number of blocks a
+    /// multiple of `every_blocks`. Anchoring failures are logged, and do not fail the commit.
+    pub fn set_anchor(&mut self, anchor: impl Anchor + 'static, every_blocks: u64) {
+        self.anchor = Some((Box::new(anchor), every_blocks.max(1)));
+    }
+
+    pub fn remove_anchor(&mut self) {
+        self.anchor = None;
+    }
+
+    /// After each commit that makes the number of blocks a multiple of `every_blocks`, collect
+    /// signatures of the chain hash from `signers`, and commit them in a checkpoint block, see
+    /// `crate::checkpoint`. Signing failures are logged, and do not fail the commit.
+    pub fn set_checkpoint_signers(
+        &mut self,
+        signers: Vec<Box<dyn CheckpointSigner>>,
+        every_blocks: u64,
+    ) {
+        self.checkpoint_signers = Some((signers, every_blocks.max(1)));
+    }
+
+    pub fn remove_checkpoint_signers(&mut self) {
+        self.checkpoint_signers = None;
+    }
+
+    /// Consult `access_controller` on every `upsert`, `delete` and `commit_block`, which fail
+    /// with `LedgerError::Unauthorized` if it denies them, see `crate::access`.
+    pub fn set_access_controller(&mut self, access_controller: impl AccessController + 'static) {
+        self.access_controller = Some(Box::new(access_controller));
+    }
+
+    pub fn remove_access_controller(&mut self) {
+        self.access_controller = None;
+    }
+
+    /// Use `principal_source` for the principal of the caller passed to the access controller.
+    pub fn set_principal_source(&mut self, principal_source: impl PrincipalSource + 'static) {
+        self.principal_source = Box::new(principal_source);
+    }
+
+    /// Set the write policy of `label`, in a block of its own, so that it is enforced by every
+    /// process that opens the ledger. There must not be any uncommitted entries. The caller must
+    /// be allowed to write `label`, and be one of the writers of its current policy.
+    pub fn set_label_policy(&mut self, label: &str, policy: LabelPolicy) -> anyhow::Result<()> {
+        self.validate_label(label)?;
+        self._check_label_policy_change(label, AccessOperation::Upsert)?;
+        self._commit_label_policy_record(label, Some(policy))
+    }
+
+    /// Remove the write policy of `label`, in a block of its own. The caller must be allowed to
+    /// write `label`, and be one of the writers of its current policy.
+    pub fn remove_label_policy(&mut self, label: &str) -> anyhow::Result<()> {
+        self.validate_label(label)?;
+        self._check_label_policy_change(label, AccessOperation::Delete)?;
+        self._commit_label_policy_record(label, None)
+    }
+
+    /// Check that the caller may change the policy of `label`. The current policy is checked
+    /// only for its writers, otherwise a read-only label could never be made writable again.
+    fn _check_label_policy_change(
+        &self,
+        label: &str,
+        operation: AccessOperation,
+    ) -> Result<(), LedgerError> {
+        self._check_access(operation, label, &[])?;
+        if let Some(policy) = self.label_policies.get(label) {
+            let principal = self.principal_source.principal();
+            if let Some(reason) = policy.check_writer(&principal) {
+                return Err(LedgerError::Unauthorized(format!(
+                    "cannot change the policy of label {:?}: {}",
+                    label, reason
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the committed write policy of `label`, if it has one.
+    pub fn label_policy(&self, label: &str) -> Option<&LabelPolicy> {
+        self.label_policies.get(label)
+    }
+
+    fn _commit_label_policy_record(
+        &mut self,
+        label: &str,
+        policy: Option<LabelPolicy>,
+    ) -> anyhow::Result<()> {
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot change a label policy with uncommitted entries"
+            ));
+        }
+        match policy {
+            Some(policy) => self._insert_entry_into_next_block(
+                POLICY_LABEL,
+                label,
+                to_vec(&policy)?,
+                Operation::Upsert,
+            )?,
+            None => self._insert_entry_into_next_block(
+                POLICY_LABEL,
+                label,
+                Vec::new(),
+                Operation::Delete,
+            )?,
+        }
+        let result = self.commit_block();
+        if result.is_err() {
+            self.next_block_entries.clear();
+        }
+        result
+    }
+
+    fn _check_access(
+        &self,
+        operation: AccessOperation,
+        label: &str,
+        key: &[u8],
+    ) -> Result<(), LedgerError> {
+        let access_controller = match self.access_controller.as_ref() {
+            Some(access_controller) => access_controller,
+            None => return Ok(()),
+        };
+        let principal = self.principal_source.principal();
+        let request = AccessRequest {
+            principal: &principal,
+            operation,
+            label,
+            key,
+        };
+        if access_controller.is_allowed(&request) {
+            Ok(())
+        } else {
+            Err(LedgerError::Unauthorized(format!(
+                "principal {} is not allowed to {} label {:?} key {:?}",
+                hex::encode(&principal),
+                operation,
+                label,
+                String::from_utf8_lossy(key)
+            )))
+        }
+    }
+
+    /// Use `cold_storage` for the blocks spilled by `spill_to_cold_storage`, and to fetch them
+    /// when they are read with `get_block_at_offset`.
+    pub fn set_cold_storage(&mut self, cold_storage: impl ColdStorage + 'static) {
+        self.cold_storage = Some(Box::new(cold_storage));
+    }
+
+    /// Keys of the ledger, see `LedgerMapBuilder::encryption_keys`.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_keys(&self) -> Option<&EncryptionKeys> {
+        self.encryption_keys.as_ref()
+    }
+
+    /// Encrypt new blocks with `key`, of the new key epoch `epoch`. The blocks of the earlier
+    /// epochs stay readable with their keys until they are re-encrypted with `rewrap`. Enables
+    /// the encryption of new blocks if the ledger has no encryption keys yet.
+    #[cfg(feature = "encryption")]
+    pub fn rotate_encryption_key(
+        &mut self,
+        epoch: u32,
+        key: [u8; encryption::ENCRYPTION_KEY_LEN],
+    ) -> Result<(), LedgerError> {
+        match &mut self.encryption_keys {
+            Some(encryption_keys) => encryption_keys.rotate(epoch, key),
+            None => {
+                self.encryption_keys = Some(EncryptionKeys::new(epoch, key));
+                Ok(())
+            }
         }
     }
 
@@ -91,6 +863,49 @@ impl LedgerMap {
     }
 
     pub fn commit_block(&mut self) -> anyhow::Result<()> {
+        if !self.next_block_entries.is_empty() {
+            self._check_access(AccessOperation::CommitBlock, "", &[])?;
+        }
+        self._commit_block()
+    }
+
+    /// Like `commit_block`, but also returns which keys the block changed, so that callers can
+    /// e.g. invalidate their caches without comparing the index before and after the commit.
+    /// Returns `None` if there was nothing to commit.
+    pub fn commit_block_described(&mut self) -> anyhow::Result<Option<CommitResult>> {
+        if self.next_block_entries.is_empty() {
+            return Ok(None);
+        }
+        let mut changed = self
+            .next_block_entries
+            .values()
+            .flat_map(|values| values.values())
+            .map(|entry| {
+                (
+                    entry.label().to_string(),
+                    entry.key().to_vec(),
+                    entry.operation(),
+                )
+            })
+            .collect::<Vec<_>>();
+        if self.deterministic {
+            changed.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        }
+        // A checkpoint block may be committed right after the block, so it is looked up by index
+        let block_index = self.get_blocks_count();
+        self.commit_block()?;
+        let block_hash = self
+            .get_chain_hash_at(block_index)
+            .ok_or_else(|| anyhow::format_err!("Committed block {} not found", block_index))?;
+        Ok(Some(CommitResult {
+            block_hash,
+            block_index,
+            changed,
+        }))
+    }
+
+    /// Commit the next block, without an access check, for the blocks the ledger writes itself.
+    fn _commit_block(&mut self) -> anyhow::Result<()> {
         if self.next_block_entries.is_empty() {
             // debug!("Commit of empty block invoked, skipping");
         } else {
@@ -98,86 +913,560 @@ impl LedgerMap {
                 "Commit non-empty block, with {} entries",
                 self.next_block_entries.len()
             );
-            let mut block_entries = Vec::new();
+            let labels = self.next_block_entries.keys().cloned().collect::<Vec<_>>();
+            for label in labels {
+                self._materialize_label(&label)?;
+            }
+            let mut block_entries = self
+                .next_block_entries
+                .values()
+                .flat_map(|values| values.values().cloned())
+                .collect::<Vec<_>>();
+            if self.deterministic {
+                block_entries.sort_by(|a, b| (a.label(), a.key()).cmp(&(b.label(), b.key())));
+            }
+            let block_timestamp = self._next_block_timestamp();
+            let parent_hash = self.metadata.borrow().get_last_block_chain_hash().to_vec();
+            let mut block = LedgerBlock::new_with_version(
+                self.block_version,
+                block_entries,
+                block_timestamp,
+                parent_hash,
+            )?;
+            if self.entry_hashes {
+                let entry_hashes = block
+                    .entries()
+                    .iter()
+                    .flat_map(hashing::entry_hash)
+                    .collect::<Vec<_>>();
+                block.add_field(BlockField::new(BLOCK_FIELD_ENTRY_HASHES, entry_hashes))?;
+            }
+            // Policy records are applied before the block is persisted, so that an invalid one
+            // is rejected instead of being committed
+            let label_policies = match self.next_block_entries.get(POLICY_LABEL) {
+                Some(entries) => {
+                    let mut label_policies = self.label_policies.clone();
+                    for entry in entries.values() {
+                        apply_label_policy_record(&mut label_policies, entry)?;
+                    }
+                    Some(label_policies)
+                }
+                None => None,
+            };
+            self._persist_block(block)?;
+            if let Some(label_policies) = label_policies {
+                self.label_policies = label_policies;
+            }
+            let block_index = self.get_blocks_count() as u64 - 1;
             for (label, values) in self.next_block_entries.iter() {
-                if match &self.labels_to_index {
-                    Some(labels_to_index) => labels_to_index.contains(label),
-                    None => true,
-                } {
+                record_label_block(&mut self.label_blocks, label, block_index as usize);
+                for entry in values.values() {
+                    self.live_state.apply(entry);
+                    self.label_stats.apply(entry, block_index, block_timestamp);
+                    apply_value_ref_record(&mut self.value_refs, &mut self.blob_ref_counts, entry)?;
+                    apply_blob_record(&mut self.blobs, entry)?;
+                }
+                if self._is_label_indexed(label) {
+                    #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+                    for entry in values.values() {
+                        self.certified_index.apply(entry);
+                    }
+                    for entry in values.values() {
+                        update_secondary_indexes(&mut self.secondary_indexes, &self.entries, entry);
+                    }
+                    let kind = self._label_index_kind(label);
                     self.entries
                         .entry(label.clone())
-                        .or_default()
+                        .or_insert_with(|| LabelIndex::new(kind))
                         .extend(values.clone())
                 };
-                for (_key, entry) in values.iter() {
-                    block_entries.push(entry.clone());
-                }
             }
-            let block_timestamp = (self.current_timestamp_nanos)();
-            let parent_hash = self.metadata.borrow().get_last_block_chain_hash().to_vec();
-            let block = LedgerBlock::new(block_entries, block_timestamp, parent_hash);
-            self._persist_block(block)?;
+            let is_checkpoint_block = self.next_block_entries.contains_key(CHECKPOINT_LABEL);
             self.next_block_entries.clear();
+            self._anchor_if_due(block_timestamp);
+            if !is_checkpoint_block {
+                self._checkpoint_if_due();
+            }
         }
         Ok(())
     }
 
-    pub fn get<S: AsRef<str>>(&self, label: S, key: &[u8]) -> Result<EntryValue, LedgerError> {
-        fn lookup<'a>(
-            map: &'a IndexMap<String, IndexMap<EntryKey, LedgerEntry>>,
-            label: &String,
-            key: &[u8],
-        ) -> Option<&'a LedgerEntry> {
-            match map.get(label) {
-                Some(entries) => entries.get(key),
-                None => None,
+    /// Timestamp of the next block from the clock, raised to 1 ns after the timestamp of the
+    /// previous block if timestamps must be monotonic and the clock went backwards.
+    fn _next_block_timestamp(&self) -> u64 {
+        let timestamp_ns = self.clock.now_nanos();
+        if !self.monotonic_timestamps || self.get_blocks_count() == 0 {
+            return timestamp_ns;
+        }
+        let last_timestamp_ns = self.metadata.borrow().get_last_block_timestamp_ns();
+        timestamp_ns.max(last_timestamp_ns.saturating_add(1))
+    }
+
+    fn _anchor_if_due(&mut self, block_timestamp: u64) {
+        let blocks_count = self.get_blocks_count() as u64;
+        if let Some((anchor, every_blocks)) = self.anchor.as_mut() {
+            if blocks_count.is_multiple_of(*every_blocks) {
+                let point = AnchorPoint {
+                    blocks_count,
+                    chain_hash: self.metadata.borrow().get_last_block_chain_hash().to_vec(),
+                    timestamp_ns: block_timestamp,
+                };
+                if let Err(err) = anchor.anchor(&point) {
+                    warn!("Failed to anchor block {}: {}", blocks_count, err);
+                }
             }
         }
+    }
 
-        let label = label.as_ref().to_string();
-        for map in [&self.next_block_entries, &self.entries] {
-            if let Some(entry) = lookup(map, &label, key) {
-                match entry.operation() {
-                    Operation::Upsert => {
-                        return Ok(entry.value().to_vec());
-                    }
-                    Operation::Delete => {
-                        return Err(LedgerError::EntryNotFound);
-                    }
+    fn _checkpoint_if_due(&mut self) {
+        let blocks_count = self.get_blocks_count() as u64;
+        let tip_hash = self.get_latest_block_hash();
+        let signatures = match self.checkpoint_signers.as_mut() {
+            Some((signers, every_blocks)) if blocks_count.is_multiple_of(*every_blocks) => {
+                let message = Checkpoint::message(blocks_count, &tip_hash);
+                signers
+                    .iter_mut()
+                    .filter_map(|signer| match signer.sign(&message) {
+                        Ok(signature) => Some(CheckpointSignature {
+                            signer_id: signer.signer_id(),
+                            signature,
+                        }),
+                        Err(err) => {
+                            warn!(
+                                "Checkpoint signer {:?} failed to sign block {}: {}",
+                                signer, blocks_count, err
+                            );
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            }
+            _ => return,
+        };
+        if signatures.is_empty() {
+            warn!("No signatures for the checkpoint of block {}", blocks_count);
+            return;
+        }
+        let checkpoint = Checkpoint {
+            blocks_count,
+            tip_hash,
+            signatures,
+        };
+        let result = to_vec(&checkpoint)
+            .map_err(anyhow::Error::from)
+            .and_then(|value| {
+                self._insert_entry_into_next_block(
+                    CHECKPOINT_LABEL,
+                    blocks_count.to_le_bytes(),
+                    value,
+                    Operation::Upsert,
+                )?;
+                self._commit_block()
+            });
+        if let Err(err) = result {
+            warn!(
+                "Failed to commit the checkpoint of block {}: {}",
+                blocks_count, err
+            );
+            self.next_block_entries.clear();
+        }
+    }
+
+    /// Verify that each checkpoint block of the ledger is signed by at least `quorum` distinct
+    /// signers of `signer_ids`, and that it matches the chain hash it checkpoints. Fails if a
+    /// checkpoint is missing, i.e. if a block that makes the number of blocks a multiple of
+    /// `every_blocks` is not directly followed by its checkpoint block, so that a writer cannot
+    /// avoid the quorum by dropping checkpoints. Returns the verified checkpoints.
+    pub fn verify_checkpoints(
+        &self,
+        signer_ids: &[Vec<u8>],
+        quorum: usize,
+        every_blocks: u64,
+        verifier: &dyn SignatureVerifier,
+    ) -> anyhow::Result<Vec<Checkpoint>> {
+        let every_blocks = every_blocks.max(1);
+        let mut checkpoints = Vec::new();
+        let mut expected_checkpoint = None;
+        for (height, block) in self.iter_raw().enumerate() {
+            let (_, ledger_block) = block?;
+            let mut is_checkpoint_block = false;
+            for entry in ledger_block.entries() {
+                if entry.label() != CHECKPOINT_LABEL {
+                    continue;
+                }
+                is_checkpoint_block = true;
+                let checkpoint = Checkpoint::try_from_slice(entry.value())?;
+                // The checkpoint block directly follows the checkpointed block
+                if checkpoint.blocks_count != height as u64
+                    || checkpoint.tip_hash != ledger_block.parent_hash()
+                {
+                    return Err(anyhow::format_err!(
+                        "Checkpoint of block {} does not match the chain at block {}",
+                        checkpoint.blocks_count,
+                        height
+                    ));
+                }
+                let valid_signers = checkpoint.count_valid_signers(signer_ids, verifier);
+                if valid_signers < quorum {
+                    return Err(anyhow::format_err!(
+                        "Checkpoint of block {} has {} valid signatures, the quorum is {}",
+                        checkpoint.blocks_count,
+                        valid_signers,
+                        quorum
+                    ));
+                }
+                checkpoints.push(checkpoint);
+            }
+            if let Some(blocks_count) = expected_checkpoint.take() {
+                if !is_checkpoint_block {
+                    return Err(anyhow::format_err!(
+                        "Missing checkpoint of block {}",
+                        blocks_count
+                    ));
                 }
+            } else if !is_checkpoint_block && (height as u64 + 1).is_multiple_of(every_blocks) {
+                expected_checkpoint = Some(height as u64 + 1);
             }
         }
+        if let Some(blocks_count) = expected_checkpoint {
+            return Err(anyhow::format_err!(
+                "Missing checkpoint of block {}",
+                blocks_count
+            ));
+        }
+        Ok(checkpoints)
+    }
 
-        Err(LedgerError::EntryNotFound)
+    /// Block version that new blocks are written with.
+    pub fn get_block_version(&self) -> u32 {
+        self.block_version
     }
 
-    pub fn count_entries_for_label<S: AsRef<str>>(&self, label: S) -> u64 {
-        self.entries
-            .get(label.as_ref())
-            .map(|m| m.len() as u64)
-            .unwrap_or_default()
-            + self
-                .next_block_entries
-                .get(label.as_ref()).map(|m| m.len() as u64)
-                .unwrap_or_default()
+    /// Returns the identity and configuration of the ledger, if it was created with a genesis block.
+    pub fn ledger_info(&self) -> Option<&LedgerInfo> {
+        self.ledger_info.as_ref()
     }
 
-    pub fn upsert<S: AsRef<str>, K: AsRef<[u8]>, V: AsRef<[u8]>>(
-        &mut self,
-        label: S,
-        key: K,
-        value: V,
-    ) -> Result<(), LedgerError> {
-        self._insert_entry_into_next_block(label, key, value, Operation::Upsert)
+    /// Limit the persistent storage used by the ledger to `quota_bytes`, or remove the limit with `None`.
+    /// A `commit_block` that would exceed the quota fails with `LedgerError::QuotaExceeded`,
+    /// and the uncommitted entries are kept.
+    pub fn set_storage_quota(&mut self, quota_bytes: Option<u64>) {
+        self.storage_quota_bytes = quota_bytes;
     }
 
-    pub fn put<S: AsRef<str>, K: AsRef<[u8]>, V: AsRef<[u8]>>(
-        &mut self,
-        label: S,
-        key: K,
-        value: V,
-    ) -> Result<(), LedgerError> {
-        self.upsert(label, key, value)
+    pub fn get_storage_quota(&self) -> Option<u64> {
+        self.storage_quota_bytes
+    }
+
+    /// Set the maximum key size accepted by `upsert` and `delete`.
+    /// Larger keys are rejected with `LedgerError::KeyTooLarge`.
+    pub fn set_max_key_size(&mut self, max_size_bytes: usize) {
+        self.max_key_size_bytes = max_size_bytes;
+    }
+
+    pub fn get_max_key_size(&self) -> usize {
+        self.max_key_size_bytes
+    }
+
+    /// Set the maximum value size accepted by `upsert`.
+    /// Larger values are rejected with `LedgerError::ValueTooLarge`.
+    pub fn set_max_value_size(&mut self, max_size_bytes: usize) {
+        self.max_value_size_bytes = max_size_bytes;
+    }
+
+    pub fn get_max_value_size(&self) -> usize {
+        self.max_value_size_bytes
+    }
+
+    /// Set the size of the chunks of the values stored with `upsert_chunked`. Chunks are never
+    /// larger than the maximum value size.
+    pub fn set_chunk_size(&mut self, chunk_size_bytes: usize) {
+        self.chunk_size_bytes = chunk_size_bytes;
+    }
+
+    pub fn get_chunk_size(&self) -> usize {
+        self.chunk_size_bytes
+    }
+
+    /// Set the tolerated skew of the timestamps of the blocks appended with `append_raw_blocks`.
+    /// Blocks outside of the window are rejected with `LedgerError::TimestampOutOfRange`.
+    pub fn set_timestamp_skew(&mut self, timestamp_skew: TimestampSkew) {
+        self.timestamp_skew = timestamp_skew;
+    }
+
+    pub fn get_timestamp_skew(&self) -> TimestampSkew {
+        self.timestamp_skew
+    }
+
+    /// Returns the value of `key` of `label`. The entries of the reserved labels, e.g.
+    /// `MERGE_LABEL`, are not indexed, so they are read from the blocks that hold them.
+    pub fn get<S: AsRef<str>>(&self, label: S, key: &[u8]) -> Result<EntryValue, LedgerError> {
+        if label.as_ref().starts_with(RESERVED_LABEL_PREFIX) {
+            return self._get_reserved(label.as_ref(), key);
+        }
+        let (entry, value_ref) = self._lookup_value(label.as_ref(), key)?;
+        self._read_value(entry, value_ref)
+    }
+
+    /// Returns the value of `key` of the reserved `label`, from the uncommitted entries or else
+    /// from the newest block with entries of the label that has the key.
+    fn _get_reserved(&self, label: &str, key: &[u8]) -> Result<EntryValue, LedgerError> {
+        let pending = self
+            .next_block_entries
+            .get(label)
+            .and_then(|entries| entries.get(key))
+            .cloned();
+        let committed = || -> Result<Option<LedgerEntry>, LedgerError> {
+            for block_index in self.label_blocks.get(label).into_iter().flatten().rev() {
+                let offset = self
+                    .metadata
+                    .borrow()
+                    .block_start_pos(*block_index)
+                    .ok_or_else(|| {
+                        LedgerError::Other(format!("Block {} not found", block_index))
+                    })?;
+                let (_, ledger_block) = self._persisted_block_read(offset)?;
+                if let Some(entry) = ledger_block
+                    .entries()
+                    .iter()
+                    .find(|entry| entry.label() == label && entry.key() == key)
+                {
+                    return Ok(Some(entry.clone()));
+                }
+            }
+            Ok(None)
+        };
+        let entry = match pending {
+            Some(entry) => entry,
+            None => committed()?.ok_or(LedgerError::EntryNotFound)?,
+        };
+        match entry.operation() {
+            Operation::Upsert => Ok(entry.value().to_vec()),
+            Operation::Delete => Err(LedgerError::EntryNotFound),
+        }
+    }
+
+    /// Returns the values of `keys` of `label`, as `get` returns them, in the order of `keys`.
+    /// The entries of the label are looked up once for all keys.
+    pub fn get_many<S: AsRef<str>>(
+        &self,
+        label: S,
+        keys: &[&[u8]],
+    ) -> Vec<Result<EntryValue, LedgerError>> {
+        let label = label.as_ref();
+        let next_block_entries = self.next_block_entries.get(label);
+        let entries = match self._label_entries(label) {
+            Ok(entries) => entries,
+            Err(err) => return keys.iter().map(|_| Err(err.clone())).collect(),
+        };
+        keys.iter()
+            .map(|key| {
+                let (entry, value_ref) =
+                    self._lookup_value_in(label, key, next_block_entries, entries)?;
+                self._read_value(entry, value_ref)
+            })
+            .collect()
+    }
+
+    /// Returns a reader that streams the value of `key` of `label`, as `get` returns it. The
+    /// chunks of a chunked value (see `upsert_chunked`) or blob (see `put_blob`) are read from
+    /// the persistent storage one at a time, and the value is verified against its hash when the
+    /// end is reached, so that a large value is never held in memory as a whole.
+    pub fn get_reader<S: AsRef<str>>(
+        &self,
+        label: S,
+        key: &[u8],
+    ) -> Result<ValueReader<'_>, LedgerError> {
+        let (entry, value_ref) = self._lookup_value(label.as_ref(), key)?;
+        match self._resolve_value_ref(entry, value_ref)? {
+            Some(chunked_value) => Ok(ValueReader::chunked(self, chunked_value)),
+            None => Ok(ValueReader::inline(entry.value())),
+        }
+    }
+
+    /// Returns the entry that holds the value of `key` of `label`, uncommitted or committed, and
+    /// the kind of reference it holds instead of the value, if any.
+    fn _lookup_value(
+        &self,
+        label: &str,
+        key: &[u8],
+    ) -> Result<(&LedgerEntry, Option<ValueRef>), LedgerError> {
+        self._lookup_value_in(
+            label,
+            key,
+            self.next_block_entries.get(label),
+            self._label_entries(label)?,
+        )
+    }
+
+    /// Like `_lookup_value`, with the uncommitted and committed entries of the label.
+    fn _lookup_value_in<'a>(
+        &'a self,
+        label: &str,
+        key: &[u8],
+        next_block_entries: Option<&'a IndexMap<EntryKey, LedgerEntry>>,
+        entries: Option<&'a LabelIndex>,
+    ) -> Result<(&'a LedgerEntry, Option<ValueRef>), LedgerError> {
+        let pending = next_block_entries.and_then(|entries| entries.get(key));
+        let committed = entries.and_then(|entries| entries.get(key));
+        for (entry, committed) in [(pending, false), (committed, true)] {
+            if let Some(entry) = entry {
+                return match entry.operation() {
+                    Operation::Upsert if committed => Ok((
+                        entry,
+                        self.value_refs
+                            .get(&(label.to_string(), key.to_vec()))
+                            .cloned(),
+                    )),
+                    // Only blob references can be uncommitted, the others are committed at once
+                    Operation::Upsert => Ok((
+                        entry,
+                        self._has_pending_blob_ref(label, key)?
+                            .then(|| ValueRef::Blob(entry.value().to_vec())),
+                    )),
+                    Operation::Delete => Err(LedgerError::EntryNotFound),
+                };
+            }
+        }
+
+        Err(LedgerError::EntryNotFound)
+    }
+
+    /// Returns the value of `entry`, or the value it refers to with `value_ref`.
+    fn _read_value(
+        &self,
+        entry: &LedgerEntry,
+        value_ref: Option<ValueRef>,
+    ) -> Result<EntryValue, LedgerError> {
+        match self._resolve_value_ref(entry, value_ref)? {
+            Some(chunked_value) => self._read_chunked_value(&chunked_value),
+            None => Ok(entry.value().to_vec()),
+        }
+    }
+
+    /// Returns the `ChunkedValue` that `entry` refers to with `value_ref`, if any.
+    fn _resolve_value_ref(
+        &self,
+        entry: &LedgerEntry,
+        value_ref: Option<ValueRef>,
+    ) -> Result<Option<ChunkedValue>, LedgerError> {
+        match value_ref {
+            None => Ok(None),
+            Some(ValueRef::Chunked) => ChunkedValue::try_from_slice(entry.value())
+                .map(Some)
+                .map_err(|e| LedgerError::Serialization(e.to_string())),
+            Some(ValueRef::Blob(hash)) => match self.blobs.get(&hash) {
+                Some(chunked_value) => Ok(Some(chunked_value.clone())),
+                None => Err(LedgerError::BlockCorrupted(format!(
+                    "Blob {} not found",
+                    hex::encode(hash)
+                ))),
+            },
+        }
+    }
+
+    /// Statistics of the committed entries of `label`, or `None` if the label has no committed
+    /// entries. They are maintained as blocks are committed, so this is cheap to call.
+    pub fn label_stats(&self, label: &str) -> Option<LabelStats> {
+        self.label_stats.get(label).cloned()
+    }
+
+    pub fn count_entries_for_label<S: AsRef<str>>(&self, label: S) -> u64 {
+        self._label_entries(label.as_ref())
+            .ok()
+            .flatten()
+            .map(|m| m.len() as u64)
+            .unwrap_or_default()
+            + self
+                .next_block_entries
+                .get(label.as_ref())
+                .map(|m| m.len() as u64)
+                .unwrap_or_default()
+    }
+
+    /// Check that `label` can be used for entries of this ledger, see `validate_label`. Labels
+    /// that the ledger does not have yet must also pass `validate_new_label`, so that labels
+    /// with empty segments that were committed before can still be updated and deleted.
+    pub fn validate_label(&self, label: &str) -> Result<(), LedgerError> {
+        let is_known_label = self.label_blocks.contains_key(label)
+            || self.entries.contains_key(label)
+            || self.lazy_entries.contains_key(label)
+            || self.next_block_entries.contains_key(label);
+        match is_known_label {
+            true => validate_label(label),
+            false => validate_new_label(label),
+        }
+    }
+
+    pub fn upsert<S: AsRef<str>, K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        label: S,
+        key: K,
+        value: V,
+    ) -> Result<(), LedgerError> {
+        self.validate_label(label.as_ref())?;
+        self._check_access(AccessOperation::Upsert, label.as_ref(), key.as_ref())?;
+        self._insert_entry_into_next_block(label.as_ref(), key.as_ref(), value, Operation::Upsert)?;
+        self._forget_value_ref(label.as_ref(), key.as_ref())?;
+        self._auto_commit_if_due()
+    }
+
+    /// Upsert all `(key, value)` pairs of `items` into `label` in the next block, for bulk
+    /// loading. The label is checked and looked up once, instead of once per item, and the
+    /// entries are staged in a map pre-sized from the size hint of `items`. If any item fails
+    /// its checks, none of the items is staged.
+    pub fn upsert_many<S, K, V, I>(&mut self, label: S, items: I) -> Result<(), LedgerError>
+    where
+        S: AsRef<str>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let label = label.as_ref();
+        self.validate_label(label)?;
+        self._check_label_policy(label, Operation::Upsert)?;
+        // Only keys that may hold a chunked value or a blob reference need to be looked up
+        let has_value_refs = self
+            .value_refs
+            .range((label.to_string(), Vec::new())..)
+            .next()
+            .is_some_and(|((ref_label, _), _)| ref_label == label)
+            || self.next_block_entries.contains_key(BLOB_REF_LABEL);
+        let items = items.into_iter();
+        let mut staged = IndexMap::with_capacity(items.size_hint().0);
+        let mut forget_keys = Vec::new();
+        for (key, value) in items {
+            let (key, value) = (key.as_ref(), value.as_ref());
+            self._check_access(AccessOperation::Upsert, label, key)?;
+            self._check_key_size(key)?;
+            self._check_value_size(value)?;
+            if has_value_refs {
+                forget_keys.push(key.to_vec());
+            }
+            staged.insert(
+                key.to_vec(),
+                LedgerEntry::new(label, key, value, Operation::Upsert),
+            );
+        }
+        match self.next_block_entries.get_mut(label) {
+            Some(entries) => entries.extend(staged),
+            None => {
+                self.next_block_entries.insert(label.to_string(), staged);
+            }
+        }
+        for key in forget_keys {
+            self._forget_value_ref(label, &key)?;
+        }
+        self._auto_commit_if_due()
+    }
+
+    pub fn put<S: AsRef<str>, K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        label: S,
+        key: K,
+        value: V,
+    ) -> Result<(), LedgerError> {
+        self.upsert(label, key, value)
     }
 
     pub fn delete<S: AsRef<str>, K: AsRef<[u8]>>(
@@ -185,45 +1474,416 @@ impl LedgerMap {
         label: S,
         key: K,
     ) -> Result<(), LedgerError> {
-        self._insert_entry_into_next_block(label, key, Vec::new(), Operation::Delete)
+        self.validate_label(label.as_ref())?;
+        self._check_access(AccessOperation::Delete, label.as_ref(), key.as_ref())?;
+        self._insert_entry_into_next_block(
+            label.as_ref(),
+            key.as_ref(),
+            Vec::new(),
+            Operation::Delete,
+        )?;
+        self._forget_value_ref(label.as_ref(), key.as_ref())?;
+        self._auto_commit_if_due()
+    }
+
+    /// Delete `keys` of `label` in the next block, in one call. All keys are checked before any
+    /// delete is staged, so that either all of them are staged, or none.
+    pub fn delete_many<S: AsRef<str>, K: AsRef<[u8]>>(
+        &mut self,
+        label: S,
+        keys: &[K],
+    ) -> Result<(), LedgerError> {
+        let label = label.as_ref();
+        self.validate_label(label)?;
+        self._check_label_policy(label, Operation::Delete)?;
+        for key in keys {
+            self._check_access(AccessOperation::Delete, label, key.as_ref())?;
+            self._check_key_size(key.as_ref())?;
+        }
+        for key in keys {
+            self._insert_entry_into_next_block(label, key, Vec::new(), Operation::Delete)?;
+            self._forget_value_ref(label, key.as_ref())?;
+        }
+        self._auto_commit_if_due()
+    }
+
+    /// Commit the next block if it holds enough entries, see `LedgerMapBuilder::auto_commit`.
+    fn _auto_commit_if_due(&mut self) -> Result<(), LedgerError> {
+        let Some(max_entries) = self.auto_commit_entries else {
+            return Ok(());
+        };
+        let entries: usize = self.next_block_entries.values().map(IndexMap::len).sum();
+        if entries < max_entries {
+            return Ok(());
+        }
+        self.commit_block().map_err(|err| {
+            err.downcast::<LedgerError>()
+                .unwrap_or_else(|err| LedgerError::Other(err.to_string()))
+        })
+    }
+
+    /// Store the value read from `reader` under `key` of `label`, split into chunks of at most
+    /// the chunk size (see `set_chunk_size`), so that the value can be larger than the maximum
+    /// value size. Each chunk is committed in a block of its own under `CHUNK_LABEL`, and only
+    /// one chunk is held in memory at a time. The entry itself is then committed with the
+    /// borsh-encoded `ChunkedValue` that refers to the chunks, which `get` re-assembles and
+    /// verifies. There must not be any uncommitted entries.
+    ///
+    /// `iter` and `query` return the encoded `ChunkedValue` of such entries, see `is_chunked`.
+    /// Chunked values are not carried over by `merge_from`, which skips the reserved labels.
+    pub fn upsert_chunked<S: AsRef<str>, K: AsRef<[u8]>>(
+        &mut self,
+        label: S,
+        key: K,
+        mut reader: impl std::io::Read,
+    ) -> anyhow::Result<()> {
+        let mut writer = self.put_writer(label, key)?;
+        std::io::copy(&mut reader, &mut writer)?;
+        writer.finish()
+    }
+
+    /// Returns a writer that stores the value written to it under `key` of `label`, as
+    /// `upsert_chunked` does: every time a chunk is full, it is committed in a block of its own,
+    /// and `ValueWriter::finish` commits the last chunk and the entry. If the writer is dropped
+    /// without `finish`, the entry is not written. There must not be any uncommitted entries.
+    pub fn put_writer<S: AsRef<str>, K: AsRef<[u8]>>(
+        &mut self,
+        label: S,
+        key: K,
+    ) -> anyhow::Result<ValueWriter<'_>> {
+        let (label, key) = (label.as_ref(), key.as_ref());
+        self.validate_label(label)?;
+        self._check_access(AccessOperation::Upsert, label, key)?;
+        self._check_access(AccessOperation::CommitBlock, "", &[])?;
+        self._check_label_policy(label, Operation::Upsert)?;
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot store a chunked value with uncommitted entries"
+            ));
+        }
+        let chunk_size_bytes = self.chunk_size_bytes.min(self.max_value_size_bytes).max(1);
+        Ok(ValueWriter::new(self, label, key, chunk_size_bytes))
+    }
+
+    /// Commit the chunk with index `chunk_index` of the chunked value of `key` of `label`, in a
+    /// block of its own. Returns the index of the block.
+    pub(crate) fn commit_chunk(
+        &mut self,
+        label: &str,
+        key: &[u8],
+        chunk_index: u32,
+        chunk: Vec<u8>,
+    ) -> anyhow::Result<u64> {
+        let result = to_vec(&(label, key, chunk_index))
+            .map_err(anyhow::Error::from)
+            .and_then(|chunk_key| {
+                self._insert_entry_into_next_block(
+                    CHUNK_LABEL,
+                    chunk_key,
+                    chunk,
+                    Operation::Upsert,
+                )?;
+                // Read the index before the commit, which may be followed by a checkpoint block
+                let block_index = self.get_blocks_count() as u64;
+                self._commit_block()?;
+                Ok(block_index)
+            });
+        if result.is_err() {
+            self.next_block_entries.clear();
+        }
+        result
+    }
+
+    /// Commit the entry `key` of `label` with its `chunked_value`, once all chunks are committed.
+    pub(crate) fn commit_chunked_value(
+        &mut self,
+        label: &str,
+        key: &[u8],
+        chunked_value: &ChunkedValue,
+    ) -> anyhow::Result<()> {
+        let mut commit = || -> anyhow::Result<()> {
+            self._forget_value_ref(label, key)?;
+            self._insert_entry_into_next_block(
+                label,
+                key,
+                to_vec(chunked_value)?,
+                Operation::Upsert,
+            )?;
+            self._insert_entry_into_next_block(
+                CHUNKED_LABEL,
+                to_vec(&(label, key))?,
+                Vec::new(),
+                Operation::Upsert,
+            )?;
+            self._commit_block()
+        };
+        let result = commit();
+        if result.is_err() {
+            // The chunks committed so far are not referenced by any entry
+            self.next_block_entries.clear();
+        }
+        result
+    }
+
+    /// Returns whether the committed value of `key` of `label` is stored in chunks, see
+    /// `upsert_chunked`.
+    pub fn is_chunked(&self, label: &str, key: &[u8]) -> bool {
+        self.value_refs.get(&(label.to_string(), key.to_vec())) == Some(&ValueRef::Chunked)
+    }
+
+    /// Re-assemble a chunked value or blob from the chunks of its `ChunkedValue`.
+    fn _read_chunked_value(&self, chunked_value: &ChunkedValue) -> Result<EntryValue, LedgerError> {
+        let mut value = Vec::with_capacity(chunked_value.len as usize);
+        for block_index in &chunked_value.chunk_blocks {
+            value.extend(self.read_chunk(*block_index)?);
+        }
+        chunked_value.check(value.len() as u64, &sha2::Sha256::digest(&value))?;
+        Ok(value)
+    }
+
+    /// Read the chunk of a chunked value from the block with index `block_index`.
+    pub(crate) fn read_chunk(&self, block_index: u64) -> Result<EntryValue, LedgerError> {
+        let offset = self
+            .metadata
+            .borrow()
+            .block_start_pos(block_index as usize)
+            .ok_or_else(|| {
+                LedgerError::BlockCorrupted(format!("Chunk block {} not found", block_index))
+            })?;
+        // Pruning keeps the chunks, so the block in the persistent storage has them
+        let (_, ledger_block) = self._persisted_block_read(offset)?;
+        ledger_block
+            .entries()
+            .iter()
+            .find(|entry| entry.label() == CHUNK_LABEL)
+            .map(|entry| entry.value().to_vec())
+            .ok_or_else(|| {
+                LedgerError::BlockCorrupted(format!(
+                    "Block {} does not contain a chunk",
+                    block_index
+                ))
+            })
+    }
+
+    /// Store `value` in the content-addressed blob store, unless a blob with the same content
+    /// is already stored, and returns its SHA-256 hash, to be referenced by entries with
+    /// `upsert_blob_ref`. A blob is stored once, as the chunks of a chunked value (see
+    /// `upsert_chunked`), regardless of the number of entries that refer to it. There must not be
+    /// any uncommitted entries.
+    pub fn put_blob(&mut self, value: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let hash = sha2::Sha256::digest(value).to_vec();
+        if self.blobs.contains_key(&hash) {
+            return Ok(hash);
+        }
+        self._check_access(AccessOperation::CommitBlock, "", &[])?;
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot store a blob with uncommitted entries"
+            ));
+        }
+        let chunk_size_bytes = self.chunk_size_bytes.min(self.max_value_size_bytes).max(1);
+        let mut chunked_value = ChunkedValue {
+            len: value.len() as u64,
+            sha256: hash.clone(),
+            chunk_blocks: Vec::new(),
+        };
+        for (chunk_index, chunk) in value.chunks(chunk_size_bytes).enumerate() {
+            let block_index =
+                self.commit_chunk(BLOB_LABEL, &hash, chunk_index as u32, chunk.to_vec())?;
+            chunked_value.chunk_blocks.push(block_index);
+        }
+        let result = to_vec(&chunked_value)
+            .map_err(anyhow::Error::from)
+            .and_then(|encoded| {
+                self._insert_entry_into_next_block(BLOB_LABEL, &hash, encoded, Operation::Upsert)?;
+                self._commit_block()
+            });
+        if result.is_err() {
+            self.next_block_entries.clear();
+        }
+        result.map(|_| hash)
+    }
+
+    /// Returns whether a blob with the SHA-256 hash `hash` is committed in the blob store.
+    pub fn has_blob(&self, hash: &[u8]) -> bool {
+        self.blobs.contains_key(hash)
+    }
+
+    /// Returns the number of committed entries that refer to the blob with the SHA-256 hash
+    /// `hash`, see `upsert_blob_ref`.
+    pub fn blob_ref_count(&self, hash: &[u8]) -> u64 {
+        self.blob_ref_counts.get(hash).copied().unwrap_or_default()
+    }
+
+    /// Remove the blobs that no committed entry refers to from the blob store, in a block of its
+    /// own, and reclaim the storage of their chunks by compacting the blocks that hold them,
+    /// like `prune_blocks` does. Blocks without entry hashes are only compacted if none of their
+    /// entries remain. There must not be any uncommitted entries. Returns the number of bytes
+    /// reclaimed.
+    pub fn gc_blobs(&mut self) -> anyhow::Result<u64> {
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot collect blobs with uncommitted entries"
+            ));
+        }
+        let unreferenced = self
+            .blobs
+            .keys()
+            .filter(|hash| !self.blob_ref_counts.contains_key(*hash))
+            .cloned()
+            .collect::<Vec<_>>();
+        if unreferenced.is_empty() {
+            return Ok(0);
+        }
+        info!("Collecting {} unreferenced blobs", unreferenced.len());
+        let result = unreferenced.iter().try_for_each(|hash| {
+            self._insert_entry_into_next_block(BLOB_LABEL, hash, Vec::new(), Operation::Delete)
+        });
+        let result = result
+            .map_err(anyhow::Error::from)
+            .and_then(|_| self._commit_block());
+        if let Err(err) = result {
+            self.next_block_entries.clear();
+            return Err(err);
+        }
+
+        // Drop the chunks and the records of the collected blobs
+        self._compact_blocks(|ledger_map, _, entry| {
+            Ok(match entry.label() {
+                BLOB_LABEL => ledger_map.blobs.contains_key(entry.key()),
+                CHUNK_LABEL => <(String, Vec<u8>, u32)>::try_from_slice(entry.key())
+                    .map_or(true, |(label, key, _)| {
+                        label != BLOB_LABEL || ledger_map.blobs.contains_key(&key)
+                    }),
+                _ => true,
+            })
+        })
+    }
+
+    /// Set the value of `key` of `label` to the blob with the SHA-256 hash `hash`, which must be
+    /// committed with `put_blob`. The entry holds only the hash, and `get` and `get_reader`
+    /// return the content of the blob. `iter` and `query` return the hash.
+    pub fn upsert_blob_ref<S: AsRef<str>, K: AsRef<[u8]>>(
+        &mut self,
+        label: S,
+        key: K,
+        hash: &[u8],
+    ) -> Result<(), LedgerError> {
+        let (label, key) = (label.as_ref(), key.as_ref());
+        self.validate_label(label)?;
+        self._check_access(AccessOperation::Upsert, label, key)?;
+        if !self.has_blob(hash) {
+            return Err(LedgerError::EntryNotFound);
+        }
+        self._insert_entry_into_next_block(label, key, hash, Operation::Upsert)?;
+        self._forget_value_ref(label, key)?;
+        let record_key =
+            to_vec(&(label, key)).map_err(|e| LedgerError::Serialization(e.to_string()))?;
+        self._insert_entry_into_next_block(BLOB_REF_LABEL, record_key, hash, Operation::Upsert)
+    }
+
+    /// Returns whether the next block sets `key` of `label` to a blob reference.
+    fn _has_pending_blob_ref(&self, label: &str, key: &[u8]) -> Result<bool, LedgerError> {
+        let record_key =
+            to_vec(&(label, key)).map_err(|e| LedgerError::Serialization(e.to_string()))?;
+        Ok(self
+            .next_block_entries
+            .get(BLOB_REF_LABEL)
+            .and_then(|records| records.get(&record_key))
+            .is_some_and(|record| record.operation() == Operation::Upsert))
+    }
+
+    /// Mark the entry `key` of `label` as holding a plain value in the next block, if it holds a
+    /// reference, committed or not, since the next block replaces its value.
+    fn _forget_value_ref(&mut self, label: &str, key: &[u8]) -> Result<(), LedgerError> {
+        let record_key =
+            to_vec(&(label, key)).map_err(|e| LedgerError::Serialization(e.to_string()))?;
+        let committed = self
+            .value_refs
+            .get(&(label.to_string(), key.to_vec()))
+            .cloned();
+        if committed == Some(ValueRef::Chunked) {
+            self._insert_entry_into_next_block(
+                CHUNKED_LABEL,
+                &record_key,
+                Vec::new(),
+                Operation::Delete,
+            )?;
+        }
+        if matches!(committed, Some(ValueRef::Blob(_))) || self._has_pending_blob_ref(label, key)? {
+            self._insert_entry_into_next_block(
+                BLOB_REF_LABEL,
+                &record_key,
+                Vec::new(),
+                Operation::Delete,
+            )?;
+        }
+        Ok(())
     }
 
     pub fn refresh_ledger(&mut self) -> anyhow::Result<()> {
-        self.metadata.borrow_mut().clear();
+        self.data_partition_bounds = self._read_data_partition_bounds()?;
+        *self.metadata.borrow_mut() = Metadata::with_start_pos(self.data_partition_bounds.0);
         self.entries.clear();
+        self.lazy_entries.clear();
         self.next_block_entries.clear();
+        self.ledger_info = None;
+        self.live_state.clear();
+        self.label_stats.clear();
+        self.label_blocks.clear();
+        self.label_policies.clear();
+        self.value_refs.clear();
+        self.blob_ref_counts.clear();
+        self.blobs.clear();
+        #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+        self.certified_index.clear();
+        for index in self
+            .secondary_indexes
+            .values_mut()
+            .flat_map(|i| i.values_mut())
+        {
+            index.clear();
+        }
 
         // If the backend is empty or non-existing, just return
-        if persistent_storage_size_bytes() == 0 {
+        if self._storage_size_bytes() == 0 {
             warn!("Persistent storage is empty");
             return Ok(());
         }
 
-        let data_part_entry = partition_table::get_data_partition();
-        if persistent_storage_size_bytes() < data_part_entry.start_lba {
+        if self._storage_size_bytes() < self.data_partition_bounds.0 {
             warn!("No data found in persistent storage");
             return Ok(());
         }
 
         let mut expected_parent_hash = Vec::new();
+        let mut prev_timestamp_ns = None;
+        let mut block_version = self.block_version;
         let mut updates = Vec::new();
         // Step 1: Read all Ledger Blocks
-        for entry in self.iter_raw() {
-            let (block_header, ledger_block) = entry?;
+        for entry in self.iter_raw_with_hashes() {
+            let (block_header, ledger_block, new_chain_hash) = entry?;
 
-            if ledger_block.parent_hash() != expected_parent_hash {
+            if self.reject_non_monotonic_timestamps
+                && prev_timestamp_ns.is_some_and(|prev| ledger_block.timestamp() <= prev)
+            {
                 return Err(anyhow::format_err!(
-                    "Hash mismatch: expected parent hash {:?}, got {:?}",
-                    expected_parent_hash,
-                    ledger_block.parent_hash()
+                    "Block at offset {} has timestamp {}, not after the previous block timestamp {}",
+                    ledger_block.get_offset(),
+                    ledger_block.timestamp(),
+                    prev_timestamp_ns.unwrap_or_default()
                 ));
-            };
+            }
+            prev_timestamp_ns = Some(ledger_block.timestamp());
 
-            let new_chain_hash = Self::_compute_block_chain_hash(
-                ledger_block.parent_hash(),
-                ledger_block.entries(),
-                ledger_block.timestamp(),
-            )?;
+            if ledger_block.parent_hash() != expected_parent_hash {
+                return Err(LedgerError::HashMismatch {
+                    expected: expected_parent_hash,
+                    actual: ledger_block.parent_hash().to_vec(),
+                    offset: ledger_block.get_offset(),
+                }
+                .into());
+            };
 
             let next_block_start_pos = self.metadata.borrow().next_block_start_pos()
                 + block_header.jump_bytes_next_block() as u64;
@@ -233,301 +1893,3233 @@ impl LedgerMap {
                 next_block_start_pos,
             );
             expected_parent_hash = new_chain_hash;
-
+            block_version =
+                Self::_loaded_block_version(block_version, &block_header, &ledger_block);
             updates.push(ledger_block);
         }
 
-        // Step 2: Add ledger entries into the index (self.entries) for quick search
-        for ledger_block in updates.into_iter() {
-            for ledger_entry in ledger_block.entries() {
-                // Skip entries that are not in the labels_to_index
-                if !match &self.labels_to_index {
-                    Some(labels_to_index) => labels_to_index.contains(ledger_entry.label()),
-                    None => true,
-                } {
-                    continue;
-                }
-                let entries = match self.entries.get_mut(ledger_entry.label()) {
-                    Some(entries) => entries,
-                    None => {
-                        let new_map = IndexMap::new();
-                        self.entries
-                            .insert(ledger_entry.label().to_string(), new_map);
-                        self.entries
-                            .get_mut(ledger_entry.label())
-                            .ok_or(anyhow::format_err!(
-                                "Entry label {:?} not found",
-                                ledger_entry.label()
-                            ))?
-                    }
-                };
+        self.block_version = block_version;
+        if let Some(first_block) = updates.first() {
+            self.ledger_info = Self::_read_ledger_info(first_block)?;
+        }
 
-                match &ledger_entry.operation() {
-                    Operation::Upsert => {
-                        entries.insert(ledger_entry.key().to_vec(), ledger_entry.clone());
-                    }
-                    Operation::Delete => {
-                        entries.swap_remove(&ledger_entry.key().to_vec());
-                    }
+        // Step 2: Add ledger entries into the index (self.entries) for quick search
+        let mut lazy_labels = BTreeSet::new();
+        for (block_index, ledger_block) in updates.into_iter().enumerate() {
+            self._index_block(block_index, &ledger_block, &mut lazy_labels)?;
+        }
+        if self.lazy_index {
+            for label in lazy_labels {
+                if self._is_label_indexed(&label) {
+                    self.lazy_entries.insert(label, OnceCell::new());
                 }
             }
+            let labels = self.secondary_indexes.keys().cloned().collect::<Vec<_>>();
+            for label in labels {
+                self._rebuild_secondary_indexes(&label)?;
+            }
+            #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+            self._rebuild_certified_index();
         }
         debug!("Ledger refreshed successfully");
 
         Ok(())
     }
 
-    pub fn next_block_iter(&self, label: Option<&str>) -> impl Iterator<Item = &LedgerEntry> {
-        match label {
-            Some(label) => self
-                .next_block_entries
-                .get(label)
-                .map(|entries| entries.values())
-                .unwrap_or_default()
-                .filter(|entry| entry.operation() == Operation::Upsert)
-                .collect::<Vec<_>>()
-                .into_iter(),
-            None => self
-                .next_block_entries
-                .values()
-                .flat_map(|entries| entries.values())
-                .filter(|entry| entry.operation() == Operation::Upsert)
-                .collect::<Vec<_>>()
-                .into_iter(),
-        }
-    }
+    /// Add the entries of the committed block `block_index` to the index. With a lazy index,
+    /// the labels whose index is loaded on first access are added to `lazy_labels` instead.
+    fn _index_block(
+        &mut self,
+        block_index: usize,
+        ledger_block: &LedgerBlock,
+        lazy_labels: &mut BTreeSet<String>,
+    ) -> anyhow::Result<()> {
+        for ledger_entry in ledger_block.entries() {
+            record_label_block(&mut self.label_blocks, ledger_entry.label(), block_index);
+            self.live_state.apply(ledger_entry);
+            self.label_stats
+                .apply(ledger_entry, block_index as u64, ledger_block.timestamp());
+            apply_label_policy_record(&mut self.label_policies, ledger_entry)?;
+            apply_value_ref_record(
+                &mut self.value_refs,
+                &mut self.blob_ref_counts,
+                ledger_entry,
+            )?;
+            apply_blob_record(&mut self.blobs, ledger_entry)?;
+            if ledger_entry.label() == RENAME_LABEL && ledger_entry.operation() == Operation::Upsert
+            {
+                let old_label = String::from_utf8_lossy(ledger_entry.key());
+                let new_label = String::from_utf8_lossy(ledger_entry.value());
+                if self.lazy_index {
+                    self._rename_value_refs(&old_label, &new_label);
+                    lazy_labels.insert(new_label.to_string());
+                } else {
+                    self._apply_label_rename(&old_label, &new_label)?;
+                }
+            }
+            // Skip entries that are not in the labels_to_index
+            if !self._is_label_indexed(ledger_entry.label()) {
+                continue;
+            }
+            // The index of the label is loaded on first access
+            if self.lazy_index {
+                lazy_labels.insert(ledger_entry.label().to_string());
+                continue;
+            }
+            #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+            self.certified_index.apply(ledger_entry);
+            update_secondary_indexes(&mut self.secondary_indexes, &self.entries, ledger_entry);
+            let kind = self._label_index_kind(ledger_entry.label());
+            let entries = self
+                .entries
+                .entry(ledger_entry.label().to_string())
+                .or_insert_with(|| LabelIndex::new(kind));
 
-    pub fn for_each<F>(&self, label: &str, mut f: F)
-    where
-        F: FnMut(&[u8], &[u8]),
-    {
-        if let Some(entries) = self.entries.get(label) {
-            for (key, entry) in entries.iter() {
-                f(key.as_slice(), entry.value());
+            match &ledger_entry.operation() {
+                Operation::Upsert => {
+                    entries.insert(ledger_entry.key().to_vec(), ledger_entry.clone());
+                }
+                Operation::Delete => {
+                    entries.remove(ledger_entry.key());
+                }
             }
         }
-        if let Some(entries) = self.next_block_entries.get(label) {
-            for (key, entry) in entries.iter() {
-                f(key.as_slice(), entry.value());
+        Ok(())
+    }
+
+    /// Rebuild the certified index from the live state. The certified index covers all labels,
+    /// so with a lazy index it is not built from the label indexes.
+    #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+    fn _rebuild_certified_index(&mut self) {
+        self.certified_index.clear();
+        for leaf in self.live_state.leaves() {
+            if self._is_label_indexed(&leaf.label) {
+                self.certified_index.insert_value_hash(
+                    leaf.label.as_bytes(),
+                    &leaf.key,
+                    leaf.value_hash,
+                );
             }
         }
     }
 
-    pub fn iter(&self, label: Option<&str>) -> impl Iterator<Item = &LedgerEntry> {
-        match label {
-            Some(label) => self
-                .entries
-                .get(label)
-                .map(|entries| entries.values())
+    /// Load the blocks that were appended to the persistent storage since the ledger was loaded,
+    /// e.g. by a writer in another process, without reloading the blocks that are already
+    /// loaded. If the persistent storage no longer continues the loaded chain, e.g. because it
+    /// was compacted or restored, the whole ledger is reloaded with `refresh_ledger`. Returns the
+    /// number of blocks that were loaded. Uncommitted entries are kept.
+    pub fn refresh_new_blocks(&mut self) -> anyhow::Result<usize> {
+        if self._read_data_partition_bounds()? != self.data_partition_bounds
+            || !self._is_loaded_tip_unchanged()
+        {
+            self.refresh_ledger()?;
+            return Ok(self.get_blocks_count());
+        }
+        let mut expected_parent_hash = self.get_latest_block_hash();
+        let mut prev_timestamp_ns =
+            (self.get_blocks_count() > 0).then(|| self.get_latest_block_timestamp_ns());
+        let mut next_block_start_pos = self.get_next_block_start_pos();
+        let mut block_version = self.block_version;
+        let mut updates = Vec::new();
+        let mut continues_chain = true;
+        for block in self._iter_raw_from(next_block_start_pos) {
+            let (block_header, ledger_block) = block?;
+            if ledger_block.parent_hash() != expected_parent_hash {
+                continues_chain = false;
+                break;
+            }
+            if self.reject_non_monotonic_timestamps
+                && prev_timestamp_ns.is_some_and(|prev| ledger_block.timestamp() <= prev)
+            {
+                return Err(anyhow::format_err!(
+                    "Block at offset {} has timestamp {}, not after the previous block timestamp {}",
+                    ledger_block.get_offset(),
+                    ledger_block.timestamp(),
+                    prev_timestamp_ns.unwrap_or_default()
+                ));
+            }
+            prev_timestamp_ns = Some(ledger_block.timestamp());
+            expected_parent_hash = Self::_block_chain_hash(&ledger_block)?;
+            next_block_start_pos += block_header.jump_bytes_next_block() as u64;
+            block_version =
+                Self::_loaded_block_version(block_version, &block_header, &ledger_block);
+            updates.push((
+                ledger_block,
+                expected_parent_hash.clone(),
+                next_block_start_pos,
+            ));
+        }
+        if !continues_chain {
+            self.refresh_ledger()?;
+            return Ok(self.get_blocks_count());
+        }
+        self.block_version = block_version;
+
+        let first_block_index = self.get_blocks_count();
+        let mut lazy_labels = BTreeSet::new();
+        for (i, (ledger_block, chain_hash, next_block_start_pos)) in updates.iter().enumerate() {
+            self.metadata.borrow_mut().update_from_appended_block(
+                chain_hash,
+                ledger_block.timestamp(),
+                *next_block_start_pos,
+            );
+            if first_block_index + i == 0 {
+                self.ledger_info = Self::_read_ledger_info(ledger_block)?;
+            }
+            // The labels renamed away change as well
+            for entry in ledger_block.entries() {
+                if entry.label() == RENAME_LABEL && entry.operation() == Operation::Upsert {
+                    lazy_labels.insert(String::from_utf8_lossy(entry.key()).to_string());
+                }
+            }
+            self._index_block(first_block_index + i, ledger_block, &mut lazy_labels)?;
+        }
+        if self.lazy_index && !updates.is_empty() {
+            // The indexes of the changed labels are reloaded on next access
+            for label in lazy_labels {
+                if self._is_label_indexed(&label) {
+                    self.entries.swap_remove(&label);
+                    self.lazy_entries.insert(label.clone(), OnceCell::new());
+                    self._rebuild_secondary_indexes(&label)?;
+                }
+            }
+            #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+            self._rebuild_certified_index();
+        }
+        Ok(updates.len())
+    }
+
+    /// Block version to write new blocks with after loading `ledger_block`: at least its
+    /// version, so that the version set by `migrate_format` or another writer is kept when the
+    /// ledger is loaded again. Pruned blocks are skipped, since they are rewritten in version 2.
+    fn _loaded_block_version(
+        block_version: u32,
+        block_header: &LedgerBlockHeader,
+        ledger_block: &LedgerBlock,
+    ) -> u32 {
+        match ledger_block.is_pruned() {
+            true => block_version,
+            false => block_version.max(block_header.block_version()),
+        }
+    }
+
+    /// Returns whether the last loaded block is still in the persistent storage as it was loaded.
+    fn _is_loaded_tip_unchanged(&self) -> bool {
+        if self.get_blocks_count() == 0 {
+            return true;
+        }
+        self._persisted_block_read(self.get_latest_block_start_pos())
+            .ok()
+            .and_then(|(_, ledger_block)| Self::_block_chain_hash(&ledger_block).ok())
+            .is_some_and(|chain_hash| chain_hash == self.get_latest_block_hash())
+    }
+
+    /// Rebuild the index of `label` from the persistent storage, without refreshing the rest of
+    /// the ledger, e.g. to recover from a corrupted index of a single label. Only the blocks with
+    /// entries of the label, of the labels renamed to it, or with renames are decoded; blocks of
+    /// version 3 and later are skipped based on their `BlockSummary`. The secondary indexes of
+    /// the label are rebuilt as well. Uncommitted entries are kept.
+    pub fn refresh_label(&mut self, label: &str) -> anyhow::Result<()> {
+        let entries = self._load_label_index(label, false)?;
+        #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+        {
+            let old_keys = self
+                ._label_entries(label)
+                .ok()
+                .flatten()
+                .into_iter()
+                .flat_map(|e| e.values())
+                .map(|entry| entry.key().to_vec())
+                .collect::<Vec<_>>();
+            for key in old_keys {
+                self.certified_index.apply(&LedgerEntry::new(
+                    label,
+                    &key,
+                    Vec::new(),
+                    Operation::Delete,
+                ));
+            }
+        }
+        self.lazy_entries.swap_remove(label);
+        match entries {
+            Some(entries) => {
+                #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+                for entry in entries.values() {
+                    self.certified_index.apply(entry);
+                }
+                self.entries.insert(label.to_string(), entries);
+            }
+            None => {
+                self.entries.swap_remove(label);
+            }
+        }
+        self._rebuild_secondary_indexes(label)?;
+        debug!("Label {} refreshed successfully", label);
+        Ok(())
+    }
+
+    /// Build the index of `label` from the blocks with entries of the label, of the labels
+    /// renamed to it, or with renames, replaying them as `refresh_ledger` does. The blocks are
+    /// found with `label_blocks` if `use_label_blocks`, and otherwise by scanning the block
+    /// headers and summaries. Returns `None` if the label has no index.
+    fn _load_label_index(
+        &self,
+        label: &str,
+        use_label_blocks: bool,
+    ) -> anyhow::Result<Option<LabelIndex>> {
+        let read_blocks = |labels: &BTreeSet<String>| -> anyhow::Result<Vec<LedgerBlock>> {
+            if !use_label_blocks {
+                return self
+                    ._iter_raw_touching(|block_label| labels.contains(block_label))
+                    .map(|block| block.map(|(_, ledger_block)| ledger_block))
+                    .collect();
+            }
+            let block_indexes = labels
+                .iter()
+                .filter_map(|label| self.label_blocks.get(label))
+                .flatten()
+                .copied()
+                .collect::<BTreeSet<_>>();
+            block_indexes
+                .into_iter()
+                .map(|block_index| {
+                    let offset = self
+                        .metadata
+                        .borrow()
+                        .block_start_pos(block_index)
+                        .ok_or_else(|| anyhow::format_err!("Block {} not found", block_index))?;
+                    Ok(self._persisted_block_read(offset)?.1)
+                })
+                .collect()
+        };
+
+        // Labels whose entries may end up under `label` through renames
+        let mut renames = Vec::new();
+        for ledger_block in read_blocks(&BTreeSet::from([RENAME_LABEL.to_string()]))? {
+            for entry in ledger_block.entries() {
+                if entry.label() == RENAME_LABEL && entry.operation() == Operation::Upsert {
+                    renames.push((
+                        String::from_utf8_lossy(entry.key()).to_string(),
+                        String::from_utf8_lossy(entry.value()).to_string(),
+                    ));
+                }
+            }
+        }
+        let mut lineage = BTreeSet::from([label.to_string()]);
+        loop {
+            let len = lineage.len();
+            for (old_label, new_label) in &renames {
+                if lineage.contains(old_label) || lineage.contains(new_label) {
+                    lineage.insert(old_label.clone());
+                    lineage.insert(new_label.clone());
+                }
+            }
+            if lineage.len() == len {
+                break;
+            }
+        }
+
+        // Replay the entries and renames of these labels
+        let mut indexes: IndexMap<String, LabelIndex> = IndexMap::new();
+        let mut block_labels = lineage.clone();
+        block_labels.insert(RENAME_LABEL.to_string());
+        for ledger_block in read_blocks(&block_labels)? {
+            for ledger_entry in ledger_block.entries() {
+                if ledger_entry.label() == RENAME_LABEL
+                    && ledger_entry.operation() == Operation::Upsert
+                {
+                    let old_label = String::from_utf8_lossy(ledger_entry.key());
+                    let new_label = String::from_utf8_lossy(ledger_entry.value());
+                    let Some(old_entries) = indexes.swap_remove(old_label.as_ref()) else {
+                        continue;
+                    };
+                    if !self._is_label_indexed(&new_label) {
+                        continue;
+                    }
+                    let kind = self._label_index_kind(&new_label);
+                    let new_entries = indexes
+                        .entry(new_label.to_string())
+                        .or_insert_with(|| LabelIndex::new(kind));
+                    for (key, entry) in old_entries {
+                        if entry.operation() == Operation::Upsert {
+                            let renamed = LedgerEntry::new(
+                                &new_label,
+                                &key,
+                                entry.value(),
+                                Operation::Upsert,
+                            );
+                            new_entries.insert(key, renamed);
+                        }
+                    }
+                    continue;
+                }
+                if !lineage.contains(ledger_entry.label())
+                    || !self._is_label_indexed(ledger_entry.label())
+                {
+                    continue;
+                }
+                let kind = self._label_index_kind(ledger_entry.label());
+                let entries = indexes
+                    .entry(ledger_entry.label().to_string())
+                    .or_insert_with(|| LabelIndex::new(kind));
+                match ledger_entry.operation() {
+                    Operation::Upsert => {
+                        entries.insert(ledger_entry.key().to_vec(), ledger_entry.clone());
+                    }
+                    Operation::Delete => {
+                        entries.remove(ledger_entry.key());
+                    }
+                }
+            }
+        }
+        Ok(indexes.swap_remove(label))
+    }
+
+    /// Committed entries of `label`, loading its index first if it is not materialized yet.
+    fn _label_entries(&self, label: &str) -> Result<Option<&LabelIndex>, LedgerError> {
+        if let Some(entries) = self.entries.get(label) {
+            return Ok(Some(entries));
+        }
+        let Some(cell) = self.lazy_entries.get(label) else {
+            return Ok(None);
+        };
+        if cell.get().is_none() {
+            let entries = self._load_label_index(label, true).map_err(|err| {
+                error!("Failed to load the index of label {}: {}", label, err);
+                LedgerError::Other(err.to_string())
+            })?;
+            let _ = cell.set(entries);
+        }
+        Ok(cell.get().and_then(Option::as_ref))
+    }
+
+    /// Committed entries of all labels, label by label, loading the indexes that are not
+    /// materialized yet. Fails if the index of a label fails to load.
+    fn _all_label_entries(&self) -> Result<Vec<(&String, &LabelIndex)>, LedgerError> {
+        let mut all_entries = self.entries.iter().collect::<Vec<_>>();
+        for label in self.lazy_entries.keys() {
+            if let Some(entries) = self._label_entries(label)? {
+                all_entries.push((label, entries));
+            }
+        }
+        Ok(all_entries)
+    }
+
+    /// Move the index of `label` from `lazy_entries` to `entries`, loading it first if needed,
+    /// before the index is modified.
+    fn _materialize_label(&mut self, label: &str) -> anyhow::Result<()> {
+        let Some(cell) = self.lazy_entries.get(label) else {
+            return Ok(());
+        };
+        if cell.get().is_none() {
+            let _ = cell.set(self._load_label_index(label, true)?);
+        }
+        if let Some(entries) = self
+            .lazy_entries
+            .swap_remove(label)
+            .and_then(OnceCell::into_inner)
+            .flatten()
+        {
+            self.entries.insert(label.to_string(), entries);
+        }
+        Ok(())
+    }
+
+    /// Rename the label `old_label` to `new_label`, in a block of its own that records the
+    /// rename. From then on, the entries of `old_label` are read under `new_label`, while the
+    /// blocks keep them under `old_label`, so the history remains verifiable. `new_label` must
+    /// not have any entries, and there must not be any uncommitted entries.
+    ///
+    /// The rename is checked as a delete of `old_label` and an upsert of `new_label`, and the
+    /// policy of `old_label`, if it has one, moves to `new_label`.
+    pub fn rename_label(&mut self, old_label: &str, new_label: &str) -> anyhow::Result<()> {
+        self.validate_label(old_label)?;
+        self.validate_label(new_label)?;
+        if old_label == new_label {
+            return Err(anyhow::format_err!(
+                "Cannot rename label {:?} to itself",
+                old_label
+            ));
+        }
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot rename a label with uncommitted entries"
+            ));
+        }
+        if self.iter(Some(new_label)).next().is_some() {
+            return Err(anyhow::format_err!(
+                "Cannot rename label {:?} to {:?}, which already has entries",
+                old_label,
+                new_label
+            ));
+        }
+        self._check_access(AccessOperation::Delete, old_label, &[])?;
+        self._check_access(AccessOperation::Upsert, new_label, &[])?;
+        self._check_label_policy(old_label, Operation::Delete)?;
+        self._check_label_policy(new_label, Operation::Upsert)?;
+        self._materialize_label(old_label)?;
+        self._materialize_label(new_label)?;
+        let result = self._insert_label_rename_records(old_label, new_label);
+        let result = result.and_then(|_| self.commit_block());
+        if result.is_err() {
+            self.next_block_entries.clear();
+            return result;
+        }
+        self._apply_label_rename(old_label, new_label)
+    }
+
+    /// Insert the record of the rename of `old_label` to `new_label` into the next block, with
+    /// the records that move the policy of `old_label` to `new_label`.
+    fn _insert_label_rename_records(
+        &mut self,
+        old_label: &str,
+        new_label: &str,
+    ) -> anyhow::Result<()> {
+        self._insert_entry_into_next_block(RENAME_LABEL, old_label, new_label, Operation::Upsert)?;
+        if let Some(policy) = self.label_policies.get(old_label) {
+            let policy = to_vec(policy)?;
+            self._insert_entry_into_next_block(
+                POLICY_LABEL,
+                old_label,
+                Vec::new(),
+                Operation::Delete,
+            )?;
+            self._insert_entry_into_next_block(POLICY_LABEL, new_label, policy, Operation::Upsert)?;
+        }
+        Ok(())
+    }
+
+    /// Move the value references of `old_label` to `new_label`.
+    fn _rename_value_refs(&mut self, old_label: &str, new_label: &str) {
+        let renamed_value_refs = self
+            .value_refs
+            .iter()
+            .filter(|((label, _), _)| label == old_label)
+            .map(|((label, key), value_ref)| (label.clone(), key.clone(), value_ref.clone()))
+            .collect::<Vec<_>>();
+        for (label, key, value_ref) in renamed_value_refs {
+            self.value_refs.remove(&(label, key.clone()));
+            self.value_refs
+                .insert((new_label.to_string(), key), value_ref);
+        }
+    }
+
+    /// Move the indexed entries of `old_label` to `new_label`.
+    fn _apply_label_rename(&mut self, old_label: &str, new_label: &str) -> anyhow::Result<()> {
+        self._rename_value_refs(old_label, new_label);
+        let old_entries = match self.entries.swap_remove(old_label) {
+            Some(old_entries) => old_entries,
+            None => return Ok(()),
+        };
+        if !self._is_label_indexed(new_label) {
+            return Ok(());
+        }
+        let kind = self._label_index_kind(new_label);
+        let new_entries = self
+            .entries
+            .entry(new_label.to_string())
+            .or_insert_with(|| LabelIndex::new(kind));
+        for (key, entry) in old_entries {
+            if entry.operation() != Operation::Upsert {
+                continue;
+            }
+            let renamed = LedgerEntry::new(new_label, &key, entry.value(), Operation::Upsert);
+            #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+            {
+                self.certified_index.apply(&LedgerEntry::new(
+                    old_label,
+                    &key,
+                    Vec::new(),
+                    Operation::Delete,
+                ));
+                self.certified_index.apply(&renamed);
+            }
+            new_entries.insert(key, renamed);
+        }
+        self._rebuild_secondary_indexes(old_label)?;
+        self._rebuild_secondary_indexes(new_label)
+    }
+
+    /// Maintain the secondary index `index_name` of the committed entries of `label`, with the
+    /// index keys that `extractor` returns for each entry value. A previously registered index
+    /// of the same name is replaced. The label must be indexed. Fails if the index of the label
+    /// fails to load.
+    pub fn register_index<S: AsRef<str>>(
+        &mut self,
+        label: S,
+        index_name: S,
+        extractor: impl Fn(&[u8]) -> Vec<IndexKey> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let extractor: IndexExtractor = Box::new(extractor);
+        self.secondary_indexes
+            .entry(label.as_ref().to_string())
+            .or_default()
+            .insert(
+                index_name.as_ref().to_string(),
+                SecondaryIndex::new(extractor),
+            );
+        self._rebuild_secondary_indexes(label.as_ref())
+    }
+
+    /// Returns the committed entries of `label` with the key `index_key` in the secondary index
+    /// `index_name`, in ascending order of the entry keys.
+    pub fn get_by_index<S: AsRef<str>>(
+        &self,
+        label: S,
+        index_name: S,
+        index_key: &[u8],
+    ) -> Result<Vec<&LedgerEntry>, LedgerError> {
+        let index = self
+            .secondary_indexes
+            .get(label.as_ref())
+            .and_then(|indexes| indexes.get(index_name.as_ref()))
+            .ok_or_else(|| {
+                LedgerError::Other(format!(
+                    "No index {:?} registered for label {:?}",
+                    index_name.as_ref(),
+                    label.as_ref()
+                ))
+            })?;
+        let entries = self._label_entries(label.as_ref())?;
+        Ok(index
+            .get(index_key)
+            .filter_map(|key| entries.and_then(|entries| entries.get(key)))
+            .collect())
+    }
+
+    /// Rebuild the secondary indexes of `label` from its committed entries, materializing its
+    /// index first. Fails if the index of the label fails to load.
+    fn _rebuild_secondary_indexes(&mut self, label: &str) -> anyhow::Result<()> {
+        if !self.secondary_indexes.contains_key(label) {
+            return Ok(());
+        }
+        self._materialize_label(label)?;
+        let indexes = match self.secondary_indexes.get_mut(label) {
+            Some(indexes) => indexes,
+            None => return Ok(()),
+        };
+        for index in indexes.values_mut() {
+            index.clear();
+            for entry in self.entries.get(label).into_iter().flat_map(|e| e.values()) {
+                if entry.operation() == Operation::Upsert {
+                    index.update(entry.key(), None, Some(entry.value()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify the hash chain of all persisted blocks, without changing the in-memory state.
+    /// Returns the chain hash of the last block, or `LedgerError::HashMismatch` for the first
+    /// block whose parent hash does not match the chain hash of the previous block.
+    pub fn verify_chain(&self) -> anyhow::Result<Vec<u8>> {
+        let mut expected_parent_hash = Vec::new();
+        for entry in self.iter_raw_with_hashes() {
+            let (_, ledger_block, chain_hash) = entry?;
+            if ledger_block.parent_hash() != expected_parent_hash {
+                return Err(LedgerError::HashMismatch {
+                    expected: expected_parent_hash,
+                    actual: ledger_block.parent_hash().to_vec(),
+                    offset: ledger_block.get_offset(),
+                }
+                .into());
+            }
+            expected_parent_hash = chain_hash;
+        }
+        Ok(expected_parent_hash)
+    }
+
+    /// Re-verify the blocks with indexes `from_block..to_block`: that each block links to the
+    /// previous one, and that its chain hash matches the one computed when the ledger was loaded.
+    /// Only the blocks of the range are read, so large ledgers can be scrubbed in the background
+    /// a range at a time, instead of running `verify_chain` or `refresh_ledger` over everything.
+    pub fn verify_range(&self, from_block: usize, to_block: usize) -> anyhow::Result<()> {
+        let blocks_count = self.get_blocks_count();
+        if from_block > to_block || to_block > blocks_count {
+            return Err(anyhow::format_err!(
+                "Invalid block range {}..{} of a ledger with {} blocks",
+                from_block,
+                to_block,
+                blocks_count
+            ));
+        }
+        if from_block == to_block {
+            return Ok(());
+        }
+        let start = self
+            .metadata
+            .borrow()
+            .block_start_pos(from_block)
+            .ok_or_else(|| anyhow::format_err!("Block {} not found", from_block))?;
+        let mut expected_parent_hash = match from_block.checked_sub(1) {
+            Some(index) => self.get_chain_hash_at(index).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let mut index = from_block;
+        for block in self._iter_raw_from(start).take(to_block - from_block) {
+            let (_, ledger_block) = block?;
+            if ledger_block.parent_hash() != expected_parent_hash {
+                return Err(LedgerError::HashMismatch {
+                    expected: expected_parent_hash,
+                    actual: ledger_block.parent_hash().to_vec(),
+                    offset: ledger_block.get_offset(),
+                }
+                .into());
+            }
+            let chain_hash = Self::_block_chain_hash(&ledger_block)?;
+            if Some(&chain_hash) != self.get_chain_hash_at(index).as_ref() {
+                return Err(LedgerError::BlockCorrupted(format!(
+                    "Chain hash of block {} at offset {} changed since the ledger was loaded",
+                    index,
+                    ledger_block.get_offset()
+                ))
+                .into());
+            }
+            expected_parent_hash = chain_hash;
+            index += 1;
+        }
+        if index != to_block {
+            return Err(LedgerError::BlockCorrupted(format!(
+                "Block {} not found in the persistent storage",
+                index
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Determine the relation of the local chain to a remote chain with `remote_height` blocks and
+    /// the chain hash `remote_tip_hash` (empty for an empty chain). The chain hashes of the local
+    /// blocks are kept in the metadata, so no blocks are read or hashed.
+    ///
+    /// A longer remote chain is never trusted to extend the local chain from its height alone:
+    /// it is reported as `ForkStatus::NeedsVerification`.
+    pub fn check_fork(
+        &self,
+        remote_tip_hash: &[u8],
+        remote_height: u64,
+    ) -> anyhow::Result<ForkStatus> {
+        let local_height = self.get_blocks_count() as u64;
+        if remote_height > local_height {
+            return Ok(ForkStatus::NeedsVerification);
+        }
+        let local_hash_at_remote_height = match remote_height.checked_sub(1) {
+            Some(index) => match self.get_chain_hash_at(index as usize) {
+                Some(chain_hash) => chain_hash,
+                None => {
+                    return Err(anyhow::format_err!(
+                        "Block at height {} not found",
+                        remote_height
+                    ))
+                }
+            },
+            None => Vec::new(),
+        };
+        if local_hash_at_remote_height != remote_tip_hash {
+            Ok(ForkStatus::Fork)
+        } else if remote_height == local_height {
+            Ok(ForkStatus::Equal)
+        } else {
+            Ok(ForkStatus::Descendant)
+        }
+    }
+
+    /// Like `check_fork`, with `remote_hash_at_local_height` the chain hash of the remote chain
+    /// at the local height, i.e. of its block at the index of the last local block (empty for an
+    /// empty local chain), which the peer returns with `get_chain_hash_at`. A longer remote chain
+    /// is then an `Ancestor` if that hash is the local tip, and a `Fork` otherwise.
+    pub fn check_fork_at(
+        &self,
+        remote_tip_hash: &[u8],
+        remote_height: u64,
+        remote_hash_at_local_height: &[u8],
+    ) -> anyhow::Result<ForkStatus> {
+        match self.check_fork(remote_tip_hash, remote_height)? {
+            ForkStatus::NeedsVerification
+                if remote_hash_at_local_height == self.get_latest_block_hash() =>
+            {
+                Ok(ForkStatus::Ancestor)
+            }
+            ForkStatus::NeedsVerification => Ok(ForkStatus::Fork),
+            status => Ok(status),
+        }
+    }
+
+    /// Move the data partition of an existing ledger to `new_start_lba`, relocating all blocks,
+    /// and persist the updated partition table. This can be used to enlarge (or shrink) the
+    /// reserved region in front of the data partition.
+    ///
+    /// Block headers only hold relative offsets, so the blocks are copied as-is.
+    /// The relocation is not crash-safe, so it's advisable to back up the ledger beforehand.
+    pub fn relocate_data_partition(&mut self, new_start_lba: u64) -> anyhow::Result<()> {
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot relocate the data partition with uncommitted entries"
+            ));
+        }
+        if self.data_partition != partition_table::PART_DATA {
+            return Err(anyhow::format_err!(
+                "Only the default data partition can be relocated"
+            ));
+        }
+        self._activate_storage();
+        let mut table = partition_table::get_partition_table();
+        let old_start_lba = table.entries[partition_table::PART_DATA].start_lba;
+        if new_start_lba == old_start_lba {
+            return Ok(());
+        }
+        table.entries[partition_table::PART_DATA].start_lba = new_start_lba;
+        table.validate().map_err(anyhow::Error::msg)?;
+
+        let data_len = self.metadata.borrow().next_block_start_pos() - old_start_lba;
+        info!(
+            "Relocating {} bytes of data partition from 0x{:0x} to 0x{:0x}",
+            data_len, old_start_lba, new_start_lba
+        );
+        self._persistent_storage_copy(old_start_lba, new_start_lba, data_len)?;
+        // Mark the end of the block chain at the new location
+        self._storage_write(
+            new_start_lba + data_len,
+            &[0u8; size_of::<LedgerBlockHeader>()],
+        )?;
+        self._activate_storage();
+        table.persist().map_err(anyhow::Error::msg)?;
+
+        self.refresh_ledger()
+    }
+
+    /// Rewrite all blocks of the ledger in place in block version `target_version`, and write
+    /// new blocks in that version from then on. The ledger is loaded again with at least the
+    /// block version of its blocks, so the migration also holds for later `LedgerMap` instances,
+    /// except for an empty ledger, which has no blocks to record it.
+    /// Chain hashes do not depend on the block version, so the recomputed chain must end in the
+    /// same tip hash as before. The previous tip hash is also recorded in the
+    /// `BLOCK_FIELD_MIGRATION_TIP_HASH` field of the tip block.
+    /// The rewritten blocks are staged after the end of the ledger and then moved into place,
+    /// so the ledger should be backed up beforehand and not be used by others meanwhile.
+    pub fn migrate_format(&mut self, target_version: u32) -> anyhow::Result<()> {
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot migrate the block format with uncommitted entries"
+            ));
+        }
+        if !(2..=LATEST_BLOCK_VERSION).contains(&target_version) {
+            return Err(LedgerError::UnsupportedBlockVersion(target_version).into());
+        }
+        if self.data_partition_bounds.1.is_some() {
+            // The rewritten blocks are staged after the end of the ledger
+            return Err(anyhow::format_err!(
+                "Cannot migrate the block format of a ledger followed by another partition"
+            ));
+        }
+        let (data_start, data_end, num_blocks, old_tip_hash) = {
+            let metadata = self.metadata.borrow();
+            (
+                metadata.first_block_start_pos(),
+                metadata.next_block_start_pos(),
+                metadata.num_blocks(),
+                metadata.get_last_block_chain_hash().to_vec(),
+            )
+        };
+        if num_blocks == 0 {
+            self.block_version = target_version;
+            return Ok(());
+        }
+        info!(
+            "Migrating {} blocks to block version {}",
+            num_blocks, target_version
+        );
+
+        let mut read_pos = data_start;
+        let mut write_pos = data_end;
+        let mut prev_block_final_pos = None;
+        for block_num in 1..=num_blocks {
+            let (block_header, ledger_block) = self._persisted_block_read(read_pos)?;
+            read_pos += block_header.jump_bytes_next_block() as u64;
+            let mut ledger_block = ledger_block.into_version(target_version)?;
+            if block_num == num_blocks {
+                ledger_block.add_field(BlockField::new(
+                    BLOCK_FIELD_MIGRATION_TIP_HASH,
+                    &old_tip_hash,
+                ))?;
+            }
+            // Compute the jump to the previous block for the final position of the block
+            let block_final_pos = data_start + (write_pos - data_end);
+            let jump_bytes_prev_block =
+                (prev_block_final_pos.unwrap_or_default() as i64 - block_final_pos as i64) as i32;
+            write_pos +=
+                self._write_block(write_pos, jump_bytes_prev_block, &ledger_block, None)? as u64;
+            prev_block_final_pos = Some(block_final_pos);
+        }
+
+        let new_data_len = write_pos - data_end;
+        self._persistent_storage_copy(data_end, data_start, new_data_len)?;
+        self._storage_write(
+            data_start + new_data_len,
+            &[0u8; size_of::<LedgerBlockHeader>()],
+        )?;
+        self.refresh_ledger()?;
+
+        let new_tip_hash = self.metadata.borrow().get_last_block_chain_hash().to_vec();
+        if new_tip_hash != old_tip_hash {
+            return Err(anyhow::format_err!(
+                "Block chain tip hash changed during migration: {} != {}",
+                hex::encode(new_tip_hash),
+                hex::encode(old_tip_hash)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Prune the first `blocks_count` blocks of the ledger, to reclaim the storage used by their
+    /// history. A pruned block keeps its header, timestamp and parent hash, so that the chain still
+    /// verifies from the first block, but only the entries that are still needed to rebuild the
+    /// index: the current values of indexed labels, all entries of labels that are not indexed,
+    /// and the entries of reserved labels. Blocks with entry hashes (see
+    /// `LedgerMapBuilder::entry_hashes`) keep the hashes of all their original entries, so that
+    /// their chain hash is still computed from the block. Other blocks are only pruned if none
+    /// of their entries are needed, and then record their chain hash, see `hashing`.
+    /// Reading a pruned block with `get_block_at_offset` fails with `LedgerError::BlockPruned`,
+    /// unless the full block can be fetched from the cold storage, see `spill_to_cold_storage`.
+    /// The blocks are compacted in place, in steps that are completed when the ledger is loaded
+    /// again if they are interrupted. Returns the number of bytes reclaimed.
+    pub fn prune_blocks(&mut self, blocks_count: usize) -> anyhow::Result<u64> {
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot prune blocks with uncommitted entries"
+            ));
+        }
+        let num_blocks = self.metadata.borrow().num_blocks();
+        let blocks_count = blocks_count.min(num_blocks);
+        if blocks_count == 0 {
+            return Ok(0);
+        }
+        info!("Pruning {} of {} blocks", blocks_count, num_blocks);
+        self._compact_blocks(|ledger_map, block_num, entry| {
+            Ok(block_num > blocks_count || ledger_map._is_entry_needed_for_index(entry)?)
+        })
+    }
+
+    /// Rewrite every block with entries for which `keep` returns false, given the 1-based block
+    /// number, as a pruned block without these entries, and move the following blocks down to
+    /// reclaim the storage. A block is only rewritten if the pruned block is smaller.
+    ///
+    /// The blocks are compacted in steps of up to `COMPACTION_STEP_BYTES`. Each step is staged
+    /// as a `CompactionJournal` after the end of the ledger, which is committed in the partition
+    /// table before the blocks are overwritten, see `partition_table::PART_JOURNAL`. Between the
+    /// steps, the last compacted block jumps over the reclaimed bytes to the next block that is
+    /// not compacted yet, so the chain stays valid at all times. The in-memory index does not
+    /// change, since the pruned entries are not needed for it. Returns the number of bytes
+    /// reclaimed.
+    fn _compact_blocks(
+        &mut self,
+        keep: impl Fn(&Self, usize, &LedgerEntry) -> Result<bool, LedgerError>,
+    ) -> anyhow::Result<u64> {
+        if self.data_partition_bounds.1.is_some() {
+            return Err(anyhow::format_err!(
+                "Cannot prune the blocks of a ledger followed by another partition"
+            ));
+        }
+        let (data_start, data_end, num_blocks) = {
+            let metadata = self.metadata.borrow();
+            (
+                metadata.first_block_start_pos(),
+                metadata.next_block_start_pos(),
+                metadata.num_blocks(),
+            )
+        };
+        let header_len = LedgerBlockHeader::sizeof() as u64;
+        let with_jumps =
+            |header: &LedgerBlockHeader, jump_bytes_prev: i32, jump_bytes_next: u32| {
+                let new_header = LedgerBlockHeader::new_with_version(
+                    header.block_version(),
+                    jump_bytes_prev,
+                    jump_bytes_next,
+                )
+                .with_encrypted(header.is_encrypted());
+                match header.summary_len() {
+                    Some(summary_len) => new_header.with_summary_len(summary_len),
+                    None => new_header,
+                }
+            };
+
+        let mut read_pos = data_start;
+        let mut write_pos = data_start;
+        // The last block that is in its final position, with its header
+        let mut placed: Option<(u64, LedgerBlockHeader)> = None;
+        // The blocks of the current step, with their final positions
+        let mut staged: Vec<(u64, LedgerBlockHeader, Vec<u8>)> = Vec::new();
+        let mut staged_len = 0;
+        for block_num in 1..=num_blocks {
+            let block_start_pos = read_pos;
+            let block_header = self._persisted_block_header_read(block_start_pos)?;
+            let block_len = block_header.jump_bytes_next_block() as u64;
+            let mut raw_block = vec![0u8; block_len as usize];
+            self._storage_read(block_start_pos, &mut raw_block)
+                .map_err(anyhow::Error::msg)?;
+            let (_, ledger_block, chain_hash) = self.get_block_from_slice(&raw_block)?;
+            let ledger_block = ledger_block.with_offset(block_start_pos);
+            if self.get_chain_hash_at(block_num - 1).as_ref() != Some(&chain_hash) {
+                return Err(LedgerError::BlockCorrupted(format!(
+                    "Block at offset {} changed since the ledger was loaded",
+                    block_start_pos
+                ))
+                .into());
+            }
+            read_pos += block_len;
+            let compacted =
+                self._compact_block(&ledger_block, &chain_hash, block_len, |entry| {
+                    keep(self, block_num, entry)
+                })?;
+            let (header, body) = match compacted {
+                Some(compacted) => compacted,
+                None => {
+                    // Blocks followed by the padding of an interrupted compaction are trimmed
+                    let used_len = header_len
+                        + Self::_block_body_len(&block_header, &raw_block[header_len as usize..])?
+                            as u64;
+                    if block_start_pos == write_pos && used_len == block_len {
+                        placed = Some((write_pos, block_header));
+                        write_pos += block_len;
+                        continue;
+                    }
+                    raw_block.truncate(used_len as usize);
+                    let header = with_jumps(
+                        &block_header,
+                        block_header.jump_bytes_prev_block(),
+                        used_len as u32,
+                    );
+                    (header, raw_block.split_off(header_len as usize))
+                }
+            };
+            let prev_block_pos = staged
+                .last()
+                .map(|(pos, _, _)| *pos)
+                .or(placed.as_ref().map(|(pos, _)| *pos));
+            let jump_bytes_prev = match prev_block_pos {
+                Some(prev_block_pos) => (prev_block_pos as i64 - write_pos as i64) as i32,
+                None => header.jump_bytes_prev_block(),
+            };
+            let jump_bytes_next = header.jump_bytes_next_block();
+            staged.push((
+                write_pos,
+                with_jumps(&header, jump_bytes_prev, jump_bytes_next),
+                body,
+            ));
+            write_pos += jump_bytes_next as u64;
+            staged_len += jump_bytes_next as usize;
+            if staged_len < COMPACTION_STEP_BYTES && block_num < num_blocks {
+                continue;
+            }
+
+            // Commit the step. Unless it is the last one, the last staged block jumps over the
+            // reclaimed bytes to the next block to compact, otherwise the chain ends after it.
+            let (last_pos, last_header, _) = staged.last_mut().expect("Staged block");
+            if read_pos < data_end {
+                let jump_bytes_next = u32::try_from(read_pos - *last_pos).map_err(|_| {
+                    anyhow::format_err!(
+                        "Cannot reclaim more than 4 GiB at once, prune fewer blocks"
+                    )
+                })?;
+                *last_header = with_jumps(
+                    last_header,
+                    last_header.jump_bytes_prev_block(),
+                    jump_bytes_next,
+                );
+            }
+            let (last_pos, last_header) = (*last_pos, last_header.clone());
+            let mut journal = CompactionJournal {
+                dst: staged[0].0,
+                data: Vec::with_capacity(staged_len + header_len as usize),
+                headers: Vec::new(),
+            };
+            for (_, header, body) in staged.drain(..) {
+                journal.data.extend_from_slice(&header.serialize()?);
+                journal.data.extend_from_slice(&body);
+            }
+            if let Some((prev_pos, prev_header)) = &placed {
+                let jump_bytes_next = (journal.dst - prev_pos) as u32;
+                let prev_header = with_jumps(
+                    prev_header,
+                    prev_header.jump_bytes_prev_block(),
+                    jump_bytes_next,
+                );
+                journal.headers.push((*prev_pos, prev_header.serialize()?));
+            }
+            if read_pos < data_end {
+                let next_header = self._persisted_block_header_read(read_pos)?;
+                let jump_bytes_prev = (last_pos as i64 - read_pos as i64) as i32;
+                let next_header = with_jumps(
+                    &next_header,
+                    jump_bytes_prev,
+                    next_header.jump_bytes_next_block(),
+                );
+                journal.headers.push((read_pos, next_header.serialize()?));
+            } else {
+                journal
+                    .data
+                    .extend_from_slice(&[0u8; size_of::<LedgerBlockHeader>()]);
+            }
+            self._run_compaction_step(&journal, data_end + header_len)?;
+            placed = Some((last_pos, last_header));
+            staged_len = 0;
+        }
+
+        if write_pos < data_end {
+            self._reload_block_positions()?;
+        }
+        Ok(data_end - write_pos)
+    }
+
+    /// Header and serialized body of `ledger_block` with the chain hash `chain_hash`, pruned to
+    /// the entries for which `keep` returns true, or `None` if it keeps all its entries, cannot be
+    /// pruned, or would not get smaller than its `block_len`, see `prune_blocks`. The header does
+    /// not jump to the previous block yet.
+    fn _compact_block(
+        &self,
+        ledger_block: &LedgerBlock,
+        chain_hash: &[u8],
+        block_len: u64,
+        keep: impl Fn(&LedgerEntry) -> Result<bool, LedgerError>,
+    ) -> anyhow::Result<Option<(LedgerBlockHeader, Vec<u8>)>> {
+        let kept = ledger_block
+            .entries()
+            .iter()
+            .map(&keep)
+            .collect::<Result<Vec<_>, _>>()?;
+        if kept.iter().all(|kept| *kept) {
+            return Ok(None);
+        }
+        let mut fields = ledger_block
+            .fields()
+            .iter()
+            .filter(|field| field.tag != BLOCK_FIELD_PRUNED_ENTRY_INDEXES)
+            .cloned()
+            .collect::<Vec<_>>();
+        let entries = match ledger_block.entry_hashes() {
+            Some(_) => {
+                let indexes = ledger_block.pruned_entry_indexes().unwrap_or_else(|| {
+                    (0..ledger_block.entries().len() as u32).collect::<Vec<_>>()
+                });
+                fields.push(BlockField::new(
+                    BLOCK_FIELD_PRUNED_ENTRY_INDEXES,
+                    indexes
+                        .iter()
+                        .zip(&kept)
+                        .filter(|(_, kept)| **kept)
+                        .flat_map(|(index, _)| index.to_le_bytes())
+                        .collect::<Vec<_>>(),
+                ));
+                ledger_block
+                    .entries()
+                    .iter()
+                    .zip(&kept)
+                    .filter(|(_, kept)| **kept)
+                    .map(|(entry, _)| entry.clone())
+                    .collect()
+            }
+            // The chain hash of version 1 covers the entry bytes, so it can only be recorded for
+            // blocks without entries
+            None if kept.iter().any(|kept| *kept) => return Ok(None),
+            None => {
+                fields.push(BlockField::new(BLOCK_FIELD_PRUNED_CHAIN_HASH, chain_hash));
+                Vec::new()
+            }
+        };
+        let pruned_block = LedgerBlock::new_v2(
+            entries,
+            ledger_block.timestamp(),
+            ledger_block.parent_hash().to_vec(),
+            fields,
+        );
+        if Self::_block_chain_hash(&pruned_block)? != chain_hash {
+            return Err(anyhow::format_err!(
+                "Chain hash of the block at offset {} changed during pruning",
+                ledger_block.get_offset()
+            ));
+        }
+        let body = self._serialize_block_body(&pruned_block)?;
+        let pruned_block_len = LedgerBlockHeader::sizeof() as u64 + body.len() as u64;
+        if pruned_block_len >= block_len {
+            return Ok(None);
+        }
+        let header =
+            LedgerBlockHeader::new_with_version(pruned_block.version(), 0, pruned_block_len as u32)
+                .with_encrypted(self._encrypts_blocks());
+        Ok(Some((header, body)))
+    }
+
+    /// Re-encrypt the blocks encrypted under the keys of earlier key epochs with the key of the
+    /// current epoch, see `rotate_encryption_key`, after which the earlier keys are no longer
+    /// needed. The blocks keep their size and are rewritten in place, in steps that are committed
+    /// like those of `prune_blocks`, so an interrupted rewrap is completed when the ledger is
+    /// opened again. Blocks written before the encryption was enabled stay unencrypted, since
+    /// encrypting them would change their size. Returns the number of re-encrypted blocks.
+    #[cfg(feature = "encryption")]
+    pub fn rewrap(&mut self) -> anyhow::Result<usize> {
+        let encryption_keys = self
+            .encryption_keys
+            .as_ref()
+            .ok_or_else(|| anyhow::format_err!("The ledger has no encryption keys"))?;
+        if self.data_partition_bounds.1.is_some() {
+            return Err(anyhow::format_err!(
+                "Cannot rewrap the blocks of a ledger followed by another partition"
+            ));
+        }
+        let (data_start, data_end) = {
+            let metadata = self.metadata.borrow();
+            (
+                metadata.first_block_start_pos(),
+                metadata.next_block_start_pos(),
+            )
+        };
+        let header_len = LedgerBlockHeader::sizeof();
+        let current_epoch = encryption_keys.current_epoch();
+        let mut journal: Option<CompactionJournal> = None;
+        let mut rewrapped = 0;
+        let mut pos = data_start;
+        while pos < data_end {
+            let block_header = self._persisted_block_header_read(pos)?;
+            let block_len = block_header.jump_bytes_next_block() as u64;
+            let mut raw_block = vec![0u8; block_len as usize];
+            self._storage_read(pos, &mut raw_block)
+                .map_err(anyhow::Error::msg)?;
+            let body = &raw_block[header_len..];
+            let needs_rewrap =
+                block_header.is_encrypted() && encryption::body_epoch(body)? != current_epoch;
+            if needs_rewrap {
+                let aad = block_header.block_version().to_le_bytes();
+                let plaintext = encryption_keys.decrypt(&aad, body)?;
+                let new_body = encryption_keys.encrypt(&aad, &plaintext)?;
+                if new_body.len() != encryption::encrypted_body_len(body)? {
+                    return Err(LedgerError::BlockCorrupted(format!(
+                        "Re-encrypted block at offset {} changed its size",
+                        pos
+                    ))
+                    .into());
+                }
+                raw_block[header_len..header_len + new_body.len()].copy_from_slice(&new_body);
+                journal
+                    .get_or_insert_with(|| CompactionJournal {
+                        dst: pos,
+                        data: Vec::new(),
+                        headers: Vec::new(),
+                    })
+                    .data
+                    .extend_from_slice(&raw_block);
+                rewrapped += 1;
+            }
+            pos += block_len;
+            // A step ends before the next block that is not re-encrypted
+            let step_done = journal.as_ref().is_some_and(|journal| {
+                !needs_rewrap || journal.data.len() >= COMPACTION_STEP_BYTES || pos >= data_end
+            });
+            if step_done {
+                let journal = journal.take().expect("Journal");
+                self._run_compaction_step(&journal, data_end + header_len as u64)?;
+            }
+        }
+        Ok(rewrapped)
+    }
+
+    /// Stage `journal` at `staging_pos`, commit it in the partition table, apply it and clear it
+    /// again, see `_compact_blocks`.
+    fn _run_compaction_step(
+        &self,
+        journal: &CompactionJournal,
+        staging_pos: u64,
+    ) -> anyhow::Result<()> {
+        let journal_bytes = to_vec(journal)?;
+        let required_bytes = staging_pos + 8 + journal_bytes.len() as u64;
+        if let Some(quota_bytes) = self
+            ._storage_limit_bytes()
+            .filter(|quota_bytes| required_bytes > *quota_bytes)
+        {
+            return Err(LedgerError::QuotaExceeded {
+                quota_bytes,
+                required_bytes,
+            }
+            .into());
+        }
+        self._storage_write_chunked(staging_pos, &(journal_bytes.len() as u64).to_le_bytes())?;
+        self._storage_write_chunked(staging_pos + 8, &journal_bytes)?;
+        self._activate_storage();
+        partition_table::write_journal_offset(Some(staging_pos)).map_err(anyhow::Error::msg)?;
+        self._apply_compaction_journal(journal)?;
+        self._activate_storage();
+        partition_table::write_journal_offset(None).map_err(anyhow::Error::msg)
+    }
+
+    fn _apply_compaction_journal(&self, journal: &CompactionJournal) -> anyhow::Result<()> {
+        self._storage_write_chunked(journal.dst, &journal.data)?;
+        for (offset, header) in &journal.headers {
+            self._storage_write(*offset, header)?;
+        }
+        Ok(())
+    }
+
+    /// Complete the compaction step that was interrupted, if any, see `_compact_blocks`.
+    fn _replay_compaction_journal(&self) -> anyhow::Result<()> {
+        self._activate_storage();
+        let journal_pos =
+            match partition_table::read_journal_offset().map_err(anyhow::Error::msg)? {
+                Some(journal_pos) => journal_pos,
+                None => return Ok(()),
+            };
+        warn!("Completing the interrupted compaction of the ledger blocks");
+        let mut journal_len = [0u8; 8];
+        self._storage_read(journal_pos, &mut journal_len)
+            .map_err(anyhow::Error::msg)?;
+        let mut journal_bytes = vec![0u8; u64::from_le_bytes(journal_len) as usize];
+        self._storage_read(journal_pos + 8, &mut journal_bytes)
+            .map_err(anyhow::Error::msg)?;
+        let journal = CompactionJournal::try_from_slice(&journal_bytes)?;
+        self._apply_compaction_journal(&journal)?;
+        self._activate_storage();
+        partition_table::write_journal_offset(None).map_err(anyhow::Error::msg)
+    }
+
+    /// Reload the positions of the blocks after they were moved, and check that their chain
+    /// hashes did not change, without rebuilding the index.
+    fn _reload_block_positions(&mut self) -> anyhow::Result<()> {
+        let loaded = self
+            .metadata
+            .replace(Metadata::with_start_pos(self.data_partition_bounds.0));
+        let mut blocks_count = 0;
+        for block in self.iter_raw_with_hashes() {
+            let (block_header, ledger_block, chain_hash) = block?;
+            if loaded.block_chain_hash(blocks_count) != Some(chain_hash.as_slice()) {
+                self.metadata.replace(loaded);
+                return Err(anyhow::format_err!(
+                    "Chain hash of block {} changed during pruning",
+                    blocks_count
+                ));
+            }
+            let next_block_start_pos = self.metadata.borrow().next_block_start_pos()
+                + block_header.jump_bytes_next_block() as u64;
+            self.metadata.borrow_mut().update_from_appended_block(
+                &chain_hash,
+                ledger_block.timestamp(),
+                next_block_start_pos,
+            );
+            blocks_count += 1;
+        }
+        if blocks_count != loaded.num_blocks() {
+            self.metadata.replace(loaded);
+            return Err(anyhow::format_err!(
+                "Pruning changed the number of blocks to {}",
+                blocks_count
+            ));
+        }
+        Ok(())
+    }
+
+    /// Store the full first `blocks_count` blocks in the cold storage, then prune them from the
+    /// persistent storage with `prune_blocks`, so that only the recent blocks are kept there.
+    /// Blocks pruned earlier are skipped. Returns the number of bytes reclaimed.
+    pub fn spill_to_cold_storage(&mut self, blocks_count: usize) -> anyhow::Result<u64> {
+        if self.cold_storage.is_none() {
+            return Err(anyhow::format_err!("No cold storage is configured"));
+        }
+        let mut spilled = Vec::new();
+        for block in self.iter_raw().take(blocks_count) {
+            let (block_header, ledger_block) = block?;
+            if ledger_block.is_pruned() {
+                continue;
+            }
+            let raw_block = self.read_raw_blocks(
+                ledger_block.get_offset(),
+                block_header.jump_bytes_next_block() as u64,
+            )?;
+            spilled.push((Self::_block_chain_hash(&ledger_block)?, raw_block));
+        }
+        if let Some(cold_storage) = self.cold_storage.as_mut() {
+            for (chain_hash, raw_block) in &spilled {
+                cold_storage.put_block(chain_hash, raw_block)?;
+            }
+        }
+        info!("Spilled {} blocks to cold storage", spilled.len());
+        self.prune_blocks(blocks_count)
+    }
+
+    /// Returns true if the persisted `entry` is needed to rebuild the current index, i.e. it is
+    /// the current value of an indexed entry, or its liveness cannot be told from the index.
+    /// Fails if an index fails to load, so that no entry is pruned based on a partial index.
+    fn _is_entry_needed_for_index(&self, entry: &LedgerEntry) -> Result<bool, LedgerError> {
+        if entry.label().starts_with(RESERVED_LABEL_PREFIX)
+            || !self._is_label_indexed(entry.label())
+        {
+            return Ok(true);
+        }
+        if entry.operation() != Operation::Upsert {
+            return Ok(false);
+        }
+        // Entries keep their label when the label is renamed, so look in all labels
+        Ok(self
+            ._label_entries(entry.label())?
+            .into_iter()
+            .chain(
+                self._all_label_entries()?
+                    .into_iter()
+                    .map(|(_, entries)| entries),
+            )
+            .any(|entries| entries.get(entry.key()) == Some(entry)))
+    }
+
+    pub fn next_block_iter(&self, label: Option<&str>) -> impl Iterator<Item = &LedgerEntry> {
+        match label {
+            Some(label) => self
+                .next_block_entries
+                .get(label)
+                .map(|entries| entries.values())
                 .unwrap_or_default()
                 .filter(|entry| entry.operation() == Operation::Upsert)
                 .collect::<Vec<_>>()
                 .into_iter(),
             None => self
-                .entries
-                .values()
-                .flat_map(|entries| entries.values())
+                .next_block_entries
+                .values()
+                .flat_map(|entries| entries.values())
+                .filter(|entry| entry.operation() == Operation::Upsert)
+                .collect::<Vec<_>>()
+                .into_iter(),
+        }
+    }
+
+    pub fn for_each<F>(&self, label: &str, mut f: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        if let Some(entries) = self._label_entries(label).ok().flatten() {
+            for (key, entry) in entries.iter() {
+                f(key.as_slice(), entry.value());
+            }
+        }
+        if let Some(entries) = self.next_block_entries.get(label) {
+            for (key, entry) in entries.iter() {
+                f(key.as_slice(), entry.value());
+            }
+        }
+    }
+
+    /// Returns the entries of `label`, committed or not, for which `predicate(key, value)` is true.
+    /// Uncommitted entries take precedence over committed entries with the same key, as in `get`.
+    /// Chunked values (see `upsert_chunked`) and blob references (see `upsert_blob_ref`) are
+    /// resolved as in `get`: the predicate sees, and the returned entries hold, the full values.
+    pub fn find<S: AsRef<str>, F: FnMut(&[u8], &[u8]) -> bool>(
+        &self,
+        label: S,
+        mut predicate: F,
+    ) -> Result<Vec<LedgerEntry>, LedgerError> {
+        let label = label.as_ref();
+        let pending = self.next_block_entries.get(label);
+        let entries = self._label_entries(label)?;
+        let committed = entries
+            .into_iter()
+            .flat_map(|entries| entries.values())
+            .filter(|entry| !pending.is_some_and(|pending| pending.contains_key(entry.key())));
+        let mut found = Vec::new();
+        for entry in committed.chain(pending.into_iter().flat_map(|entries| entries.values())) {
+            if entry.operation() != Operation::Upsert {
+                continue;
+            }
+            match self._lookup_value_in(label, entry.key(), pending, entries)? {
+                (entry, None) => {
+                    if predicate(entry.key(), entry.value()) {
+                        found.push(entry.clone());
+                    }
+                }
+                (entry, value_ref) => {
+                    let value = self._read_value(entry, value_ref)?;
+                    if predicate(entry.key(), &value) {
+                        found.push(LedgerEntry::new(
+                            label,
+                            entry.key(),
+                            value,
+                            Operation::Upsert,
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Returns the entries of `label` whose value contains `needle`, see `find`.
+    pub fn find_value_containing<S: AsRef<str>>(
+        &self,
+        label: S,
+        needle: &[u8],
+    ) -> Result<Vec<LedgerEntry>, LedgerError> {
+        self.find(label, |_, value| {
+            needle.is_empty() || value.windows(needle.len()).any(|window| window == needle)
+        })
+    }
+
+    /// Returns the committed entries that match `query`, see `Query`.
+    pub fn query(&self, query: &Query) -> anyhow::Result<Vec<&LedgerEntry>> {
+        let entries = match self._label_entries(&query.label)? {
+            Some(entries) => entries,
+            None => return Ok(Vec::new()),
+        };
+        let candidates: Box<dyn Iterator<Item = &LedgerEntry>> = match query.updated_after_ns {
+            Some(timestamp_ns) => Box::new(
+                self._keys_updated_after(&query.label, timestamp_ns)?
+                    .into_iter()
+                    .filter_map(|key| entries.get(&key)),
+            ),
+            None => Box::new(entries.values()),
+        };
+        Ok(candidates
+            .filter(|entry| entry.operation() == Operation::Upsert)
+            .filter(|entry| query.matches(entry))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// Returns the keys of `label` written in the blocks with a timestamp after `timestamp_ns`,
+    /// from the most recently written, walking the block chain back from the tip.
+    fn _keys_updated_after(&self, label: &str, timestamp_ns: u64) -> Result<IndexSet<EntryKey>> {
+        let mut keys = IndexSet::new();
+        if self.get_blocks_count() == 0 {
+            return Ok(keys);
+        }
+        // Entries written before a rename of the label are recorded under the previous label
+        let mut labels = vec![label.to_string()];
+        let first_block_start_pos = self.metadata.borrow().first_block_start_pos();
+        let mut block_start_pos = self.get_latest_block_start_pos();
+        loop {
+            let (block_header, block) = self._persisted_block_read(block_start_pos)?;
+            if block.timestamp() <= timestamp_ns {
+                break;
+            }
+            for entry in block.entries().iter().rev() {
+                if entry.label() == RENAME_LABEL {
+                    let renamed_to = String::from_utf8_lossy(entry.value());
+                    if labels.iter().any(|label| *label == renamed_to) {
+                        labels.push(String::from_utf8_lossy(entry.key()).to_string());
+                    }
+                } else if labels.iter().any(|label| label == entry.label()) {
+                    keys.insert(entry.key().to_vec());
+                }
+            }
+            if block_start_pos <= first_block_start_pos {
+                break;
+            }
+            block_start_pos =
+                (block_start_pos as i64 + block_header.jump_bytes_prev_block() as i64) as u64;
+        }
+        Ok(keys)
+    }
+
+    /// Returns a standalone proof of the committed entry `key` of `label`, which can be verified
+    /// with `verify_entry_proof` against the current tip hash, see `ProofBlob`.
+    pub fn prove_entry<S: AsRef<str>>(&self, label: S, key: &[u8]) -> anyhow::Result<ProofBlob> {
+        let entry = self
+            ._label_entries(label.as_ref())?
+            .and_then(|entries| entries.get(key))
+            .filter(|entry| entry.operation() == Operation::Upsert)
+            .ok_or(LedgerError::EntryNotFound)?;
+
+        // Walk back from the tip to the block with the latest write of the entry
+        let first_block_start_pos = self.metadata.borrow().first_block_start_pos();
+        let mut block_start_pos = self.get_latest_block_start_pos();
+        let mut blocks = Vec::new();
+        loop {
+            let (block_header, block) = self._persisted_block_read(block_start_pos)?;
+            let block = if block.is_pruned() {
+                let chain_hash = Self::_block_chain_hash(&block)?;
+                self._cold_block_read(block_start_pos, &chain_hash)?.1
+            } else {
+                block
+            };
+            let is_entry_block = block.entries().contains(entry);
+            let parent_hash = block.parent_hash().to_vec();
+            blocks.push(ProofBlock {
+                timestamp: block.timestamp(),
+                entries: block.entries().to_vec(),
+                entry_hashes: block.entry_hashes().is_some(),
+            });
+            if is_entry_block {
+                blocks.reverse();
+                return Ok(ProofBlob {
+                    version: PROOF_BLOB_VERSION,
+                    label: entry.label().to_string(),
+                    key: key.to_vec(),
+                    parent_hash,
+                    blocks,
+                });
+            }
+            if block_start_pos <= first_block_start_pos {
+                return Err(LedgerError::EntryNotFound.into());
+            }
+            block_start_pos =
+                (block_start_pos as i64 + block_header.jump_bytes_prev_block() as i64) as u64;
+        }
+    }
+
+    /// Returns the state root of the current live entries, see `crate::state_proof`. It is kept
+    /// up to date on commit, and is identical on replicas with identical live entries, whatever
+    /// histories led to them.
+    pub fn state_root(&self) -> Vec<u8> {
+        self.live_state.state_root().to_vec()
+    }
+
+    /// Returns the digests of the local state, for a peer to plan what the local ledger is
+    /// missing with `sync_plan`.
+    pub fn state_digests(&self) -> StateDigests {
+        StateDigests {
+            blocks_count: self.get_blocks_count() as u64,
+            tip_chain_hash: self.get_latest_block_hash(),
+            state_root: self.state_root(),
+            label_state_roots: self
+                .live_state
+                .label_state_roots()
+                .into_iter()
+                .map(|(label, root)| (label, root.to_vec()))
+                .collect(),
+        }
+    }
+
+    /// Plan the anti-entropy sync of a peer with the digests `remote`: if the chain of the peer
+    /// is a prefix of ours, it is missing exactly the blocks after it. If the chains forked, only
+    /// the labels whose live entries differ need to be reconciled, e.g. with `merge_from`.
+    pub fn sync_plan(&self, remote: &StateDigests) -> anyhow::Result<SyncPlan> {
+        match self.check_fork(&remote.tip_chain_hash, remote.blocks_count)? {
+            ForkStatus::Equal => Ok(SyncPlan::InSync),
+            // Only the peer can verify whether its chain extends ours, as it has the chain hash
+            // at our height, so it plans the sync with our digests
+            ForkStatus::Ancestor | ForkStatus::NeedsVerification => Ok(SyncPlan::PeerAhead),
+            ForkStatus::Descendant => {
+                let start = match self
+                    .metadata
+                    .borrow()
+                    .block_start_pos(remote.blocks_count as usize)
+                {
+                    Some(start) => start,
+                    None => {
+                        return Err(anyhow::format_err!(
+                            "Block at height {} not found",
+                            remote.blocks_count
+                        ))
+                    }
+                };
+                Ok(SyncPlan::SendBlocks {
+                    start,
+                    end: self.get_next_block_start_pos(),
+                    blocks: self.get_blocks_count() as u64 - remote.blocks_count,
+                })
+            }
+            ForkStatus::Fork => {
+                if remote.state_root == self.state_root() {
+                    return Ok(SyncPlan::InSync);
+                }
+                let local_roots = self.live_state.label_state_roots();
+                let labels = local_roots
+                    .keys()
+                    .chain(remote.label_state_roots.keys())
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .filter(|label| {
+                        local_roots.get(*label).map(|root| root.as_slice())
+                            != remote.label_state_roots.get(*label).map(Vec::as_slice)
+                    })
+                    .cloned()
+                    .collect();
+                Ok(SyncPlan::SendLabels(labels))
+            }
+        }
+    }
+
+    /// Returns the state root of the live entries after the first `block_height` blocks, see
+    /// `crate::state_proof`.
+    pub fn state_root_at(&self, block_height: u64) -> anyhow::Result<Vec<u8>> {
+        Ok(self._state_tree_at(block_height)?.1.state_root().to_vec())
+    }
+
+    /// Returns a proof that `key` of `label` does not exist after the first `block_height`
+    /// blocks, which can be verified with `verify_non_inclusion_proof` against the state root
+    /// at that height.
+    pub fn prove_non_inclusion<S: AsRef<str>>(
+        &self,
+        label: S,
+        key: &[u8],
+        block_height: u64,
+    ) -> anyhow::Result<NonInclusionProof> {
+        let (leaves, tree) = self._state_tree_at(block_height)?;
+        let sort_key = (label.as_ref().as_bytes(), key);
+        let index =
+            leaves.partition_point(|leaf| (leaf.label.as_bytes(), &leaf.key[..]) < sort_key);
+        if leaves
+            .get(index)
+            .is_some_and(|leaf| (leaf.label.as_bytes(), &leaf.key[..]) == sort_key)
+        {
+            return Err(anyhow::format_err!(
+                "Entry {:?} of label {:?} exists at block height {}",
+                String::from_utf8_lossy(key),
+                label.as_ref(),
+                block_height
+            ));
+        }
+        let leaf_proof = |index: usize| StateLeafProof {
+            index: index as u64,
+            leaf: leaves[index].clone(),
+            path: tree.path(index),
+        };
+        Ok(NonInclusionProof {
+            version: NON_INCLUSION_PROOF_VERSION,
+            label: label.as_ref().to_string(),
+            key: key.to_vec(),
+            block_height,
+            leaf_count: leaves.len() as u64,
+            lower: index.checked_sub(1).map(leaf_proof),
+            upper: (index < leaves.len()).then(|| leaf_proof(index)),
+        })
+    }
+
+    /// Replay the first `block_height` blocks into the sorted live entries, and the state tree
+    /// over them. The current live entries are used as they are, without a replay.
+    fn _state_tree_at(&self, block_height: u64) -> anyhow::Result<(Vec<StateLeaf>, StateTree)> {
+        if block_height > self.get_blocks_count() as u64 {
+            return Err(anyhow::format_err!(
+                "Block height {} is beyond the {} blocks of the ledger",
+                block_height,
+                self.get_blocks_count()
+            ));
+        }
+        let leaves = if block_height == self.get_blocks_count() as u64 {
+            self.live_state.leaves()
+        } else {
+            let mut state = LiveState::default();
+            for block in self.iter_raw().take(block_height as usize) {
+                let (_, ledger_block) = block?;
+                let ledger_block = if ledger_block.is_pruned() {
+                    let chain_hash = Self::_block_chain_hash(&ledger_block)?;
+                    self._cold_block_read(ledger_block.get_offset(), &chain_hash)?
+                        .1
+                } else {
+                    ledger_block
+                };
+                for entry in ledger_block.entries() {
+                    state.apply(entry);
+                }
+            }
+            state.leaves()
+        };
+        let tree = StateTree::new(leaves.iter().map(StateLeaf::hash).collect());
+        Ok((leaves, tree))
+    }
+
+    /// Iterate over the committed entries of `label`, or of all indexed labels if `None`, label
+    /// by label. The order of the entries of a label follows its `LabelIndexKind`: by key for a
+    /// `BTreeMap`, unspecified for a `HashMap`, and for the default `IndexMap`, the order in which
+    /// the keys were first committed, except that deleting a key moves the last entry into its
+    /// place. As the index is rebuilt from the blocks on `refresh_ledger`, and compaction and
+    /// pruning rewrite the blocks, callers should not rely on this order; use `iter_sorted` for
+    /// a stable order. With `LedgerMapBuilder::lazy_index`, the indexes are loaded on first use,
+    /// and if one fails to load, the failure is logged and no entries are returned.
+    pub fn iter(&self, label: Option<&str>) -> impl Iterator<Item = &LedgerEntry> {
+        match label {
+            Some(label) => self
+                ._label_entries(label)
+                .ok()
+                .flatten()
+                .into_iter()
+                .flat_map(|entries| entries.values())
+                .filter(|entry| entry.operation() == Operation::Upsert)
+                .collect::<Vec<_>>()
+                .into_iter(),
+            None => self
+                ._all_label_entries()
+                .unwrap_or_default()
+                .into_iter()
+                .flat_map(|(_, entries)| entries.values())
                 .filter(|entry| entry.operation() == Operation::Upsert)
                 .collect::<Vec<_>>()
                 .into_iter(),
         }
     }
 
-    pub fn iter_raw(
-        &self,
-    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock)>> + '_ {
-        let data_start = partition_table::get_data_partition().start_lba;
-        (0..).scan(data_start, |state, _| {
-            let (block_header, ledger_block) = match self._persisted_block_read(*state) {
-                Ok(decoded) => decoded,
-                Err(LedgerError::BlockEmpty) => return None,
-                Err(LedgerError::BlockCorrupted(err)) => {
-                    return Some(Err(anyhow::format_err!(
-                        "Failed to read Ledger block: {}",
-                        err
-                    )))
-                }
-                Err(err) => {
-                    return Some(Err(anyhow::format_err!(
-                        "Failed to read Ledger block: {}",
-                        err
-                    )))
+    /// Iterate over the committed entries of `label` in lexicographic order of their keys,
+    /// whatever the `LabelIndexKind` of the label.
+    pub fn iter_sorted(&self, label: &str) -> impl Iterator<Item = &LedgerEntry> {
+        let mut entries = self.iter(Some(label)).collect::<Vec<_>>();
+        entries.sort_unstable_by(|a, b| a.key().cmp(b.key()));
+        entries.into_iter()
+    }
+
+    /// Page of at most `limit` committed entries of `label` in key order, starting after `cursor`
+    /// or at the first entry if `None`. Also returns the cursor of the next page, or `None` if
+    /// there are no more entries. A zero `limit` is taken as 1, so that every page makes progress.
+    /// With a `LabelIndexKind::BTreeMap` index, the page is read from the cursor on; other indexes
+    /// are scanned once per page, without sorting all the entries.
+    pub fn iter_page(
+        &self,
+        label: &str,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> (Vec<&LedgerEntry>, Option<Cursor>) {
+        let limit = limit.max(1);
+        // One more entry than the page tells if there is a next page
+        let mut page = self
+            ._label_entries(label)
+            .ok()
+            .flatten()
+            .map(|entries| {
+                entries.first_after(
+                    cursor.map(|cursor| cursor.after_key.as_slice()),
+                    limit + 1,
+                    |entry| entry.operation() == Operation::Upsert,
+                )
+            })
+            .unwrap_or_default();
+        let next_cursor = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|last| Cursor {
+                after_key: last.key().to_vec(),
+            })
+        } else {
+            None
+        };
+        (page, next_cursor)
+    }
+
+    /// Iterate over the committed entries of `label` whose last write is in a block with a
+    /// timestamp in `from_ns..to_ns`, e.g. the entries modified in a month. The block of the
+    /// last write of each key is tracked as blocks are committed, so no blocks are read. Like
+    /// `iter`, this only yields entries of indexed labels.
+    pub fn iter_in_time_range<'a>(
+        &'a self,
+        label: &'a str,
+        from_ns: u64,
+        to_ns: u64,
+    ) -> impl Iterator<Item = &'a LedgerEntry> + 'a {
+        self.iter(Some(label)).filter(move |entry| {
+            self.label_stats
+                .last_write_block(label, entry.key())
+                .and_then(|block_index| {
+                    self.metadata
+                        .borrow()
+                        .block_timestamp_ns(block_index as usize)
+                })
+                .is_some_and(|timestamp_ns| (from_ns..to_ns).contains(&timestamp_ns))
+        })
+    }
+
+    /// Iterate over the committed entries of all indexed labels that match the glob `pattern`,
+    /// label by label. See `label_matches` for the pattern syntax.
+    pub fn iter_labels_matching<'a>(
+        &'a self,
+        pattern: &'a str,
+    ) -> impl Iterator<Item = &'a LedgerEntry> + 'a {
+        self.entries
+            .keys()
+            .chain(self.lazy_entries.keys())
+            .filter(move |label| label_matches(pattern, label))
+            .filter_map(|label| self._label_entries(label).ok().flatten())
+            .flat_map(|entries| entries.values())
+            .filter(|entry| entry.operation() == Operation::Upsert)
+    }
+
+    /// Iterate over the committed blocks in the persistent storage, with their headers.
+    /// The start offset of each block in the storage is `LedgerBlock::get_offset`.
+    pub fn iter_raw(
+        &self,
+    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock)>> + '_ {
+        self._iter_raw_from(self.data_partition_bounds.0)
+    }
+
+    /// Like `iter_raw`, but from the block with index `block_index` on, without reading the blocks
+    /// before it. Yields nothing if there is no such block.
+    pub fn iter_raw_from_block(
+        &self,
+        block_index: usize,
+    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock)>> + '_ {
+        let start = self
+            .get_block_start_pos(block_index)
+            .unwrap_or_else(|| self._storage_size_bytes());
+        self._iter_raw_from(start)
+    }
+
+    /// Iterate over the committed blocks from the block at storage offset `start` on.
+    fn _iter_raw_from(
+        &self,
+        start: u64,
+    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock)>> + '_ {
+        (0..).scan(start, |state, _| {
+            if *state >= self._storage_size_bytes() {
+                return None;
+            }
+            let (block_header, ledger_block) = match self._persisted_block_read(*state) {
+                Ok(decoded) => decoded,
+                Err(LedgerError::BlockEmpty) => return None,
+                Err(LedgerError::BlockCorrupted(err)) => {
+                    return Some(Err(anyhow::format_err!(
+                        "Failed to read Ledger block: {}",
+                        err
+                    )))
+                }
+                Err(err) => {
+                    return Some(Err(anyhow::format_err!(
+                        "Failed to read Ledger block: {}",
+                        err
+                    )))
+                }
+            };
+            *state += block_header.jump_bytes_next_block() as u64;
+            Some(Ok((block_header, ledger_block)))
+        })
+    }
+
+    /// Scan all entries of the committed blocks from `handle` on, see `Scan`. Pass
+    /// `ScanHandle::default()` to start at the first block, or a handle saved from `Scan::handle`
+    /// to resume an earlier scan, e.g. after a restart of the process.
+    pub fn scan(&self, handle: ScanHandle) -> Scan<'_> {
+        Scan::new(self, handle)
+    }
+
+    /// Like `iter_raw`, but from the first block with a timestamp after `timestamp_ns` on, e.g.
+    /// for incremental jobs that process the blocks committed since their previous run. The
+    /// first block is found from the block timestamps kept in memory, without reading blocks.
+    pub fn iter_blocks_since(
+        &self,
+        timestamp_ns: u64,
+    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock)>> + '_ {
+        let start = {
+            let metadata = self.metadata.borrow();
+            metadata
+                .first_block_index_after(timestamp_ns)
+                .and_then(|block_index| metadata.block_start_pos(block_index))
+                .unwrap_or_else(|| metadata.next_block_start_pos())
+        };
+        self._iter_raw_from(start)
+    }
+
+    /// Iterate over the committed blocks after the block with the chain hash `chain_hash`, e.g.
+    /// for a sync client to resume after the last block it has. An empty `chain_hash`, the
+    /// parent hash of the first block, iterates over all blocks. Fails with
+    /// `LedgerError::UnknownChainHash` if no block has the chain hash, which is the case if the
+    /// client followed a fork of the chain, see `check_fork`.
+    pub fn iter_blocks_after(
+        &self,
+        chain_hash: &[u8],
+    ) -> Result<
+        impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock)>> + '_,
+        LedgerError,
+    > {
+        let start = if chain_hash.is_empty() {
+            self.data_partition_bounds.0
+        } else {
+            let metadata = self.metadata.borrow();
+            let block_index = metadata
+                .block_index_by_chain_hash(chain_hash)
+                .ok_or_else(|| LedgerError::UnknownChainHash(chain_hash.to_vec()))?;
+            metadata
+                .block_start_pos(block_index + 1)
+                .unwrap_or_else(|| metadata.next_block_start_pos())
+        };
+        Ok(self._iter_raw_from(start))
+    }
+
+    /// Like `iter_raw`, but only the blocks with entries of `label`, e.g. to follow the history
+    /// of a label. Blocks of version 3 and later are skipped based on their `BlockSummary`,
+    /// without decompressing their bodies; older blocks are decoded to check their entries.
+    pub fn iter_raw_for_label<'a>(
+        &'a self,
+        label: &'a str,
+    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock)>> + 'a {
+        self._iter_raw_touching(move |block_label| block_label == label)
+    }
+
+    /// Like `iter_raw_for_label`, but the blocks with entries of any label for which `touches`
+    /// returns true.
+    fn _iter_raw_touching<'a>(
+        &'a self,
+        touches: impl Fn(&str) -> bool + 'a,
+    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock)>> + 'a {
+        let read_block = move |offset: u64| -> Result<_, LedgerError> {
+            let block_header = self._persisted_block_header_read(offset)?;
+            if let Some(summary_len) = block_header.summary_len() {
+                let summary = self._persisted_block_summary_read(offset, summary_len)?;
+                if !summary.labels.iter().any(|label| touches(label)) {
+                    return Ok((block_header, None));
+                }
+            }
+            let (block_header, ledger_block) = self._persisted_block_read(offset)?;
+            if ledger_block
+                .entries()
+                .iter()
+                .any(|entry| touches(entry.label()))
+            {
+                Ok((block_header, Some(ledger_block)))
+            } else {
+                Ok((block_header, None))
+            }
+        };
+        (0..)
+            .scan(self.data_partition_bounds.0, move |state, _| {
+                let (block_header, ledger_block) = match read_block(*state) {
+                    Ok(decoded) => decoded,
+                    Err(LedgerError::BlockEmpty) => return None,
+                    Err(err) => {
+                        return Some(Err(anyhow::format_err!(
+                            "Failed to read Ledger block: {}",
+                            err
+                        )))
+                    }
+                };
+                *state += block_header.jump_bytes_next_block() as u64;
+                Some(Ok(
+                    ledger_block.map(|ledger_block| (block_header, ledger_block))
+                ))
+            })
+            .filter_map(|block| block.transpose())
+    }
+
+    /// Header and `BlockSummary` of the block at storage offset `offset`. Only the header and
+    /// the summary are read for blocks of version 3 and later; the summary of older blocks is
+    /// computed from their decoded entries.
+    pub fn get_block_summary_at_offset(
+        &self,
+        offset: u64,
+    ) -> Result<(LedgerBlockHeader, BlockSummary), LedgerError> {
+        let block_header = self._persisted_block_header_read(offset)?;
+        match block_header.summary_len() {
+            Some(summary_len) => Ok((
+                block_header,
+                self._persisted_block_summary_read(offset, summary_len)?,
+            )),
+            None => {
+                let (block_header, ledger_block) = self._persisted_block_read(offset)?;
+                Ok((block_header, ledger_block.summary()))
+            }
+        }
+    }
+
+    /// Like `iter_raw`, but also returns the chain hash of each block, like
+    /// `iter_raw_from_slice`.
+    pub fn iter_raw_with_hashes(
+        &self,
+    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock, Vec<u8>)>> + '_ {
+        self.iter_raw().map(|block| {
+            let (block_header, ledger_block) = block?;
+            let chain_hash = Self::_block_chain_hash(&ledger_block)?;
+            Ok((block_header, ledger_block, chain_hash))
+        })
+    }
+
+    pub fn iter_raw_from_slice<'a>(
+        &'a self,
+        data: &'a [u8],
+    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock, Vec<u8>)>> + 'a {
+        (0..).scan(0usize, move |offset, _| {
+            // End iteration if the offset is at or past the end of the slice.
+            if *offset >= data.len() {
+                return None;
+            }
+            // Ensure there's at least enough bytes to read a header.
+            if data.len() - *offset < LedgerBlockHeader::sizeof() {
+                return None;
+            }
+            // Attempt to parse a block from the current offset.
+            match self.get_block_from_slice(&data[*offset..]) {
+                Ok((header, block, block_hash)) => {
+                    let block_offset = *offset as u64;
+                    let jump = header.jump_bytes_next_block() as usize;
+                    // Avoid an infinite loop if jump is zero.
+                    if jump == 0 {
+                        return Some(Err(anyhow::format_err!("Block jump length is zero")));
+                    }
+                    *offset += jump;
+                    Some(Ok((header, block.with_offset(block_offset), block_hash)))
+                }
+                Err(LedgerError::BlockEmpty) => {
+                    // End iteration if a block is empty.
+                    None
+                }
+                Err(err) => Some(Err(anyhow::format_err!(
+                    "Failed to read Ledger block: {}",
+                    err
+                ))),
+            }
+        })
+    }
+
+    pub fn get_block_at_offset(
+        &self,
+        offset: u64,
+    ) -> Result<(LedgerBlockHeader, LedgerBlock), LedgerError> {
+        let offset = if offset < self.metadata.borrow().first_block_start_pos() {
+            self.metadata.borrow().first_block_start_pos()
+        } else {
+            offset
+        };
+        let (block_header, ledger_block) = self._persisted_block_read(offset)?;
+        if ledger_block.is_pruned() {
+            return self._cold_block_read(offset, &hashing::block_chain_hash(&ledger_block)?);
+        }
+        Ok((block_header, ledger_block))
+    }
+
+    /// Fetch the full block of the pruned block at `offset` from the cold storage, verified
+    /// against its `chain_hash`. Fails with `LedgerError::BlockPruned` if it is not available.
+    fn _cold_block_read(
+        &self,
+        offset: u64,
+        chain_hash: &[u8],
+    ) -> Result<(LedgerBlockHeader, LedgerBlock), LedgerError> {
+        let cold_storage = match &self.cold_storage {
+            Some(cold_storage) => cold_storage,
+            None => return Err(LedgerError::BlockPruned { offset }),
+        };
+        let raw_block = match cold_storage.get_block(chain_hash) {
+            Ok(Some(raw_block)) => raw_block,
+            Ok(None) => return Err(LedgerError::BlockPruned { offset }),
+            Err(err) => {
+                warn!(
+                    "Failed to fetch block {} from cold storage: {}",
+                    offset, err
+                );
+                return Err(LedgerError::BlockPruned { offset });
+            }
+        };
+        let (block_header, ledger_block, block_hash) = self.get_block_from_slice(&raw_block)?;
+        if block_hash != chain_hash {
+            return Err(LedgerError::BlockCorrupted(format!(
+                "Block {} from cold storage has the chain hash {}, expected {}",
+                offset,
+                hex::encode(block_hash),
+                hex::encode(chain_hash)
+            )));
+        }
+        Ok((block_header, ledger_block.with_offset(offset)))
+    }
+
+    pub fn get_block_from_slice(
+        &self,
+        data: &[u8],
+    ) -> Result<(LedgerBlockHeader, LedgerBlock, Vec<u8>), LedgerError> {
+        let header_size = LedgerBlockHeader::sizeof();
+        if data.len() < header_size {
+            return Err(LedgerError::BlockCorrupted("Block too short".to_string()));
+        }
+        let block_header = LedgerBlockHeader::deserialize(data)?;
+        let end = block_header.jump_bytes_next_block() as usize;
+
+        // Check if there's enough data for the block payload.
+        if data.len() < end || end < header_size {
+            return Err(LedgerError::BlockCorrupted("Block too short".to_string()));
+        }
+
+        let block = self._deserialize_block_body(&block_header, &data[header_size..end])?;
+        let block_hash = Self::_block_chain_hash(&block)
+            .map_err(|e| LedgerError::BlockCorrupted(e.to_string()))?;
+        Ok((block_header, block, block_hash))
+    }
+
+    /// Returns the raw bytes (headers and bodies) of the committed blocks from the storage offset
+    /// `start` on, which must be the start of a block or the end of the ledger. Blocks are added
+    /// while they fit in `max_bytes`, but at least one block is returned if there is one.
+    /// The bytes can be appended to a replica of the ledger with `append_raw_blocks`.
+    pub fn read_raw_blocks(&self, start: u64, max_bytes: u64) -> Result<Vec<u8>, LedgerError> {
+        let end = self.get_next_block_start_pos();
+        let mut pos = start;
+        while pos < end {
+            let mut buf = [0u8; size_of::<LedgerBlockHeader>()];
+            self._storage_read(pos, &mut buf)
+                .map_err(|e| LedgerError::BlockCorrupted(e.to_string()))?;
+            let block_len_bytes =
+                LedgerBlockHeader::deserialize(buf.as_ref())?.jump_bytes_next_block() as u64;
+            if block_len_bytes < LedgerBlockHeader::sizeof() as u64 {
+                return Err(LedgerError::BlockCorrupted(format!(
+                    "Invalid block length {} at offset {}",
+                    block_len_bytes, pos
+                )));
+            }
+            if pos > start && pos + block_len_bytes - start > max_bytes {
+                break;
+            }
+            pos += block_len_bytes;
+        }
+        let mut bytes = vec![0u8; pos.saturating_sub(start) as usize];
+        self._storage_read(start, &mut bytes)
+            .map_err(|e| LedgerError::BlockCorrupted(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    /// Verify raw blocks, e.g. returned by `read_raw_blocks` of another replica, and append them
+    /// to the ledger. Each block must extend the tip of the ledger, with a timestamp within the
+    /// tolerated skew (see `set_timestamp_skew`), and there must not be any uncommitted entries.
+    /// Returns the number of appended blocks. If a block does not verify, the preceding blocks
+    /// are still appended and the error is returned.
+    pub fn append_raw_blocks(&mut self, bytes: &[u8]) -> anyhow::Result<usize> {
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot append blocks to a ledger with uncommitted entries"
+            ));
+        }
+        let mut expected_parent_hash = self.get_latest_block_hash();
+        let mut parent_timestamp_ns =
+            (self.get_blocks_count() > 0).then(|| self.get_latest_block_timestamp_ns());
+        let max_timestamp_ns = self._max_block_timestamp_ns();
+        let mut block_start = 0;
+        let mut verified = Ok(());
+        for block in self.iter_raw_from_slice(bytes) {
+            let (block_header, ledger_block, hash) = match block {
+                Ok(block) => block,
+                Err(err) => {
+                    verified = Err(err);
+                    break;
+                }
+            };
+            if ledger_block.parent_hash() != expected_parent_hash {
+                verified = Err(LedgerError::HashMismatch {
+                    expected: expected_parent_hash,
+                    actual: ledger_block.parent_hash().to_vec(),
+                    offset: self.get_next_block_start_pos() + block_start as u64,
+                }
+                .into());
+                break;
+            }
+            if let Err(err) = self._verify_block_timestamp(
+                &ledger_block,
+                parent_timestamp_ns,
+                max_timestamp_ns,
+                self.get_next_block_start_pos() + block_start as u64,
+            ) {
+                verified = Err(err.into());
+                break;
+            }
+            parent_timestamp_ns = Some(ledger_block.timestamp());
+            expected_parent_hash = hash;
+            block_start += block_header.jump_bytes_next_block() as usize;
+        }
+
+        let blocks_count = self.get_blocks_count();
+        if block_start > 0 {
+            let pos = self.get_next_block_start_pos();
+            let required_bytes = pos + block_start as u64 + LedgerBlockHeader::sizeof() as u64;
+            if let Some(quota_bytes) = self._storage_limit_bytes() {
+                if required_bytes > quota_bytes {
+                    return Err(LedgerError::QuotaExceeded {
+                        quota_bytes,
+                        required_bytes,
+                    }
+                    .into());
+                }
+            }
+            self._storage_write(pos, &bytes[..block_start])?;
+            // Mark the end of the block chain, as after a commit
+            self._storage_write(
+                pos + block_start as u64,
+                &[0u8; size_of::<LedgerBlockHeader>()],
+            )?;
+            self._sync_if_durable()?;
+            self.refresh_ledger()?;
+        }
+        verified.map(|_| self.get_blocks_count() - blocks_count)
+    }
+
+    /// Returns a portable snapshot of the committed blocks of the ledger, see `Snapshot`. With
+    /// `include_index`, the snapshot also holds the committed entries of the indexed labels.
+    pub fn snapshot(&self, include_index: bool) -> anyhow::Result<Snapshot> {
+        self._activate_storage();
+        let partitions = partition_table::get_partition_table()
+            .entries
+            .iter()
+            .map(|entry| SnapshotPartition {
+                name: entry
+                    .name
+                    .iter()
+                    .take_while(|b| **b != 0)
+                    .copied()
+                    .collect(),
+                start_lba: entry.start_lba,
+            })
+            .collect();
+        let index_checkpoint = include_index
+            .then(|| self._index_checkpoint())
+            .transpose()?;
+        Ok(Snapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            partitions,
+            data_partition: self.data_partition as u32,
+            block_version: self.get_block_version(),
+            blocks_count: self.get_blocks_count() as u64,
+            tip_chain_hash: self.get_latest_block_hash(),
+            tip_timestamp_ns: self.get_latest_block_timestamp_ns(),
+            blocks: self.read_raw_blocks(self.get_data_start_pos(), u64::MAX)?,
+            index_checkpoint,
+        })
+    }
+
+    /// Write a portable snapshot of the ledger to the file `path`, see `snapshot`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
+    pub fn export_snapshot(
+        &self,
+        path: &std::path::Path,
+        include_index: bool,
+    ) -> anyhow::Result<()> {
+        self.snapshot(include_index)?.write_to_file(path)
+    }
+
+    /// Restore the blocks of `snapshot` into this ledger, which must be empty. The blocks are
+    /// verified as by `append_raw_blocks`, and must end with the chain hash of the snapshot.
+    pub fn import_snapshot(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        if self.get_blocks_count() > 0 || !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot import a snapshot into a ledger that is not empty"
+            ));
+        }
+        self.append_raw_blocks(&snapshot.blocks)?;
+        if self.get_blocks_count() as u64 != snapshot.blocks_count
+            || self.get_latest_block_hash() != snapshot.tip_chain_hash
+        {
+            return Err(LedgerError::HashMismatch {
+                expected: snapshot.tip_chain_hash.clone(),
+                actual: self.get_latest_block_hash(),
+                offset: self.get_next_block_start_pos(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Restore the ledger from a snapshot archive, read from a file or from bytes, replacing all
+    /// the blocks of the ledger. The blocks of the archive are verified, and the storage quota is
+    /// checked, before anything is written. The index checkpoint of the archive, if any, is
+    /// compared with the restored index when all labels are indexed. There must not be any
+    /// uncommitted entries.
+    pub fn restore_snapshot<'a>(
+        &mut self,
+        source: impl Into<SnapshotSource<'a>>,
+    ) -> anyhow::Result<()> {
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot restore a snapshot into a ledger with uncommitted entries"
+            ));
+        }
+        let snapshot = source.into().read()?;
+        self._verify_snapshot_blocks(&snapshot)?;
+
+        // Check the quota and grow the storage for the restored blocks before the current blocks
+        // are dropped, so that a full storage leaves the ledger as it was
+        let end_pos = self.get_data_start_pos() + snapshot.blocks.len() as u64;
+        let required_bytes = end_pos + LedgerBlockHeader::sizeof() as u64;
+        if let Some(quota_bytes) = self
+            ._storage_limit_bytes()
+            .filter(|quota_bytes| required_bytes > *quota_bytes)
+        {
+            return Err(LedgerError::QuotaExceeded {
+                quota_bytes,
+                required_bytes,
+            }
+            .into());
+        }
+        if end_pos >= self.get_next_block_start_pos() + LedgerBlockHeader::sizeof() as u64 {
+            self._storage_write(end_pos, &[0u8; size_of::<LedgerBlockHeader>()])?;
+        }
+
+        info!(
+            "Restoring {} blocks from a snapshot, replacing {} blocks",
+            snapshot.blocks_count,
+            self.get_blocks_count()
+        );
+        self._storage_write(
+            self.get_data_start_pos(),
+            &[0u8; size_of::<LedgerBlockHeader>()],
+        )?;
+        self.refresh_ledger()?;
+        self.import_snapshot(&snapshot)?;
+
+        if let (Some(checkpoint), None) = (&snapshot.index_checkpoint, &self.labels_to_index) {
+            let sort_key = |entry: &LedgerEntry| (entry.label().to_string(), entry.key().to_vec());
+            let mut expected = checkpoint.clone();
+            expected.sort_by_key(sort_key);
+            let mut actual = self._index_checkpoint()?;
+            actual.sort_by_key(sort_key);
+            if actual != expected {
+                return Err(anyhow::format_err!(
+                    "Restored index does not match the index checkpoint of the snapshot"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify the chain and the timestamps of the blocks of `snapshot` up to its tip hash, as
+    /// `append_raw_blocks` does, without writing them.
+    fn _verify_snapshot_blocks(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let mut expected_parent_hash = Vec::new();
+        let mut parent_timestamp_ns = None;
+        let max_timestamp_ns = self._max_block_timestamp_ns();
+        let mut blocks_count = 0;
+        for block in self.iter_raw_from_slice(&snapshot.blocks) {
+            let (_, ledger_block, hash) = block?;
+            if ledger_block.parent_hash() != expected_parent_hash {
+                return Err(LedgerError::HashMismatch {
+                    expected: expected_parent_hash,
+                    actual: ledger_block.parent_hash().to_vec(),
+                    offset: ledger_block.get_offset(),
                 }
-            };
-            *state += block_header.jump_bytes_next_block() as u64;
-            Some(Ok((block_header, ledger_block)))
+                .into());
+            }
+            self._verify_block_timestamp(
+                &ledger_block,
+                parent_timestamp_ns,
+                max_timestamp_ns,
+                ledger_block.get_offset(),
+            )?;
+            parent_timestamp_ns = Some(ledger_block.timestamp());
+            expected_parent_hash = hash;
+            blocks_count += 1;
+        }
+        if blocks_count != snapshot.blocks_count || expected_parent_hash != snapshot.tip_chain_hash
+        {
+            return Err(anyhow::format_err!(
+                "Snapshot blocks end with {} blocks and the chain hash {}, expected {} and {}",
+                blocks_count,
+                hex::encode(expected_parent_hash),
+                snapshot.blocks_count,
+                hex::encode(&snapshot.tip_chain_hash)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Latest block timestamp accepted by `append_raw_blocks`, see `TimestampSkew`.
+    fn _max_block_timestamp_ns(&self) -> u64 {
+        self.timestamp_skew
+            .max_ahead_of_local_ns
+            .map(|max_ahead_ns| self.clock.now_nanos().saturating_add(max_ahead_ns))
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Verify that the timestamp of an appended `ledger_block` at `offset` is within the
+    /// `TimestampSkew` allowed after its parent block and before `max_timestamp_ns`.
+    fn _verify_block_timestamp(
+        &self,
+        ledger_block: &LedgerBlock,
+        parent_timestamp_ns: Option<u64>,
+        max_timestamp_ns: u64,
+        offset: u64,
+    ) -> Result<(), LedgerError> {
+        let min_timestamp_ns = parent_timestamp_ns
+            .zip(self.timestamp_skew.max_behind_parent_ns)
+            .map(|(parent_ns, max_behind_ns)| parent_ns.saturating_sub(max_behind_ns))
+            .unwrap_or_default();
+        if !(min_timestamp_ns..=max_timestamp_ns).contains(&ledger_block.timestamp()) {
+            return Err(LedgerError::TimestampOutOfRange {
+                offset,
+                timestamp_ns: ledger_block.timestamp(),
+                min_ns: min_timestamp_ns,
+                max_ns: max_timestamp_ns,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the committed entries of the indexed labels, as stored in snapshots.
+    fn _index_checkpoint(&self) -> Result<Vec<LedgerEntry>, LedgerError> {
+        Ok(self
+            ._all_label_entries()?
+            .into_iter()
+            .flat_map(|(_, entries)| entries.values())
+            .filter(|entry| entry.operation() == Operation::Upsert)
+            .cloned()
+            .collect())
+    }
+
+    /// Copy the committed blocks of the ledger, with the partition table, to a new backing file
+    /// at `path`. Every block is verified while it is read, and the clone is verified again once
+    /// written, so that a backup is known to be good when it is created. `path` must not exist.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn clone_to(&self, path: &std::path::Path) -> anyhow::Result<CloneReport> {
+        if path.exists() {
+            return Err(anyhow::format_err!(
+                "Cannot clone the ledger to {}, which already exists",
+                path.display()
+            ));
+        }
+        // The clone is written and read back through its own backing file, the storage backend
+        // of the thread is never swapped
+        let mut target = platform_specific::BackingFile::new(Some(path.to_path_buf()))
+            .map_err(anyhow::Error::msg)?;
+
+        let mut partition_table = vec![0u8; PartitionTable::size()];
+        self._storage_read(
+            partition_table::PARTITION_TABLE_START_OFFSET,
+            &mut partition_table,
+        )
+        .map_err(anyhow::Error::msg)?;
+        target
+            .write(
+                partition_table::PARTITION_TABLE_START_OFFSET,
+                &partition_table,
+            )
+            .map_err(LedgerError::from)?;
+
+        // Copy and verify the source blocks, a chunk at a time
+        let data_start = self.get_data_start_pos();
+        let data_end = self.get_next_block_start_pos();
+        let mut expected_parent_hash = Vec::new();
+        let mut blocks_count = 0u64;
+        let mut chunks = Vec::new();
+        let mut pos = data_start;
+        while pos < data_end {
+            let chunk = self.read_raw_blocks(pos, CLONE_CHUNK_SIZE_BYTES)?;
+            blocks_count += self._verify_raw_chunk(&chunk, pos, &mut expected_parent_hash)?;
+            target.write(pos, &chunk).map_err(LedgerError::from)?;
+            chunks.push((pos, chunk.len()));
+            pos += chunk.len() as u64;
+        }
+        if expected_parent_hash != self.get_latest_block_hash() {
+            return Err(anyhow::format_err!(
+                "Ledger blocks end with the chain hash {}, expected {}",
+                hex::encode(expected_parent_hash),
+                hex::encode(self.get_latest_block_hash())
+            ));
+        }
+        // Mark the end of the block chain
+        target
+            .write(pos, &[0u8; size_of::<LedgerBlockHeader>()])
+            .map_err(LedgerError::from)?;
+
+        // Read the clone back and verify it again
+        let mut tip_chain_hash = Vec::new();
+        let mut clone_blocks_count = 0u64;
+        for (pos, len) in chunks {
+            let mut chunk = vec![0u8; len];
+            target.read(pos, &mut chunk).map_err(anyhow::Error::msg)?;
+            clone_blocks_count += self._verify_raw_chunk(&chunk, pos, &mut tip_chain_hash)?;
+        }
+        let mut end_marker = [0u8; size_of::<LedgerBlockHeader>()];
+        target
+            .read(pos, &mut end_marker)
+            .map_err(anyhow::Error::msg)?;
+        if tip_chain_hash != expected_parent_hash
+            || clone_blocks_count != blocks_count
+            || end_marker != [0u8; size_of::<LedgerBlockHeader>()]
+        {
+            return Err(anyhow::format_err!(
+                "Clone at {} has {} blocks and the chain hash {}, expected {} and {}",
+                path.display(),
+                clone_blocks_count,
+                hex::encode(tip_chain_hash),
+                blocks_count,
+                hex::encode(expected_parent_hash)
+            ));
+        }
+        info!(
+            "Cloned {} blocks ({} bytes) to {}",
+            blocks_count,
+            data_end - data_start,
+            path.display()
+        );
+        Ok(CloneReport {
+            path: path.to_path_buf(),
+            blocks_count,
+            blocks_bytes: data_end - data_start,
+            tip_chain_hash,
         })
     }
 
-    pub fn iter_raw_from_slice<'a>(
-        &'a self,
-        data: &'a [u8],
-    ) -> impl Iterator<Item = anyhow::Result<(LedgerBlockHeader, LedgerBlock, Vec<u8>)>> + 'a {
-        (0..).scan(0usize, move |offset, _| {
-            // End iteration if the offset is at or past the end of the slice.
-            if *offset >= data.len() {
-                return None;
+    /// Verify the raw blocks in `chunk`, read from offset `pos`, and that they continue the chain
+    /// from `expected_parent_hash`, which is updated to the chain hash of the last block.
+    /// Returns the number of blocks in the chunk.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn _verify_raw_chunk(
+        &self,
+        chunk: &[u8],
+        pos: u64,
+        expected_parent_hash: &mut Vec<u8>,
+    ) -> anyhow::Result<u64> {
+        let mut blocks_count = 0;
+        for block in self.iter_raw_from_slice(chunk) {
+            let (_, ledger_block, hash) = block?;
+            if ledger_block.parent_hash() != expected_parent_hash.as_slice() {
+                return Err(LedgerError::HashMismatch {
+                    expected: expected_parent_hash.clone(),
+                    actual: ledger_block.parent_hash().to_vec(),
+                    offset: pos + ledger_block.get_offset(),
+                }
+                .into());
             }
-            // Ensure there's at least enough bytes to read a header.
-            if data.len() - *offset < LedgerBlockHeader::sizeof() {
-                return None;
+            *expected_parent_hash = hash;
+            blocks_count += 1;
+        }
+        Ok(blocks_count)
+    }
+
+    /// Merge a diverged copy of the ledger into this one. `other_blocks` are all the raw blocks of
+    /// the other ledger, e.g. as returned by its `read_raw_blocks` from the start of the data
+    /// partition, because the persistent storage of a platform holds only one ledger at a time.
+    ///
+    /// The common prefix of both ledgers is kept. If only the other ledger has blocks after it,
+    /// they are appended as they are. If both have, the last writes of their blocks are committed
+    /// in a new block, with conflicts with our last writes resolved by `strategy`, together with a
+    /// `MergeRecord` of the resolutions under `MERGE_LABEL`. Entries of reserved labels in their
+    /// blocks are not merged. There must not be any uncommitted entries.
+    pub fn merge_from(
+        &mut self,
+        other_blocks: &[u8],
+        mut strategy: MergeStrategy,
+    ) -> anyhow::Result<MergeOutcome> {
+        if !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "Cannot merge into a ledger with uncommitted entries"
+            ));
+        }
+
+        let mut ours = Vec::new();
+        for block in self.iter_raw_with_hashes() {
+            let (_, ledger_block, hash) = block?;
+            ours.push((ledger_block, hash));
+        }
+
+        let mut theirs = Vec::new();
+        let mut parent_hash = Vec::new();
+        for block in self.iter_raw_from_slice(other_blocks) {
+            let (block_header, ledger_block, hash) = block?;
+            if ledger_block.parent_hash() != parent_hash {
+                return Err(LedgerError::HashMismatch {
+                    expected: parent_hash,
+                    actual: ledger_block.parent_hash().to_vec(),
+                    offset: ledger_block.get_offset(),
+                }
+                .into());
             }
-            // Attempt to parse a block from the current offset.
-            match self.get_block_from_slice(&data[*offset..]) {
-                Ok((header, block, block_hash)) => {
-                    let block_offset = *offset as u64;
-                    let jump = header.jump_bytes_next_block() as usize;
-                    // Avoid an infinite loop if jump is zero.
-                    if jump == 0 {
-                        return Some(Err(anyhow::format_err!("Block jump length is zero")));
+            parent_hash = hash.clone();
+            theirs.push((block_header, ledger_block, hash));
+        }
+
+        let common = ours
+            .iter()
+            .zip(theirs.iter())
+            .take_while(|((_, our_hash), (_, _, their_hash))| our_hash == their_hash)
+            .count();
+        if common == theirs.len() {
+            return Ok(MergeOutcome::UpToDate);
+        }
+        if common == ours.len() {
+            let start = theirs[common].1.get_offset() as usize;
+            let blocks = self.append_raw_blocks(&other_blocks[start..])?;
+            return Ok(MergeOutcome::FastForward { blocks });
+        }
+
+        fn last_writes<'a>(
+            blocks: impl Iterator<Item = &'a LedgerBlock>,
+        ) -> BTreeMap<(String, Vec<u8>), (LedgerEntry, u64)> {
+            let mut writes = BTreeMap::new();
+            for ledger_block in blocks {
+                for entry in ledger_block.entries() {
+                    if !entry.label().starts_with(RESERVED_LABEL_PREFIX) {
+                        writes.insert(
+                            (entry.label().to_string(), entry.key().to_vec()),
+                            (entry.clone(), ledger_block.timestamp()),
+                        );
                     }
-                    *offset += jump;
-                    Some(Ok((header, block.with_offset(block_offset), block_hash)))
                 }
-                Err(LedgerError::BlockEmpty) => {
-                    // End iteration if a block is empty.
-                    None
+            }
+            writes
+        }
+        let our_writes = last_writes(ours[common..].iter().map(|(block, _)| block));
+        let their_writes = last_writes(theirs[common..].iter().map(|(_, block, _)| block));
+
+        let mut record = MergeRecord {
+            base_hash: match common {
+                0 => Vec::new(),
+                _ => ours[common - 1].1.clone(),
+            },
+            theirs_tip_hash: parent_hash,
+            applied_entries: 0,
+            resolutions: Vec::new(),
+        };
+        let mut applied = Vec::new();
+        for ((label, key), (their_entry, theirs_timestamp_ns)) in their_writes {
+            if let Some((our_entry, ours_timestamp_ns)) =
+                our_writes.get(&(label.clone(), key.clone()))
+            {
+                if our_entry.operation() == their_entry.operation()
+                    && our_entry.value() == their_entry.value()
+                {
+                    continue;
+                }
+                let winner = strategy.resolve(&MergeConflict {
+                    ours: our_entry.clone(),
+                    ours_timestamp_ns: *ours_timestamp_ns,
+                    theirs: their_entry.clone(),
+                    theirs_timestamp_ns,
+                });
+                record
+                    .resolutions
+                    .push(MergeResolution { label, key, winner });
+                if winner == MergeSide::Ours {
+                    continue;
+                }
+            }
+            applied.push(their_entry);
+        }
+
+        record.applied_entries = applied.len() as u64;
+        let result = applied.into_iter().try_for_each(|entry| {
+            self._insert_entry_into_next_block(
+                entry.label(),
+                entry.key(),
+                entry.value(),
+                entry.operation(),
+            )
+        });
+        let result = result.map_err(anyhow::Error::from).and_then(|_| {
+            self._insert_entry_into_next_block(
+                MERGE_LABEL,
+                &record.theirs_tip_hash,
+                to_vec(&record)?,
+                Operation::Upsert,
+            )?;
+            self.commit_block()
+        });
+        if let Err(err) = result {
+            // Do not leave a partial merge behind as uncommitted entries
+            self.next_block_entries.clear();
+            return Err(err);
+        }
+        Ok(MergeOutcome::Merged(record))
+    }
+
+    /// Offset in the persistent storage where the data partition of the ledger starts.
+    pub fn get_data_start_pos(&self) -> u64 {
+        self.data_partition_bounds.0
+    }
+
+    pub fn get_blocks_count(&self) -> usize {
+        self.metadata.borrow().num_blocks()
+    }
+
+    pub fn get_latest_block_start_pos(&self) -> u64 {
+        self.metadata
+            .borrow()
+            .tip_block_start_pos()
+            .unwrap_or_default()
+    }
+
+    pub fn get_latest_block_hash(&self) -> Vec<u8> {
+        self.metadata.borrow().get_last_block_chain_hash().to_vec()
+    }
+
+    /// Returns the chain hash of the ledger as of the block with the given index, i.e. right after
+    /// the block was committed, without reading any block. A client can pin this hash, and later
+    /// verify that the current chain descends from it with `check_fork(hash, block_index + 1)`.
+    pub fn get_chain_hash_at(&self, block_index: usize) -> Option<Vec<u8>> {
+        self.metadata
+            .borrow()
+            .block_chain_hash(block_index)
+            .map(|chain_hash| chain_hash.to_vec())
+    }
+
+    /// Returns the offset in the persistent storage of the committed block with the given index,
+    /// without reading any block.
+    pub fn get_block_start_pos(&self, block_index: usize) -> Option<u64> {
+        self.metadata.borrow().block_start_pos(block_index)
+    }
+
+    /// Labels of the entries of the block with the given index, sorted, or `None` if there is no
+    /// such block. Only the header and the `BlockSummary` are read for blocks of version 3 and
+    /// later. The labels are the ones stored in the block, also if they were renamed since.
+    pub fn block_labels(&self, block_index: usize) -> Result<Option<Vec<String>>, LedgerError> {
+        let block_start_pos = self.metadata.borrow().block_start_pos(block_index);
+        block_start_pos
+            .map(|offset| {
+                self.get_block_summary_at_offset(offset)
+                    .map(|(_, summary)| summary.labels)
+            })
+            .transpose()
+    }
+
+    /// Indexes of the committed blocks with entries of `label`, in order, from an index that is
+    /// maintained as blocks are committed. Like `block_labels`, this is by the label stored in
+    /// the blocks, so the blocks of a renamed label stay under its old label.
+    pub fn blocks_touching_label(&self, label: &str) -> Vec<usize> {
+        self.label_blocks.get(label).cloned().unwrap_or_default()
+    }
+
+    /// Returns the committed block with the given chain hash, if there is one. The chain hashes
+    /// are not persisted: they are kept in memory, by block, as the blocks are committed or read
+    /// when the ledger is opened, so the lookup does not read the storage.
+    pub fn get_block_by_hash(
+        &self,
+        chain_hash: &[u8],
+    ) -> Result<Option<(LedgerBlockHeader, LedgerBlock)>, LedgerError> {
+        let block_start_pos = {
+            let metadata = self.metadata.borrow();
+            metadata
+                .block_index_by_chain_hash(chain_hash)
+                .and_then(|index| metadata.block_start_pos(index))
+        };
+        block_start_pos
+            .map(|offset| self.get_block_at_offset(offset))
+            .transpose()
+    }
+
+    /// Root hash of the certified entries and the ledger tip hash, to be set as the certified
+    /// data of the canister after each commit. See `set_certified_data`.
+    #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+    pub fn certified_root_hash(&self) -> [u8; 32] {
+        self.certified_index
+            .root_hash(&self.get_latest_block_hash())
+    }
+
+    /// Set the certified data of the canister to `certified_root_hash()`.
+    /// Must be called from an update call, typically right after `commit_block`.
+    #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+    pub fn set_certified_data(&self) {
+        ic_cdk::api::set_certified_data(&self.certified_root_hash());
+    }
+
+    /// Get the value of an indexed entry together with the data certificate of the canister
+    /// and a hash tree witness, with which agents can verify the value and the ledger tip hash.
+    /// Must be called from a query call, after the certified data was set with `set_certified_data`.
+    #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+    pub fn get_certified<S: AsRef<str>>(
+        &self,
+        label: S,
+        key: &[u8],
+    ) -> Result<crate::certification::CertifiedEntry, LedgerError> {
+        let label = label.as_ref();
+        let value = self.get(label, key)?;
+        let certificate = ic_cdk::api::data_certificate().ok_or_else(|| {
+            LedgerError::Other("Data certificate is only available in query calls".to_string())
+        })?;
+        Ok(crate::certification::CertifiedEntry {
+            value,
+            certificate,
+            witness: self
+                .certified_index
+                .witness(&self.get_latest_block_hash(), label, key),
+        })
+    }
+
+    pub fn get_latest_block_timestamp_ns(&self) -> u64 {
+        self.metadata.borrow().get_last_block_timestamp_ns()
+    }
+
+    pub fn get_next_block_start_pos(&self) -> u64 {
+        self.metadata.borrow().next_block_start_pos()
+    }
+
+    /// Compute the storage usage of the ledger. This reads all committed blocks.
+    pub fn storage_stats(&self) -> anyhow::Result<StorageStats> {
+        fn entry_bytes(entry: &LedgerEntry) -> u64 {
+            (entry.label().len() + entry.key().len() + entry.value().len()) as u64
+        }
+
+        let mut total_entry_bytes = 0;
+        let mut tombstone_bytes = 0;
+        for block in self.iter_raw() {
+            let (_block_header, ledger_block) = block?;
+            for entry in ledger_block.entries() {
+                if entry.label().starts_with(RESERVED_LABEL_PREFIX) {
+                    continue;
+                }
+                total_entry_bytes += entry_bytes(entry);
+                if entry.operation() == Operation::Delete {
+                    tombstone_bytes += entry_bytes(entry);
                 }
-                Err(err) => Some(Err(anyhow::format_err!(
-                    "Failed to read Ledger block: {}",
-                    err
-                ))),
             }
+        }
+
+        let label_live_bytes = self
+            ._all_label_entries()?
+            .into_iter()
+            .map(|(label, entries)| {
+                let live_bytes = entries
+                    .values()
+                    .filter(|entry| entry.operation() == Operation::Upsert)
+                    .map(entry_bytes)
+                    .sum();
+                (label.clone(), live_bytes)
+            })
+            .collect::<IndexMap<_, _>>();
+        let live_bytes: u64 = label_live_bytes.values().sum();
+
+        let metadata = self.metadata.borrow();
+        Ok(StorageStats {
+            storage_bytes: self._storage_size_bytes(),
+            blocks_bytes: metadata.next_block_start_pos() - metadata.first_block_start_pos(),
+            label_live_bytes,
+            tombstone_bytes,
+            reclaimable_bytes: total_entry_bytes.saturating_sub(live_bytes),
         })
     }
 
-    pub fn get_block_at_offset(
+    pub fn get_next_block_entries_count(&self, label: Option<&str>) -> usize {
+        self.next_block_iter(label).count()
+    }
+
+    /// Chain hash of a persisted block: computed from its entries, or recorded if it was pruned.
+    fn _block_chain_hash(block: &LedgerBlock) -> anyhow::Result<Vec<u8>> {
+        Ok(hashing::block_chain_hash(block)?)
+    }
+
+    /// Data structure of the in-memory index of `label`, see `LedgerMapBuilder::label_index_kind`.
+    fn _label_index_kind(&self, label: &str) -> LabelIndexKind {
+        self.label_index_kinds
+            .get(label)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Labels in the reserved namespace are never indexed; they hold internal entries.
+    fn _is_label_indexed(&self, label: &str) -> bool {
+        !label.starts_with(RESERVED_LABEL_PREFIX)
+            && match &self.labels_to_index {
+                Some(labels_to_index) => labels_to_index.contains(label),
+                None => true,
+            }
+    }
+
+    fn _read_ledger_info(ledger_block: &LedgerBlock) -> Result<Option<LedgerInfo>, LedgerError> {
+        ledger_block
+            .entries()
+            .iter()
+            .find(|entry| entry.label() == GENESIS_LABEL && entry.key() == GENESIS_INFO_KEY)
+            .map(|entry| {
+                LedgerInfo::try_from_slice(entry.value())
+                    .map_err(|e| LedgerError::Serialization(e.to_string()))
+            })
+            .transpose()
+    }
+
+    /// Commit the genesis block, which must be the first block of the ledger.
+    fn _commit_genesis_block(&mut self, metadata: BTreeMap<String, Vec<u8>>) -> anyhow::Result<()> {
+        if self.get_blocks_count() > 0 || !self.next_block_entries.is_empty() {
+            return Err(anyhow::format_err!(
+                "The genesis block must be the first block of the ledger"
+            ));
+        }
+        let created_at_ns = self.clock.now_nanos();
+        let ledger_info = LedgerInfo {
+            ledger_id: generate_ledger_id(created_at_ns, self.deterministic)?,
+            created_at_ns,
+            format_version: self.block_version,
+            hash_algorithm: HASH_ALGORITHM.to_string(),
+            metadata,
+        };
+        self._insert_entry_into_next_block(
+            GENESIS_LABEL,
+            GENESIS_INFO_KEY,
+            to_vec(&ledger_info)?,
+            Operation::Upsert,
+        )?;
+        self._commit_block()?;
+        self.ledger_info = Some(ledger_info);
+        Ok(())
+    }
+
+    /// Offset in the persistent storage that the ledger must not write past: the storage quota,
+    /// or the start of the next partition, whichever is lower.
+    fn _storage_limit_bytes(&self) -> Option<u64> {
+        self.storage_quota_bytes
+            .into_iter()
+            .chain(self.data_partition_bounds.1)
+            .min()
+    }
+
+    fn _read_data_partition_bounds(&self) -> anyhow::Result<(u64, Option<u64>)> {
+        self._activate_storage();
+        let table = partition_table::get_partition_table();
+        let start = match table.entries.get(self.data_partition) {
+            Some(entry) => entry.start_lba,
+            None => {
+                return Err(anyhow::format_err!(
+                    "Partition {} not found in {}",
+                    self.data_partition,
+                    table
+                ))
+            }
+        };
+        let end = table
+            .entries
+            .get(self.data_partition + 1)
+            .filter(|entry| entry.is_used())
+            .map(|entry| entry.start_lba);
+        Ok((start, end))
+    }
+
+    fn _persist_block(&self, ledger_block: LedgerBlock) -> anyhow::Result<()> {
+        let block_start_pos = self.metadata.borrow().next_block_start_pos();
+        let jump_bytes_prev_block = (self
+            .metadata
+            .borrow()
+            .tip_block_start_pos()
+            .unwrap_or_default() as i64
+            - block_start_pos as i64) as i32;
+        let jump_bytes_next_block = self._write_block(
+            block_start_pos,
+            jump_bytes_prev_block,
+            &ledger_block,
+            self._storage_limit_bytes(),
+        )?;
+
+        let new_chain_hash = Self::_block_chain_hash(&ledger_block)?;
+        let next_block_start_pos = block_start_pos + jump_bytes_next_block as u64;
+        self.metadata.borrow_mut().update_from_appended_block(
+            &new_chain_hash,
+            ledger_block.timestamp(),
+            next_block_start_pos,
+        );
+
+        // Finally, persist LedgerBlockHeader number of bytes to mark the end of the block chain
+        self._storage_write(next_block_start_pos, &[0u8; size_of::<LedgerBlockHeader>()])?;
+        self._sync_if_durable()?;
+        Ok(())
+    }
+
+    /// Write the block and its header at `block_start_pos`, and return the total number
+    /// of bytes written, which is also the jump to the next block.
+    /// If the block and the end-of-chain marker after it would not fit below `limit_bytes`,
+    /// fails with `LedgerError::QuotaExceeded` without writing the block header.
+    fn _write_block(
         &self,
-        offset: u64,
-    ) -> Result<(LedgerBlockHeader, LedgerBlock), LedgerError> {
-        let offset = if offset < self.metadata.borrow().first_block_start_pos() {
-            self.metadata.borrow().first_block_start_pos()
+        block_start_pos: u64,
+        jump_bytes_prev_block: i32,
+        ledger_block: &LedgerBlock,
+        limit_bytes: Option<u64>,
+    ) -> anyhow::Result<u32> {
+        // First stream the block data, in chunks, right after the space reserved for the header.
+        // The header is written last, so an interrupted write never yields a valid-looking block.
+        // The block is serialized only once: past the limit, the writer only counts the bytes.
+        // Encrypted blocks are serialized in memory first, since they are encrypted at once.
+        self._activate_storage();
+        let encrypted = self._encrypts_blocks();
+        let block_serialized_len = if encrypted {
+            let body = self._serialize_block_body(ledger_block)?;
+            let body_end = block_start_pos + LedgerBlockHeader::sizeof() as u64 + body.len() as u64;
+            if limit_bytes.is_none_or(|limit_bytes| body_end <= limit_bytes) {
+                self._storage_write_chunked(
+                    block_start_pos + LedgerBlockHeader::sizeof() as u64,
+                    &body,
+                )?;
+            }
+            body.len() as u64
         } else {
-            offset
+            ledger_block
+                .serialize_into(
+                    PersistentStorageWriter::new(
+                        block_start_pos + LedgerBlockHeader::sizeof() as u64,
+                    )
+                    .with_limit(limit_bytes.map(|limit_bytes| {
+                        limit_bytes.saturating_sub(LedgerBlockHeader::sizeof() as u64)
+                    })),
+                )
+                .and_then(PersistentStorageWriter::finish)
+                .map_err(LedgerError::from)?
         };
-        self._persisted_block_read(offset)
+        // Block header, block data, and the end-of-chain marker (an empty block header)
+        let required_bytes =
+            block_start_pos + block_serialized_len + 2 * LedgerBlockHeader::sizeof() as u64;
+        if let Some(quota_bytes) = limit_bytes.filter(|quota_bytes| required_bytes > *quota_bytes) {
+            return Err(LedgerError::QuotaExceeded {
+                quota_bytes,
+                required_bytes,
+            }
+            .into());
+        }
+        info!(
+            "Appending block @timestamp {} with {} bytes data: {}",
+            ledger_block.timestamp(),
+            block_serialized_len,
+            ledger_block
+        );
+
+        // Then persist block header
+        let jump_bytes_next_block =
+            (block_serialized_len as usize + LedgerBlockHeader::sizeof()) as u32;
+        let mut block_header = LedgerBlockHeader::new_with_version(
+            ledger_block.version(),
+            jump_bytes_prev_block,
+            jump_bytes_next_block,
+        )
+        .with_encrypted(encrypted);
+        if let Some(summary_len) = ledger_block.summary_len()?.filter(|_| !encrypted) {
+            block_header = block_header.with_summary_len(summary_len);
+        }
+        let serialized_block_header = block_header.serialize()?;
+        self._storage_write(block_start_pos, &serialized_block_header)?;
+        Ok(jump_bytes_next_block)
+    }
+
+    /// Whether new blocks are encrypted, see `LedgerMapBuilder::encryption_keys`.
+    fn _encrypts_blocks(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.encryption_keys.is_some();
+        #[cfg(not(feature = "encryption"))]
+        false
     }
 
-    pub fn get_block_from_slice(
+    /// Serialized body of `ledger_block`, encrypted if new blocks are encrypted. The block
+    /// version is authenticated with the encrypted body.
+    fn _serialize_block_body(&self, ledger_block: &LedgerBlock) -> anyhow::Result<Vec<u8>> {
+        let body = ledger_block.serialize_into(Vec::new())?;
+        #[cfg(feature = "encryption")]
+        if let Some(encryption_keys) = &self.encryption_keys {
+            return Ok(encryption_keys.encrypt(&ledger_block.version().to_le_bytes(), &body)?);
+        }
+        Ok(body)
+    }
+
+    /// Block with the header `block_header` and the serialized `body`, which is decrypted first
+    /// if the block is encrypted.
+    fn _deserialize_block_body(
         &self,
-        data: &[u8],
-    ) -> Result<(LedgerBlockHeader, LedgerBlock, Vec<u8>), LedgerError> {
-        let header_size = LedgerBlockHeader::sizeof();
-        if data.len() < header_size {
-            return Err(LedgerError::BlockCorrupted("Block too short".to_string()));
+        block_header: &LedgerBlockHeader,
+        body: &[u8],
+    ) -> Result<LedgerBlock, LedgerError> {
+        if !block_header.is_encrypted() {
+            return LedgerBlock::deserialize(body, block_header.block_version());
         }
-        let block_header = LedgerBlockHeader::deserialize(data)?;
-        let end = block_header.jump_bytes_next_block() as usize;
+        #[cfg(feature = "encryption")]
+        if let Some(encryption_keys) = &self.encryption_keys {
+            let version = block_header.block_version();
+            let body = encryption_keys.decrypt(&version.to_le_bytes(), body)?;
+            return LedgerBlock::deserialize(&body, version);
+        }
+        Err(LedgerError::Encryption(
+            "The block is encrypted, but the ledger has no encryption keys".to_string(),
+        ))
+    }
 
-        // Check if there's enough data for the block payload.
-        if data.len() < end || end < header_size {
-            return Err(LedgerError::BlockCorrupted("Block too short".to_string()));
+    /// Length of the serialized `body` of the block with the header `block_header`, without the
+    /// padding that an interrupted compaction may leave after it.
+    fn _block_body_len(
+        block_header: &LedgerBlockHeader,
+        body: &[u8],
+    ) -> Result<usize, LedgerError> {
+        if !block_header.is_encrypted() {
+            return LedgerBlock::serialized_len(body, block_header.block_version());
         }
+        #[cfg(feature = "encryption")]
+        return encryption::encrypted_body_len(body);
+        #[cfg(not(feature = "encryption"))]
+        Err(LedgerError::Encryption(
+            "The block is encrypted, but the encryption feature is not enabled".to_string(),
+        ))
+    }
 
-        let block =
-            LedgerBlock::deserialize(&data[header_size..end], block_header.block_version())?;
-        let block_hash = Self::_compute_block_chain_hash(
-            block.parent_hash(),
-            block.entries(),
-            block.timestamp(),
-        )
-        .map_err(|e| LedgerError::BlockCorrupted(e.to_string()))?;
-        Ok((block_header, block, block_hash))
+    /// Copy `len` bytes of persistent storage from offset `src` to offset `dst`, in chunks.
+    /// Overlapping regions are handled by copying back-to-front when moving data forward.
+    fn _persistent_storage_copy(&self, src: u64, dst: u64, len: u64) -> anyhow::Result<()> {
+        let chunk_size = PERSISTENT_STORAGE_WRITE_CHUNK_SIZE as u64;
+        let mut buf = vec![0u8; PERSISTENT_STORAGE_WRITE_CHUNK_SIZE];
+        let mut copied = 0;
+        while copied < len {
+            let n = chunk_size.min(len - copied);
+            let pos = if dst > src { len - copied - n } else { copied };
+            let chunk = &mut buf[..n as usize];
+            self._storage_read(src + pos, chunk)
+                .map_err(anyhow::Error::msg)?;
+            self._storage_write(dst + pos, chunk)?;
+            copied += n;
+        }
+        Ok(())
     }
 
-    pub fn get_blocks_count(&self) -> usize {
-        self.metadata.borrow().num_blocks()
+    /// Make the storage backend of this ledger the active one of the thread, before accessing
+    /// the persistent storage through the `platform_specific` functions.
+    fn _activate_storage(&self) {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        platform_specific::activate_storage_backend(&self.storage_backend);
     }
 
-    pub fn get_latest_block_start_pos(&self) -> u64 {
-        self.metadata
-            .borrow()
-            .tip_block_start_pos()
-            .unwrap_or_default()
+    fn _storage_read(&self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        self._activate_storage();
+        persistent_storage_read(offset, buf)
     }
 
-    pub fn get_latest_block_hash(&self) -> Vec<u8> {
-        self.metadata.borrow().get_last_block_chain_hash().to_vec()
+    fn _storage_write(&self, offset: u64, buf: &[u8]) -> Result<(), LedgerError> {
+        self._activate_storage();
+        persistent_storage_write(offset, buf)
     }
 
-    pub fn get_latest_block_timestamp_ns(&self) -> u64 {
-        self.metadata.borrow().get_last_block_timestamp_ns()
+    /// Write `buf` in chunks of `PERSISTENT_STORAGE_WRITE_CHUNK_SIZE` bytes.
+    fn _storage_write_chunked(&self, offset: u64, buf: &[u8]) -> Result<(), LedgerError> {
+        for (i, chunk) in buf.chunks(PERSISTENT_STORAGE_WRITE_CHUNK_SIZE).enumerate() {
+            self._storage_write(
+                offset + (i * PERSISTENT_STORAGE_WRITE_CHUNK_SIZE) as u64,
+                chunk,
+            )?;
+        }
+        Ok(())
     }
 
-    pub fn get_next_block_start_pos(&self) -> u64 {
-        self.metadata.borrow().next_block_start_pos()
+    /// Sync the persistent storage with `Durability::Sync`, see `LedgerMapBuilder::durability`.
+    fn _sync_if_durable(&self) -> Result<(), LedgerError> {
+        if self.durability != Durability::Sync {
+            return Ok(());
+        }
+        self._activate_storage();
+        persistent_storage_sync().map_err(|e| LedgerError::from(std::io::Error::other(e)))
     }
 
-    pub fn get_next_block_entries_count(&self, label: Option<&str>) -> usize {
-        self.next_block_iter(label).count()
+    fn _storage_size_bytes(&self) -> u64 {
+        self._activate_storage();
+        persistent_storage_size_bytes()
     }
 
-    fn _compute_block_chain_hash(
-        parent_block_hash: &[u8],
-        block_entries: &[LedgerEntry],
-        block_timestamp: u64,
-    ) -> anyhow::Result<Vec<u8>> {
-        let mut hasher = sha2::Sha256::new();
-        hasher.update(parent_block_hash);
-        for entry in block_entries.iter() {
-            hasher.update(to_vec(entry)?);
-        }
-        hasher.update(block_timestamp.to_le_bytes());
-        Ok(hasher.finalize().to_vec())
+    fn _persisted_block_header_read(&self, offset: u64) -> Result<LedgerBlockHeader, LedgerError> {
+        let mut buf = [0u8; size_of::<LedgerBlockHeader>()];
+        self._storage_read(offset, &mut buf)
+            .map_err(|e| LedgerError::BlockCorrupted(e.to_string()))?;
+        LedgerBlockHeader::deserialize(buf.as_ref())
     }
 
-    fn _persist_block(&self, ledger_block: LedgerBlock) -> anyhow::Result<()> {
-        let block_serialized_data = ledger_block.serialize()?;
-        info!(
-            "Appending block @timestamp {} with {} bytes data: {}",
-            ledger_block.timestamp(),
-            block_serialized_data.len(),
-            ledger_block
-        );
-        // Prepare block header
-        let jump_bytes_prev_block = (self
-            .metadata
-            .borrow()
-            .tip_block_start_pos()
-            .unwrap_or_default() as i64
-            - self.metadata.borrow().next_block_start_pos() as i64)
-            as i32;
-        let jump_bytes_next_block =
-            (block_serialized_data.len() + LedgerBlockHeader::sizeof()) as u32;
-        let serialized_block_header =
-            LedgerBlockHeader::new(jump_bytes_prev_block, jump_bytes_next_block).serialize()?;
-
-        // First persist block header
-        persistent_storage_write(
-            self.metadata.borrow().next_block_start_pos(),
-            &serialized_block_header,
-        );
+    /// Read only the `BlockSummary` of the block at `offset`, which must have one.
+    fn _persisted_block_summary_read(
+        &self,
+        offset: u64,
+        summary_len: u32,
+    ) -> Result<BlockSummary, LedgerError> {
+        let mut buf = vec![0u8; summary_len as usize];
+        self._storage_read(offset + LedgerBlockHeader::sizeof() as u64, &mut buf)
+            .map_err(|e| LedgerError::BlockCorrupted(e.to_string()))?;
+        BlockSummary::deserialize(&buf)
+    }
 
-        // Then persist block data
-        persistent_storage_write(
-            self.metadata.borrow().next_block_start_pos() + LedgerBlockHeader::sizeof() as u64,
-            &block_serialized_data,
-        );
+    /// Header and block at storage offset `offset`.
+    pub(crate) fn read_block_at(
+        &self,
+        offset: u64,
+    ) -> Result<(LedgerBlockHeader, LedgerBlock), LedgerError> {
+        self._persisted_block_read(offset)
+    }
 
-        let new_chain_hash = Self::_compute_block_chain_hash(
-            ledger_block.parent_hash(),
-            ledger_block.entries(),
-            ledger_block.timestamp(),
-        )?;
-        let next_block_start_pos =
-            self.metadata.borrow().next_block_start_pos() + jump_bytes_next_block as u64;
-        self.metadata.borrow_mut().update_from_appended_block(
-            &new_chain_hash,
-            ledger_block.timestamp(),
-            next_block_start_pos,
-        );
+    /// Switch the ledger to the active storage backend of the thread, e.g. a backing file that
+    /// was reopened after it had been replaced, and reload the ledger from it.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub(crate) fn refresh_from_active_storage(&mut self) -> anyhow::Result<()> {
+        self.storage_backend =
+            platform_specific::active_storage_backend().map_err(anyhow::Error::msg)?;
+        self.refresh_ledger()
+    }
 
-        // Finally, persist LedgerBlockHeader number of bytes to mark the end of the block chain
-        persistent_storage_write(
-            self.metadata.borrow().next_block_start_pos() + jump_bytes_next_block as u64,
-            &[0u8; size_of::<LedgerBlockHeader>()],
-        );
-        Ok(())
+    /// Read `buf.len()` bytes at `offset` from the persistent storage of the ledger.
+    #[cfg(feature = "server")]
+    pub(crate) fn read_storage(&self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        self._storage_read(offset, buf)
+    }
+
+    /// Size of the persistent storage of the ledger, in bytes.
+    pub(crate) fn storage_size_bytes(&self) -> u64 {
+        self._storage_size_bytes()
+    }
+
+    /// Storage offset of the first block.
+    pub(crate) fn data_start_offset(&self) -> u64 {
+        self.data_partition_bounds.0
     }
 
     fn _persisted_block_read(
@@ -535,25 +5127,62 @@ impl LedgerMap {
         offset: u64,
     ) -> Result<(LedgerBlockHeader, LedgerBlock), LedgerError> {
         // Find out how many bytes we need to read ==> block len in bytes
-        let mut buf = [0u8; size_of::<LedgerBlockHeader>()];
-        persistent_storage_read(offset, &mut buf)
-            .map_err(|e| LedgerError::BlockCorrupted(e.to_string()))?;
-
-        let block_header = LedgerBlockHeader::deserialize(buf.as_ref())?;
+        let block_header = self._persisted_block_header_read(offset)?;
         let block_len_bytes = block_header.jump_bytes_next_block();
 
         // Read the block as raw bytes
         let mut buf = vec![0u8; block_len_bytes as usize];
-        persistent_storage_read(offset + LedgerBlockHeader::sizeof() as u64, &mut buf)
-            .map_err(|e| LedgerError::Other(e.to_string()))?;
+        self._storage_read(offset + LedgerBlockHeader::sizeof() as u64, &mut buf)
+            .map_err(|e| LedgerError::BlockCorrupted(e.to_string()))?;
 
-        let block = LedgerBlock::deserialize(buf.as_ref(), block_header.block_version())
-            .map_err(|err| LedgerError::BlockCorrupted(err.to_string()))?
+        let block = self
+            ._deserialize_block_body(&block_header, buf.as_ref())
+            .map_err(|err| match err {
+                LedgerError::Encryption(_) => err,
+                err => LedgerError::BlockCorrupted(err.to_string()),
+            })?
             .with_offset(offset);
 
         Ok((block_header, block))
     }
 
+    fn _check_key_size(&self, key: &[u8]) -> Result<(), LedgerError> {
+        if key.len() > self.max_key_size_bytes {
+            return Err(LedgerError::KeyTooLarge {
+                size_bytes: key.len(),
+                max_size_bytes: self.max_key_size_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    fn _check_value_size(&self, value: &[u8]) -> Result<(), LedgerError> {
+        if value.len() > self.max_value_size_bytes {
+            return Err(LedgerError::ValueTooLarge {
+                size_bytes: value.len(),
+                max_size_bytes: self.max_value_size_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    fn _check_label_policy(&self, label: &str, operation: Operation) -> Result<(), LedgerError> {
+        if let Some(policy) = self.label_policies.get(label) {
+            let access_operation = match operation {
+                Operation::Upsert => AccessOperation::Upsert,
+                Operation::Delete => AccessOperation::Delete,
+            };
+            let principal = self.principal_source.principal();
+            if let Some(reason) = policy.check(&principal, access_operation) {
+                return Err(LedgerError::Unauthorized(format!(
+                    "cannot {} label {:?}: {}",
+                    access_operation, label, reason
+                )));
+            }
+        }
+        Ok(())
+    }
+
     fn _insert_entry_into_next_block<S: AsRef<str>, K: AsRef<[u8]>, V: AsRef<[u8]>>(
         &mut self,
         label: S,
@@ -561,6 +5190,9 @@ impl LedgerMap {
         value: V,
         operation: Operation,
     ) -> Result<(), LedgerError> {
+        self._check_key_size(key.as_ref())?;
+        self._check_value_size(value.as_ref())?;
+        self._check_label_policy(label.as_ref(), operation)?;
         let entry = LedgerEntry::new(label.as_ref(), key, value, operation);
         match self.next_block_entries.get_mut(entry.label()) {
             Some(entries) => {
@@ -578,6 +5210,674 @@ impl LedgerMap {
     }
 }
 
+/// Record that the block `block_index` has entries of `label`. Blocks are recorded in order.
+fn record_label_block(
+    label_blocks: &mut BTreeMap<String, Vec<usize>>,
+    label: &str,
+    block_index: usize,
+) {
+    match label_blocks.get_mut(label) {
+        Some(blocks) => {
+            if blocks.last() != Some(&block_index) {
+                blocks.push(block_index);
+            }
+        }
+        None => {
+            label_blocks.insert(label.to_string(), vec![block_index]);
+        }
+    }
+}
+
+/// Apply `entry` to `label_policies` if it is a record of a label policy.
+fn apply_label_policy_record(
+    label_policies: &mut BTreeMap<String, LabelPolicy>,
+    entry: &LedgerEntry,
+) -> Result<(), LedgerError> {
+    if entry.label() != POLICY_LABEL {
+        return Ok(());
+    }
+    let label = String::from_utf8_lossy(entry.key()).to_string();
+    match entry.operation() {
+        Operation::Upsert => {
+            let policy = LabelPolicy::try_from_slice(entry.value())
+                .map_err(|e| LedgerError::Serialization(e.to_string()))?;
+            label_policies.insert(label, policy);
+        }
+        Operation::Delete => {
+            label_policies.remove(&label);
+        }
+    }
+    Ok(())
+}
+
+/// Apply `entry` to `value_refs` if it is a record of a chunked value or of a blob reference,
+/// and keep the number of references to each blob in `blob_ref_counts`.
+fn apply_value_ref_record(
+    value_refs: &mut BTreeMap<(String, Vec<u8>), ValueRef>,
+    blob_ref_counts: &mut BTreeMap<Vec<u8>, u64>,
+    entry: &LedgerEntry,
+) -> Result<(), LedgerError> {
+    let is_blob_ref = match entry.label() {
+        CHUNKED_LABEL => false,
+        BLOB_REF_LABEL => true,
+        _ => return Ok(()),
+    };
+    let label_key = <(String, Vec<u8>)>::try_from_slice(entry.key())
+        .map_err(|e| LedgerError::Serialization(e.to_string()))?;
+    let old_value_ref = match entry.operation() {
+        Operation::Upsert => {
+            let value_ref = match is_blob_ref {
+                true => {
+                    *blob_ref_counts.entry(entry.value().to_vec()).or_default() += 1;
+                    ValueRef::Blob(entry.value().to_vec())
+                }
+                false => ValueRef::Chunked,
+            };
+            value_refs.insert(label_key, value_ref)
+        }
+        Operation::Delete => match value_refs.get(&label_key) {
+            Some(ValueRef::Blob(_)) if is_blob_ref => value_refs.remove(&label_key),
+            Some(ValueRef::Chunked) if !is_blob_ref => value_refs.remove(&label_key),
+            _ => None,
+        },
+    };
+    if let Some(ValueRef::Blob(hash)) = old_value_ref {
+        if let Some(count) = blob_ref_counts.get_mut(&hash) {
+            *count -= 1;
+            if *count == 0 {
+                blob_ref_counts.remove(&hash);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply `entry` to `blobs` if it is a blob record.
+fn apply_blob_record(
+    blobs: &mut BTreeMap<Vec<u8>, ChunkedValue>,
+    entry: &LedgerEntry,
+) -> Result<(), LedgerError> {
+    if entry.label() != BLOB_LABEL {
+        return Ok(());
+    }
+    match entry.operation() {
+        Operation::Upsert => {
+            let chunked_value = ChunkedValue::try_from_slice(entry.value())
+                .map_err(|e| LedgerError::Serialization(e.to_string()))?;
+            blobs.insert(entry.key().to_vec(), chunked_value);
+        }
+        Operation::Delete => {
+            blobs.remove(entry.key());
+        }
+    }
+    Ok(())
+}
+
+/// Update the secondary indexes of the label of `entry`, before `entry` is applied to `entries`.
+fn update_secondary_indexes(
+    secondary_indexes: &mut IndexMap<String, IndexMap<String, SecondaryIndex>>,
+    entries: &IndexMap<String, LabelIndex>,
+    entry: &LedgerEntry,
+) {
+    let indexes = match secondary_indexes.get_mut(entry.label()) {
+        Some(indexes) => indexes,
+        None => return,
+    };
+    let old_value = entries
+        .get(entry.label())
+        .and_then(|entries| entries.get(entry.key()))
+        .filter(|old_entry| old_entry.operation() == Operation::Upsert)
+        .map(|old_entry| old_entry.value());
+    let new_value = match entry.operation() {
+        Operation::Upsert => Some(entry.value()),
+        Operation::Delete => None,
+    };
+    for index in indexes.values_mut() {
+        index.update(entry.key(), old_value, new_value);
+    }
+}
+
+/// Persistent storage that `LedgerMapBuilder::build` activates.
+#[derive(Debug)]
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    )),
+    allow(dead_code)
+)]
+enum BuilderStorage {
+    /// Keep the currently active persistent storage.
+    Current,
+    File(Option<std::path::PathBuf>),
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    SegmentedFile(Option<std::path::PathBuf>, u64),
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    Memory,
+}
+
+/// Builder for LedgerMap instances, as returned by `LedgerMap::builder()`.
+///
+/// Example usage:
+///
+/// ```rust,no_run
+/// use ledger_map::LedgerMap;
+///
+/// let ledger_map = LedgerMap::builder()
+///     .labels_to_index(vec!["users".to_string()])
+///     .path(Some("/tmp/ledger_map/data.bin".into()))
+///     .max_value_size(1024 * 1024)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct LedgerMapBuilder {
+    labels_to_index: Option<Vec<String>>,
+    label_index_kinds: BTreeMap<String, LabelIndexKind>,
+    #[cfg_attr(
+        not(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            all(target_arch = "wasm32", target_os = "wasi")
+        )),
+        allow(dead_code)
+    )]
+    storage: BuilderStorage,
+    partition_table: Option<PartitionTable>,
+    clock: Box<dyn Clock>,
+    anchor: Option<(Box<dyn Anchor>, u64)>,
+    checkpoint_signers: Option<(Vec<Box<dyn CheckpointSigner>>, u64)>,
+    access_controller: Option<Box<dyn AccessController>>,
+    principal_source: Box<dyn PrincipalSource>,
+    cold_storage: Option<Box<dyn ColdStorage>>,
+    #[cfg(feature = "encryption")]
+    encryption_keys: Option<EncryptionKeys>,
+    storage_quota_bytes: Option<u64>,
+    max_key_size_bytes: usize,
+    max_value_size_bytes: usize,
+    chunk_size_bytes: usize,
+    timestamp_skew: TimestampSkew,
+    genesis_metadata: Option<BTreeMap<String, Vec<u8>>>,
+    block_version: u32,
+    entry_hashes: bool,
+    deterministic: bool,
+    monotonic_timestamps: bool,
+    reject_non_monotonic_timestamps: bool,
+    lazy_index: bool,
+    hash_algorithm: String,
+    durability: Durability,
+    auto_commit_entries: Option<usize>,
+    data_partition: usize,
+}
+
+impl Default for LedgerMapBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LedgerMapBuilder {
+    pub fn new() -> Self {
+        LedgerMapBuilder {
+            labels_to_index: None,
+            label_index_kinds: BTreeMap::new(),
+            storage: BuilderStorage::Current,
+            partition_table: None,
+            clock: Box::new(SystemClock),
+            anchor: None,
+            checkpoint_signers: None,
+            access_controller: None,
+            principal_source: Box::new(platform_specific::get_caller_principal),
+            cold_storage: None,
+            #[cfg(feature = "encryption")]
+            encryption_keys: None,
+            storage_quota_bytes: None,
+            max_key_size_bytes: DEFAULT_MAX_KEY_SIZE_BYTES,
+            max_value_size_bytes: DEFAULT_MAX_VALUE_SIZE_BYTES,
+            chunk_size_bytes: DEFAULT_CHUNK_SIZE_BYTES,
+            timestamp_skew: TimestampSkew::default(),
+            genesis_metadata: None,
+            block_version: LEDGER_BLOCK_VERSION,
+            entry_hashes: false,
+            deterministic: false,
+            monotonic_timestamps: false,
+            reject_non_monotonic_timestamps: false,
+            lazy_index: false,
+            hash_algorithm: HASH_ALGORITHM.to_string(),
+            durability: Durability::default(),
+            auto_commit_entries: None,
+            data_partition: partition_table::PART_DATA,
+        }
+    }
+
+    /// Index only the given labels. By default, all labels are indexed.
+    pub fn labels_to_index(mut self, labels_to_index: Vec<String>) -> Self {
+        self.labels_to_index = Some(labels_to_index);
+        self
+    }
+
+    /// Use `kind` as the data structure of the in-memory index of the committed entries of
+    /// `label`, instead of an `IndexMap`. It determines the order in which `iter` and `query`
+    /// return the entries of the label: insertion order for `IndexMap`, key order for
+    /// `BTreeMap`, and no particular order for `HashMap`, which has the fastest lookups.
+    pub fn label_index_kind(mut self, label: impl Into<String>, kind: LabelIndexKind) -> Self {
+        self.label_index_kinds.insert(label.into(), kind);
+        self
+    }
+
+    /// Store the ledger in the file at `path`, or in the default file if `None`.
+    /// In the browser, where the ledger is always stored in the browser storage, `build` fails if
+    /// a path is given.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", feature = "browser"),
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
+    pub fn path(mut self, path: Option<std::path::PathBuf>) -> Self {
+        self.storage = BuilderStorage::File(path);
+        self
+    }
+
+    /// Store the ledger in multiple segment files of at most `segment_size_bytes` each.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn segmented_path(
+        mut self,
+        path: Option<std::path::PathBuf>,
+        segment_size_bytes: u64,
+    ) -> Self {
+        self.storage = BuilderStorage::SegmentedFile(path, segment_size_bytes);
+        self
+    }
+
+    /// Store the ledger in a new, empty `MemoryStorage`, see `LedgerMap::new_in_memory`.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn in_memory(mut self) -> Self {
+        self.storage = BuilderStorage::Memory;
+        self
+    }
+
+    /// Use a custom partition table layout if the persistent storage is not yet initialized.
+    pub fn partition_table(mut self, partition_table: PartitionTable) -> Self {
+        self.partition_table = Some(partition_table);
+        self
+    }
+
+    /// Use `timestamp_fn` instead of the system clock for block timestamps.
+    pub fn timestamp_fn(self, timestamp_fn: fn() -> u64) -> Self {
+        self.clock(timestamp_fn)
+    }
+
+    /// Use `clock` instead of the system clock for block timestamps.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// See `LedgerMap::set_anchor`.
+    pub fn anchor(mut self, anchor: impl Anchor + 'static, every_blocks: u64) -> Self {
+        self.anchor = Some((Box::new(anchor), every_blocks.max(1)));
+        self
+    }
+
+    /// See `LedgerMap::set_checkpoint_signers`.
+    pub fn checkpoint_signers(
+        mut self,
+        signers: Vec<Box<dyn CheckpointSigner>>,
+        every_blocks: u64,
+    ) -> Self {
+        self.checkpoint_signers = Some((signers, every_blocks.max(1)));
+        self
+    }
+
+    /// See `LedgerMap::set_access_controller`.
+    pub fn access_controller(mut self, access_controller: impl AccessController + 'static) -> Self {
+        self.access_controller = Some(Box::new(access_controller));
+        self
+    }
+
+    /// See `LedgerMap::set_principal_source`.
+    pub fn principal_source(mut self, principal_source: impl PrincipalSource + 'static) -> Self {
+        self.principal_source = Box::new(principal_source);
+        self
+    }
+
+    /// See `LedgerMap::set_cold_storage`.
+    pub fn cold_storage(mut self, cold_storage: impl ColdStorage + 'static) -> Self {
+        self.cold_storage = Some(Box::new(cold_storage));
+        self
+    }
+
+    /// Encrypt new blocks with the current key of `encryption_keys`, and decrypt the encrypted
+    /// blocks with the key of their key epoch, see `encryption`.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_keys(mut self, encryption_keys: EncryptionKeys) -> Self {
+        self.encryption_keys = Some(encryption_keys);
+        self
+    }
+
+    /// See `LedgerMap::set_storage_quota`.
+    pub fn storage_quota(mut self, quota_bytes: u64) -> Self {
+        self.storage_quota_bytes = Some(quota_bytes);
+        self
+    }
+
+    /// See `LedgerMap::set_max_key_size`.
+    pub fn max_key_size(mut self, max_size_bytes: usize) -> Self {
+        self.max_key_size_bytes = max_size_bytes;
+        self
+    }
+
+    /// See `LedgerMap::set_max_value_size`.
+    pub fn max_value_size(mut self, max_size_bytes: usize) -> Self {
+        self.max_value_size_bytes = max_size_bytes;
+        self
+    }
+
+    /// See `LedgerMap::set_chunk_size`.
+    pub fn chunk_size(mut self, chunk_size_bytes: usize) -> Self {
+        self.chunk_size_bytes = chunk_size_bytes;
+        self
+    }
+
+    /// See `LedgerMap::set_timestamp_skew`.
+    pub fn timestamp_skew(mut self, timestamp_skew: TimestampSkew) -> Self {
+        self.timestamp_skew = timestamp_skew;
+        self
+    }
+
+    /// Write a genesis block with a new ledger identity and the given application-defined
+    /// metadata if the ledger is empty. See `LedgerMap::ledger_info`.
+    pub fn genesis(mut self, metadata: BTreeMap<String, Vec<u8>>) -> Self {
+        self.genesis_metadata = Some(metadata);
+        self
+    }
+
+    /// Write new blocks with the given block version. Blocks of all supported versions
+    /// can be read, regardless of this setting. A ledger whose blocks use a later version, e.g.
+    /// after `LedgerMap::migrate_format`, keeps writing new blocks in that version.
+    pub fn block_version(mut self, block_version: u32) -> Self {
+        self.block_version = block_version;
+        self
+    }
+
+    /// Store the hash of each entry in the new blocks, and derive their chain hash from the entry
+    /// hashes (chain hash version 2, see `hashing`), so that a single entry can be verified with
+    /// only the hashes of the other entries of its block. Blocks with entry hashes need block
+    /// version 2 or later, so this also raises the block version to 2 if needed.
+    pub fn entry_hashes(mut self) -> Self {
+        self.entry_hashes = true;
+        self.block_version = self.block_version.max(2);
+        self
+    }
+
+    /// Load the index of each label lazily: `refresh_ledger` only verifies the chain and records
+    /// the blocks with entries of each label, and the index of a label is built from its blocks
+    /// when it is first accessed, e.g. by `get` or `iter`. This speeds up opening a large ledger
+    /// of which only a few labels are read. Labels with secondary indexes are loaded on refresh.
+    /// As labels are loaded from the current persistent storage, the backing file of the ledger
+    /// must be the active one when a label is first accessed.
+    pub fn lazy_index(mut self, lazy_index: bool) -> Self {
+        self.lazy_index = lazy_index;
+        self
+    }
+
+    /// Use `hash_algorithm` for the chain hashes. Only `HASH_ALGORITHM` ("sha256") is supported,
+    /// so `build` fails for any other algorithm, rather than silently hashing with SHA-256.
+    pub fn hash_algorithm(mut self, hash_algorithm: impl Into<String>) -> Self {
+        self.hash_algorithm = hash_algorithm.into();
+        self
+    }
+
+    /// Make the committed blocks durable as configured by `durability`, see `Durability`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Commit the next block automatically as soon as it holds `max_entries` entries, after
+    /// `upsert`, `upsert_many`, `delete` or `delete_many`. If the automatic commit fails, the
+    /// entries stay in the next block, as after a failed `LedgerMap::commit_block`, and the write
+    /// returns the error. A zero `max_entries` is taken as 1, which commits every write.
+    pub fn auto_commit(mut self, max_entries: usize) -> Self {
+        self.auto_commit_entries = Some(max_entries.max(1));
+        self
+    }
+
+    /// Make the ledger reproducible, so that the same operations result in the same blocks and
+    /// block hashes on any machine, e.g. for golden-hash tests:
+    /// - block timestamps come from a `CounterClock` starting at 0, with a step of 1 ns; call
+    ///   `clock` after this method to use another clock,
+    /// - the entries of a block are ordered by label and key, instead of the order of the writes,
+    /// - the ledger id of the genesis block is derived from the genesis timestamp only.
+    pub fn deterministic(mut self) -> Self {
+        self.clock = Box::new(CounterClock::new(0, 1));
+        self.deterministic = true;
+        self
+    }
+
+    /// Make block timestamps strictly increasing: if the clock goes backwards, or does not
+    /// advance, a new block gets the timestamp of the previous block plus 1 ns.
+    pub fn monotonic_timestamps(mut self) -> Self {
+        self.monotonic_timestamps = true;
+        self
+    }
+
+    /// Fail to load a ledger whose block timestamps are not strictly increasing, e.g. because
+    /// it was written without `monotonic_timestamps` by a clock that went backwards.
+    pub fn reject_non_monotonic_timestamps(mut self) -> Self {
+        self.reject_non_monotonic_timestamps = true;
+        self
+    }
+
+    /// Store the blocks in the partition with index `data_partition` of the partition table,
+    /// instead of the data partition. Used by `LedgerSet`.
+    pub(crate) fn data_partition(mut self, data_partition: usize) -> Self {
+        self.data_partition = data_partition;
+        self
+    }
+
+    /// Activate the configured persistent storage and load the ledger from it.
+    pub fn build(self) -> anyhow::Result<LedgerMap> {
+        if self.hash_algorithm != HASH_ALGORITHM {
+            return Err(anyhow::format_err!(
+                "Unsupported hash algorithm {:?}, only {:?} is supported",
+                self.hash_algorithm,
+                HASH_ALGORITHM
+            ));
+        }
+        #[cfg(all(target_arch = "wasm32", feature = "browser"))]
+        if let BuilderStorage::File(Some(path)) = &self.storage {
+            return Err(anyhow::format_err!(
+                "Cannot store the ledger at {:?}: in the browser, it is stored in the browser storage",
+                path
+            ));
+        }
+        if !(1..=LATEST_BLOCK_VERSION).contains(&self.block_version) {
+            return Err(LedgerError::UnsupportedBlockVersion(self.block_version).into());
+        }
+        if self.entry_hashes && self.block_version < 2 {
+            return Err(anyhow::format_err!(
+                "Entry hashes need block version 2 or later, not {}",
+                self.block_version
+            ));
+        }
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            all(target_arch = "wasm32", target_os = "wasi")
+        ))]
+        {
+            let activated = match self.storage {
+                BuilderStorage::Current => Ok(()),
+                BuilderStorage::File(path) => platform_specific::set_backing_file(path),
+                #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+                BuilderStorage::SegmentedFile(path, segment_size_bytes) => {
+                    platform_specific::set_segmented_backing_file(path, segment_size_bytes)
+                }
+                #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+                BuilderStorage::Memory => {
+                    platform_specific::set_memory_storage();
+                    Ok(())
+                }
+            };
+            activated.map_err(|e| anyhow::format_err!("{:?}", e))?;
+        }
+        if let Some(partition_table) = self.partition_table {
+            partition_table
+                .persist_if_uninitialized()
+                .map_err(|e| anyhow::format_err!("{:?}", e))?;
+        }
+
+        let mut result = LedgerMap {
+            metadata: RefCell::new(Metadata::new()),
+            labels_to_index: self.labels_to_index.map(AHashSet::from_iter),
+            entries: IndexMap::new(),
+            lazy_entries: IndexMap::new(),
+            label_index_kinds: self.label_index_kinds,
+            next_block_entries: IndexMap::new(),
+            secondary_indexes: IndexMap::new(),
+            live_state: LiveState::default(),
+            label_stats: LabelStatsIndex::default(),
+            label_blocks: BTreeMap::new(),
+            clock: self.clock,
+            anchor: self.anchor,
+            checkpoint_signers: self.checkpoint_signers,
+            access_controller: self.access_controller,
+            principal_source: self.principal_source,
+            label_policies: BTreeMap::new(),
+            value_refs: BTreeMap::new(),
+            blobs: BTreeMap::new(),
+            blob_ref_counts: BTreeMap::new(),
+            cold_storage: self.cold_storage,
+            #[cfg(feature = "encryption")]
+            encryption_keys: self.encryption_keys,
+            storage_quota_bytes: self.storage_quota_bytes,
+            max_key_size_bytes: self.max_key_size_bytes,
+            max_value_size_bytes: self.max_value_size_bytes,
+            chunk_size_bytes: self.chunk_size_bytes,
+            timestamp_skew: self.timestamp_skew,
+            ledger_info: None,
+            block_version: self.block_version,
+            entry_hashes: self.entry_hashes,
+            deterministic: self.deterministic,
+            monotonic_timestamps: self.monotonic_timestamps,
+            reject_non_monotonic_timestamps: self.reject_non_monotonic_timestamps,
+            lazy_index: self.lazy_index,
+            durability: self.durability,
+            auto_commit_entries: self.auto_commit_entries,
+            data_partition: self.data_partition,
+            data_partition_bounds: (0, None),
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            storage_backend: platform_specific::active_storage_backend()
+                .map_err(anyhow::Error::msg)?,
+            #[cfg(all(target_arch = "wasm32", feature = "ic"))]
+            certified_index: Default::default(),
+        };
+        result._replay_compaction_journal()?;
+        result.refresh_ledger()?;
+        if let Some(metadata) = self.genesis_metadata {
+            if result.get_blocks_count() == 0 {
+                result._commit_genesis_block(metadata)?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Generate a random (version 4) UUID as the ledger identifier. In deterministic mode it is
+/// derived from the creation time instead, so that replicas create the same ledger.
+fn generate_ledger_id(created_at_ns: u64, deterministic: bool) -> Result<String, LedgerError> {
+    let mut bytes = [0u8; 16];
+    if deterministic {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(created_at_ns.to_le_bytes());
+        bytes.copy_from_slice(&hasher.finalize()[..16]);
+    } else {
+        crate::platform_specific::fill_random(&mut bytes).map_err(LedgerError::Other)?;
+    }
+    // Mark as a version 4 (random), variant 1 UUID
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+/// Writer that buffers data and flushes it to persistent storage in chunks of
+/// `PERSISTENT_STORAGE_WRITE_CHUNK_SIZE` bytes, starting at the given offset.
+/// Once a chunk would end past the optional limit, nothing more is written to persistent
+/// storage, but the bytes are still counted.
+struct PersistentStorageWriter {
+    offset: u64,
+    bytes_written: u64,
+    buf: Vec<u8>,
+    limit: Option<u64>,
+    limit_exceeded: bool,
+}
+
+const PERSISTENT_STORAGE_WRITE_CHUNK_SIZE: usize = 64 * 1024;
+/// Maximum number of bytes of blocks that `LedgerMap::_compact_blocks` moves in one step, which
+/// is also about the storage that it needs after the end of the ledger.
+const COMPACTION_STEP_BYTES: usize = 1024 * 1024;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const CLONE_CHUNK_SIZE_BYTES: u64 = 16 * 1024 * 1024;
+
+impl PersistentStorageWriter {
+    fn new(offset: u64) -> Self {
+        PersistentStorageWriter {
+            offset,
+            bytes_written: 0,
+            buf: Vec::with_capacity(PERSISTENT_STORAGE_WRITE_CHUNK_SIZE),
+            limit: None,
+            limit_exceeded: false,
+        }
+    }
+
+    /// Do not write past the offset `limit`, if any.
+    fn with_limit(mut self, limit: Option<u64>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Flush any remaining buffered data and return the total number of bytes written to the
+    /// writer, including any bytes past the limit.
+    fn finish(mut self) -> std::io::Result<u64> {
+        std::io::Write::flush(&mut self)?;
+        Ok(self.bytes_written)
+    }
+}
+
+impl std::io::Write for PersistentStorageWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= PERSISTENT_STORAGE_WRITE_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            let end = self.offset + self.bytes_written + self.buf.len() as u64;
+            self.limit_exceeded |= self.limit.is_some_and(|limit| end > limit);
+            if !self.limit_exceeded {
+                persistent_storage_write(self.offset + self.bytes_written, &self.buf)?;
+            }
+            self.bytes_written += self.buf.len() as u64;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 #[path = "ledger_map_tests.rs"]
 mod ledger_map_tests;
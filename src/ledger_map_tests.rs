@@ -4,8 +4,16 @@ mod tests {
 
     use crate::info;
 
-    use crate::ledger_entry::LedgerBlockHeader;
-    use crate::{partition_table, LedgerBlock, LedgerEntry, LedgerError, LedgerMap, Operation};
+    use crate::ledger_entry::{LedgerBlockHeader, LATEST_BLOCK_VERSION};
+    use crate::ledger_map::MERGE_LABEL;
+    use crate::partition_table::PartitionTable;
+    use crate::{
+        label_matches, partition_table, Anchor, AnchorPoint, Cursor, ForkStatus, LabelIndexKind,
+        LabelStats, LedgerBlock, LedgerEntry, LedgerError, LedgerMap, MergeOutcome, MergeRecord,
+        MergeResolution, MergeSide, MergeStrategy, Operation, Query, ScanHandle, Snapshot,
+        TimestampSkew,
+    };
+    use borsh::BorshDeserialize;
 
     #[cfg(not(target_arch = "wasm32"))]
     fn log_init() {
@@ -27,7 +35,7 @@ mod tests {
         // Create a temporary directory for the test
         let file_path = tempfile::tempdir()
             .unwrap()
-            .into_path()
+            .keep()
             .join("test_ledger_store.bin");
 
         fn mock_get_timestamp_nanos() -> u64 {
@@ -39,6 +47,18 @@ mod tests {
             .with_timestamp_fn(mock_get_timestamp_nanos)
     }
 
+    fn incompressible_bytes(len: usize) -> Vec<u8> {
+        let mut x = 0x2545f4914f6cdd1du64;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
     #[test]
     fn test_get_num_entries_for_label() {
         let mut ledger_map = new_temp_ledger(None);
@@ -68,9 +88,9 @@ mod tests {
 
         // Test after deleting entries
         ledger_map.delete("Label1", b"key1").unwrap();
-        assert_eq!(ledger_map.count_entries_for_label("Label1"), 4);  // Delete operation adds a tombstone entry
+        assert_eq!(ledger_map.count_entries_for_label("Label1"), 4); // Delete operation adds a tombstone entry
         ledger_map.commit_block().unwrap();
-        assert_eq!(ledger_map.count_entries_for_label("Label1"), 3);  // Tombstone remains after commit
+        assert_eq!(ledger_map.count_entries_for_label("Label1"), 3); // Tombstone remains after commit
     }
 
     #[test]
@@ -88,12 +108,11 @@ mod tests {
             0,
             vec![],
         );
-        let cumulative_hash = LedgerMap::_compute_block_chain_hash(
+        let cumulative_hash = crate::hashing::chain_hash(
             &parent_hash,
             ledger_block.entries(),
             ledger_block.timestamp(),
-        )
-        .unwrap();
+        );
 
         // Cumulative hash is a sha256 hash of the parent hash, key, and value
         // Obtained from a reference run
@@ -351,204 +370,3516 @@ mod tests {
     }
 
     #[test]
-    fn test_ledger_block_offsets() {
-        // Create a new ledger
+    fn test_commit_block_larger_than_write_chunk() {
         let mut ledger_map = new_temp_ledger(None);
 
-        // Create some dummy entries
-        ledger_map.upsert("label1", b"key1", b"value1").unwrap();
+        // Incompressible values, so the serialized block spans several write chunks
+        let value =
+            incompressible_bytes(crate::ledger_map::PERSISTENT_STORAGE_WRITE_CHUNK_SIZE * 3);
+        ledger_map.upsert("Label1", b"big", &value).unwrap();
+        ledger_map.upsert("Label1", b"small", b"value").unwrap();
         ledger_map.commit_block().unwrap();
-        ledger_map.upsert("label1a", b"key2a", b"value2aa").unwrap();
+        let chain_hash = ledger_map.get_latest_block_hash();
+
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get("Label1", b"big").unwrap(), value);
+        assert_eq!(ledger_map.get("Label1", b"small").unwrap(), b"value");
+        assert_eq!(ledger_map.get_latest_block_hash(), chain_hash);
+        assert_eq!(ledger_map.get_blocks_count(), 1);
+    }
+
+    #[test]
+    fn test_new_with_partition_table() {
+        log_init();
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let partition_table = PartitionTable::builder()
+            .data_partition_start(4096)
+            .build()
+            .unwrap();
+        let mut ledger_map =
+            LedgerMap::new_with_partition_table(None, Some(file_path.clone()), partition_table)
+                .unwrap();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
         ledger_map.commit_block().unwrap();
-        ledger_map
-            .upsert("label1bb", b"key3bbb", b"value3bbbb")
+        assert_eq!(ledger_map.get_latest_block_start_pos(), 4096);
+
+        // Reopening with the default layout still honors the persisted one
+        let ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        assert_eq!(ledger_map.get_latest_block_start_pos(), 4096);
+        assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), b"value1");
+    }
+
+    #[test]
+    fn test_relocate_data_partition() {
+        log_init();
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let partition_table = PartitionTable::builder()
+            .data_partition_start(4096)
+            .build()
             .unwrap();
+        let mut ledger_map =
+            LedgerMap::new_with_partition_table(None, Some(file_path.clone()), partition_table)
+                .unwrap();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
         ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label2", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        let chain_hash = ledger_map.get_latest_block_hash();
 
-        let (headers, blocks): (Vec<_>, Vec<_>) = ledger_map.iter_raw().map(|x| x.unwrap()).unzip();
+        // Uncommitted entries prevent relocation
+        ledger_map.upsert("Label1", b"key3", b"value3").unwrap();
+        assert!(ledger_map.relocate_data_partition(65536).is_err());
+        ledger_map.delete("Label1", b"key3").unwrap();
+        ledger_map.commit_block().unwrap();
+        let chain_hash_after_delete = ledger_map.get_latest_block_hash();
+        assert_ne!(chain_hash, chain_hash_after_delete);
+
+        // Grow the reserved region, then shrink it back
+        for new_start_lba in [65536, 8192] {
+            ledger_map.relocate_data_partition(new_start_lba).unwrap();
+            assert_eq!(
+                partition_table::get_data_partition().start_lba,
+                new_start_lba
+            );
+            assert_eq!(ledger_map.get_blocks_count(), 3);
+            assert_eq!(ledger_map.get_latest_block_hash(), chain_hash_after_delete);
+            assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), b"value1");
+            assert_eq!(ledger_map.get("Label2", b"key2").unwrap(), b"value2");
+            let (_header, block) = ledger_map.get_block_at_offset(0).unwrap();
+            assert_eq!(block.get_offset(), new_start_lba);
+        }
 
-        let header_len_bytes = headers
-            .iter()
-            .map(|x| x.serialize().unwrap().len() as u32)
-            .collect::<Vec<_>>();
-        let blocks_len_bytes = blocks
-            .iter()
-            .map(|x| x.serialize().unwrap().len() as u32)
-            .collect::<Vec<_>>();
-        let blk0_bytes = (header_len_bytes[0] + blocks_len_bytes[0]) as u64;
-        let blk1_bytes = (header_len_bytes[1] + blocks_len_bytes[1]) as u64;
-        let blk2_bytes = (header_len_bytes[2] + blocks_len_bytes[2]) as u64;
-        assert_eq!(headers[0].jump_bytes_prev_block(), 0);
-        assert_eq!(headers[0].jump_bytes_next_block(), blk0_bytes as u32);
+        // Invalid boundaries are rejected
+        assert!(ledger_map.relocate_data_partition(16).is_err());
 
-        assert_eq!(headers[1].jump_bytes_prev_block(), -(blk0_bytes as i32));
-        assert_eq!(headers[1].jump_bytes_next_block(), blk1_bytes as u32);
-        assert_eq!(headers[2].jump_bytes_prev_block(), -(blk1_bytes as i32));
-        assert_eq!(headers[2].jump_bytes_next_block(), blk2_bytes as u32);
+        // New blocks are appended after the relocated ones, and a reopen honors the new layout
+        ledger_map.upsert("Label1", b"key4", b"value4").unwrap();
+        ledger_map.commit_block().unwrap();
+        let ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 4);
+        assert_eq!(ledger_map.get("Label1", b"key4").unwrap(), b"value4");
     }
 
     #[test]
-    fn test_get_block_at_offset() {
-        // Create a new ledger
-        let mut ledger_map = new_temp_ledger(None);
-
-        // Create some entries and commit them
-        ledger_map.upsert("label1", b"key1", b"value1").unwrap();
+    fn test_new_with_segmented_path() {
+        log_init();
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let segment_size_bytes = 1024 * 1024;
+        let mut ledger_map =
+            LedgerMap::new_with_segmented_path(None, Some(file_path.clone()), segment_size_bytes)
+                .unwrap();
+
+        // Incompressible values, so that blocks span multiple segments
+        let value = incompressible_bytes(segment_size_bytes as usize * 3 / 2);
+        ledger_map.upsert("Label1", b"key1", &value).unwrap();
         ledger_map.commit_block().unwrap();
-        let first_block_pos = ledger_map.metadata.borrow().first_block_start_pos();
-        assert!(first_block_pos > 0);
-
-        ledger_map.upsert("label2", b"key2", b"value2").unwrap();
+        ledger_map.upsert("Label1", b"key2", &value).unwrap();
         ledger_map.commit_block().unwrap();
-        let second_block_pos = ledger_map.get_latest_block_start_pos();
+        assert!(ledger_map.get_next_block_start_pos() > 10 * segment_size_bytes);
 
-        // Test getting block at first position
-        let (header1, block1) = ledger_map.get_block_at_offset(0).unwrap();
-        assert_eq!(block1.entries().len(), 1);
-        assert_eq!(block1.entries()[0].label(), "label1");
-        assert_eq!(block1.entries()[0].key(), b"key1");
-        assert_eq!(block1.entries()[0].value(), b"value1");
-        assert_eq!(header1.jump_bytes_prev_block(), 0);
-        assert!(header1.jump_bytes_next_block() > 0);
+        let ledger_map =
+            LedgerMap::new_with_segmented_path(None, Some(file_path), segment_size_bytes).unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 2);
+        assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), value);
+        assert_eq!(ledger_map.get("Label1", b"key2").unwrap(), value);
+    }
 
-        // Test getting block at second position
-        let (header2, block2) = ledger_map.get_block_at_offset(second_block_pos).unwrap();
-        assert_eq!(block2.entries().len(), 1);
-        assert_eq!(block2.entries()[0].label(), "label2");
-        assert_eq!(block2.entries()[0].key(), b"key2");
-        assert_eq!(block2.entries()[0].value(), b"value2");
-        assert!(header2.jump_bytes_prev_block() < 0); // Should point back to previous block
+    #[test]
+    fn test_new_in_memory() {
+        let mut ledger_map = LedgerMap::new_in_memory(None).unwrap();
+        assert_eq!(crate::platform_specific::get_backing_file_path(), None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 1);
+        assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), b"value1");
 
-        // Test getting block at invalid position (before first block)
-        let result = ledger_map.get_block_at_offset(0);
-        assert!(result.is_ok()); // Should return first block instead of error
-        let (header, block) = result.unwrap();
-        assert_eq!(block.entries()[0].label(), "label1"); // Should get first block
-        assert_eq!(header.jump_bytes_prev_block(), 0);
+        // Each in-memory ledger starts empty
+        let ledger_map = LedgerMap::new_in_memory(None).unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 0);
+    }
 
-        // Test getting block at non-existent position
-        let invalid_pos = second_block_pos + 1000;
-        let result = ledger_map.get_block_at_offset(invalid_pos);
-        assert!(result.is_err());
+    #[test]
+    fn test_in_memory_ledgers_on_one_thread() {
+        let mut first = LedgerMap::new_in_memory(None).unwrap();
+        let mut second = LedgerMap::new_in_memory(None).unwrap();
+
+        // Interleaved commits go to the storage of each ledger
+        first.upsert("Label1", b"key1", b"first").unwrap();
+        first.commit_block().unwrap();
+        second.upsert("Label1", b"key1", b"second").unwrap();
+        second.commit_block().unwrap();
+        first.upsert("Label1", b"key2", b"first").unwrap();
+        first.commit_block().unwrap();
+
+        first.refresh_ledger().unwrap();
+        second.refresh_ledger().unwrap();
+        assert_eq!(first.get_blocks_count(), 2);
+        assert_eq!(second.get_blocks_count(), 1);
+        assert_eq!(first.get("Label1", b"key1").unwrap(), b"first");
+        assert_eq!(second.get("Label1", b"key1").unwrap(), b"second");
+        assert_eq!(
+            second.get("Label1", b"key2"),
+            Err(LedgerError::EntryNotFound)
+        );
+        first.verify_chain().unwrap();
+        second.verify_chain().unwrap();
+        assert_eq!(first.iter_raw().count(), 2);
+        assert_eq!(second.iter_raw().count(), 1);
     }
+
     #[test]
-    fn test_get_block_from_slice() {
-        // Create a new ledger
-        let ledger_map = new_temp_ledger(None);
+    fn test_storage_stats() {
+        let mut ledger_map = new_temp_ledger(None);
 
-        // Create test data: a valid block header and block
-        let entries = vec![
-            LedgerEntry::new("test_label", b"key1", b"value1", Operation::Upsert),
-            LedgerEntry::new("test_label", b"key2", b"value2", Operation::Upsert),
-        ];
-        let timestamp = 12345u64;
-        let parent_hash = vec![1, 2, 3, 4];
-        let block = LedgerBlock::new(entries, timestamp, parent_hash.clone());
+        let stats = ledger_map.storage_stats().unwrap();
+        assert_eq!(stats.blocks_bytes, 0);
+        assert!(stats.label_live_bytes.is_empty());
 
-        // Serialize the block
-        let block_data = block.serialize().unwrap();
-        let block_len = block_data.len() as u32;
+        // Entry bytes are label + key + value lengths, e.g. 2 + 2 + 2 bytes for ("L1", "k1", "v1")
+        ledger_map.upsert("L1", b"k1", b"v1").unwrap();
+        ledger_map.upsert("L1", b"k2", b"v2").unwrap();
+        ledger_map.upsert("L2", b"k1", b"v1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("L1", b"k1", b"v3").unwrap();
+        ledger_map.delete("L1", b"k2").unwrap();
+        ledger_map.commit_block().unwrap();
 
-        // Create a header
-        let header = LedgerBlockHeader::new(0, block_len + LedgerBlockHeader::sizeof() as u32);
-        let header_data = header.serialize().unwrap();
+        let stats = ledger_map.storage_stats().unwrap();
+        assert_eq!(
+            stats.blocks_bytes,
+            ledger_map.get_next_block_start_pos() - partition_table::get_data_partition().start_lba
+        );
+        assert!(stats.storage_bytes >= ledger_map.get_next_block_start_pos());
+        assert_eq!(stats.label_live_bytes.get("L1"), Some(&6));
+        assert_eq!(stats.label_live_bytes.get("L2"), Some(&6));
+        assert_eq!(stats.tombstone_bytes, 4);
+        // 28 bytes in total, 12 bytes live
+        assert_eq!(stats.reclaimable_bytes, 16);
+
+        // Same stats after re-reading the ledger from the persistent storage
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.storage_stats().unwrap(), stats);
+    }
 
-        // Combine header and block data
-        let mut test_data = header_data;
-        test_data.extend_from_slice(&block_data);
+    #[test]
+    fn test_storage_quota() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
 
-        // Test successful parsing
-        let result = ledger_map.get_block_from_slice(&test_data);
+        let quota_bytes = ledger_map.get_next_block_start_pos() + 10;
+        ledger_map.set_storage_quota(Some(quota_bytes));
+        assert_eq!(ledger_map.get_storage_quota(), Some(quota_bytes));
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        let err = ledger_map.commit_block().unwrap_err();
+        match err.downcast_ref::<LedgerError>() {
+            Some(LedgerError::QuotaExceeded {
+                quota_bytes: quota,
+                required_bytes,
+            }) => {
+                assert_eq!(*quota, quota_bytes);
+                assert!(*required_bytes > quota_bytes);
+            }
+            _ => panic!("Unexpected error: {:?}", err),
+        }
 
-        let (parsed_header, parsed_block, block_hash) = result.unwrap();
-        assert_eq!(parsed_header.block_version(), 1);
+        // Nothing was committed, and the entry is still pending
+        assert_eq!(ledger_map.get_blocks_count(), 1);
+        assert_eq!(ledger_map.get_next_block_entries_count(None), 1);
+        assert_eq!(ledger_map.get("Label1", b"key2").unwrap(), b"value2");
+        assert_eq!(ledger_map.iter(Some("Label1")).count(), 1);
+
+        ledger_map.set_storage_quota(None);
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 2);
+        assert_eq!(ledger_map.iter(Some("Label1")).count(), 2);
+    }
+
+    #[test]
+    fn test_key_and_value_size_limits() {
+        let mut ledger_map = new_temp_ledger(None);
         assert_eq!(
-            parsed_header.jump_bytes_next_block(),
-            block_len + LedgerBlockHeader::sizeof() as u32
+            ledger_map.get_max_key_size(),
+            crate::ledger_map::DEFAULT_MAX_KEY_SIZE_BYTES
         );
-        assert_eq!(parsed_block.entries().len(), 2);
-        assert_eq!(parsed_block.timestamp(), timestamp);
-        assert_eq!(parsed_block.parent_hash(), parent_hash);
         assert_eq!(
-            block_hash,
-            // Reference block hash value, manually checked
-            vec![
-                177, 198, 241, 134, 225, 141, 141, 22, 23, 248, 31, 12, 28, 136, 225, 4, 153, 216,
-                221, 225, 99, 159, 155, 79, 183, 71, 23, 177, 109, 79, 233, 34
-            ]
+            ledger_map.get_max_value_size(),
+            crate::ledger_map::DEFAULT_MAX_VALUE_SIZE_BYTES
         );
 
-        // Test with insufficient data (truncated block)
-        let truncated_data = test_data[..test_data.len() - 10].to_vec();
-        let result = ledger_map.get_block_from_slice(&truncated_data);
-        assert!(result.is_err());
-        match result.unwrap_err() {
-            LedgerError::BlockCorrupted(_) => {} // Expected error
-            err => panic!("Unexpected error: {:?}", err),
-        }
+        ledger_map.set_max_key_size(4);
+        ledger_map.set_max_value_size(6);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        assert_eq!(
+            ledger_map.upsert("Label1", b"key10", b"value1"),
+            Err(LedgerError::KeyTooLarge {
+                size_bytes: 5,
+                max_size_bytes: 4
+            })
+        );
+        assert_eq!(
+            ledger_map.upsert("Label1", b"key2", b"value10"),
+            Err(LedgerError::ValueTooLarge {
+                size_bytes: 7,
+                max_size_bytes: 6
+            })
+        );
+        assert_eq!(
+            ledger_map.delete("Label1", b"key10"),
+            Err(LedgerError::KeyTooLarge {
+                size_bytes: 5,
+                max_size_bytes: 4
+            })
+        );
+        assert_eq!(ledger_map.get_next_block_entries_count(None), 1);
+        ledger_map.delete("Label1", b"key1").unwrap();
+        assert_eq!(ledger_map.get_next_block_entries_count(None), 0);
+    }
 
-        // Test with corrupted header (invalid version)
-        let mut corrupted_data = test_data.clone();
-        corrupted_data[0] = 99; // Set invalid version
-        let result = ledger_map.get_block_from_slice(&corrupted_data);
-        assert!(result.is_err());
+    #[test]
+    fn test_label_validation() {
+        let mut ledger_map = new_temp_ledger(None);
+        let invalid_labels = [
+            String::new(),
+            "L".repeat(crate::ledger_map::MAX_LABEL_SIZE_BYTES + 1),
+            "Label\n1".to_string(),
+            "Label\u{0}1".to_string(),
+            format!("{}checkpoint", crate::ledger_map::RESERVED_LABEL_PREFIX),
+            "users//eu".to_string(),
+            "users/".to_string(),
+            "/users".to_string(),
+        ];
+        for label in invalid_labels.iter() {
+            match ledger_map.upsert(label, b"key1", b"value1") {
+                Err(LedgerError::InvalidLabel(_)) => {}
+                result => panic!("Unexpected result for label {:?}: {:?}", label, result),
+            }
+            match ledger_map.delete(label, b"key1") {
+                Err(LedgerError::InvalidLabel(_)) => {}
+                result => panic!("Unexpected result for label {:?}: {:?}", label, result),
+            }
+        }
+        assert_eq!(ledger_map.get_next_block_entries_count(None), 0);
 
-        // Test with empty data
-        let result = ledger_map.get_block_from_slice(&[]);
-        assert!(result.is_err());
+        ledger_map
+            .upsert(
+                "L".repeat(crate::ledger_map::MAX_LABEL_SIZE_BYTES),
+                b"key1",
+                b"value1",
+            )
+            .unwrap();
+        ledger_map
+            .upsert("Ünïcode/label 1", b"key1", b"value1")
+            .unwrap();
+        ledger_map.upsert("ledger/__", b"key1", b"value1").unwrap();
+        assert_eq!(ledger_map.get_next_block_entries_count(None), 3);
 
-        // Test with data too small for header
-        let result = ledger_map.get_block_from_slice(&[0, 1, 2]);
-        assert!(result.is_err());
+        // A label with empty segments, committed before they were rejected, can still be used
+        ledger_map
+            ._insert_entry_into_next_block("users//eu", b"key1", b"value1", Operation::Upsert)
+            .unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("users//eu", b"key2", b"value2").unwrap();
+        ledger_map.delete("users//eu", b"key1").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get("users//eu", b"key2").unwrap(), b"value2");
+        assert!(ledger_map.upsert("users//us", b"key1", b"value1").is_err());
     }
 
     #[test]
-    fn test_iter_raw_from_slice() {
-        // Create a new ledger
-        let ledger_map = new_temp_ledger(None);
-
-        // Create multiple blocks
-        let blocks_count = 3;
-        let mut test_data = Vec::new();
+    fn test_refresh_hash_mismatch() {
+        let mut ledger_map = new_temp_ledger(None);
+        let entry = LedgerEntry::new("Label1", b"key1", b"value1", Operation::Upsert);
+        let block = LedgerBlock::new(vec![entry], 0, vec![1u8; 32]);
+        ledger_map._persist_block(block).unwrap();
 
-        let mut offset = 0u32;
-        // Create and serialize multiple blocks
-        for i in 0..blocks_count {
-            let entries = vec![LedgerEntry::new(
-                format!("label{}", i),
-                format!("key{}", i).as_bytes(),
-                format!("value{}", i).as_bytes(),
-                Operation::Upsert,
-            )];
-            let timestamp = (i as u64) * 1000;
-            let parent_hash = vec![i as u8; 4];
-            let block = LedgerBlock::new(entries, timestamp, parent_hash);
+        let err = ledger_map.refresh_ledger().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LedgerError>(),
+            Some(&LedgerError::HashMismatch {
+                expected: vec![],
+                actual: vec![1u8; 32],
+                offset: partition_table::get_data_partition().start_lba,
+            })
+        );
+    }
 
-            let block_data = block.serialize().unwrap();
-            let block_len = block_data.len() as u32;
-            let jump = block_len + LedgerBlockHeader::sizeof() as u32;
+    #[test]
+    fn test_block_header_too_short() {
+        assert_eq!(
+            LedgerBlockHeader::deserialize(&[1, 0, 0, 0, 0, 0]),
+            Err(LedgerError::BlockCorrupted(
+                "Block header too short".to_string()
+            ))
+        );
+    }
 
-            let header = LedgerBlockHeader::new(jump as i32 - offset as i32, jump);
-            offset += jump;
-            let header_data = header.serialize().unwrap();
+    #[test]
+    fn test_ledger_map_builder() {
+        log_init();
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::builder()
+            .labels_to_index(vec!["Label1".to_string()])
+            .path(Some(file_path.clone()))
+            .timestamp_fn(|| 1234)
+            .storage_quota(16 * 1024 * 1024)
+            .max_key_size(8)
+            .max_value_size(16)
+            .build()
+            .unwrap();
+        assert_eq!(ledger_map.get_file_path(), Some(file_path.clone()));
+        assert_eq!(ledger_map.get_storage_quota(), Some(16 * 1024 * 1024));
+        assert_eq!(ledger_map.get_max_key_size(), 8);
+        assert_eq!(ledger_map.get_max_value_size(), 16);
 
-            test_data.extend_from_slice(&header_data);
-            test_data.extend_from_slice(&block_data);
-        }
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label2", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get_latest_block_timestamp_ns(), 1234);
+        assert_eq!(ledger_map.iter(Some("Label1")).count(), 1);
+        assert_eq!(ledger_map.iter(Some("Label2")).count(), 0);
 
-        // Test iterating through all blocks
-        let blocks: Vec<_> = ledger_map
-            .iter_raw_from_slice(&test_data)
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
+        let ledger_map = LedgerMap::builder().path(Some(file_path)).build().unwrap();
+        assert_eq!(ledger_map.get("Label2", b"key2").unwrap(), b"value2");
+    }
 
-        assert_eq!(blocks.len(), blocks_count);
+    #[test]
+    fn test_builder_hash_algorithm_durability_and_auto_commit() {
+        let err = LedgerMap::builder()
+            .in_memory()
+            .hash_algorithm("blake3")
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("Unsupported hash algorithm"));
 
-        // Reference block hashes, from a good run
-        let expected_block_hashes = vec![
-            vec![
-                59, 212, 243, 209, 119, 48, 119, 30, 19, 102, 137, 70, 162, 25, 101, 154, 229, 58,
-                186, 226, 164, 114, 252, 88, 255, 180, 170, 221, 196, 0, 141, 101,
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(file_path))
+            .hash_algorithm(crate::HASH_ALGORITHM)
+            .durability(crate::Durability::Sync)
+            .auto_commit(3)
+            .build()
+            .unwrap();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 0);
+        // Writes to a key that is already in the next block replace its entry
+        ledger_map.upsert("Label1", b"key2", b"value3").unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 0);
+        ledger_map.delete("Label1", b"key0").unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 1);
+        assert_eq!(ledger_map.next_block_iter(None).count(), 0);
+        ledger_map
+            .upsert_many("Label1", [(b"key3", b"value3"), (b"key4", b"value4")])
+            .unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 1);
+        ledger_map.delete_many("Label1", &[b"key2"]).unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 2);
+        assert_eq!(ledger_map.iter(Some("Label1")).count(), 3);
+    }
+
+    #[test]
+    fn test_injected_clock() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        let now = Arc::new(AtomicU64::new(1000));
+        let clock_now = now.clone();
+        let mut ledger_map =
+            new_temp_ledger(None).with_clock(move || clock_now.load(Ordering::SeqCst));
+
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get_latest_block_timestamp_ns(), 1000);
+
+        now.store(2000, Ordering::SeqCst);
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get_latest_block_timestamp_ns(), 2000);
+    }
+
+    #[test]
+    fn test_genesis_block() {
+        log_init();
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let metadata = std::collections::BTreeMap::from([("app".to_string(), b"test".to_vec())]);
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(file_path.clone()))
+            .timestamp_fn(|| 1234)
+            .genesis(metadata.clone())
+            .build()
+            .unwrap();
+        let ledger_info = ledger_map.ledger_info().unwrap().clone();
+        assert_eq!(ledger_info.created_at_ns, 1234);
+        assert_eq!(ledger_info.format_version, 1);
+        assert_eq!(ledger_info.hash_algorithm, "sha256");
+        assert_eq!(ledger_info.metadata, metadata);
+        assert_eq!(ledger_info.ledger_id.len(), 36);
+        // A random (version 4, variant 1) UUID
+        assert_eq!(&ledger_info.ledger_id[14..15], "4");
+        assert!("89ab".contains(&ledger_info.ledger_id[19..20]));
+        assert_eq!(ledger_map.get_blocks_count(), 1);
+        // The genesis entry is not visible to users
+        assert_eq!(ledger_map.iter(None).count(), 0);
+
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        // Reopening does not write another genesis block, and keeps the ledger identity
+        let ledger_map = LedgerMap::builder()
+            .path(Some(file_path))
+            .genesis(Default::default())
+            .build()
+            .unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 2);
+        assert_eq!(ledger_map.ledger_info(), Some(&ledger_info));
+        assert_eq!(ledger_map.iter(None).count(), 1);
+
+        // Ledger identifiers are unique
+        let other_ledger_map = LedgerMap::builder()
+            .path(Some(
+                tempfile::tempdir()
+                    .unwrap()
+                    .keep()
+                    .join("test_ledger_store.bin"),
+            ))
+            .timestamp_fn(|| 1234)
+            .genesis(Default::default())
+            .build()
+            .unwrap();
+        assert_ne!(
+            other_ledger_map.ledger_info().unwrap().ledger_id,
+            ledger_info.ledger_id
+        );
+        assert_eq!(new_temp_ledger(None).ledger_info(), None);
+    }
+
+    #[test]
+    fn test_block_version_2() {
+        log_init();
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path.clone())).unwrap();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        // Append version 2 blocks to a ledger with version 1 blocks
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(file_path.clone()))
+            .block_version(2)
+            .build()
+            .unwrap();
+        assert_eq!(ledger_map.get_block_version(), 2);
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        let versions = ledger_map
+            .iter_raw()
+            .map(|block| {
+                let (header, block) = block.unwrap();
+                assert_eq!(header.block_version(), block.version());
+                block.version()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(versions, vec![1, 2]);
+        assert_eq!(ledger_map.get("Label1", b"key2").unwrap(), b"value2");
+
+        assert!(LedgerMap::builder()
+            .block_version(LATEST_BLOCK_VERSION + 1)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_migrate_format() {
+        let mut ledger_map = new_temp_ledger(None);
+        for i in 0..5u32 {
+            ledger_map
+                .upsert("Label1", i.to_le_bytes(), incompressible_bytes(1000))
+                .unwrap();
+            ledger_map
+                .upsert("Label2", b"key", i.to_le_bytes())
+                .unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        let tip_hash = ledger_map.get_latest_block_hash();
+        let entries = ledger_map.iter(None).cloned().collect::<Vec<_>>();
+        assert!(ledger_map.migrate_format(1).is_err());
+
+        ledger_map.migrate_format(2).unwrap();
+        assert_eq!(ledger_map.get_block_version(), 2);
+        assert_eq!(ledger_map.get_blocks_count(), 5);
+        assert_eq!(ledger_map.get_latest_block_hash(), tip_hash);
+        assert_eq!(ledger_map.iter(None).cloned().collect::<Vec<_>>(), entries);
+        let blocks = ledger_map
+            .iter_raw()
+            .map(|block| block.unwrap().1)
+            .collect::<Vec<_>>();
+        assert!(blocks.iter().all(|block| block.version() == 2));
+        assert_eq!(
+            blocks[4].field(crate::ledger_entry::BLOCK_FIELD_MIGRATION_TIP_HASH),
+            Some(tip_hash.as_slice())
+        );
+
+        // New blocks are appended in the new format
+        ledger_map.upsert("Label2", b"key", b"value").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 6);
+        assert_eq!(ledger_map.get("Label2", b"key").unwrap(), b"value");
+        assert_eq!(
+            ledger_map.iter_raw().last().unwrap().unwrap().1.version(),
+            2
+        );
+
+        // The new format is kept when the ledger is opened again
+        let file_path = ledger_map.get_file_path().unwrap();
+        drop(ledger_map);
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        assert_eq!(ledger_map.get_block_version(), 2);
+        ledger_map.upsert("Label2", b"key", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(
+            ledger_map.iter_raw().last().unwrap().unwrap().1.version(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let mut ledger_map = new_temp_ledger(None);
+        assert_eq!(ledger_map.verify_chain().unwrap(), Vec::<u8>::new());
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(
+            ledger_map.verify_chain().unwrap(),
+            ledger_map.get_latest_block_hash()
+        );
+
+        let tip_hash = ledger_map.get_latest_block_hash();
+        let tip_offset = ledger_map.get_next_block_start_pos();
+        let entry = LedgerEntry::new("Label1", b"key3", b"value3", Operation::Upsert);
+        let block = LedgerBlock::new(vec![entry], 0, vec![1u8; 32]);
+        ledger_map._persist_block(block).unwrap();
+        let err = ledger_map.verify_chain().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LedgerError>(),
+            Some(&LedgerError::HashMismatch {
+                expected: tip_hash,
+                actual: vec![1u8; 32],
+                offset: tip_offset,
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_range() {
+        let mut ledger_map = new_temp_ledger(None);
+        for i in 0..3u8 {
+            ledger_map.upsert("Label1", [i], b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        ledger_map.verify_range(0, 3).unwrap();
+        ledger_map.verify_range(1, 2).unwrap();
+        ledger_map.verify_range(2, 2).unwrap();
+        assert!(ledger_map.verify_range(2, 4).is_err());
+        assert!(ledger_map.verify_range(2, 1).is_err());
+
+        // Rewrite the last block with other entries: it still links to its parent, so only
+        // the chain hash comparison catches it
+        let block_start_pos = ledger_map.get_latest_block_start_pos();
+        let entry = LedgerEntry::new("Label1", b"key", b"tampered", Operation::Upsert);
+        let block = LedgerBlock::new(vec![entry], 0, ledger_map.get_chain_hash_at(1).unwrap());
+        let jump_bytes_prev_block = (ledger_map.metadata.borrow().block_start_pos(1).unwrap()
+            as i64
+            - block_start_pos as i64) as i32;
+        ledger_map
+            ._write_block(block_start_pos, jump_bytes_prev_block, &block, None)
+            .unwrap();
+        ledger_map.verify_range(0, 2).unwrap();
+        let err = ledger_map.verify_range(1, 3).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LedgerError>(),
+            Some(LedgerError::BlockCorrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_and_append_raw_blocks() {
+        let mut source = new_temp_ledger(None);
+        for i in 0..3u8 {
+            source.upsert("Label1", [i], b"value").unwrap();
+            source.commit_block().unwrap();
+        }
+        let data_start = partition_table::get_data_partition().start_lba;
+        let all_blocks = source.read_raw_blocks(data_start, u64::MAX).unwrap();
+        assert_eq!(
+            all_blocks.len() as u64,
+            source.get_next_block_start_pos() - data_start
+        );
+        // At least one block is returned, even if it does not fit
+        let first_block = source.read_raw_blocks(data_start, 1).unwrap();
+        assert!(!first_block.is_empty() && first_block.len() < all_blocks.len());
+        assert!(source
+            .read_raw_blocks(source.get_next_block_start_pos(), u64::MAX)
+            .unwrap()
+            .is_empty());
+        let tip_hash = source.get_latest_block_hash();
+
+        let mut replica = new_temp_ledger(None);
+        assert_eq!(replica.append_raw_blocks(&first_block).unwrap(), 1);
+        // The first block does not extend the tip anymore
+        let err = replica.append_raw_blocks(&first_block).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LedgerError>(),
+            Some(LedgerError::HashMismatch { .. })
+        ));
+        assert_eq!(
+            replica
+                .append_raw_blocks(&all_blocks[first_block.len()..])
+                .unwrap(),
+            2
+        );
+        assert_eq!(replica.get_blocks_count(), 3);
+        assert_eq!(replica.get_latest_block_hash(), tip_hash);
+        assert_eq!(replica.get("Label1", &[2]).unwrap(), b"value".to_vec());
+
+        replica.upsert("Label1", b"key", b"value").unwrap();
+        assert!(replica.append_raw_blocks(&[]).is_err());
+    }
+
+    #[test]
+    fn test_merge_from() {
+        let data_start = partition_table::get_data_partition().start_lba;
+        let mut theirs = new_temp_ledger(None);
+        theirs.upsert("Label1", b"shared", b"base").unwrap();
+        theirs.commit_block().unwrap();
+        let base_blocks = theirs.read_raw_blocks(data_start, u64::MAX).unwrap();
+        let base_hash = theirs.get_latest_block_hash();
+
+        // Their edits after the common block
+        theirs.set_clock(|| 20);
+        theirs.upsert("Label1", b"theirs_only", b"t").unwrap();
+        theirs.upsert("Label1", b"conflict_new", b"theirs").unwrap();
+        theirs.upsert("Label1", b"conflict_old", b"theirs").unwrap();
+        theirs.upsert("Label1", b"same", b"v").unwrap();
+        theirs.commit_block().unwrap();
+        let their_blocks = theirs.read_raw_blocks(data_start, u64::MAX).unwrap();
+        let their_tip_hash = theirs.get_latest_block_hash();
+
+        // A replica of the common block is a fast-forward, and then up to date
+        let mut replica = new_temp_ledger(None);
+        replica.append_raw_blocks(&base_blocks).unwrap();
+        assert_eq!(
+            replica
+                .merge_from(&their_blocks, MergeStrategy::LastWriterWins)
+                .unwrap(),
+            MergeOutcome::FastForward { blocks: 1 }
+        );
+        assert_eq!(replica.get_latest_block_hash(), their_tip_hash);
+        assert_eq!(
+            replica
+                .merge_from(&base_blocks, MergeStrategy::LastWriterWins)
+                .unwrap(),
+            MergeOutcome::UpToDate
+        );
+
+        // Our edits after the common block, before and after theirs
+        let mut ours = new_temp_ledger(None);
+        ours.append_raw_blocks(&base_blocks).unwrap();
+        ours.set_clock(|| 10);
+        ours.upsert("Label1", b"conflict_new", b"ours").unwrap();
+        ours.upsert("Label1", b"same", b"v").unwrap();
+        ours.commit_block().unwrap();
+        ours.set_clock(|| 30);
+        ours.delete("Label1", b"conflict_old").unwrap();
+        ours.upsert("Label1", b"ours_only", b"o").unwrap();
+        ours.commit_block().unwrap();
+
+        let outcome = ours
+            .merge_from(&their_blocks, MergeStrategy::LastWriterWins)
+            .unwrap();
+        let record = match outcome {
+            MergeOutcome::Merged(record) => record,
+            outcome => panic!("Unexpected merge outcome {:?}", outcome),
+        };
+        assert_eq!(record.base_hash, base_hash);
+        assert_eq!(record.theirs_tip_hash, their_tip_hash);
+        assert_eq!(record.applied_entries, 2);
+        assert_eq!(
+            record.resolutions,
+            vec![
+                MergeResolution {
+                    label: "Label1".to_string(),
+                    key: b"conflict_new".to_vec(),
+                    winner: MergeSide::Theirs,
+                },
+                MergeResolution {
+                    label: "Label1".to_string(),
+                    key: b"conflict_old".to_vec(),
+                    winner: MergeSide::Ours,
+                },
+            ]
+        );
+        assert_eq!(ours.get_blocks_count(), 4);
+        assert_eq!(ours.get("Label1", b"shared").unwrap(), b"base".to_vec());
+        assert_eq!(ours.get("Label1", b"theirs_only").unwrap(), b"t".to_vec());
+        assert_eq!(ours.get("Label1", b"ours_only").unwrap(), b"o".to_vec());
+        assert_eq!(
+            ours.get("Label1", b"conflict_new").unwrap(),
+            b"theirs".to_vec()
+        );
+        assert_eq!(
+            ours.get("Label1", b"conflict_old"),
+            Err(LedgerError::EntryNotFound)
+        );
+        // The merge record is persisted in the merge block
+        let stored_record = ours.get(MERGE_LABEL, &their_tip_hash).unwrap();
+        assert_eq!(MergeRecord::try_from_slice(&stored_record).unwrap(), record);
+
+        // A custom strategy keeps all of our entries
+        let mut ours_again = new_temp_ledger(None);
+        ours_again.append_raw_blocks(&base_blocks).unwrap();
+        ours_again
+            .upsert("Label1", b"conflict_new", b"ours")
+            .unwrap();
+        ours_again.commit_block().unwrap();
+        let outcome = ours_again
+            .merge_from(
+                &their_blocks,
+                MergeStrategy::Custom(Box::new(|_| MergeSide::Ours)),
+            )
+            .unwrap();
+        assert!(matches!(outcome, MergeOutcome::Merged(_)));
+        assert_eq!(
+            ours_again.get("Label1", b"conflict_new").unwrap(),
+            b"ours".to_vec()
+        );
+
+        ours_again.upsert("Label1", b"key", b"value").unwrap();
+        assert!(ours_again
+            .merge_from(&their_blocks, MergeStrategy::LastWriterWins)
+            .is_err());
+    }
+
+    #[test]
+    fn test_refresh_new_blocks() {
+        for lazy_index in [false, true] {
+            let mut writer = new_temp_ledger(None);
+            let file_path = writer.get_file_path().unwrap();
+            writer.upsert("Label1", b"key1", b"value1").unwrap();
+            writer.commit_block().unwrap();
+            let mut reader = LedgerMap::builder()
+                .path(Some(file_path.clone()))
+                .lazy_index(lazy_index)
+                .build()
+                .unwrap();
+            assert_eq!(reader.get("Label1", b"key1").unwrap(), b"value1");
+            assert_eq!(reader.refresh_new_blocks().unwrap(), 0);
+
+            writer.upsert("Label1", b"key1", b"value2").unwrap();
+            writer.upsert("Label2", b"key1", b"value1").unwrap();
+            writer.commit_block().unwrap();
+            writer.rename_label("Label2", "Label3").unwrap();
+            assert_eq!(reader.refresh_new_blocks().unwrap(), 2);
+            assert_eq!(reader.get("Label1", b"key1").unwrap(), b"value2");
+            assert!(reader.get("Label2", b"key1").is_err());
+            assert_eq!(reader.get("Label3", b"key1").unwrap(), b"value1");
+            assert_eq!(
+                reader.get_latest_block_hash(),
+                writer.get_latest_block_hash()
+            );
+            assert_eq!(
+                reader.get_next_block_start_pos(),
+                writer.get_next_block_start_pos()
+            );
+
+            // A rewritten chain is reloaded as a whole
+            let other_path = tempfile::tempdir().unwrap().keep().join("other.bin");
+            let mut other = LedgerMap::new_with_path(None, Some(other_path.clone())).unwrap();
+            other.upsert("Label1", b"key1", b"other").unwrap();
+            other.commit_block().unwrap();
+            drop(other);
+            drop(writer);
+            std::fs::copy(&other_path, &file_path).unwrap();
+            assert_eq!(reader.refresh_new_blocks().unwrap(), 1);
+            assert_eq!(reader.get("Label1", b"key1").unwrap(), b"other");
+            assert!(reader.get("Label3", b"key1").is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_fork() {
+        let mut ledger_map = new_temp_ledger(None);
+        assert_eq!(ledger_map.check_fork(&[], 0).unwrap(), ForkStatus::Equal);
+        let mut hashes = Vec::new();
+        for i in 0..3u8 {
+            ledger_map.upsert("Label1", [i], b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+            hashes.push(ledger_map.get_latest_block_hash());
+        }
+
+        assert_eq!(
+            ledger_map.check_fork(&hashes[2], 3).unwrap(),
+            ForkStatus::Equal
+        );
+        assert_eq!(
+            ledger_map.check_fork(&hashes[0], 1).unwrap(),
+            ForkStatus::Descendant
+        );
+        assert_eq!(
+            ledger_map.check_fork(&hashes[1], 2).unwrap(),
+            ForkStatus::Descendant
+        );
+        assert_eq!(
+            ledger_map.check_fork(&[], 0).unwrap(),
+            ForkStatus::Descendant
+        );
+        // A longer remote chain is verified with its chain hash at the local height
+        assert_eq!(
+            ledger_map.check_fork(b"other", 4).unwrap(),
+            ForkStatus::NeedsVerification
+        );
+        assert_eq!(
+            ledger_map.check_fork_at(b"other", 4, &hashes[2]).unwrap(),
+            ForkStatus::Ancestor
+        );
+        assert_eq!(
+            ledger_map.check_fork_at(b"other", 4, &hashes[1]).unwrap(),
+            ForkStatus::Fork
+        );
+        assert_eq!(
+            ledger_map.check_fork_at(&hashes[0], 1, b"ignored").unwrap(),
+            ForkStatus::Descendant
+        );
+        // A rewritten remote history
+        assert_eq!(
+            ledger_map.check_fork(&hashes[0], 2).unwrap(),
+            ForkStatus::Fork
+        );
+        assert_eq!(
+            ledger_map.check_fork(&hashes[1], 3).unwrap(),
+            ForkStatus::Fork
+        );
+    }
+
+    #[test]
+    fn test_anchor() {
+        struct RecordingAnchor(std::sync::Arc<std::sync::Mutex<Vec<AnchorPoint>>>);
+
+        impl Anchor for RecordingAnchor {
+            fn anchor(&mut self, point: &AnchorPoint) -> anyhow::Result<()> {
+                self.0.lock().unwrap().push(point.clone());
+                Ok(())
+            }
+        }
+
+        let points = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_anchor(RecordingAnchor(points.clone()), 2);
+        let mut hashes = Vec::new();
+        for i in 0..5u8 {
+            ledger_map.upsert("Label1", [i], b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+            hashes.push(ledger_map.get_latest_block_hash());
+        }
+        // Empty commits do not add blocks, and are not anchored
+        ledger_map.commit_block().unwrap();
+
+        let points = points.lock().unwrap().clone();
+        assert_eq!(
+            points
+                .iter()
+                .map(|point| (point.blocks_count, point.chain_hash.clone()))
+                .collect::<Vec<_>>(),
+            vec![(2, hashes[1].clone()), (4, hashes[3].clone())]
+        );
+        // The anchor points can be checked against the ledger
+        for point in points {
+            assert_eq!(
+                ledger_map
+                    .check_fork(&point.chain_hash, point.blocks_count)
+                    .unwrap(),
+                ForkStatus::Descendant
+            );
+        }
+    }
+
+    #[test]
+    fn test_label_matches() {
+        assert!(label_matches("users/*", "users/eu"));
+        assert!(!label_matches("users/*", "users/eu/profile"));
+        assert!(!label_matches("users/*", "users"));
+        assert!(label_matches("users/**", "users/eu/profile"));
+        assert!(label_matches("users/**", "users"));
+        assert!(label_matches("users/*/profile", "users/eu/profile"));
+        assert!(label_matches("**/profile", "users/eu/profile"));
+        assert!(label_matches("users/e*", "users/eu"));
+        assert!(!label_matches("users/e*", "users/us"));
+        assert!(label_matches("users/eu", "users/eu"));
+        assert!(!label_matches("users/eu", "users/eu2"));
+        assert!(label_matches("**/eu/**/profile", "users/eu/a/eu/b/profile"));
+        assert!(!label_matches(
+            "**/eu/**/profile",
+            "users/eu/a/eu/b/settings"
+        ));
+        assert!(label_matches("*a*b*", "xxaxxbxxab"));
+        assert!(!label_matches(
+            "*a*b*c",
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaab"
+        ));
+        assert!(label_matches("**", "users/eu"));
+    }
+
+    #[test]
+    fn test_iter_labels_matching() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("users/eu", b"alice", b"1").unwrap();
+        ledger_map.upsert("users/us", b"bob", b"2").unwrap();
+        ledger_map
+            .upsert("users/eu/profile", b"alice", b"3")
+            .unwrap();
+        ledger_map.upsert("groups/eu", b"admins", b"4").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.delete("users/us", b"bob").unwrap();
+        ledger_map.upsert("users/us", b"carol", b"5").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let values = |pattern| {
+            let mut values = ledger_map
+                .iter_labels_matching(pattern)
+                .map(|entry| entry.value().to_vec())
+                .collect::<Vec<_>>();
+            values.sort();
+            values
+        };
+        assert_eq!(values("users/*"), vec![b"1".to_vec(), b"5".to_vec()]);
+        assert_eq!(
+            values("users/**"),
+            vec![b"1".to_vec(), b"3".to_vec(), b"5".to_vec()]
+        );
+        assert_eq!(values("*/eu"), vec![b"1".to_vec(), b"4".to_vec()]);
+        assert!(values("orders/*").is_empty());
+    }
+
+    #[test]
+    fn test_rename_label() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("customers", b"key1", b"value1").unwrap();
+        ledger_map.upsert("customers", b"key2", b"value2").unwrap();
+        ledger_map.upsert("orders", b"key1", b"order1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.delete("customers", b"key2").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        assert!(ledger_map.rename_label("customers", "orders").is_err());
+        assert!(ledger_map.rename_label("customers", "customers").is_err());
+        ledger_map.rename_label("customers", "clients").unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 3);
+
+        let check = |ledger_map: &LedgerMap| {
+            assert_eq!(
+                ledger_map.get("clients", b"key1").unwrap(),
+                b"value1".to_vec()
+            );
+            assert_eq!(
+                ledger_map.get("clients", b"key2"),
+                Err(LedgerError::EntryNotFound)
+            );
+            assert_eq!(
+                ledger_map.get("customers", b"key1"),
+                Err(LedgerError::EntryNotFound)
+            );
+            let entries = ledger_map.iter(Some("clients")).collect::<Vec<_>>();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].label(), "clients");
+            assert_eq!(
+                ledger_map.get("orders", b"key1").unwrap(),
+                b"order1".to_vec()
+            );
+        };
+        check(&ledger_map);
+        ledger_map.refresh_ledger().unwrap();
+        check(&ledger_map);
+
+        // The history keeps the old label
+        ledger_map.verify_chain().unwrap();
+        let first_block = ledger_map.iter_raw().next().unwrap().unwrap().1;
+        assert!(first_block
+            .entries()
+            .iter()
+            .any(|entry| entry.label() == "customers"));
+
+        // The old label can be used again
+        ledger_map.upsert("customers", b"key3", b"value3").unwrap();
+        assert!(ledger_map.rename_label("clients", "customers2").is_err());
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.iter(Some("customers")).count(), 1);
+        assert_eq!(ledger_map.iter(Some("clients")).count(), 1);
+    }
+
+    #[test]
+    fn test_secondary_index() {
+        let mut ledger_map = new_temp_ledger(None);
+        // Values are "<owner>,<tag>,..."
+        let by_tag = |value: &[u8]| -> Vec<Vec<u8>> {
+            value
+                .split(|b| *b == b',')
+                .skip(1)
+                .map(<[u8]>::to_vec)
+                .collect()
+        };
+        ledger_map
+            .upsert("docs", b"doc1", b"alice,red,blue")
+            .unwrap();
+        ledger_map.upsert("docs", b"doc2", b"bob,red").unwrap();
+        ledger_map.commit_block().unwrap();
+        // Registering an index covers the existing entries
+        ledger_map
+            .register_index("docs", "by_tag", by_tag)
+            .unwrap();
+
+        let keys = |ledger_map: &LedgerMap, tag: &[u8]| {
+            ledger_map
+                .get_by_index("docs", "by_tag", tag)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.key().to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(&ledger_map, b"red"),
+            vec![b"doc1".to_vec(), b"doc2".to_vec()]
+        );
+        assert_eq!(keys(&ledger_map, b"blue"), vec![b"doc1".to_vec()]);
+
+        // The index is maintained on commit, and covers committed entries only
+        ledger_map.upsert("docs", b"doc1", b"alice,green").unwrap();
+        ledger_map.delete("docs", b"doc2").unwrap();
+        ledger_map.upsert("docs", b"doc3", b"carol,blue").unwrap();
+        assert_eq!(keys(&ledger_map, b"blue"), vec![b"doc1".to_vec()]);
+        ledger_map.commit_block().unwrap();
+        assert!(keys(&ledger_map, b"red").is_empty());
+        assert_eq!(keys(&ledger_map, b"blue"), vec![b"doc3".to_vec()]);
+        assert_eq!(keys(&ledger_map, b"green"), vec![b"doc1".to_vec()]);
+
+        // And rebuilt on refresh
+        ledger_map.refresh_ledger().unwrap();
+        assert!(keys(&ledger_map, b"red").is_empty());
+        assert_eq!(keys(&ledger_map, b"blue"), vec![b"doc3".to_vec()]);
+        assert_eq!(keys(&ledger_map, b"green"), vec![b"doc1".to_vec()]);
+
+        assert!(ledger_map
+            .get_by_index("docs", "by_owner", b"alice")
+            .is_err());
+    }
+
+    #[test]
+    fn test_find() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map
+            .upsert("Label1", b"key1", b"hello world")
+            .unwrap();
+        ledger_map
+            .upsert("Label1", b"key2", b"goodbye world")
+            .unwrap();
+        ledger_map
+            .upsert("Label1", b"key3", b"hello there")
+            .unwrap();
+        ledger_map
+            .upsert("Label2", b"key1", b"hello world")
+            .unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.delete("Label1", b"key3").unwrap();
+        ledger_map
+            .upsert("Label1", b"key4", b"hello again")
+            .unwrap();
+
+        let keys = |entries: Result<Vec<LedgerEntry>, LedgerError>| {
+            let mut keys = entries
+                .unwrap()
+                .iter()
+                .map(|entry| entry.key().to_vec())
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+        assert_eq!(
+            keys(ledger_map.find_value_containing("Label1", b"hello")),
+            vec![b"key1".to_vec(), b"key4".to_vec()]
+        );
+        assert_eq!(
+            keys(ledger_map.find_value_containing("Label1", b"world")),
+            vec![b"key1".to_vec(), b"key2".to_vec()]
+        );
+        assert_eq!(
+            keys(ledger_map.find_value_containing("Label1", b"")).len(),
+            3
+        );
+        assert!(ledger_map
+            .find_value_containing("Label3", b"hello")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            keys(ledger_map.find("Label1", |key, value| key.ends_with(b"2")
+                && value.len() > 5)),
+            vec![b"key2".to_vec()]
+        );
+
+        // Chunked values are searched and returned in full, not as their chunk references
+        ledger_map.commit_block().unwrap();
+        ledger_map.set_chunk_size(64);
+        let value = [b"chunk ".repeat(100), b"needle".to_vec()].concat();
+        ledger_map
+            .upsert_chunked("Label1", b"key5", value.as_slice())
+            .unwrap();
+        assert!(ledger_map.is_chunked("Label1", b"key5"));
+        let found = ledger_map
+            .find_value_containing("Label1", b"needle")
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].key(), b"key5");
+        assert_eq!(found[0].value(), value.as_slice());
+    }
+
+    #[test]
+    fn test_query() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        let now = Arc::new(AtomicU64::new(1000));
+        let clock_now = now.clone();
+        let mut ledger_map =
+            new_temp_ledger(None).with_clock(move || clock_now.load(Ordering::SeqCst));
+        ledger_map.upsert("users", b"a/1", b"alice").unwrap();
+        ledger_map.upsert("users", b"a/2", b"bob").unwrap();
+        ledger_map.upsert("users", b"b/1", b"carol").unwrap();
+        ledger_map.upsert("groups", b"a/1", b"admins").unwrap();
+        ledger_map.commit_block().unwrap();
+        now.store(2000, Ordering::SeqCst);
+        ledger_map.upsert("users", b"b/2", b"dave").unwrap();
+        ledger_map.commit_block().unwrap();
+        now.store(3000, Ordering::SeqCst);
+        ledger_map.upsert("users", b"a/1", b"alice2").unwrap();
+        ledger_map.delete("users", b"b/1").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let keys = |ledger_map: &LedgerMap, query: &Query| {
+            ledger_map
+                .query(query)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.key().to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(&ledger_map, &Query::label("users")),
+            vec![b"a/1".to_vec(), b"a/2".to_vec(), b"b/2".to_vec()]
+        );
+        assert_eq!(
+            keys(&ledger_map, &Query::label("users").key_prefix(b"a/")),
+            vec![b"a/1".to_vec(), b"a/2".to_vec()]
+        );
+        assert_eq!(
+            keys(
+                &ledger_map,
+                &Query::label("users").key_prefix(b"a/").limit(1)
+            ),
+            vec![b"a/1".to_vec()]
+        );
+        // Most recently updated first, deleted entries are skipped
+        assert_eq!(
+            keys(&ledger_map, &Query::label("users").updated_after(1000)),
+            vec![b"a/1".to_vec(), b"b/2".to_vec()]
+        );
+        assert_eq!(
+            keys(&ledger_map, &Query::label("users").updated_after(2000)),
+            vec![b"a/1".to_vec()]
+        );
+        assert!(keys(&ledger_map, &Query::label("users").updated_after(3000)).is_empty());
+        assert_eq!(
+            keys(
+                &ledger_map,
+                &Query::label("users").filter(|entry| entry.value().len() == 3)
+            ),
+            vec![b"a/2".to_vec()]
+        );
+        assert!(keys(&ledger_map, &Query::label("missing")).is_empty());
+
+        // Entries written before a rename are found under the new label
+        now.store(4000, Ordering::SeqCst);
+        ledger_map.rename_label("users", "people").unwrap();
+        assert_eq!(
+            keys(&ledger_map, &Query::label("people").updated_after(1500)),
+            vec![b"a/1".to_vec(), b"b/2".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_export_import() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label2", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.delete("Label1", b"key1").unwrap();
+        ledger_map.upsert("Label1", b"key3", b"value3").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let path = tempfile::tempdir().unwrap().keep().join("backup.lmsnap");
+        ledger_map.export_snapshot(&path, true).unwrap();
+        let snapshot = Snapshot::read_from_file(&path).unwrap();
+        assert_eq!(snapshot.blocks_count, 2);
+        assert_eq!(snapshot.tip_chain_hash, ledger_map.get_latest_block_hash());
+        assert_eq!(snapshot.partitions[1].name, b"DATA");
+        assert_eq!(
+            snapshot.index_checkpoint.as_ref().unwrap().len(),
+            ledger_map.iter(None).count()
+        );
+        assert!(ledger_map
+            .snapshot(false)
+            .unwrap()
+            .index_checkpoint
+            .is_none());
+
+        // The snapshot restores into a ledger with a different partition layout
+        let file_path = tempfile::tempdir().unwrap().keep().join("restored.bin");
+        let partition_table = PartitionTable::builder()
+            .data_partition_start(16 * 1024 * 1024)
+            .build()
+            .unwrap();
+        let mut restored = LedgerMap::builder()
+            .path(Some(file_path))
+            .partition_table(partition_table)
+            .build()
+            .unwrap();
+        restored.import_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.get_blocks_count(), 2);
+        assert_eq!(
+            restored.get_latest_block_hash(),
+            ledger_map.get_latest_block_hash()
+        );
+        assert_eq!(restored.get("Label1", b"key3").unwrap(), b"value3");
+        assert!(restored.get("Label1", b"key1").is_err());
+        // Only into an empty ledger
+        assert!(restored.import_snapshot(&snapshot).is_err());
+
+        let mut tampered = snapshot.clone();
+        tampered.tip_chain_hash = vec![0; 32];
+        let mut other = new_temp_ledger(None);
+        assert!(other.import_snapshot(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        let bytes = ledger_map.snapshot(true).unwrap().to_bytes().unwrap();
+        let tip_hash = ledger_map.get_latest_block_hash();
+
+        // Restore from bytes, replacing the blocks of another ledger
+        let mut restored = new_temp_ledger(None);
+        restored.upsert("Label2", b"other", b"value").unwrap();
+        restored.commit_block().unwrap();
+        restored.restore_snapshot(&bytes).unwrap();
+        assert_eq!(restored.get_blocks_count(), 2);
+        assert_eq!(restored.get_latest_block_hash(), tip_hash);
+        assert_eq!(restored.get("Label1", b"key2").unwrap(), b"value2");
+        assert!(restored.get("Label2", b"other").is_err());
+
+        // Restore from a file
+        let path = tempfile::tempdir().unwrap().keep().join("backup.lmsnap");
+        std::fs::write(&path, &bytes).unwrap();
+        let mut restored = new_temp_ledger(None);
+        restored.restore_snapshot(&path).unwrap();
+        assert_eq!(restored.get_latest_block_hash(), tip_hash);
+
+        // A corrupted archive is rejected before the ledger is modified
+        let mut snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        let last = snapshot.blocks.len() - 1;
+        snapshot.blocks[last] ^= 0xff;
+        let corrupted = snapshot.to_bytes().unwrap();
+        assert!(restored.restore_snapshot(&corrupted).is_err());
+        assert_eq!(restored.get_latest_block_hash(), tip_hash);
+        assert!(restored.restore_snapshot(&bytes[1..]).is_err());
+
+        // A snapshot that does not fit into the storage quota is rejected before the ledger is
+        // modified
+        let mut restored = new_temp_ledger(None);
+        restored.upsert("Label2", b"other", b"value").unwrap();
+        restored.commit_block().unwrap();
+        let other_tip_hash = restored.get_latest_block_hash();
+        restored.set_storage_quota(Some(restored.get_next_block_start_pos() + 16));
+        assert!(restored.restore_snapshot(&bytes).is_err());
+        assert_eq!(restored.get_latest_block_hash(), other_tip_hash);
+        restored.refresh_ledger().unwrap();
+        assert_eq!(restored.get("Label2", b"other").unwrap(), b"value");
+
+        // The index checkpoint must match the restored index
+        let mut snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        snapshot.index_checkpoint.as_mut().unwrap().pop();
+        let mut restored = new_temp_ledger(None);
+        assert!(restored
+            .restore_snapshot(&snapshot.to_bytes().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_clone_to() {
+        let mut ledger_map = new_temp_ledger(None);
+        for i in 0..3u8 {
+            ledger_map.upsert("Label1", [i], [i; 100]).unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        let source_path = ledger_map.get_file_path().unwrap();
+        let clone_path = tempfile::tempdir().unwrap().keep().join("clone.bin");
+        let report = ledger_map.clone_to(&clone_path).unwrap();
+        assert_eq!(report.path, clone_path);
+        assert_eq!(report.blocks_count, 3);
+        assert_eq!(report.tip_chain_hash, ledger_map.get_latest_block_hash());
+        // The source ledger keeps using its own backing file
+        assert_eq!(ledger_map.get_file_path().unwrap(), source_path);
+        ledger_map.upsert("Label1", b"key", b"value").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert!(ledger_map.clone_to(&clone_path).is_err());
+
+        let clone = LedgerMap::new_with_path(None, Some(clone_path)).unwrap();
+        assert_eq!(clone.get_blocks_count(), 3);
+        assert_eq!(clone.get("Label1", &[2]).unwrap(), vec![2; 100]);
+        assert_eq!(clone.get_latest_block_hash(), report.tip_chain_hash);
+    }
+
+    #[test]
+    fn test_prune_blocks() {
+        let new_ledger = |entry_hashes: bool| {
+            let file_path = tempfile::tempdir()
+                .unwrap()
+                .keep()
+                .join("test_ledger_store.bin");
+            let builder = LedgerMap::builder().path(Some(file_path));
+            let mut ledger_map = if entry_hashes {
+                builder.entry_hashes().build().unwrap()
+            } else {
+                builder.build().unwrap()
+            };
+            for i in 0..4u8 {
+                ledger_map
+                    .upsert("Label1", b"key1", incompressible_bytes(1000 + i as usize))
+                    .unwrap();
+                ledger_map.upsert("Label2", [i], [i; 10]).unwrap();
+                ledger_map.commit_block().unwrap();
+            }
+            ledger_map.delete("Label2", [0]).unwrap();
+            ledger_map.commit_block().unwrap();
+            ledger_map.refresh_ledger().unwrap();
+            ledger_map
+        };
+        let mut ledger_map = new_ledger(true);
+        let tip_hash = ledger_map.get_latest_block_hash();
+        let first_block_pos = ledger_map.get_data_start_pos();
+        let entries = ledger_map.iter(None).cloned().collect::<Vec<_>>();
+
+        let reclaimed = ledger_map.prune_blocks(3).unwrap();
+        assert!(reclaimed > 2000);
+        assert_eq!(ledger_map.get_blocks_count(), 5);
+        assert_eq!(ledger_map.get_latest_block_hash(), tip_hash);
+        assert_eq!(ledger_map.verify_chain().unwrap(), tip_hash);
+        assert_eq!(ledger_map.iter(None).cloned().collect::<Vec<_>>(), entries);
+        assert!(matches!(
+            ledger_map.get_block_at_offset(first_block_pos),
+            Err(LedgerError::BlockPruned { .. })
+        ));
+        assert!(ledger_map
+            .iter_raw()
+            .map(|block| block.unwrap().1.is_pruned())
+            .eq([true, true, true, false, false]));
+        // The pruned blocks keep the entries still needed for the index
+        let (_, second_block) = ledger_map.iter_raw().nth(1).unwrap().unwrap();
+        assert_eq!(second_block.entries().len(), 1);
+        assert_eq!(second_block.pruned_entry_indexes(), Some(vec![1]));
+
+        // Pruning again does not change the pruned blocks, and the ledger keeps growing
+        assert_eq!(ledger_map.prune_blocks(2).unwrap(), 0);
+        ledger_map.upsert("Label2", b"new", b"value").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 6);
+        assert_eq!(ledger_map.get("Label2", &[3]).unwrap(), vec![3; 10]);
+        assert!(ledger_map.get("Label2", &[0]).is_err());
+        assert_eq!(
+            ledger_map.get("Label1", b"key1").unwrap(),
+            incompressible_bytes(1003)
+        );
+
+        // Without entry hashes, only the blocks without needed entries are pruned
+        let mut ledger_map = new_ledger(false);
+        let tip_hash = ledger_map.get_latest_block_hash();
+        let entries = ledger_map.iter(None).cloned().collect::<Vec<_>>();
+        assert!(ledger_map.prune_blocks(3).unwrap() > 1000);
+        assert_eq!(ledger_map.verify_chain().unwrap(), tip_hash);
+        assert_eq!(ledger_map.iter(None).cloned().collect::<Vec<_>>(), entries);
+        assert!(ledger_map
+            .iter_raw()
+            .map(|block| block.unwrap().1.is_pruned())
+            .eq([true, false, false, false, false]));
+        let (_, first_block) = ledger_map.iter_raw().next().unwrap().unwrap();
+        assert!(first_block.entries().is_empty());
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get_latest_block_hash(), tip_hash);
+        assert_eq!(ledger_map.get("Label2", &[1]).unwrap(), vec![1; 10]);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption_key_rotation() {
+        use crate::encryption::{self, EncryptionKeys};
+
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let open = |encryption_keys: Option<EncryptionKeys>| {
+            let builder = LedgerMap::builder().path(Some(file_path.clone()));
+            match encryption_keys {
+                Some(encryption_keys) => builder.encryption_keys(encryption_keys).build(),
+                None => builder.build(),
+            }
+        };
+        let block_epochs = |ledger_map: &LedgerMap| {
+            let start = ledger_map.get_data_start_pos();
+            let bytes = ledger_map.read_raw_blocks(start, u64::MAX).unwrap();
+            let mut pos = 0;
+            let mut epochs = Vec::new();
+            while pos < bytes.len() {
+                let header = LedgerBlockHeader::deserialize(&bytes[pos..]).unwrap();
+                assert!(header.is_encrypted());
+                epochs.push(encryption::body_epoch(&bytes[pos + 16..]).unwrap());
+                pos += header.jump_bytes_next_block() as usize;
+            }
+            epochs
+        };
+
+        let mut ledger_map = open(Some(EncryptionKeys::new(1, [1u8; 32]))).unwrap();
+        for i in 0..2u8 {
+            ledger_map.upsert("Label1", [i], b"secret value").unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        let file_bytes = std::fs::read(&file_path).unwrap();
+        assert!(!file_bytes
+            .windows(b"secret value".len())
+            .any(|window| window == b"secret value"));
+        drop(ledger_map);
+        assert!(open(None).is_err());
+
+        // New blocks use the new key, the earlier blocks keep theirs until they are rewrapped
+        let mut ledger_map = open(Some(EncryptionKeys::new(1, [1u8; 32]))).unwrap();
+        assert_eq!(ledger_map.get("Label1", &[1]).unwrap(), b"secret value");
+        ledger_map.rotate_encryption_key(2, [2u8; 32]).unwrap();
+        assert!(ledger_map.rotate_encryption_key(1, [3u8; 32]).is_err());
+        ledger_map.upsert("Label1", [2], b"secret value").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(block_epochs(&ledger_map), vec![1, 1, 2]);
+        let tip_hash = ledger_map.get_latest_block_hash();
+
+        assert_eq!(ledger_map.rewrap().unwrap(), 2);
+        assert_eq!(block_epochs(&ledger_map), vec![2, 2, 2]);
+        assert_eq!(ledger_map.rewrap().unwrap(), 0);
+        assert_eq!(ledger_map.verify_chain().unwrap(), tip_hash);
+        drop(ledger_map);
+
+        // Only the key of the current epoch is needed after the rewrap
+        let ledger_map = open(Some(EncryptionKeys::new(2, [2u8; 32]))).unwrap();
+        assert_eq!(ledger_map.get_latest_block_hash(), tip_hash);
+        for i in 0..3u8 {
+            assert_eq!(ledger_map.get("Label1", &[i]).unwrap(), b"secret value");
+        }
+    }
+
+    #[test]
+    fn test_spill_to_cold_storage() {
+        use crate::cold_storage::DirColdStorage;
+
+        let cold_dir = tempfile::tempdir().unwrap().keep().join("cold");
+        let mut ledger_map = new_temp_ledger(None);
+        assert!(ledger_map.spill_to_cold_storage(1).is_err());
+        ledger_map.set_cold_storage(DirColdStorage::new(cold_dir.clone()));
+        let mut offsets = Vec::new();
+        for i in 0..4u8 {
+            offsets.push(ledger_map.get_next_block_start_pos());
+            ledger_map
+                .upsert("Label1", b"key1", incompressible_bytes(1000 + i as usize))
+                .unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        let blocks = offsets
+            .iter()
+            .map(|offset| ledger_map.get_block_at_offset(*offset).unwrap().1)
+            .collect::<Vec<_>>();
+        let tip_hash = ledger_map.get_latest_block_hash();
+
+        assert!(ledger_map.spill_to_cold_storage(2).unwrap() > 1000);
+        assert_eq!(ledger_map.verify_chain().unwrap(), tip_hash);
+        assert_eq!(
+            ledger_map.get("Label1", b"key1").unwrap(),
+            incompressible_bytes(1003)
+        );
+        // Blocks are fetched from the cold storage, at their new offsets
+        let first_block = ledger_map.get_block_at_offset(offsets[0]).unwrap().1;
+        assert_eq!(first_block.entries(), blocks[0].entries());
+        let second_block_offset = ledger_map
+            .iter_raw()
+            .nth(1)
+            .unwrap()
+            .unwrap()
+            .1
+            .get_offset();
+        let second_block = ledger_map
+            .get_block_at_offset(second_block_offset)
+            .unwrap()
+            .1;
+        assert_eq!(second_block.entries(), blocks[1].entries());
+        assert_eq!(second_block.get_offset(), second_block_offset);
+
+        // Without the cold storage, the pruned blocks are not available
+        std::fs::remove_dir_all(&cold_dir).unwrap();
+        assert!(matches!(
+            ledger_map.get_block_at_offset(offsets[0]),
+            Err(LedgerError::BlockPruned { .. })
+        ));
+    }
+
+    #[test]
+    fn test_prove_entry() {
+        use crate::{verify_entry_proof, ProofBlob};
+
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label1", b"key1", b"old").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label2", b"key3", b"value3").unwrap();
+        ledger_map.commit_block().unwrap();
+        let tip_hash = ledger_map.get_latest_block_hash();
+
+        let proof = ledger_map.prove_entry("Label1", b"key1").unwrap();
+        assert_eq!(proof.blocks.len(), 2);
+        let proof = ProofBlob::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+        let entry = verify_entry_proof(&proof, &tip_hash).unwrap();
+        assert_eq!(entry.value(), b"value1");
+        assert!(verify_entry_proof(&proof, &[0; 32]).is_err());
+        assert!(ledger_map.prove_entry("Label1", b"missing").is_err());
+
+        // A proof of a superseded value does not verify
+        let mut tampered = proof.clone();
+        tampered.blocks[0].entries[0] =
+            LedgerEntry::new("Label1", b"key1", b"forged", Operation::Upsert);
+        assert!(verify_entry_proof(&tampered, &tip_hash).is_err());
+        let mut stale = proof.clone();
+        stale.blocks.push(crate::proof::ProofBlock {
+            entries: vec![LedgerEntry::new("Label1", b"key1", b"", Operation::Delete)],
+            timestamp: 0,
+            entry_hashes: false,
+        });
+        assert!(verify_entry_proof(&stale, &tip_hash).is_err());
+    }
+
+    #[test]
+    fn test_prove_non_inclusion() {
+        use crate::{verify_non_inclusion_proof, NonInclusionProof};
+
+        let mut ledger_map = new_temp_ledger(None);
+        for key in [b"b", b"d", b"f"] {
+            ledger_map.upsert("Label1", key, b"value").unwrap();
+        }
+        ledger_map.commit_block().unwrap();
+        ledger_map.delete("Label1", b"d").unwrap();
+        ledger_map.upsert("Label2", b"a", b"value").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let root_1 = ledger_map.state_root_at(1).unwrap();
+        let root_2 = ledger_map.state_root_at(2).unwrap();
+        assert_ne!(root_1, root_2);
+        assert_ne!(ledger_map.state_root_at(0).unwrap(), root_1);
+        assert!(ledger_map.state_root_at(3).is_err());
+
+        // Between two keys, before the first key, after the last key, and in an empty state
+        for (label, key, height, root) in [
+            ("Label1", &b"c"[..], 1, &root_1),
+            ("Label1", b"a", 1, &root_1),
+            ("Label1", b"g", 1, &root_1),
+            ("Label1", b"d", 2, &root_2),
+            ("Label3", b"a", 2, &root_2),
+        ] {
+            let proof = ledger_map.prove_non_inclusion(label, key, height).unwrap();
+            let proof = NonInclusionProof::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+            verify_non_inclusion_proof(&proof, root).unwrap();
+            assert!(verify_non_inclusion_proof(&proof, &[0; 32]).is_err());
+        }
+        let proof = ledger_map.prove_non_inclusion("Label1", b"x", 0).unwrap();
+        verify_non_inclusion_proof(&proof, &ledger_map.state_root_at(0).unwrap()).unwrap();
+
+        // Existing keys cannot be proven absent, and forged proofs do not verify
+        assert!(ledger_map.prove_non_inclusion("Label1", b"d", 1).is_err());
+        let mut forged = ledger_map.prove_non_inclusion("Label1", b"c", 1).unwrap();
+        forged.key = b"d".to_vec();
+        verify_non_inclusion_proof(&forged, &root_1).unwrap_err();
+        let mut forged = ledger_map.prove_non_inclusion("Label1", b"a", 1).unwrap();
+        forged.upper = None;
+        verify_non_inclusion_proof(&forged, &root_1).unwrap_err();
+    }
+
+    #[test]
+    fn test_state_root() {
+        let mut ledger_map = new_temp_ledger(Some(vec!["Label1".to_string()]));
+        let empty_root = ledger_map.state_root();
+        assert_eq!(empty_root, ledger_map.state_root_at(0).unwrap());
+
+        // The root is updated on commit, and covers the labels that are not indexed
+        ledger_map.upsert("Label1", b"a", b"value1").unwrap();
+        ledger_map.upsert("Label2", b"b", b"value2").unwrap();
+        assert_eq!(ledger_map.state_root(), empty_root);
+        ledger_map.commit_block().unwrap();
+        let root = ledger_map.state_root();
+        assert_ne!(root, empty_root);
+        assert_eq!(root, ledger_map.state_root_at(1).unwrap());
+        ledger_map.upsert("Label2", b"c", b"value3").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.delete("Label2", b"c").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.state_root(), root);
+        ledger_map.rename_label("Label2", "Label3").unwrap();
+        let renamed_root = ledger_map.state_root();
+        assert_ne!(renamed_root, root);
+        assert_eq!(renamed_root, ledger_map.state_root_at(4).unwrap());
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.state_root(), renamed_root);
+        let tip_hash = ledger_map.get_latest_block_hash();
+
+        // A replica with the same live entries, after a different history, has the same root
+        let mut replica = new_temp_ledger(None);
+        replica.upsert("Label3", b"b", b"value2").unwrap();
+        replica.commit_block().unwrap();
+        replica.upsert("Label1", b"a", b"value1").unwrap();
+        replica.commit_block().unwrap();
+        assert_ne!(replica.get_latest_block_hash(), tip_hash);
+        assert_eq!(replica.state_root(), renamed_root);
+        replica.upsert("Label1", b"a", b"value4").unwrap();
+        replica.commit_block().unwrap();
+        assert_ne!(replica.state_root(), renamed_root);
+    }
+
+    #[test]
+    fn test_sync_plan() {
+        use crate::SyncPlan;
+
+        let data_start = partition_table::get_data_partition().start_lba;
+        let mut ledger_map = new_temp_ledger(None);
+        let empty_digests = ledger_map.state_digests();
+        ledger_map.upsert("Label1", b"a", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        let base_blocks = ledger_map.read_raw_blocks(data_start, u64::MAX).unwrap();
+        let base_digests = ledger_map.state_digests();
+        ledger_map.upsert("Label1", b"b", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        let digests = ledger_map.state_digests();
+        assert_eq!(ledger_map.sync_plan(&digests).unwrap(), SyncPlan::InSync);
+        assert_eq!(
+            ledger_map.sync_plan(&empty_digests).unwrap(),
+            SyncPlan::SendBlocks {
+                start: data_start,
+                end: ledger_map.get_next_block_start_pos(),
+                blocks: 2
+            }
+        );
+
+        // A peer with a prefix of the chain is sent exactly its missing blocks
+        let (start, end) = match ledger_map.sync_plan(&base_digests).unwrap() {
+            SyncPlan::SendBlocks { start, end, blocks } => {
+                assert_eq!(blocks, 1);
+                (start, end)
+            }
+            plan => panic!("Unexpected sync plan {:?}", plan),
+        };
+        let missing_blocks = ledger_map.read_raw_blocks(start, end - start).unwrap();
+        assert_eq!(missing_blocks.len() as u64, end - start);
+
+        let mut replica = new_temp_ledger(None);
+        assert_eq!(replica.sync_plan(&digests).unwrap(), SyncPlan::PeerAhead);
+        replica.append_raw_blocks(&base_blocks).unwrap();
+        replica.append_raw_blocks(&missing_blocks).unwrap();
+        assert_eq!(replica.state_digests(), digests);
+
+        // After a fork, only the labels with different live entries need to be exchanged
+        let mut fork = new_temp_ledger(None);
+        fork.append_raw_blocks(&base_blocks).unwrap();
+        fork.upsert("Label1", b"b", b"value2").unwrap();
+        fork.upsert("Label2", b"c", b"value3").unwrap();
+        fork.commit_block().unwrap();
+        assert_eq!(
+            fork.sync_plan(&digests).unwrap(),
+            SyncPlan::SendLabels(vec!["Label2".to_string()])
+        );
+        fork.delete("Label2", b"c").unwrap();
+        fork.upsert("Label1", b"d", b"value4").unwrap();
+        fork.commit_block().unwrap();
+        assert_eq!(
+            fork.sync_plan(&digests).unwrap(),
+            SyncPlan::SendLabels(vec!["Label1".to_string()])
+        );
+        fork.delete("Label1", b"d").unwrap();
+        fork.commit_block().unwrap();
+        assert_eq!(fork.sync_plan(&digests).unwrap(), SyncPlan::InSync);
+    }
+
+    #[test]
+    fn test_checkpoint_quorum() {
+        use crate::checkpoint::CheckpointSignature;
+        use crate::{Checkpoint, CheckpointSigner, SignatureVerifier, CHECKPOINT_LABEL};
+
+        // Toy signature scheme: the signature is the hash of the signer id and the message
+        struct TestSigner(Vec<u8>, bool);
+        impl CheckpointSigner for TestSigner {
+            fn signer_id(&self) -> Vec<u8> {
+                self.0.clone()
+            }
+            fn sign(&mut self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+                if !self.1 {
+                    return Err(anyhow::format_err!("Signer is offline"));
+                }
+                Ok(sha256(&[&self.0[..], message].concat()))
+            }
+        }
+        struct TestVerifier;
+        impl SignatureVerifier for TestVerifier {
+            fn verify(&self, signer_id: &[u8], message: &[u8], signature: &[u8]) -> bool {
+                sha256(&[signer_id, message].concat()) == signature
+            }
+        }
+        fn sha256(data: &[u8]) -> Vec<u8> {
+            use sha2::Digest;
+            sha2::Sha256::digest(data).to_vec()
+        }
+
+        let signer_ids = vec![b"s1".to_vec(), b"s2".to_vec(), b"s3".to_vec()];
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_checkpoint_signers(
+            vec![
+                Box::new(TestSigner(b"s1".to_vec(), true)),
+                Box::new(TestSigner(b"s2".to_vec(), true)),
+                Box::new(TestSigner(b"s3".to_vec(), false)),
+            ],
+            3,
+        );
+        for key in [b"a", b"b", b"c"] {
+            ledger_map.upsert("Label1", key, b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        // Blocks: a, b, c, checkpoint of 3 blocks
+        assert_eq!(ledger_map.get_blocks_count(), 4);
+        let checkpoints = ledger_map
+            .verify_checkpoints(&signer_ids, 2, 3, &TestVerifier)
+            .unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].blocks_count, 3);
+        assert_eq!(checkpoints[0].signatures.len(), 2);
+        let err = ledger_map
+            .verify_checkpoints(&signer_ids, 3, 3, &TestVerifier)
+            .unwrap_err();
+        assert!(err.to_string().contains("2 valid signatures"));
+        // Signatures of signers outside of the set do not count
+        assert!(ledger_map
+            .verify_checkpoints(&signer_ids[1..], 2, 3, &TestVerifier)
+            .is_err());
+        for key in [b"d", b"e"] {
+            ledger_map.upsert("Label1", key, b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        assert_eq!(ledger_map.get_blocks_count(), 7);
+        assert_eq!(
+            ledger_map
+                .verify_checkpoints(&signer_ids, 2, 3, &TestVerifier)
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // A dropped checkpoint is detected, e.g. if all signers are offline
+        let mut unsigned = new_temp_ledger(None);
+        unsigned.set_checkpoint_signers(vec![Box::new(TestSigner(b"s3".to_vec(), false))], 2);
+        for key in [b"a", b"b"] {
+            unsigned.upsert("Label1", key, b"value").unwrap();
+            unsigned.commit_block().unwrap();
+        }
+        assert_eq!(unsigned.get_blocks_count(), 2);
+        assert!(unsigned
+            .verify_checkpoints(&signer_ids, 0, 3, &TestVerifier)
+            .is_ok());
+        let err = unsigned
+            .verify_checkpoints(&signer_ids, 0, 2, &TestVerifier)
+            .unwrap_err();
+        assert!(err.to_string().contains("Missing checkpoint of block 2"));
+
+        // A checkpoint of another chain hash, e.g. forged with a single key, is rejected
+        let mut forged = new_temp_ledger(None);
+        forged.upsert("Label1", b"a", b"value").unwrap();
+        forged.commit_block().unwrap();
+        let checkpoint = Checkpoint {
+            blocks_count: 1,
+            tip_hash: vec![0; 32],
+            signatures: vec![CheckpointSignature {
+                signer_id: b"s1".to_vec(),
+                signature: sha256(&[&b"s1"[..], &Checkpoint::message(1, &[0; 32])].concat()),
+            }],
+        };
+        forged
+            ._insert_entry_into_next_block(
+                CHECKPOINT_LABEL,
+                1u64.to_le_bytes(),
+                borsh::to_vec(&checkpoint).unwrap(),
+                Operation::Upsert,
+            )
+            .unwrap();
+        forged.commit_block().unwrap();
+        let err = forged
+            .verify_checkpoints(&signer_ids, 1, 1, &TestVerifier)
+            .unwrap_err();
+        assert!(err.to_string().contains("does not match the chain"));
+    }
+
+    #[test]
+    fn test_access_controller() {
+        use crate::{AccessOperation, AccessRequest};
+        use std::sync::{Arc, Mutex};
+
+        let principal = Arc::new(Mutex::new(b"writer".to_vec()));
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_principal_source({
+            let principal = principal.clone();
+            move || principal.lock().unwrap().clone()
+        });
+        ledger_map.set_access_controller(|request: &AccessRequest| match request.operation {
+            AccessOperation::Upsert | AccessOperation::CommitBlock => {
+                request.principal == b"writer" || request.principal == b"admin"
+            }
+            AccessOperation::Delete => request.principal == b"admin",
+        });
+
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        assert!(matches!(
+            ledger_map.delete("Label1", b"key1"),
+            Err(LedgerError::Unauthorized(_))
+        ));
+        ledger_map.commit_block().unwrap();
+
+        // A reader can neither write nor commit the writes of others
+        *principal.lock().unwrap() = b"reader".to_vec();
+        let err = ledger_map.upsert("Label1", b"key2", b"value2").unwrap_err();
+        assert_eq!(err.code(), 13);
+        assert!(err.to_string().contains("upsert label \"Label1\""));
+        *principal.lock().unwrap() = b"admin".to_vec();
+        ledger_map.delete("Label1", b"key1").unwrap();
+        *principal.lock().unwrap() = b"reader".to_vec();
+        let err = ledger_map.commit_block().unwrap_err();
+        assert_eq!(crate::error_code(&err), 13);
+        // The denied block stays uncommitted, and committing an empty block is not checked
+        *principal.lock().unwrap() = b"admin".to_vec();
+        ledger_map.commit_block().unwrap();
+        *principal.lock().unwrap() = b"reader".to_vec();
+        ledger_map.commit_block().unwrap();
+
+        ledger_map.remove_access_controller();
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(
+            ledger_map.get("Label1", b"key1"),
+            Err(LedgerError::EntryNotFound)
+        );
+        assert_eq!(ledger_map.get_blocks_count(), 3);
+    }
+
+    #[test]
+    fn test_label_policies() {
+        use crate::LabelPolicy;
+        use std::sync::{Arc, Mutex};
+
+        let principal = Arc::new(Mutex::new(b"writer".to_vec()));
+        let principal_source = {
+            let principal = principal.clone();
+            move || principal.lock().unwrap().clone()
+        };
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_principal_source(principal_source.clone());
+        for label in ["config", "audit", "orders"] {
+            ledger_map.upsert(label, b"key1", b"value1").unwrap();
+        }
+        ledger_map.commit_block().unwrap();
+
+        let writers_only = LabelPolicy {
+            writers: Some(vec![b"writer".to_vec()]),
+            ..Default::default()
+        };
+        let append_only = LabelPolicy {
+            append_only: true,
+            ..Default::default()
+        };
+        ledger_map.upsert("orders", b"key2", b"value2").unwrap();
+        assert!(ledger_map
+            .set_label_policy("config", LabelPolicy::default())
+            .is_err());
+        ledger_map.commit_block().unwrap();
+        ledger_map
+            .set_label_policy(
+                "config",
+                LabelPolicy {
+                    read_only: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        ledger_map
+            .set_label_policy("audit", append_only.clone())
+            .unwrap();
+        ledger_map
+            .set_label_policy("orders", writers_only.clone())
+            .unwrap();
+        assert!(ledger_map
+            .set_label_policy("__ledger/x", append_only.clone())
+            .is_err());
+
+        let check = |ledger_map: &mut LedgerMap| {
+            *principal.lock().unwrap() = b"writer".to_vec();
+            assert!(matches!(
+                ledger_map.upsert("config", b"key1", b"value2"),
+                Err(LedgerError::Unauthorized(_))
+            ));
+            ledger_map.upsert("audit", b"key2", b"value2").unwrap();
+            assert!(matches!(
+                ledger_map.delete("audit", b"key1"),
+                Err(LedgerError::Unauthorized(_))
+            ));
+            ledger_map.delete("orders", b"key2").unwrap();
+            *principal.lock().unwrap() = b"reader".to_vec();
+            let err = ledger_map.upsert("orders", b"key3", b"value3").unwrap_err();
+            assert!(err.to_string().contains("not a writer"));
+            ledger_map.upsert("other", b"key1", b"value1").unwrap();
+            ledger_map.commit_block().unwrap();
+        };
+        check(&mut ledger_map);
+
+        // The policies are persisted in the ledger
+        assert_eq!(ledger_map.label_policy("orders"), Some(&writers_only));
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.label_policy("orders"), Some(&writers_only));
+        assert_eq!(ledger_map.label_policy("audit"), Some(&append_only));
+        check(&mut ledger_map);
+
+        ledger_map.remove_label_policy("config").unwrap();
+        assert_eq!(ledger_map.label_policy("config"), None);
+        ledger_map.upsert("config", b"key1", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get("config", b"key1").unwrap(), b"value2");
+        // Policy records are not entries of the state
+        assert!(ledger_map
+            .iter(None)
+            .all(|entry| !entry.label().starts_with("__ledger/")));
+
+        // Only the writers of a label can change its policy, or rename it
+        *principal.lock().unwrap() = b"reader".to_vec();
+        assert!(matches!(
+            ledger_map
+                .remove_label_policy("orders")
+                .unwrap_err()
+                .downcast_ref::<LedgerError>(),
+            Some(LedgerError::Unauthorized(_))
+        ));
+        assert!(ledger_map
+            .set_label_policy("orders", LabelPolicy::default())
+            .is_err());
+        assert!(ledger_map.rename_label("orders", "orders2").is_err());
+        // Renaming is a delete of the old label
+        assert!(ledger_map.rename_label("audit", "audit2").is_err());
+        assert_eq!(ledger_map.label_policy("orders"), Some(&writers_only));
+
+        // The policy moves with the label
+        *principal.lock().unwrap() = b"writer".to_vec();
+        ledger_map.rename_label("orders", "orders2").unwrap();
+        assert_eq!(ledger_map.label_policy("orders"), None);
+        assert_eq!(ledger_map.label_policy("orders2"), Some(&writers_only));
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.label_policy("orders2"), Some(&writers_only));
+        *principal.lock().unwrap() = b"reader".to_vec();
+        assert!(ledger_map.upsert("orders2", b"key3", b"value3").is_err());
+    }
+
+    #[test]
+    fn test_deterministic_mode() {
+        use crate::{Clock, CounterClock};
+        use std::collections::BTreeMap;
+
+        let build = |reverse: bool| {
+            let file_path = tempfile::tempdir()
+                .unwrap()
+                .keep()
+                .join("test_ledger_store.bin");
+            let mut ledger_map = LedgerMap::builder()
+                .path(Some(file_path))
+                .deterministic()
+                .genesis(BTreeMap::from([("name".to_string(), b"test".to_vec())]))
+                .build()
+                .unwrap();
+            let mut writes = vec![
+                ("Label2", b"key2"),
+                ("Label1", b"key1"),
+                ("Label1", b"key0"),
+            ];
+            if reverse {
+                writes.reverse();
+            }
+            for (label, key) in writes {
+                ledger_map.upsert(label, key, b"value").unwrap();
+            }
+            ledger_map.commit_block().unwrap();
+            (
+                ledger_map.get_latest_block_hash(),
+                ledger_map.get_latest_block_timestamp_ns(),
+                ledger_map.ledger_info().unwrap().ledger_id.clone(),
+            )
+        };
+        let (tip_hash, timestamp_ns, ledger_id) = build(false);
+        // Genesis timestamp, genesis block, and the block of the writes
+        assert_eq!(timestamp_ns, 2);
+        assert_eq!(build(true), (tip_hash, timestamp_ns, ledger_id));
+
+        let clock = CounterClock::new(100, 10);
+        assert_eq!(clock.now_nanos(), 100);
+        assert_eq!(clock.now_nanos(), 110);
+    }
+
+    #[test]
+    fn test_entry_hashes() {
+        use crate::hashing;
+        use crate::ledger_entry::{BlockField, BLOCK_FIELD_ENTRY_HASHES};
+        use crate::proof::{verify_entry_proof, ProofBlob};
+
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        assert!(LedgerMap::builder()
+            .entry_hashes()
+            .block_version(1)
+            .path(Some(file_path.clone()))
+            .build()
+            .is_err());
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(file_path))
+            .entry_hashes()
+            .build()
+            .unwrap();
+        assert_eq!(ledger_map.get_block_version(), 2);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label2", b"key3", b"value3").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 2);
+        ledger_map.verify_chain().unwrap();
+
+        // A single entry verifies with the entry hashes of its block
+        let (_, block) = ledger_map.iter_raw().next().unwrap().unwrap();
+        let entry_hashes = block.entry_hashes().unwrap();
+        assert_eq!(entry_hashes.len(), 2);
+        let chain_hash = ledger_map.get_chain_hash_at(0).unwrap();
+        assert_ne!(
+            chain_hash,
+            hashing::chain_hash(block.parent_hash(), block.entries(), block.timestamp())
+        );
+        for (index, entry) in block.entries().iter().enumerate() {
+            assert!(hashing::verify_entry(
+                entry,
+                index,
+                &entry_hashes,
+                block.parent_hash(),
+                block.timestamp(),
+                &chain_hash
+            ));
+        }
+
+        // Entry proofs cover blocks with entry hashes, and survive a roundtrip
+        let proof = ledger_map.prove_entry("Label1", b"key2").unwrap();
+        let proof = ProofBlob::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+        let tip_hash = ledger_map.get_latest_block_hash();
+        assert_eq!(
+            verify_entry_proof(&proof, &tip_hash).unwrap().value(),
+            b"value2"
+        );
+
+        // Entry hashes that do not match the entries are detected
+        let tampered = LedgerBlock::new_v2(
+            block.entries().to_vec(),
+            block.timestamp(),
+            block.parent_hash().to_vec(),
+            vec![BlockField::new(BLOCK_FIELD_ENTRY_HASHES, [0u8; 64])],
+        );
+        assert!(matches!(
+            hashing::block_chain_hash(&tampered),
+            Err(LedgerError::BlockCorrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_upsert_chunked() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_max_value_size(1000);
+        ledger_map.set_chunk_size(64);
+        let value = (0..3000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let blocks_count = ledger_map.get_blocks_count();
+
+        ledger_map
+            .upsert_chunked("Label1", b"artifact", value.as_slice())
+            .unwrap();
+        // 47 chunks of at most 64 bytes, each in a block of its own, then the entry
+        assert_eq!(ledger_map.get_blocks_count(), blocks_count + 48);
+        assert!(ledger_map.is_chunked("Label1", b"artifact"));
+        assert_eq!(ledger_map.get("Label1", b"artifact").unwrap(), value);
+        let chunked_value = crate::ChunkedValue::try_from_slice(
+            ledger_map.iter(Some("Label1")).next().unwrap().value(),
+        )
+        .unwrap();
+        assert_eq!(chunked_value.len, 3000);
+        assert_eq!(chunked_value.chunk_blocks.len(), 47);
+
+        // The chunking survives a reload
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get("Label1", b"artifact").unwrap(), value);
+
+        // Uncommitted entries are rejected
+        ledger_map.upsert("Label1", b"key", b"value").unwrap();
+        assert!(ledger_map
+            .upsert_chunked("Label1", b"other", value.as_slice())
+            .is_err());
+        ledger_map.commit_block().unwrap();
+
+        // A plain upsert replaces the chunked value
+        ledger_map.upsert("Label1", b"artifact", b"small").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert!(!ledger_map.is_chunked("Label1", b"artifact"));
+        assert_eq!(ledger_map.get("Label1", b"artifact").unwrap(), b"small");
+        ledger_map.refresh_ledger().unwrap();
+        assert!(!ledger_map.is_chunked("Label1", b"artifact"));
+        assert_eq!(ledger_map.get("Label1", b"artifact").unwrap(), b"small");
+    }
+
+    #[test]
+    fn test_get_reader() {
+        use std::io::Read;
+
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_chunk_size(64);
+        let value = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        ledger_map
+            .upsert_chunked("Label1", b"artifact", value.as_slice())
+            .unwrap();
+        ledger_map.upsert("Label1", b"key", b"value").unwrap();
+
+        let mut reader = ledger_map.get_reader("Label1", b"artifact").unwrap();
+        assert_eq!(reader.len(), 1000);
+        // Read in pieces that do not line up with the chunks
+        let mut read_value = Vec::new();
+        let mut buf = [0u8; 100];
+        loop {
+            let read_len = reader.read(&mut buf).unwrap();
+            if read_len == 0 {
+                break;
+            }
+            read_value.extend_from_slice(&buf[..read_len]);
+        }
+        assert_eq!(read_value, value);
+
+        // Plain values, including the uncommitted ones
+        let mut read_value = Vec::new();
+        ledger_map
+            .get_reader("Label1", b"key")
+            .unwrap()
+            .read_to_end(&mut read_value)
+            .unwrap();
+        assert_eq!(read_value, b"value");
+        assert!(matches!(
+            ledger_map.get_reader("Label1", b"missing"),
+            Err(LedgerError::EntryNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_put_writer() {
+        use std::io::Write;
+
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_chunk_size(64);
+        let value = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let blocks_count = ledger_map.get_blocks_count();
+
+        let mut writer = ledger_map.put_writer("Label1", b"artifact").unwrap();
+        for piece in value.chunks(100) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.len(), 1000);
+        writer.finish().unwrap();
+        // 16 chunks of at most 64 bytes, then the entry
+        assert_eq!(ledger_map.get_blocks_count(), blocks_count + 17);
+        assert!(ledger_map.is_chunked("Label1", b"artifact"));
+        assert_eq!(ledger_map.get("Label1", b"artifact").unwrap(), value);
+
+        // Without finish, the entry is not written
+        let mut writer = ledger_map.put_writer("Label1", b"unfinished").unwrap();
+        writer.write_all(&value).unwrap();
+        drop(writer);
+        assert_eq!(
+            ledger_map.get("Label1", b"unfinished"),
+            Err(LedgerError::EntryNotFound)
+        );
+        assert_eq!(ledger_map.get_next_block_entries_count(None), 0);
+    }
+
+    #[test]
+    fn test_blob_store() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_chunk_size(64);
+        let attachment = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let hash = ledger_map.put_blob(&attachment).unwrap();
+        assert!(ledger_map.has_blob(&hash));
+        let blocks_count = ledger_map.get_blocks_count();
+        // The same content is stored once
+        assert_eq!(ledger_map.put_blob(&attachment).unwrap(), hash);
+        assert_eq!(ledger_map.get_blocks_count(), blocks_count);
+
+        ledger_map
+            .upsert_blob_ref("Label1", b"mail1", &hash)
+            .unwrap();
+        ledger_map
+            .upsert_blob_ref("Label1", b"mail2", &hash)
+            .unwrap();
+        // Uncommitted references are resolved too
+        assert_eq!(ledger_map.get("Label1", b"mail1").unwrap(), attachment);
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), blocks_count + 1);
+        assert_eq!(ledger_map.get("Label1", b"mail2").unwrap(), attachment);
+        // The entries hold only the hash
+        for entry in ledger_map.iter(Some("Label1")) {
+            assert_eq!(entry.value(), hash);
+        }
+
+        ledger_map.refresh_ledger().unwrap();
+        assert!(ledger_map.has_blob(&hash));
+        assert_eq!(ledger_map.get("Label1", b"mail1").unwrap(), attachment);
+
+        // A plain upsert replaces the reference
+        ledger_map.upsert("Label1", b"mail1", b"plain").unwrap();
+        assert_eq!(ledger_map.get("Label1", b"mail1").unwrap(), b"plain");
+        ledger_map.commit_block().unwrap();
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get("Label1", b"mail1").unwrap(), b"plain");
+        assert_eq!(ledger_map.get("Label1", b"mail2").unwrap(), attachment);
+
+        assert_eq!(
+            ledger_map.upsert_blob_ref("Label1", b"mail3", [0u8; 32].as_slice()),
+            Err(LedgerError::EntryNotFound)
+        );
+    }
+
+    #[test]
+    fn test_gc_blobs() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.set_chunk_size(64);
+        let attachment = vec![7u8; 1000];
+        let other = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let hash = ledger_map.put_blob(&attachment).unwrap();
+        let other_hash = ledger_map.put_blob(&other).unwrap();
+        ledger_map.upsert_blob_ref("Label1", b"a", &hash).unwrap();
+        ledger_map.upsert_blob_ref("Label1", b"b", &hash).unwrap();
+        ledger_map
+            .upsert_blob_ref("Label1", b"c", &other_hash)
+            .unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.blob_ref_count(&hash), 2);
+        assert_eq!(ledger_map.blob_ref_count(&other_hash), 1);
+
+        // Nothing to collect while the blobs are referenced
+        assert_eq!(ledger_map.gc_blobs().unwrap(), 0);
+
+        ledger_map.delete("Label1", b"a").unwrap();
+        ledger_map.upsert("Label1", b"c", b"plain").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.blob_ref_count(&hash), 1);
+        assert_eq!(ledger_map.blob_ref_count(&other_hash), 0);
+
+        let tip_hash = ledger_map.get_latest_block_hash();
+        assert!(ledger_map.gc_blobs().unwrap() > 0);
+        assert!(ledger_map.has_blob(&hash));
+        assert!(!ledger_map.has_blob(&other_hash));
+        assert_eq!(ledger_map.get("Label1", b"b").unwrap(), attachment);
+        assert_eq!(ledger_map.get("Label1", b"c").unwrap(), b"plain");
+        // The chain still verifies up to the same tip, with the gc block on top
+        assert_eq!(
+            ledger_map.verify_chain().unwrap(),
+            ledger_map.get_latest_block_hash()
+        );
+        assert_eq!(
+            ledger_map
+                .check_fork(&tip_hash, ledger_map.get_blocks_count() as u64 - 1)
+                .unwrap(),
+            ForkStatus::Descendant
+        );
+
+        ledger_map.refresh_ledger().unwrap();
+        assert!(!ledger_map.has_blob(&other_hash));
+        assert_eq!(ledger_map.blob_ref_count(&hash), 1);
+        assert_eq!(ledger_map.get("Label1", b"b").unwrap(), attachment);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value2b").unwrap();
+        ledger_map.upsert("Label1", b"key3", b"value3").unwrap();
+        ledger_map.delete("Label1", b"key1").unwrap();
+
+        let keys: [&[u8]; 4] = [b"key1", b"key2", b"key3", b"missing"];
+        let values = ledger_map.get_many("Label1", &keys);
+        assert_eq!(
+            values,
+            vec![
+                Err(LedgerError::EntryNotFound),
+                Ok(b"value2b".to_vec()),
+                Ok(b"value3".to_vec()),
+                Err(LedgerError::EntryNotFound),
+            ]
+        );
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(ledger_map.get("Label1", key), value);
+        }
+        assert!(ledger_map.get_many("Label1", &[]).is_empty());
+        assert_eq!(
+            ledger_map.get_many("Label2", &keys[..1]),
+            vec![Err(LedgerError::EntryNotFound)]
+        );
+    }
+
+    #[test]
+    fn test_delete_many() {
+        let mut ledger_map = new_temp_ledger(None);
+        for key in [b"key1", b"key2", b"key3"] {
+            ledger_map.upsert("Label1", key, b"value").unwrap();
+        }
+        ledger_map.commit_block().unwrap();
+
+        ledger_map
+            .delete_many("Label1", &[b"key1", b"key2"])
+            .unwrap();
+        // The deletes are staged in the next block
+        assert_eq!(
+            ledger_map.get("Label1", b"key1"),
+            Err(LedgerError::EntryNotFound)
+        );
+        assert_eq!(ledger_map.get_blocks_count(), 1);
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get_blocks_count(), 2);
+        assert_eq!(
+            ledger_map.get("Label1", b"key1"),
+            Err(LedgerError::EntryNotFound)
+        );
+        assert_eq!(
+            ledger_map.get("Label1", b"key2"),
+            Err(LedgerError::EntryNotFound)
+        );
+        assert_eq!(ledger_map.get("Label1", b"key3").unwrap(), b"value");
+
+        // A single invalid key rejects the whole batch
+        ledger_map.set_max_key_size(4);
+        assert!(matches!(
+            ledger_map.delete_many("Label1", &[b"key3".to_vec(), b"too long".to_vec()]),
+            Err(LedgerError::KeyTooLarge { .. })
+        ));
+        assert_eq!(ledger_map.get_next_block_entries_count(None), 0);
+        assert_eq!(ledger_map.get("Label1", b"key3").unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_upsert_many() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label1", b"key0", b"old").unwrap();
+        ledger_map
+            .upsert_many(
+                "Label1",
+                (0..100u32).map(|i| (format!("key{}", i), format!("value{}", i))),
+            )
+            .unwrap();
+        assert_eq!(ledger_map.get_next_block_entries_count(Some("Label1")), 100);
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get("Label1", b"key0").unwrap(), b"value0");
+        assert_eq!(ledger_map.get("Label1", b"key99").unwrap(), b"value99");
+        assert_eq!(ledger_map.count_entries_for_label("Label1"), 100);
+
+        // Upserting replaces a chunked value
+        ledger_map.set_chunk_size(4);
+        ledger_map
+            .upsert_chunked("Label1", b"big", &b"chunked value"[..])
+            .unwrap();
+        ledger_map
+            .upsert_many("Label1", [(b"big", b"small")])
+            .unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get("Label1", b"big").unwrap(), b"small");
+
+        // A single invalid item rejects the whole batch
+        ledger_map.set_max_value_size(8);
+        assert!(matches!(
+            ledger_map.upsert_many(
+                "Label1",
+                [
+                    (b"key1".to_vec(), b"v".to_vec()),
+                    (b"key2".to_vec(), b"too long value".to_vec())
+                ]
+            ),
+            Err(LedgerError::ValueTooLarge { .. })
+        ));
+        assert_eq!(ledger_map.get_next_block_entries_count(None), 0);
+        assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), b"value1");
+    }
+
+    #[test]
+    fn test_block_summary() {
+        log_init();
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path.clone())).unwrap();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        // Append version 3 blocks to a ledger with a version 1 block
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(file_path))
+            .block_version(3)
+            .build()
+            .unwrap();
+        ledger_map.upsert("Label2", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label2", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.upsert("Label3", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(ledger_map.get("Label2", b"key2").unwrap(), b"value2");
+
+        let blocks = ledger_map
+            .iter_raw()
+            .map(|block| block.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(blocks[0].0.summary_len(), None);
+        for (header, block) in &blocks[1..] {
+            assert_eq!(block.version(), 3);
+            assert_eq!(header.summary_len(), block.summary_len().unwrap());
+            let (_, summary) = ledger_map
+                .get_block_summary_at_offset(block.get_offset())
+                .unwrap();
+            assert_eq!(summary, block.summary());
+        }
+        let (_, summary) = ledger_map
+            .get_block_summary_at_offset(blocks[2].1.get_offset())
+            .unwrap();
+        assert_eq!(summary.entry_count, 2);
+        assert_eq!(summary.labels, vec!["Label1", "Label3"]);
+
+        let label_block_offsets = |label| {
+            ledger_map
+                .iter_raw_for_label(label)
+                .map(|block| block.unwrap().1.get_offset())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            label_block_offsets("Label1"),
+            vec![blocks[0].1.get_offset(), blocks[2].1.get_offset()]
+        );
+        assert_eq!(
+            label_block_offsets("Label2"),
+            vec![blocks[1].1.get_offset()]
+        );
+        assert!(label_block_offsets("Label4").is_empty());
+    }
+
+    #[test]
+    fn test_label_stats() {
+        let mut ledger_map = new_temp_ledger(None);
+        assert_eq!(ledger_map.label_stats("Label1"), None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label1", b"key2", b"value22").unwrap();
+        ledger_map.upsert("Label2", b"key1", b"v").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label1", b"key1", b"val").unwrap();
+        ledger_map.delete("Label1", b"key2").unwrap();
+        ledger_map.delete("Label1", b"key3").unwrap();
+        ledger_map.commit_block().unwrap();
+        // Uncommitted entries are not counted
+        ledger_map.upsert("Label1", b"key4", b"value4").unwrap();
+
+        let expected = LabelStats {
+            live_entries: 1,
+            tombstones: 2,
+            value_bytes: 3,
+            last_updated_block: 1,
+            last_updated_timestamp_ns: 0,
+        };
+        assert_eq!(ledger_map.label_stats("Label1"), Some(expected.clone()));
+        assert_eq!(
+            ledger_map.label_stats("Label2").unwrap().last_updated_block,
+            0
+        );
+
+        // The statistics are rebuilt from the blocks at refresh
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.label_stats("Label1"), Some(expected.clone()));
+
+        ledger_map.rename_label("Label1", "Label3").unwrap();
+        assert_eq!(ledger_map.label_stats("Label1"), None);
+        assert_eq!(
+            ledger_map.label_stats("Label3"),
+            Some(LabelStats {
+                last_updated_block: 2,
+                ..expected
+            })
+        );
+    }
+
+    #[test]
+    fn test_block_labels() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label2", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.delete("Label1", b"key1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Label3", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        assert_eq!(
+            ledger_map.block_labels(0).unwrap(),
+            Some(vec!["Label1".to_string(), "Label2".to_string()])
+        );
+        assert_eq!(
+            ledger_map.block_labels(1).unwrap(),
+            Some(vec!["Label1".to_string()])
+        );
+        assert_eq!(ledger_map.block_labels(3).unwrap(), None);
+        assert_eq!(ledger_map.blocks_touching_label("Label1"), vec![0, 1]);
+        assert_eq!(ledger_map.blocks_touching_label("Label3"), vec![2]);
+        assert!(ledger_map.blocks_touching_label("Label4").is_empty());
+
+        // The index is rebuilt from the blocks at refresh
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.blocks_touching_label("Label1"), vec![0, 1]);
+        assert_eq!(ledger_map.blocks_touching_label("Label2"), vec![0]);
+    }
+
+    #[test]
+    fn test_commit_block_described() {
+        let mut ledger_map = new_temp_ledger(None);
+        assert_eq!(ledger_map.commit_block_described().unwrap(), None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        ledger_map.upsert("Label2", b"key2", b"value2").unwrap();
+        ledger_map.delete("Label1", b"key1").unwrap();
+        let result = ledger_map.commit_block_described().unwrap().unwrap();
+        assert_eq!(result.block_index, 1);
+        assert_eq!(result.block_hash, ledger_map.get_latest_block_hash());
+        assert_eq!(
+            result.changed,
+            vec![
+                ("Label2".to_string(), b"key2".to_vec(), Operation::Upsert),
+                ("Label1".to_string(), b"key1".to_vec(), Operation::Delete),
+            ]
+        );
+        let (_, block) = ledger_map.iter_raw().last().unwrap().unwrap();
+        assert_eq!(
+            block
+                .entries()
+                .iter()
+                .map(|entry| (
+                    entry.label().to_string(),
+                    entry.key().to_vec(),
+                    entry.operation()
+                ))
+                .collect::<Vec<_>>(),
+            result.changed
+        );
+    }
+
+    #[test]
+    fn test_iter_blocks_since() {
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(
+                tempfile::tempdir()
+                    .unwrap()
+                    .keep()
+                    .join("test_ledger_store.bin"),
+            ))
+            .clock(crate::CounterClock::new(100, 10))
+            .build()
+            .unwrap();
+        for i in 0..5u32 {
+            ledger_map
+                .upsert("Label1", i.to_le_bytes(), b"value")
+                .unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        let timestamps = ledger_map
+            .iter_raw()
+            .map(|block| block.unwrap().1.timestamp())
+            .collect::<Vec<_>>();
+
+        let since = |timestamp_ns| {
+            ledger_map
+                .iter_blocks_since(timestamp_ns)
+                .map(|block| block.unwrap().1.timestamp())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(since(0), timestamps);
+        assert_eq!(since(timestamps[1]), timestamps[2..].to_vec());
+        assert_eq!(since(timestamps[1] + 1), timestamps[2..].to_vec());
+        assert!(since(timestamps[4]).is_empty());
+    }
+
+    #[test]
+    fn test_iter_blocks_after() {
+        let mut ledger_map = new_temp_ledger(None);
+        for i in 0..3u32 {
+            ledger_map
+                .upsert("Label1", i.to_le_bytes(), b"value")
+                .unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        let hashes = ledger_map
+            .iter_raw_with_hashes()
+            .map(|block| block.unwrap().2)
+            .collect::<Vec<_>>();
+
+        let after = |chain_hash: &[u8]| {
+            ledger_map.iter_blocks_after(chain_hash).map(|blocks| {
+                blocks
+                    .map(|block| block.unwrap().1.parent_hash().to_vec())
+                    .collect::<Vec<_>>()
+            })
+        };
+        // Each block has the chain hash of the previous block as its parent hash
+        assert_eq!(
+            after(&[]).unwrap(),
+            vec![vec![], hashes[0].clone(), hashes[1].clone()]
+        );
+        assert_eq!(
+            after(&hashes[0]).unwrap(),
+            vec![hashes[0].clone(), hashes[1].clone()]
+        );
+        assert!(after(&hashes[2]).unwrap().is_empty());
+        assert_eq!(
+            after(b"unknown hash").unwrap_err(),
+            LedgerError::UnknownChainHash(b"unknown hash".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_iter_in_time_range() {
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(
+                tempfile::tempdir()
+                    .unwrap()
+                    .keep()
+                    .join("test_ledger_store.bin"),
+            ))
+            .clock(crate::CounterClock::new(100, 10))
+            .build()
+            .unwrap();
+        // Blocks at 100, 110, 120, and 130 ns
+        for key in [b"key1", b"key2", b"key3"] {
+            ledger_map.upsert("Label1", key, b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        ledger_map.upsert("Label1", b"key1", b"new value").unwrap();
+        ledger_map.delete("Label1", b"key3").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let keys_in_range = |from_ns, to_ns| {
+            ledger_map
+                .iter_in_time_range("Label1", from_ns, to_ns)
+                .map(|entry| entry.key().to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys_in_range(100, 120), vec![b"key2".to_vec()]);
+        assert_eq!(keys_in_range(130, 140), vec![b"key1".to_vec()]);
+        assert!(keys_in_range(120, 130).is_empty());
+        assert_eq!(keys_in_range(0, u64::MAX).len(), 2);
+    }
+
+    #[test]
+    fn test_monotonic_timestamps() {
+        let write_blocks = |monotonic: bool| {
+            let file_path = tempfile::tempdir()
+                .unwrap()
+                .keep()
+                .join("test_ledger_store.bin");
+            let mut builder = LedgerMap::builder()
+                .path(Some(file_path.clone()))
+                .clock(|| 1000u64);
+            if monotonic {
+                builder = builder.monotonic_timestamps();
+            }
+            let mut ledger_map = builder.build().unwrap();
+            for i in 0..3u32 {
+                ledger_map
+                    .upsert("Label1", i.to_le_bytes(), b"value")
+                    .unwrap();
+                ledger_map.commit_block().unwrap();
+            }
+            let timestamps = ledger_map
+                .iter_raw()
+                .map(|block| block.unwrap().1.timestamp())
+                .collect::<Vec<_>>();
+            (file_path, timestamps)
+        };
+        let reopen = |file_path| {
+            LedgerMap::builder()
+                .path(Some(file_path))
+                .reject_non_monotonic_timestamps()
+                .build()
+        };
+
+        let (file_path, timestamps) = write_blocks(false);
+        assert_eq!(timestamps, vec![1000, 1000, 1000]);
+        assert!(reopen(file_path).is_err());
+
+        let (file_path, timestamps) = write_blocks(true);
+        assert_eq!(timestamps, vec![1000, 1001, 1002]);
+        assert_eq!(reopen(file_path).unwrap().get_blocks_count(), 3);
+    }
+
+    #[test]
+    fn test_append_raw_blocks_timestamp_skew() {
+        let temp_path = || {
+            Some(
+                tempfile::tempdir()
+                    .unwrap()
+                    .keep()
+                    .join("test_ledger_store.bin"),
+            )
+        };
+        let timestamps = std::sync::Mutex::new(vec![1000u64, 900, 5000]);
+        let mut source = LedgerMap::builder()
+            .path(temp_path())
+            .clock(move || timestamps.lock().unwrap().remove(0))
+            .build()
+            .unwrap();
+        for i in 0..3u8 {
+            source.upsert("Label1", [i], b"value").unwrap();
+            source.commit_block().unwrap();
+        }
+        let blocks = source
+            .read_raw_blocks(source.get_data_start_pos(), u64::MAX)
+            .unwrap();
+
+        let mut replica = LedgerMap::builder()
+            .path(temp_path())
+            .clock(|| 2000u64)
+            .timestamp_skew(TimestampSkew {
+                max_ahead_of_local_ns: Some(1000),
+                max_behind_parent_ns: Some(50),
+            })
+            .build()
+            .unwrap();
+        // Offset in `blocks` of the first block that the replica does not have yet
+        let pos = |replica: &LedgerMap| {
+            (replica.get_next_block_start_pos() - replica.get_data_start_pos()) as usize
+        };
+        let timestamp_error = |replica: &mut LedgerMap| {
+            replica
+                .append_raw_blocks(&blocks[pos(replica)..])
+                .unwrap_err()
+                .downcast::<LedgerError>()
+                .unwrap()
+        };
+        // The second block is 100 ns behind its parent
+        assert!(matches!(
+            timestamp_error(&mut replica),
+            LedgerError::TimestampOutOfRange {
+                timestamp_ns: 900,
+                min_ns: 950,
+                max_ns: 3000,
+                ..
+            }
+        ));
+        assert_eq!(replica.get_blocks_count(), 1);
+
+        // The third block is more than 1000 ns ahead of the local clock
+        replica.set_timestamp_skew(TimestampSkew {
+            max_ahead_of_local_ns: Some(1000),
+            max_behind_parent_ns: Some(200),
+        });
+        assert!(matches!(
+            timestamp_error(&mut replica),
+            LedgerError::TimestampOutOfRange {
+                timestamp_ns: 5000,
+                ..
+            }
+        ));
+        assert_eq!(replica.get_blocks_count(), 2);
+
+        replica.set_timestamp_skew(TimestampSkew::default());
+        assert_eq!(
+            replica.append_raw_blocks(&blocks[pos(&replica)..]).unwrap(),
+            1
+        );
+        assert_eq!(
+            replica.get_latest_block_hash(),
+            source.get_latest_block_hash()
+        );
+    }
+
+    #[test]
+    fn test_label_index_kind() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(file_path))
+            .label_index_kind("sorted", LabelIndexKind::BTreeMap)
+            .label_index_kind("hashed", LabelIndexKind::HashMap)
+            .build()
+            .unwrap();
+        for key in [b"key3", b"key1", b"key2"] {
+            ledger_map.upsert("sorted", key, b"value").unwrap();
+            ledger_map.upsert("hashed", key, key).unwrap();
+            ledger_map.upsert("default", key, b"value").unwrap();
+        }
+        ledger_map.commit_block().unwrap();
+        ledger_map.delete("hashed", b"key2").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let keys = |ledger_map: &LedgerMap, label| {
+            ledger_map
+                .iter(Some(label))
+                .map(|entry| entry.key().to_vec())
+                .collect::<Vec<_>>()
+        };
+        let sorted = vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()];
+        let inserted = vec![b"key3".to_vec(), b"key1".to_vec(), b"key2".to_vec()];
+        assert_eq!(keys(&ledger_map, "sorted"), sorted);
+        assert_eq!(keys(&ledger_map, "default"), inserted);
+        assert_eq!(ledger_map.get("hashed", b"key1").unwrap(), b"key1");
+        assert!(ledger_map.get("hashed", b"key2").is_err());
+
+        // The chosen kinds are kept when the index is rebuilt from the blocks
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(keys(&ledger_map, "sorted"), sorted);
+        assert_eq!(keys(&ledger_map, "default"), inserted);
+        assert_eq!(ledger_map.get("hashed", b"key3").unwrap(), b"key3");
+        assert!(ledger_map.get("hashed", b"key2").is_err());
+    }
+
+    #[test]
+    fn test_iter_sorted() {
+        let mut ledger_map = new_temp_ledger(None);
+        for key in [b"key3", b"key1", b"key4", b"key2"] {
+            ledger_map.upsert("Label1", key, b"value").unwrap();
+        }
+        ledger_map.commit_block().unwrap();
+        // Deleting a key moves the last entry of the default index into its place
+        ledger_map.delete("Label1", b"key3").unwrap();
+        ledger_map.upsert("Label1", b"key0", b"value").unwrap();
+        ledger_map.commit_block().unwrap();
+        // Uncommitted entries are not yielded
+        ledger_map.upsert("Label1", b"key5", b"value").unwrap();
+
+        let sorted_keys = |ledger_map: &LedgerMap| {
+            ledger_map
+                .iter_sorted("Label1")
+                .map(|entry| entry.key().to_vec())
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            b"key0".to_vec(),
+            b"key1".to_vec(),
+            b"key2".to_vec(),
+            b"key4".to_vec(),
+        ];
+        assert_eq!(sorted_keys(&ledger_map), expected);
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(sorted_keys(&ledger_map), expected);
+        assert_eq!(ledger_map.iter_sorted("Label2").count(), 0);
+    }
+
+    #[test]
+    fn test_iter_page() {
+        for kind in [
+            LabelIndexKind::IndexMap,
+            LabelIndexKind::BTreeMap,
+            LabelIndexKind::HashMap,
+        ] {
+            let mut ledger_map = LedgerMap::builder()
+                .in_memory()
+                .label_index_kind("Label1", kind)
+                .build()
+                .unwrap();
+            check_iter_page(&mut ledger_map);
+        }
+    }
+
+    fn check_iter_page(ledger_map: &mut LedgerMap) {
+        for key in [b"key4", b"key1", b"key3", b"key2", b"key5"] {
+            ledger_map.upsert("Label1", key, b"value").unwrap();
+        }
+        ledger_map.commit_block().unwrap();
+        let page_keys = |page: &[&LedgerEntry]| {
+            page.iter()
+                .map(|entry| entry.key().to_vec())
+                .collect::<Vec<_>>()
+        };
+
+        let (page, cursor) = ledger_map.iter_page("Label1", None, 2);
+        assert_eq!(page_keys(&page), vec![b"key1".to_vec(), b"key2".to_vec()]);
+        let cursor = cursor.unwrap();
+
+        // The cursor survives a round trip through its string form, and a refresh
+        let cursor: Cursor = cursor.to_string().parse().unwrap();
+        ledger_map.delete("Label1", b"key3").unwrap();
+        ledger_map.upsert("Label1", b"key0", b"value").unwrap();
+        ledger_map.upsert("Label1", b"key6", b"value").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.refresh_ledger().unwrap();
+
+        let (page, cursor) = ledger_map.iter_page("Label1", Some(&cursor), 2);
+        assert_eq!(page_keys(&page), vec![b"key4".to_vec(), b"key5".to_vec()]);
+        let (page, cursor) = ledger_map.iter_page("Label1", cursor.as_ref(), 2);
+        assert_eq!(page_keys(&page), vec![b"key6".to_vec()]);
+        assert_eq!(cursor, None);
+
+        // A zero limit still makes progress
+        let (page, cursor) = ledger_map.iter_page("Label1", None, 0);
+        assert_eq!(page_keys(&page), vec![b"key0".to_vec()]);
+        assert!(cursor.is_some());
+
+        assert_eq!(ledger_map.iter_page("Label2", None, 2), (vec![], None));
+        assert!("not hex".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn test_scan_resume() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        // Large enough for the pruned block to be smaller
+        ledger_map
+            .upsert("Label1", b"key2", incompressible_bytes(100))
+            .unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.commit_block().unwrap(); // Empty blocks are skipped
+        ledger_map.upsert("Label1", b"key1", b"value3").unwrap();
+        ledger_map.delete("Label1", b"key2").unwrap();
+        ledger_map.upsert("Label2", b"key1", b"value4").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let all = ledger_map
+            .scan(ScanHandle::default())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(all.len(), 5);
+        assert_eq!(all[3].operation(), Operation::Delete);
+
+        // Stop in the middle of a block, and resume from the saved handle
+        let mut scan = ledger_map.scan(ScanHandle::default());
+        let first = scan
+            .by_ref()
+            .take(3)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let saved = scan.handle().to_bytes().unwrap();
+        drop(scan);
+        let handle = ScanHandle::from_bytes(&saved).unwrap();
+        let rest = ledger_map
+            .scan(handle.clone())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!([first, rest].concat(), all);
+
+        // A handle at the end yields the blocks committed later
+        let mut scan = ledger_map.scan(handle.clone());
+        assert_eq!(scan.by_ref().count(), 2);
+        let end = scan.handle().clone();
+        ledger_map.upsert("Label1", b"key3", b"value5").unwrap();
+        ledger_map.commit_block().unwrap();
+        let new = ledger_map.scan(end).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(new.len(), 1);
+        assert_eq!(new[0].key(), b"key3");
+
+        // Resuming fails after the blocks were rewritten
+        let mut scan = ledger_map.scan(ScanHandle::default());
+        scan.next().unwrap().unwrap();
+        let handle = scan.handle().clone();
+        drop(scan);
+        ledger_map.prune_blocks(1).unwrap();
+        let mut scan = ledger_map.scan(handle);
+        assert!(scan.next().unwrap().is_err());
+        assert!(scan.next().is_none());
+    }
+
+    #[test]
+    fn test_refresh_label() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("customers", b"key1", b"value1").unwrap();
+        ledger_map.upsert("customers", b"key2", b"value2").unwrap();
+        ledger_map.upsert("orders", b"key1", b"order1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.rename_label("customers", "clients").unwrap();
+        ledger_map.upsert("clients", b"key3", b"value3").unwrap();
+        ledger_map.delete("clients", b"key2").unwrap();
+        ledger_map.commit_block().unwrap();
+        let clients = ledger_map
+            .iter(Some("clients"))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(clients.len(), 2);
+
+        // Corrupt the index of a label, and rebuild it
+        ledger_map
+            .entries
+            .get_mut("clients")
+            .unwrap()
+            .remove(b"key1");
+        ledger_map
+            .entries
+            .get_mut("orders")
+            .unwrap()
+            .remove(b"key1");
+        ledger_map.upsert("clients", b"key4", b"value4").unwrap();
+        ledger_map.refresh_label("clients").unwrap();
+        assert_eq!(
+            ledger_map
+                .iter(Some("clients"))
+                .cloned()
+                .collect::<Vec<_>>(),
+            clients
+        );
+        // Other labels are left as they are, and uncommitted entries are kept
+        assert_eq!(ledger_map.iter(Some("orders")).count(), 0);
+        assert_eq!(ledger_map.get("clients", b"key4").unwrap(), b"value4");
+
+        ledger_map.refresh_label("orders").unwrap();
+        assert_eq!(ledger_map.get("orders", b"key1").unwrap(), b"order1");
+        ledger_map.refresh_label("customers").unwrap();
+        assert!(ledger_map.entries.get("customers").is_none());
+    }
+
+    #[test]
+    fn test_lazy_index() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(file_path.clone()))
+            .build()
+            .unwrap();
+        ledger_map.upsert("customers", b"key1", b"value1").unwrap();
+        ledger_map.upsert("customers", b"key2", b"value2").unwrap();
+        ledger_map.upsert("orders", b"key1", b"order1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.rename_label("customers", "clients").unwrap();
+        ledger_map.delete("clients", b"key2").unwrap();
+        ledger_map.upsert("orders", b"key2", b"order2").unwrap();
+        ledger_map.commit_block().unwrap();
+        let expected = ledger_map.iter(None).cloned().collect::<Vec<_>>();
+
+        let mut ledger_map = LedgerMap::builder()
+            .path(Some(file_path))
+            .lazy_index(true)
+            .build()
+            .unwrap();
+        assert!(ledger_map.entries.is_empty());
+        assert_eq!(ledger_map.get("orders", b"key2").unwrap(), b"order2");
+        // Only the accessed label is loaded
+        assert!(ledger_map.lazy_entries["orders"].get().is_some());
+        assert!(ledger_map.lazy_entries["clients"].get().is_none());
+        assert_eq!(ledger_map.get("clients", b"key1").unwrap(), b"value1");
+        assert!(ledger_map.get("clients", b"key2").is_err());
+        assert!(ledger_map.get("customers", b"key1").is_err());
+        let mut entries = ledger_map.iter(None).cloned().collect::<Vec<_>>();
+        entries.sort_by(|a, b| (a.label(), a.key()).cmp(&(b.label(), b.key())));
+        let mut expected_sorted = expected.clone();
+        expected_sorted.sort_by(|a, b| (a.label(), a.key()).cmp(&(b.label(), b.key())));
+        assert_eq!(entries, expected_sorted);
+
+        // The index of a label is materialized before it is modified
+        ledger_map.upsert("orders", b"key3", b"order3").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert!(!ledger_map.lazy_entries.contains_key("orders"));
+        assert_eq!(ledger_map.iter(Some("orders")).count(), 3);
+        ledger_map.rename_label("clients", "users").unwrap();
+        assert_eq!(ledger_map.get("users", b"key1").unwrap(), b"value1");
+
+        // Secondary indexes are built from the materialized index
+        ledger_map.refresh_ledger().unwrap();
+        ledger_map
+            .register_index("users", "by_value", |value| vec![value.to_vec()])
+            .unwrap();
+        assert_eq!(
+            ledger_map
+                .get_by_index("users", "by_value", b"value1")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(ledger_map.get("users", b"key1").unwrap(), b"value1");
+    }
+
+    #[test]
+    fn test_ledger_block_offsets() {
+        // Create a new ledger
+        let mut ledger_map = new_temp_ledger(None);
+
+        // Create some dummy entries
+        ledger_map.upsert("label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("label1a", b"key2a", b"value2aa").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map
+            .upsert("label1bb", b"key3bbb", b"value3bbbb")
+            .unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let (headers, blocks): (Vec<_>, Vec<_>) = ledger_map.iter_raw().map(|x| x.unwrap()).unzip();
+
+        let header_len_bytes = headers
+            .iter()
+            .map(|x| x.serialize().unwrap().len() as u32)
+            .collect::<Vec<_>>();
+        let blocks_len_bytes = blocks
+            .iter()
+            .map(|x| x.serialize().unwrap().len() as u32)
+            .collect::<Vec<_>>();
+        let blk0_bytes = (header_len_bytes[0] + blocks_len_bytes[0]) as u64;
+        let blk1_bytes = (header_len_bytes[1] + blocks_len_bytes[1]) as u64;
+        let blk2_bytes = (header_len_bytes[2] + blocks_len_bytes[2]) as u64;
+        assert_eq!(headers[0].jump_bytes_prev_block(), 0);
+        assert_eq!(headers[0].jump_bytes_next_block(), blk0_bytes as u32);
+
+        assert_eq!(headers[1].jump_bytes_prev_block(), -(blk0_bytes as i32));
+        assert_eq!(headers[1].jump_bytes_next_block(), blk1_bytes as u32);
+        assert_eq!(headers[2].jump_bytes_prev_block(), -(blk1_bytes as i32));
+        assert_eq!(headers[2].jump_bytes_next_block(), blk2_bytes as u32);
+    }
+
+    #[test]
+    fn test_get_block_at_offset() {
+        // Create a new ledger
+        let mut ledger_map = new_temp_ledger(None);
+
+        // Create some entries and commit them
+        ledger_map.upsert("label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        let first_block_pos = ledger_map.metadata.borrow().first_block_start_pos();
+        assert!(first_block_pos > 0);
+
+        ledger_map.upsert("label2", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        let second_block_pos = ledger_map.get_latest_block_start_pos();
+
+        // Test getting block at first position
+        let (header1, block1) = ledger_map.get_block_at_offset(0).unwrap();
+        assert_eq!(block1.entries().len(), 1);
+        assert_eq!(block1.entries()[0].label(), "label1");
+        assert_eq!(block1.entries()[0].key(), b"key1");
+        assert_eq!(block1.entries()[0].value(), b"value1");
+        assert_eq!(header1.jump_bytes_prev_block(), 0);
+        assert!(header1.jump_bytes_next_block() > 0);
+
+        // Test getting block at second position
+        let (header2, block2) = ledger_map.get_block_at_offset(second_block_pos).unwrap();
+        assert_eq!(block2.entries().len(), 1);
+        assert_eq!(block2.entries()[0].label(), "label2");
+        assert_eq!(block2.entries()[0].key(), b"key2");
+        assert_eq!(block2.entries()[0].value(), b"value2");
+        assert!(header2.jump_bytes_prev_block() < 0); // Should point back to previous block
+
+        // Test getting block at invalid position (before first block)
+        let result = ledger_map.get_block_at_offset(0);
+        assert!(result.is_ok()); // Should return first block instead of error
+        let (header, block) = result.unwrap();
+        assert_eq!(block.entries()[0].label(), "label1"); // Should get first block
+        assert_eq!(header.jump_bytes_prev_block(), 0);
+
+        // Test getting block at non-existent position
+        let invalid_pos = second_block_pos + 1000;
+        let result = ledger_map.get_block_at_offset(invalid_pos);
+        assert!(result.is_err());
+    }
+    #[test]
+    fn test_get_block_from_slice() {
+        // Create a new ledger
+        let ledger_map = new_temp_ledger(None);
+
+        // Create test data: a valid block header and block
+        let entries = vec![
+            LedgerEntry::new("test_label", b"key1", b"value1", Operation::Upsert),
+            LedgerEntry::new("test_label", b"key2", b"value2", Operation::Upsert),
+        ];
+        let timestamp = 12345u64;
+        let parent_hash = vec![1, 2, 3, 4];
+        let block = LedgerBlock::new(entries, timestamp, parent_hash.clone());
+
+        // Serialize the block
+        let block_data = block.serialize().unwrap();
+        let block_len = block_data.len() as u32;
+
+        // Create a header
+        let header = LedgerBlockHeader::new(0, block_len + LedgerBlockHeader::sizeof() as u32);
+        let header_data = header.serialize().unwrap();
+
+        // Combine header and block data
+        let mut test_data = header_data;
+        test_data.extend_from_slice(&block_data);
+
+        // Test successful parsing
+        let result = ledger_map.get_block_from_slice(&test_data);
+
+        let (parsed_header, parsed_block, block_hash) = result.unwrap();
+        assert_eq!(parsed_header.block_version(), 1);
+        assert_eq!(
+            parsed_header.jump_bytes_next_block(),
+            block_len + LedgerBlockHeader::sizeof() as u32
+        );
+        assert_eq!(parsed_block.entries().len(), 2);
+        assert_eq!(parsed_block.timestamp(), timestamp);
+        assert_eq!(parsed_block.parent_hash(), parent_hash);
+        assert_eq!(
+            block_hash,
+            // Reference block hash value, manually checked
+            vec![
+                177, 198, 241, 134, 225, 141, 141, 22, 23, 248, 31, 12, 28, 136, 225, 4, 153, 216,
+                221, 225, 99, 159, 155, 79, 183, 71, 23, 177, 109, 79, 233, 34
+            ]
+        );
+
+        // Test with insufficient data (truncated block)
+        let truncated_data = test_data[..test_data.len() - 10].to_vec();
+        let result = ledger_map.get_block_from_slice(&truncated_data);
+        assert!(result.is_err());
+        match result.unwrap_err() {
+            LedgerError::BlockCorrupted(_) => {} // Expected error
+            err => panic!("Unexpected error: {:?}", err),
+        }
+
+        // Test with corrupted header (invalid version)
+        let mut corrupted_data = test_data.clone();
+        corrupted_data[0] = 99; // Set invalid version
+        let result = ledger_map.get_block_from_slice(&corrupted_data);
+        assert!(result.is_err());
+
+        // Test with empty data
+        let result = ledger_map.get_block_from_slice(&[]);
+        assert!(result.is_err());
+
+        // Test with data too small for header
+        let result = ledger_map.get_block_from_slice(&[0, 1, 2]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_iter_raw_with_hashes() {
+        let mut ledger_map = new_temp_ledger(None);
+        for i in 0..3u8 {
+            ledger_map.upsert("Label1", [i], [i]).unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+
+        let blocks = ledger_map
+            .iter_raw_with_hashes()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(blocks.len(), 3);
+        let mut parent_hash = Vec::new();
+        for (_, block, chain_hash) in &blocks {
+            assert_eq!(block.parent_hash(), parent_hash);
+            parent_hash = chain_hash.clone();
+        }
+        assert_eq!(parent_hash, ledger_map.get_latest_block_hash());
+        assert_eq!(
+            blocks.last().unwrap().1.get_offset(),
+            ledger_map.get_latest_block_start_pos()
+        );
+        let from_block = ledger_map
+            .iter_raw_from_block(1)
+            .map(|block| block.unwrap().1.get_offset())
+            .collect::<Vec<_>>();
+        assert_eq!(from_block, [blocks[1].1.get_offset(), blocks[2].1.get_offset()]);
+        assert_eq!(ledger_map.iter_raw_from_block(3).count(), 0);
+
+        // Same offsets and hashes as iterating over the raw bytes of the blocks
+        let first_block_start_pos = blocks[0].1.get_offset();
+        let raw_blocks = ledger_map
+            .read_raw_blocks(first_block_start_pos, u64::MAX)
+            .unwrap();
+        for ((_, block, chain_hash), from_slice) in blocks
+            .iter()
+            .zip(ledger_map.iter_raw_from_slice(&raw_blocks))
+        {
+            let (_, block_from_slice, chain_hash_from_slice) = from_slice.unwrap();
+            assert_eq!(
+                block.get_offset(),
+                first_block_start_pos + block_from_slice.get_offset()
+            );
+            assert_eq!(*chain_hash, chain_hash_from_slice);
+        }
+    }
+
+    #[test]
+    fn test_get_block_by_hash() {
+        let mut ledger_map = new_temp_ledger(None);
+        assert_eq!(ledger_map.get_chain_hash_at(0), None);
+        for i in 0..3u8 {
+            ledger_map.upsert("Label1", [i], [i]).unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+
+        let check = |ledger_map: &LedgerMap| {
+            for (index, block) in ledger_map.iter_raw_with_hashes().enumerate() {
+                let (_, block, chain_hash) = block.unwrap();
+                assert_eq!(
+                    ledger_map.get_chain_hash_at(index),
+                    Some(chain_hash.clone())
+                );
+                let (_, found) = ledger_map.get_block_by_hash(&chain_hash).unwrap().unwrap();
+                assert_eq!(found.get_offset(), block.get_offset());
+                assert_eq!(found.entries(), block.entries());
+            }
+            assert_eq!(ledger_map.get_chain_hash_at(3), None);
+            assert!(ledger_map.get_block_by_hash(&[0u8; 32]).unwrap().is_none());
+        };
+        check(&ledger_map);
+        // The hashes are rebuilt when the ledger is loaded
+        ledger_map.refresh_ledger().unwrap();
+        check(&ledger_map);
+    }
+
+    #[test]
+    fn test_get_chain_hash_at() {
+        let mut ledger_map = new_temp_ledger(None);
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(
+            ledger_map.get_chain_hash_at(0),
+            Some(ledger_map.get_latest_block_hash())
+        );
+
+        // Pin the chain hash as of block 1, and check later that the chain descends from it
+        ledger_map.upsert("Label1", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        let pinned = ledger_map.get_chain_hash_at(1).unwrap();
+        assert_eq!(
+            ledger_map.check_fork(&pinned, 2).unwrap(),
+            ForkStatus::Equal
+        );
+        ledger_map.upsert("Label1", b"key3", b"value3").unwrap();
+        ledger_map.commit_block().unwrap();
+        assert_eq!(
+            ledger_map.check_fork(&pinned, 2).unwrap(),
+            ForkStatus::Descendant
+        );
+        assert_eq!(ledger_map.get_chain_hash_at(1), Some(pinned.clone()));
+        assert_ne!(ledger_map.get_chain_hash_at(2), Some(pinned.clone()));
+        assert_eq!(
+            ledger_map.check_fork(&[0u8; 32], 2).unwrap(),
+            ForkStatus::Fork
+        );
+        assert_eq!(ledger_map.get_chain_hash_at(3), None);
+    }
+
+    #[test]
+    fn test_iter_raw_from_slice() {
+        // Create a new ledger
+        let ledger_map = new_temp_ledger(None);
+
+        // Create multiple blocks
+        let blocks_count = 3;
+        let mut test_data = Vec::new();
+
+        let mut offset = 0u32;
+        // Create and serialize multiple blocks
+        for i in 0..blocks_count {
+            let entries = vec![LedgerEntry::new(
+                format!("label{}", i),
+                format!("key{}", i).as_bytes(),
+                format!("value{}", i).as_bytes(),
+                Operation::Upsert,
+            )];
+            let timestamp = (i as u64) * 1000;
+            let parent_hash = vec![i as u8; 4];
+            let block = LedgerBlock::new(entries, timestamp, parent_hash);
+
+            let block_data = block.serialize().unwrap();
+            let block_len = block_data.len() as u32;
+            let jump = block_len + LedgerBlockHeader::sizeof() as u32;
+
+            let header = LedgerBlockHeader::new(jump as i32 - offset as i32, jump);
+            offset += jump;
+            let header_data = header.serialize().unwrap();
+
+            test_data.extend_from_slice(&header_data);
+            test_data.extend_from_slice(&block_data);
+        }
+
+        // Test iterating through all blocks
+        let blocks: Vec<_> = ledger_map
+            .iter_raw_from_slice(&test_data)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(blocks.len(), blocks_count);
+
+        // Reference block hashes, from a good run
+        let expected_block_hashes = [
+            vec![
+                59, 212, 243, 209, 119, 48, 119, 30, 19, 102, 137, 70, 162, 25, 101, 154, 229, 58,
+                186, 226, 164, 114, 252, 88, 255, 180, 170, 221, 196, 0, 141, 101,
             ],
             vec![
                 202, 133, 25, 97, 117, 141, 6, 71, 57, 235, 24, 42, 72, 240, 55, 77, 162, 50, 248,
@@ -611,14 +3942,20 @@ mod tests {
         let mut ledger_map = new_temp_ledger(None);
 
         // Insert test data
-        let keys = vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()];
-        let values = vec![b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()];
-        
+        let keys = [b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()];
+        let values = [b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()];
+
         // Insert entries and commit
-        ledger_map.upsert("Label1", keys[0].clone(), values[0].clone()).unwrap();
-        ledger_map.upsert("Label1", keys[1].clone(), values[1].clone()).unwrap();
+        ledger_map
+            .upsert("Label1", keys[0].clone(), values[0].clone())
+            .unwrap();
+        ledger_map
+            .upsert("Label1", keys[1].clone(), values[1].clone())
+            .unwrap();
         ledger_map.commit_block().unwrap();
-        ledger_map.upsert("Label1", keys[2].clone(), values[2].clone()).unwrap();
+        ledger_map
+            .upsert("Label1", keys[2].clone(), values[2].clone())
+            .unwrap();
 
         // Use for_each to collect entries
         let mut collected = Vec::new();
@@ -0,0 +1,259 @@
+/// This module implements a set of named, independent ledgers in a single persistent storage,
+/// e.g. in the one stable memory of an Internet Computer canister.
+///
+/// Each ledger has its own block chain in its own partition of the partition table, with a fixed
+/// capacity chosen when the ledger is created. Partitions follow each other in the order the
+/// ledgers were created, after the start of the data partition, and a trailing `FREE` partition
+/// marks where the next ledger will be placed. Commits that would not fit in the capacity of a
+/// ledger fail with `LedgerError::QuotaExceeded`.
+///
+/// Example usage:
+///
+/// ```rust,no_run
+/// use ledger_map::LedgerSet;
+///
+/// let mut ledgers = LedgerSet::open(None).unwrap();
+/// if ledgers.get("accounts").is_none() {
+///     ledgers.create("accounts", 64 * 1024 * 1024).unwrap();
+/// }
+/// let accounts = ledgers.get_mut("accounts").unwrap();
+/// accounts.upsert("balance", b"alice", b"100").unwrap();
+/// accounts.commit_block().unwrap();
+/// ```
+use crate::ledger_entry::LedgerBlockHeader;
+use crate::ledger_map::LedgerMap;
+use crate::partition_table::{self, PartitionTableEntry, PART_DATA};
+use crate::platform_specific::persistent_storage_write;
+use indexmap::IndexMap;
+
+/// Maximum length of a ledger name, which is stored as the name of its partition.
+pub const MAX_LEDGER_NAME_BYTES: usize = 8;
+
+const FREE_PARTITION_NAME: &[u8] = b"FREE";
+const RESERVED_PARTITION_NAMES: [&[u8]; 3] = [b"PARTTABL", b"DATA", FREE_PARTITION_NAME];
+
+fn partition_name(name: &[u8]) -> [u8; MAX_LEDGER_NAME_BYTES] {
+    PartitionTableEntry::new(name, 0).name
+}
+
+#[derive(Debug)]
+pub struct LedgerSet {
+    ledgers: IndexMap<String, LedgerMap>,
+    /// Persistent storage of the set, activated before the partition table is changed.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    storage_backend: crate::platform_specific::SharedStorageBackend,
+}
+
+impl LedgerSet {
+    /// Open the ledgers stored at `path` (or in the default file, if `None`).
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
+    pub fn open(path: Option<std::path::PathBuf>) -> anyhow::Result<Self> {
+        crate::platform_specific::set_backing_file(path).map_err(anyhow::Error::msg)?;
+        Self::open_current()
+    }
+
+    /// Open the ledgers of the active persistent storage, e.g. the stable memory of a canister.
+    pub fn open_current() -> anyhow::Result<Self> {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        let storage_backend =
+            crate::platform_specific::active_storage_backend().map_err(anyhow::Error::msg)?;
+        let table = partition_table::get_partition_table();
+        let mut ledgers = IndexMap::new();
+        for (index, entry) in table.entries.iter().enumerate().skip(PART_DATA + 1) {
+            if !entry.is_used() || entry.name == partition_name(FREE_PARTITION_NAME) {
+                break;
+            }
+            let name_len = entry
+                .name
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(entry.name.len());
+            let name = String::from_utf8(entry.name[..name_len].to_vec())
+                .map_err(|e| anyhow::format_err!("Invalid ledger name in [{}]: {}", entry, e))?;
+            ledgers.insert(name, Self::_open_ledger(index)?);
+        }
+        Ok(LedgerSet {
+            ledgers,
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            storage_backend,
+        })
+    }
+
+    fn _open_ledger(data_partition: usize) -> anyhow::Result<LedgerMap> {
+        LedgerMap::builder().data_partition(data_partition).build()
+    }
+
+    /// Make the persistent storage of the set the active one of the thread.
+    fn _activate_storage(&self) {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        crate::platform_specific::activate_storage_backend(&self.storage_backend);
+    }
+
+    /// Create a new, empty ledger named `name`, which can hold up to `capacity_bytes` of blocks.
+    /// Names are at most `MAX_LEDGER_NAME_BYTES` long. The first ledger of a set takes over the
+    /// data partition, which must not hold any blocks yet.
+    pub fn create(&mut self, name: &str, capacity_bytes: u64) -> anyhow::Result<&mut LedgerMap> {
+        if name.is_empty() || name.len() > MAX_LEDGER_NAME_BYTES || name.contains('\0') {
+            return Err(anyhow::format_err!(
+                "Ledger name {:?} must be 1 to {} bytes long, without NUL characters",
+                name,
+                MAX_LEDGER_NAME_BYTES
+            ));
+        }
+        if RESERVED_PARTITION_NAMES.contains(&name.as_bytes()) {
+            return Err(anyhow::format_err!("Ledger name {:?} is reserved", name));
+        }
+        if self.ledgers.contains_key(name) {
+            return Err(anyhow::format_err!("Ledger {:?} already exists", name));
+        }
+        if capacity_bytes < 2 * LedgerBlockHeader::sizeof() as u64 {
+            return Err(anyhow::format_err!(
+                "Ledger capacity of {} bytes is too small",
+                capacity_bytes
+            ));
+        }
+
+        self._activate_storage();
+        let mut table = partition_table::get_partition_table();
+        let free_index = table
+            .entries
+            .iter()
+            .position(|entry| entry.name == partition_name(FREE_PARTITION_NAME));
+        let index = match free_index {
+            Some(free_index) => free_index,
+            None => {
+                if table.entries.len() != PART_DATA + 1 {
+                    return Err(anyhow::format_err!(
+                        "Unexpected partition layout for a ledger set: {}",
+                        table
+                    ));
+                }
+                if Self::_open_ledger(PART_DATA)?.get_blocks_count() > 0 {
+                    return Err(anyhow::format_err!(
+                        "The data partition already holds a ledger"
+                    ));
+                }
+                table
+                    .add_new_entry(PartitionTableEntry::new(
+                        FREE_PARTITION_NAME,
+                        table.entries[PART_DATA].start_lba,
+                    ))
+                    .map_err(anyhow::Error::msg)?;
+                PART_DATA + 1
+            }
+        };
+        let start_lba = table.entries[index].start_lba;
+        let end_lba = start_lba
+            .checked_add(capacity_bytes)
+            .ok_or_else(|| anyhow::format_err!("Ledger capacity is too large"))?;
+        table.entries[index] = PartitionTableEntry::new(name.as_bytes(), start_lba);
+        table
+            .add_new_entry(PartitionTableEntry::new(FREE_PARTITION_NAME, end_lba))
+            .map_err(anyhow::Error::msg)?;
+        table.validate().map_err(anyhow::Error::msg)?;
+        // The region may hold stale data, so mark the partition as empty before using it
+        persistent_storage_write(start_lba, &[0u8; std::mem::size_of::<LedgerBlockHeader>()])?;
+        table.persist().map_err(anyhow::Error::msg)?;
+
+        let ledger_map = Self::_open_ledger(index)?;
+        Ok(self.ledgers.entry(name.to_string()).or_insert(ledger_map))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LedgerMap> {
+        self.ledgers.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut LedgerMap> {
+        self.ledgers.get_mut(name)
+    }
+
+    /// Returns the names of the ledgers, in the order they were created.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.ledgers.keys().map(String::as_str)
+    }
+}
+
+#[cfg(all(test, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+    use crate::LedgerError;
+
+    #[test]
+    fn test_ledger_set() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledgers = LedgerSet::open(Some(file_path.clone())).unwrap();
+        assert_eq!(ledgers.names().count(), 0);
+        ledgers.create("first", 4096).unwrap();
+        ledgers.create("second", 1024 * 1024).unwrap();
+        assert!(ledgers.create("second", 4096).is_err());
+        assert!(ledgers.create("FREE", 4096).is_err());
+        assert!(ledgers.create("too_long_name", 4096).is_err());
+
+        let first = ledgers.get_mut("first").unwrap();
+        first.upsert("Label1", b"key", b"first").unwrap();
+        first.commit_block().unwrap();
+        let second = ledgers.get_mut("second").unwrap();
+        second.upsert("Label1", b"key", b"second").unwrap();
+        second.commit_block().unwrap();
+        second.upsert("Label1", b"key2", b"second").unwrap();
+        second.commit_block().unwrap();
+
+        // A commit that does not fit in the capacity of the ledger fails
+        let first = ledgers.get_mut("first").unwrap();
+        let mut x = 0x2545f4914f6cdd1du64;
+        let incompressible = (0..8192)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect::<Vec<_>>();
+        first.upsert("Label1", b"big", incompressible).unwrap();
+        assert!(matches!(
+            first
+                .commit_block()
+                .unwrap_err()
+                .downcast_ref::<LedgerError>(),
+            Some(LedgerError::QuotaExceeded { .. })
+        ));
+
+        let ledgers = LedgerSet::open(Some(file_path)).unwrap();
+        assert_eq!(ledgers.names().collect::<Vec<_>>(), vec!["first", "second"]);
+        let first = ledgers.get("first").unwrap();
+        assert_eq!(first.get_blocks_count(), 1);
+        assert_eq!(first.get("Label1", b"key").unwrap(), b"first");
+        let second = ledgers.get("second").unwrap();
+        assert_eq!(second.get_blocks_count(), 2);
+        assert_eq!(second.get("Label1", b"key").unwrap(), b"second");
+        assert_ne!(
+            first.get_latest_block_hash(),
+            second.get_latest_block_hash()
+        );
+        assert_eq!(
+            second.get_data_start_pos(),
+            first.get_data_start_pos() + 4096
+        );
+    }
+
+    #[test]
+    fn test_ledger_set_with_existing_ledger() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path.clone())).unwrap();
+        ledger_map.upsert("Label1", b"key", b"value").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let mut ledgers = LedgerSet::open(Some(file_path)).unwrap();
+        assert!(ledgers.create("first", 4096).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+/// This module runs a LedgerMap in a dedicated thread, for async network services.
+///
+/// The persistent storage is bound to the thread that opens it, so requests are sent to the
+/// thread that owns the ledger as jobs, and run one at a time in the order they were sent.
+use crate::{error, LedgerMap};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce(&mut LedgerMap) + Send>;
+
+/// Handle to the thread that owns the ledger. The thread stops when all handles are dropped.
+#[derive(Clone)]
+pub(crate) struct LedgerThread {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl LedgerThread {
+    /// Open the ledger stored at `path` (or in the default file, if `None`) in a new thread.
+    pub(crate) fn spawn(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (opened_tx, opened_rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("ledger-map".to_string())
+            .spawn(move || {
+                let mut ledger_map = match LedgerMap::new_with_path(None, path) {
+                    Ok(ledger_map) => {
+                        let _ = opened_tx.send(Ok(()));
+                        ledger_map
+                    }
+                    Err(err) => {
+                        let _ = opened_tx.send(Err(err));
+                        return;
+                    }
+                };
+                for job in receiver {
+                    // A panicking job drops its result sender, so that its caller gets an error,
+                    // and the ledger is reloaded in case the job left it half updated
+                    if catch_unwind(AssertUnwindSafe(|| job(&mut ledger_map))).is_err() {
+                        error!("Ledger job panicked, reloading the ledger");
+                        if let Err(err) = ledger_map.refresh_ledger() {
+                            error!("Failed to reload the ledger: {}", err);
+                        }
+                    }
+                }
+            })?;
+        opened_rx.recv()??;
+        Ok(LedgerThread { jobs })
+    }
+
+    /// Run `f` with the ledger in the ledger thread.
+    pub(crate) async fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut LedgerMap) -> anyhow::Result<R> + Send + 'static,
+    ) -> anyhow::Result<R> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let job: Job = Box::new(move |ledger_map| {
+            let _ = result_tx.send(f(ledger_map));
+        });
+        self.jobs
+            .send(job)
+            .map_err(|_| anyhow::format_err!("Ledger thread stopped"))?;
+        result_rx
+            .await
+            .map_err(|_| anyhow::format_err!("Ledger job panicked or the ledger thread stopped"))?
+    }
+}
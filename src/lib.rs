@@ -1,7 +1,7 @@
 //! This module implements a key-value storage system called LedgerMap.
 //!
 //! The LedgerMap struct provides methods for inserting, deleting, and retrieving key-value entries.
-//! It journals the entries in a binary file on x86-64 systems or in stable memory in the
+//! It journals the entries in a binary file on x86-64 and WASI systems or in stable memory in the
 //! Internet Computer canister. Each entry is appended to the file along with its length,
 //! allowing efficient retrieval and updates.
 //!
@@ -58,6 +58,13 @@
 //! // Label2 entries still exist
 //! assert_eq!(ledger_map.iter(Some("Label2")).count(), 1);
 //! ```
+//!
+//! Without the default `std` feature, the crate is `no_std` and needs only `alloc`: it then
+//! provides just the `ledger_core` module, for embedded devices that journal the ledger to flash.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
 #[cfg(all(target_arch = "wasm32", feature = "ic"))]
 #[macro_use]
@@ -76,29 +83,178 @@ pub use platform_specific_wasm32_browser as platform_specific;
 #[cfg(all(target_arch = "wasm32", feature = "browser"))]
 pub mod wasm;
 
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(all(feature = "std", any(target_arch = "x86_64", target_arch = "aarch64")))]
 #[macro_use]
 pub mod platform_specific_x86_64;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(all(feature = "std", any(target_arch = "x86_64", target_arch = "aarch64")))]
 pub use platform_specific_x86_64 as platform_specific;
+#[cfg(all(
+    feature = "std",
+    any(target_os = "ios", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod platform_specific_mobile;
+#[cfg(all(
+    feature = "std",
+    windows,
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod platform_specific_windows;
+
+#[cfg(all(feature = "std", target_arch = "wasm32", target_os = "wasi"))]
+#[macro_use]
+pub mod platform_specific_wasm32_wasi;
+#[cfg(all(feature = "std", target_arch = "wasm32", target_os = "wasi"))]
+pub use platform_specific_wasm32_wasi as platform_specific;
 
 // Core modules
+#[cfg(feature = "std")]
+pub mod access;
+#[cfg(feature = "std")]
+pub mod anchor;
+#[cfg(all(target_arch = "wasm32", feature = "ic"))]
+mod certification;
+#[cfg(feature = "std")]
+pub mod checkpoint;
+#[cfg(feature = "std")]
+pub mod cold_storage;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 mod errors;
+#[cfg(all(
+    feature = "testing",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub mod faulty_storage;
+#[cfg(all(feature = "ffi", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub mod ffi;
+#[cfg(all(feature = "grpc", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub mod grpc;
+#[cfg(feature = "std")]
+pub mod hashing;
+#[cfg(feature = "std")]
+mod label_index;
+#[cfg(feature = "std")]
+mod label_stats;
+pub mod ledger_core;
+#[cfg(feature = "std")]
 pub mod ledger_entry;
+#[cfg(feature = "std")]
 mod ledger_map;
+#[cfg(feature = "std")]
+mod ledger_set;
+#[cfg(all(
+    any(feature = "server", feature = "grpc", feature = "p2p"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod ledger_thread;
+#[cfg(feature = "std")]
 mod metadata;
+#[cfg(all(feature = "s3", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub mod object_storage;
+#[cfg(all(feature = "p2p", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub mod p2p;
+#[cfg(feature = "std")]
 pub mod partition_table;
+#[cfg(all(feature = "std", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod partitioned_ledger_map;
+#[cfg(feature = "std")]
+pub mod proof;
+#[cfg(feature = "std")]
+mod query;
+#[cfg(all(feature = "redb", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub mod redb_storage;
+#[cfg(feature = "std")]
+mod scan;
+#[cfg(feature = "std")]
+mod secondary_index;
+#[cfg(all(
+    feature = "server",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod state_proof;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "std")]
+mod value_reader;
+#[cfg(feature = "std")]
+mod value_writer;
 
 // Re-exports
-pub use errors::LedgerError;
-pub use ledger_entry::{EntryKey, EntryValue, LedgerBlock, LedgerEntry, Operation};
-pub use ledger_map::LedgerMap;
+#[cfg(feature = "std")]
+pub use access::{AccessController, AccessOperation, AccessRequest, LabelPolicy, PrincipalSource};
+#[cfg(feature = "std")]
+pub use anchor::{Anchor, AnchorPoint};
+#[cfg(all(target_arch = "wasm32", feature = "ic"))]
+pub use certification::CertifiedEntry;
+#[cfg(feature = "std")]
+pub use checkpoint::{Checkpoint, CheckpointSigner, SignatureVerifier};
+#[cfg(feature = "std")]
+pub use cold_storage::ColdStorage;
+#[cfg(feature = "std")]
+pub use errors::error_code;
+pub use errors::{LedgerError, OTHER_ERROR_CODE};
+#[cfg(feature = "std")]
+pub use label_index::LabelIndexKind;
+#[cfg(feature = "std")]
+pub use label_stats::LabelStats;
+pub use ledger_core::{CoreLedger, CoreStorage};
+#[cfg(feature = "std")]
+pub use ledger_entry::{BlockSummary, EntryKey, EntryValue, LedgerBlock, LedgerEntry, Operation};
+#[cfg(feature = "std")]
+pub use ledger_map::{
+    label_matches, ChunkedValue, Clock, CloneReport, CommitResult, CounterClock, Cursor,
+    Durability, ForkStatus, LedgerInfo, LedgerMap, LedgerMapBuilder, MergeConflict, MergeOutcome,
+    MergeRecord, MergeResolution, MergeSide, MergeStrategy, StorageStats, SystemClock,
+    TimestampSkew, BLOB_LABEL, BLOB_REF_LABEL, CHECKPOINT_LABEL, CHUNKED_LABEL, CHUNK_LABEL,
+    HASH_ALGORITHM, MERGE_LABEL, POLICY_LABEL, RENAME_LABEL,
+};
+#[cfg(feature = "std")]
+pub use ledger_set::LedgerSet;
+#[cfg(feature = "std")]
 pub use metadata::Metadata;
+#[cfg(all(feature = "std", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub use partitioned_ledger_map::LabelPartitionedLedgerMap;
+#[cfg(feature = "std")]
+pub use proof::{verify_entry_proof, ProofBlob};
+#[cfg(feature = "std")]
+pub use query::Query;
+#[cfg(feature = "std")]
+pub use scan::{Scan, ScanHandle};
+#[cfg(feature = "std")]
+pub use secondary_index::{IndexExtractor, IndexKey};
+#[cfg(feature = "std")]
+pub use snapshot::{Snapshot, SnapshotSource};
+#[cfg(feature = "std")]
+pub use state_proof::{verify_non_inclusion_proof, NonInclusionProof, StateDigests, SyncPlan};
+#[cfg(feature = "std")]
+pub use value_reader::ValueReader;
+#[cfg(feature = "std")]
+pub use value_writer::ValueWriter;
 
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(all(
+    feature = "std",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    )
+))]
 pub use platform_specific::{debug, error, info, warn};
+#[cfg(feature = "std")]
 pub use platform_specific::{export_debug, export_error, export_info, export_warn};
 
 // Type aliases
-use std::{collections::HashSet, hash::BuildHasherDefault};
+#[cfg(feature = "std")]
+use std::{
+    collections::{HashMap, HashSet},
+    hash::BuildHasherDefault,
+};
+#[cfg(feature = "std")]
 pub type AHashSet<K> = HashSet<K, BuildHasherDefault<ahash::AHasher>>;
+#[cfg(feature = "std")]
+pub type AHashMap<K, V> = HashMap<K, V, BuildHasherDefault<ahash::AHasher>>;
@@ -15,6 +15,7 @@ struct ParsedArgs {
     upsert: Option<(String, String)>,
     delete: Option<String>,
     path: Option<String>,
+    migrate_format: Option<u32>,
 }
 
 /// Parse the command-line arguments using clap library
@@ -31,6 +32,11 @@ fn parse_args() -> ParsedArgs {
         )
         .arg(arg!(--delete <KEY> "Delete key").required(false))
         .arg(arg!(--path <VALUE> "Specify file path for the ledger").required(false))
+        .arg(
+            arg!(--"migrate-format" <VERSION> "Rewrite the ledger in place in the given block version")
+                .required(false)
+                .value_parser(clap::value_parser!(u32)),
+        )
         .get_matches();
 
     let list = *matches.get_one::<bool>("list").unwrap_or(&false);
@@ -46,11 +52,14 @@ fn parse_args() -> ParsedArgs {
 
     let path = matches.get_one::<String>("path").map(|s| s.to_string());
 
+    let migrate_format = matches.get_one::<u32>("migrate-format").copied();
+
     ParsedArgs {
         list,
         upsert,
         delete,
         path,
+        migrate_format,
     }
 }
 
@@ -61,6 +70,7 @@ fn parse_args() -> ParsedArgs {
         upsert: None,
         delete: None,
         path: None,
+        migrate_format: None,
     }
 }
 
@@ -96,6 +106,12 @@ fn main() -> anyhow::Result<()> {
     let mut ledger_map =
         LedgerMap::new_with_path(None, ledger_path).expect("Failed to create ledger");
 
+    if let Some(target_version) = args.migrate_format {
+        // Rewrite the existing blocks in the new block format
+        ledger_map.migrate_format(target_version)?;
+        println!("Migrated ledger to block version {}", target_version);
+    }
+
     if args.list {
         println!("Listing entries:");
         // Iterate over the entries in the ledger and print them
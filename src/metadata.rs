@@ -1,6 +1,7 @@
 use crate::debug;
 use crate::partition_table;
 use borsh::{BorshDeserialize, BorshSerialize};
+use std::collections::HashMap;
 
 /// Struct representing the metadata of the ledger.
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
@@ -19,6 +20,14 @@ pub struct MetadataV1 {
     next_block_start_pos: u64,
     /// The offset in the persistent storage where the first block was written.
     first_block_start_pos: u64,
+    /// The chain hash of each block, by block index.
+    block_chain_hashes: Vec<Vec<u8>>,
+    /// The index of the last block with each chain hash.
+    block_indexes_by_chain_hash: HashMap<Vec<u8>, usize>,
+    /// The offset in the persistent storage of each block, by block index.
+    block_start_positions: Vec<u64>,
+    /// The timestamp of each block, by block index.
+    block_timestamps_ns: Vec<u64>,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
@@ -28,7 +37,17 @@ pub enum Metadata {
 
 impl Default for Metadata {
     fn default() -> Self {
-        let next_block_start_pos = partition_table::get_data_partition().start_lba;
+        Metadata::with_start_pos(partition_table::get_data_partition().start_lba)
+    }
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Metadata::default()
+    }
+
+    /// Metadata of an empty ledger whose first block will be written at `next_block_start_pos`.
+    pub fn with_start_pos(next_block_start_pos: u64) -> Self {
         debug!("next_block_start_pos: 0x{:0x}", next_block_start_pos);
         Metadata::V1(MetadataV1 {
             num_blocks: 0,
@@ -38,14 +57,12 @@ impl Default for Metadata {
             tip_block_start_pos: Some(next_block_start_pos),
             next_block_start_pos,
             first_block_start_pos: next_block_start_pos,
+            block_chain_hashes: Vec::new(),
+            block_indexes_by_chain_hash: HashMap::new(),
+            block_start_positions: Vec::new(),
+            block_timestamps_ns: Vec::new(),
         })
     }
-}
-
-impl Metadata {
-    pub fn new() -> Self {
-        Metadata::default()
-    }
 
     pub fn clear(&mut self) {
         *self = Metadata::default();
@@ -93,6 +110,47 @@ impl Metadata {
         }
     }
 
+    /// The chain hash of the block with the given index, if there is such a block.
+    pub fn block_chain_hash(&self, index: usize) -> Option<&[u8]> {
+        match self {
+            Metadata::V1(metadata) => metadata.block_chain_hashes.get(index).map(Vec::as_slice),
+        }
+    }
+
+    /// The offset in the persistent storage of the block with the given index.
+    pub fn block_start_pos(&self, index: usize) -> Option<u64> {
+        match self {
+            Metadata::V1(metadata) => metadata.block_start_positions.get(index).copied(),
+        }
+    }
+
+    /// The timestamp of the block with the given index.
+    pub fn block_timestamp_ns(&self, index: usize) -> Option<u64> {
+        match self {
+            Metadata::V1(metadata) => metadata.block_timestamps_ns.get(index).copied(),
+        }
+    }
+
+    /// The index of the first block with a timestamp after `timestamp_ns`, if there is one.
+    pub fn first_block_index_after(&self, timestamp_ns: u64) -> Option<usize> {
+        match self {
+            Metadata::V1(metadata) => metadata
+                .block_timestamps_ns
+                .iter()
+                .position(|block_timestamp_ns| *block_timestamp_ns > timestamp_ns),
+        }
+    }
+
+    /// The index of the block with the given chain hash.
+    pub fn block_index_by_chain_hash(&self, chain_hash: &[u8]) -> Option<usize> {
+        match self {
+            Metadata::V1(metadata) => metadata
+                .block_indexes_by_chain_hash
+                .get(chain_hash)
+                .copied(),
+        }
+    }
+
     pub fn update_from_appended_block(
         &mut self,
         new_chain_hash: &[u8],
@@ -103,6 +161,12 @@ impl Metadata {
             Metadata::V1(metadata) => {
                 metadata.num_blocks += 1;
                 let block_start_pos = metadata.next_block_start_pos;
+                metadata
+                    .block_indexes_by_chain_hash
+                    .insert(new_chain_hash.to_vec(), metadata.block_chain_hashes.len());
+                metadata.block_chain_hashes.push(new_chain_hash.to_vec());
+                metadata.block_start_positions.push(block_start_pos);
+                metadata.block_timestamps_ns.push(block_timestamp_ns);
                 metadata.prev_block_start_pos = metadata.tip_block_start_pos;
                 metadata.tip_block_chain_hash = new_chain_hash.to_vec();
                 metadata.tip_block_timestamp_ns = block_timestamp_ns;
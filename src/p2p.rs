@@ -0,0 +1,499 @@
+/// This module implements peer-to-peer replication of a ledger over libp2p.
+///
+/// Peers periodically gossip the tip of their ledger (block count, chain hash and end offset)
+/// on a gossipsub topic. A peer that learns about a longer ledger requests the missing blocks
+/// from the peer that relayed the announcement, with the `/ledger-map/blocks/2` request-response
+/// protocol. The response carries the chain hash of the peer at the local height, and the blocks
+/// are only appended if `LedgerMap::check_fork_at` confirms that the peer extends the local
+/// chain. `LedgerMap::append_raw_blocks` then verifies that each block extends the local hash
+/// chain. Peers that announce or send blocks that do not verify are ignored for a while, with an
+/// exponential backoff.
+///
+/// Example usage:
+///
+/// ```rust,no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use ledger_map::p2p::{replicate, P2pConfig};
+///
+/// let config = P2pConfig {
+///     peers: vec!["/ip4/10.0.0.1/tcp/4001".parse()?],
+///     ..P2pConfig::default()
+/// };
+/// replicate(None, config).await?;
+/// # Ok(())
+/// # }
+/// ```
+use crate::ledger_thread::LedgerThread;
+use crate::{debug, info, warn, ForkStatus, LedgerMap};
+use borsh::{BorshDeserialize, BorshSerialize};
+use libp2p::futures::StreamExt;
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{gossipsub, noise, tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Gossipsub topic of the block announcements, unless configured otherwise.
+pub const DEFAULT_TOPIC: &str = "ledger-map/blocks";
+
+/// Protocol for fetching raw blocks from a peer.
+pub const BLOCKS_PROTOCOL: &str = "/ledger-map/blocks/2";
+
+/// How long a peer is ignored after its first invalid announcement or response. The time doubles
+/// with each further one, up to `MAX_BAD_PEER_BACKOFF`.
+const BAD_PEER_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Longest time a peer is ignored for invalid announcements or responses.
+const MAX_BAD_PEER_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+pub struct P2pConfig {
+    /// Addresses to listen on for connections of other peers.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Peers to connect to on startup.
+    pub peers: Vec<Multiaddr>,
+    /// Gossipsub topic of the block announcements. Peers replicating different ledgers must
+    /// use different topics.
+    pub topic: String,
+    /// How often the local ledger tip is announced to the other peers.
+    pub announce_interval: Duration,
+    /// Maximum number of block bytes sent or requested in a single response.
+    pub max_response_bytes: u64,
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        P2pConfig {
+            listen_addrs: vec!["/ip4/0.0.0.0/tcp/0"
+                .parse()
+                .expect("Invalid listen address")],
+            peers: Vec::new(),
+            topic: DEFAULT_TOPIC.to_string(),
+            announce_interval: Duration::from_secs(5),
+            max_response_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// Tip of the ledger of a peer, gossiped to the other peers.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockAnnouncement {
+    pub blocks_count: u64,
+    pub tip_hash: Vec<u8>,
+    pub next_block_start: u64,
+}
+
+impl BlockAnnouncement {
+    fn new(ledger_map: &LedgerMap) -> Self {
+        BlockAnnouncement {
+            blocks_count: ledger_map.get_blocks_count() as u64,
+            tip_hash: ledger_map.get_latest_block_hash(),
+            next_block_start: ledger_map.get_next_block_start_pos(),
+        }
+    }
+}
+
+/// Request for the raw blocks from the block with index `start_block` on, i.e. after the first
+/// `start_block` blocks of the requesting peer.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlocksRequest {
+    pub start_block: u64,
+    pub max_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlocksResponse {
+    /// Raw blocks, as returned by `LedgerMap::read_raw_blocks`. Empty if the peer does not have
+    /// blocks from the requested index on.
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>,
+    /// Number of blocks in the ledger of the peer.
+    pub blocks_count: u64,
+    /// Chain hash of the ledger of the peer.
+    #[serde(with = "serde_bytes")]
+    pub tip_hash: Vec<u8>,
+    /// Chain hash of the ledger of the peer after its first `start_block` blocks, i.e. at the
+    /// height of the requesting peer (empty for `start_block` 0), see `LedgerMap::check_fork_at`.
+    #[serde(with = "serde_bytes")]
+    pub hash_at_start: Vec<u8>,
+}
+
+impl BlocksResponse {
+    fn new(
+        ledger_map: &LedgerMap,
+        request: &BlocksRequest,
+        max_bytes: u64,
+    ) -> anyhow::Result<Self> {
+        let start_block = request.start_block as usize;
+        let blocks_count = ledger_map.get_blocks_count();
+        if start_block > blocks_count {
+            return Err(anyhow::format_err!("Block {} not found", start_block));
+        }
+        let (start, hash_at_start) = match start_block.checked_sub(1) {
+            None => (ledger_map.get_data_start_pos(), Vec::new()),
+            Some(index) => (
+                ledger_map
+                    .get_block_start_pos(start_block)
+                    .unwrap_or(ledger_map.get_next_block_start_pos()),
+                ledger_map.get_chain_hash_at(index).unwrap_or_default(),
+            ),
+        };
+        Ok(BlocksResponse {
+            bytes: ledger_map.read_raw_blocks(start, max_bytes)?,
+            blocks_count: blocks_count as u64,
+            tip_hash: ledger_map.get_latest_block_hash(),
+            hash_at_start,
+        })
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: gossipsub::Behaviour,
+    blocks: request_response::cbor::Behaviour<BlocksRequest, BlocksResponse>,
+}
+
+struct Replicator {
+    swarm: Swarm<Behaviour>,
+    ledger: LedgerThread,
+    topic: gossipsub::IdentTopic,
+    max_response_bytes: u64,
+    local_tip: BlockAnnouncement,
+    /// Peer with an outstanding blocks request, at most one at a time.
+    fetching_from: Option<PeerId>,
+    /// Peers that announced or sent blocks that did not verify: when they are ignored until, and
+    /// for how long they will be ignored after the next offence.
+    bad_peers: HashMap<PeerId, (Instant, Duration)>,
+}
+
+impl Replicator {
+    /// Load the blocks committed by the local writer since the last announcement, and announce
+    /// the tip of the ledger.
+    async fn announce(&mut self) -> anyhow::Result<()> {
+        self.local_tip = self
+            .ledger
+            .run(|ledger_map| {
+                ledger_map.refresh_new_blocks()?;
+                Ok(BlockAnnouncement::new(ledger_map))
+            })
+            .await?;
+        let data = borsh::to_vec(&self.local_tip)?;
+        if let Err(err) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(self.topic.clone(), data)
+        {
+            // Typically there are no peers subscribed to the topic yet
+            debug!("Not announcing the ledger tip: {}", err);
+        }
+        Ok(())
+    }
+
+    fn is_bad_peer(&self, peer: &PeerId) -> bool {
+        self.bad_peers
+            .get(peer)
+            .is_some_and(|(until, _)| Instant::now() < *until)
+    }
+
+    /// Ignore `peer` for a while, longer with each offence.
+    fn back_off(&mut self, peer: PeerId) {
+        let delay = match self.bad_peers.get(&peer) {
+            Some((_, delay)) => *delay,
+            None => BAD_PEER_BACKOFF,
+        };
+        warn!("Ignoring peer {} for {:?}", peer, delay);
+        self.bad_peers.insert(
+            peer,
+            (
+                Instant::now() + delay,
+                (delay * 2).min(MAX_BAD_PEER_BACKOFF),
+            ),
+        );
+    }
+
+    fn request_blocks(&mut self, peer: PeerId) {
+        let request = BlocksRequest {
+            start_block: self.local_tip.blocks_count,
+            max_bytes: self.max_response_bytes,
+        };
+        self.swarm
+            .behaviour_mut()
+            .blocks
+            .send_request(&peer, request);
+        self.fetching_from = Some(peer);
+    }
+
+    async fn on_announcement(&mut self, peer: PeerId, data: &[u8]) {
+        if self.is_bad_peer(&peer) || self.fetching_from.is_some() {
+            return;
+        }
+        let announcement = match BlockAnnouncement::try_from_slice(data) {
+            Ok(announcement) => announcement,
+            Err(err) => {
+                warn!("Invalid block announcement from {}: {}", peer, err);
+                self.back_off(peer);
+                return;
+            }
+        };
+        let tip_hash = announcement.tip_hash.clone();
+        let status = self
+            .ledger
+            .run(move |ledger_map| ledger_map.check_fork(&tip_hash, announcement.blocks_count))
+            .await;
+        match status {
+            // Only a longer chain can have blocks to fetch, which is verified with the response
+            Ok(ForkStatus::NeedsVerification) => {
+                debug!(
+                    "Peer {} announced {} blocks, fetching from block {}",
+                    peer, announcement.blocks_count, self.local_tip.blocks_count
+                );
+                self.request_blocks(peer);
+            }
+            Ok(ForkStatus::Fork) => debug!("Peer {} is on a fork of the ledger", peer),
+            Ok(_) => {}
+            Err(err) => warn!("Failed to check the ledger of {}: {}", peer, err),
+        }
+    }
+
+    async fn on_request(
+        &mut self,
+        request: BlocksRequest,
+        channel: request_response::ResponseChannel<BlocksResponse>,
+    ) {
+        let max_bytes = request.max_bytes.min(self.max_response_bytes);
+        let start_block = request.start_block;
+        let response = self
+            .ledger
+            .run(move |ledger_map| BlocksResponse::new(ledger_map, &request, max_bytes))
+            .await
+            .unwrap_or_else(|err| {
+                debug!("No blocks from block {}: {}", start_block, err);
+                BlocksResponse {
+                    bytes: Vec::new(),
+                    blocks_count: self.local_tip.blocks_count,
+                    tip_hash: self.local_tip.tip_hash.clone(),
+                    hash_at_start: Vec::new(),
+                }
+            });
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .blocks
+            .send_response(channel, response);
+    }
+
+    /// Append the blocks of `response` if the peer extends the local chain, as verified with
+    /// `LedgerMap::check_fork_at`, and fetch more blocks while they keep extending it.
+    async fn on_response(&mut self, peer: PeerId, response: BlocksResponse) {
+        self.fetching_from = None;
+        let applied = self
+            .ledger
+            .run(move |ledger_map| {
+                let status = ledger_map.check_fork_at(
+                    &response.tip_hash,
+                    response.blocks_count,
+                    &response.hash_at_start,
+                )?;
+                let appended = match status {
+                    ForkStatus::Ancestor if !response.bytes.is_empty() => {
+                        ledger_map.append_raw_blocks(&response.bytes)
+                    }
+                    ForkStatus::Ancestor => Err(anyhow::format_err!(
+                        "No blocks in the response, although the peer has more blocks"
+                    )),
+                    // The peer is not ahead (anymore), or on a fork
+                    status => {
+                        debug!("Not appending blocks from a peer with status {:?}", status);
+                        Ok(0)
+                    }
+                };
+                Ok((appended, BlockAnnouncement::new(ledger_map)))
+            })
+            .await;
+        match applied {
+            Ok((appended, local_tip)) => {
+                self.local_tip = local_tip;
+                match appended {
+                    Ok(0) => {}
+                    Ok(count) => {
+                        info!("Replicated {} blocks from {}", count, peer);
+                        self.bad_peers.remove(&peer);
+                        // Fetch more while the peer is ahead, which the next response verifies
+                        self.request_blocks(peer);
+                    }
+                    Err(err) => {
+                        warn!("Rejected blocks from {}: {}", peer, err);
+                        self.back_off(peer);
+                    }
+                }
+            }
+            Err(err) => warn!("Failed to apply blocks from {}: {}", peer, err),
+        }
+    }
+
+    async fn on_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Replicating ledger on {}", address);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message,
+                ..
+            })) => {
+                self.on_announcement(propagation_source, &message.data)
+                    .await
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Blocks(request_response::Event::Message {
+                peer,
+                message,
+                ..
+            })) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => self.on_request(request, channel).await,
+                request_response::Message::Response { response, .. } => {
+                    self.on_response(peer, response).await
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Blocks(
+                request_response::Event::OutboundFailure { peer, error, .. },
+            )) => {
+                warn!("Failed to fetch blocks from {}: {}", peer, error);
+                self.fetching_from = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Replicate the ledger stored at `path` (or in the default file, if `None`) with the peers,
+/// until the returned future is dropped or fails.
+pub async fn replicate(path: Option<PathBuf>, config: P2pConfig) -> anyhow::Result<()> {
+    let ledger = LedgerThread::spawn(path)?;
+    let swarm = libp2p::SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(|key| {
+            let gossipsub = gossipsub::Behaviour::new(
+                gossipsub::MessageAuthenticity::Signed(key.clone()),
+                gossipsub::Config::default(),
+            )?;
+            let blocks = request_response::cbor::Behaviour::new(
+                [(StreamProtocol::new(BLOCKS_PROTOCOL), ProtocolSupport::Full)],
+                request_response::Config::default(),
+            );
+            Ok(Behaviour { gossipsub, blocks })
+        })?
+        .with_swarm_config(|swarm_config| {
+            swarm_config.with_idle_connection_timeout(Duration::from_secs(60))
+        })
+        .build();
+
+    let mut replicator = Replicator {
+        swarm,
+        ledger,
+        topic: gossipsub::IdentTopic::new(&config.topic),
+        max_response_bytes: config.max_response_bytes,
+        local_tip: BlockAnnouncement {
+            blocks_count: 0,
+            tip_hash: Vec::new(),
+            next_block_start: 0,
+        },
+        fetching_from: None,
+        bad_peers: HashMap::new(),
+    };
+    let topic = replicator.topic.clone();
+    replicator
+        .swarm
+        .behaviour_mut()
+        .gossipsub
+        .subscribe(&topic)?;
+    for addr in config.listen_addrs {
+        replicator.swarm.listen_on(addr)?;
+    }
+    for addr in config.peers {
+        replicator.swarm.dial(addr)?;
+    }
+
+    let mut announce_interval = tokio::time::interval(config.announce_interval);
+    loop {
+        tokio::select! {
+            _ = announce_interval.tick() => replicator.announce().await?,
+            event = replicator.swarm.select_next_some() => replicator.on_event(event).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_announcement() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        ledger_map.upsert("label", b"key", b"value").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        let announcement = BlockAnnouncement::new(&ledger_map);
+        assert_eq!(announcement.blocks_count, 1);
+        assert_eq!(
+            announcement.next_block_start,
+            ledger_map.get_next_block_start_pos()
+        );
+        let data = borsh::to_vec(&announcement).unwrap();
+        assert_eq!(
+            BlockAnnouncement::try_from_slice(&data).unwrap(),
+            announcement
+        );
+    }
+
+    #[test]
+    fn test_blocks_response() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        for key in [b"key1", b"key2"] {
+            ledger_map.upsert("label", key, b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+        let request = |start_block| BlocksRequest {
+            start_block,
+            max_bytes: u64::MAX,
+        };
+
+        let response = BlocksResponse::new(&ledger_map, &request(1), u64::MAX).unwrap();
+        assert_eq!(response.blocks_count, 2);
+        assert_eq!(response.tip_hash, ledger_map.get_latest_block_hash());
+        assert_eq!(
+            Some(response.hash_at_start),
+            ledger_map.get_chain_hash_at(0)
+        );
+        assert_eq!(
+            response.bytes,
+            ledger_map
+                .read_raw_blocks(ledger_map.get_latest_block_start_pos(), u64::MAX)
+                .unwrap()
+        );
+
+        let response = BlocksResponse::new(&ledger_map, &request(0), u64::MAX).unwrap();
+        assert!(response.hash_at_start.is_empty());
+        assert!(BlocksResponse::new(&ledger_map, &request(2), u64::MAX)
+            .unwrap()
+            .bytes
+            .is_empty());
+        assert!(BlocksResponse::new(&ledger_map, &request(3), u64::MAX).is_err());
+    }
+}
@@ -9,10 +9,11 @@ use crate::platform_specific::{
     persistent_storage_grow, persistent_storage_read, persistent_storage_size_bytes,
     persistent_storage_write, PERSISTENT_STORAGE_PAGE_SIZE,
 };
-use crate::{debug, info};
+use crate::{debug, info, warn};
 
 pub const PARTITION_TABLE_START_OFFSET: u64 = 0;
 pub const PARTITION_TABLE_MAX_ENTRIES: usize = 128;
+pub const DEFAULT_DATA_PARTITION_START_LBA: u64 = 8 * 1024 * 1024;
 const EXPECTED_MAGIC_BYTES: [u8; 8] = [0x4c, 0x65, 0x64, 0x67, 0x50, 0x61, 0x72, 0x74]; // "LedgPart"
 
 #[derive(Serialize, Clone, Debug)]
@@ -154,11 +155,18 @@ impl PartitionTable {
             ))
             .unwrap();
         table
-            .add_new_entry(PartitionTableEntry::new(b"DATA", 8 * 1024 * 1024))
+            .add_new_entry(PartitionTableEntry::new(
+                b"DATA",
+                DEFAULT_DATA_PARTITION_START_LBA,
+            ))
             .unwrap();
         table
     }
 
+    pub fn builder() -> PartitionTableBuilder {
+        PartitionTableBuilder::new()
+    }
+
     pub fn size() -> usize {
         PartitionTableHeader::size() + PARTITION_TABLE_MAX_ENTRIES * PartitionTableEntry::size()
     }
@@ -186,7 +194,7 @@ impl PartitionTable {
         let mut entries = Vec::new();
         let mut num_entries = 0;
 
-        for i in 0..PARTITION_TABLE_MAX_ENTRIES {
+        for i in 0..PART_JOURNAL {
             let entry_offset = PartitionTableHeader::size() + i * PartitionTableEntry::size();
             let entry = PartitionTableEntry::from_bytes(
                 &buf[entry_offset..entry_offset + PartitionTableEntry::size()],
@@ -220,7 +228,7 @@ impl PartitionTable {
             buf[offset..offset + PartitionTableEntry::size()].copy_from_slice(&entry.to_bytes());
         }
 
-        persistent_storage_write(PARTITION_TABLE_START_OFFSET, &buf);
+        persistent_storage_write(PARTITION_TABLE_START_OFFSET, &buf)?;
         info!(
             "Wrote {} bytes of partition table to persistent storage at LBA {}",
             buf.len(),
@@ -229,8 +237,45 @@ impl PartitionTable {
         Ok(())
     }
 
+    /// Persist this partition table, unless the persistent storage already holds a valid one.
+    /// Returns the partition table that is in effect afterwards, so that an existing ledger
+    /// always keeps the layout it was created with.
+    pub fn persist_if_uninitialized(&self) -> Result<PartitionTable, String> {
+        if persistent_storage_size_bytes() >= Self::required_size_bytes() {
+            let mut buf = vec![0; PartitionTableHeader::size()];
+            persistent_storage_read(PARTITION_TABLE_START_OFFSET, &mut buf)?;
+            if PartitionTableHeader::from_bytes(&buf).is_ok() {
+                let existing = Self::read_from_persistent_storage()?;
+                if existing.entries != self.entries {
+                    warn!(
+                        "Persistent storage already has a different partition table, keeping it: {}",
+                        existing
+                    );
+                }
+                return Ok(existing);
+            }
+        }
+        self.persist()?;
+        Ok(self.clone())
+    }
+
+    /// Check that all partitions start after the partition table itself, in ascending order.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut prev_start_lba = Self::required_size_bytes();
+        for entry in self.entries.iter().skip(PART_DATA) {
+            if entry.start_lba < prev_start_lba {
+                return Err(format!(
+                    "Partition [{}] starts at {}, expected at least {}",
+                    entry, entry.start_lba, prev_start_lba
+                ));
+            }
+            prev_start_lba = entry.start_lba;
+        }
+        Ok(())
+    }
+
     pub fn add_new_entry(&mut self, entry: PartitionTableEntry) -> Result<(), String> {
-        if self.num_entries as usize >= PARTITION_TABLE_MAX_ENTRIES {
+        if self.num_entries as usize >= PART_JOURNAL {
             return Err("Partition table full".to_string());
         }
         self.entries.push(entry);
@@ -284,6 +329,58 @@ impl std::fmt::Display for PartitionTable {
     }
 }
 
+/// Builder for a custom partition table layout, to be used when creating a new ledger.
+///
+/// The partition table itself always starts at `PARTITION_TABLE_START_OFFSET` and occupies
+/// `PartitionTable::size()` bytes. Everything between the end of the partition table and the
+/// start of the data partition is reserved, so a smaller data partition start offset results
+/// in a smaller reserved header region.
+#[derive(Clone, Debug)]
+pub struct PartitionTableBuilder {
+    data_start_lba: u64,
+}
+
+impl Default for PartitionTableBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartitionTableBuilder {
+    pub fn new() -> Self {
+        PartitionTableBuilder {
+            data_start_lba: DEFAULT_DATA_PARTITION_START_LBA,
+        }
+    }
+
+    /// Set the offset in the persistent storage where the data partition (the ledger blocks) starts.
+    pub fn data_partition_start(mut self, start_lba: u64) -> Self {
+        self.data_start_lba = start_lba;
+        self
+    }
+
+    pub fn build(self) -> Result<PartitionTable, String> {
+        if self.data_start_lba < PartitionTable::required_size_bytes() {
+            return Err(format!(
+                "Data partition start {} overlaps the partition table, which requires {} bytes",
+                self.data_start_lba,
+                PartitionTable::required_size_bytes()
+            ));
+        }
+        let mut table = PartitionTable {
+            num_entries: 0,
+            header: PartitionTableHeader::new(),
+            entries: Vec::with_capacity(PARTITION_TABLE_MAX_ENTRIES),
+        };
+        table.add_new_entry(PartitionTableEntry::new(
+            b"PARTTABL",
+            PartitionTableHeader::size() as u64,
+        ))?;
+        table.add_new_entry(PartitionTableEntry::new(b"DATA", self.data_start_lba))?;
+        Ok(table)
+    }
+}
+
 pub fn get_partition_table() -> PartitionTable {
     PartitionTable::read_from_persistent_storage().unwrap_or_default()
 }
@@ -298,6 +395,42 @@ pub fn get_data_partition() -> PartitionTableEntry {
 
 pub const PART_RESERVED: usize = 0;
 pub const PART_DATA: usize = 1;
+/// The last entry of the partition table is never a partition. While the blocks of a ledger are
+/// compacted in place (see `LedgerMap::prune_blocks`), it holds the offset of the journal of the
+/// current compaction step, so that an interrupted step is completed when the ledger is loaded
+/// again. `PartitionTable::persist` clears it.
+pub const PART_JOURNAL: usize = PARTITION_TABLE_MAX_ENTRIES - 1;
+const JOURNAL_ENTRY_NAME: &[u8] = b"JOURNAL";
+
+fn journal_entry_offset() -> u64 {
+    PARTITION_TABLE_START_OFFSET
+        + (PartitionTableHeader::size() + PART_JOURNAL * PartitionTableEntry::size()) as u64
+}
+
+/// Offset of the pending compaction journal, if any, see `PART_JOURNAL`.
+pub fn read_journal_offset() -> Result<Option<u64>, String> {
+    if persistent_storage_size_bytes() < PartitionTable::required_size_bytes() {
+        return Ok(None);
+    }
+    let mut buf = [0u8; 16];
+    persistent_storage_read(journal_entry_offset(), &mut buf)?;
+    let entry = PartitionTableEntry::from_bytes(&buf)?;
+    Ok(
+        (entry == PartitionTableEntry::new(JOURNAL_ENTRY_NAME, entry.start_lba))
+            .then_some(entry.start_lba),
+    )
+}
+
+/// Record the offset of the pending compaction journal, or clear it with `None`, see
+/// `PART_JOURNAL`.
+pub fn write_journal_offset(offset: Option<u64>) -> Result<(), String> {
+    let entry = match offset {
+        Some(offset) => PartitionTableEntry::new(JOURNAL_ENTRY_NAME, offset),
+        None => PartitionTableEntry::default(),
+    };
+    persistent_storage_write(journal_entry_offset(), &entry.to_bytes())?;
+    Ok(())
+}
 
 #[cfg(test)]
 mod tests {
@@ -321,7 +454,7 @@ mod tests {
     fn test_persistent_storage_read_and_write() {
         let file_path = tempfile::tempdir()
             .unwrap()
-            .into_path()
+            .keep()
             .join("test_ledger_store.bin");
         crate::platform_specific::set_backing_file(Some(file_path)).unwrap();
 
@@ -334,6 +467,77 @@ mod tests {
         assert_eq!(table.header.magic_bytes, read_table.header.magic_bytes);
         assert_eq!(table.num_entries, read_table.num_entries);
         assert_eq!(table.entries, read_table.entries);
+
+        // The journal entry is not a partition, and persisting the table clears it
+        assert_eq!(read_journal_offset(), Ok(None));
+        write_journal_offset(Some(12345)).unwrap();
+        assert_eq!(read_journal_offset(), Ok(Some(12345)));
+        let read_table = PartitionTable::read_from_persistent_storage().unwrap();
+        assert_eq!(table.entries, read_table.entries);
+        write_journal_offset(None).unwrap();
+        assert_eq!(read_journal_offset(), Ok(None));
+        write_journal_offset(Some(12345)).unwrap();
+        table.persist().unwrap();
+        assert_eq!(read_journal_offset(), Ok(None));
+    }
+
+    #[test]
+    fn test_partition_table_builder() {
+        let table = PartitionTable::builder().build().unwrap();
+        assert_eq!(table.entries, PartitionTable::new().entries);
+
+        let table = PartitionTable::builder()
+            .data_partition_start(4096)
+            .build()
+            .unwrap();
+        assert_eq!(table.num_entries, 2);
+        assert_eq!(table.entries[PART_DATA].start_lba, 4096);
+
+        assert!(PartitionTable::builder()
+            .data_partition_start(PartitionTable::required_size_bytes() - 1)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_partition_table_validate() {
+        let mut table = PartitionTable::new();
+        assert!(table.validate().is_ok());
+
+        table.entries[PART_DATA].start_lba = PartitionTable::required_size_bytes() - 1;
+        assert!(table.validate().is_err());
+
+        table.entries[PART_DATA].start_lba = 8192;
+        table
+            .add_new_entry(PartitionTableEntry::new(b"TESTPART", 4096))
+            .unwrap();
+        assert!(table.validate().is_err());
+    }
+
+    #[test]
+    fn test_persist_if_uninitialized() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        crate::platform_specific::set_backing_file(Some(file_path)).unwrap();
+
+        let table = PartitionTable::builder()
+            .data_partition_start(4096)
+            .build()
+            .unwrap();
+        let in_effect = table.persist_if_uninitialized().unwrap();
+        assert_eq!(in_effect.entries, table.entries);
+        assert_eq!(get_data_partition().start_lba, 4096);
+
+        // A subsequent open with a different layout keeps the persisted one
+        let other = PartitionTable::builder()
+            .data_partition_start(8192)
+            .build()
+            .unwrap();
+        let in_effect = other.persist_if_uninitialized().unwrap();
+        assert_eq!(in_effect.entries, table.entries);
+        assert_eq!(get_data_partition().start_lba, 4096);
     }
 
     #[test]
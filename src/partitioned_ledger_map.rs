@@ -0,0 +1,213 @@
+/// This module implements a LedgerMap that keeps groups of labels in separate backing files.
+///
+/// Each group of labels has its own, independent, block chain in its own backing file, so that
+/// high-churn labels can be compacted or archived without touching the rest of the ledger.
+/// Labels that are not assigned to any group are stored in the default backing file.
+///
+/// Each group is a `LedgerMap` with its own backing file, which it activates itself before every
+/// access to its persistent storage. Refreshing a group reopens its backing file, which may have
+/// been replaced in the meantime, e.g. by an archived copy.
+use crate::errors::LedgerError;
+use crate::ledger_entry::{EntryValue, LedgerEntry};
+use crate::ledger_map::LedgerMap;
+use crate::{platform_specific, AHashMap};
+use std::path::PathBuf;
+
+const DEFAULT_PARTITION: usize = 0;
+
+#[derive(Debug)]
+struct LabelPartition {
+    path: PathBuf,
+    ledger_map: LedgerMap,
+}
+
+#[derive(Debug)]
+pub struct LabelPartitionedLedgerMap {
+    partitions: Vec<LabelPartition>,
+    label_to_partition: AHashMap<String, usize>,
+}
+
+impl LabelPartitionedLedgerMap {
+    /// Create a new LabelPartitionedLedgerMap instance.
+    /// Labels in each of `label_partitions` are stored in the accompanying backing file,
+    /// and all other labels are stored in `default_path`.
+    pub fn new(
+        default_path: PathBuf,
+        label_partitions: Vec<(Vec<String>, PathBuf)>,
+    ) -> anyhow::Result<Self> {
+        let mut partitions = vec![LabelPartition {
+            ledger_map: LedgerMap::new_with_path(None, Some(default_path.clone()))?,
+            path: default_path,
+        }];
+        let mut label_to_partition = AHashMap::default();
+        for (labels, path) in label_partitions {
+            if partitions.iter().any(|p| p.path == path) {
+                return Err(anyhow::format_err!(
+                    "Backing file {:?} is used by more than one partition",
+                    path
+                ));
+            }
+            for label in labels.iter() {
+                if label_to_partition
+                    .insert(label.clone(), partitions.len())
+                    .is_some()
+                {
+                    return Err(anyhow::format_err!(
+                        "Label {} is assigned to more than one partition",
+                        label
+                    ));
+                }
+            }
+            partitions.push(LabelPartition {
+                ledger_map: LedgerMap::new_with_path(Some(labels), Some(path.clone()))?,
+                path,
+            });
+        }
+        Ok(LabelPartitionedLedgerMap {
+            partitions,
+            label_to_partition,
+        })
+    }
+
+    fn partition_index(&self, label: &str) -> usize {
+        self.label_to_partition
+            .get(label)
+            .copied()
+            .unwrap_or(DEFAULT_PARTITION)
+    }
+
+    /// Returns the backing file in which the given label is stored.
+    pub fn get_file_path_for_label<S: AsRef<str>>(&self, label: S) -> PathBuf {
+        self.partitions[self.partition_index(label.as_ref())]
+            .path
+            .clone()
+    }
+
+    /// Returns the LedgerMap that stores the given label, e.g. to inspect its blocks.
+    pub fn ledger_map_for_label<S: AsRef<str>>(&self, label: S) -> &LedgerMap {
+        &self.partitions[self.partition_index(label.as_ref())].ledger_map
+    }
+
+    pub fn get<S: AsRef<str>>(&self, label: S, key: &[u8]) -> Result<EntryValue, LedgerError> {
+        self.ledger_map_for_label(label.as_ref()).get(label, key)
+    }
+
+    pub fn upsert<S: AsRef<str>, K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        label: S,
+        key: K,
+        value: V,
+    ) -> Result<(), LedgerError> {
+        let index = self.partition_index(label.as_ref());
+        self.partitions[index].ledger_map.upsert(label, key, value)
+    }
+
+    pub fn delete<S: AsRef<str>, K: AsRef<[u8]>>(
+        &mut self,
+        label: S,
+        key: K,
+    ) -> Result<(), LedgerError> {
+        let index = self.partition_index(label.as_ref());
+        self.partitions[index].ledger_map.delete(label, key)
+    }
+
+    pub fn iter(&self, label: &str) -> impl Iterator<Item = &LedgerEntry> {
+        self.ledger_map_for_label(label).iter(Some(label))
+    }
+
+    /// Commit the pending entries of every partition, each into its own block chain.
+    pub fn commit_block(&mut self) -> anyhow::Result<()> {
+        for partition in self.partitions.iter_mut() {
+            partition.ledger_map.commit_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn refresh_ledger(&mut self) -> anyhow::Result<()> {
+        for index in 0..self.partitions.len() {
+            self.reopen(index)?;
+        }
+        Ok(())
+    }
+
+    /// Refresh only the partition that stores the given label, e.g. after it has been
+    /// compacted or restored from an archive.
+    pub fn refresh_partition_for_label<S: AsRef<str>>(&mut self, label: S) -> anyhow::Result<()> {
+        let index = self.partition_index(label.as_ref());
+        self.reopen(index)
+    }
+
+    /// Reopen the backing file of the partition with index `index`, and reload its ledger.
+    fn reopen(&mut self, index: usize) -> anyhow::Result<()> {
+        let partition = &mut self.partitions[index];
+        platform_specific::set_backing_file(Some(partition.path.clone()))
+            .map_err(|e| anyhow::format_err!("{:?}", e))?;
+        partition.ledger_map.refresh_from_active_storage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_partitioned_ledger_map() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let default_path = dir.join("default.bin");
+        let churn_path = dir.join("churn.bin");
+        let mut ledger_map = LabelPartitionedLedgerMap::new(
+            default_path.clone(),
+            vec![(vec!["Churn".to_string()], churn_path.clone())],
+        )
+        .unwrap();
+        assert_eq!(ledger_map.get_file_path_for_label("Churn"), churn_path);
+        assert_eq!(ledger_map.get_file_path_for_label("Other"), default_path);
+
+        ledger_map.upsert("Stable", b"key1", b"value1").unwrap();
+        ledger_map.upsert("Churn", b"key2", b"value2").unwrap();
+        ledger_map.commit_block().unwrap();
+        ledger_map.upsert("Churn", b"key2", b"value3").unwrap();
+        ledger_map.commit_block().unwrap();
+
+        assert_eq!(ledger_map.get("Stable", b"key1").unwrap(), b"value1");
+        assert_eq!(ledger_map.get("Churn", b"key2").unwrap(), b"value3");
+        assert_eq!(
+            ledger_map.ledger_map_for_label("Stable").get_blocks_count(),
+            1
+        );
+        assert_eq!(
+            ledger_map.ledger_map_for_label("Churn").get_blocks_count(),
+            2
+        );
+        assert_eq!(ledger_map.iter("Churn").count(), 1);
+
+        // Each partition is a standalone ledger, which can be archived independently
+        let churn_only = LedgerMap::new_with_path(None, Some(churn_path.clone())).unwrap();
+        assert_eq!(churn_only.iter(Some("Stable")).count(), 0);
+        assert_eq!(churn_only.get("Churn", b"key2").unwrap(), b"value3");
+
+        fs_err::remove_file(&churn_path).unwrap();
+        ledger_map.refresh_partition_for_label("Churn").unwrap();
+        assert_eq!(
+            ledger_map.get("Churn", b"key2").unwrap_err(),
+            LedgerError::EntryNotFound
+        );
+        assert_eq!(ledger_map.get("Stable", b"key1").unwrap(), b"value1");
+
+        ledger_map.refresh_ledger().unwrap();
+        assert_eq!(ledger_map.get("Stable", b"key1").unwrap(), b"value1");
+    }
+
+    #[test]
+    fn test_label_in_multiple_partitions() {
+        let dir = tempfile::tempdir().unwrap().keep();
+        let result = LabelPartitionedLedgerMap::new(
+            dir.join("default.bin"),
+            vec![
+                (vec!["Label1".to_string()], dir.join("first.bin")),
+                (vec!["Label1".to_string()], dir.join("second.bin")),
+            ],
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! This module contains the mobile (iOS and Android) specifics of the native platform layer. The
+//! storage backends of `platform_specific_x86_64` use it to find the default directory of the
+//! ledger in the app sandbox, and to open and flush the backing file:
+//!
+//! - On iOS, the default directory is `Library/Application Support` in the app container. The
+//!   backing file gets a data protection class, by default the one of
+//!   `NSFileProtectionCompleteUntilFirstUserAuthentication`, so that the app can keep writing in
+//!   the background while the device is locked. Committed blocks are flushed with `F_FULLFSYNC`,
+//!   as `fsync` does not flush the drive cache on Apple platforms.
+//! - On Android, the default directory is the `files` directory of the internal storage of the
+//!   app, `/data/data/<package>/files`. Apps should rather pass `Context.getFilesDir()` to
+//!   `set_default_data_dir`, which also covers secondary users and profiles. Committed blocks are
+//!   flushed with `fdatasync`.
+//!
+//! `Durability::Sync` is the default on both, as the app may be killed at any time, and the
+//! backing file is flushed once per committed block rather than on every write.
+use fs_err::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Directory of the default backing file, if set with `set_default_data_dir`.
+static DEFAULT_DATA_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Use `data_dir` as the directory of the default backing file, e.g. the directory returned by
+/// `Context.getFilesDir()` on Android. Must be called before creating a LedgerMap without a path.
+pub fn set_default_data_dir(data_dir: PathBuf) {
+    *DEFAULT_DATA_DIR
+        .lock()
+        .expect("Failed to lock the default data dir") = Some(data_dir);
+}
+
+/// Directory of the default backing file in the app sandbox.
+pub(crate) fn default_data_dir() -> Option<PathBuf> {
+    if let Some(data_dir) = DEFAULT_DATA_DIR
+        .lock()
+        .expect("Failed to lock the default data dir")
+        .clone()
+    {
+        return Some(data_dir);
+    }
+    #[cfg(target_os = "ios")]
+    return dirs::data_local_dir();
+    #[cfg(target_os = "android")]
+    return std::fs::read("/proc/self/cmdline")
+        .ok()
+        .and_then(|cmdline| app_files_dir(&cmdline));
+}
+
+/// The `files` directory of the internal storage of the app, given the command line of the app
+/// process, which starts with the package name, optionally followed by `:<process>`.
+#[cfg(target_os = "android")]
+fn app_files_dir(cmdline: &[u8]) -> Option<PathBuf> {
+    let process_name = cmdline.split(|byte| *byte == 0).next()?;
+    let package = std::str::from_utf8(process_name).ok()?.split(':').next()?;
+    if package.is_empty() || package.contains('/') {
+        // Not an app process, e.g. a command line tool
+        return None;
+    }
+    Some(Path::new("/data/data").join(package).join("files"))
+}
+
+/// Data protection class of a file on iOS, see the `NSFileProtectionType` of the same name.
+#[cfg(target_os = "ios")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileProtectionClass {
+    /// The file can only be accessed while the device is unlocked.
+    Complete = 1,
+    /// An open file can still be accessed after the device is locked.
+    CompleteUnlessOpen = 2,
+    /// The file can be accessed after the device has been unlocked once after a restart.
+    CompleteUntilFirstUserAuthentication = 3,
+    /// The file is not protected.
+    None = 4,
+}
+
+/// `fcntl` command to set the data protection class of a file, from `<sys/fcntl.h>`.
+#[cfg(target_os = "ios")]
+const F_SETPROTECTIONCLASS: libc::c_int = 64;
+
+#[cfg(target_os = "ios")]
+static FILE_PROTECTION_CLASS: Mutex<FileProtectionClass> =
+    Mutex::new(FileProtectionClass::CompleteUntilFirstUserAuthentication);
+
+/// Data protection class of the backing files opened from now on.
+#[cfg(target_os = "ios")]
+pub fn set_file_protection_class(protection_class: FileProtectionClass) {
+    *FILE_PROTECTION_CLASS
+        .lock()
+        .expect("Failed to lock the file protection class") = protection_class;
+}
+
+/// Open the backing file at `file_path`, creating it if needed. On iOS, also set the data
+/// protection class of the file.
+pub(crate) fn open_backing_file(file_path: &Path) -> Result<File, String> {
+    let file = fs_err::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(file_path)
+        .map_err(|e| e.to_string())?;
+    #[cfg(target_os = "ios")]
+    {
+        use std::os::fd::AsRawFd;
+        let protection_class = *FILE_PROTECTION_CLASS
+            .lock()
+            .expect("Failed to lock the file protection class");
+        // SAFETY: the file descriptor is valid while `file` is open.
+        let result = unsafe {
+            libc::fcntl(
+                file.as_raw_fd(),
+                F_SETPROTECTIONCLASS,
+                protection_class as libc::c_int,
+            )
+        };
+        if result == -1 {
+            return Err(format!(
+                "Failed to set the protection class {:?} of backing file {:?}: {}",
+                protection_class,
+                file_path,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(file)
+}
+
+/// Flush the writes to the backing file to the disk.
+pub(crate) fn flush_backing_file(file: &File) -> Result<(), String> {
+    #[cfg(target_os = "ios")]
+    {
+        use std::os::fd::AsRawFd;
+        // SAFETY: the file descriptor is valid while `file` is open.
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_FULLFSYNC) } == -1 {
+            return Err(format!(
+                "Failed to flush backing file {:?}: {}",
+                file.path(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+    #[cfg(target_os = "android")]
+    file.sync_data()
+        .map_err(|e| format!("Failed to flush backing file {:?}: {}", file.path(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "android")]
+    #[test]
+    fn test_app_files_dir() {
+        assert_eq!(
+            app_files_dir(b"com.example.app\0"),
+            Some(PathBuf::from("/data/data/com.example.app/files"))
+        );
+        assert_eq!(
+            app_files_dir(b"com.example.app:sync\0"),
+            Some(PathBuf::from("/data/data/com.example.app/files"))
+        );
+        assert_eq!(app_files_dir(b"/system/bin/sh\0-c\0"), None);
+        assert_eq!(app_files_dir(b""), None);
+    }
+
+    #[test]
+    fn test_set_default_data_dir() {
+        let data_dir = tempfile::tempdir().unwrap().keep();
+        set_default_data_dir(data_dir.clone());
+        assert_eq!(default_data_dir(), Some(data_dir));
+    }
+}
@@ -1,8 +1,14 @@
+use crate::ledger_entry::LedgerBlockHeader;
+use crate::{Clock, LedgerError, SystemClock};
 use anyhow::Result;
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use js_sys::Error;
 use std::cell::RefCell;
+use std::io::{Read, Write};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast; // for `dyn_ref`
 use web_sys::Storage;
@@ -16,17 +22,48 @@ use web_sys::Storage;
 /// - The entire ledger is held in ephemeral (in‑memory) storage, which is populated by JavaScript on page load.
 /// - Changes to the ledger in ephemeral storage must be explicitly committed to persistent storage if needed.
 ///
-/// Note that the ephemeral storage can be much larger than the last block. In such cases, only the last block should be
-/// persisted. The logic to determine the start of the last block is not known at this level, so the function
-/// `persist_last_block(block_start: u64)` is provided for higher-level callers to specify which part of the ledger
-/// constitutes the "last block."
-
-/// We store the "last block" (or relevant ledger data) in local storage using this key.
+/// Note that the ephemeral storage can be much larger than the last block. `persist_ledger` persists as many of the
+/// last blocks as fit in the persistent storage budget (see `set_persistent_storage_budget`), and
+/// `persist_last_block(block_start: u64)` persists everything from the given block start.
+/// The persisted data is zlib compressed, base64 encoded and split into chunks, each stored under its own local
+/// storage key. There are two slots of chunks: new data is written to the unused slot, and only then the manifest
+/// (`PERSISTENT_STORAGE_MANIFEST_KEY`) is switched to it, so that an interrupted or failed write keeps the previous
+/// data. Local storage therefore needs room for twice the persistent storage budget while persisting.
+
+/// Legacy key of the persisted data, from before it was split into chunks. Still read on initialization.
 const PERSISTENT_STORAGE_DATA_KEY: &str = "ledger_map_last_block";
 
-/// We store the offset of the last block in local storage under this key.
+/// Legacy key of the offset of the persisted data, from before the manifest. Still read on initialization.
 const PERSISTENT_STORAGE_OFFSET_KEY: &str = "ledger_map_last_block_offset";
 
+/// Legacy key of the number of chunks of the persisted data, from before the manifest. Still read on initialization.
+const PERSISTENT_STORAGE_CHUNKS_KEY: &str = "ledger_map_chunks";
+
+/// Legacy key of the compression of the persisted data. Data without it is not compressed.
+const PERSISTENT_STORAGE_COMPRESSION_KEY: &str = "ledger_map_compression";
+
+/// Value of `PERSISTENT_STORAGE_COMPRESSION_KEY` for zlib compressed data.
+const PERSISTENT_STORAGE_COMPRESSION_ZLIB: &str = "zlib";
+
+/// We store the slot, offset, number of chunks and compression of the persisted data under this key, as
+/// `<slot> <offset> <chunks> <compression>`. It is written last, switching to the newly persisted data at once.
+const PERSISTENT_STORAGE_MANIFEST_KEY: &str = "ledger_map_manifest";
+
+/// Each chunk of the persisted data is stored under this key prefix, followed by the chunk index for slot 0, or
+/// by the slot and the chunk index for slot 1, see `chunk_key`.
+const PERSISTENT_STORAGE_CHUNK_KEY_PREFIX: &str = "ledger_map_chunk_";
+
+/// Maximum length of a single chunk of the persisted (base64 encoded) data.
+const PERSISTENT_STORAGE_CHUNK_LEN: usize = 512 * 1024;
+
+/// We store the latest timestamp handed out by `SystemClock` under this key, so that block timestamps do not go
+/// backwards across sessions.
+const CLOCK_LAST_TIMESTAMP_KEY: &str = "ledger_map_clock_last_ns";
+
+/// Default budget for the persisted (base64 encoded) data. Two copies fit in the ~5MB local storage while
+/// persisting, leaving some of it for others.
+pub const DEFAULT_PERSISTENT_STORAGE_BUDGET_BYTES: u64 = 2 * 1024 * 1024;
+
 thread_local! {
     /// Ephemeral (in‑memory) ledger data. May be larger than what we persist.
     static EPHEMERAL_STORAGE: RefCell<Vec<u8>> = RefCell::new(Vec::new());
@@ -40,6 +77,16 @@ thread_local! {
     /// Browser local storage handle, if available.
     /// If multi-threading is introduced in the future, you may need to synchronize access here.
     static PERSISTENT_LOCAL_STORAGE: RefCell<Option<Storage>> = RefCell::new(None);
+
+    /// Maximum number of bytes of (base64 encoded) data persisted in local storage.
+    static PERSISTENT_STORAGE_BUDGET_BYTES: RefCell<u64> = const { RefCell::new(DEFAULT_PERSISTENT_STORAGE_BUDGET_BYTES) };
+
+    /// Start offset of the data last persisted by `persist_ledger`. The blocks that fit in the budget only move
+    /// forward as the ledger grows, so the next `persist_ledger` starts searching from there.
+    static PERSISTED_START: RefCell<Option<u64>> = const { RefCell::new(None) };
+
+    /// Timestamp in nanoseconds at `performance.now() == 0`, set at the first use of `SystemClock`.
+    static CLOCK_ORIGIN_NS: RefCell<Option<u64>> = const { RefCell::new(None) };
 }
 
 //-------------------------------------
@@ -97,21 +144,38 @@ fn is_storage_initialized() -> bool {
     PERSISTENT_LOCAL_STORAGE.with(|ls| ls.borrow().is_some())
 }
 
+/// Returns whether the ledger data can be persisted in the browser local storage.
+/// Local storage is not available in Web Workers, where only the ephemeral storage is used.
+#[wasm_bindgen]
+pub fn is_persistent_storage_available() -> bool {
+    is_storage_initialized()
+}
+
+/// Returns the browser local storage, or `None` outside of a window, e.g. in a (Shared)Worker.
+fn window_local_storage() -> Option<Storage> {
+    let window = js_sys::global().dyn_into::<web_sys::Window>().ok()?;
+    let storage = window
+        .local_storage()
+        .expect("no local storage exists")
+        .expect("failed to get local storage");
+    Some(storage)
+}
+
 /// Ensures that local storage is initialized. Called automatically at startup.
 ///
 /// If you run in a browser environment with default WASM, this function
 /// is single-threaded, so no concurrency concerns arise here.
+/// In a (Shared)Worker there is no local storage, so the ledger is kept in ephemeral storage only.
 #[wasm_bindgen(start)]
 pub fn ensure_storage_is_initialized() {
     if is_storage_initialized() {
         return;
     }
 
-    let window = web_sys::window().expect("no global window exists");
-    let storage = window
-        .local_storage()
-        .expect("no local storage exists")
-        .expect("failed to get local storage");
+    let Some(storage) = window_local_storage() else {
+        warn!("No local storage outside of a window, the ledger data is not persisted");
+        return;
+    };
 
     PERSISTENT_LOCAL_STORAGE.with(|ls| {
         *ls.borrow_mut() = Some(storage);
@@ -122,12 +186,9 @@ pub fn ensure_storage_is_initialized() {
 /// Clears both browser local storage and the in-memory (ephemeral) storage.
 #[wasm_bindgen]
 pub fn clear_storage() {
-    let window = web_sys::window().expect("no global window exists");
-    let storage = window
-        .local_storage()
-        .expect("no local storage exists")
-        .expect("failed to get local storage");
-    storage.clear().expect("failed to clear local storage");
+    if let Some(storage) = window_local_storage() {
+        storage.clear().expect("failed to clear local storage");
+    }
 
     PERSISTENT_LOCAL_STORAGE.with(|ls| {
         *ls.borrow_mut() = None;
@@ -144,6 +205,7 @@ pub fn clear_ephemeral_storage() {
     });
     EPHEMERAL_STORAGE_VALID_BEGIN.with(|b| *b.borrow_mut() = 0);
     EPHEMERAL_STORAGE_VALID_END.with(|e| *e.borrow_mut() = 0);
+    PERSISTED_START.with(|start| *start.borrow_mut() = None);
 }
 
 /// Reads data from ephemeral storage only.
@@ -181,7 +243,7 @@ pub fn persistent_storage_read(offset: u64, buf: &mut [u8]) -> Result<(), String
 /// Resizes ephemeral storage if needed and updates the valid region.
 /// This function does NOT persist the data to browser local storage.
 /// To persist the latest block, call `persist_last_block`.
-pub fn persistent_storage_write(offset: u64, buf: &[u8]) {
+pub fn persistent_storage_write(offset: u64, buf: &[u8]) -> Result<(), LedgerError> {
     EPHEMERAL_STORAGE.with(|es| {
         let mut storage = es.borrow_mut();
         let current_len = storage.len() as u64;
@@ -209,10 +271,16 @@ pub fn persistent_storage_write(offset: u64, buf: &[u8]) {
             }
         });
     });
+    Ok(())
 }
 
 pub const PERSISTENT_STORAGE_PAGE_SIZE: u64 = 64 * 1024;
 
+/// Nothing to sync: the blocks are persisted to the browser storage by `persist_last_block`.
+pub fn persistent_storage_sync() -> Result<(), String> {
+    Ok(())
+}
+
 pub fn persistent_storage_grow(additional_pages: u64) -> Result<u64, String> {
     debug!(
         "persistent_storage_grow: {} additional_pages.",
@@ -235,6 +303,11 @@ pub fn persistent_storage_size_bytes() -> u64 {
     EPHEMERAL_STORAGE.with(|es| es.borrow().len() as u64)
 }
 
+/// Returns the first valid offset in the ephemeral storage buffer.
+pub fn persistent_storage_first_valid_offset() -> u64 {
+    EPHEMERAL_STORAGE_VALID_BEGIN.with(|b| *b.borrow())
+}
+
 /// Returns the last valid offset in the ephemeral storage buffer.
 pub fn persistent_storage_last_valid_offset() -> u64 {
     EPHEMERAL_STORAGE_VALID_END.with(|e| *e.borrow())
@@ -245,23 +318,18 @@ pub fn persistent_storage_last_valid_offset() -> u64 {
 /// and valid offsets are set to 0.
 pub fn init_ephemeral_storage_from_persistent() -> Result<(), String> {
     info!("Initializing ephemeral storage from persistent storage.");
-    let (persistent_data, persistent_offset) = PERSISTENT_LOCAL_STORAGE.with(|ls| {
-        if let Some(storage) = &*ls.borrow() {
-            (
-                storage.get_item(PERSISTENT_STORAGE_DATA_KEY).ok().flatten(),
-                storage
-                    .get_item(PERSISTENT_STORAGE_OFFSET_KEY)
-                    .ok()
-                    .flatten(),
-            )
-        } else {
-            (None, None)
-        }
-    });
+    let (persistent_data, persistent_offset, compression) =
+        PERSISTENT_LOCAL_STORAGE.with(|ls| match &*ls.borrow() {
+            Some(storage) => read_persisted_data(storage),
+            None => (None, None, None),
+        });
 
     match (persistent_data, persistent_offset) {
         (Some(data), Some(offset)) => {
-            let decoded = decode_bytes(&data);
+            let decoded = match compression.as_deref() {
+                Some(PERSISTENT_STORAGE_COMPRESSION_ZLIB) => decompress_bytes(&decode_bytes(&data)),
+                _ => decode_bytes(&data),
+            };
             if decoded.is_empty() {
                 error!("Persistent ledger data was corrupted or invalid base64; resetting ephemeral storage.");
                 report_and_recover_corrupted_ledger();
@@ -311,66 +379,294 @@ fn report_and_recover_corrupted_ledger() {
     EPHEMERAL_STORAGE_VALID_END.with(|e| *e.borrow_mut() = 0);
 }
 
-/// Persists the last block of the ledger (from `block_start` to the end of ephemeral storage)
-/// in the browser local storage. Overwrites any previous ledger data in local storage.
+/// Sets the maximum number of bytes of (base64 encoded) ledger data persisted in local storage.
+pub fn set_persistent_storage_budget(budget_bytes: u64) {
+    PERSISTENT_STORAGE_BUDGET_BYTES.with(|b| *b.borrow_mut() = budget_bytes);
+    // With a larger budget, more of the earlier blocks may fit again
+    PERSISTED_START.with(|start| *start.borrow_mut() = None);
+}
+
+pub fn get_persistent_storage_budget() -> u64 {
+    PERSISTENT_STORAGE_BUDGET_BYTES.with(|b| *b.borrow())
+}
+
+/// Persists the ledger data (from `block_start` to the end of ephemeral storage) in the browser local storage.
+/// Replaces any previous ledger data in local storage, once the new data is written.
 pub fn persist_last_block(block_start: u64) -> Result<(), String> {
+    persist_from(block_start).map_err(String::from)
+}
+
+/// Persists as many blocks as fit in the persistent storage budget, ideally all blocks from `data_start`,
+/// otherwise the longest tail of blocks, down to the last block only. Returns the offset that the persisted
+/// data starts at. Fails with `LedgerError::QuotaExceeded` if not even the last block can be persisted.
+///
+/// The search starts at the blocks persisted last time, so each call compresses about as much data as fits in
+/// the budget, rather than the whole ledger.
+pub fn persist_ledger(data_start: u64) -> Result<u64, LedgerError> {
+    let data_end = persistent_storage_last_valid_offset();
+    let budget_bytes = get_persistent_storage_budget();
+    let block_starts = block_starts(data_start, data_end)?;
+    let Some(&last_block_start) = block_starts.last() else {
+        return Ok(data_end);
+    };
+    let first_candidate = PERSISTED_START
+        .with(|start| *start.borrow())
+        .and_then(|start| block_starts.binary_search(&start).ok())
+        .unwrap_or_default();
+    let candidates = &block_starts[first_candidate..];
+    // The compressed size shrinks with each dropped block, so find the first block that fits by bisection
+    let first_fitting = candidates.partition_point(|&block_start| {
+        !encode_from(block_start).is_ok_and(|encoded| encoded.len() as u64 <= budget_bytes)
+    });
+    let mut last_err = None;
+    for &block_start in &candidates[first_fitting..] {
+        // The browser quota may be lower than the budget, in which case fewer blocks are tried next
+        match persist_from(block_start) {
+            Ok(()) => {
+                PERSISTED_START.with(|start| *start.borrow_mut() = Some(block_start));
+                return Ok(block_start);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    let err = match last_err {
+        Some(err) => err,
+        None => LedgerError::QuotaExceeded {
+            quota_bytes: budget_bytes,
+            required_bytes: encode_from(last_block_start)?.len() as u64,
+        },
+    };
+    error!("Failed to persist the ledger: {}", err);
+    Err(err)
+}
+
+/// Returns the start offsets of the blocks between `data_start` and `data_end`, from the headers in ephemeral storage.
+fn block_starts(data_start: u64, data_end: u64) -> Result<Vec<u64>, LedgerError> {
+    let mut block_starts = Vec::new();
+    let mut block_start = data_start;
+    let mut buf = [0u8; LedgerBlockHeader::sizeof()];
+    while block_start + LedgerBlockHeader::sizeof() as u64 <= data_end {
+        persistent_storage_read(block_start, &mut buf).map_err(LedgerError::BlockCorrupted)?;
+        let jump_bytes_next_block = match LedgerBlockHeader::deserialize(&buf) {
+            Ok(header) => header.jump_bytes_next_block(),
+            Err(LedgerError::BlockEmpty) => break,
+            Err(err) => return Err(err),
+        };
+        if jump_bytes_next_block == 0 {
+            break;
+        }
+        block_starts.push(block_start);
+        block_start += jump_bytes_next_block as u64;
+    }
+    Ok(block_starts)
+}
+
+/// Compresses and base64 encodes the ledger data from `start` to the end of ephemeral storage.
+fn encode_from(start: u64) -> Result<String, LedgerError> {
     EPHEMERAL_STORAGE.with(|es| {
         let storage = es.borrow();
-        info!(
-            "Persisting block of data in BROWSER LOCAL STORAGE: [{}..{}]",
-            block_start,
-            storage.len()
-        );
-        if block_start as usize > storage.len() {
-            return Err(format!(
+        if start as usize > storage.len() {
+            return Err(LedgerError::Other(format!(
                 "block_start {} is beyond ephemeral storage length {}",
-                block_start,
+                start,
                 storage.len()
-            ));
+            )));
         }
-        let last_block = &storage[block_start as usize..];
-        let encoded = encode_bytes(last_block);
-        let last_block_offset_str = format!("{}", block_start);
+        Ok(encode_bytes(&compress_bytes(&storage[start as usize..])?))
+    })
+}
+
+/// Slot, offset, number of chunks and compression of the persisted data, see `PERSISTENT_STORAGE_MANIFEST_KEY`.
+struct PersistedManifest {
+    slot: u8,
+    offset: String,
+    num_chunks: usize,
+    compression: String,
+}
+
+impl PersistedManifest {
+    fn read(storage: &Storage) -> Option<Self> {
+        let manifest = storage
+            .get_item(PERSISTENT_STORAGE_MANIFEST_KEY)
+            .ok()
+            .flatten()?;
+        let mut fields = manifest.split(' ');
+        let slot = fields.next()?.parse::<u8>().ok().filter(|slot| *slot < 2)?;
+        let offset = fields.next()?.to_string();
+        let num_chunks = fields.next()?.parse().ok()?;
+        let compression = fields.next()?.to_string();
+        Some(Self {
+            slot,
+            offset,
+            num_chunks,
+            compression,
+        })
+    }
 
-        PERSISTENT_LOCAL_STORAGE.with(|ls| {
-            if let Some(storage) = &*ls.borrow() {
-                write_with_quota_check(storage, PERSISTENT_STORAGE_DATA_KEY, &encoded)?;
+    fn to_value(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.slot, self.offset, self.num_chunks, self.compression
+        )
+    }
+}
+
+/// Local storage key of chunk `index` of `slot`. Slot 0 uses the keys of the legacy (single slot) chunks.
+fn chunk_key(slot: u8, index: usize) -> String {
+    match slot {
+        0 => format!("{}{}", PERSISTENT_STORAGE_CHUNK_KEY_PREFIX, index),
+        _ => format!("{}{}_{}", PERSISTENT_STORAGE_CHUNK_KEY_PREFIX, slot, index),
+    }
+}
+
+/// Persists the ledger data from `start` to the end of ephemeral storage, in chunks, within the budget.
+/// The chunks are written to the unused slot before the manifest switches to them, so a failed write keeps the
+/// previously persisted data.
+fn persist_from(start: u64) -> Result<(), LedgerError> {
+    info!(
+        "Persisting ledger data in BROWSER LOCAL STORAGE: [{}..{}]",
+        start,
+        persistent_storage_size_bytes()
+    );
+    let encoded = encode_from(start)?;
+    let required_bytes = encoded.len() as u64;
+    let budget_bytes = get_persistent_storage_budget();
+    if required_bytes > budget_bytes {
+        return Err(LedgerError::QuotaExceeded {
+            quota_bytes: budget_bytes,
+            required_bytes,
+        });
+    }
+
+    PERSISTENT_LOCAL_STORAGE.with(|ls| {
+        let ls = ls.borrow();
+        let storage = ls.as_ref().ok_or_else(|| {
+            LedgerError::Other("Persistent local storage not initialized".to_string())
+        })?;
+        // Data persisted without a manifest is in slot 0
+        let old_slot = PersistedManifest::read(storage).map_or(0, |manifest| manifest.slot);
+        let new_slot = 1 - old_slot;
+        // Chunks left over from an interrupted write
+        remove_chunks(storage, new_slot, 0);
+        // `encoded` is ASCII, so splitting it at any byte index is safe
+        let chunks: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(PERSISTENT_STORAGE_CHUNK_LEN)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+            .collect();
+        let manifest = PersistedManifest {
+            slot: new_slot,
+            offset: start.to_string(),
+            num_chunks: chunks.len(),
+            compression: PERSISTENT_STORAGE_COMPRESSION_ZLIB.to_string(),
+        };
+        let written = chunks
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, chunk)| {
+                write_with_quota_check(storage, &chunk_key(new_slot, i), chunk, required_bytes)
+            })
+            // Written last, so that the persisted data is only used once it's complete
+            .and_then(|_| {
                 write_with_quota_check(
                     storage,
-                    PERSISTENT_STORAGE_OFFSET_KEY,
-                    &last_block_offset_str,
+                    PERSISTENT_STORAGE_MANIFEST_KEY,
+                    &manifest.to_value(),
+                    required_bytes,
                 )
-            } else {
-                Err("Persistent local storage not initialized".into())
-            }
-        })
+            });
+        match written {
+            Ok(()) => remove_unused_data(storage, old_slot),
+            Err(_) => remove_chunks(storage, new_slot, chunks.len()),
+        }
+        written
     })
 }
 
+/// Reads the persisted (base64 encoded) data, its offset and its compression, from the slot of the manifest or
+/// from the legacy keys.
+fn read_persisted_data(storage: &Storage) -> (Option<String>, Option<String>, Option<String>) {
+    let get_item = |key: &str| storage.get_item(key).ok().flatten();
+    let read_chunks = |slot: u8, num_chunks: usize| {
+        (0..num_chunks)
+            .map(|i| get_item(&chunk_key(slot, i)))
+            .collect::<Option<String>>()
+    };
+    if let Some(manifest) = PersistedManifest::read(storage) {
+        return (
+            read_chunks(manifest.slot, manifest.num_chunks),
+            Some(manifest.offset),
+            Some(manifest.compression),
+        );
+    }
+    let data = match get_item(PERSISTENT_STORAGE_CHUNKS_KEY) {
+        Some(num_chunks) => num_chunks
+            .parse::<usize>()
+            .ok()
+            .and_then(|num_chunks| read_chunks(0, num_chunks)),
+        None => get_item(PERSISTENT_STORAGE_DATA_KEY),
+    };
+    (
+        data,
+        get_item(PERSISTENT_STORAGE_OFFSET_KEY),
+        get_item(PERSISTENT_STORAGE_COMPRESSION_KEY),
+    )
+}
+
+/// Removes the chunks of `slot`, at least `num_chunks` and any further ones left over from an interrupted write.
+fn remove_chunks(storage: &Storage, slot: u8, num_chunks: usize) {
+    let mut i = 0;
+    while i < num_chunks
+        || storage
+            .get_item(&chunk_key(slot, i))
+            .ok()
+            .flatten()
+            .is_some()
+    {
+        let _ = storage.remove_item(&chunk_key(slot, i));
+        i += 1;
+    }
+}
+
+/// Removes the previously persisted data of `old_slot` and the legacy keys, after switching to the other slot.
+fn remove_unused_data(storage: &Storage, old_slot: u8) {
+    remove_chunks(storage, old_slot, 0);
+    let _ = storage.remove_item(PERSISTENT_STORAGE_CHUNKS_KEY);
+    let _ = storage.remove_item(PERSISTENT_STORAGE_OFFSET_KEY);
+    let _ = storage.remove_item(PERSISTENT_STORAGE_COMPRESSION_KEY);
+    let _ = storage.remove_item(PERSISTENT_STORAGE_DATA_KEY);
+}
+
 //-------------------------------------
 // Internal Utility Functions
 //-------------------------------------
 
 /// Writes to `localStorage` and checks for quota errors or other exceptions,
-/// returning a descriptive error if something goes wrong.
-fn write_with_quota_check(storage: &Storage, key: &str, value: &str) -> Result<(), String> {
+/// returning `LedgerError::QuotaExceeded` with `required_bytes` if the browser quota is exceeded.
+fn write_with_quota_check(
+    storage: &Storage,
+    key: &str,
+    value: &str,
+    required_bytes: u64,
+) -> Result<(), LedgerError> {
     match storage.set_item(key, value) {
         Ok(_) => Ok(()),
         Err(e) => {
             // Try casting the error to a js_sys::Error and check its name property.
             if let Some(js_error) = e.dyn_ref::<Error>() {
                 if js_error.name() == "QuotaExceededError" {
-                    return Err(format!(
-                        "Browser storage quota exceeded when writing key '{}'",
-                        key
-                    ));
+                    warn!("Browser storage quota exceeded when writing key '{}'", key);
+                    return Err(LedgerError::QuotaExceeded {
+                        quota_bytes: get_persistent_storage_budget(),
+                        required_bytes,
+                    });
                 }
             }
             // Otherwise, it's a different error
-            Err(format!(
-                "Failed to write key '{}' to local storage: {:?}",
-                key, e
-            ))
+            Err(LedgerError::Io {
+                kind: std::io::ErrorKind::Other,
+                message: format!("Failed to write key '{}' to local storage: {:?}", key, e),
+            })
         }
     }
 }
@@ -380,6 +676,26 @@ fn encode_bytes(bytes: &[u8]) -> String {
     BASE64.encode(bytes)
 }
 
+/// Compresses bytes with zlib.
+fn compress_bytes(bytes: &[u8]) -> Result<Vec<u8>, LedgerError> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompresses zlib compressed bytes.
+/// Returns an empty vector on error and logs it, like `decode_bytes`.
+fn decompress_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::new();
+    match ZlibDecoder::new(bytes).read_to_end(&mut decompressed) {
+        Ok(_) => decompressed,
+        Err(e) => {
+            error!("Failed to decompress persisted data: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
 /// Decodes a base64 string into bytes.
 /// Returns an empty vector on error and logs it, which triggers a reset
 /// in ledger initialization logic if relevant.
@@ -400,7 +716,65 @@ pub fn get_backing_file_path() -> Option<std::path::PathBuf> {
     None
 }
 
-/// Returns a timestamp in nanoseconds, derived from the browser's high-resolution timer.
-pub fn get_timestamp_nanos() -> u64 {
-    (js_sys::Date::now() * 1_000_000.0) as u64
+/// Returns `performance.now()` of the window or worker, in milliseconds, if available.
+fn performance_now_ms() -> Option<f64> {
+    js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("performance"))
+        .ok()?
+        .dyn_into::<web_sys::Performance>()
+        .ok()
+        .map(|performance| performance.now())
+}
+
+fn persisted_clock_last_ns() -> Option<u64> {
+    PERSISTENT_LOCAL_STORAGE.with(|ls| {
+        ls.borrow()
+            .as_ref()
+            .and_then(|storage| storage.get_item(CLOCK_LAST_TIMESTAMP_KEY).ok().flatten())
+            .and_then(|value| value.parse().ok())
+    })
+}
+
+fn persist_clock_last_ns(timestamp_ns: u64) {
+    PERSISTENT_LOCAL_STORAGE.with(|ls| {
+        if let Some(storage) = ls.borrow().as_ref() {
+            if let Err(e) = storage.set_item(CLOCK_LAST_TIMESTAMP_KEY, &timestamp_ns.to_string()) {
+                warn!("Failed to persist the clock timestamp: {:?}", e);
+            }
+        }
+    })
+}
+
+/// Timestamps in nanoseconds from the monotonic `performance.now()`, offset by an epoch that is
+/// taken from the wall clock (`Date.now()`) at the first use in the session. The epoch is raised
+/// past the last timestamp persisted in local storage, so timestamps do not go backwards when the
+/// user changes the wall clock, neither within a session nor across sessions.
+/// Falls back to the wall clock if `performance` is not available.
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> u64 {
+        let wall_clock_ns = || (js_sys::Date::now() * 1_000_000.0) as u64;
+        let Some(now_ms) = performance_now_ms() else {
+            return wall_clock_ns();
+        };
+        let since_origin_ns = (now_ms * 1_000_000.0) as u64;
+        let origin_ns = CLOCK_ORIGIN_NS.with(|origin| {
+            *origin.borrow_mut().get_or_insert_with(|| {
+                let epoch_ns = wall_clock_ns()
+                    .max(persisted_clock_last_ns().map_or(0, |last_ns| last_ns.saturating_add(1)));
+                epoch_ns.saturating_sub(since_origin_ns)
+            })
+        });
+        let timestamp_ns = origin_ns.saturating_add(since_origin_ns);
+        persist_clock_last_ns(timestamp_ns);
+        timestamp_ns
+    }
+}
+
+/// There is no caller identity on this platform, so the default principal is empty.
+pub(crate) fn get_caller_principal() -> Vec<u8> {
+    Vec::new()
+}
+
+/// Fill `buf` with random bytes from the browser (`crypto.getRandomValues`).
+pub(crate) fn fill_random(buf: &mut [u8]) -> Result<(), String> {
+    getrandom::fill(buf).map_err(|e| e.to_string())
 }
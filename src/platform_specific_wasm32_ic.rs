@@ -2,10 +2,16 @@
 /// It provides implementations and abstractions unique to the environment.
 ///
 pub use crate::{debug, error, info, warn}; // created in the crate root by macro_export
+use crate::{Clock, LedgerError, LedgerMap, SystemClock};
 pub use ic_canister_log::log;
 use ic_canister_log::{declare_log_buffer, export, LogEntry};
 #[allow(unused_imports)]
 use ic_cdk::println;
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::Memory;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+use std::time::Duration;
 
 // Keep up to "capacity" last messages.
 declare_log_buffer!(name = DEBUG, capacity = 10000);
@@ -59,8 +65,40 @@ pub fn export_error() -> Vec<LogEntry> {
 
 pub const PERSISTENT_STORAGE_PAGE_SIZE: u64 = 64 * 1024;
 
+thread_local! {
+    // Memory that the ledger is stored in, if not the entire stable memory
+    static STABLE_MEMORY: RefCell<Option<Box<dyn Memory>>> = const { RefCell::new(None) };
+}
+
+/// Store the ledger in `memory` instead of the entire stable memory, e.g. in a virtual memory
+/// of an `ic_stable_structures::memory_manager::MemoryManager`, which lets the ledger coexist
+/// with other stable structures of the canister. Must be called before creating the LedgerMap.
+///
+/// Example usage:
+///
+/// ```rust,ignore
+/// use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+/// use ic_stable_structures::DefaultMemoryImpl;
+///
+/// let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+/// ledger_map::platform_specific::set_stable_memory(memory_manager.get(MemoryId::new(0)));
+/// let ledger_map = ledger_map::LedgerMap::new(None).unwrap();
+/// ```
+pub fn set_stable_memory(memory: impl Memory + 'static) {
+    STABLE_MEMORY.with(|stable_memory| stable_memory.replace(Some(Box::new(memory))));
+}
+
 pub fn persistent_storage_size_bytes() -> u64 {
-    ic_cdk::api::stable::stable_size() * PERSISTENT_STORAGE_PAGE_SIZE
+    let size_pages = STABLE_MEMORY.with(|stable_memory| match stable_memory.borrow().as_ref() {
+        Some(memory) => memory.size(),
+        None => ic_cdk::api::stable::stable_size(),
+    });
+    size_pages * PERSISTENT_STORAGE_PAGE_SIZE
+}
+
+/// Returns the first valid offset of the persistent storage, which is always 0 on this platform.
+pub fn persistent_storage_first_valid_offset() -> u64 {
+    0
 }
 
 pub fn persistent_storage_last_valid_offset() -> u64 {
@@ -68,20 +106,35 @@ pub fn persistent_storage_last_valid_offset() -> u64 {
 }
 
 pub fn persistent_storage_read(offset: u64, buf: &mut [u8]) -> Result<(), String> {
-    ic_cdk::api::stable::stable_read(offset, buf);
+    STABLE_MEMORY.with(|stable_memory| match stable_memory.borrow().as_ref() {
+        Some(memory) => memory.read(offset, buf),
+        None => ic_cdk::api::stable::stable_read(offset, buf),
+    });
     Ok(())
 }
 
-pub fn persistent_storage_write(offset: u64, buf: &[u8]) {
+/// Write `buf` at `offset`, growing the stable memory as needed. Fails with
+/// `LedgerError::StorageFull` if the stable memory cannot grow any further.
+pub fn persistent_storage_write(offset: u64, buf: &[u8]) -> Result<(), LedgerError> {
     let stable_memory_size_bytes = persistent_storage_size_bytes();
     if stable_memory_size_bytes < offset + buf.len() as u64 {
         let stable_memory_bytes_new = offset + (buf.len() as u64).max(PERSISTENT_STORAGE_PAGE_SIZE);
         persistent_storage_grow(
             (stable_memory_bytes_new - stable_memory_size_bytes) / PERSISTENT_STORAGE_PAGE_SIZE + 1,
         )
-        .unwrap();
+        .map_err(LedgerError::StorageFull)?;
     }
-    ic_cdk::api::stable::stable_write(offset, buf)
+    STABLE_MEMORY.with(|stable_memory| match stable_memory.borrow().as_ref() {
+        Some(memory) => memory.write(offset, buf),
+        None => ic_cdk::api::stable::stable_write(offset, buf),
+    });
+    Ok(())
+}
+
+/// Nothing to sync: writes to the stable memory are durable once the message that made them
+/// completes successfully.
+pub fn persistent_storage_sync() -> Result<(), String> {
+    Ok(())
 }
 
 pub fn persistent_storage_grow(additional_pages: u64) -> Result<u64, String> {
@@ -89,9 +142,262 @@ pub fn persistent_storage_grow(additional_pages: u64) -> Result<u64, String> {
         "persistent_storage_grow: {} additional_pages.",
         additional_pages
     );
-    ic_cdk::api::stable::stable_grow(additional_pages).map_err(|err| format!("{:?}", err))
+    STABLE_MEMORY.with(|stable_memory| match stable_memory.borrow().as_ref() {
+        Some(memory) => match memory.grow(additional_pages) {
+            -1 => Err(format!(
+                "Failed to grow the stable memory by {} pages",
+                additional_pages
+            )),
+            previous_size_pages => Ok(previous_size_pages as u64),
+        },
+        None => {
+            ic_cdk::api::stable::stable_grow(additional_pages).map_err(|err| format!("{:?}", err))
+        }
+    })
+}
+
+/// Register a periodic timer that commits the staged entries of `ledger_map` every `interval`.
+/// After each commit that appends a block, `on_commit` is called with the new block hash,
+/// e.g. to update the certified data of the canister. Failed commits are logged and retried
+/// on the next tick. The timer can be stopped with `ic_cdk_timers::clear_timer`.
+///
+/// Example usage:
+///
+/// ```rust,ignore
+/// thread_local! {
+///     static LEDGER_MAP: RefCell<LedgerMap> = RefCell::new(LedgerMap::new(None).unwrap());
+/// }
+///
+/// #[ic_cdk::init]
+/// fn init() {
+///     ledger_map::platform_specific::set_auto_commit_timer(
+///         &LEDGER_MAP,
+///         Duration::from_secs(10),
+///         |block_hash| ic_cdk::api::set_certified_data(block_hash),
+///     );
+/// }
+/// ```
+pub fn set_auto_commit_timer(
+    ledger_map: &'static LocalKey<RefCell<LedgerMap>>,
+    interval: Duration,
+    mut on_commit: impl FnMut(&[u8]) + 'static,
+) -> TimerId {
+    ic_cdk_timers::set_timer_interval(interval, move || {
+        let block_hash = ledger_map.with(|ledger_map| {
+            let mut ledger_map = ledger_map.borrow_mut();
+            let blocks_count = ledger_map.get_blocks_count();
+            if let Err(err) = ledger_map.commit_block() {
+                error!("Auto-commit of the ledger failed: {}", err);
+                return None;
+            }
+            if ledger_map.get_blocks_count() > blocks_count {
+                Some(ledger_map.get_latest_block_hash())
+            } else {
+                None
+            }
+        });
+        // Called outside of the ledger borrow, so the callback can use the ledger too
+        if let Some(block_hash) = block_hash {
+            on_commit(&block_hash);
+        }
+    })
+}
+
+/// Maximum number of bytes returned by a single `data_fetch` call, well below the IC response size limit.
+pub const DATA_FETCH_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Read raw ledger data from the persistent storage, starting at `position` (by default the
+/// start of the data partition), up to `max_bytes` and the end of the committed blocks.
+/// Returns the position to continue from and the data, which can be parsed with
+/// `LedgerMap::iter_raw_from_slice`. Only complete blocks are returned, unless a single
+/// block is larger than `max_bytes`.
+pub fn data_fetch(
+    ledger_map: &LedgerMap,
+    position: Option<u64>,
+    max_bytes: Option<u64>,
+) -> Result<(u64, Vec<u8>), String> {
+    let data_start = crate::partition_table::get_data_partition().start_lba;
+    let data_end = ledger_map.get_next_block_start_pos();
+    let position = position.unwrap_or(data_start).max(data_start);
+    let max_bytes = max_bytes
+        .unwrap_or(DATA_FETCH_MAX_BYTES)
+        .min(DATA_FETCH_MAX_BYTES);
+    if position >= data_end {
+        return Ok((position, Vec::new()));
+    }
+    // Stop at the last block boundary that fits within max_bytes
+    let mut end = position;
+    while end < data_end {
+        let (block_header, _) = ledger_map
+            .get_block_at_offset(end)
+            .map_err(|e| e.to_string())?;
+        let block_end = end + block_header.jump_bytes_next_block() as u64;
+        if block_end - position > max_bytes && end > position {
+            break;
+        }
+        end = block_end;
+    }
+    let mut buf = vec![0u8; (end - position) as usize];
+    persistent_storage_read(position, &mut buf)?;
+    Ok((end, buf))
+}
+
+/// Ledger metadata as key-value pairs, for the `metadata` endpoint.
+pub fn metadata(ledger_map: &LedgerMap) -> Vec<(String, String)> {
+    vec![
+        (
+            "ledger:num_blocks".to_string(),
+            ledger_map.get_blocks_count().to_string(),
+        ),
+        (
+            "ledger:tip_block_hash".to_string(),
+            hex::encode(ledger_map.get_latest_block_hash()),
+        ),
+        (
+            "ledger:tip_block_timestamp_ns".to_string(),
+            ledger_map.get_latest_block_timestamp_ns().to_string(),
+        ),
+        (
+            "ledger:data_start".to_string(),
+            crate::partition_table::get_data_partition()
+                .start_lba
+                .to_string(),
+        ),
+        (
+            "ledger:data_end".to_string(),
+            ledger_map.get_next_block_start_pos().to_string(),
+        ),
+        (
+            "ledger:block_version".to_string(),
+            ledger_map.get_block_version().to_string(),
+        ),
+    ]
 }
 
-pub(crate) fn get_timestamp_nanos() -> u64 {
-    ic_cdk::api::time()
+/// Raw persisted blocks (header and body), starting with the block number `start`,
+/// up to `length` blocks and `DATA_FETCH_MAX_BYTES` in total.
+/// Each block can be parsed with `LedgerMap::get_block_from_slice`.
+pub fn get_blocks(ledger_map: &LedgerMap, start: u64, length: u64) -> Result<Vec<Vec<u8>>, String> {
+    let mut blocks = Vec::new();
+    let mut total_bytes = 0u64;
+    for entry in ledger_map
+        .iter_raw_from_block(usize::try_from(start).unwrap_or(usize::MAX))
+        .take(length as usize)
+    {
+        let (block_header, ledger_block) = entry.map_err(|e| e.to_string())?;
+        let block_len = block_header.jump_bytes_next_block() as u64;
+        if total_bytes + block_len > DATA_FETCH_MAX_BYTES && !blocks.is_empty() {
+            break;
+        }
+        let mut buf = vec![0u8; block_len as usize];
+        persistent_storage_read(ledger_block.get_offset(), &mut buf)?;
+        total_bytes += block_len;
+        blocks.push(buf);
+    }
+    Ok(blocks)
+}
+
+/// Generate the standard candid endpoints of a canister that serves a `LedgerMap` stored in
+/// the thread-local `$ledger_map: RefCell<LedgerMap>`:
+///
+/// - `data_fetch(opt nat64, opt nat64) -> (variant { Ok: record { nat64; blob }; Err: text }) query`
+/// - `metadata() -> (vec record { text; text }) query`
+/// - `get_blocks(nat64, nat64) -> (variant { Ok: vec blob; Err: text }) query`
+/// - `get_entry(text, blob) -> (variant { Ok: blob; Err: text }) query`
+///
+/// The canister must depend on `ic-cdk`, which the endpoint attributes expand into.
+///
+/// Example usage:
+///
+/// ```rust,ignore
+/// thread_local! {
+///     static LEDGER_MAP: RefCell<LedgerMap> = RefCell::new(LedgerMap::new(None).unwrap());
+/// }
+///
+/// ledger_map::ic_endpoints!(LEDGER_MAP);
+/// ```
+#[macro_export]
+macro_rules! ic_endpoints {
+    ($ledger_map:ident) => {
+        #[ic_cdk::query]
+        fn data_fetch(
+            position: Option<u64>,
+            max_bytes: Option<u64>,
+        ) -> Result<(u64, Vec<u8>), String> {
+            $ledger_map.with(|ledger_map| {
+                $crate::platform_specific_wasm32_ic::data_fetch(
+                    &ledger_map.borrow(),
+                    position,
+                    max_bytes,
+                )
+            })
+        }
+
+        #[ic_cdk::query]
+        fn metadata() -> Vec<(String, String)> {
+            $ledger_map.with(|ledger_map| {
+                $crate::platform_specific_wasm32_ic::metadata(&ledger_map.borrow())
+            })
+        }
+
+        #[ic_cdk::query]
+        fn get_blocks(start: u64, length: u64) -> Result<Vec<Vec<u8>>, String> {
+            $ledger_map.with(|ledger_map| {
+                $crate::platform_specific_wasm32_ic::get_blocks(&ledger_map.borrow(), start, length)
+            })
+        }
+
+        #[ic_cdk::query]
+        fn get_entry(label: String, key: Vec<u8>) -> Result<Vec<u8>, String> {
+            $ledger_map.with(|ledger_map| {
+                ledger_map
+                    .borrow()
+                    .get(&label, &key)
+                    .map_err(|e| e.to_string())
+            })
+        }
+    };
+}
+
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+}
+
+/// Returns the principal of the caller of the current canister method.
+pub(crate) fn get_caller_principal() -> Vec<u8> {
+    ic_cdk::api::msg_caller().as_slice().to_vec()
+}
+
+thread_local! {
+    static RANDOM_COUNTER: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// Fill `buf` with bytes that are unique to this call. Randomness is only available
+/// asynchronously on the Internet Computer (`raw_rand`), so they are derived from the canister
+/// id, the time, the instruction counter and a call counter; they are unique, not unpredictable.
+pub(crate) fn fill_random(buf: &mut [u8]) -> Result<(), String> {
+    use sha2::Digest;
+
+    let counter = RANDOM_COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        counter.get()
+    });
+    let mut filled = 0;
+    let mut block = 0u64;
+    while filled < buf.len() {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(ic_cdk::api::canister_self().as_slice());
+        hasher.update(ic_cdk::api::time().to_le_bytes());
+        hasher.update(ic_cdk::api::instruction_counter().to_le_bytes());
+        hasher.update(counter.to_le_bytes());
+        hasher.update(block.to_le_bytes());
+        let hash = hasher.finalize();
+        let n = hash.len().min(buf.len() - filled);
+        buf[filled..filled + n].copy_from_slice(&hash[..n]);
+        filled += n;
+        block += 1;
+    }
+    Ok(())
 }
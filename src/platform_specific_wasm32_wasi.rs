@@ -0,0 +1,217 @@
+/// This module contains functionalities specific to the wasm32-wasi (WASI preview 1) target.
+/// It keeps the persistent storage in a regular file, through the WASI file APIs, so that
+/// LedgerMap can run in server-side wasm runtimes such as wasmtime or Spin.
+///
+/// The runtime must grant access to the directory of the backing file, e.g. with
+/// `wasmtime run --dir . ...` for the default `data.bin` in the current directory.
+///
+use crate::{Clock, LedgerError, SystemClock};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+pub use log::{debug, error, info, warn};
+use std::cell::RefCell;
+
+pub struct BackingFile {
+    file: File,
+    file_path: PathBuf,
+}
+
+impl BackingFile {
+    pub fn new(file_path: Option<PathBuf>) -> Result<Self, String> {
+        let file_path = file_path.unwrap_or_else(default_file_path);
+        if let Some(parent) = file_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create directory {:?}: {}", parent, e))?;
+        }
+
+        debug!("Opening persistent storage {:?}", file_path);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&file_path)
+            .map_err(|e| format!("Failed to open {:?}: {}", file_path, e))?;
+
+        Ok(BackingFile { file, file_path })
+    }
+
+    pub fn size_bytes(&self) -> Result<u64, String> {
+        self.file
+            .metadata()
+            .map(|metadata| metadata.len())
+            .map_err(|e| format!("Failed to retrieve metadata: {}", e))
+    }
+
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        if offset + buf.len() as u64 > self.size_bytes()? {
+            return Err(
+                "Failed to read from persistent storage: read beyond end of file.".to_string(),
+            );
+        }
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| e.to_string())?;
+        self.file.read_exact(buf).map_err(|e| e.to_string())
+    }
+
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        let required_size_bytes = offset + (buf.len() as u64).max(PERSISTENT_STORAGE_PAGE_SIZE);
+        if self.file.metadata()?.len() < required_size_bytes {
+            // Extending the file with set_len fills the new space with zeros
+            self.file.set_len(required_size_bytes)?;
+            info!(
+                "Growing persistent storage to {} bytes.",
+                required_size_bytes
+            );
+        }
+
+        debug!(
+            "Writing {} bytes to persistent storage @offset 0x{:0x}",
+            buf.len(),
+            offset
+        );
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    /// Flush the writes to the backing file to the disk.
+    pub fn sync(&mut self) -> Result<(), String> {
+        self.file
+            .sync_data()
+            .map_err(|e| format!("Failed to sync backing file {:?}: {}", self.file_path, e))
+    }
+
+    pub fn grow(&mut self, additional_pages: u64) -> Result<u64, String> {
+        let previous_size_bytes = self.size_bytes()?;
+        let new_size_bytes =
+            previous_size_bytes + (additional_pages * PERSISTENT_STORAGE_PAGE_SIZE);
+        self.file
+            .set_len(new_size_bytes)
+            .map_err(|e| format!("Failed to set file length: {}", e))?;
+        Ok(previous_size_bytes)
+    }
+}
+
+fn default_file_path() -> PathBuf {
+    PathBuf::from("data.bin")
+}
+
+thread_local! {
+    pub static BACKING_FILE: RefCell<Option<BackingFile>> = const { RefCell::new(None) };
+}
+
+pub fn set_backing_file(file_path: Option<PathBuf>) -> Result<(), String> {
+    let backing_file = BackingFile::new(file_path)?;
+    BACKING_FILE.with(|cell| {
+        cell.replace(Some(backing_file));
+    });
+    Ok(())
+}
+
+pub fn get_backing_file_path() -> Option<PathBuf> {
+    BACKING_FILE.with(|backing_file| {
+        backing_file
+            .borrow()
+            .as_ref()
+            .map(|backing_file| backing_file.file_path.clone())
+    })
+}
+
+/// Run `f` with the backing file, opening the default backing file if needed.
+fn with_backing_file<R>(
+    f: impl FnOnce(&mut BackingFile) -> Result<R, String>,
+) -> Result<R, String> {
+    BACKING_FILE.with(|backing_file| {
+        let mut binding = backing_file.borrow_mut();
+        if binding.is_none() {
+            *binding = Some(BackingFile::new(None)?);
+        }
+        match binding.as_mut() {
+            Some(backing_file) => f(backing_file),
+            None => Err("Failed to access backing file".to_string()),
+        }
+    })
+}
+
+pub fn persistent_storage_size_bytes() -> u64 {
+    BACKING_FILE.with(|backing_file| {
+        backing_file
+            .borrow()
+            .as_ref()
+            .and_then(|backing_file| backing_file.size_bytes().ok())
+            .unwrap_or(0)
+    })
+}
+
+/// Returns the first valid offset of the persistent storage, which is always 0 on this platform.
+pub fn persistent_storage_first_valid_offset() -> u64 {
+    0
+}
+
+pub fn persistent_storage_last_valid_offset() -> u64 {
+    persistent_storage_size_bytes()
+}
+
+pub fn persistent_storage_read(offset: u64, buf: &mut [u8]) -> Result<(), String> {
+    with_backing_file(|backing_file| backing_file.read(offset, buf))
+}
+
+/// Write `buf` at `offset`, growing the backing file as needed.
+pub fn persistent_storage_write(offset: u64, buf: &[u8]) -> Result<(), LedgerError> {
+    with_backing_file(|backing_file| Ok(backing_file.write(offset, buf)))
+        .map_err(std::io::Error::other)
+        .and_then(|result| result)
+        .map_err(LedgerError::from)
+}
+
+pub fn persistent_storage_grow(additional_pages: u64) -> Result<u64, String> {
+    with_backing_file(|backing_file| backing_file.grow(additional_pages))
+}
+
+/// Make the writes to the backing file so far durable.
+pub fn persistent_storage_sync() -> Result<(), String> {
+    with_backing_file(|backing_file| backing_file.sync())
+}
+
+pub const PERSISTENT_STORAGE_PAGE_SIZE: u64 = 64 * 1024;
+
+// These functions exist only for compatibility with the other wasm32 implementations.
+pub fn export_debug() -> Vec<String> {
+    Vec::new()
+}
+
+pub fn export_info() -> Vec<String> {
+    Vec::new()
+}
+
+pub fn export_warn() -> Vec<String> {
+    Vec::new()
+}
+
+pub fn export_error() -> Vec<String> {
+    Vec::new()
+}
+
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+}
+
+/// There is no caller identity on this platform, so the default principal is empty.
+pub(crate) fn get_caller_principal() -> Vec<u8> {
+    Vec::new()
+}
+
+/// Fill `buf` with random bytes from the operating system.
+pub(crate) fn fill_random(buf: &mut [u8]) -> Result<(), String> {
+    getrandom::fill(buf).map_err(|e| e.to_string())
+}
@@ -0,0 +1,114 @@
+//! This module contains the Windows specifics of the native platform layer. The storage backends
+//! of `platform_specific_x86_64` use it on Windows to open, lock and flush the backing file:
+//!
+//! - Paths are made absolute, so that the backing file does not depend on the current directory.
+//! - The backing file is opened without sharing write or delete access, so that other processes
+//!   can read the ledger but not modify it while it is open.
+//! - An advisory lock with `LockFileEx` marks the backing file as in use.
+//! - Committed blocks are flushed with `FlushFileBuffers`, as Windows does not otherwise
+//!   guarantee that writes reach the disk before the system stops. `Durability::Sync` is the
+//!   default on Windows for this reason, and the backing file is flushed once per block.
+use fs_err::File;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::{Path, PathBuf};
+use windows_sys::Win32::Foundation::{ERROR_LOCK_VIOLATION, HANDLE};
+use windows_sys::Win32::Storage::FileSystem::{
+    FlushFileBuffers, LockFileEx, FILE_SHARE_READ, LOCKFILE_EXCLUSIVE_LOCK,
+    LOCKFILE_FAIL_IMMEDIATELY,
+};
+use windows_sys::Win32::System::IO::OVERLAPPED;
+
+/// Offset of the byte that is locked to mark a backing file as in use. Windows enforces locks on
+/// reads and writes, so the byte lies far beyond the end of any ledger, where it does not get in
+/// the way of reading the ledger.
+const LOCK_OFFSET: u64 = u64::MAX - 1;
+
+/// Absolute form of `file_path`, with the separators normalized.
+pub(crate) fn absolute_path(file_path: &Path) -> Result<PathBuf, String> {
+    std::path::absolute(file_path)
+        .map_err(|e| format!("Invalid backing file path {:?}: {}", file_path, e))
+}
+
+/// Open the backing file at `file_path`, creating it if needed, and lock it. Fails if another
+/// process has the file open for writing.
+pub(crate) fn open_backing_file(file_path: &Path) -> Result<File, String> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .share_mode(FILE_SHARE_READ)
+        .open(file_path)
+        .map_err(|e| format!("Failed to open backing file {:?}: {}", file_path, e))?;
+    lock_backing_file(&file, file_path)?;
+    Ok(File::from_parts(file, file_path))
+}
+
+/// Take the advisory lock of the backing file, which is released when the file is closed.
+fn lock_backing_file(file: &std::fs::File, file_path: &Path) -> Result<(), String> {
+    // SAFETY: the handle is valid while `file` is open, and `overlapped` outlives the call, which
+    // completes synchronously as the file is not opened for overlapped I/O.
+    let locked = unsafe {
+        let mut overlapped: OVERLAPPED = std::mem::zeroed();
+        overlapped.Anonymous.Anonymous.Offset = LOCK_OFFSET as u32;
+        overlapped.Anonymous.Anonymous.OffsetHigh = (LOCK_OFFSET >> 32) as u32;
+        LockFileEx(
+            file.as_raw_handle() as HANDLE,
+            LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY,
+            0,
+            1,
+            0,
+            &mut overlapped,
+        )
+    };
+    if locked == 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+            return Err(format!(
+                "Backing file {:?} is in use by another process",
+                file_path
+            ));
+        }
+        return Err(format!(
+            "Failed to lock backing file {:?}: {}",
+            file_path, err
+        ));
+    }
+    Ok(())
+}
+
+/// Flush the writes to the backing file to the disk.
+pub(crate) fn flush_backing_file(file: &File) -> Result<(), String> {
+    // SAFETY: the handle is valid while `file` is open.
+    if unsafe { FlushFileBuffers(file.file().as_raw_handle() as HANDLE) } == 0 {
+        return Err(format!(
+            "Failed to flush backing file {:?}: {}",
+            file.path(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backing_file_lock() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let file = open_backing_file(&file_path).unwrap();
+        let err = open_backing_file(&file_path).unwrap_err();
+        assert!(err.contains(&format!("{:?}", file_path)));
+        // Reading the ledger from elsewhere is still possible
+        assert!(std::fs::read(&file_path).is_ok());
+        flush_backing_file(&file).unwrap();
+
+        drop(file);
+        assert!(open_backing_file(&file_path).is_ok());
+    }
+}
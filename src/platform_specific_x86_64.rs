@@ -1,14 +1,47 @@
 /// This module contains functionalities specific to the x86_64/aarch64 architecture.
 /// It includes implementations and optimizations tailored for this 64-bit environment,
 /// allowing LedgerMap to run on x86_64/aarch64 platforms and share most of the code with
-/// the wasm32 platform.
+/// the wasm32 platform. On Windows, the backing file is opened, locked and flushed with the
+/// Windows specifics of `platform_specific_windows`, and on iOS and Android, its default
+/// directory and how it is opened and flushed come from `platform_specific_mobile`.
 ///
+use crate::{Clock, LedgerError, SystemClock};
 use std::io::{Read, Seek, SeekFrom, Write};
 
 use fs_err::{File, OpenOptions};
 pub use log::{debug, error, info, warn};
 use std::cell::RefCell;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[cfg(any(target_os = "ios", target_os = "android"))]
+pub use crate::platform_specific_mobile::set_default_data_dir;
+#[cfg(target_os = "ios")]
+pub use crate::platform_specific_mobile::{set_file_protection_class, FileProtectionClass};
+
+/// Backend of the persistent storage: a flat, growable, byte-addressable storage.
+pub trait StorageBackend: Send {
+    /// Path of the storage, if the storage lives on the local file system.
+    fn path(&self) -> Option<PathBuf>;
+    fn size_bytes(&self) -> Result<u64, String>;
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), String>;
+    /// Write `buf` at `offset`, growing the storage as needed. Fails with
+    /// `std::io::ErrorKind::StorageFull` when the storage cannot grow any further.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()>;
+    /// Grow the storage by `additional_pages` pages, returning the previous size in bytes.
+    fn grow(&mut self, additional_pages: u64) -> Result<u64, String>;
+    /// Make the writes so far durable, e.g. with fsync. Called after each committed block with
+    /// `Durability::Sync`. Backends without a volatile cache need not implement it.
+    fn sync(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for dyn StorageBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StorageBackend({:?})", self.path())
+    }
+}
 
 pub struct BackingFile {
     file: File,
@@ -18,11 +51,18 @@ pub struct BackingFile {
 impl BackingFile {
     pub fn new(file_path: Option<PathBuf>) -> Result<Self, String> {
         let file_path = file_path.unwrap_or_else(default_file_path);
+        #[cfg(windows)]
+        let file_path = crate::platform_specific_windows::absolute_path(&file_path)?;
         fs_err::create_dir_all(file_path.parent().expect("Could not find parent directory"))
             .map_err(|e| format!("{:?}", e))?;
 
         debug!("Opening persistent storage {:?}", file_path);
 
+        #[cfg(windows)]
+        let file = crate::platform_specific_windows::open_backing_file(&file_path)?;
+        #[cfg(any(target_os = "ios", target_os = "android"))]
+        let file = crate::platform_specific_mobile::open_backing_file(&file_path)?;
+        #[cfg(not(any(windows, target_os = "ios", target_os = "android")))]
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -62,20 +102,15 @@ impl BackingFile {
         Ok(())
     }
 
-    pub fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), String> {
-        let file_size_bytes = self.metadata()?.len();
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        let file_size_bytes = self.file.metadata()?.len();
         if file_size_bytes < offset + (buf.len() as u64).max(PERSISTENT_STORAGE_PAGE_SIZE) {
             let file_size_bytes_new = offset + (buf.len() as u64).max(PERSISTENT_STORAGE_PAGE_SIZE);
-            self.file
-                .set_len(file_size_bytes_new)
-                .map_err(|e| e.to_string())?;
+            self.file.set_len(file_size_bytes_new)?;
             // Fill new file space with zeros
+            self.file.seek(SeekFrom::Start(file_size_bytes))?;
             self.file
-                .seek(SeekFrom::Start(file_size_bytes))
-                .map_err(|e| e.to_string())?;
-            self.file
-                .write_all(&vec![0; (file_size_bytes_new - file_size_bytes) as usize])
-                .map_err(|e| e.to_string())?;
+                .write_all(&vec![0; (file_size_bytes_new - file_size_bytes) as usize])?;
             info!(
                 "Growing persistent storage to {} bytes.",
                 file_size_bytes_new
@@ -88,13 +123,29 @@ impl BackingFile {
             offset
         );
 
-        self.file
-            .seek(SeekFrom::Start(offset))
-            .map_err(|e| e.to_string())?;
-        self.file.write_all(buf).map_err(|e| e.to_string())?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)?;
         Ok(())
     }
 
+    /// Flush the writes to the backing file to the disk.
+    pub fn sync(&mut self) -> Result<(), String> {
+        #[cfg(windows)]
+        {
+            crate::platform_specific_windows::flush_backing_file(&self.file)
+        }
+        #[cfg(any(target_os = "ios", target_os = "android"))]
+        {
+            crate::platform_specific_mobile::flush_backing_file(&self.file)
+        }
+        #[cfg(not(any(windows, target_os = "ios", target_os = "android")))]
+        {
+            self.file
+                .sync_data()
+                .map_err(|e| format!("Failed to sync backing file {:?}: {}", self.file_path, e))
+        }
+    }
+
     pub fn grow(&mut self, additional_pages: u64) -> Result<u64, String> {
         let previous_size_bytes = self.metadata()?.len();
         let new_size_bytes =
@@ -130,82 +181,437 @@ impl BackingFile {
     }
 }
 
+impl StorageBackend for BackingFile {
+    fn path(&self) -> Option<PathBuf> {
+        Some(self.file_path.clone())
+    }
+
+    fn size_bytes(&self) -> Result<u64, String> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        BackingFile::read(self, offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        BackingFile::write(self, offset, buf)
+    }
+
+    fn grow(&mut self, additional_pages: u64) -> Result<u64, String> {
+        BackingFile::grow(self, additional_pages)
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        BackingFile::sync(self)
+    }
+}
+
+/// Persistent storage split across multiple segment files of bounded size.
+/// Segment `N` of storage `path` is stored in the file `path.N` (e.g. `data.bin.00000`),
+/// and a new segment file is created whenever the storage grows beyond the last segment.
+pub struct SegmentedFile {
+    file_path: PathBuf,
+    segment_size_bytes: u64,
+    segments: Vec<File>,
+}
+
+impl SegmentedFile {
+    pub fn new(file_path: Option<PathBuf>, segment_size_bytes: u64) -> Result<Self, String> {
+        if segment_size_bytes == 0
+            || !segment_size_bytes.is_multiple_of(PERSISTENT_STORAGE_PAGE_SIZE)
+        {
+            return Err(format!(
+                "Segment size {} is not a multiple of the page size {}",
+                segment_size_bytes, PERSISTENT_STORAGE_PAGE_SIZE
+            ));
+        }
+        let file_path = file_path.unwrap_or_else(default_file_path);
+        fs_err::create_dir_all(file_path.parent().expect("Could not find parent directory"))
+            .map_err(|e| format!("{:?}", e))?;
+
+        let mut segments = Vec::new();
+        loop {
+            let segment_path = Self::segment_path(&file_path, segments.len());
+            if !segment_path.exists() {
+                break;
+            }
+            segments.push(Self::open_segment(&segment_path)?);
+        }
+        debug!(
+            "Opened persistent storage {:?} with {} segments",
+            file_path,
+            segments.len()
+        );
+
+        Ok(SegmentedFile {
+            file_path,
+            segment_size_bytes,
+            segments,
+        })
+    }
+
+    pub fn segment_path(file_path: &Path, index: usize) -> PathBuf {
+        let mut segment_path = file_path.as_os_str().to_owned();
+        segment_path.push(format!(".{:05}", index));
+        PathBuf::from(segment_path)
+    }
+
+    fn open_segment(segment_path: &Path) -> Result<File, String> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(segment_path)
+            .map_err(|e| e.to_string())
+    }
+
+    fn segment_len(segment: &File) -> Result<u64, String> {
+        segment
+            .metadata()
+            .map(|metadata| metadata.len())
+            .map_err(|e| format!("Failed to retrieve metadata: {}", e))
+    }
+
+    /// Grow the segments (and create new ones) so that the storage has at least `size_bytes`.
+    fn ensure_size(&mut self, size_bytes: u64) -> Result<(), String> {
+        if size_bytes == 0 || self.size_bytes()? >= size_bytes {
+            return Ok(());
+        }
+        let last_index = ((size_bytes - 1) / self.segment_size_bytes) as usize;
+        for index in 0..=last_index {
+            if index == self.segments.len() {
+                let segment_path = Self::segment_path(&self.file_path, index);
+                info!("Creating persistent storage segment {:?}", segment_path);
+                self.segments.push(Self::open_segment(&segment_path)?);
+            }
+            let segment_size_bytes = if index < last_index {
+                self.segment_size_bytes
+            } else {
+                size_bytes - last_index as u64 * self.segment_size_bytes
+            };
+            let segment = &self.segments[index];
+            if Self::segment_len(segment)? < segment_size_bytes {
+                segment
+                    .set_len(segment_size_bytes)
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the range `offset..offset + len` into (segment index, offset in segment, range in buffer).
+    fn segment_ranges(&self, offset: u64, len: usize) -> Vec<(usize, u64, std::ops::Range<usize>)> {
+        let mut ranges = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let abs_offset = offset + pos as u64;
+            let segment_offset = abs_offset % self.segment_size_bytes;
+            let n = ((self.segment_size_bytes - segment_offset) as usize).min(len - pos);
+            ranges.push((
+                (abs_offset / self.segment_size_bytes) as usize,
+                segment_offset,
+                pos..pos + n,
+            ));
+            pos += n;
+        }
+        ranges
+    }
+}
+
+impl StorageBackend for SegmentedFile {
+    fn path(&self) -> Option<PathBuf> {
+        Some(self.file_path.clone())
+    }
+
+    fn size_bytes(&self) -> Result<u64, String> {
+        match self.segments.last() {
+            Some(segment) => Ok((self.segments.len() as u64 - 1) * self.segment_size_bytes
+                + Self::segment_len(segment)?),
+            None => Ok(0),
+        }
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        if offset + buf.len() as u64 > self.size_bytes()? {
+            return Err(
+                "Failed to read from persistent storage: read beyond end of file.".to_string(),
+            );
+        }
+        for (index, segment_offset, range) in self.segment_ranges(offset, buf.len()) {
+            let segment = &mut self.segments[index];
+            segment
+                .seek(SeekFrom::Start(segment_offset))
+                .map_err(|e| e.to_string())?;
+            segment
+                .read_exact(&mut buf[range])
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.ensure_size(offset + (buf.len() as u64).max(PERSISTENT_STORAGE_PAGE_SIZE))
+            .map_err(std::io::Error::other)?;
+        debug!(
+            "Writing {} bytes to persistent storage @offset 0x{:0x}",
+            buf.len(),
+            offset
+        );
+        for (index, segment_offset, range) in self.segment_ranges(offset, buf.len()) {
+            let segment = &mut self.segments[index];
+            segment.seek(SeekFrom::Start(segment_offset))?;
+            segment.write_all(&buf[range])?;
+        }
+        Ok(())
+    }
+
+    fn grow(&mut self, additional_pages: u64) -> Result<u64, String> {
+        let previous_size_bytes = self.size_bytes()?;
+        self.ensure_size(previous_size_bytes + additional_pages * PERSISTENT_STORAGE_PAGE_SIZE)?;
+        Ok(previous_size_bytes)
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        for segment in &self.segments {
+            segment
+                .sync_data()
+                .map_err(|e| format!("Failed to sync segment of {:?}: {}", self.file_path, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Persistent storage held in memory, like the ephemeral storage of the browser, for fast and
+/// isolated tests. Its contents are lost when it is dropped, unless taken with `into_bytes`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Storage with the given contents, e.g. from `into_bytes` of another memory storage.
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        MemoryStorage { data }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn size_bytes(&self) -> Result<u64, String> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        let end = offset + buf.len() as u64;
+        if end > self.data.len() as u64 {
+            return Err(
+                "Failed to read from persistent storage: read beyond end of storage.".to_string(),
+            );
+        }
+        buf.copy_from_slice(&self.data[offset as usize..end as usize]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        // Grow in whole pages at least, like the backing file
+        let min_size_bytes = offset + (buf.len() as u64).max(PERSISTENT_STORAGE_PAGE_SIZE);
+        if (self.data.len() as u64) < min_size_bytes {
+            self.data.resize(min_size_bytes as usize, 0);
+        }
+        self.data[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn grow(&mut self, additional_pages: u64) -> Result<u64, String> {
+        let previous_size_bytes = self.data.len() as u64;
+        self.data.resize(
+            (previous_size_bytes + additional_pages * PERSISTENT_STORAGE_PAGE_SIZE) as usize,
+            0,
+        );
+        Ok(previous_size_bytes)
+    }
+}
+
 fn default_file_path() -> PathBuf {
-    dirs::data_local_dir()
+    #[cfg(any(target_os = "ios", target_os = "android"))]
+    let data_dir = crate::platform_specific_mobile::default_data_dir();
+    #[cfg(not(any(target_os = "ios", target_os = "android")))]
+    let data_dir = dirs::data_local_dir();
+    data_dir
         .map(|path| path.join("ledger-map").join("data.bin"))
         .unwrap_or_else(|| PathBuf::from("data.bin"))
 }
 
+/// Storage backend that is owned by a `LedgerMap`, and is the active backend of the thread while
+/// the ledger accesses it.
+pub type SharedStorageBackend = Arc<Mutex<Box<dyn StorageBackend>>>;
+
 thread_local! {
-    pub static BACKING_FILE: RefCell<Option<BackingFile>> = const { RefCell::new(None) };
+    /// Active storage backend of the thread, used by the `persistent_storage_*` functions.
+    /// Every `LedgerMap` keeps its own backend and activates it before each storage access,
+    /// so several ledgers with different backends can be used on the same thread.
+    pub static BACKING_FILE: RefCell<Option<SharedStorageBackend>> = const { RefCell::new(None) };
 }
 
 pub fn set_backing_file(file_path: Option<PathBuf>) -> Result<(), String> {
-    BACKING_FILE.with(|backing_file| {
-        backing_file.replace(Some(BackingFile::new(file_path)?));
-        Ok(())
-    })
+    // The backing file is locked while it is open on Windows, so close the previous one first,
+    // in case the same file is opened again
+    #[cfg(windows)]
+    BACKING_FILE.with(|backing_file| backing_file.replace(None));
+    set_storage_backend(Box::new(BackingFile::new(file_path)?));
+    Ok(())
 }
 
-pub fn get_backing_file_path() -> Option<PathBuf> {
+/// Use multiple segment files of at most `segment_size_bytes` each as the persistent storage.
+pub fn set_segmented_backing_file(
+    file_path: Option<PathBuf>,
+    segment_size_bytes: u64,
+) -> Result<(), String> {
+    set_storage_backend(Box::new(SegmentedFile::new(file_path, segment_size_bytes)?));
+    Ok(())
+}
+
+/// Use a new, empty `MemoryStorage` as the persistent storage.
+pub fn set_memory_storage() {
+    set_storage_backend(Box::new(MemoryStorage::new()));
+}
+
+/// Make `backend` the active storage backend of the thread. Ledgers that are already open keep
+/// using their own backends.
+pub fn set_storage_backend(backend: Box<dyn StorageBackend>) {
     BACKING_FILE.with(|backing_file| {
-        backing_file
-            .borrow()
-            .as_ref()
-            .map(|bf| bf.file_path.clone())
+        backing_file.replace(Some(Arc::new(Mutex::new(backend))));
     })
 }
 
-pub fn get_or_create_backing_file() -> Result<BackingFile, String> {
+/// Returns the active storage backend of the thread, opening the default backing file if needed.
+pub fn active_storage_backend() -> Result<SharedStorageBackend, String> {
     BACKING_FILE.with(|backing_file| {
         let mut binding = backing_file.borrow_mut();
-
         if binding.is_none() {
             // Initialize the backing file if it doesn't exist
-            let new_file = BackingFile::new(None).map_err(|e| e.to_string())?;
-            *binding = Some(new_file);
+            *binding = Some(Arc::new(Mutex::new(Box::new(BackingFile::new(None)?))));
         }
-
-        // Return a clone of the BackingFile reference
         binding
-            .as_ref()
-            .map(|bf| BackingFile {
-                file: bf.file.try_clone().expect("Failed to clone file handle"),
-                file_path: bf.file_path.clone(),
-            })
+            .clone()
             .ok_or_else(|| "Failed to access backing file".to_string())
     })
 }
 
+/// Make `backend` the active storage backend of the thread, unless it already is.
+pub fn activate_storage_backend(backend: &SharedStorageBackend) {
+    BACKING_FILE.with(|backing_file| {
+        let is_active = backing_file
+            .borrow()
+            .as_ref()
+            .is_some_and(|active| Arc::ptr_eq(active, backend));
+        if !is_active {
+            backing_file.replace(Some(backend.clone()));
+        }
+    })
+}
+
+/// Run `f` with `backend` as the active storage backend of the thread, then switch back to the
+/// previous backend, even if `f` panics.
+pub fn with_swapped_storage_backend<R>(backend: &SharedStorageBackend, f: impl FnOnce() -> R) -> R {
+    /// Switches back to the previous backend when dropped, also while unwinding.
+    struct SwapGuard {
+        previous: Option<SharedStorageBackend>,
+    }
+
+    impl Drop for SwapGuard {
+        fn drop(&mut self) {
+            let previous = self.previous.take();
+            BACKING_FILE.with(|backing_file| backing_file.replace(previous));
+        }
+    }
+
+    let previous = BACKING_FILE.with(|backing_file| backing_file.replace(Some(backend.clone())));
+    let _guard = SwapGuard { previous };
+    f()
+}
+
+pub fn get_backing_file_path() -> Option<PathBuf> {
+    BACKING_FILE.with(|backing_file| {
+        backing_file.borrow().as_ref().and_then(|backend| {
+            backend
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .path()
+        })
+    })
+}
+
+/// Run `f` with the active storage backend, initializing the default backing file if needed.
+fn with_storage_backend<R>(
+    f: impl FnOnce(&mut dyn StorageBackend) -> Result<R, String>,
+) -> Result<R, String> {
+    let backend = active_storage_backend()?;
+    let mut backend = backend.lock().unwrap_or_else(PoisonError::into_inner);
+    f(backend.as_mut())
+}
+
 pub fn persistent_storage_size_bytes() -> u64 {
     BACKING_FILE.with(|backing_file| {
         backing_file
             .borrow()
             .as_ref()
-            .and_then(|bf| bf.metadata().ok().map(|metadata| metadata.len()))
+            .and_then(|backend| {
+                backend
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .size_bytes()
+                    .ok()
+            })
             .unwrap_or(0)
     })
 }
 
+/// Returns the first valid offset of the persistent storage, which is always 0 on this platform.
+pub fn persistent_storage_first_valid_offset() -> u64 {
+    0
+}
+
 pub fn persistent_storage_last_valid_offset() -> u64 {
     persistent_storage_size_bytes()
 }
 
 pub fn persistent_storage_read(offset: u64, buf: &mut [u8]) -> Result<(), String> {
-    let mut backing_file = get_or_create_backing_file()?;
-    backing_file.read(offset, buf)
+    with_storage_backend(|backend| backend.read(offset, buf))
 }
 
-pub fn persistent_storage_write(offset: u64, buf: &[u8]) {
-    let mut backing_file = get_or_create_backing_file().expect("Backing file should exist");
-    backing_file
-        .write(offset, buf)
-        .expect("Failed to write to persistent storage");
+/// Write `buf` at `offset`, growing the persistent storage as needed.
+pub fn persistent_storage_write(offset: u64, buf: &[u8]) -> Result<(), LedgerError> {
+    with_storage_backend(|backend| Ok(backend.write(offset, buf)))
+        .map_err(std::io::Error::other)
+        .and_then(|result| result)
+        .map_err(LedgerError::from)
 }
 
 pub fn persistent_storage_grow(additional_pages: u64) -> Result<u64, String> {
-    let mut backing_file = get_or_create_backing_file()?;
-    backing_file.grow(additional_pages)
+    with_storage_backend(|backend| backend.grow(additional_pages))
+}
+
+/// Make the writes to the persistent storage so far durable, see `StorageBackend::sync`.
+pub fn persistent_storage_sync() -> Result<(), String> {
+    with_storage_backend(|backend| backend.sync())
 }
 
 pub const PERSISTENT_STORAGE_PAGE_SIZE: u64 = 64 * 1024;
@@ -227,9 +633,128 @@ pub fn export_error() -> Vec<String> {
     Vec::new()
 }
 
-pub(crate) fn get_timestamp_nanos() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_nanos() as u64
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+}
+
+/// There is no caller identity on this platform, so the default principal is empty.
+pub(crate) fn get_caller_principal() -> Vec<u8> {
+    Vec::new()
+}
+
+/// Fill `buf` with random bytes from the operating system.
+pub(crate) fn fill_random(buf: &mut [u8]) -> Result<(), String> {
+    getrandom::fill(buf).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_clock() {
+        let before_ns = SystemClock.now_nanos();
+        // Later than 2020-01-01
+        assert!(before_ns > 1_577_836_800_000_000_000);
+        assert!(SystemClock.now_nanos() >= before_ns);
+    }
+
+    #[test]
+    fn test_swapped_storage_backend_restored_after_panic() {
+        set_memory_storage();
+        persistent_storage_write(0, b"outer").unwrap();
+
+        let swapped: SharedStorageBackend = Arc::new(Mutex::new(Box::new(MemoryStorage::new())));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            with_swapped_storage_backend(&swapped, || {
+                persistent_storage_write(0, b"inner").unwrap();
+                panic!("failure while swapped");
+            })
+        }));
+        assert!(result.is_err());
+
+        // The outer backend is active again, and the swapped one kept the write
+        let mut buf = [0u8; 5];
+        persistent_storage_read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"outer");
+        swapped.lock().unwrap().read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"inner");
+    }
+
+    #[test]
+    fn test_segmented_file_read_write() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        assert!(SegmentedFile::new(Some(file_path.clone()), 1000).is_err());
+
+        let mut storage =
+            SegmentedFile::new(Some(file_path.clone()), PERSISTENT_STORAGE_PAGE_SIZE).unwrap();
+        assert_eq!(storage.size_bytes().unwrap(), 0);
+
+        // Write across the boundary of the first and second segment, into the third one
+        let data = (0..PERSISTENT_STORAGE_PAGE_SIZE + 200)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let offset = PERSISTENT_STORAGE_PAGE_SIZE - 100;
+        storage.write(offset, &data).unwrap();
+        assert_eq!(storage.size_bytes().unwrap(), offset + data.len() as u64);
+        assert!(SegmentedFile::segment_path(&file_path, 2).exists());
+        assert!(!SegmentedFile::segment_path(&file_path, 3).exists());
+
+        let mut buf = vec![0u8; data.len()];
+        storage.read(offset, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert!(storage
+            .read(storage.size_bytes().unwrap() - 10, &mut buf)
+            .is_err());
+
+        // Reopening picks up the existing segments
+        let mut storage =
+            SegmentedFile::new(Some(file_path.clone()), PERSISTENT_STORAGE_PAGE_SIZE).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        storage.read(offset, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        let previous_size = storage.grow(2).unwrap();
+        assert_eq!(
+            storage.size_bytes().unwrap(),
+            previous_size + 2 * PERSISTENT_STORAGE_PAGE_SIZE
+        );
+    }
+
+    #[test]
+    fn test_memory_storage_read_write() {
+        let mut storage = MemoryStorage::new();
+        assert_eq!(storage.size_bytes().unwrap(), 0);
+        assert!(storage.path().is_none());
+
+        storage.write(100, b"hello").unwrap();
+        assert_eq!(
+            storage.size_bytes().unwrap(),
+            100 + PERSISTENT_STORAGE_PAGE_SIZE
+        );
+        let mut buf = [0u8; 7];
+        storage.read(99, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0hello\0");
+        assert!(storage
+            .read(storage.size_bytes().unwrap() - 3, &mut buf)
+            .is_err());
+
+        let previous_size = storage.grow(2).unwrap();
+        assert_eq!(
+            storage.size_bytes().unwrap(),
+            previous_size + 2 * PERSISTENT_STORAGE_PAGE_SIZE
+        );
+
+        let mut storage = MemoryStorage::from_bytes(storage.into_bytes());
+        storage.read(99, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0hello\0");
+    }
 }
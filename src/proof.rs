@@ -0,0 +1,153 @@
+/// This module implements standalone proofs of ledger entries, which third parties can verify
+/// without access to the ledger storage, given only a trusted (e.g. published or anchored) tip
+/// hash of the ledger.
+///
+/// A proof holds the block with the latest write of the entry and all the blocks after it, up to
+/// the tip, so that the verifier can recompute the chain hash up to the tip and check that the
+/// entry was not overwritten or deleted since. Proofs of recently written entries are small.
+///
+/// Example usage:
+///
+/// ```rust,no_run
+/// use ledger_map::{verify_entry_proof, ProofBlob, LedgerMap};
+///
+/// let ledger_map = LedgerMap::new_with_path(None, None).unwrap();
+/// let proof_bytes = ledger_map.prove_entry("accounts", b"alice").unwrap().to_bytes().unwrap();
+/// let tip_hash = ledger_map.get_latest_block_hash();
+///
+/// // Elsewhere, with only the proof bytes and the published tip hash
+/// let proof = ProofBlob::from_bytes(&proof_bytes).unwrap();
+/// let entry = verify_entry_proof(&proof, &tip_hash).unwrap();
+/// println!("alice: {:?}", entry.value());
+/// ```
+use crate::hashing;
+use crate::ledger_entry::{LedgerEntry, Operation};
+use crate::LedgerError;
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Format version of the entry proofs written by this version of the library.
+/// Version 2 added `ProofBlock::entry_hashes`.
+pub const PROOF_BLOB_VERSION: u32 = 2;
+
+/// Block of an entry proof: the data that the chain hash of a block is computed from.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProofBlock {
+    pub entries: Vec<LedgerEntry>,
+    pub timestamp: u64,
+    /// Whether the chain hash of the block is computed from the entry hashes (chain hash
+    /// version 2), see `hashing`.
+    pub entry_hashes: bool,
+}
+
+/// Block of an entry proof of version 1, whose chain hashes are all of version 1.
+#[derive(BorshDeserialize)]
+struct ProofBlockV1 {
+    entries: Vec<LedgerEntry>,
+    timestamp: u64,
+}
+
+#[derive(BorshDeserialize)]
+struct ProofBlobV1 {
+    version: u32,
+    label: String,
+    key: Vec<u8>,
+    parent_hash: Vec<u8>,
+    blocks: Vec<ProofBlockV1>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProofBlob {
+    pub version: u32,
+    pub label: String,
+    pub key: Vec<u8>,
+    /// Parent hash of the first block of the proof.
+    pub parent_hash: Vec<u8>,
+    /// The block with the latest write of the entry, followed by all the blocks up to the tip.
+    pub blocks: Vec<ProofBlock>,
+}
+
+impl ProofBlob {
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(borsh::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let version = u32::deserialize(&mut &bytes[..])?;
+        match version {
+            1 => {
+                let proof = ProofBlobV1::try_from_slice(bytes)?;
+                Ok(ProofBlob {
+                    version: proof.version,
+                    label: proof.label,
+                    key: proof.key,
+                    parent_hash: proof.parent_hash,
+                    blocks: proof
+                        .blocks
+                        .into_iter()
+                        .map(|block| ProofBlock {
+                            entries: block.entries,
+                            timestamp: block.timestamp,
+                            entry_hashes: false,
+                        })
+                        .collect(),
+                })
+            }
+            PROOF_BLOB_VERSION => Ok(Self::try_from_slice(bytes)?),
+            _ => Err(anyhow::format_err!(
+                "Unsupported entry proof version {}, the latest supported is {}",
+                version,
+                PROOF_BLOB_VERSION
+            )),
+        }
+    }
+}
+
+/// Verify `proof` against `trusted_tip_hash`, the chain hash of the ledger tip obtained from a
+/// trusted source. Returns the proven entry, which is the current value of its key at that tip.
+pub fn verify_entry_proof<'a>(
+    proof: &'a ProofBlob,
+    trusted_tip_hash: &[u8],
+) -> anyhow::Result<&'a LedgerEntry> {
+    let is_proven_key =
+        |entry: &&LedgerEntry| entry.label() == proof.label && entry.key() == proof.key;
+    let (first_block, later_blocks) = proof
+        .blocks
+        .split_first()
+        .ok_or_else(|| anyhow::format_err!("Entry proof has no blocks"))?;
+    let entry = first_block
+        .entries
+        .iter()
+        .find(is_proven_key)
+        .filter(|entry| entry.operation() == Operation::Upsert)
+        .ok_or(LedgerError::EntryNotFound)?;
+    if later_blocks
+        .iter()
+        .any(|block| block.entries.iter().any(|entry| is_proven_key(&entry)))
+    {
+        return Err(anyhow::format_err!(
+            "Entry proof has later writes of the entry"
+        ));
+    }
+
+    let mut chain_hash = proof.parent_hash.clone();
+    for block in &proof.blocks {
+        chain_hash = if block.entry_hashes {
+            let entry_hashes = block
+                .entries
+                .iter()
+                .map(hashing::entry_hash)
+                .collect::<Vec<_>>();
+            hashing::chain_hash_from_entry_hashes(&chain_hash, &entry_hashes, block.timestamp)
+        } else {
+            hashing::chain_hash(&chain_hash, &block.entries, block.timestamp)
+        };
+    }
+    if chain_hash != trusted_tip_hash {
+        return Err(anyhow::format_err!(
+            "Entry proof ends with the chain hash {}, expected {}",
+            hex::encode(chain_hash),
+            hex::encode(trusted_tip_hash)
+        ));
+    }
+    Ok(entry)
+}
@@ -0,0 +1,86 @@
+/// This module implements a composable filter over the committed entries of a label, executed by
+/// `LedgerMap::query`. The conditions are pushed down to the index and the block chain where
+/// possible, e.g. `updated_after` only reads the blocks committed after the given timestamp.
+///
+/// Example usage:
+///
+/// ```rust,no_run
+/// use ledger_map::{LedgerMap, Query};
+///
+/// let ledger_map = LedgerMap::new_with_path(None, None).unwrap();
+/// let query = Query::label("users")
+///     .key_prefix(b"team-a/")
+///     .updated_after(1_700_000_000_000_000_000)
+///     .limit(20);
+/// for entry in ledger_map.query(&query).unwrap() {
+///     println!("{:?}", entry);
+/// }
+/// ```
+use crate::ledger_entry::LedgerEntry;
+
+type EntryPredicate<'a> = Box<dyn Fn(&LedgerEntry) -> bool + 'a>;
+
+pub struct Query<'a> {
+    pub(crate) label: String,
+    pub(crate) key_prefix: Vec<u8>,
+    pub(crate) updated_after_ns: Option<u64>,
+    pub(crate) limit: Option<usize>,
+    predicates: Vec<EntryPredicate<'a>>,
+}
+
+impl std::fmt::Debug for Query<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Query")
+            .field("label", &self.label)
+            .field("key_prefix", &self.key_prefix)
+            .field("updated_after_ns", &self.updated_after_ns)
+            .field("limit", &self.limit)
+            .field("predicates", &self.predicates.len())
+            .finish()
+    }
+}
+
+impl<'a> Query<'a> {
+    /// Query the committed entries of `label`.
+    pub fn label<S: AsRef<str>>(label: S) -> Self {
+        Query {
+            label: label.as_ref().to_string(),
+            key_prefix: Vec::new(),
+            updated_after_ns: None,
+            limit: None,
+            predicates: Vec::new(),
+        }
+    }
+
+    /// Only the entries whose key starts with `prefix`.
+    pub fn key_prefix<K: AsRef<[u8]>>(mut self, prefix: K) -> Self {
+        self.key_prefix = prefix.as_ref().to_vec();
+        self
+    }
+
+    /// Only the entries last written in a block with a timestamp strictly after `timestamp_ns`.
+    /// The entries are then returned from the most recently updated to the least recently updated.
+    pub fn updated_after(mut self, timestamp_ns: u64) -> Self {
+        self.updated_after_ns = Some(timestamp_ns);
+        self
+    }
+
+    /// At most `limit` entries.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Only the entries for which `predicate` is true. Can be called several times, in which case
+    /// all predicates must be true.
+    pub fn filter<F: Fn(&LedgerEntry) -> bool + 'a>(mut self, predicate: F) -> Self {
+        self.predicates.push(Box::new(predicate));
+        self
+    }
+
+    /// Returns true if `entry` passes the key prefix and the predicates of the query.
+    pub(crate) fn matches(&self, entry: &LedgerEntry) -> bool {
+        entry.key().starts_with(&self.key_prefix)
+            && self.predicates.iter().all(|predicate| predicate(entry))
+    }
+}
@@ -0,0 +1,315 @@
+/// This module implements a persistent storage backend on top of the redb embedded database.
+///
+/// The persistent storage is split into fixed-size chunks, stored in a redb table keyed by the
+/// offset of the chunk. The database can be shared with the rest of the application, since the
+/// ledger only uses its own tables. Written chunks are buffered in memory and committed together
+/// in a single redb write transaction on `StorageBackend::sync`, when too many chunks are pending,
+/// and on drop. Build the ledger with `Durability::Sync` to commit every block in its own
+/// transaction, or call `platform_specific::persistent_storage_sync` to commit the pending writes.
+///
+/// Example usage:
+///
+/// ```rust,no_run
+/// use ledger_map::redb_storage::RedbStorage;
+/// use ledger_map::{platform_specific, Durability, LedgerMap};
+/// use std::sync::Arc;
+///
+/// let db = Arc::new(redb::Database::create("/tmp/ledger_map/app.redb").unwrap());
+/// let storage = RedbStorage::new_with_database(db, "ledger_map").unwrap();
+/// platform_specific::set_storage_backend(Box::new(storage));
+/// let ledger_map = LedgerMap::builder()
+///     .durability(Durability::Sync)
+///     .build()
+///     .unwrap();
+/// ```
+use crate::platform_specific::{StorageBackend, PERSISTENT_STORAGE_PAGE_SIZE};
+use crate::{error, info};
+use redb::{Database, ReadableTable, TableDefinition};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const SIZE_BYTES_KEY: &str = "size_bytes";
+/// Number of pending chunks after which they are committed without waiting for a sync.
+const MAX_PENDING_CHUNKS: usize = 256;
+
+pub struct RedbStorage {
+    db: Arc<Database>,
+    path: Option<PathBuf>,
+    chunks_table: String,
+    meta_table: String,
+    chunk_size_bytes: u64,
+    size_bytes: u64,
+    committed_size_bytes: u64,
+    /// Chunks written since the last commit, by chunk key.
+    pending_chunks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl RedbStorage {
+    /// Create (or open) a dedicated redb database at `path` for the persistent storage.
+    pub fn new(path: PathBuf) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            fs_err::create_dir_all(parent).map_err(|e| format!("{:?}", e))?;
+        }
+        let db = Database::create(&path).map_err(|e| e.to_string())?;
+        let mut storage = Self::new_with_database(Arc::new(db), "ledger_map")?;
+        storage.path = Some(path);
+        Ok(storage)
+    }
+
+    /// Use tables prefixed with `table_name` in an existing redb database for the persistent storage.
+    pub fn new_with_database(db: Arc<Database>, table_name: &str) -> Result<Self, String> {
+        Self::new_with_chunk_size(db, table_name, PERSISTENT_STORAGE_PAGE_SIZE)
+    }
+
+    pub fn new_with_chunk_size(
+        db: Arc<Database>,
+        table_name: &str,
+        chunk_size_bytes: u64,
+    ) -> Result<Self, String> {
+        if chunk_size_bytes == 0 {
+            return Err("Chunk size must be non-zero".to_string());
+        }
+        let chunks_table = format!("{}_chunks", table_name);
+        let meta_table = format!("{}_meta", table_name);
+
+        // Create the tables if needed, so that read transactions can always open them
+        let txn = db.begin_write().map_err(|e| e.to_string())?;
+        txn.open_table(TableDefinition::<u64, &[u8]>::new(&chunks_table))
+            .map_err(|e| e.to_string())?;
+        let size_bytes = txn
+            .open_table(TableDefinition::<&str, u64>::new(&meta_table))
+            .map_err(|e| e.to_string())?
+            .get(SIZE_BYTES_KEY)
+            .map_err(|e| e.to_string())?
+            .map(|v| v.value())
+            .unwrap_or_default();
+        txn.commit().map_err(|e| e.to_string())?;
+        info!(
+            "Opened redb storage {} of size {} bytes",
+            table_name, size_bytes
+        );
+
+        Ok(RedbStorage {
+            db,
+            path: None,
+            chunks_table,
+            meta_table,
+            chunk_size_bytes,
+            size_bytes,
+            committed_size_bytes: size_bytes,
+            pending_chunks: BTreeMap::new(),
+        })
+    }
+
+    fn chunks_table(&self) -> TableDefinition<'_, u64, &'static [u8]> {
+        TableDefinition::new(&self.chunks_table)
+    }
+
+    fn meta_table(&self) -> TableDefinition<'_, &'static str, u64> {
+        TableDefinition::new(&self.meta_table)
+    }
+
+    /// Split the range `offset..offset + len` into (chunk key, offset in chunk, range in buffer).
+    fn chunk_ranges(&self, offset: u64, len: usize) -> Vec<(u64, usize, std::ops::Range<usize>)> {
+        let mut ranges = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let abs_offset = offset + pos as u64;
+            let chunk_offset = (abs_offset % self.chunk_size_bytes) as usize;
+            let n = (self.chunk_size_bytes as usize - chunk_offset).min(len - pos);
+            ranges.push((abs_offset - chunk_offset as u64, chunk_offset, pos..pos + n));
+            pos += n;
+        }
+        ranges
+    }
+
+    /// Read the committed chunk at `chunk_key`, resized to the chunk size.
+    fn read_committed_chunk(&self, chunk_key: u64) -> Result<Vec<u8>, String> {
+        let txn = self.db.begin_read().map_err(|e| e.to_string())?;
+        let table = txn
+            .open_table(self.chunks_table())
+            .map_err(|e| e.to_string())?;
+        // Chunks are never truncated, but may be shorter than the chunk size
+        let mut chunk = table
+            .get(chunk_key)
+            .map_err(|e| e.to_string())?
+            .map(|chunk| chunk.value().to_vec())
+            .unwrap_or_default();
+        chunk.resize(self.chunk_size_bytes as usize, 0);
+        Ok(chunk)
+    }
+
+    /// Commit the pending chunks and the storage size in a single write transaction.
+    fn commit_pending(&mut self) -> Result<(), String> {
+        if self.pending_chunks.is_empty() && self.committed_size_bytes == self.size_bytes {
+            return Ok(());
+        }
+        let txn = self.db.begin_write().map_err(|e| e.to_string())?;
+        {
+            let mut table = txn
+                .open_table(self.chunks_table())
+                .map_err(|e| e.to_string())?;
+            for (chunk_key, chunk) in &self.pending_chunks {
+                table
+                    .insert(*chunk_key, chunk.as_slice())
+                    .map_err(|e| e.to_string())?;
+            }
+            txn.open_table(self.meta_table())
+                .map_err(|e| e.to_string())?
+                .insert(SIZE_BYTES_KEY, self.size_bytes)
+                .map_err(|e| e.to_string())?;
+        }
+        txn.commit().map_err(|e| e.to_string())?;
+        self.pending_chunks.clear();
+        self.committed_size_bytes = self.size_bytes;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> Result<(), String> {
+        for (chunk_key, chunk_offset, range) in self.chunk_ranges(offset, buf.len()) {
+            if !self.pending_chunks.contains_key(&chunk_key) {
+                let chunk = self.read_committed_chunk(chunk_key)?;
+                self.pending_chunks.insert(chunk_key, chunk);
+            }
+            let chunk = self
+                .pending_chunks
+                .get_mut(&chunk_key)
+                .expect("pending chunk was just inserted");
+            chunk[chunk_offset..chunk_offset + range.len()].copy_from_slice(&buf[range]);
+        }
+        let size_bytes_min = offset + (buf.len() as u64).max(PERSISTENT_STORAGE_PAGE_SIZE);
+        self.size_bytes = self.size_bytes.max(size_bytes_min);
+        if self.pending_chunks.len() > MAX_PENDING_CHUNKS {
+            self.commit_pending()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RedbStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit_pending() {
+            error!("Failed to commit redb storage {}: {}", self.chunks_table, e);
+        }
+    }
+}
+
+impl StorageBackend for RedbStorage {
+    fn path(&self) -> Option<PathBuf> {
+        self.path.clone()
+    }
+
+    fn size_bytes(&self) -> Result<u64, String> {
+        Ok(self.size_bytes)
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+        if offset + buf.len() as u64 > self.size_bytes {
+            return Err(
+                "Failed to read from persistent storage: read beyond end of file.".to_string(),
+            );
+        }
+        let txn = self.db.begin_read().map_err(|e| e.to_string())?;
+        let table = txn
+            .open_table(self.chunks_table())
+            .map_err(|e| e.to_string())?;
+        for (chunk_key, chunk_offset, range) in self.chunk_ranges(offset, buf.len()) {
+            let n = range.len();
+            if let Some(chunk) = self.pending_chunks.get(&chunk_key) {
+                buf[range].copy_from_slice(&chunk[chunk_offset..chunk_offset + n]);
+                continue;
+            }
+            match table.get(chunk_key).map_err(|e| e.to_string())? {
+                Some(chunk) => {
+                    // Chunks are never truncated, but may be shorter than the chunk size
+                    let mut data = chunk.value().to_vec();
+                    data.resize(self.chunk_size_bytes as usize, 0);
+                    buf[range].copy_from_slice(&data[chunk_offset..chunk_offset + n]);
+                }
+                None => buf[range].fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.write_bytes(offset, buf).map_err(std::io::Error::other)
+    }
+
+    fn grow(&mut self, additional_pages: u64) -> Result<u64, String> {
+        let previous_size_bytes = self.size_bytes;
+        self.size_bytes += additional_pages * PERSISTENT_STORAGE_PAGE_SIZE;
+        Ok(previous_size_bytes)
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        self.commit_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LedgerMap;
+
+    #[test]
+    fn test_redb_storage_read_write() {
+        let path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.redb");
+        let mut storage = RedbStorage::new(path.clone()).unwrap();
+        assert_eq!(storage.size_bytes().unwrap(), 0);
+
+        let offset = PERSISTENT_STORAGE_PAGE_SIZE - 100;
+        let data = (0..PERSISTENT_STORAGE_PAGE_SIZE + 200)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        storage.write(offset, &data).unwrap();
+        assert_eq!(storage.size_bytes().unwrap(), offset + data.len() as u64);
+
+        let mut buf = vec![0u8; data.len()];
+        storage.read(offset, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        let mut buf = vec![1u8; 50];
+        storage.read(0, &mut buf).unwrap();
+        assert_eq!(buf, vec![0u8; 50]);
+        assert!(storage.read(storage.size_bytes, &mut buf).is_err());
+
+        // Writes are committed on sync, and the pending writes on drop
+        storage.sync().unwrap();
+        assert!(storage.pending_chunks.is_empty());
+        storage.write(0, b"pending").unwrap();
+        assert_eq!(storage.pending_chunks.len(), 1);
+        drop(storage);
+
+        let mut storage = RedbStorage::new(path).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        storage.read(offset, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        let mut buf = vec![0u8; 7];
+        storage.read(0, &mut buf).unwrap();
+        assert_eq!(buf, b"pending");
+    }
+
+    #[test]
+    fn test_ledger_map_on_shared_redb_database() {
+        let path = tempfile::tempdir().unwrap().keep().join("test_app.redb");
+        let db = Arc::new(Database::create(path).unwrap());
+
+        let storage = RedbStorage::new_with_database(db.clone(), "ledger").unwrap();
+        crate::platform_specific::set_storage_backend(Box::new(storage));
+        let mut ledger_map = LedgerMap::new(None).unwrap();
+        ledger_map.upsert("Label1", b"key1", b"value1").unwrap();
+        ledger_map.commit_block().unwrap();
+        let chain_hash = ledger_map.get_latest_block_hash();
+        crate::platform_specific::persistent_storage_sync().unwrap();
+
+        let storage = RedbStorage::new_with_database(db, "ledger").unwrap();
+        crate::platform_specific::set_storage_backend(Box::new(storage));
+        let ledger_map = LedgerMap::new(None).unwrap();
+        assert_eq!(ledger_map.get("Label1", b"key1").unwrap(), b"value1");
+        assert_eq!(ledger_map.get_latest_block_hash(), chain_hash);
+    }
+}
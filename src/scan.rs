@@ -0,0 +1,159 @@
+//! This module implements resumable scans over all entries of the committed blocks, see
+//! `LedgerMap::scan`. The position of a scan is a `ScanHandle`, which can be saved at any point
+//! and used to resume the scan later, e.g. after a restart of a long-running export job.
+use crate::errors::LedgerError;
+use crate::ledger_entry::LedgerEntry;
+use crate::ledger_map::LedgerMap;
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Position of a scan: the storage offset of the current block and the index of the next entry
+/// in it. The default handle starts a scan at the first block.
+///
+/// The handle also records the parent hash and the number of entries of the current block, so
+/// that resuming fails instead of yielding the wrong entries if the blocks were rewritten since,
+/// e.g. by `prune_blocks`, `migrate_format` or `restore_snapshot`. Blocks committed after the
+/// handle was saved are scanned as well.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanHandle {
+    /// Storage offset of the current block, 0 if the scan has not read a block yet.
+    pub block_offset: u64,
+    /// Index of the next entry in the current block.
+    pub entry_index: u32,
+    /// Number of entries of the current block.
+    pub entries_count: u32,
+    /// Parent hash of the current block.
+    pub parent_hash: Vec<u8>,
+}
+
+impl ScanHandle {
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(borsh::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self::try_from_slice(bytes)?)
+    }
+}
+
+/// Iterator over all entries of the committed blocks from a `ScanHandle` on, in block order,
+/// including deletes and the entries of labels that are not indexed. One block is held in memory
+/// at a time. The scan stops after the first error.
+pub struct Scan<'a> {
+    ledger_map: &'a LedgerMap,
+    handle: ScanHandle,
+    /// Current block, once read.
+    block: Option<ScannedBlock>,
+    failed: bool,
+}
+
+struct ScannedBlock {
+    entries: Vec<LedgerEntry>,
+    /// Distance to the next block in the persistent storage, in bytes.
+    len: u64,
+    parent_hash: Vec<u8>,
+}
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(ledger_map: &'a LedgerMap, handle: ScanHandle) -> Self {
+        Scan {
+            ledger_map,
+            handle,
+            block: None,
+            failed: false,
+        }
+    }
+
+    /// Position of the scan after the entries yielded so far, to be saved to resume the scan.
+    pub fn handle(&self) -> &ScanHandle {
+        &self.handle
+    }
+
+    /// Read the block at `offset` and make it the current block. Returns `false` at the end of
+    /// the blocks.
+    fn enter_block(&mut self, offset: u64) -> Result<bool, LedgerError> {
+        let Some(block) = self.read_block(offset)? else {
+            return Ok(false);
+        };
+        self.handle = ScanHandle {
+            block_offset: offset,
+            entry_index: 0,
+            entries_count: block.entries.len() as u32,
+            parent_hash: block.parent_hash.clone(),
+        };
+        self.block = Some(block);
+        Ok(true)
+    }
+
+    /// Read the block at `offset`, or `None` at the end of the blocks.
+    fn read_block(&self, offset: u64) -> Result<Option<ScannedBlock>, LedgerError> {
+        if offset >= self.ledger_map.storage_size_bytes() {
+            return Ok(None);
+        }
+        match self.ledger_map.read_block_at(offset) {
+            Ok((block_header, block)) => Ok(Some(ScannedBlock {
+                entries: block.entries().to_vec(),
+                len: block_header.jump_bytes_next_block() as u64,
+                parent_hash: block.parent_hash().to_vec(),
+            })),
+            Err(LedgerError::BlockEmpty) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<LedgerEntry>, LedgerError> {
+        loop {
+            match &self.block {
+                None if self.handle.block_offset == 0 => {
+                    if !self.enter_block(self.ledger_map.data_start_offset())? {
+                        return Ok(None);
+                    }
+                }
+                None => {
+                    // Resuming from a saved handle
+                    match self.read_block(self.handle.block_offset)? {
+                        Some(block)
+                            if block.parent_hash == self.handle.parent_hash
+                                && block.entries.len() == self.handle.entries_count as usize =>
+                        {
+                            self.block = Some(block);
+                        }
+                        _ => {
+                            return Err(LedgerError::Other(format!(
+                                "The block at offset {} changed since the scan handle was saved",
+                                self.handle.block_offset
+                            )))
+                        }
+                    }
+                }
+                Some(block) => {
+                    if let Some(entry) = block.entries.get(self.handle.entry_index as usize) {
+                        let entry = entry.clone();
+                        self.handle.entry_index += 1;
+                        return Ok(Some(entry));
+                    }
+                    let offset = self.handle.block_offset + block.len;
+                    if !self.enter_block(offset)? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<LedgerEntry, LedgerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
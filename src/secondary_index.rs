@@ -0,0 +1,71 @@
+/// This module implements user-defined secondary indexes of the values of a label, see
+/// `LedgerMap::register_index`.
+use crate::ledger_entry::EntryKey;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Key of a secondary index, extracted from an entry value.
+pub type IndexKey = Vec<u8>;
+
+/// Function that extracts the secondary index keys from an entry value.
+pub type IndexExtractor = Box<dyn Fn(&[u8]) -> Vec<IndexKey> + Send>;
+
+pub(crate) struct SecondaryIndex {
+    extractor: IndexExtractor,
+    entry_keys: BTreeMap<IndexKey, BTreeSet<EntryKey>>,
+}
+
+impl std::fmt::Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SecondaryIndex {{ index_keys: {} }}",
+            self.entry_keys.len()
+        )
+    }
+}
+
+impl SecondaryIndex {
+    pub(crate) fn new(extractor: IndexExtractor) -> Self {
+        SecondaryIndex {
+            extractor,
+            entry_keys: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entry_keys.clear();
+    }
+
+    /// Update the index for the entry `key`, whose value changes from `old_value` to
+    /// `new_value`. `None` stands for a missing or deleted entry.
+    pub(crate) fn update(
+        &mut self,
+        key: &[u8],
+        old_value: Option<&[u8]>,
+        new_value: Option<&[u8]>,
+    ) {
+        if let Some(old_value) = old_value {
+            for index_key in (self.extractor)(old_value) {
+                if let Some(keys) = self.entry_keys.get_mut(&index_key) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        self.entry_keys.remove(&index_key);
+                    }
+                }
+            }
+        }
+        if let Some(new_value) = new_value {
+            for index_key in (self.extractor)(new_value) {
+                self.entry_keys
+                    .entry(index_key)
+                    .or_default()
+                    .insert(key.to_vec());
+            }
+        }
+    }
+
+    /// Returns the keys of the entries with `index_key`, in ascending order.
+    pub(crate) fn get(&self, index_key: &[u8]) -> impl Iterator<Item = &EntryKey> {
+        self.entry_keys.get(index_key).into_iter().flatten()
+    }
+}
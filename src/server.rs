@@ -0,0 +1,377 @@
+/// This module implements a read-only HTTP server for inspecting a ledger, and for serving its
+/// raw bytes to replicas.
+///
+/// Endpoints:
+/// - `GET /metadata`: block count, tip hash, timestamps and storage offsets.
+/// - `GET /blocks?start=<index>&limit=<count>`: decoded committed blocks.
+/// - `GET /entries/{label}`: current entries of an indexed label, 404 if it has none.
+/// - `GET /raw?offset=<offset>&length=<bytes>`: raw bytes of the persistent storage, at most
+///   `RAW_MAX_BYTES` per request. Raw bytes from the first block start can be applied by a
+///   replica as-is.
+/// - `GET /ws/blocks?start=<index>`: WebSocket pushing committed blocks as they appear, with the
+///   `websocket` feature (see `websocket`).
+///
+/// Keys and values are base64-encoded, hashes are hex-encoded.
+///
+/// The persistent storage is bound to the thread that opens it, so the server opens the ledger
+/// in a dedicated thread and loads the newly appended blocks before answering each request, to
+/// pick up blocks committed by the writer process.
+use crate::info;
+use crate::ledger_thread::LedgerThread;
+use crate::{LedgerBlock, LedgerEntry, LedgerError, LedgerMap, Operation};
+use axum::extract::{FromRef, Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+/// Maximum number of bytes returned by a single `/raw` request.
+pub const RAW_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Maximum number of blocks returned by a single `/blocks` request.
+pub const BLOCKS_MAX_LIMIT: usize = 1000;
+
+#[derive(Clone)]
+struct ServerState {
+    ledger: LedgerThread,
+    #[cfg(feature = "websocket")]
+    blocks: tokio::sync::broadcast::Sender<std::sync::Arc<websocket::BlockMessage>>,
+}
+
+impl FromRef<ServerState> for LedgerThread {
+    fn from_ref(state: &ServerState) -> Self {
+        state.ledger.clone()
+    }
+}
+
+/// Load the blocks committed by the writer since the last request, and run `f` with the ledger.
+async fn run<R: Send + 'static>(
+    ledger: &LedgerThread,
+    f: impl FnOnce(&LedgerMap) -> anyhow::Result<R> + Send + 'static,
+) -> Result<R, ServerError> {
+    ledger
+        .run(move |ledger_map| {
+            ledger_map.refresh_new_blocks()?;
+            f(ledger_map)
+        })
+        .await
+        .map_err(ServerError::from)
+}
+
+struct ServerError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ServerError {
+    fn internal(message: &str) -> Self {
+        ServerError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: message.to_string(),
+        }
+    }
+
+    fn bad_request(message: &str) -> Self {
+        ServerError {
+            status: StatusCode::BAD_REQUEST,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ServerError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<LedgerError>() {
+            Some(LedgerError::EntryNotFound) | Some(LedgerError::BlockPruned { .. }) => {
+                ServerError {
+                    status: StatusCode::NOT_FOUND,
+                    message: err.to_string(),
+                }
+            }
+            _ => ServerError::internal(&err.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        (self.status, self.message).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct EntryJson {
+    label: String,
+    key: String,
+    value: String,
+    operation: Operation,
+}
+
+impl From<&LedgerEntry> for EntryJson {
+    fn from(entry: &LedgerEntry) -> Self {
+        EntryJson {
+            label: entry.label().to_string(),
+            key: BASE64.encode(entry.key()),
+            value: BASE64.encode(entry.value()),
+            operation: entry.operation(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BlockJson {
+    offset: u64,
+    length: u32,
+    version: u32,
+    timestamp: u64,
+    parent_hash: String,
+    entries: Vec<EntryJson>,
+}
+
+impl BlockJson {
+    fn new(block: &LedgerBlock, length: u32) -> Self {
+        BlockJson {
+            offset: block.get_offset(),
+            length,
+            version: block.version(),
+            timestamp: block.timestamp(),
+            parent_hash: hex::encode(block.parent_hash()),
+            entries: block.entries().iter().map(EntryJson::from).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MetadataJson {
+    blocks_count: usize,
+    tip_hash: String,
+    tip_timestamp_ns: u64,
+    data_start: u64,
+    tip_block_start: u64,
+    next_block_start: u64,
+}
+
+#[derive(Deserialize)]
+struct BlocksQuery {
+    #[serde(default)]
+    start: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct RawQuery {
+    offset: u64,
+    length: u64,
+}
+
+async fn metadata(State(ledger): State<LedgerThread>) -> Result<Json<MetadataJson>, ServerError> {
+    run(&ledger, |ledger_map| {
+        Ok(MetadataJson {
+            blocks_count: ledger_map.get_blocks_count(),
+            tip_hash: hex::encode(ledger_map.get_latest_block_hash()),
+            tip_timestamp_ns: ledger_map.get_latest_block_timestamp_ns(),
+            data_start: ledger_map.get_data_start_pos(),
+            tip_block_start: ledger_map.get_latest_block_start_pos(),
+            next_block_start: ledger_map.get_next_block_start_pos(),
+        })
+    })
+    .await
+    .map(Json)
+}
+
+async fn blocks(
+    State(ledger): State<LedgerThread>,
+    Query(query): Query<BlocksQuery>,
+) -> Result<Json<Vec<BlockJson>>, ServerError> {
+    let limit = query
+        .limit
+        .unwrap_or(BLOCKS_MAX_LIMIT)
+        .min(BLOCKS_MAX_LIMIT);
+    run(&ledger, move |ledger_map| {
+        ledger_map
+            .iter_raw()
+            .skip(query.start)
+            .take(limit)
+            .map(|block| {
+                block.map(|(header, block)| BlockJson::new(&block, header.jump_bytes_next_block()))
+            })
+            .collect()
+    })
+    .await
+    .map(Json)
+}
+
+async fn entries(
+    State(ledger): State<LedgerThread>,
+    Path(label): Path<String>,
+) -> Result<Json<Vec<EntryJson>>, ServerError> {
+    run(&ledger, move |ledger_map| {
+        let entries = ledger_map
+            .iter(Some(&label))
+            .map(EntryJson::from)
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return Err(LedgerError::EntryNotFound.into());
+        }
+        Ok(entries)
+    })
+    .await
+    .map(Json)
+}
+
+async fn raw(
+    State(ledger): State<LedgerThread>,
+    Query(query): Query<RawQuery>,
+) -> Result<Response, ServerError> {
+    if query.length > RAW_MAX_BYTES {
+        return Err(ServerError::bad_request(&format!(
+            "At most {} bytes can be requested at once",
+            RAW_MAX_BYTES
+        )));
+    }
+    let end = query
+        .offset
+        .checked_add(query.length)
+        .ok_or_else(|| ServerError::bad_request("Offset and length overflow"))?;
+    let bytes = run(&ledger, move |ledger_map| {
+        // Do not serve the uncommitted storage after the end of the ledger
+        let end = end.min(ledger_map.get_next_block_start_pos());
+        let mut buf = vec![0u8; end.saturating_sub(query.offset) as usize];
+        ledger_map
+            .read_storage(query.offset, &mut buf)
+            .map_err(anyhow::Error::msg)?;
+        Ok(buf)
+    })
+    .await?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], bytes).into_response())
+}
+
+fn router(state: ServerState) -> Router {
+    let router = Router::new()
+        .route("/metadata", get(metadata))
+        .route("/blocks", get(blocks))
+        .route("/entries/{label}", get(entries))
+        .route("/raw", get(raw));
+    #[cfg(feature = "websocket")]
+    let router = router.route("/ws/blocks", get(websocket::blocks_ws));
+    router.with_state(state)
+}
+
+/// Serve the ledger stored at `path` (or in the default file, if `None`) read-only over HTTP
+/// on `addr`, until the returned future is dropped.
+pub async fn serve(addr: impl ToSocketAddrs, path: Option<PathBuf>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    serve_listener(listener, path).await
+}
+
+/// Like `serve`, but on an already bound listener.
+pub async fn serve_listener(listener: TcpListener, path: Option<PathBuf>) -> anyhow::Result<()> {
+    let ledger = LedgerThread::spawn(path)?;
+    let state = ServerState {
+        #[cfg(feature = "websocket")]
+        blocks: websocket::spawn_poller(ledger.clone()),
+        ledger,
+    };
+    info!("Serving ledger on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn http_get(addr: std::net::SocketAddr, path: &str) -> (String, Vec<u8>) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let path = file_path.clone();
+        let (next_block_start, tip_hash) = std::thread::spawn(move || {
+            let mut ledger_map = LedgerMap::new_with_path(None, Some(path)).unwrap();
+            ledger_map.upsert("label", b"key", b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+            (
+                ledger_map.get_next_block_start_pos(),
+                ledger_map.get_latest_block_hash(),
+            )
+        })
+        .join()
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, Some(file_path.clone())));
+
+        let (head, body) = http_get(addr, "/metadata").await;
+        assert!(head.contains(" 200 "), "{}", head);
+        let metadata: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(metadata["blocks_count"], 1);
+        assert_eq!(metadata["tip_hash"], hex::encode(&tip_hash));
+        assert_eq!(metadata["next_block_start"], next_block_start);
+
+        let (_, body) = http_get(addr, "/blocks?start=0").await;
+        let blocks: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(blocks.as_array().unwrap().len(), 1);
+        assert_eq!(blocks[0]["entries"][0]["key"], BASE64.encode(b"key"));
+
+        let (_, body) = http_get(addr, "/entries/label").await;
+        let entries: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entries[0]["value"], BASE64.encode(b"value"));
+
+        let data_start = metadata["data_start"].as_u64().unwrap();
+        let (_, body) = http_get(
+            addr,
+            &format!("/raw?offset={}&length={}", data_start, RAW_MAX_BYTES),
+        )
+        .await;
+        assert_eq!(body.len() as u64, next_block_start - data_start);
+
+        let (head, _) =
+            http_get(addr, &format!("/raw?offset=0&length={}", RAW_MAX_BYTES + 1)).await;
+        assert!(head.contains(" 400 "), "{}", head);
+        let (head, _) = http_get(addr, &format!("/raw?offset={}&length=1", u64::MAX)).await;
+        assert!(head.contains(" 400 "), "{}", head);
+        let (head, _) = http_get(addr, "/entries/missing").await;
+        assert!(head.contains(" 404 "), "{}", head);
+
+        // Blocks committed after the server started are served
+        let path = file_path.clone();
+        std::thread::spawn(move || {
+            let mut ledger_map = LedgerMap::new_with_path(None, Some(path)).unwrap();
+            ledger_map.upsert("label2", b"key", b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+        })
+        .join()
+        .unwrap();
+        let (_, body) = http_get(addr, "/metadata").await;
+        let metadata: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(metadata["blocks_count"], 2);
+        let (head, _) = http_get(addr, "/entries/label2").await;
+        assert!(head.contains(" 200 "), "{}", head);
+    }
+}
@@ -0,0 +1,240 @@
+/// This module implements the `GET /ws/blocks?start=<index>` WebSocket endpoint of the server,
+/// which pushes committed blocks to subscribers as they appear in the ledger.
+///
+/// Each block is sent as a JSON text message with the block index, offset, length, version,
+/// timestamp, the hex-encoded chain hash and the base64-encoded raw block bytes (header and
+/// body), which replicas can apply as-is at `offset`. With `start`, the blocks from that index
+/// on are sent first; otherwise only blocks committed after subscribing are sent.
+///
+/// A single task polls the ledger for new blocks while there are subscribers, and broadcasts
+/// them to all of them.
+use super::{run, ServerState};
+use crate::ledger_thread::LedgerThread;
+use crate::{warn, LedgerMap};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::Response;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How often the ledger is checked for new blocks.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of blocks buffered per subscriber. Subscribers that fall further behind are
+/// resynchronized from the ledger.
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Serialize)]
+pub(super) struct BlockMessage {
+    index: usize,
+    offset: u64,
+    length: u32,
+    version: u32,
+    timestamp: u64,
+    hash: String,
+    bytes: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct SubscribeQuery {
+    start: Option<usize>,
+}
+
+/// Read the committed blocks from index `start` on, with their raw bytes and chain hashes.
+/// The blocks are read from the storage offset of block `start`, without reading the blocks
+/// before it.
+fn read_blocks(ledger_map: &LedgerMap, start: usize) -> anyhow::Result<Vec<BlockMessage>> {
+    let Some(start_pos) = ledger_map.get_block_start_pos(start) else {
+        return Ok(Vec::new());
+    };
+    let bytes = ledger_map.read_raw_blocks(start_pos, u64::MAX)?;
+    ledger_map
+        .iter_raw_from_slice(&bytes)
+        .enumerate()
+        .map(|(i, block)| {
+            let (header, block, hash) = block?;
+            let block_start = block.get_offset() as usize;
+            let length = header.jump_bytes_next_block();
+            Ok(BlockMessage {
+                index: start + i,
+                offset: start_pos + block.get_offset(),
+                length,
+                version: block.version(),
+                timestamp: block.timestamp(),
+                hash: hex::encode(hash),
+                bytes: BASE64.encode(&bytes[block_start..block_start + length as usize]),
+            })
+        })
+        .collect()
+}
+
+/// Spawn the task that polls the ledger for new blocks, and returns the channel it sends them to.
+/// The ledger is only polled while there are subscribers, and only the blocks appended since the
+/// last poll are loaded, see `LedgerMap::refresh_new_blocks`.
+pub(super) fn spawn_poller(ledger: LedgerThread) -> broadcast::Sender<Arc<BlockMessage>> {
+    let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+    let blocks = sender.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut next_index = None;
+        loop {
+            interval.tick().await;
+            if blocks.receiver_count() == 0 {
+                // Subscribers catch up on the blocks committed in between, see `subscribe`
+                next_index = None;
+                continue;
+            }
+            let start = next_index;
+            let polled = ledger
+                .run(move |ledger_map| {
+                    ledger_map.refresh_new_blocks()?;
+                    let new_blocks = match start {
+                        Some(start) => read_blocks(ledger_map, start)?,
+                        // Blocks committed before the first poll are not new
+                        None => Vec::new(),
+                    };
+                    Ok((ledger_map.get_blocks_count(), new_blocks))
+                })
+                .await;
+            match polled {
+                Ok((blocks_count, new_blocks)) => {
+                    next_index = Some(blocks_count);
+                    for block in new_blocks {
+                        // Fails only if there are no subscribers
+                        let _ = blocks.send(Arc::new(block));
+                    }
+                }
+                Err(err) => warn!("Failed to poll the ledger for new blocks: {}", err),
+            }
+        }
+    });
+    sender
+}
+
+pub(super) async fn blocks_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<ServerState>,
+    Query(query): Query<SubscribeQuery>,
+) -> Response {
+    ws.on_upgrade(move |socket| subscribe(socket, state, query.start))
+}
+
+async fn send_block(socket: &mut WebSocket, block: &BlockMessage) -> bool {
+    match serde_json::to_string(block) {
+        Ok(text) => socket.send(Message::Text(text.into())).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Send the blocks from index `start` on from the ledger, and returns the index of the next block.
+async fn send_backlog(
+    socket: &mut WebSocket,
+    ledger: &LedgerThread,
+    start: usize,
+) -> Option<usize> {
+    let blocks = match run(ledger, move |ledger_map| read_blocks(ledger_map, start)).await {
+        Ok(blocks) => blocks,
+        Err(err) => {
+            warn!("Failed to read the ledger blocks: {}", err.message);
+            return None;
+        }
+    };
+    let mut next_index = start;
+    for block in blocks {
+        if !send_block(socket, &block).await {
+            return None;
+        }
+        next_index = block.index + 1;
+    }
+    Some(next_index)
+}
+
+async fn subscribe(mut socket: WebSocket, state: ServerState, start: Option<usize>) {
+    // Subscribe before reading the backlog, so that no block is missed in between
+    let mut receiver = state.blocks.subscribe();
+    let start = match start {
+        Some(start) => start,
+        None => match run(
+            &state.ledger,
+            |ledger_map| Ok(ledger_map.get_blocks_count()),
+        )
+        .await
+        {
+            Ok(blocks_count) => blocks_count,
+            Err(err) => {
+                warn!("Failed to read the ledger blocks: {}", err.message);
+                return;
+            }
+        },
+    };
+    let mut next_index = match send_backlog(&mut socket, &state.ledger, start).await {
+        Some(index) => index,
+        None => return,
+    };
+    loop {
+        match receiver.recv().await {
+            Ok(block) => {
+                // Skip blocks already sent with the backlog
+                if block.index < next_index {
+                    continue;
+                }
+                // The poller does not broadcast the blocks committed before its first poll
+                if block.index > next_index {
+                    match send_backlog(&mut socket, &state.ledger, next_index).await {
+                        Some(index) => next_index = index,
+                        None => return,
+                    }
+                    continue;
+                }
+                if !send_block(&mut socket, &block).await {
+                    return;
+                }
+                next_index = block.index + 1;
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                match send_backlog(&mut socket, &state.ledger, next_index).await {
+                    Some(index) => next_index = index,
+                    None => return,
+                }
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_blocks() {
+        let file_path = tempfile::tempdir()
+            .unwrap()
+            .keep()
+            .join("test_ledger_store.bin");
+        let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+        for key in [b"key1", b"key2"] {
+            ledger_map.upsert("label", key, b"value").unwrap();
+            ledger_map.commit_block().unwrap();
+        }
+
+        let blocks = read_blocks(&ledger_map, 1).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].index, 1);
+        assert_eq!(blocks[0].offset, ledger_map.get_latest_block_start_pos());
+        assert_eq!(
+            blocks[0].hash,
+            hex::encode(ledger_map.get_latest_block_hash())
+        );
+        let bytes = BASE64.decode(&blocks[0].bytes).unwrap();
+        assert_eq!(bytes.len(), blocks[0].length as usize);
+        let blocks = read_blocks(&ledger_map, 0).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].offset, ledger_map.get_data_start_pos());
+        assert!(read_blocks(&ledger_map, 2).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+/// This module implements portable snapshot archives (`.lmsnap`) of a ledger, for backups and
+/// for moving a ledger between platforms, e.g. from a file to the stable memory of a canister or
+/// to a browser. A snapshot is self-describing: besides the raw blocks, it records the format
+/// version, the partition layout and the metadata of the exported ledger, and optionally a
+/// checkpoint of the index, so that the state can be read without replaying the blocks.
+///
+/// Blocks do not depend on their position in the persistent storage, so a snapshot can be
+/// imported into a ledger with a different partition layout.
+///
+/// Example usage:
+///
+/// ```rust,no_run
+/// use ledger_map::LedgerMap;
+///
+/// let ledger_map = LedgerMap::new_with_path(None, None).unwrap();
+/// ledger_map.export_snapshot("backup.lmsnap".as_ref(), true).unwrap();
+///
+/// let mut restored = LedgerMap::new_with_path(None, Some("restored.bin".into())).unwrap();
+/// restored
+///     .restore_snapshot(std::path::Path::new("backup.lmsnap"))
+///     .unwrap();
+/// ```
+use crate::ledger_entry::LedgerEntry;
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Magic bytes at the start of a snapshot archive.
+pub const SNAPSHOT_MAGIC: &[u8; 6] = b"LMSNAP";
+
+/// Format version of the snapshot archives written by this version of the library.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Partition of the exported ledger, as in its partition table.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotPartition {
+    pub name: Vec<u8>,
+    pub start_lba: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub format_version: u32,
+    pub partitions: Vec<SnapshotPartition>,
+    /// Index of the data partition of the exported ledger in `partitions`.
+    pub data_partition: u32,
+    /// Block version the exported ledger was writing.
+    pub block_version: u32,
+    pub blocks_count: u64,
+    /// Chain hash of the last block, empty for an empty ledger.
+    pub tip_chain_hash: Vec<u8>,
+    pub tip_timestamp_ns: u64,
+    /// Raw blocks (headers and bodies), as returned by `LedgerMap::read_raw_blocks`.
+    pub blocks: Vec<u8>,
+    /// Committed entries of the indexed labels at the time of the export, if requested.
+    pub index_checkpoint: Option<Vec<LedgerEntry>>,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        self.serialize(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let body = bytes
+            .strip_prefix(SNAPSHOT_MAGIC.as_slice())
+            .ok_or_else(|| anyhow::format_err!("Not a ledger snapshot: invalid magic bytes"))?;
+        // The format version comes first, so that it can be checked before the rest is decoded
+        let format_version = u32::deserialize(&mut &body[..])?;
+        if format_version > SNAPSHOT_FORMAT_VERSION {
+            return Err(anyhow::format_err!(
+                "Unsupported snapshot format version {}, the latest supported is {}",
+                format_version,
+                SNAPSHOT_FORMAT_VERSION
+            ));
+        }
+        Ok(Self::try_from_slice(body)?)
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
+    pub fn read_from_file(path: &std::path::Path) -> anyhow::Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
+    pub fn write_to_file(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
+}
+
+/// Source of a snapshot archive for `LedgerMap::restore_snapshot`: a file, or the archive bytes,
+/// e.g. uploaded to a canister or fetched in a browser.
+#[derive(Clone, Copy, Debug)]
+pub enum SnapshotSource<'a> {
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "wasm32", target_os = "wasi")
+    ))]
+    Path(&'a std::path::Path),
+    Bytes(&'a [u8]),
+}
+
+impl SnapshotSource<'_> {
+    pub fn read(&self) -> anyhow::Result<Snapshot> {
+        match self {
+            #[cfg(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                all(target_arch = "wasm32", target_os = "wasi")
+            ))]
+            SnapshotSource::Path(path) => Snapshot::read_from_file(path),
+            SnapshotSource::Bytes(bytes) => Snapshot::from_bytes(bytes),
+        }
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "wasm32", target_os = "wasi")
+))]
+impl<'a> From<&'a std::path::Path> for SnapshotSource<'a> {
+    fn from(path: &'a std::path::Path) -> Self {
+        SnapshotSource::Path(path)
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "wasm32", target_os = "wasi")
+))]
+impl<'a> From<&'a std::path::PathBuf> for SnapshotSource<'a> {
+    fn from(path: &'a std::path::PathBuf) -> Self {
+        SnapshotSource::Path(path)
+    }
+}
+
+impl<'a> From<&'a [u8]> for SnapshotSource<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        SnapshotSource::Bytes(bytes)
+    }
+}
+
+impl<'a> From<&'a Vec<u8>> for SnapshotSource<'a> {
+    fn from(bytes: &'a Vec<u8>) -> Self {
+        SnapshotSource::Bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger_entry::Operation;
+
+    #[test]
+    fn test_snapshot_bytes_roundtrip() {
+        let snapshot = Snapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            partitions: vec![SnapshotPartition {
+                name: b"DATA".to_vec(),
+                start_lba: 4096,
+            }],
+            data_partition: 0,
+            block_version: 1,
+            blocks_count: 0,
+            tip_chain_hash: vec![],
+            tip_timestamp_ns: 0,
+            blocks: vec![],
+            index_checkpoint: Some(vec![LedgerEntry::new(
+                "Label1",
+                b"key",
+                b"value",
+                Operation::Upsert,
+            )]),
+        };
+        let bytes = snapshot.to_bytes().unwrap();
+        assert!(bytes.starts_with(SNAPSHOT_MAGIC));
+        assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+        assert!(Snapshot::from_bytes(&bytes[1..]).is_err());
+
+        let mut future = snapshot.clone();
+        future.format_version = SNAPSHOT_FORMAT_VERSION + 1;
+        assert!(Snapshot::from_bytes(&future.to_bytes().unwrap()).is_err());
+    }
+}
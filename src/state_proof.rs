@@ -0,0 +1,411 @@
+/// This module implements a commitment to the live key-value state of the ledger (the state
+/// root), and proofs that a key does not exist in the state at a block height.
+///
+/// Unlike the chain hash, which covers the history, the state root only depends on the live
+/// entries, so replicas that converged to identical state have identical state roots.
+/// It is the root of a binary Merkle tree over the live entries of all non-reserved labels,
+/// indexed or not, sorted by label and key:
+///
+/// ```text
+/// leaf = sha256(0x00 || borsh(label, key, sha256(value)))
+/// node = sha256(0x01 || left || right)
+/// ```
+///
+/// A node without a sibling, at the end of an odd-sized level, is promoted to the next level
+/// unchanged. The state root also commits to the number of leaves, so that the position of the
+/// last leaf can be verified:
+///
+/// ```text
+/// state_root = sha256(0x02 || leaf_count as u64 LE || tree_root)
+/// ```
+///
+/// where the tree root of an empty state is 32 zero bytes.
+///
+/// A non-inclusion proof consists of the two adjacent leaves around the key in the sorted order,
+/// with their Merkle paths; at the ends of the order, one of them is missing.
+///
+/// For anti-entropy sync of replicas, `StateDigests` also hold a state root per label, over the
+/// live entries of the label only, so that replicas with forked chains can find out which labels
+/// they need to exchange, see `LedgerMap::sync_plan`.
+use crate::ledger_entry::{EntryKey, LedgerEntry, Operation};
+use crate::ledger_map::{RENAME_LABEL, RESERVED_LABEL_PREFIX};
+use borsh::{BorshDeserialize, BorshSerialize};
+use sha2::Digest;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+/// Format version of the non-inclusion proofs written by this version of the library.
+pub const NON_INCLUSION_PROOF_VERSION: u32 = 1;
+
+/// Live entry of the state, a leaf of the state tree. Only the hash of the value is needed to
+/// verify a proof, so proofs do not reveal the values of the neighboring entries.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StateLeaf {
+    pub label: String,
+    pub key: Vec<u8>,
+    pub value_hash: [u8; 32],
+}
+
+impl StateLeaf {
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update([0u8]);
+        hasher.update(borsh::to_vec(self).expect("Failed to serialize a state leaf"));
+        hasher.finalize().into()
+    }
+
+    fn sort_key(&self) -> (&[u8], &[u8]) {
+        (self.label.as_bytes(), &self.key)
+    }
+}
+
+fn state_root(leaf_count: u64, tree_root: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update([2u8]);
+    hasher.update(leaf_count.to_le_bytes());
+    hasher.update(tree_root);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Live entries of the state, by label and key, with the hashes of their values. The state root
+/// is computed when it is first requested after a change, and then cached.
+#[derive(Debug, Default)]
+pub(crate) struct LiveState {
+    value_hashes: BTreeMap<(String, EntryKey), [u8; 32]>,
+    state_root: Cell<Option<[u8; 32]>>,
+}
+
+impl LiveState {
+    pub(crate) fn clear(&mut self) {
+        self.value_hashes.clear();
+        self.state_root.set(None);
+    }
+
+    /// Apply a committed entry: upserts and deletes of non-reserved labels, and label renames.
+    pub(crate) fn apply(&mut self, entry: &LedgerEntry) {
+        if entry.label() == RENAME_LABEL && entry.operation() == Operation::Upsert {
+            let old_label = String::from_utf8_lossy(entry.key()).to_string();
+            let new_label = String::from_utf8_lossy(entry.value()).to_string();
+            let renamed = self
+                .value_hashes
+                .keys()
+                .filter(|(label, _)| *label == old_label)
+                .cloned()
+                .collect::<Vec<_>>();
+            for state_key in renamed {
+                if let Some(value_hash) = self.value_hashes.remove(&state_key) {
+                    self.value_hashes
+                        .insert((new_label.clone(), state_key.1), value_hash);
+                }
+            }
+        } else if !entry.label().starts_with(RESERVED_LABEL_PREFIX) {
+            let state_key = (entry.label().to_string(), entry.key().to_vec());
+            match entry.operation() {
+                Operation::Upsert => {
+                    self.value_hashes
+                        .insert(state_key, sha2::Sha256::digest(entry.value()).into());
+                }
+                Operation::Delete => {
+                    self.value_hashes.remove(&state_key);
+                }
+            }
+        } else {
+            return;
+        }
+        self.state_root.set(None);
+    }
+
+    /// Leaves of the state tree, in the sorted order.
+    pub(crate) fn leaves(&self) -> Vec<StateLeaf> {
+        self.value_hashes
+            .iter()
+            .map(|((label, key), value_hash)| StateLeaf {
+                label: label.clone(),
+                key: key.clone(),
+                value_hash: *value_hash,
+            })
+            .collect()
+    }
+
+    /// State roots of the live entries of each label on their own.
+    pub(crate) fn label_state_roots(&self) -> BTreeMap<String, [u8; 32]> {
+        self.leaves()
+            .chunk_by(|a, b| a.label == b.label)
+            .map(|leaves| {
+                let tree = StateTree::new(leaves.iter().map(StateLeaf::hash).collect());
+                (leaves[0].label.clone(), tree.state_root())
+            })
+            .collect()
+    }
+
+    pub(crate) fn state_root(&self) -> [u8; 32] {
+        if let Some(state_root) = self.state_root.get() {
+            return state_root;
+        }
+        let state_root =
+            StateTree::new(self.leaves().iter().map(StateLeaf::hash).collect()).state_root();
+        self.state_root.set(Some(state_root));
+        state_root
+    }
+}
+
+/// Merkle tree over the hashes of sorted leaves, with all levels kept for proofs.
+pub(crate) struct StateTree {
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl StateTree {
+    pub(crate) fn new(leaf_hashes: Vec<[u8; 32]>) -> Self {
+        let mut levels = vec![leaf_hashes];
+        while levels.last().is_some_and(|level| level.len() > 1) {
+            let level = levels.last().expect("Levels are not empty");
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        StateTree { levels }
+    }
+
+    /// State root of the tree, see the module documentation.
+    pub(crate) fn state_root(&self) -> [u8; 32] {
+        let tree_root = match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => *root,
+            None => [0u8; 32],
+        };
+        state_root(self.levels[0].len() as u64, &tree_root)
+    }
+
+    /// Hashes of the siblings on the path from the leaf at `index` to the root.
+    pub(crate) fn path(&self, index: usize) -> Vec<Vec<u8>> {
+        let mut path = Vec::new();
+        let mut index = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                path.push(level[sibling].to_vec());
+            }
+            index /= 2;
+        }
+        path
+    }
+}
+
+/// Compute the state root from the leaf at `index` of `leaf_count` leaves and its Merkle `path`.
+fn root_from_path(
+    leaf_hash: [u8; 32],
+    index: u64,
+    leaf_count: u64,
+    path: &[Vec<u8>],
+) -> anyhow::Result<[u8; 32]> {
+    if index >= leaf_count {
+        return Err(anyhow::format_err!(
+            "Leaf index {} out of {} leaves",
+            index,
+            leaf_count
+        ));
+    }
+    let mut siblings = path.iter();
+    let mut next_sibling = || -> anyhow::Result<[u8; 32]> {
+        siblings
+            .next()
+            .and_then(|sibling| sibling.as_slice().try_into().ok())
+            .ok_or_else(|| anyhow::format_err!("Invalid Merkle path"))
+    };
+    let (mut hash, mut index, mut count) = (leaf_hash, index, leaf_count);
+    while count > 1 {
+        if index % 2 == 1 {
+            hash = node_hash(&next_sibling()?, &hash);
+        } else if index + 1 < count {
+            hash = node_hash(&hash, &next_sibling()?);
+        }
+        index /= 2;
+        count = count.div_ceil(2);
+    }
+    if siblings.next().is_some() {
+        return Err(anyhow::format_err!("Merkle path is too long"));
+    }
+    Ok(state_root(leaf_count, &hash))
+}
+
+/// Leaf of the state tree with its position and Merkle path.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StateLeafProof {
+    pub index: u64,
+    pub leaf: StateLeaf,
+    pub path: Vec<Vec<u8>>,
+}
+
+impl StateLeafProof {
+    fn verify(&self, leaf_count: u64, state_root: &[u8]) -> anyhow::Result<()> {
+        let root = root_from_path(self.leaf.hash(), self.index, leaf_count, &self.path)?;
+        if root != state_root {
+            return Err(anyhow::format_err!(
+                "State leaf {} does not match the state root",
+                self.index
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Proof that `key` of `label` does not exist at `block_height`, see `LedgerMap::prove_non_inclusion`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NonInclusionProof {
+    pub version: u32,
+    pub label: String,
+    pub key: Vec<u8>,
+    pub block_height: u64,
+    pub leaf_count: u64,
+    /// Last leaf before the key in the sorted order, if any.
+    pub lower: Option<StateLeafProof>,
+    /// First leaf after the key in the sorted order, if any.
+    pub upper: Option<StateLeafProof>,
+}
+
+impl NonInclusionProof {
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(borsh::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let proof = Self::try_from_slice(bytes)?;
+        if proof.version > NON_INCLUSION_PROOF_VERSION {
+            return Err(anyhow::format_err!(
+                "Unsupported non-inclusion proof version {}, the latest supported is {}",
+                proof.version,
+                NON_INCLUSION_PROOF_VERSION
+            ));
+        }
+        Ok(proof)
+    }
+}
+
+/// Verify `proof` against `trusted_state_root`, the state root of the ledger at the block height
+/// of the proof, obtained from a trusted source (see `LedgerMap::state_root_at`).
+pub fn verify_non_inclusion_proof(
+    proof: &NonInclusionProof,
+    trusted_state_root: &[u8],
+) -> anyhow::Result<()> {
+    let key = (proof.label.as_bytes(), proof.key.as_slice());
+    if let Some(lower) = &proof.lower {
+        lower.verify(proof.leaf_count, trusted_state_root)?;
+        if lower.leaf.sort_key() >= key {
+            return Err(anyhow::format_err!("Lower leaf is not before the key"));
+        }
+    }
+    if let Some(upper) = &proof.upper {
+        upper.verify(proof.leaf_count, trusted_state_root)?;
+        if upper.leaf.sort_key() <= key {
+            return Err(anyhow::format_err!("Upper leaf is not after the key"));
+        }
+    }
+    // The leaves must be adjacent, so that there is no room for the key between them
+    let adjacent = match (&proof.lower, &proof.upper) {
+        (Some(lower), Some(upper)) => upper.index == lower.index + 1,
+        (Some(lower), None) => lower.index + 1 == proof.leaf_count,
+        (None, Some(upper)) => upper.index == 0,
+        (None, None) => proof.leaf_count == 0 && trusted_state_root == state_root(0, &[0u8; 32]),
+    };
+    if !adjacent {
+        return Err(anyhow::format_err!(
+            "Leaves of the non-inclusion proof are not adjacent"
+        ));
+    }
+    Ok(())
+}
+
+/// Digests of the state of a replica, which it sends to a peer for `LedgerMap::sync_plan`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StateDigests {
+    pub blocks_count: u64,
+    /// Chain hash of the last block, empty for an empty ledger.
+    pub tip_chain_hash: Vec<u8>,
+    pub state_root: Vec<u8>,
+    /// State roots of the live entries of each label, see `crate::state_proof`.
+    pub label_state_roots: BTreeMap<String, Vec<u8>>,
+}
+
+/// What a peer is missing compared to the local ledger, as returned by `LedgerMap::sync_plan`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncPlan {
+    /// The peer has the same blocks, or the same live entries after forked chains.
+    InSync,
+    /// The chain of the peer is a prefix of the local chain, so it is only missing the `blocks`
+    /// raw blocks from the storage offset `start` to `end`, see `LedgerMap::read_raw_blocks`.
+    SendBlocks { start: u64, end: u64, blocks: u64 },
+    /// The chain of the peer is longer. The digests do not tell whether it extends the local
+    /// chain, so nothing is sent; the peer plans instead with the local digests, which it can
+    /// verify against its own chain hash at the local height, and gets `SendBlocks` if its chain
+    /// extends the local chain, or `SendLabels` if they forked.
+    PeerAhead,
+    /// The chains forked, and the live entries of these labels differ, including the labels that
+    /// only one of the replicas has. Only these labels need to be exchanged and reconciled.
+    SendLabels(Vec<String>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(key: u8) -> StateLeaf {
+        StateLeaf {
+            label: "Label1".to_string(),
+            key: vec![key],
+            value_hash: [key; 32],
+        }
+    }
+
+    #[test]
+    fn test_label_state_roots() {
+        let mut state = LiveState::default();
+        state.apply(&LedgerEntry::new("Label1", b"a", b"1", Operation::Upsert));
+        state.apply(&LedgerEntry::new("Label2", b"a", b"1", Operation::Upsert));
+        let roots = state.label_state_roots();
+        assert_eq!(roots.keys().collect::<Vec<_>>(), vec!["Label1", "Label2"]);
+        assert_eq!(
+            roots["Label1"],
+            StateTree::new(vec![state.leaves()[0].hash()]).state_root()
+        );
+
+        state.apply(&LedgerEntry::new("Label2", b"b", b"2", Operation::Upsert));
+        let updated = state.label_state_roots();
+        assert_eq!(updated["Label1"], roots["Label1"]);
+        assert_ne!(updated["Label2"], roots["Label2"]);
+    }
+
+    #[test]
+    fn test_state_tree_paths() {
+        for leaf_count in 1..=9u8 {
+            let leaves = (0..leaf_count).map(leaf).collect::<Vec<_>>();
+            let tree = StateTree::new(leaves.iter().map(StateLeaf::hash).collect());
+            for (index, leaf) in leaves.iter().enumerate() {
+                let root = root_from_path(
+                    leaf.hash(),
+                    index as u64,
+                    leaf_count as u64,
+                    &tree.path(index),
+                )
+                .unwrap();
+                assert_eq!(root, tree.state_root());
+            }
+        }
+        assert_ne!(
+            StateTree::new(Vec::new()).state_root(),
+            StateTree::new(vec![leaf(0).hash()]).state_root()
+        );
+    }
+}
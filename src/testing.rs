@@ -0,0 +1,260 @@
+//! This module implements support for property-based and model-based testing of applications
+//! that use the ledger, with `proptest`: `Arbitrary` implementations of the entries, blocks and
+//! sequences of ledger operations, and `ReferenceModel`, a plain `HashMap` with the semantics of
+//! the ledger, to check the ledger (and the application logic on top of it) against.
+//!
+//! Labels and keys are drawn from small sets, so that generated operations often hit the same
+//! entries. Proptest shrinks failing operation sequences to minimal ones.
+//!
+//! Example usage:
+//!
+//! ```rust,no_run
+//! use ledger_map::testing::{LedgerOp, ReferenceModel};
+//! use ledger_map::LedgerMap;
+//! use proptest::prelude::*;
+//!
+//! proptest! {
+//!     #[test]
+//!     fn ledger_matches_model(ops in proptest::collection::vec(any::<LedgerOp>(), 0..64)) {
+//!         let mut ledger_map = LedgerMap::new_with_path(None, None).unwrap();
+//!         let mut model = ReferenceModel::default();
+//!         for op in &ops {
+//!             op.apply_to(&mut ledger_map).unwrap();
+//!             model.apply(op);
+//!         }
+//!         model.check(&ledger_map).map_err(TestCaseError::fail)?;
+//!     }
+//! }
+//! ```
+use crate::ledger_entry::{LedgerBlock, LedgerEntry, Operation, LATEST_BLOCK_VERSION};
+use crate::{LedgerError, LedgerMap};
+use proptest::prelude::*;
+use std::collections::HashMap;
+
+/// Labels of the generated entries and operations.
+pub fn label_strategy() -> impl Strategy<Value = String> {
+    prop::sample::select(vec!["Label1", "Label2", "accounts", "accounts/eu"])
+        .prop_map(str::to_string)
+}
+
+/// Keys of the generated entries and operations, up to 2 bytes from a small alphabet.
+pub fn key_strategy() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(b'a'..=b'd', 0..=2)
+}
+
+pub fn value_strategy() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 0..32)
+}
+
+impl Arbitrary for Operation {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![Just(Operation::Upsert), Just(Operation::Delete)].boxed()
+    }
+}
+
+impl Arbitrary for LedgerEntry {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (
+            label_strategy(),
+            key_strategy(),
+            value_strategy(),
+            any::<Operation>(),
+        )
+            .prop_map(|(label, key, value, operation)| match operation {
+                Operation::Upsert => LedgerEntry::new(label, key, value, operation),
+                Operation::Delete => LedgerEntry::new(label, key, Vec::new(), operation),
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for LedgerBlock {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (
+            1u32..=LATEST_BLOCK_VERSION,
+            prop::collection::vec(any::<LedgerEntry>(), 0..8),
+            any::<u64>(),
+            prop::collection::vec(any::<u8>(), 32),
+        )
+            .prop_map(|(version, entries, timestamp, parent_hash)| {
+                LedgerBlock::new_with_version(version, entries, timestamp, parent_hash)
+                    .expect("Block version is supported")
+            })
+            .boxed()
+    }
+}
+
+/// Operation on a ledger, for model-based testing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LedgerOp {
+    Upsert {
+        label: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        label: String,
+        key: Vec<u8>,
+    },
+    CommitBlock,
+}
+
+impl LedgerOp {
+    pub fn apply_to(&self, ledger_map: &mut LedgerMap) -> anyhow::Result<()> {
+        match self {
+            LedgerOp::Upsert { label, key, value } => Ok(ledger_map.upsert(label, key, value)?),
+            LedgerOp::Delete { label, key } => Ok(ledger_map.delete(label, key)?),
+            LedgerOp::CommitBlock => ledger_map.commit_block(),
+        }
+    }
+}
+
+impl Arbitrary for LedgerOp {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            4 => (label_strategy(), key_strategy(), value_strategy())
+                .prop_map(|(label, key, value)| LedgerOp::Upsert { label, key, value }),
+            2 => (label_strategy(), key_strategy())
+                .prop_map(|(label, key)| LedgerOp::Delete { label, key }),
+            1 => Just(LedgerOp::CommitBlock),
+        ]
+        .boxed()
+    }
+}
+
+/// Reference model of a ledger with all labels indexed: the committed entries, and the
+/// uncommitted writes of the next block, which `LedgerMap::get` also returns.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReferenceModel {
+    committed: HashMap<(String, Vec<u8>), Vec<u8>>,
+    /// Uncommitted writes: the new value, or `None` for a delete.
+    pending: HashMap<(String, Vec<u8>), Option<Vec<u8>>>,
+}
+
+impl ReferenceModel {
+    pub fn apply(&mut self, op: &LedgerOp) {
+        match op {
+            LedgerOp::Upsert { label, key, value } => {
+                self.pending
+                    .insert((label.clone(), key.clone()), Some(value.clone()));
+            }
+            LedgerOp::Delete { label, key } => {
+                self.pending.insert((label.clone(), key.clone()), None);
+            }
+            LedgerOp::CommitBlock => {
+                for (entry_key, value) in self.pending.drain() {
+                    match value {
+                        Some(value) => self.committed.insert(entry_key, value),
+                        None => self.committed.remove(&entry_key),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Value of `key` of `label`, as returned by `LedgerMap::get`.
+    pub fn get(&self, label: &str, key: &[u8]) -> Option<&Vec<u8>> {
+        let entry_key = (label.to_string(), key.to_vec());
+        match self.pending.get(&entry_key) {
+            Some(value) => value.as_ref(),
+            None => self.committed.get(&entry_key),
+        }
+    }
+
+    /// Committed entries, by label and key.
+    pub fn committed(&self) -> &HashMap<(String, Vec<u8>), Vec<u8>> {
+        &self.committed
+    }
+
+    /// Check that `ledger_map` has the same committed entries as the model, and returns the
+    /// same values for the uncommitted writes.
+    pub fn check(&self, ledger_map: &LedgerMap) -> Result<(), String> {
+        let mut ledger_entries = HashMap::new();
+        for entry in ledger_map.iter(None) {
+            ledger_entries.insert(
+                (entry.label().to_string(), entry.key().to_vec()),
+                entry.value().to_vec(),
+            );
+        }
+        if ledger_entries != self.committed {
+            return Err(format!(
+                "Committed entries differ: ledger {:?}, model {:?}",
+                ledger_entries, self.committed
+            ));
+        }
+        for (label, key) in self.pending.keys() {
+            let expected = self.get(label, key).cloned();
+            let actual = match ledger_map.get(label, key) {
+                Ok(value) => Some(value),
+                Err(LedgerError::EntryNotFound) => None,
+                Err(err) => return Err(err.to_string()),
+            };
+            if actual != expected {
+                return Err(format!(
+                    "Entry {:?} of label {:?}: ledger {:?}, model {:?}",
+                    key, label, actual, expected
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::test_runner::TestRunner;
+
+    #[test]
+    fn test_generated_blocks_roundtrip() {
+        let mut runner = TestRunner::default();
+        runner
+            .run(&any::<LedgerBlock>(), |block| {
+                let bytes = block.serialize().unwrap();
+                let version = match &block {
+                    LedgerBlock::V1(_) => 1,
+                    LedgerBlock::V2(_) => 2,
+                    LedgerBlock::V3(_) => 3,
+                };
+                prop_assert_eq!(LedgerBlock::deserialize(&bytes, version).unwrap(), block);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn test_ledger_matches_reference_model(
+            ops in prop::collection::vec(any::<LedgerOp>(), 0..48)
+        ) {
+            let file_path = tempfile::tempdir()
+                .unwrap()
+                .keep()
+                .join("test_ledger_store.bin");
+            let mut ledger_map = LedgerMap::new_with_path(None, Some(file_path)).unwrap();
+            let mut model = ReferenceModel::default();
+            for op in ops.iter().chain([&LedgerOp::CommitBlock]) {
+                op.apply_to(&mut ledger_map).unwrap();
+                model.apply(op);
+                model.check(&ledger_map).map_err(TestCaseError::fail)?;
+            }
+            ledger_map.refresh_ledger().unwrap();
+            model.check(&ledger_map).map_err(TestCaseError::fail)?;
+        }
+    }
+}
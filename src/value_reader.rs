@@ -0,0 +1,139 @@
+//! This module implements `ValueReader`, which streams an entry value, see
+//! `LedgerMap::get_reader`.
+use crate::errors::LedgerError;
+use crate::ledger_map::{ChunkedValue, LedgerMap};
+use sha2::Digest;
+use std::io;
+
+/// Reader of an entry value. Plain values are read from the index, and the chunks of chunked
+/// values from the persistent storage, one chunk at a time. Reading a chunked value fails with
+/// `io::ErrorKind::InvalidData` if it does not match its length and hash, which is checked when
+/// the end of the value is reached.
+pub struct ValueReader<'a> {
+    source: ValueSource<'a>,
+}
+
+enum ValueSource<'a> {
+    Inline(&'a [u8]),
+    Chunked {
+        ledger_map: &'a LedgerMap,
+        chunked_value: ChunkedValue,
+        /// Index of the next chunk to read in `chunked_value.chunk_blocks`.
+        next_chunk: usize,
+        /// Current chunk, and the position of the next byte to read in it.
+        chunk: Vec<u8>,
+        chunk_pos: usize,
+        len: u64,
+        hasher: Box<sha2::Sha256>,
+    },
+}
+
+impl<'a> ValueReader<'a> {
+    pub(crate) fn inline(value: &'a [u8]) -> Self {
+        ValueReader {
+            source: ValueSource::Inline(value),
+        }
+    }
+
+    pub(crate) fn chunked(ledger_map: &'a LedgerMap, chunked_value: ChunkedValue) -> Self {
+        ValueReader {
+            source: ValueSource::Chunked {
+                ledger_map,
+                chunked_value,
+                next_chunk: 0,
+                chunk: Vec::new(),
+                chunk_pos: 0,
+                len: 0,
+                hasher: Box::new(sha2::Sha256::new()),
+            },
+        }
+    }
+
+    /// Length of the whole value, in bytes.
+    pub fn len(&self) -> u64 {
+        match &self.source {
+            ValueSource::Inline(value) => value.len() as u64,
+            ValueSource::Chunked { chunked_value, .. } => chunked_value.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl std::fmt::Debug for ValueReader<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ValueReader {{ len: {} }}", self.len())
+    }
+}
+
+impl io::Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.source {
+            ValueSource::Inline(value) => io::Read::read(value, buf),
+            ValueSource::Chunked {
+                ledger_map,
+                chunked_value,
+                next_chunk,
+                chunk,
+                chunk_pos,
+                len,
+                hasher,
+            } => {
+                while *chunk_pos == chunk.len() {
+                    let block_index = match chunked_value.chunk_blocks.get(*next_chunk) {
+                        Some(block_index) => *block_index,
+                        None => {
+                            if *next_chunk == chunked_value.chunk_blocks.len() {
+                                // Check the value once, when the end is first reached
+                                *next_chunk += 1;
+                                chunked_value
+                                    .check(*len, &(**hasher).clone().finalize())
+                                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                            }
+                            return Ok(0);
+                        }
+                    };
+                    *chunk = ledger_map.read_chunk(block_index).map_err(|e| match e {
+                        LedgerError::BlockCorrupted(_) => {
+                            io::Error::new(io::ErrorKind::InvalidData, e)
+                        }
+                        _ => io::Error::other(e),
+                    })?;
+                    *chunk_pos = 0;
+                    *next_chunk += 1;
+                    hasher.update(&chunk[..]);
+                    *len += chunk.len() as u64;
+                }
+                let read_len = buf.len().min(chunk.len() - *chunk_pos);
+                buf[..read_len].copy_from_slice(&chunk[*chunk_pos..*chunk_pos + read_len]);
+                *chunk_pos += read_len;
+                if *chunk_pos == chunk.len() {
+                    // Release the chunk before the next one is read
+                    *chunk = Vec::new();
+                    *chunk_pos = 0;
+                }
+                Ok(read_len)
+            }
+        }
+    }
+}
+
+/// Async reads, for the async runtimes of the servers. The reads do not block on I/O other than
+/// the persistent storage, so they complete immediately.
+#[cfg(all(
+    any(feature = "server", feature = "grpc", feature = "p2p"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+impl tokio::io::AsyncRead for ValueReader<'_> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        let read_len = io::Read::read(&mut *self, buf.initialize_unfilled())?;
+        buf.advance(read_len);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
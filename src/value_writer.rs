@@ -0,0 +1,99 @@
+//! This module implements `ValueWriter`, which stores a large entry value in chunks as it is
+//! written, see `LedgerMap::put_writer`.
+use crate::ledger_map::{ChunkedValue, LedgerMap};
+use sha2::Digest;
+use std::io;
+
+/// Writer of a chunked entry value. The written bytes are collected into chunks of the chunk
+/// size, and each full chunk is committed right away, so that at most one chunk is held in
+/// memory. `flush` does not commit partial chunks. Call `finish` to commit the entry.
+pub struct ValueWriter<'a> {
+    ledger_map: &'a mut LedgerMap,
+    label: String,
+    key: Vec<u8>,
+    chunk_size_bytes: usize,
+    chunk: Vec<u8>,
+    chunked_value: ChunkedValue,
+    hasher: sha2::Sha256,
+}
+
+impl<'a> ValueWriter<'a> {
+    pub(crate) fn new(
+        ledger_map: &'a mut LedgerMap,
+        label: &str,
+        key: &[u8],
+        chunk_size_bytes: usize,
+    ) -> Self {
+        ValueWriter {
+            ledger_map,
+            label: label.to_string(),
+            key: key.to_vec(),
+            chunk_size_bytes,
+            chunk: Vec::new(),
+            chunked_value: ChunkedValue {
+                len: 0,
+                sha256: Vec::new(),
+                chunk_blocks: Vec::new(),
+            },
+            hasher: sha2::Sha256::new(),
+        }
+    }
+
+    /// Number of bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.chunked_value.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Commit the last chunk, and then the entry with its `ChunkedValue`.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self._commit_chunk()?;
+        self.chunked_value.sha256 = self.hasher.clone().finalize().to_vec();
+        self.ledger_map
+            .commit_chunked_value(&self.label, &self.key, &self.chunked_value)
+    }
+
+    fn _commit_chunk(&mut self) -> anyhow::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::take(&mut self.chunk);
+        let chunk_index = self.chunked_value.chunk_blocks.len() as u32;
+        let block_index =
+            self.ledger_map
+                .commit_chunk(&self.label, &self.key, chunk_index, chunk)?;
+        self.chunked_value.chunk_blocks.push(block_index);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for ValueWriter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ValueWriter {{ label: {:?}, len: {} }}",
+            self.label,
+            self.len()
+        )
+    }
+}
+
+impl io::Write for ValueWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.chunk.len() == self.chunk_size_bytes {
+            self._commit_chunk().map_err(io::Error::other)?;
+        }
+        let write_len = buf.len().min(self.chunk_size_bytes - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..write_len]);
+        self.hasher.update(&buf[..write_len]);
+        self.chunked_value.len += write_len as u64;
+        Ok(write_len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
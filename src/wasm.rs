@@ -1,116 +1,474 @@
-use crate::{LedgerEntry, LedgerMap};
-use js_sys::{Array, Uint8Array};
+use crate::errors::{error_code, OTHER_ERROR_CODE};
+use crate::ledger_entry::LedgerBlockHeader;
+use crate::partition_table;
+use crate::platform_specific::{
+    clear_ephemeral_storage, ensure_storage_is_initialized, init_ephemeral_storage_from_persistent,
+    is_persistent_storage_available, persist_ledger, persistent_storage_first_valid_offset,
+    persistent_storage_last_valid_offset, persistent_storage_read, persistent_storage_write,
+};
+use crate::{LedgerBlock, LedgerEntry, LedgerError, LedgerMap, Operation};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use js_sys::{Function, Reflect, Uint8Array};
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
-#[wasm_bindgen]
-pub struct WasmLedgerMap {
-    inner: LedgerMap,
+/// Build a JS `Error` with name `LedgerError` and the numeric error code in its `code` property.
+fn js_error(message: &str, code: u32) -> JsValue {
+    let error = js_sys::Error::new(message);
+    error.set_name("LedgerError");
+    // Setting a property on a fresh Error object cannot fail
+    let _ = Reflect::set(&error, &JsValue::from_str("code"), &JsValue::from(code));
+    error.into()
 }
 
-#[wasm_bindgen]
-pub struct WasmLedgerMapBlock {
-    entries: Vec<LedgerEntry>,
-    timestamp: u64,
-    parent_hash: Vec<u8>,
+fn ledger_error_to_js(error: LedgerError) -> JsValue {
+    js_error(&error.to_string(), error.code())
 }
 
-#[wasm_bindgen]
-pub struct WasmLedgerMapEntry {
-    label: String,
-    key: Vec<u8>,
-    value: Vec<u8>,
-    operation: String,
+fn anyhow_error_to_js(error: anyhow::Error) -> JsValue {
+    js_error(&error.to_string(), error_code(&error))
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_TYPES: &'static str = r#"
+/** Operation of a ledger entry. */
+export type Operation = "Upsert" | "Delete";
+
+/** Entry of the ledger, or of the next (uncommitted) block. */
+export interface LedgerEntry {
+    label: string;
+    key: Uint8Array;
+    value: Uint8Array;
+    operation: Operation;
+}
+
+/** Committed block of the ledger. */
+export interface LedgerBlock {
+    entries: LedgerEntry[];
+    timestamp: bigint;
+    parent_hash: Uint8Array;
+    offset: bigint;
+    version: number;
+}
+
+/** Block parsed and hashed by `verify_block_from_bytes()`. */
+export interface BlockVerification {
+    hash: Uint8Array;
+    parent_hash: Uint8Array;
+    timestamp: bigint;
+    version: number;
+    length: number;
+    entries_count: number;
+    extends_tip: boolean;
+}
+
+/**
+ * Result of `verify_chain()`. If the chain is not valid, `error` and `code` describe the problem,
+ * and for a broken hash chain, `offset`, `expected_hash` and `actual_hash` locate it.
+ */
+export interface ChainVerification {
+    valid: boolean;
+    blocks_count: number;
+    tip_hash?: Uint8Array;
+    error?: string;
+    code?: number;
+    offset?: bigint;
+    expected_hash?: Uint8Array;
+    actual_hash?: Uint8Array;
+}
+
+/**
+ * Outcome of restoring the ledger in `WasmLedgerMap.load()`:
+ * - `New`: nothing was persisted, the ledger is empty.
+ * - `Restored`: the persisted data was loaded and verified.
+ * - `Incomplete`: the persisted data starts at `persisted_offset` and needs the preceding ledger
+ *   data, from `data_start`, to be applied with `apply_raw_bytes()`.
+ * - `Reset`: the persisted data was corrupted and was discarded.
+ */
+export interface RestoreStatus {
+    status: "New" | "Restored" | "Incomplete" | "Reset";
+    data_start: bigint;
+    persisted_offset?: bigint;
+    error?: string;
 }
 
+/** Progress of `apply_stream()`: blocks and bytes verified and applied so far. */
+export interface StreamProgress {
+    blocks: number;
+    bytes: number;
+}
+
+/** Block pushed by the `/ws/blocks` WebSocket endpoint of the ledger server, see `subscribe_blocks()`. */
+export interface PushedBlock {
+    index: number;
+    offset: bigint;
+    timestamp: bigint;
+    hash: Uint8Array;
+    bytes: Uint8Array;
+}
+
+/** Error thrown by the ledger methods. `code` is one of the stable `LedgerErrorCode` values. */
+export interface LedgerError extends Error {
+    name: "LedgerError";
+    code: number;
+}
+"#;
+
 #[wasm_bindgen]
-impl WasmLedgerMapBlock {
-    #[wasm_bindgen(getter)]
-    pub fn entries(&self) -> Array {
-        let arr = Array::new();
-        for entry in &self.entries {
-            let wasm_entry = WasmLedgerMapEntry {
-                label: entry.label().to_string(),
-                key: entry.key().to_vec(),
-                value: entry.value().to_vec(),
-                operation: format!("{:?}", entry.operation()),
-            };
-            arr.push(&JsValue::from(wasm_entry));
+extern "C" {
+    #[wasm_bindgen(typescript_type = "LedgerBlock")]
+    pub type JsLedgerBlock;
+
+    #[wasm_bindgen(typescript_type = "RestoreStatus")]
+    pub type JsRestoreStatus;
+
+    #[wasm_bindgen(typescript_type = "BlockVerification")]
+    pub type JsBlockVerification;
+
+    #[wasm_bindgen(typescript_type = "ChainVerification")]
+    pub type JsChainVerification;
+
+    #[wasm_bindgen(typescript_type = "LedgerBlock[]")]
+    pub type JsLedgerBlockArray;
+
+    #[wasm_bindgen(typescript_type = "LedgerEntry[]")]
+    pub type JsLedgerEntryArray;
+
+    #[wasm_bindgen(typescript_type = "(block: LedgerBlock) => boolean | void")]
+    pub type JsBlockCallback;
+
+    #[wasm_bindgen(typescript_type = "(entry: LedgerEntry) => boolean | void")]
+    pub type JsEntryCallback;
+
+    #[wasm_bindgen(typescript_type = "StreamProgress")]
+    pub type JsStreamProgress;
+
+    #[wasm_bindgen(typescript_type = "(progress: StreamProgress) => void")]
+    pub type JsStreamProgressCallback;
+
+    #[wasm_bindgen(typescript_type = "(block: PushedBlock) => void")]
+    pub type JsPushedBlockCallback;
+}
+
+#[derive(Serialize)]
+struct EntryObject<'a> {
+    label: &'a str,
+    #[serde(with = "serde_bytes")]
+    key: &'a [u8],
+    #[serde(with = "serde_bytes")]
+    value: &'a [u8],
+    operation: Operation,
+}
+
+impl<'a> From<&'a LedgerEntry> for EntryObject<'a> {
+    fn from(entry: &'a LedgerEntry) -> Self {
+        EntryObject {
+            label: entry.label(),
+            key: entry.key(),
+            value: entry.value(),
+            operation: entry.operation(),
         }
-        arr
     }
+}
 
-    #[wasm_bindgen(getter)]
-    pub fn timestamp(&self) -> u64 {
-        self.timestamp
-    }
+#[derive(Serialize)]
+struct BlockObject<'a> {
+    entries: Vec<EntryObject<'a>>,
+    timestamp: u64,
+    #[serde(with = "serde_bytes")]
+    parent_hash: &'a [u8],
+    offset: u64,
+    version: u32,
+}
 
-    #[wasm_bindgen(getter)]
-    pub fn parent_hash(&self) -> Uint8Array {
-        Uint8Array::from(&self.parent_hash[..])
+impl<'a> From<&'a LedgerBlock> for BlockObject<'a> {
+    fn from(block: &'a LedgerBlock) -> Self {
+        BlockObject {
+            entries: block.entries().iter().map(EntryObject::from).collect(),
+            timestamp: block.timestamp(),
+            parent_hash: block.parent_hash(),
+            offset: block.get_offset(),
+            version: block.version(),
+        }
     }
 }
 
-#[wasm_bindgen]
-impl WasmLedgerMapEntry {
-    #[wasm_bindgen(getter)]
-    pub fn label(&self) -> String {
-        self.label.clone()
-    }
+#[derive(Serialize)]
+struct BlockVerification {
+    #[serde(with = "serde_bytes")]
+    hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    parent_hash: Vec<u8>,
+    timestamp: u64,
+    version: u32,
+    length: u32,
+    entries_count: u32,
+    extends_tip: bool,
+}
 
-    #[wasm_bindgen(getter)]
-    pub fn key(&self) -> Uint8Array {
-        Uint8Array::from(&self.key[..])
-    }
+#[derive(Default, Serialize)]
+struct ChainVerification {
+    valid: bool,
+    blocks_count: u32,
+    #[serde(with = "serde_bytes")]
+    tip_hash: Option<Vec<u8>>,
+    error: Option<String>,
+    code: Option<u32>,
+    offset: Option<u64>,
+    #[serde(with = "serde_bytes")]
+    expected_hash: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes")]
+    actual_hash: Option<Vec<u8>>,
+}
 
-    #[wasm_bindgen(getter)]
-    pub fn value(&self) -> Uint8Array {
-        Uint8Array::from(&self.value[..])
-    }
+#[derive(Debug, Serialize)]
+enum RestoreState {
+    New,
+    Restored,
+    Incomplete,
+    Reset,
+}
+
+#[derive(Serialize)]
+struct RestoreStatus {
+    status: RestoreState,
+    data_start: u64,
+    persisted_offset: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+struct StreamProgress {
+    blocks: u32,
+    bytes: u32,
+}
 
-    #[wasm_bindgen(getter)]
-    pub fn operation(&self) -> String {
-        self.operation.clone()
+/// Block message of the `/ws/blocks` WebSocket endpoint of the ledger server.
+#[derive(Deserialize)]
+struct BlockMessage {
+    index: u32,
+    offset: u64,
+    timestamp: u64,
+    hash: String,
+    bytes: String,
+}
+
+#[derive(Serialize)]
+struct PushedBlock {
+    index: u32,
+    offset: u64,
+    timestamp: u64,
+    #[serde(with = "serde_bytes")]
+    hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    bytes: Vec<u8>,
+}
+
+impl TryFrom<BlockMessage> for PushedBlock {
+    type Error = String;
+
+    fn try_from(message: BlockMessage) -> Result<Self, Self::Error> {
+        Ok(PushedBlock {
+            index: message.index,
+            offset: message.offset,
+            timestamp: message.timestamp,
+            hash: hex::decode(&message.hash).map_err(|e| e.to_string())?,
+            bytes: BASE64.decode(&message.bytes).map_err(|e| e.to_string())?,
+        })
     }
 }
 
+/// Convert `value` to a plain JS object, with u64 values as `bigint` and bytes as `Uint8Array`.
+fn to_js<T: Serialize, R: JsCast>(value: &T) -> Result<R, JsValue> {
+    let serializer =
+        serde_wasm_bindgen::Serializer::new().serialize_large_number_types_as_bigints(true);
+    value
+        .serialize(&serializer)
+        .map(|value| value.unchecked_into())
+        .map_err(|e| js_error(&e.to_string(), OTHER_ERROR_CODE))
+}
+
+/// Call `callback` with `value`, and return whether the iteration should continue,
+/// which is the case unless the callback returns `false`.
+fn call_until_false<C: JsCast>(callback: &C, value: JsValue) -> Result<bool, JsValue> {
+    let result = callback
+        .unchecked_ref::<Function>()
+        .call1(&JsValue::NULL, &value)?;
+    Ok(result.as_bool() != Some(false))
+}
+
+/// Subscribe to the blocks pushed by the WebSocket endpoint of the ledger server at `url`,
+/// e.g. `ws://localhost:8080/ws/blocks?start=5`, and call `callback` with each block.
+/// A block can be applied to a replica with `apply_raw_bytes(block.offset, block.bytes)`, after
+/// checking with `verify_block_from_bytes(block.bytes)` that it extends the replica tip.
+/// Returns the WebSocket, which can be closed to end the subscription.
+#[wasm_bindgen]
+pub fn subscribe_blocks(
+    url: &str,
+    callback: JsPushedBlockCallback,
+) -> Result<web_sys::WebSocket, JsValue> {
+    let socket = web_sys::WebSocket::new(url)?;
+    let callback: Function = callback.unchecked_into();
+    let onmessage =
+        Closure::<dyn FnMut(web_sys::MessageEvent)>::new(move |event: web_sys::MessageEvent| {
+            let Some(text) = event.data().as_string() else {
+                warn!("Ignoring non-text message from the block subscription");
+                return;
+            };
+            let block = js_sys::JSON::parse(&text)
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|value| {
+                    serde_wasm_bindgen::from_value::<BlockMessage>(value).map_err(|e| e.to_string())
+                })
+                .and_then(PushedBlock::try_from);
+            let result = match block {
+                Ok(block) => to_js::<_, JsValue>(&block)
+                    .and_then(|block| callback.call1(&JsValue::NULL, &block)),
+                Err(err) => {
+                    warn!("Ignoring invalid block message: {}", err);
+                    return;
+                }
+            };
+            if let Err(err) = result {
+                warn!("Block subscription callback failed: {:?}", err);
+            }
+        });
+    socket.set_onmessage(Some(onmessage.into_js_value().unchecked_ref()));
+    Ok(socket)
+}
+
+#[wasm_bindgen]
+pub struct WasmLedgerMap {
+    inner: LedgerMap,
+    restore_status: Option<RestoreStatus>,
+    auto_persist: bool,
+}
+
 #[wasm_bindgen]
 impl WasmLedgerMap {
     #[wasm_bindgen(constructor)]
     pub fn new(labels_to_index: Option<Vec<String>>) -> Result<WasmLedgerMap, JsValue> {
-        let inner =
-            LedgerMap::new(labels_to_index).map_err(|e| JsValue::from_str(&e.to_string()))?;
-        Ok(WasmLedgerMap { inner })
+        let inner = LedgerMap::new(labels_to_index).map_err(anyhow_error_to_js)?;
+        Ok(WasmLedgerMap {
+            inner,
+            restore_status: None,
+            auto_persist: true,
+        })
+    }
+
+    /// Initialize the storage, restore the ledger from the browser persistent storage and
+    /// verify it, in one call. The outcome is available from `restore_status()`.
+    pub async fn load(labels_to_index: Option<Vec<String>>) -> Result<WasmLedgerMap, JsValue> {
+        ensure_storage_is_initialized();
+        // Create the ledger on empty storage, so that it can be returned even if the
+        // persisted data cannot be verified on its own
+        clear_ephemeral_storage();
+        let mut inner = LedgerMap::new(labels_to_index).map_err(anyhow_error_to_js)?;
+
+        let restored = init_ephemeral_storage_from_persistent();
+        let mut restore_status = RestoreStatus {
+            status: RestoreState::New,
+            data_start: partition_table::get_data_partition().start_lba,
+            persisted_offset: None,
+            error: None,
+        };
+        if let Err(err) = restored {
+            restore_status.status = RestoreState::Reset;
+            restore_status.error = Some(err);
+        } else if persistent_storage_last_valid_offset() > 0 {
+            restore_status.persisted_offset = Some(persistent_storage_first_valid_offset());
+            match inner.refresh_ledger() {
+                Ok(()) => restore_status.status = RestoreState::Restored,
+                Err(err) => {
+                    restore_status.status = RestoreState::Incomplete;
+                    restore_status.error = Some(err.to_string());
+                }
+            }
+        }
+        info!("Ledger loaded, restore status: {:?}", restore_status.status);
+        Ok(WasmLedgerMap {
+            inner,
+            restore_status: Some(restore_status),
+            auto_persist: true,
+        })
+    }
+
+    /// Outcome of restoring the ledger, if it was created with `load()`.
+    pub fn restore_status(&self) -> Result<Option<JsRestoreStatus>, JsValue> {
+        self.restore_status.as_ref().map(to_js).transpose()
     }
 
     pub fn upsert(&mut self, label: &str, key: &[u8], value: &[u8]) -> Result<(), JsValue> {
         self.inner
             .upsert(label, key.to_vec(), value.to_vec())
-            .map_err(|e| JsValue::from_str(&e.to_string()))
+            .map_err(ledger_error_to_js)
     }
 
     pub fn get(&self, label: &str, key: &[u8]) -> Result<Vec<u8>, JsValue> {
         self.inner
             .get(label, key)
             .map(|v| v.clone())
-            .map_err(|e| JsValue::from_str(&e.to_string()))
+            .map_err(ledger_error_to_js)
+    }
+
+    /// Values of `keys` of `label`, in one call, with `undefined` for the keys that are not found.
+    pub fn get_many(&self, label: &str, keys: Vec<Uint8Array>) -> Result<js_sys::Array, JsValue> {
+        let keys = keys.iter().map(Uint8Array::to_vec).collect::<Vec<_>>();
+        let keys = keys.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let values = js_sys::Array::new();
+        for value in self.inner.get_many(label, &keys) {
+            match value {
+                Ok(value) => values.push(&Uint8Array::from(&value[..])),
+                Err(LedgerError::EntryNotFound) => values.push(&JsValue::UNDEFINED),
+                Err(err) => return Err(ledger_error_to_js(err)),
+            };
+        }
+        Ok(values)
     }
 
     pub fn delete(&mut self, label: &str, key: &[u8]) -> Result<(), JsValue> {
-        self.inner
-            .delete(label, key)
-            .map_err(|e| JsValue::from_str(&e.to_string()))
+        self.inner.delete(label, key).map_err(ledger_error_to_js)
     }
 
     pub fn refresh(&mut self) -> Result<(), JsValue> {
-        self.inner
-            .refresh_ledger()
-            .map_err(|e| JsValue::from_str(&e.to_string()))
+        self.inner.refresh_ledger().map_err(anyhow_error_to_js)
     }
 
+    /// Commit the next block. Unless disabled with `set_auto_persist(false)`, the ledger is also
+    /// persisted in the browser local storage, as far as the budget allows but at least the new
+    /// last block, as the anchor for verifying the ledger after a reload. In a (Shared)Worker,
+    /// where there is no local storage, nothing is persisted.
+    /// The block is committed even if not even the last block can be persisted, which is only
+    /// logged as a warning; the previously persisted data is kept in that case.
     pub fn commit_block(&mut self) -> Result<(), JsValue> {
-        self.inner
-            .commit_block()
-            .map_err(|e| JsValue::from_str(&e.to_string()))
+        let blocks_count = self.inner.get_blocks_count();
+        self.inner.commit_block().map_err(anyhow_error_to_js)?;
+        if self.auto_persist
+            && is_persistent_storage_available()
+            && self.inner.get_blocks_count() > blocks_count
+        {
+            if let Err(err) = persist_ledger(partition_table::get_data_partition().start_lba) {
+                warn!("The committed block was not persisted: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the maximum number of bytes of (base64 encoded) ledger data persisted in the
+    /// browser local storage. The default is 2 MiB.
+    pub fn set_persistent_storage_budget(&mut self, budget_bytes: u64) {
+        crate::platform_specific::set_persistent_storage_budget(budget_bytes);
+    }
+
+    /// Enable or disable persisting the ledger in the browser local storage on each commit.
+    /// Enabled by default.
+    pub fn set_auto_persist(&mut self, enabled: bool) {
+        self.auto_persist = enabled;
+    }
+
+    pub fn get_auto_persist(&self) -> bool {
+        self.auto_persist
     }
 
     pub fn get_blocks_count(&self) -> usize {
@@ -134,35 +492,230 @@ impl WasmLedgerMap {
         self.inner.get_next_block_start_pos()
     }
 
-    pub fn get_block_entries(&self, label: Option<String>) -> Array {
-        let entries: Vec<_> = self.inner.iter(label.as_deref()).collect();
-        let arr = Array::new();
-        for entry in entries {
-            info!("entry: {:#?}", entry);
-            let wasm_entry = WasmLedgerMapEntry {
-                label: entry.label().to_string(),
-                key: entry.key().to_vec(),
-                value: entry.value().to_vec(),
-                operation: format!("{:?}", entry.operation()),
-            };
-            arr.push(&JsValue::from(wasm_entry));
+    /// Returns the committed block with the given index, or `undefined` if there is no such block.
+    pub fn get_block(&self, index: usize) -> Result<Option<JsLedgerBlock>, JsValue> {
+        match self.inner.iter_raw_from_block(index).next() {
+            Some(entry) => {
+                let (_, block) = entry.map_err(anyhow_error_to_js)?;
+                to_js(&BlockObject::from(&block)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns up to `count` committed blocks, starting with the block with index `start`.
+    pub fn get_blocks(&self, start: usize, count: usize) -> Result<JsLedgerBlockArray, JsValue> {
+        let blocks = self
+            .inner
+            .iter_raw_from_block(start)
+            .take(count)
+            .map(|entry| entry.map(|(_, block)| block))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(anyhow_error_to_js)?;
+        to_js(&blocks.iter().map(BlockObject::from).collect::<Vec<_>>())
+    }
+
+    /// Parse and hash a raw block (header and body), e.g. downloaded from another replica,
+    /// without applying it. `extends_tip` tells whether the block can be appended to this ledger.
+    /// Throws a `LedgerError` if the block is corrupted.
+    pub fn verify_block_from_bytes(&self, bytes: &[u8]) -> Result<JsBlockVerification, JsValue> {
+        let (block_header, block, hash) = self
+            .inner
+            .get_block_from_slice(bytes)
+            .map_err(ledger_error_to_js)?;
+        to_js(&BlockVerification {
+            extends_tip: block.parent_hash() == self.inner.get_latest_block_hash(),
+            hash,
+            parent_hash: block.parent_hash().to_vec(),
+            timestamp: block.timestamp(),
+            version: block.version(),
+            length: block_header.jump_bytes_next_block(),
+            entries_count: block.entries().len() as u32,
+        })
+    }
+
+    /// Verify the hash chain of all blocks in the storage, without changing the ledger.
+    pub fn verify_chain(&self) -> Result<JsChainVerification, JsValue> {
+        let verification = match self.inner.verify_chain() {
+            Ok(tip_hash) => ChainVerification {
+                valid: true,
+                blocks_count: self.inner.get_blocks_count() as u32,
+                tip_hash: Some(tip_hash),
+                ..Default::default()
+            },
+            Err(err) => {
+                let mut verification = ChainVerification {
+                    blocks_count: self.inner.get_blocks_count() as u32,
+                    error: Some(err.to_string()),
+                    code: Some(error_code(&err)),
+                    ..Default::default()
+                };
+                if let Some(LedgerError::HashMismatch {
+                    expected,
+                    actual,
+                    offset,
+                }) = err.downcast_ref::<LedgerError>()
+                {
+                    verification.offset = Some(*offset);
+                    verification.expected_hash = Some(expected.clone());
+                    verification.actual_hash = Some(actual.clone());
+                }
+                verification
+            }
+        };
+        to_js(&verification)
+    }
+
+    /// Returns the raw persisted bytes (header and body) of the block at `offset`.
+    pub fn get_raw_block_bytes(&self, offset: u64) -> Result<Uint8Array, JsValue> {
+        let (block_header, block) = self
+            .inner
+            .get_block_at_offset(offset)
+            .map_err(ledger_error_to_js)?;
+        self.get_raw_bytes(
+            block.get_offset(),
+            block_header.jump_bytes_next_block() as u64,
+        )
+    }
+
+    /// Returns up to `len` raw bytes of the persistent storage, starting at `start`. The bytes
+    /// end at the end of the committed blocks, `get_next_block_start_pos()`.
+    pub fn get_raw_bytes(&self, start: u64, len: u64) -> Result<Uint8Array, JsValue> {
+        let len = len.min(self.inner.get_next_block_start_pos().saturating_sub(start));
+        let mut buf = vec![0u8; len as usize];
+        persistent_storage_read(start, &mut buf).map_err(|e| js_error(&e, OTHER_ERROR_CODE))?;
+        Ok(Uint8Array::from(&buf[..]))
+    }
+
+    /// Writes raw bytes, e.g. obtained from `get_raw_bytes` of another replica, to the
+    /// persistent storage at `offset` and reloads the ledger from the storage.
+    /// The bytes within the data partition must be whole blocks, which are verified with their
+    /// hash chain before anything is written. Fails if the resulting ledger does not verify.
+    pub fn apply_raw_bytes(&mut self, offset: u64, bytes: &[u8]) -> Result<(), JsValue> {
+        self.verify_raw_blocks(offset, bytes)
+            .map_err(anyhow_error_to_js)?;
+        let end = offset + bytes.len() as u64;
+        let extends_storage = end >= persistent_storage_last_valid_offset();
+        persistent_storage_write(offset, bytes).map_err(ledger_error_to_js)?;
+        if extends_storage {
+            // Mark the end of the block chain, as after a commit
+            persistent_storage_write(end, &[0u8; LedgerBlockHeader::sizeof()])
+                .map_err(ledger_error_to_js)?;
         }
-        arr
-    }
-
-    pub fn get_next_block_entries(&self, label: Option<String>) -> Array {
-        let entries: Vec<_> = self.inner.next_block_iter(label.as_deref()).collect();
-        let arr = Array::new();
-        for entry in entries {
-            let wasm_entry = WasmLedgerMapEntry {
-                label: entry.label().to_string(),
-                key: entry.key().to_vec(),
-                value: entry.value().to_vec(),
-                operation: format!("{:?}", entry.operation()),
+        self.inner.refresh_ledger().map_err(anyhow_error_to_js)
+    }
+
+    /// Read raw ledger bytes from `stream`, e.g. the body of a `fetch()` response, and apply
+    /// them block by block as they arrive. The stream must start with the block that extends
+    /// the tip of this ledger, i.e. at `get_next_block_start_pos()`. Each block is verified
+    /// against the hash chain before it is written, and `progress` is called after every chunk
+    /// of the stream. Blocks verified before an error remain applied.
+    pub async fn apply_stream(
+        &mut self,
+        stream: web_sys::ReadableStream,
+        progress: Option<JsStreamProgressCallback>,
+    ) -> Result<JsStreamProgress, JsValue> {
+        let reader: web_sys::ReadableStreamDefaultReader = stream.get_reader().unchecked_into();
+        let mut position = self.inner.get_next_block_start_pos();
+        let mut parent_hash = self.inner.get_latest_block_hash();
+        let mut pending = Vec::new();
+        let mut applied = StreamProgress::default();
+        let mut result = Ok(());
+        loop {
+            let chunk = match wasm_bindgen_futures::JsFuture::from(reader.read()).await {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
             };
-            arr.push(&JsValue::from(wasm_entry));
+            if Reflect::get(&chunk, &JsValue::from_str("done"))?.is_truthy() {
+                if !pending.is_empty() {
+                    result = Err(ledger_error_to_js(LedgerError::BlockCorrupted(format!(
+                        "Stream ended within a block, {} bytes left",
+                        pending.len()
+                    ))));
+                }
+                break;
+            }
+            let value: Uint8Array = Reflect::get(&chunk, &JsValue::from_str("value"))?.into();
+            pending.extend_from_slice(&value.to_vec());
+            if let Err(err) = self.apply_stream_blocks(
+                &mut pending,
+                &mut position,
+                &mut parent_hash,
+                &mut applied,
+            ) {
+                result = Err(ledger_error_to_js(err));
+                break;
+            }
+            if let Some(progress) = &progress {
+                progress
+                    .unchecked_ref::<Function>()
+                    .call1(&JsValue::NULL, &to_js::<_, JsValue>(&applied)?)?;
+            }
+        }
+        reader.release_lock();
+
+        if applied.blocks > 0 {
+            // Mark the end of the block chain, as after a commit
+            persistent_storage_write(position, &[0u8; LedgerBlockHeader::sizeof()])
+                .map_err(ledger_error_to_js)?;
+            self.inner.refresh_ledger().map_err(anyhow_error_to_js)?;
+        }
+        result?;
+        to_js(&applied)
+    }
+
+    /// Call `callback` with each committed block, starting with the block with index `start`.
+    /// Blocks are read from the storage one at a time. Iteration stops early if the callback
+    /// returns `false`.
+    pub fn for_each_block(&self, start: usize, callback: &JsBlockCallback) -> Result<(), JsValue> {
+        for entry in self.inner.iter_raw_from_block(start) {
+            let (_, block) = entry.map_err(anyhow_error_to_js)?;
+            if !call_until_false(callback, to_js(&BlockObject::from(&block))?)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `callback` with each entry, optionally only of the given label, without building
+    /// an array of all entries first. Iteration stops early if the callback returns `false`.
+    pub fn for_each_entry(
+        &self,
+        label: Option<String>,
+        callback: &JsEntryCallback,
+    ) -> Result<(), JsValue> {
+        for entry in self.inner.iter(label.as_deref()) {
+            if !call_until_false(callback, to_js(&EntryObject::from(entry))?)? {
+                break;
+            }
         }
-        arr
+        Ok(())
+    }
+
+    /// Returns an array of all entries, optionally only of the given label.
+    /// Prefer `for_each_entry` for labels with many entries.
+    pub fn get_block_entries(&self, label: Option<String>) -> Result<JsLedgerEntryArray, JsValue> {
+        let entries: Vec<_> = self
+            .inner
+            .iter(label.as_deref())
+            .map(EntryObject::from)
+            .collect();
+        to_js(&entries)
+    }
+
+    pub fn get_next_block_entries(
+        &self,
+        label: Option<String>,
+    ) -> Result<JsLedgerEntryArray, JsValue> {
+        let entries: Vec<_> = self
+            .inner
+            .next_block_iter(label.as_deref())
+            .map(EntryObject::from)
+            .collect();
+        to_js(&entries)
     }
 
     pub fn get_next_block_entries_count(&self, label: Option<String>) -> usize {
@@ -170,6 +723,100 @@ impl WasmLedgerMap {
     }
 }
 
+impl WasmLedgerMap {
+    /// Verify the blocks of raw `bytes` to be written at `offset` by `apply_raw_bytes`: the
+    /// bytes within the data partition must be whole blocks, optionally followed by the end
+    /// marker, chained to each other and to the block preceding `offset`, if that is known.
+    fn verify_raw_blocks(&self, offset: u64, bytes: &[u8]) -> anyhow::Result<()> {
+        let data_start = partition_table::get_data_partition().start_lba;
+        let skip = data_start.saturating_sub(offset);
+        if skip >= bytes.len() as u64 {
+            return Ok(());
+        }
+        let blocks_offset = offset + skip;
+        let blocks = &bytes[skip as usize..];
+        let mut parent_hash = if blocks_offset == data_start {
+            Some(Vec::new())
+        } else if blocks_offset == self.inner.get_next_block_start_pos() {
+            Some(self.inner.get_latest_block_hash())
+        } else {
+            // Replaces a block of this ledger, or fills a gap before the persisted data
+            self.inner
+                .get_block_at_offset(blocks_offset)
+                .ok()
+                .filter(|(_, block)| block.get_offset() == blocks_offset)
+                .map(|(_, block)| block.parent_hash().to_vec())
+        };
+        let mut consumed = 0;
+        for block in self.inner.iter_raw_from_slice(blocks) {
+            let (block_header, block, hash) = block?;
+            if let Some(parent_hash) =
+                parent_hash.filter(|expected| block.parent_hash() != expected.as_slice())
+            {
+                return Err(LedgerError::HashMismatch {
+                    expected: parent_hash,
+                    actual: block.parent_hash().to_vec(),
+                    offset: blocks_offset + consumed as u64,
+                }
+                .into());
+            }
+            parent_hash = Some(hash);
+            consumed += block_header.jump_bytes_next_block() as usize;
+        }
+        if consumed > blocks.len() || blocks[consumed..].iter().any(|b| *b != 0) {
+            return Err(LedgerError::BlockCorrupted(format!(
+                "Raw bytes at offset {} do not end with a whole block",
+                blocks_offset + consumed.min(blocks.len()) as u64
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Verify and write all complete blocks at the start of `pending`, and remove them from it.
+    fn apply_stream_blocks(
+        &self,
+        pending: &mut Vec<u8>,
+        position: &mut u64,
+        parent_hash: &mut Vec<u8>,
+        applied: &mut StreamProgress,
+    ) -> Result<(), LedgerError> {
+        let mut consumed = 0;
+        while pending.len() - consumed >= LedgerBlockHeader::sizeof() {
+            let data = &pending[consumed..];
+            let block_header = match LedgerBlockHeader::deserialize(data) {
+                Ok(block_header) => block_header,
+                // The end marker of the source ledger, nothing follows it
+                Err(LedgerError::BlockEmpty) => {
+                    consumed = pending.len();
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            let length = block_header.jump_bytes_next_block() as usize;
+            if data.len() < length {
+                break;
+            }
+            let (_, block, hash) = self.inner.get_block_from_slice(&data[..length])?;
+            if block.parent_hash() != parent_hash.as_slice() {
+                return Err(LedgerError::HashMismatch {
+                    expected: parent_hash.clone(),
+                    actual: block.parent_hash().to_vec(),
+                    offset: *position,
+                });
+            }
+            persistent_storage_write(*position, &data[..length])?;
+            *position += length as u64;
+            *parent_hash = hash;
+            applied.blocks += 1;
+            applied.bytes += length as u32;
+            consumed += length;
+        }
+        pending.drain(..consumed);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 #[path = "wasm_tests.rs"]
 mod wasm_tests;
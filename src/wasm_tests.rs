@@ -5,8 +5,11 @@ use crate::platform_specific_wasm32_browser::{
     PERSISTENT_STORAGE_PAGE_SIZE,
 };
 use crate::wasm::WasmLedgerMap;
-use js_sys::{Object, Reflect};
-use wasm_bindgen::{JsCast, JsValue};
+use crate::LedgerError;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use js_sys::{Array, Object, Reflect, Uint8Array};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
 use wasm_bindgen_test::*;
 
 // Configure tests to run in the browser.
@@ -31,6 +34,11 @@ fn create_test_ledger() -> WasmLedgerMap {
     ledger
 }
 
+/// Returns the property `name` of the JS object `obj`.
+fn js_get(obj: &JsValue, name: &str) -> JsValue {
+    Reflect::get(obj, &JsValue::from_str(name)).unwrap()
+}
+
 //
 // Persistent Storage Tests
 //
@@ -52,7 +60,7 @@ fn test_persistent_storage_write_read() {
     clear_storage();
     ensure_storage_is_initialized();
     let data = b"Hello, Wasm!";
-    persistent_storage_write(0, data);
+    persistent_storage_write(0, data).unwrap();
     let mut buf = vec![0u8; data.len()];
     persistent_storage_read(0, &mut buf).unwrap();
     assert_eq!(&buf, data, "Data read should match data written");
@@ -64,7 +72,7 @@ fn test_persistent_storage_grow() {
     ensure_storage_is_initialized();
     // Write initial data.
     let data = b"Data";
-    persistent_storage_write(0, data);
+    persistent_storage_write(0, data).unwrap();
     let initial_size = persistent_storage_size_bytes();
     // Grow by 2 pages.
     persistent_storage_grow(2).unwrap();
@@ -91,14 +99,24 @@ fn test_persist_last_block() {
     // Simulate a new browser session.
     clear_ephemeral_storage();
     init_ephemeral_storage_from_persistent().unwrap();
-    let err_msg = ledger.refresh().unwrap_err().as_string().unwrap();
+    let err = ledger.refresh().unwrap_err();
+    assert_eq!(
+        Reflect::get(&err, &JsValue::from_str("code")).unwrap(),
+        JsValue::from(crate::errors::OTHER_ERROR_CODE)
+    );
+    let err_msg = err
+        .dyn_into::<js_sys::Error>()
+        .unwrap()
+        .message()
+        .as_string()
+        .unwrap();
     info!("Error message: {}", err_msg);
     assert!(err_msg
         .starts_with("Failed to read Ledger block: Requested data offset [8388608..8388624]"));
     // Simulate a reload.
     clear_ephemeral_storage();
     init_ephemeral_storage_from_persistent().unwrap();
-    persistent_storage_write(0, &buf);
+    persistent_storage_write(0, &buf).unwrap();
     ledger.refresh().unwrap();
 
     assert_eq!(ledger.get("label1", b"key1").unwrap(), b"value1".to_vec());
@@ -108,6 +126,162 @@ fn test_persist_last_block() {
 // Ledger (WasmLedgerMap) Tests
 //
 
+#[wasm_bindgen_test]
+async fn test_ledger_load() {
+    clear_storage();
+    let ledger = WasmLedgerMap::load(None).await.unwrap();
+    let status: JsValue = ledger.restore_status().unwrap().unwrap().into();
+    assert_eq!(js_get(&status, "status").as_string().unwrap(), "New");
+    assert!(WasmLedgerMap::new(None)
+        .unwrap()
+        .restore_status()
+        .unwrap()
+        .is_none());
+
+    // Persist the entire ledger, which can be restored on its own
+    let ledger = create_test_ledger();
+    let data_start = js_get(&status, "data_start");
+    persist_last_block(u64::try_from(data_start.clone()).unwrap()).unwrap();
+    let restored = WasmLedgerMap::load(None).await.unwrap();
+    let status: JsValue = restored.restore_status().unwrap().unwrap().into();
+    assert_eq!(js_get(&status, "status").as_string().unwrap(), "Restored");
+    assert_eq!(restored.get_blocks_count(), 2);
+    assert_eq!(restored.get("label1", b"key3").unwrap(), b"value3".to_vec());
+
+    // Persist only the last block, which needs the preceding data to be applied
+    let tip_start = ledger.get_latest_block_start_pos();
+    let data_start = u64::try_from(data_start).unwrap();
+    let preceding = ledger
+        .get_raw_bytes(data_start, tip_start - data_start)
+        .unwrap();
+    persist_last_block(tip_start).unwrap();
+    let mut restored = WasmLedgerMap::load(None).await.unwrap();
+    let status: JsValue = restored.restore_status().unwrap().unwrap().into();
+    assert_eq!(js_get(&status, "status").as_string().unwrap(), "Incomplete");
+    assert_eq!(
+        js_get(&status, "persisted_offset"),
+        JsValue::from(tip_start)
+    );
+    restored
+        .apply_raw_bytes(data_start, &preceding.to_vec())
+        .unwrap();
+    assert_eq!(restored.get_blocks_count(), 2);
+    assert_eq!(restored.get("label2", b"key2").unwrap(), b"value2".to_vec());
+}
+
+#[wasm_bindgen_test]
+async fn test_ledger_auto_persist() {
+    clear_storage();
+    ensure_storage_is_initialized();
+    let mut ledger = WasmLedgerMap::new(None).expect("Failed to create WasmLedgerMap");
+    assert!(ledger.get_auto_persist());
+    ledger.upsert("test", b"key1", b"value1").unwrap();
+    ledger.commit_block().unwrap();
+
+    // The committed block survives a reload without persisting it explicitly
+    let restored = WasmLedgerMap::load(None).await.unwrap();
+    let status: JsValue = restored.restore_status().unwrap().unwrap().into();
+    assert_eq!(js_get(&status, "status").as_string().unwrap(), "Restored");
+    assert_eq!(restored.get("test", b"key1").unwrap(), b"value1".to_vec());
+
+    // With auto-persist disabled, the persisted last block stays unchanged
+    let mut ledger = restored;
+    ledger.set_auto_persist(false);
+    ledger.upsert("test", b"key2", b"value2").unwrap();
+    ledger.commit_block().unwrap();
+    let restored = WasmLedgerMap::load(None).await.unwrap();
+    assert_eq!(restored.get_blocks_count(), 1);
+    assert!(restored.get("test", b"key2").is_err());
+}
+
+/// Returns `len` pseudo-random bytes, which don't compress.
+fn incompressible_bytes(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[wasm_bindgen_test]
+async fn test_ledger_restore_uncompressed_legacy_data() {
+    let ledger = create_test_ledger();
+    let data_start = crate::partition_table::get_data_partition().start_lba;
+    let data = ledger
+        .get_raw_bytes(data_start, ledger.get_next_block_start_pos() - data_start)
+        .unwrap()
+        .to_vec();
+
+    // Persisted data in the format before chunking and compression
+    clear_storage();
+    let local_storage = web_sys::window().unwrap().local_storage().unwrap().unwrap();
+    local_storage
+        .set_item("ledger_map_last_block", &BASE64.encode(&data))
+        .unwrap();
+    local_storage
+        .set_item("ledger_map_last_block_offset", &data_start.to_string())
+        .unwrap();
+
+    let restored = WasmLedgerMap::load(None).await.unwrap();
+    let status: JsValue = restored.restore_status().unwrap().unwrap().into();
+    assert_eq!(js_get(&status, "status").as_string().unwrap(), "Restored");
+    assert_eq!(restored.get_blocks_count(), 2);
+}
+
+#[wasm_bindgen_test]
+async fn test_ledger_persistence_budget() {
+    clear_storage();
+    ensure_storage_is_initialized();
+    let mut ledger = WasmLedgerMap::new(None).expect("Failed to create WasmLedgerMap");
+    // Spans multiple local storage chunks once base64 encoded
+    let large_value = incompressible_bytes(600 * 1024);
+    ledger.upsert("test", b"large", &large_value).unwrap();
+    ledger.commit_block().unwrap();
+    ledger.upsert("test", b"small", b"value").unwrap();
+    ledger.commit_block().unwrap();
+
+    let restored = WasmLedgerMap::load(None).await.unwrap();
+    let status: JsValue = restored.restore_status().unwrap().unwrap().into();
+    assert_eq!(js_get(&status, "status").as_string().unwrap(), "Restored");
+    assert_eq!(restored.get("test", b"large").unwrap(), large_value);
+
+    // With a budget too small for the large block, only the following blocks are persisted
+    let mut ledger = restored;
+    ledger.set_persistent_storage_budget(64 * 1024);
+    ledger.upsert("test", b"small", b"value2").unwrap();
+    ledger.commit_block().unwrap();
+    let tip_start = ledger.get_latest_block_start_pos();
+    let restored = WasmLedgerMap::load(None).await.unwrap();
+    let status: JsValue = restored.restore_status().unwrap().unwrap().into();
+    assert_eq!(js_get(&status, "status").as_string().unwrap(), "Incomplete");
+    assert!(u64::try_from(js_get(&status, "persisted_offset")).unwrap() <= tip_start);
+
+    // If not even the last block fits, the commit fails with a quota error
+    clear_storage();
+    ensure_storage_is_initialized();
+    let mut ledger = WasmLedgerMap::new(None).expect("Failed to create WasmLedgerMap");
+    ledger.set_persistent_storage_budget(16);
+    ledger.upsert("test", b"small", b"value3").unwrap();
+    let err = ledger.commit_block().unwrap_err();
+    assert_eq!(
+        Reflect::get(&err, &JsValue::from_str("code")).unwrap(),
+        JsValue::from(
+            LedgerError::QuotaExceeded {
+                quota_bytes: 0,
+                required_bytes: 0
+            }
+            .code()
+        )
+    );
+    ledger.set_persistent_storage_budget(
+        crate::platform_specific_wasm32_browser::DEFAULT_PERSISTENT_STORAGE_BUDGET_BYTES,
+    );
+}
+
 #[wasm_bindgen_test]
 fn test_ledger_basic_operations() {
     clear_storage();
@@ -128,6 +302,26 @@ fn test_ledger_basic_operations() {
     );
 }
 
+#[wasm_bindgen_test]
+fn test_ledger_error_code() {
+    clear_storage();
+    ensure_storage_is_initialized();
+    let mut ledger = WasmLedgerMap::new(None).expect("Failed to create WasmLedgerMap");
+    let err = ledger.get("test_label", b"missing_key").unwrap_err();
+    let js_err = err.dyn_ref::<js_sys::Error>().unwrap();
+    assert_eq!(js_err.name(), "LedgerError");
+    assert_eq!(
+        Reflect::get(&err, &JsValue::from_str("code")).unwrap(),
+        JsValue::from(LedgerError::EntryNotFound.code())
+    );
+
+    let err = ledger.upsert("", b"key", b"value").unwrap_err();
+    assert_eq!(
+        Reflect::get(&err, &JsValue::from_str("code")).unwrap(),
+        JsValue::from(LedgerError::InvalidLabel(String::new()).code())
+    );
+}
+
 #[wasm_bindgen_test]
 fn test_ledger_multiple_labels() {
     clear_storage();
@@ -171,6 +365,248 @@ fn test_ledger_block_operations() {
     assert!(hash.length() > 0, "Latest block hash should be non-empty");
 }
 
+#[wasm_bindgen_test]
+fn test_ledger_get_block() {
+    clear_storage();
+    ensure_storage_is_initialized();
+    let mut ledger = WasmLedgerMap::new(None).expect("Failed to create WasmLedgerMap");
+    ledger.upsert("test", b"key1", b"value1").unwrap();
+    ledger.commit_block().unwrap();
+    ledger.upsert("test", b"key2", b"value2").unwrap();
+    ledger.delete("test", b"key1").unwrap();
+    ledger.commit_block().unwrap();
+
+    let block: JsValue = ledger
+        .get_block(1)
+        .unwrap()
+        .expect("Block 1 should exist")
+        .into();
+    let entries: Array = js_get(&block, "entries").unchecked_into();
+    assert_eq!(entries.length(), 2);
+    assert_eq!(
+        js_get(&entries.get(1), "operation").as_string().unwrap(),
+        "Delete"
+    );
+    let parent_hash: Uint8Array = js_get(&block, "parent_hash").dyn_into().unwrap();
+    assert_eq!(
+        parent_hash.length(),
+        32,
+        "Second block should have a parent hash"
+    );
+    assert!(js_get(&block, "timestamp").is_bigint());
+    assert_eq!(
+        js_get(&block, "offset"),
+        JsValue::from(ledger.get_latest_block_start_pos())
+    );
+    assert!(ledger.get_block(2).unwrap().is_none());
+
+    let blocks_length = |start, count| {
+        ledger
+            .get_blocks(start, count)
+            .unwrap()
+            .unchecked_into::<Array>()
+            .length()
+    };
+    assert_eq!(blocks_length(0, 10), 2);
+    assert_eq!(blocks_length(1, 10), 1);
+    assert_eq!(blocks_length(0, 1), 1);
+    assert_eq!(blocks_length(5, 1), 0);
+}
+
+#[wasm_bindgen_test]
+fn test_ledger_raw_bytes_replication() {
+    let source = create_test_ledger();
+    let data_start = source.get_raw_block_bytes(0).unwrap();
+    let block_start = source.get_latest_block_start_pos();
+    let block = source.get_raw_block_bytes(block_start).unwrap().to_vec();
+    assert_eq!(
+        block.len() as u64,
+        source.get_next_block_start_pos() - block_start
+    );
+    assert!(data_start.length() > 0);
+
+    let partition_table = source.get_raw_bytes(0, block_start).unwrap().to_vec();
+    let end = source.get_next_block_start_pos();
+    let tip_hash = source.get_latest_block_hash().to_vec();
+
+    // Replicate the ledger into a fresh storage
+    clear_storage();
+    ensure_storage_is_initialized();
+    let mut replica = WasmLedgerMap::new(None).expect("Failed to create WasmLedgerMap");
+    replica.apply_raw_bytes(0, &partition_table).unwrap();
+    replica.apply_raw_bytes(block_start, &block).unwrap();
+    assert_eq!(replica.get_blocks_count(), 2);
+    assert_eq!(replica.get_next_block_start_pos(), end);
+    assert_eq!(replica.get_latest_block_hash().to_vec(), tip_hash);
+    assert_eq!(replica.get("label1", b"key3").unwrap(), b"value3".to_vec());
+
+    // Reads end at the end of the committed blocks
+    assert_eq!(
+        replica
+            .get_raw_bytes(block_start, 1024 * 1024)
+            .unwrap()
+            .to_vec(),
+        block
+    );
+    assert_eq!(replica.get_raw_bytes(end, 1024 * 1024).unwrap().length(), 0);
+    let err = replica.get_raw_bytes(end + 1024 * 1024, 16).unwrap_err();
+    assert_eq!(
+        Reflect::get(&err, &JsValue::from_str("code")).unwrap(),
+        JsValue::from(crate::errors::OTHER_ERROR_CODE)
+    );
+
+    // A corrupted block, or a partial one, is rejected before anything is written
+    let mut corrupted = block.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    assert!(replica.apply_raw_bytes(block_start, &corrupted).is_err());
+    assert!(replica
+        .apply_raw_bytes(block_start, &block[..block.len() - 1])
+        .is_err());
+    assert!(replica.apply_raw_bytes(end, &block).is_err());
+    assert_eq!(replica.get_blocks_count(), 2);
+    assert_eq!(
+        replica
+            .get_raw_bytes(block_start, 1024 * 1024)
+            .unwrap()
+            .to_vec(),
+        block
+    );
+}
+
+/// Returns a `ReadableStream` with the given bytes, as the body of a `fetch()` response.
+fn bytes_stream(bytes: &[u8]) -> web_sys::ReadableStream {
+    web_sys::Response::new_with_opt_u8_array(Some(&mut bytes.to_vec()[..]))
+        .unwrap()
+        .body()
+        .unwrap()
+}
+
+#[wasm_bindgen_test]
+async fn test_ledger_apply_stream() {
+    let source = create_test_ledger();
+    let first_block_start = crate::partition_table::get_data_partition().start_lba;
+    let end = source.get_next_block_start_pos();
+    let data = source
+        .get_raw_bytes(first_block_start, end - first_block_start)
+        .unwrap()
+        .to_vec();
+    let second_block = source
+        .get_raw_block_bytes(source.get_latest_block_start_pos())
+        .unwrap()
+        .to_vec();
+    let tip_hash = source.get_latest_block_hash().to_vec();
+
+    // A stream that does not extend the tip is rejected
+    clear_storage();
+    ensure_storage_is_initialized();
+    let mut replica = WasmLedgerMap::new(None).expect("Failed to create WasmLedgerMap");
+    let err = replica
+        .apply_stream(bytes_stream(&second_block), None)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        js_get(&err, "code"),
+        JsValue::from(
+            LedgerError::HashMismatch {
+                expected: vec![],
+                actual: vec![],
+                offset: 0
+            }
+            .code()
+        )
+    );
+    assert_eq!(replica.get_blocks_count(), 0);
+
+    let count = std::rc::Rc::new(std::cell::Cell::new(0));
+    let callback = counting_callback(&count, u32::MAX);
+    let applied: JsValue = replica
+        .apply_stream(
+            bytes_stream(&data),
+            Some(callback.as_ref().clone().unchecked_into()),
+        )
+        .await
+        .unwrap()
+        .into();
+    assert!(count.get() > 0);
+    assert_eq!(js_get(&applied, "blocks"), JsValue::from(2));
+    assert_eq!(js_get(&applied, "bytes"), JsValue::from(data.len() as u32));
+    assert_eq!(replica.get_blocks_count(), 2);
+    assert_eq!(replica.get_next_block_start_pos(), end);
+    assert_eq!(replica.get_latest_block_hash().to_vec(), tip_hash);
+    assert_eq!(replica.get("label1", b"key3").unwrap(), b"value3".to_vec());
+}
+
+#[wasm_bindgen_test]
+fn test_pushed_block_message() {
+    let ledger = create_test_ledger();
+    let offset = ledger.get_latest_block_start_pos();
+    let bytes = ledger.get_raw_block_bytes(offset).unwrap().to_vec();
+    let hash = ledger.get_latest_block_hash().to_vec();
+    let message = format!(
+        r#"{{"index":1,"offset":{},"length":{},"version":1,"timestamp":0,"hash":"{}","bytes":"{}"}}"#,
+        offset,
+        bytes.len(),
+        hex::encode(&hash),
+        BASE64.encode(&bytes)
+    );
+    let message: crate::wasm::BlockMessage =
+        serde_wasm_bindgen::from_value(js_sys::JSON::parse(&message).unwrap()).unwrap();
+    let block = crate::wasm::PushedBlock::try_from(message).unwrap();
+    assert_eq!(block.offset, offset);
+    assert_eq!(block.hash, hash);
+    assert_eq!(block.bytes, bytes);
+
+    let verification: JsValue = ledger.verify_block_from_bytes(&block.bytes).unwrap().into();
+    let verified_hash: Uint8Array = js_get(&verification, "hash").dyn_into().unwrap();
+    assert_eq!(verified_hash.to_vec(), block.hash);
+}
+
+#[wasm_bindgen_test]
+fn test_ledger_verification() {
+    let mut ledger = create_test_ledger();
+    let verification: JsValue = ledger.verify_chain().unwrap().into();
+    assert_eq!(js_get(&verification, "valid"), JsValue::TRUE);
+    let tip_hash: Uint8Array = js_get(&verification, "tip_hash").dyn_into().unwrap();
+    assert_eq!(tip_hash.to_vec(), ledger.get_latest_block_hash().to_vec());
+
+    let tip_start = ledger.get_latest_block_start_pos();
+    let tip_block = ledger.get_raw_block_bytes(tip_start).unwrap().to_vec();
+    let block: JsValue = ledger.verify_block_from_bytes(&tip_block).unwrap().into();
+    let hash: Uint8Array = js_get(&block, "hash").dyn_into().unwrap();
+    assert_eq!(hash.to_vec(), ledger.get_latest_block_hash().to_vec());
+    assert_eq!(js_get(&block, "extends_tip"), JsValue::FALSE);
+    assert_eq!(
+        js_get(&block, "length").as_f64().unwrap() as usize,
+        tip_block.len()
+    );
+
+    let err = ledger.verify_block_from_bytes(&tip_block[..8]).unwrap_err();
+    assert_eq!(
+        js_get(&err, "code"),
+        JsValue::from(LedgerError::BlockCorrupted(String::new()).code())
+    );
+
+    // Overwrite the last block with a copy of the first one, which breaks the hash chain
+    let first_block = ledger.get_raw_block_bytes(0).unwrap().to_vec();
+    persistent_storage_write(tip_start, &first_block).unwrap();
+    let verification: JsValue = ledger.verify_chain().unwrap().into();
+    assert_eq!(js_get(&verification, "valid"), JsValue::FALSE);
+    assert_eq!(js_get(&verification, "offset"), JsValue::from(tip_start));
+    assert_eq!(
+        js_get(&verification, "code"),
+        JsValue::from(
+            LedgerError::HashMismatch {
+                expected: vec![],
+                actual: vec![],
+                offset: 0
+            }
+            .code()
+        )
+    );
+    ledger.refresh().unwrap_err();
+}
+
 #[wasm_bindgen_test]
 fn test_ledger_refresh_persistence() {
     clear_storage();
@@ -192,11 +628,17 @@ fn test_ledger_refresh_persistence() {
 #[wasm_bindgen_test]
 fn test_ledger_entries() {
     let ledger = create_test_ledger();
-    let entries = ledger.get_block_entries(None);
+    let entries: Array = ledger.get_block_entries(None).unwrap().unchecked_into();
     assert_eq!(entries.length(), 3, "Should have 3 total entries");
-    let label1_entries = ledger.get_block_entries(Some("label1".to_string()));
+    let label1_entries: Array = ledger
+        .get_block_entries(Some("label1".to_string()))
+        .unwrap()
+        .unchecked_into();
     assert_eq!(label1_entries.length(), 2, "Label1 should have 2 entries");
-    let label2_entries = ledger.get_block_entries(Some("label2".to_string()));
+    let label2_entries: Array = ledger
+        .get_block_entries(Some("label2".to_string()))
+        .unwrap()
+        .unchecked_into();
     assert_eq!(label2_entries.length(), 1, "Label2 should have 1 entry");
     // Check that a sample entry has the expected properties.
     let entry = label2_entries.get(0).dyn_into::<JsValue>().unwrap();
@@ -217,6 +659,62 @@ fn test_ledger_entries() {
         Reflect::has(&entry_obj, &JsValue::from_str("operation")).unwrap(),
         "Entry should have an 'operation' property"
     );
+    let entry = label2_entries.get(0);
+    assert_eq!(js_get(&entry, "label").as_string().unwrap(), "label2");
+    let key: Uint8Array = js_get(&entry, "key").dyn_into().unwrap();
+    assert_eq!(key.to_vec(), b"key2".to_vec());
+    assert_eq!(js_get(&entry, "operation").as_string().unwrap(), "Upsert");
+}
+
+/// Builds a JS callback that counts its calls and returns `false` after `stop_after` calls.
+fn counting_callback(
+    count: &std::rc::Rc<std::cell::Cell<u32>>,
+    stop_after: u32,
+) -> Closure<dyn FnMut(JsValue) -> JsValue> {
+    let count = count.clone();
+    Closure::wrap(Box::new(move |_: JsValue| {
+        count.set(count.get() + 1);
+        JsValue::from_bool(count.get() < stop_after)
+    }) as Box<dyn FnMut(JsValue) -> JsValue>)
+}
+
+#[wasm_bindgen_test]
+fn test_ledger_for_each() {
+    let ledger = create_test_ledger();
+    let count = std::rc::Rc::new(std::cell::Cell::new(0));
+
+    let callback = counting_callback(&count, u32::MAX);
+    ledger
+        .for_each_entry(None, callback.as_ref().unchecked_ref())
+        .unwrap();
+    assert_eq!(count.get(), 3, "Should visit all 3 entries");
+
+    count.set(0);
+    ledger
+        .for_each_entry(
+            Some("label1".to_string()),
+            callback.as_ref().unchecked_ref(),
+        )
+        .unwrap();
+    assert_eq!(count.get(), 2, "Should visit the 2 entries of label1");
+
+    count.set(0);
+    ledger
+        .for_each_block(0, callback.as_ref().unchecked_ref())
+        .unwrap();
+    assert_eq!(count.get(), 2, "Should visit both blocks");
+
+    // Returning false stops the iteration
+    count.set(0);
+    let stopping_callback = counting_callback(&count, 1);
+    ledger
+        .for_each_entry(None, stopping_callback.as_ref().unchecked_ref())
+        .unwrap();
+    assert_eq!(
+        count.get(),
+        1,
+        "Iteration should stop after the first entry"
+    );
 }
 
 #[wasm_bindgen_test]
@@ -234,7 +732,10 @@ fn test_ledger_next_block_entries() {
         2,
         "Label3 next block entries count should be 2"
     );
-    let next_entries = ledger.get_next_block_entries(None);
+    let next_entries: Array = ledger
+        .get_next_block_entries(None)
+        .unwrap()
+        .unchecked_into();
     assert_eq!(
         next_entries.length(),
         2,